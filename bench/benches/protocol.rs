@@ -0,0 +1,60 @@
+//! Benchmarks of the hot paths of the protocol.
+//!
+//! Run with `cargo bench` in this directory.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use saltyrtc_client::bench::{self, Peers, SampleMessage};
+
+/// The payload sizes of the encryption benchmarks.
+const SIZES: &[usize] = &[64, 1024, 16 * 1024, 256 * 1024];
+
+fn nonce(c: &mut Criterion) {
+    let bytes = bench::nonce();
+    c.bench_function("nonce/roundtrip", |b| b.iter(|| bench::nonce_roundtrip(black_box(&bytes))));
+}
+
+fn boxes(c: &mut Criterion) {
+    let peers = Peers::new();
+    let nonce = bench::nonce();
+    let mut group = c.benchmark_group("box");
+    for &size in SIZES {
+        let data = vec![0x42; size];
+        let ciphertext = peers.encrypt(&data, &nonce);
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::new("encrypt", size), &data, |b, data| {
+            b.iter(|| peers.encrypt(black_box(data), &nonce))
+        });
+        group.bench_with_input(BenchmarkId::new("decrypt", size), &ciphertext, |b, ciphertext| {
+            b.iter(|| peers.decrypt(black_box(ciphertext), &nonce))
+        });
+        group.bench_with_input(BenchmarkId::new("encrypt_precomputed", size), &data, |b, data| {
+            b.iter(|| peers.encrypt_precomputed(black_box(data), &nonce))
+        });
+        group.bench_with_input(BenchmarkId::new("decrypt_precomputed", size), &ciphertext, |b, ciphertext| {
+            b.iter(|| peers.decrypt_precomputed(black_box(ciphertext), &nonce))
+        });
+    }
+    group.finish();
+}
+
+fn msgpack(c: &mut Criterion) {
+    let mut group = c.benchmark_group("msgpack");
+    for message in bench::sample_messages() {
+        let bytes = message.encode();
+        group.throughput(Throughput::Bytes(bytes.len() as u64));
+        group.bench_with_input(BenchmarkId::new("encode", message.name()), &message, |b, message| {
+            b.iter(|| black_box(message).encode())
+        });
+        group.bench_with_input(BenchmarkId::new("decode", message.name()), &bytes, |b, bytes| {
+            b.iter(|| SampleMessage::decode(black_box(bytes)))
+        });
+    }
+    group.finish();
+}
+
+fn handshake(c: &mut Criterion) {
+    c.bench_function("handshake", |b| b.iter(bench::handshake));
+}
+
+criterion_group!(benches, nonce, boxes, msgpack, handshake);
+criterion_main!(benches);
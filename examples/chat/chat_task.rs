@@ -5,7 +5,7 @@ use std::sync::{Arc, Mutex};
 
 use failure::{Error, bail};
 use futures::{Future, Stream, Sink, future};
-use futures::sync::mpsc::{UnboundedSender, UnboundedReceiver};
+use futures::sync::mpsc::{Sender, UnboundedSender, UnboundedReceiver};
 use futures::sync::oneshot::Sender as OneshotSender;
 use saltyrtc_client::{BoxedFuture, CloseCode};
 use saltyrtc_client::tasks::{Task, TaskMessage};
@@ -39,7 +39,7 @@ pub(crate) struct ChatTask {
     pub(crate) our_name: String,
     pub(crate) peer_name: Arc<Mutex<Option<String>>>,
     remote: Remote,
-    outgoing_tx: Option<UnboundedSender<TaskMessage>>,
+    outgoing_tx: Option<Sender<TaskMessage>>,
     incoming_tx: UnboundedSender<ChatMessage>,
     disconnect_tx: Option<OneshotSender<Option<CloseCode>>>,
 }
@@ -78,9 +78,9 @@ impl ChatTask {
         map.insert(KEY_TEXT.into(), Value::String(msg.into()));
 
         // Send message through channel
-        let tx = self.outgoing_tx.clone().expect("outgoing_tx is None");
+        let mut tx = self.outgoing_tx.clone().expect("outgoing_tx is None");
         tx
-            .unbounded_send(TaskMessage::Value(map))
+            .try_send(TaskMessage::Value(map))
             .map_err(|e| format!("Could not send message: {}", e))
     }
 
@@ -92,9 +92,9 @@ impl ChatTask {
         map.insert(KEY_NICK.into(), Value::String(new_nick.into()));
 
         // Send message through channel
-        let tx = self.outgoing_tx.clone().expect("outgoing_tx is None");
+        let mut tx = self.outgoing_tx.clone().expect("outgoing_tx is None");
         let res = tx
-            .unbounded_send(TaskMessage::Value(map))
+            .try_send(TaskMessage::Value(map))
             .map_err(|e| format!("Could not change nickname: {}", e));
         if res.is_ok() {
             self.our_name = new_nick.into();
@@ -129,7 +129,7 @@ impl Task for ChatTask {
     /// This is the point where the task can take over.
     fn start(
         &mut self,
-        outgoing_tx: UnboundedSender<TaskMessage>,
+        outgoing_tx: Sender<TaskMessage>,
         incoming_rx: UnboundedReceiver<TaskMessage>,
         disconnect_tx: OneshotSender<Option<CloseCode>>,
     ) {
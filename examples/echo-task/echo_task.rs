@@ -0,0 +1,150 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use failure::Error;
+use futures::{Future, Stream, Sink, future};
+use futures::sync::mpsc::{Sender, UnboundedSender, UnboundedReceiver};
+use futures::sync::oneshot::Sender as OneshotSender;
+use saltyrtc_client::{BoxedFuture, CloseCode};
+use saltyrtc_client::errors::TaskSendError;
+use saltyrtc_client::tasks::{SendPath, Task, TaskHandle, TaskMessage};
+use saltyrtc_client::dep::rmpv::Value;
+use tokio_core::reactor::Remote;
+
+
+// Message types
+const TYPE_ECHO: &str = "echo";
+const TYPE_ECHO_REPLY: &str = "echo-reply";
+const KEY_TEXT: &str = "text";
+
+
+/// Wrap future in a box with type erasure.
+macro_rules! boxed {
+    ($future:expr) => {{
+        Box::new($future) as BoxedFuture<_, _>
+    }}
+}
+
+
+/// A minimal custom task.
+///
+/// Every `echo` message is answered with an `echo-reply` message containing
+/// the same text. Replies are passed to the user.
+#[derive(Debug)]
+pub(crate) struct EchoTask {
+    remote: Remote,
+    handle: TaskHandle,
+    events_tx: UnboundedSender<EchoEvent>,
+}
+
+/// Events passed from the task to the user.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EchoEvent {
+    /// The peer echoed our text.
+    Reply(String),
+    /// The connection was closed.
+    Closed(CloseCode),
+}
+
+impl EchoTask {
+    /// Create a new EchoTask.
+    ///
+    /// Args:
+    ///
+    /// * `remote` A remote reference to a Tokio reactor core.
+    /// * `events_tx`: The futures channel sender through which replies are sent.
+    pub fn new(remote: Remote, events_tx: UnboundedSender<EchoEvent>) -> Self {
+        EchoTask {
+            remote,
+            handle: TaskHandle::new(),
+            events_tx,
+        }
+    }
+
+    /// Ask the peer to echo the text.
+    pub fn send_echo(&mut self, text: &str) -> Result<(), TaskSendError> {
+        self.handle.send(TaskMessage::builder(TYPE_ECHO).field(KEY_TEXT, text).build())
+    }
+}
+
+impl Task for EchoTask {
+
+    /// The echo task does not exchange any data during the handshake.
+    fn init(&mut self, _data: &Option<HashMap<String, Value>>) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Answer echo requests and forward replies to the user.
+    fn start(
+        &mut self,
+        outgoing_tx: Sender<TaskMessage>,
+        incoming_rx: UnboundedReceiver<TaskMessage>,
+        disconnect_tx: OneshotSender<Option<CloseCode>>,
+    ) {
+        info!("Peer handshake done");
+        self.handle.start(outgoing_tx, disconnect_tx);
+
+        // Replies are sent through a clone of the outgoing channel, so that
+        // they wait for room in the outgoing buffer.
+        let reply_tx = self.handle.outgoing_tx().expect("Task was just started");
+        let events_tx = self.events_tx.clone();
+        self.remote.spawn(move |_| {
+            incoming_rx.for_each(move |msg: TaskMessage| {
+                let text = msg.field(KEY_TEXT).and_then(Value::as_str).map(String::from);
+                match (msg.message_type(), text) {
+                    (Some(TYPE_ECHO), Some(text)) => {
+                        let reply = TaskMessage::builder(TYPE_ECHO_REPLY).field(KEY_TEXT, text).build();
+                        boxed!(
+                            reply_tx
+                                .clone()
+                                .send(reply)
+                                .map(|_| ())
+                                .map_err(|e| error!("Could not send echo reply: {}", e))
+                        )
+                    },
+                    (Some(TYPE_ECHO_REPLY), Some(text)) => boxed!(
+                        events_tx
+                            .clone()
+                            .send(EchoEvent::Reply(text))
+                            .map(|_| ())
+                            .map_err(|e| error!("Sending reply through channel failed: {}", e))
+                    ),
+                    _ => {
+                        if let TaskMessage::Close(reason) = msg {
+                            info!("Received close message from peer (reason: {})", reason);
+                            let _ = events_tx.unbounded_send(EchoEvent::Closed(reason));
+                        } else {
+                            warn!("Ignoring invalid message: {:?}", msg);
+                        }
+                        boxed!(future::ok(()))
+                    },
+                }
+            })
+            .map(|_| debug!("† Echo task receiving future done"))
+        });
+    }
+
+    fn supported_types(&self) -> &'static [&'static str] {
+        &[TYPE_ECHO, TYPE_ECHO_REPLY]
+    }
+
+    fn send_signaling_message(&self, _payload: &[u8]) {
+        panic!("send_signaling_message called even though task does not implement handover");
+    }
+
+    fn name(&self) -> Cow<'static, str> {
+        Cow::Borrowed("v0.echo.example.saltyrtc.org")
+    }
+
+    fn data(&self) -> Option<HashMap<String, Value>> {
+        None
+    }
+
+    fn close(&mut self, reason: CloseCode) {
+        self.handle.close(reason);
+    }
+
+    fn attach(&mut self, path: SendPath) {
+        self.handle.attach(path);
+    }
+}
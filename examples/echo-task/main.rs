@@ -0,0 +1,278 @@
+//! Implement a custom task and use it to echo lines through a peer.
+//!
+//! Start an initiator first. It prints the pairing data that must be passed
+//! to the responder:
+//!
+//!     cargo run --example echo-task -- initiator
+//!     cargo run --example echo-task -- responder --pairing-data <HEX>
+//!
+//! Both peers negotiate the echo task defined in `echo_task.rs`. Every line
+//! typed into stdin is sent to the peer, which sends it back. The echoed
+//! lines are written to stdout. Close stdin (Ctrl+D) to disconnect.
+
+#[macro_use] extern crate log;
+
+mod echo_task;
+
+use std::fs::File;
+use std::io::{self, BufRead, Read};
+use std::process;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+
+use clap::{Arg, App, AppSettings, SubCommand};
+use futures::{Stream, future};
+use futures::future::Future;
+use futures::sync::mpsc as futures_mpsc;
+use log::LevelFilter;
+use log4rs::append::console::{ConsoleAppender, Target};
+use log4rs::encode::pattern::PatternEncoder;
+use log4rs::config::{Appender, Config, Logger, Root};
+use saltyrtc_client::{SaltyClient, WsClient, CloseCode, Event, TlsConfig};
+use saltyrtc_client::crypto::{KeyPair, PairingData};
+use saltyrtc_client::dep::native_tls::Certificate;
+use saltyrtc_client::errors::SaltyError;
+use saltyrtc_client::tasks::{BoxedTask, Task};
+use tokio_core::reactor::Core;
+
+use crate::echo_task::{EchoTask, EchoEvent};
+
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+
+fn main() {
+    const ARG_PAIRING_DATA: &str = "pairing_data";
+    const ARG_HOST: &str = "host";
+    const ARG_PORT: &str = "port";
+    const ARG_CA_CERT: &str = "ca_cert";
+    const ARG_VERBOSE: &str = "verbose";
+
+    // Set up CLI arguments
+    let app = App::new("SaltyRTC Echo Task Client")
+        .version(VERSION)
+        .about("Send lines from stdin to a SaltyRTC peer and print the echo.")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(Arg::with_name(ARG_HOST)
+            .long("host")
+            .takes_value(true)
+            .default_value("localhost")
+            .help("The SaltyRTC server host"))
+        .arg(Arg::with_name(ARG_PORT)
+            .long("port")
+            .takes_value(true)
+            .default_value("8765")
+            .help("The SaltyRTC server port"))
+        .arg(Arg::with_name(ARG_CA_CERT)
+            .long("ca-cert")
+            .takes_value(true)
+            .value_name("PEM_FILE")
+            .help("Trust the CA certificate in this file (e.g. saltyrtc.crt)"))
+        .arg(Arg::with_name(ARG_VERBOSE)
+            .short("v")
+            .help("Log debug information to stderr"))
+        .subcommand(SubCommand::with_name("initiator")
+            .about("Connect as initiator and print the pairing data"))
+        .subcommand(SubCommand::with_name("responder")
+            .about("Connect as responder using the pairing data of the initiator")
+            .arg(Arg::with_name(ARG_PAIRING_DATA)
+                .long("pairing-data")
+                .takes_value(true)
+                .value_name("HEX")
+                .required(true)
+                .help("The pairing data printed by the initiator")));
+    let matches = app.get_matches();
+
+    // Set up logging
+    let level = if matches.is_present(ARG_VERBOSE) { LevelFilter::Debug } else { LevelFilter::Warn };
+    log4rs::init_config(setup_logging(level)).unwrap();
+
+    // Parse arguments
+    let host = matches.value_of(ARG_HOST).unwrap().to_string();
+    let port: u16 = matches.value_of(ARG_PORT).unwrap().parse().unwrap_or_else(|_| {
+        eprintln!("Invalid port");
+        process::exit(1);
+    });
+    let mut tls_config = TlsConfig::new();
+    if let Some(path) = matches.value_of(ARG_CA_CERT) {
+        let mut bytes = vec![];
+        File::open(path)
+            .and_then(|mut file| file.read_to_end(&mut bytes))
+            .unwrap_or_else(|e| {
+                eprintln!("Could not read {}: {}", path, e);
+                process::exit(1);
+            });
+        let cert = Certificate::from_pem(&bytes).unwrap_or_else(|e| {
+            eprintln!("Problem with CA cert: {}", e);
+            process::exit(1);
+        });
+        tls_config = tls_config.add_root_certificate(cert);
+    }
+
+    // Tokio reactor core
+    let mut core = Core::new().unwrap();
+
+    // Create new SaltyRTC client instance with the custom task
+    let (events_tx, events_rx) = futures_mpsc::unbounded::<EchoEvent>();
+    let task = EchoTask::new(core.remote(), events_tx);
+    let builder = SaltyClient::build(KeyPair::new())
+        .add_task(Box::new(task))
+        .with_tls_config(tls_config);
+    let salty = match matches.subcommand() {
+        ("initiator", _) => builder.initiator(),
+        ("responder", Some(args)) => {
+            let pairing_data = PairingData::from_hex_str(args.value_of(ARG_PAIRING_DATA).unwrap())
+                .unwrap_or_else(|e| {
+                    eprintln!("Invalid pairing data: {}", e);
+                    process::exit(1);
+                });
+            let (initiator_pubkey, auth_token) = pairing_data.into_parts();
+            builder.responder(initiator_pubkey, auth_token)
+        },
+        _ => unreachable!("Subcommand is required"),
+    }.expect("Could not create SaltyClient instance");
+
+    eprintln!("Connecting to {}:{} as {}", host, port, salty.role());
+    if let Some(pairing_data) = salty.pairing_data() {
+        eprintln!();
+        eprintln!("To connect a responder:");
+        eprintln!("cargo run --example echo-task -- --host {} --port {} responder \\", host, port);
+        eprintln!("    --pairing-data {}", pairing_data.to_hex());
+        eprintln!();
+    }
+
+    // Connect to server and do handshake
+    let salty_arc = Arc::new(RwLock::new(salty));
+    let (connect_future, event_channel) = saltyrtc_client::connect(
+            &host,
+            port,
+            None,
+            &core.handle(),
+            salty_arc.clone(),
+        )
+        .unwrap_or_else(|e| {
+            eprintln!("Could not connect: {}", e);
+            process::exit(1);
+        });
+    let event_tx = event_channel.clone_tx();
+    let handshake_future = connect_future
+        .and_then(|client| saltyrtc_client::do_handshake(client, salty_arc.clone(), event_tx, None));
+    let client: WsClient = core.run(handshake_future).unwrap_or_else(|e| {
+        eprintln!("Handshake failed: {}", e);
+        process::exit(1);
+    });
+    eprintln!("Handshake done, type lines to echo (Ctrl+D to quit)");
+
+    // Set up task loop
+    let (task, task_loop) = saltyrtc_client::task_loop(client, salty_arc.clone(), event_channel.clone_tx())
+        .unwrap_or_else(|e| {
+            eprintln!("Creating task loop failed: {}", e);
+            process::exit(1);
+        });
+
+    // Read stdin lines in a separate thread
+    let (line_tx, line_rx) = futures_mpsc::unbounded::<String>();
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            match line {
+                Ok(line) => if line_tx.unbounded_send(line).is_err() {
+                    break;
+                },
+                Err(e) => {
+                    error!("Could not read from stdin: {}", e);
+                    break;
+                },
+            }
+        }
+    });
+
+    // Send lines to the peer. Once stdin is closed, disconnect.
+    let send_task = task.clone();
+    let send_loop = line_rx
+        .map_err(|_| SaltyError::Crash("Could not read from stdin channel".into()))
+        .for_each(move |line: String| {
+            with_task(&send_task, |t| t.send_echo(&line).map_err(|e| e.to_string()))
+                .map_err(SaltyError::Task)
+        })
+        .and_then({
+            let task = task.clone();
+            move |_| {
+                close(&task);
+                Ok(())
+            }
+        });
+
+    // Print the echoed lines
+    let receive_loop = events_rx
+        .map_err(|_| SaltyError::Crash("Could not receive from task channel".into()))
+        .take_while(|event: &EchoEvent| match event {
+            EchoEvent::Reply(text) => {
+                println!("{}", text);
+                Ok(true)
+            },
+            EchoEvent::Closed(reason) => {
+                eprintln!("Connection with peer closed, reason: {}", reason);
+                Ok(false)
+            },
+        })
+        .for_each(|_| Ok(()));
+
+    // Disconnect if the peer leaves the server
+    let (_, event_rx) = event_channel.split();
+    let event_loop = event_rx
+        .map_err(|_| SaltyError::Crash("Could not receive from event channel".into()))
+        .take_while(|event: &Event| match event {
+            Event::Disconnected(_) => Ok(false),
+            _ => Ok(true),
+        })
+        .for_each(|_| Ok(()))
+        .and_then(move |_| {
+            close(&task);
+            Ok(())
+        });
+
+    // Run until one side stops and the connection is closed
+    let user_loops = future::select_all(vec![
+        Box::new(send_loop) as Box<dyn Future<Item=_, Error=_>>,
+        Box::new(receive_loop) as Box<dyn Future<Item=_, Error=_>>,
+        Box::new(event_loop) as Box<dyn Future<Item=_, Error=_>>,
+    ])
+    .map(|_| ())
+    .map_err(|(e, _, _)| e);
+    if let Err(e) = core.run(task_loop.join(user_loops)) {
+        eprintln!("Connection failed: {}", e);
+        process::exit(1);
+    }
+}
+
+/// Call the closure with the negotiated echo task.
+fn with_task<F, T>(task: &Arc<Mutex<BoxedTask>>, f: F) -> Result<T, String>
+    where F: FnOnce(&mut EchoTask) -> Result<T, String>
+{
+    let mut t = task.lock().map_err(|e| format!("Could not lock task mutex: {}", e))?;
+    let echo_task = (&mut **t as &mut dyn Task)
+        .downcast_mut::<EchoTask>()
+        .ok_or_else(|| "Chosen task is not an EchoTask".to_string())?;
+    f(echo_task)
+}
+
+/// Close the connection to the peer.
+fn close(task: &Arc<Mutex<BoxedTask>>) {
+    with_task(task, |t| {
+        t.close(CloseCode::WsGoingAway);
+        Ok(())
+    }).unwrap_or_else(|e| error!("Could not close task: {}", e));
+}
+
+fn setup_logging(level: LevelFilter) -> Config {
+    let stderr = ConsoleAppender::builder()
+        .target(Target::Stderr)
+        .encoder(Box::new(PatternEncoder::new("{d(%H:%M:%S%.3f)} [{l:<5}] {m}{n}")))
+        .build();
+    Config::builder()
+        .appender(Appender::builder().build("stderr", Box::new(stderr)))
+        .logger(Logger::builder().build("saltyrtc_client", level))
+        .build(Root::builder().appender("stderr").build(level))
+        .unwrap()
+}
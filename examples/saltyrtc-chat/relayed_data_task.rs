@@ -0,0 +1,149 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use failure::Error;
+use futures::{Future, Stream, Sink, future};
+use futures::sync::mpsc::{Sender, UnboundedSender, UnboundedReceiver};
+use futures::sync::oneshot::Sender as OneshotSender;
+use saltyrtc_client::{BoxedFuture, CloseCode};
+use saltyrtc_client::tasks::{Task, TaskMessage};
+use saltyrtc_client::dep::rmpv::Value;
+use tokio_core::reactor::Remote;
+
+
+// Message types
+const TYPE_DATA: &str = "data";
+const KEY_TYPE: &str = "type";
+const KEY_PAYLOAD: &str = "p";
+
+
+/// Wrap future in a box with type erasure.
+macro_rules! boxed {
+    ($future:expr) => {{
+        Box::new($future) as BoxedFuture<_, _>
+    }}
+}
+
+
+/// An implementation of the
+/// [Relayed Data task](https://github.com/saltyrtc/saltyrtc-meta/blob/master/Task-RelayedData.md).
+///
+/// Arbitrary values are relayed through the server, end-to-end encrypted.
+#[derive(Debug)]
+pub(crate) struct RelayedDataTask {
+    remote: Remote,
+    outgoing_tx: Option<Sender<TaskMessage>>,
+    incoming_tx: UnboundedSender<RelayedMessage>,
+    disconnect_tx: Option<OneshotSender<Option<CloseCode>>>,
+}
+
+/// Messages passed from the task to the user.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RelayedMessage {
+    Data(Value),
+    Disconnect(CloseCode),
+}
+
+impl RelayedDataTask {
+    /// Create a new RelayedDataTask.
+    ///
+    /// Args:
+    ///
+    /// * `remote` A remote reference to a Tokio reactor core.
+    /// * `incoming_tx`: The futures channel sender through which incoming data is sent.
+    pub fn new(remote: Remote, incoming_tx: UnboundedSender<RelayedMessage>) -> Self {
+        RelayedDataTask {
+            remote,
+            outgoing_tx: None,
+            incoming_tx,
+            disconnect_tx: None,
+        }
+    }
+
+    /// Send a value to the peer.
+    ///
+    /// Fails if the outgoing buffer is full.
+    pub fn send_data(&self, payload: Value) -> Result<(), String> {
+        let mut map: HashMap<String, Value> = HashMap::new();
+        map.insert(KEY_TYPE.into(), Value::String(TYPE_DATA.into()));
+        map.insert(KEY_PAYLOAD.into(), payload);
+
+        let mut tx = self.outgoing_tx.clone().ok_or_else(|| "Task has not been started".to_string())?;
+        tx
+            .try_send(TaskMessage::Value(map))
+            .map_err(|e| format!("Could not send data: {}", e))
+    }
+}
+
+impl Task for RelayedDataTask {
+
+    /// The relayed data task does not exchange any data during the handshake.
+    fn init(&mut self, _data: &Option<HashMap<String, Value>>) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Start relaying incoming data to the user.
+    fn start(
+        &mut self,
+        outgoing_tx: Sender<TaskMessage>,
+        incoming_rx: UnboundedReceiver<TaskMessage>,
+        disconnect_tx: OneshotSender<Option<CloseCode>>,
+    ) {
+        info!("Peer handshake done");
+        self.outgoing_tx = Some(outgoing_tx);
+        self.disconnect_tx = Some(disconnect_tx);
+
+        let incoming_tx = self.incoming_tx.clone();
+        self.remote.spawn(move |_| {
+            incoming_rx.for_each(move |msg: TaskMessage| {
+                let relayed = match msg {
+                    TaskMessage::Value(mut map) => match map.remove(KEY_PAYLOAD) {
+                        Some(payload) => RelayedMessage::Data(payload),
+                        None => {
+                            warn!("Data message is missing `{}` key", KEY_PAYLOAD);
+                            return boxed!(future::ok(()));
+                        },
+                    },
+                    TaskMessage::Application(_data) => {
+                        info!("Received application message from peer, ignoring");
+                        return boxed!(future::ok(()));
+                    },
+                    TaskMessage::Close(reason) => {
+                        info!("Received close message from peer (reason: {})", reason);
+                        RelayedMessage::Disconnect(reason)
+                    },
+                };
+                boxed!(
+                    incoming_tx
+                        .clone()
+                        .send(relayed)
+                        .map(|_| ())
+                        .map_err(|e| error!("Sending incoming data through channel failed: {}", e))
+                )
+            })
+            .map(|_| debug!("† Relayed data task receiving future done"))
+        });
+    }
+
+    fn supported_types(&self) -> &'static [&'static str] {
+        &[TYPE_DATA]
+    }
+
+    fn send_signaling_message(&self, _payload: &[u8]) {
+        panic!("send_signaling_message called even though task does not implement handover");
+    }
+
+    fn name(&self) -> Cow<'static, str> {
+        Cow::Borrowed("v0.relayed-data.tasks.saltyrtc.org")
+    }
+
+    fn data(&self) -> Option<HashMap<String, Value>> {
+        None
+    }
+
+    fn close(&mut self, reason: CloseCode) {
+        if let Some(channel) = self.disconnect_tx.take() {
+            let _ = channel.send(Some(reason));
+        }
+    }
+}
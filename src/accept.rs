@@ -0,0 +1,102 @@
+//! Deciding which responders an initiator accepts.
+//!
+//! By default, an initiator accepts every responder that knows the auth token
+//! (or, for trusted initiators, owns the trusted key). To restrict this, for
+//! example to reject unknown permanent keys, register an
+//! [`AcceptPolicy`](trait.AcceptPolicy.html) with
+//! [`SaltyClientBuilder::with_accept_policy`](../struct.SaltyClientBuilder.html#method.with_accept_policy).
+//!
+//! The policy is consulted once per responder, as soon as the responder has
+//! proven that it owns its permanent key (when its `key` message could be
+//! decrypted). Rejected responders are dropped with the close code 3004
+//! (Dropped by Initiator), the handshakes with other responders continue.
+
+use crate::crypto_types::PublicKey;
+
+
+/// Whether a responder should be accepted.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Decision {
+    /// Continue the handshake with the responder.
+    Accept,
+    /// Drop the responder.
+    Reject,
+}
+
+
+/// A policy that decides whether a responder is accepted.
+///
+/// Closures with the signature `FnMut(&PublicKey, bool) -> Decision`
+/// implement this trait.
+pub trait AcceptPolicy: Send {
+    /// Decide whether the responder with the public permanent key `peer_key`
+    /// is accepted.
+    ///
+    /// `used_token` is `true` if the responder authenticated with the auth
+    /// token, and `false` if its key was trusted in advance.
+    fn accept(&mut self, peer_key: &PublicKey, used_token: bool) -> Decision;
+}
+
+impl<F> AcceptPolicy for F where F: FnMut(&PublicKey, bool) -> Decision + Send {
+    fn accept(&mut self, peer_key: &PublicKey, used_token: bool) -> Decision {
+        self(peer_key, used_token)
+    }
+}
+
+
+/// A policy that only accepts responders with one of the specified public
+/// permanent keys.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AllowedKeys {
+    keys: Vec<PublicKey>,
+}
+
+impl AllowedKeys {
+    /// Only accept a single peer.
+    pub fn single(key: PublicKey) -> Self {
+        AllowedKeys { keys: vec![key] }
+    }
+
+    /// Only accept the specified peers.
+    pub fn new(keys: Vec<PublicKey>) -> Self {
+        AllowedKeys { keys }
+    }
+}
+
+impl AcceptPolicy for AllowedKeys {
+    fn accept(&mut self, peer_key: &PublicKey, _used_token: bool) -> Decision {
+        if self.keys.contains(peer_key) {
+            Decision::Accept
+        } else {
+            Decision::Reject
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allowed_keys() {
+        let key = PublicKey::from_slice(&[1; 32]).unwrap();
+        let mut policy = AllowedKeys::single(key);
+        assert_eq!(policy.accept(&key, true), Decision::Accept);
+        assert_eq!(policy.accept(&PublicKey::from_slice(&[2; 32]).unwrap(), false), Decision::Reject);
+    }
+
+    #[test]
+    fn closure_policy() {
+        let mut calls = vec![];
+        {
+            let mut policy = |_key: &PublicKey, used_token: bool| {
+                calls.push(used_token);
+                if used_token { Decision::Reject } else { Decision::Accept }
+            };
+            assert_eq!(AcceptPolicy::accept(&mut policy, &PublicKey::from_slice(&[1; 32]).unwrap(), true), Decision::Reject);
+            assert_eq!(AcceptPolicy::accept(&mut policy, &PublicKey::from_slice(&[1; 32]).unwrap(), false), Decision::Accept);
+        }
+        assert_eq!(calls, vec![true, false]);
+    }
+}
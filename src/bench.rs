@@ -0,0 +1,297 @@
+//! Internals used by the benchmarks in `bench/`.
+//!
+//! Only compiled with the `benchmarks` feature, which must never be enabled
+//! in production. Nothing in here is part of the public API.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use failure::Error;
+use futures::sync::mpsc::{Sender, UnboundedReceiver};
+use futures::sync::oneshot::Sender as OneshotSender;
+use rmpv::Value;
+
+use crate::CloseCode;
+use crate::boxes::{ByteBox, OpenBox};
+use crate::crypto_backend::{self, backend};
+use crate::crypto_types::{AuthToken, KeyPair, PublicKey, SecretKey};
+use crate::protocol::{AuthProvider, InitiatorSignaling, Nonce, ResponderSignaling, Signaling};
+use crate::protocol::cookie::Cookie;
+use crate::protocol::csn::CombinedSequence;
+use crate::protocol::messages::*;
+use crate::protocol::random::OsRandom;
+use crate::protocol::send_error::SendErrorId;
+use crate::protocol::state::SignalingState;
+use crate::protocol::types::Address;
+use crate::tasks::{Task, TaskMessage, Tasks};
+
+
+/// A random nonce from the initiator to the responder.
+pub fn nonce() -> [u8; 24] {
+    let csn = CombinedSequence::random_from(&mut OsRandom).increment().expect("CSN overflow");
+    Nonce::new(Cookie::random_from(&mut OsRandom), Address(1), Address(2), csn).into_bytes()
+}
+
+/// Decode and validate a nonce and encode it again.
+pub fn nonce_roundtrip(bytes: &[u8]) -> [u8; 24] {
+    Nonce::from_bytes(bytes).expect("Invalid nonce").into_bytes()
+}
+
+
+/// Two peers that encrypt messages for each other.
+pub struct Peers {
+    sender: KeyPair,
+    receiver: KeyPair,
+    precomputed: SecretKey,
+}
+
+impl Peers {
+    /// Create two peers with random keys.
+    pub fn new() -> Self {
+        let sender = KeyPair::new();
+        let receiver = KeyPair::new();
+        let precomputed = SecretKey(backend().box_precompute(&receiver.public_key().0, &sender.private_key().0));
+        Peers { sender, receiver, precomputed }
+    }
+
+    /// Encrypt `data` for the receiver, the way the signaling does.
+    pub fn encrypt(&self, data: &[u8], nonce: &[u8; 24]) -> Vec<u8> {
+        self.sender.encrypt(data, Nonce::from_bytes(nonce).expect("Invalid nonce"), self.receiver.public_key())
+    }
+
+    /// Decrypt data from the sender, the way the signaling does.
+    pub fn decrypt(&self, data: &[u8], nonce: &[u8; 24]) -> Vec<u8> {
+        self.receiver.decrypt(data, Nonce::from_bytes(nonce).expect("Invalid nonce"), self.sender.public_key())
+            .expect("Decryption failed")
+    }
+
+    /// Encrypt `data` for the receiver with a precomputed shared key.
+    ///
+    /// The signaling does not precompute shared keys, this is the baseline
+    /// to compare against.
+    pub fn encrypt_precomputed(&self, data: &[u8], nonce: &[u8; 24]) -> Vec<u8> {
+        crypto_backend::secretbox_seal(data, nonce, &self.precomputed)
+    }
+
+    /// Decrypt data from the sender with a precomputed shared key.
+    pub fn decrypt_precomputed(&self, data: &[u8], nonce: &[u8; 24]) -> Vec<u8> {
+        crypto_backend::secretbox_open(data, nonce, &self.precomputed).expect("Decryption failed")
+    }
+}
+
+impl Default for Peers {
+    fn default() -> Self {
+        Peers::new()
+    }
+}
+
+
+/// A signaling message of one of the types defined by the protocol.
+pub struct SampleMessage {
+    name: &'static str,
+    message: Message,
+}
+
+impl SampleMessage {
+    fn new(name: &'static str, message: Message) -> Self {
+        SampleMessage { name, message }
+    }
+
+    /// Return the type of the message, and the role of the sender if it
+    /// affects the content.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Encode the message to msgpack.
+    pub fn encode(&self) -> Vec<u8> {
+        self.message.to_msgpack()
+    }
+
+    /// Decode a message from msgpack, and return its type.
+    pub fn decode(bytes: &[u8]) -> &'static str {
+        Message::from_msgpack(bytes).expect("Invalid message").get_type()
+    }
+}
+
+/// Return one message of every type.
+pub fn sample_messages() -> Vec<SampleMessage> {
+    let key = *KeyPair::new().public_key();
+    let cookie = Cookie::random_from(&mut OsRandom);
+    let tasks = Tasks::new(Box::new(BenchTask));
+    let initiator_auth = InitiatorAuthBuilder::new(cookie.clone())
+        .set_task(BenchTask::NAME, None)
+        .build()
+        .expect("Invalid auth message");
+    let responder_auth = ResponderAuthBuilder::new(cookie.clone())
+        .add_tasks(&tasks)
+        .build()
+        .expect("Invalid auth message");
+    let client_auth = ClientAuth {
+        your_cookie: cookie.clone(),
+        subprotocols: vec!["v1.saltyrtc.org".into()],
+        ping_interval: 0,
+        your_key: Some(key),
+        extra: ExtraFields::default(),
+    };
+    let send_error_id = SendErrorId::from_slice(&[1; 8]).expect("Invalid id");
+    vec![
+        SampleMessage::new("client-hello", ClientHello::new(key).into_message()),
+        SampleMessage::new("server-hello", ServerHello::new(key).into_message()),
+        SampleMessage::new("client-auth", Message::ClientAuth(client_auth)),
+        SampleMessage::new(
+            "server-auth",
+            ServerAuth::for_initiator(cookie, None, vec![Address(2), Address(3)]).into_message(),
+        ),
+        SampleMessage::new("new-initiator", NewInitiator::default().into_message()),
+        SampleMessage::new("new-responder", NewResponder::new(Address(2)).into_message()),
+        SampleMessage::new(
+            "drop-responder",
+            DropResponder::with_reason(Address(2), DropReason::DroppedByInitiator).into_message(),
+        ),
+        SampleMessage::new("send-error", SendError::new(send_error_id).into_message()),
+        SampleMessage::new("disconnected", Disconnected::new(Address(2)).into_message()),
+        SampleMessage::new("token", Token::new(key).into_message()),
+        SampleMessage::new("key", Key::new(key).into_message()),
+        SampleMessage::new("auth (initiator)", initiator_auth.into_message()),
+        SampleMessage::new("auth (responder)", responder_auth.into_message()),
+        SampleMessage::new("close", Close::from_close_code(CloseCode::WsGoingAway).into_message()),
+    ]
+}
+
+
+/// The server side of a client connection.
+struct ServerPath {
+    address: Address,
+    client_key: PublicKey,
+    cookie: Cookie,
+    csn: CombinedSequence,
+}
+
+impl ServerPath {
+    fn new(address: u8, client_key: PublicKey) -> Self {
+        ServerPath {
+            address: Address(address),
+            client_key,
+            cookie: Cookie::random_from(&mut OsRandom),
+            csn: CombinedSequence::random_from(&mut OsRandom),
+        }
+    }
+
+    fn nonce(&mut self, destination: Address) -> Nonce {
+        let csn = self.csn.increment().expect("CSN overflow");
+        Nonce::new(self.cookie.clone(), Address(0), destination, csn)
+    }
+
+    fn server_hello(&mut self, server: &KeyPair) -> ByteBox {
+        let msg = ServerHello::new(*server.public_key()).into_message();
+        OpenBox::<Message>::new(msg, self.nonce(Address(0))).encode()
+    }
+
+    fn encrypt(&mut self, server: &KeyPair, msg: Message) -> ByteBox {
+        let nonce = self.nonce(self.address);
+        OpenBox::<Message>::new(msg, nonce).encrypt(server, &self.client_key)
+    }
+
+    /// Decrypt the client-auth message and return the cookie of the client.
+    fn client_auth(&self, server: &KeyPair, bbox: ByteBox) -> Cookie {
+        OpenBox::<Message>::decrypt(bbox, server, &self.client_key, &["client-auth"])
+            .expect("Invalid client-auth message")
+            .nonce
+            .cookie()
+            .clone()
+    }
+}
+
+/// Return the messages that should be sent.
+fn replies<S: Signaling>(signaling: &mut S, bbox: ByteBox) -> Vec<ByteBox> {
+    signaling.handle_message(bbox).expect("Handshake failed").split_replies().0
+}
+
+/// Run the server and peer handshakes of an initiator and a responder that
+/// authenticates with an auth token, through a simulated server.
+///
+/// ## Panics
+///
+/// This panics if the handshake fails.
+pub fn handshake() {
+    let server = KeyPair::new();
+    let initiator_ks = KeyPair::new();
+    let responder_ks = KeyPair::new();
+    let initiator_pk = *initiator_ks.public_key();
+    let responder_pk = *responder_ks.public_key();
+    let mut initiator = InitiatorSignaling::with_rng(
+        initiator_ks, Tasks::new(Box::new(BenchTask)), None, None, None, Box::new(OsRandom),
+    );
+    let auth_token: Option<AuthToken> = match initiator.common().auth_provider {
+        Some(AuthProvider::Token(ref token)) => Some(token.clone()),
+        _ => None,
+    };
+    let mut responder = ResponderSignaling::with_rng(
+        responder_ks, initiator_pk, auth_token, None, Tasks::new(Box::new(BenchTask)), None, Box::new(OsRandom),
+    );
+
+    // Server handshake of the initiator
+    let mut initiator_path = ServerPath::new(1, initiator_pk);
+    let mut out = replies(&mut initiator, initiator_path.server_hello(&server));
+    let cookie = initiator_path.client_auth(&server, out.remove(0));
+    let msg = ServerAuth::for_initiator(cookie, None, vec![]).into_message();
+    replies(&mut initiator, initiator_path.encrypt(&server, msg));
+
+    // Server handshake of the responder
+    let mut responder_path = ServerPath::new(2, responder_pk);
+    let mut out = replies(&mut responder, responder_path.server_hello(&server));
+    let cookie = responder_path.client_auth(&server, out.remove(1));
+    let msg = ServerAuth::for_responder(cookie, None, true).into_message();
+    let out = replies(&mut responder, responder_path.encrypt(&server, msg));
+    let msg = NewResponder::new(Address(2)).into_message();
+    replies(&mut initiator, initiator_path.encrypt(&server, msg));
+
+    // Peer handshake, starting with the token and key of the responder
+    let mut to_initiator = out;
+    while !to_initiator.is_empty() {
+        let mut to_responder = vec![];
+        for bbox in to_initiator {
+            to_responder.extend(replies(&mut initiator, bbox));
+        }
+        to_initiator = vec![];
+        for bbox in to_responder {
+            to_initiator.extend(replies(&mut responder, bbox));
+        }
+    }
+    assert_eq!(initiator.common().signaling_state(), SignalingState::Task);
+    assert_eq!(responder.common().signaling_state(), SignalingState::Task);
+}
+
+
+/// A task that does nothing.
+#[derive(Debug)]
+struct BenchTask;
+
+impl BenchTask {
+    const NAME: &'static str = "bench";
+}
+
+impl Task for BenchTask {
+    fn init(&mut self, _data: &Option<HashMap<String, Value>>) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn start(&mut self, _: Sender<TaskMessage>, _: UnboundedReceiver<TaskMessage>, _: OneshotSender<Option<CloseCode>>) {}
+
+    fn supported_types(&self) -> &'static [&'static str] {
+        &["bench"]
+    }
+
+    fn send_signaling_message(&self, _payload: &[u8]) {}
+
+    fn name(&self) -> Cow<'static, str> {
+        BenchTask::NAME.into()
+    }
+
+    fn data(&self) -> Option<HashMap<String, Value>> {
+        None
+    }
+
+    fn close(&mut self, _reason: CloseCode) {}
+}
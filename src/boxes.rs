@@ -1,28 +1,52 @@
-//! Functionality related to libsodium crypto boxes.
+//! Functionality related to NaCl crypto boxes.
 //!
 //! An open box consists of an unencrypted message and a nonce.
 //!
 //! A sealed box consists of the encrypted message bytes and a nonce.
+//!
+//! A byte box keeps the nonce and the payload in a single buffer, in the
+//! same layout as they are sent over the WebSocket. Messages are encrypted
+//! and decrypted in place, so a message is only copied when it is
+//! (de)serialized.
+
+use std::fmt;
 
 use rmp_serde as rmps;
 use rmpv::Value;
-use rust_sodium::crypto::box_::NONCEBYTES;
+use crate::crypto_backend::NONCEBYTES;
+use serde::Serialize;
 
-use crate::errors::{SignalingError, SignalingResult};
+use crate::errors::{FrameError, SaltyResult, SignalingError, SignalingResult};
 use crate::crypto::{KeyPair, PublicKey, AuthToken};
+use crate::crypto_types::MACBYTES;
 use crate::protocol::Nonce;
-use crate::protocol::messages::Message;
+use crate::protocol::messages::{Message, value_type};
+
+/// The maximum length of a received frame, including the nonce.
+///
+/// Larger frames are rejected before they are parsed.
+pub(crate) const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
 
 /// An open box (unencrypted message + nonce).
 #[derive(Debug, PartialEq)]
 pub(crate) struct OpenBox<T> {
     pub(crate) message: T,
     pub(crate) nonce: Nonce,
+    /// The msgpack bytes the message was decoded from, if they were kept.
+    pub(crate) plaintext: Option<Vec<u8>>,
+}
+
+impl<T> OpenBox<T> {
+    /// Keep the msgpack bytes of the message, e.g. for the transcript.
+    fn with_plaintext(mut self, plaintext: &[u8]) -> Self {
+        self.plaintext = Some(plaintext.to_vec());
+        self
+    }
 }
 
 impl OpenBox<Message> {
     pub(crate) fn new(message: Message, nonce: Nonce) -> Self {
-        OpenBox { message, nonce }
+        OpenBox { message, nonce, plaintext: None }
     }
 
     /// Encode without encryption into a [`ByteBox`](struct.ByteBox.html).
@@ -30,156 +54,387 @@ impl OpenBox<Message> {
     /// This should only be necessary for the server-hello message. All other
     /// messages are encrypted.
     pub(crate) fn encode(self) -> ByteBox {
-        let bytes = self.message.to_msgpack();
-        ByteBox::new(bytes, self.nonce)
+        ByteBox::serialize(&self.message.to_value(), self.nonce, 0, true)
+            .with_message_type(Some(self.message.get_type()))
     }
 
     /// Encrypt message for the `other_key` using public key cryptography.
     pub(crate) fn encrypt(self, keypair: &KeyPair, other_key: &PublicKey) -> ByteBox {
-        let encrypted = keypair.encrypt(
-            // The message bytes to be encrypted
-            &self.message.to_msgpack(),
-            // The nonce. The unsafe call to `clone()` is required because the
-            // nonce needs to be used both for encrypting, as well as being
-            // sent along with the message bytes.
-            unsafe { self.nonce.clone() },
-            // The public key of the recipient
-            other_key
-        );
-        ByteBox::new(encrypted, self.nonce)
+        ByteBox::serialize(&self.message.to_value(), self.nonce, MACBYTES, true)
+            .with_message_type(Some(self.message.get_type()))
+            .encrypt(keypair, other_key)
     }
 
     /// Encrypt token message using the `auth_token` using secret key cryptography.
     pub(crate) fn encrypt_token(self, auth_token: &AuthToken) -> ByteBox {
-        let encrypted = auth_token.encrypt(
-            // The message bytes to be encrypted
-            &self.message.to_msgpack(),
-            // The nonce. The unsafe call to `clone()` is required because the
-            // nonce needs to be used both for encrypting, as well as being
-            // sent along with the message bytes.
-            unsafe { self.nonce.clone() }
-        );
-        ByteBox::new(encrypted, self.nonce)
+        let mut bbox = ByteBox::serialize(&self.message.to_value(), self.nonce, MACBYTES, true)
+            .with_message_type(Some(self.message.get_type()));
+        // The unsafe call to `clone()` is required because the nonce needs
+        // to be used both for encrypting, as well as being sent along with
+        // the message bytes.
+        let nonce = unsafe { bbox.nonce.clone() };
+        auth_token.encrypt_in_place(bbox.payload_mut(), nonce);
+        bbox
     }
 
     /// Decode an unencrypted message into an [`OpenBox`](struct.OpenBox.html).
     ///
     /// This should only be necessary for the server-hello message. All other
     /// messages are encrypted.
-    pub(crate) fn decode(bbox: ByteBox) -> SignalingResult<Self> {
-        let message = Message::from_msgpack(&bbox.bytes)
-            .map_err(|e| SignalingError::Decode(format!("Cannot decode message payload: {}", e)))?;
-        Ok(Self::new(message, bbox.nonce))
+    pub(crate) fn decode(bbox: ByteBox, expected_types: &[&str]) -> SignalingResult<Self> {
+        let message = decode_message(bbox.payload(), expected_types)?;
+        Ok(Self::new(message, bbox.nonce).with_plaintext(&bbox.frame[NONCEBYTES..]))
     }
 
     /// Decrypt an encrypted message into an [`OpenBox`](struct.OpenBox.html).
-    pub(crate) fn decrypt(bbox: ByteBox, keypair: &KeyPair, other_key: &PublicKey) -> SignalingResult<Self> {
-        let decrypted: Vec<u8> = keypair.decrypt(
-            // The message bytes to be decrypted
-            &bbox.bytes,
-            // The nonce. The unsafe call to `clone()` is required because the
-            // nonce needs to be used both for decrypting, as well as being
-            // passed along with the message bytes.
-            unsafe { bbox.nonce.clone() },
-            // The public key of the recipient
-            other_key
-        ).map_err(|e| SignalingError::Decode(format!("Cannot decrypt message payload: {}", e)))?;
-
-        log_decrypted_bytes(&decrypted);
-
-        let message = Message::from_msgpack(&decrypted)
-            .map_err(|e| SignalingError::Decode(format!("Cannot decode message payload: {}", e)))?;
-
-        Ok(Self::new(message, bbox.nonce))
+    pub(crate) fn decrypt(
+        mut bbox: ByteBox,
+        keypair: &KeyPair,
+        other_key: &PublicKey,
+        expected_types: &[&str],
+    ) -> SignalingResult<Self> {
+        let (message, plaintext) = {
+            let decrypted = bbox.decrypt(keypair, other_key)?;
+            (decode_message(decrypted, expected_types)?, decrypted.to_vec())
+        };
+        Ok(Self::new(message, bbox.nonce).with_plaintext(&plaintext))
     }
 
     /// Decrypt token message using the `auth_token` using secret key cryptography.
-    pub(crate) fn decrypt_token(bbox: ByteBox, auth_token: &AuthToken) -> SignalingResult<Self> {
-        let decrypted = auth_token.decrypt(&bbox.bytes, unsafe { bbox.nonce.clone() })
-            .map_err(|e| SignalingError::Decode(format!("Cannot decode message payload: {}", e)))?;
-
-        log_decrypted_bytes(&decrypted);
-
-        let message = Message::from_msgpack(&decrypted)
-            .map_err(|e| SignalingError::Decode(format!("Cannot decode message payload: {}", e)))?;
+    pub(crate) fn decrypt_token(mut bbox: ByteBox, auth_token: &AuthToken, expected_types: &[&str]) -> SignalingResult<Self> {
+        let (message, plaintext) = {
+            // The unsafe call to `clone()` is required because the nonce
+            // needs to be used both for decrypting, as well as being passed
+            // along with the message bytes.
+            let nonce = unsafe { bbox.nonce.clone() };
+            let decrypted = auth_token.decrypt_in_place(bbox.payload_mut(), nonce)
+                .map_err(|e| SignalingError::Crypto(format!("Cannot decrypt message payload: {}", e)))?;
+            log_decrypted_bytes(decrypted);
+            (decode_message(decrypted, expected_types)?, decrypted.to_vec())
+        };
+        Ok(Self::new(message, bbox.nonce).with_plaintext(&plaintext))
+    }
+}
 
-        Ok(Self::new(message, bbox.nonce))
+/// Decode a message, but only if it has one of the expected types.
+///
+/// The type is checked first, so that unexpected messages are rejected with a
+/// protocol error before they are decoded. Messages of a type that is not
+/// known at all are rejected with `SignalingError::UnknownMessageType`.
+fn decode_message(bytes: &[u8], expected_types: &[&str]) -> SignalingResult<Message> {
+    let msg_type = Message::peek_type(bytes)
+        .map_err(|e| SignalingError::Decode(format!("Cannot decode message type: {}", e)))?;
+    if !Message::is_known_type(&msg_type) {
+        return Err(SignalingError::UnknownMessageType(msg_type));
+    }
+    if !expected_types.contains(&msg_type.as_str()) {
+        return Err(SignalingError::Protocol(format!(
+            "Unexpected '{}' message (expected '{}')", msg_type, expected_types.join("' or '")
+        )));
     }
+    let message = Message::from_msgpack(bytes)
+        .map_err(|e| SignalingError::Decode(format!("Cannot decode message payload: {}", e)))?;
+    trace!("Decoded message: {}", message);
+    Ok(message)
 }
 
 impl OpenBox<Value> {
     pub(crate) fn new(message: Value, nonce: Nonce) -> Self {
-        OpenBox { message, nonce }
+        OpenBox { message, nonce, plaintext: None }
     }
 
     /// Encrypt message for the `other_key` using public key cryptography.
-    pub(crate) fn encrypt(self, keypair: &KeyPair, other_key: &PublicKey) -> ByteBox {
-        let encrypted = keypair.encrypt(
-            // The message bytes to be encrypted
-            &rmps::to_vec_named(&self.message).expect("Failed to serialize value"),
-            // The nonce. The unsafe call to `clone()` is required because the
-            // nonce needs to be used both for encrypting, as well as being
-            // sent along with the message bytes.
-            unsafe { self.nonce.clone() },
-            // The public key of the recipient
-            other_key
-        );
-        ByteBox::new(encrypted, self.nonce)
+    ///
+    /// Task messages may be large, the serialized bytes are only kept in
+    /// the byte box if `keep_plaintext` is set.
+    pub(crate) fn encrypt(self, keypair: &KeyPair, other_key: &PublicKey, keep_plaintext: bool) -> ByteBox {
+        ByteBox::serialize(&self.message, self.nonce, MACBYTES, keep_plaintext)
+            .with_message_type(value_type(&self.message))
+            .encrypt(keypair, other_key)
     }
 
     /// Decrypt a task message into a dynamically typed msgpack `Value`.
     ///
-    /// This should be used after the handshake has finished.
-    pub(crate) fn decrypt(bbox: ByteBox, keypair: &KeyPair, other_key: &PublicKey) -> SignalingResult<OpenBox<Value>> {
-        let decrypted: Vec<u8> = keypair.decrypt(
-            // The message bytes to be decrypted
-            &bbox.bytes,
-            // The nonce. The unsafe call to `clone()` is required because the
-            // nonce needs to be used both for decrypting, as well as being
-            // passed along with the message bytes.
-            unsafe { bbox.nonce.clone() },
-            // The public key of the recipient
-            other_key
-        ).map_err(|e| SignalingError::Decode(format!("Cannot decrypt message payload: {}", e)))?;
-
-        log_decrypted_bytes(&decrypted);
-
-        let message: Value = rmps::from_slice(&decrypted)
-            .map_err(|e| SignalingError::Decode(format!("Cannot decode message payload: {}", e)))?;
-
-        Ok(Self::new(message, bbox.nonce))
+    /// This should be used after the handshake has finished. The decrypted
+    /// bytes are only kept if `keep_plaintext` is set.
+    pub(crate) fn decrypt(
+        mut bbox: ByteBox,
+        keypair: &KeyPair,
+        other_key: &PublicKey,
+        keep_plaintext: bool,
+    ) -> SignalingResult<OpenBox<Value>> {
+        let (message, plaintext) = {
+            let decrypted = bbox.decrypt(keypair, other_key)?;
+            let message: Value = rmps::from_slice(decrypted)
+                .map_err(|e| SignalingError::Decode(format!("Cannot decode message payload: {}", e)))?;
+            (message, if keep_plaintext { Some(decrypted.to_vec()) } else { None })
+        };
+        Ok(OpenBox { message, nonce: bbox.nonce, plaintext })
     }
 
 }
 
 
 /// A byte box (message bytes + nonce). The bytes may or may not be encrypted.
-#[derive(Debug, PartialEq)]
-pub(crate) struct ByteBox {
-    pub(crate) bytes: Vec<u8>,
+///
+/// The nonce is stored both parsed and as the first
+/// [`NONCEBYTES`](crypto/constant.NONCEBYTES.html) bytes of the frame,
+/// followed by the payload.
+///
+/// Byte boxes give access to the bytes of a frame without copying them,
+/// e.g. for large payloads that are passed on to a task or another
+/// transport. Frames that have been parsed with
+/// [`from_vec`](#method.from_vec) reuse the received buffer.
+pub struct ByteBox {
+    frame: Vec<u8>,
     pub(crate) nonce: Nonce,
+    /// The serialized message before it was encrypted, kept for the
+    /// transcript of outgoing messages.
+    plaintext: Option<Vec<u8>>,
+    /// The type of an outgoing message, if known.
+    message_type: Option<String>,
+}
+
+impl fmt::Debug for ByteBox {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // The plaintext is left out, so that it does not end up in logs.
+        f.debug_struct("ByteBox")
+            .field("frame", &self.frame)
+            .field("nonce", &self.nonce)
+            .finish()
+    }
+}
+
+impl PartialEq for ByteBox {
+    fn eq(&self, other: &Self) -> bool {
+        // The nonce is part of the frame.
+        self.frame == other.frame
+    }
 }
 
 impl ByteBox {
+    /// Create a byte box from the payload bytes and the nonce.
+    ///
+    /// This copies the payload. Prefer [`from_vec`](#method.from_vec) for
+    /// received messages.
+    #[allow(dead_code)]
     pub(crate) fn new(bytes: Vec<u8>, nonce: Nonce) -> Self {
-        ByteBox { bytes, nonce }
+        let mut frame = Self::frame_with_nonce(&nonce, bytes.len());
+        frame.extend_from_slice(&bytes);
+        ByteBox { frame, nonce, plaintext: None, message_type: None }
     }
 
-    pub(crate) fn from_slice(bytes: &[u8]) -> SignalingResult<Self> {
-        if bytes.len() <= NONCEBYTES {
-            return Err(SignalingError::Decode("Message is too short".into()));
+    /// Serialize a value directly into the payload of a new byte box.
+    ///
+    /// `reserved` zero bytes are inserted in front of the value, e.g. to make
+    /// room for the authentication tag when encrypting in place. A copy of
+    /// the serialized value is kept if `keep_plaintext` is set.
+    fn serialize<T: Serialize>(value: &T, nonce: Nonce, reserved: usize, keep_plaintext: bool) -> Self {
+        let mut frame = Self::frame_with_nonce(&nonce, reserved);
+        frame.resize(NONCEBYTES + reserved, 0);
+        rmps::encode::write_named(&mut frame, value).expect("Serialization failed");
+        let plaintext = if keep_plaintext { Some(frame[NONCEBYTES + reserved..].to_vec()) } else { None };
+        ByteBox { frame, nonce, plaintext, message_type: None }
+    }
+
+    /// Remember the type of an outgoing message.
+    fn with_message_type(mut self, message_type: Option<&str>) -> Self {
+        self.message_type = message_type.map(ToString::to_string);
+        self
+    }
+
+    /// Encrypt raw bytes for the `other_key` into a new byte box.
+    pub(crate) fn seal(data: &[u8], nonce: Nonce, keypair: &KeyPair, other_key: &PublicKey) -> Self {
+        let mut frame = Self::frame_with_nonce(&nonce, MACBYTES + data.len());
+        frame.resize(NONCEBYTES + MACBYTES, 0);
+        frame.extend_from_slice(data);
+        ByteBox { frame, nonce, plaintext: None, message_type: None }.encrypt(keypair, other_key)
+    }
+
+    /// Decrypt the payload and return the decrypted bytes.
+    ///
+    /// The payload is decrypted in place and the frame is reused for the
+    /// returned bytes.
+    pub(crate) fn open(mut self, keypair: &KeyPair, other_key: &PublicKey) -> SignalingResult<Vec<u8>> {
+        let len = self.decrypt(keypair, other_key)?.len();
+        let mut frame = self.frame;
+        let start = frame.len() - len;
+        frame.drain(..start);
+        Ok(frame)
+    }
+
+    /// Allocate a frame starting with the nonce bytes, with enough capacity
+    /// for `payload_len` more bytes.
+    fn frame_with_nonce(nonce: &Nonce, payload_len: usize) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(NONCEBYTES + payload_len);
+        // The unsafe call to `clone()` is fine, the nonce is only encoded.
+        frame.extend_from_slice(&unsafe { nonce.clone() }.into_bytes());
+        frame
+    }
+
+    /// Parse a byte box from a slice, copying the bytes.
+    pub fn from_slice(bytes: &[u8]) -> SaltyResult<Self> {
+        Self::from_vec(bytes.to_vec())
+    }
+
+    /// Parse a byte box from an owned buffer.
+    ///
+    /// In contrast to [`from_slice`](#method.from_slice), the bytes are not
+    /// copied, the buffer is used as frame.
+    ///
+    /// The frame must consist of a nonce and a non-empty payload, and must
+    /// not be longer than 16 MiB.
+    pub fn from_vec(frame: Vec<u8>) -> SaltyResult<Self> {
+        Ok(Self::parse(frame)?)
+    }
+
+    /// Parse a byte box from an owned buffer, see
+    /// [`from_vec`](#method.from_vec).
+    pub(crate) fn parse(frame: Vec<u8>) -> SignalingResult<Self> {
+        Self::check_frame_len(frame.len())?;
+        let nonce = Nonce::from_bytes(&frame[..NONCEBYTES])?;
+        Ok(ByteBox { frame, nonce, plaintext: None, message_type: None })
+    }
+
+    /// Check the length of a received frame.
+    fn check_frame_len(len: usize) -> Result<(), FrameError> {
+        if len < NONCEBYTES {
+            Err(FrameError::TooShort(NONCEBYTES, len))
+        } else if len == NONCEBYTES {
+            Err(FrameError::NonceOnly)
+        } else if len > MAX_FRAME_LEN {
+            Err(FrameError::Oversized(len, MAX_FRAME_LEN))
+        } else {
+            Ok(())
         }
-        let nonce = Nonce::from_bytes(&bytes[..24])
-            .map_err(|e| SignalingError::Decode(format!("Cannot decode nonce: {}", e)))?;
-        let bytes = bytes[24..].to_vec();
-        Ok(Self::new(bytes, nonce))
     }
 
-    pub(crate) fn into_bytes(self) -> Vec<u8> {
-        let mut bytes = Vec::with_capacity(NONCEBYTES + self.bytes.len());
-        bytes.extend(self.nonce.into_bytes().iter());
-        bytes.extend(self.bytes.iter());
-        bytes
+    /// Encrypt the payload in place for the `other_key`.
+    ///
+    /// The payload must start with `MACBYTES` bytes of space for the
+    /// authentication tag.
+    fn encrypt(mut self, keypair: &KeyPair, other_key: &PublicKey) -> Self {
+        // The unsafe call to `clone()` is required because the nonce needs to
+        // be used both for encrypting, as well as being sent along with the
+        // message bytes.
+        let nonce = unsafe { self.nonce.clone() };
+        keypair.encrypt_in_place(self.payload_mut(), nonce, other_key);
+        self
+    }
+
+    /// Decrypt the payload in place, return the decrypted bytes.
+    fn decrypt(&mut self, keypair: &KeyPair, other_key: &PublicKey) -> SignalingResult<&[u8]> {
+        // The unsafe call to `clone()` is required because the nonce needs to
+        // be used both for decrypting, as well as being passed along with the
+        // message bytes.
+        let nonce = unsafe { self.nonce.clone() };
+        let decrypted = keypair.decrypt_in_place(self.payload_mut(), nonce, other_key)
+            .map_err(|e| SignalingError::Crypto(format!("Cannot decrypt message payload: {}", e)))?;
+        log_decrypted_bytes(decrypted);
+        Ok(decrypted)
+    }
+
+    /// Return a reference to the payload bytes (without the nonce).
+    pub fn payload(&self) -> &[u8] {
+        &self.frame[NONCEBYTES..]
+    }
+
+    /// Return a mutable reference to the payload bytes (without the nonce).
+    fn payload_mut(&mut self) -> &mut [u8] {
+        &mut self.frame[NONCEBYTES..]
+    }
+
+    /// Return a reference to the nonce bytes.
+    pub fn nonce_bytes(&self) -> &[u8] {
+        &self.frame[..NONCEBYTES]
+    }
+
+    /// Return the length of the nonce and payload bytes.
+    pub fn frame_len(&self) -> usize {
+        self.frame.len()
+    }
+
+    /// Return the serialized message before it was encrypted, if it was kept.
+    pub(crate) fn plaintext(&self) -> Option<&Vec<u8>> {
+        self.plaintext.as_ref()
+    }
+
+    /// Return the type of the message, if this is an outgoing message of a
+    /// known type.
+    pub(crate) fn message_type(&self) -> Option<&String> {
+        self.message_type.as_ref()
+    }
+
+    /// Return a copy of the nonce and payload bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.frame.clone()
+    }
+
+    /// Return the nonce and payload bytes, without copying.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.frame
+    }
+
+    /// Split the byte box into the nonce and the payload bytes.
+    ///
+    /// The payload reuses the buffer of the frame, only the nonce is copied.
+    pub fn into_parts(self) -> ([u8; NONCEBYTES], Vec<u8>) {
+        let mut nonce = [0; NONCEBYTES];
+        nonce.copy_from_slice(self.nonce_bytes());
+        let mut payload = self.frame;
+        payload.drain(..NONCEBYTES);
+        (nonce, payload)
+    }
+}
+
+
+/// Reassembles byte boxes from messages that are delivered in several
+/// chunks, e.g. by a custom transport or through FFI.
+///
+/// Chunks are buffered until the last chunk of a message arrives. Frames
+/// exceeding [`MAX_FRAME_LEN`](constant.MAX_FRAME_LEN.html) are rejected as
+/// soon as the limit is crossed, and the rest of their chunks is skipped.
+#[derive(Debug, Default)]
+pub(crate) struct ByteBoxDecoder {
+    buffer: Vec<u8>,
+    /// Whether the chunks of an oversized message are skipped.
+    discarding: bool,
+}
+
+impl ByteBoxDecoder {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a chunk of a message.
+    ///
+    /// If `last` is set, the chunk completes the message and the byte box is
+    /// returned. On error, the buffered chunks are discarded. The remaining
+    /// chunks of an oversized message are skipped, so that the chunk after
+    /// its last one starts a new message.
+    pub(crate) fn decode(&mut self, chunk: &[u8], last: bool) -> SignalingResult<Option<ByteBox>> {
+        if self.discarding {
+            self.discarding = !last;
+            return Ok(None);
+        }
+        let len = self.buffer.len() + chunk.len();
+        if len > MAX_FRAME_LEN {
+            self.buffer = Vec::new();
+            self.discarding = !last;
+            return Err(FrameError::Oversized(len, MAX_FRAME_LEN).into());
+        }
+        self.buffer.extend_from_slice(chunk);
+        if !last {
+            return Ok(None);
+        }
+        let frame = self.buffer.split_off(0);
+        ByteBox::parse(frame).map(Some)
+    }
+
+    /// Return the number of bytes of an incomplete message.
+    #[cfg(test)]
+    pub(crate) fn buffered(&self) -> usize {
+        self.buffer.len()
     }
 }
 
@@ -203,6 +458,7 @@ fn log_decrypted_bytes(decrypted: &[u8]) {
 
 #[cfg(test)]
 mod tests {
+    use crate::errors::SaltyError;
     use crate::protocol::cookie::Cookie;
     use crate::protocol::csn::CombinedSequenceSnapshot;
     use crate::protocol::types::Address;
@@ -251,7 +507,48 @@ mod tests {
         let bbox = ByteBox::from_slice(&bytes).unwrap();
         assert_eq!(bbox.nonce.csn().overflow_number(), (3 << 8) + 4);
         assert_eq!(bbox.nonce.csn().sequence_number(), (5 << 24) + (6 << 16) + (7 << 8) + 8);
-        assert_eq!(bbox.bytes, vec![9, 10]);
+        assert_eq!(bbox.payload(), &[9, 10]);
+    }
+
+    #[test]
+    fn byte_box_from_vec() {
+        let bytes = vec![
+            1, 2, 3, 4, 5, 6, 7, 8,
+            8, 7, 6, 5, 4, 3, 2, 1,
+            1, 2, 3, 4, 5, 6, 7, 8,
+            9, 10,
+        ];
+        let bbox = ByteBox::from_vec(bytes.clone()).unwrap();
+        assert_eq!(bbox, ByteBox::from_slice(&bytes).unwrap());
+        assert_eq!(bbox.payload(), &[9, 10]);
+        assert_eq!(bbox.nonce.cookie().as_bytes(), &[1, 2, 3, 4, 5, 6, 7, 8, 8, 7, 6, 5, 4, 3, 2, 1]);
+        assert_eq!(bbox.into_bytes(), bytes);
+
+        assert!(ByteBox::from_vec(bytes[..24].to_vec()).is_err());
+    }
+
+    #[test]
+    fn byte_box_into_parts() {
+        let bytes = vec![
+            1, 2, 3, 4, 5, 6, 7, 8,
+            8, 7, 6, 5, 4, 3, 2, 1,
+            1, 2, 3, 4, 5, 6, 7, 8,
+            9, 10,
+        ];
+        let bbox = ByteBox::from_vec(bytes.clone()).unwrap();
+        assert_eq!(bbox.nonce_bytes(), &bytes[..24]);
+        let (nonce, payload) = bbox.into_parts();
+        assert_eq!(&nonce[..], &bytes[..24]);
+        assert_eq!(payload, vec![9, 10]);
+    }
+
+    #[test]
+    fn byte_box_seal_open() {
+        let ks_ours = KeyPair::new();
+        let ks_theirs = KeyPair::new();
+        let bbox = ByteBox::seal(&[1, 2, 3], create_test_nonce(), &ks_ours, ks_theirs.public_key());
+        assert_eq!(bbox.frame_len(), NONCEBYTES + MACBYTES + 3);
+        assert_eq!(bbox.open(&ks_theirs, ks_ours.public_key()), Ok(vec![1, 2, 3]));
     }
 
     #[test]
@@ -263,18 +560,84 @@ mod tests {
 
         let err1 = ByteBox::from_slice(&bytes_only_nonce).unwrap_err();
         let err2 = ByteBox::from_slice(&bytes_not_even_nonce).unwrap_err();
-        assert_eq!(format!("{}", err1), "Decoding error: Message is too short");
-        assert_eq!(format!("{}", err2), "Decoding error: Message is too short");
+        assert_eq!(err1, SaltyError::Frame(FrameError::NonceOnly));
+        assert_eq!(format!("{}", err1), "Invalid frame: Frame contains a nonce, but no payload");
+        assert_eq!(err2, SaltyError::Frame(FrameError::TooShort(NONCEBYTES, 8)));
+        assert_eq!(
+            format!("{}", err2),
+            "Invalid frame: Frame must start with a 24 byte nonce, but has only 8 bytes"
+        );
+    }
+
+    #[test]
+    fn byte_box_frame_len() {
+        assert_eq!(ByteBox::check_frame_len(0), Err(FrameError::TooShort(NONCEBYTES, 0)));
+        assert_eq!(ByteBox::check_frame_len(NONCEBYTES), Err(FrameError::NonceOnly));
+        assert_eq!(ByteBox::check_frame_len(NONCEBYTES + 1), Ok(()));
+        assert_eq!(ByteBox::check_frame_len(MAX_FRAME_LEN), Ok(()));
+        assert_eq!(
+            ByteBox::check_frame_len(MAX_FRAME_LEN + 1),
+            Err(FrameError::Oversized(MAX_FRAME_LEN + 1, MAX_FRAME_LEN))
+        );
+        assert!(ByteBox::from_vec(vec![1; MAX_FRAME_LEN + 1]).is_err());
+    }
+
+    #[test]
+    fn byte_box_from_slice_invalid_nonce() {
+        // Empty frame
+        assert!(ByteBox::from_slice(&[]).is_err());
+
+        // Zero cookie
+        let mut bytes = [0; 26];
+        bytes[24] = 1;
+        let err = ByteBox::parse(bytes.to_vec()).unwrap_err();
+        assert_eq!(err, SignalingError::InvalidNonce("Cookie must not be all zeros".into()));
+    }
+
+    #[test]
+    fn byte_box_decrypt_short_payload() {
+        let keypair_tx = KeyPair::new();
+        let keypair_rx = KeyPair::new();
+
+        // Payloads shorter than the authentication tag must not panic
+        for len in &[1, MACBYTES - 1, MACBYTES] {
+            let bbox = ByteBox::new(vec![0; *len], create_test_nonce());
+            let result = OpenBox::<Message>::decrypt(bbox, &keypair_rx, keypair_tx.public_key(), &["server-hello"]);
+            assert_crypto_error(result.map(|_| ()));
+            let bbox = ByteBox::new(vec![0; *len], create_test_nonce());
+            let result = OpenBox::<Value>::decrypt(bbox, &keypair_rx, keypair_tx.public_key(), false);
+            assert_crypto_error(result.map(|_| ()));
+            let bbox = ByteBox::new(vec![0; *len], create_test_nonce());
+            let result = OpenBox::<Message>::decrypt_token(bbox, &AuthToken::new(), &["token"]);
+            assert_crypto_error(result.map(|_| ()));
+        }
+    }
+
+    fn assert_crypto_error(result: SignalingResult<()>) {
+        match result {
+            Err(SignalingError::Crypto(_)) => {},
+            other => panic!("Expected crypto error, got {:?}", other),
+        }
     }
 
     #[test]
     fn byte_box_decode_message() {
         let nonce = create_test_nonce();
         let bbox = ByteBox::new(create_test_msg_bytes(), nonce);
-        let obox = OpenBox::<Message>::decode(bbox).unwrap();
+        let obox = OpenBox::<Message>::decode(bbox, &["server-hello"]).unwrap();
         assert_eq!(obox.message.get_type(), "server-hello");
     }
 
+    #[test]
+    fn byte_box_decode_unexpected_message() {
+        let nonce = create_test_nonce();
+        let bbox = ByteBox::new(create_test_msg_bytes(), nonce);
+        let err = OpenBox::<Message>::decode(bbox, &["new-initiator", "send-error"]).unwrap_err();
+        assert_eq!(err, SignalingError::Protocol(
+            "Unexpected 'server-hello' message (expected 'new-initiator' or 'send-error')".into()
+        ));
+    }
+
     #[test]
     fn byte_box_decrypt_message() {
         let nonce = create_test_nonce();
@@ -283,8 +646,9 @@ mod tests {
         let keypair_rx = KeyPair::new();
         let encrypted = keypair_tx.encrypt(&bytes, unsafe { nonce.clone() }, keypair_rx.public_key());
         let bbox = ByteBox::new(encrypted, nonce);
-        let obox = OpenBox::<Message>::decrypt(bbox, &keypair_rx, keypair_tx.public_key()).unwrap();
+        let obox = OpenBox::<Message>::decrypt(bbox, &keypair_rx, keypair_tx.public_key(), &["server-hello"]).unwrap();
         assert_eq!(obox.message.get_type(), "server-hello");
+        assert_eq!(obox.plaintext, Some(bytes));
     }
 
     #[test]
@@ -303,7 +667,7 @@ mod tests {
         let bbox = ByteBox::new(encrypted, nonce);
 
         // Decrypt byte box
-        let obox = OpenBox::decrypt_token(bbox, &auth_token).unwrap();
+        let obox = OpenBox::decrypt_token(bbox, &auth_token, &["server-hello"]).unwrap();
         assert_eq!(obox.message.get_type(), "server-hello");
     }
 
@@ -321,12 +685,13 @@ mod tests {
 
         // First, make sure that decrypting this as message fails.
         let bbox = ByteBox::new(encrypted.clone(), unsafe { nonce.clone() });
-        let decrypt_as_message = OpenBox::<Message>::decrypt(bbox, &keypair_rx, keypair_tx.public_key());
+        let decrypt_as_message = OpenBox::<Message>::decrypt(bbox, &keypair_rx, keypair_tx.public_key(), &["taskmsg"]);
         assert!(decrypt_as_message.is_err());
 
         // Then decrypt as value.
         let bbox = ByteBox::new(encrypted, nonce);
-        let obox = OpenBox::<Value>::decrypt(bbox, &keypair_rx, keypair_tx.public_key()).unwrap();
+        let obox = OpenBox::<Value>::decrypt(bbox, &keypair_rx, keypair_tx.public_key(), true).unwrap();
+        assert_eq!(obox.plaintext.as_ref(), Some(&bytes));
         match obox.message {
             Value::Map(values) => {
                 assert_eq!(values.len(), 2);
@@ -338,4 +703,55 @@ mod tests {
             other => panic!("Expected map, got {:?}", other),
         }
     }
+
+    #[test]
+    fn byte_box_decoder_chunks() {
+        let bytes = create_test_msg_bytes();
+        let mut frame = unsafe { create_test_nonce().clone() }.into_bytes().to_vec();
+        frame.extend_from_slice(&bytes);
+
+        let mut decoder = ByteBoxDecoder::new();
+        assert_eq!(decoder.decode(&frame[..10], false), Ok(None));
+        assert_eq!(decoder.decode(&frame[10..30], false), Ok(None));
+        assert_eq!(decoder.buffered(), 30);
+        let bbox = decoder.decode(&frame[30..], true).unwrap().unwrap();
+        assert_eq!(bbox, ByteBox::from_slice(&frame).unwrap());
+        assert_eq!(decoder.buffered(), 0);
+
+        // A whole message in a single chunk
+        assert_eq!(decoder.decode(&frame, true).unwrap(), Some(ByteBox::from_slice(&frame).unwrap()));
+    }
+
+    #[test]
+    fn byte_box_decoder_errors() {
+        let mut decoder = ByteBoxDecoder::new();
+
+        // Too short, the buffer is reset afterwards
+        assert_eq!(decoder.decode(&[1; 4], false), Ok(None));
+        assert!(decoder.decode(&[1; 4], true).is_err());
+        assert_eq!(decoder.buffered(), 0);
+
+        // Oversized, rejected before the last chunk
+        assert_eq!(decoder.decode(&vec![1; MAX_FRAME_LEN], false), Ok(None));
+        assert_eq!(
+            decoder.decode(&[1], false),
+            Err(FrameError::Oversized(MAX_FRAME_LEN + 1, MAX_FRAME_LEN).into())
+        );
+        assert_eq!(decoder.buffered(), 0);
+
+        // The rest of the oversized message is skipped
+        assert_eq!(decoder.decode(&[1; 100], false), Ok(None));
+        assert_eq!(decoder.decode(&[1; 100], true), Ok(None));
+        assert_eq!(decoder.buffered(), 0);
+
+        // The next message is decoded again
+        let bytes = create_test_msg_bytes();
+        let mut frame = unsafe { create_test_nonce().clone() }.into_bytes().to_vec();
+        frame.extend_from_slice(&bytes);
+        assert_eq!(decoder.decode(&frame, true).unwrap(), Some(ByteBox::from_slice(&frame).unwrap()));
+
+        // An oversized last chunk ends the message
+        assert!(decoder.decode(&vec![1; MAX_FRAME_LEN + 1], true).is_err());
+        assert_eq!(decoder.decode(&frame, true).unwrap(), Some(ByteBox::from_slice(&frame).unwrap()));
+    }
 }
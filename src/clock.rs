@@ -0,0 +1,249 @@
+//! Time sources for timeouts and heartbeats.
+//!
+//! The signaling never reads the system time directly. The responder
+//! timeouts, the rate limits and the round trip times of heartbeats are all
+//! based on the [`Clock`](trait.Clock.html) passed to
+//! [`SaltyClientBuilder::with_clock`](../struct.SaltyClientBuilder.html#method.with_clock).
+//!
+//! By default, the [`SystemClock`](struct.SystemClock.html) is used. Tests
+//! can use a [`ManualClock`](struct.ManualClock.html) to advance the time
+//! instantly instead of sleeping. Note that the connection code waits for
+//! the next timeout with a [`Sleeper`](trait.Sleeper.html), which sleeps in
+//! real time, so a manual clock is mostly useful with the sans-IO API.
+//!
+//! The sleeper is passed to
+//! [`SaltyClientBuilder::with_sleeper`](../struct.SaltyClientBuilder.html#method.with_sleeper).
+//! By default, the [`TokioSleeper`](struct.TokioSleeper.html) is used. The
+//! [`ThreadSleeper`](struct.ThreadSleeper.html) does not depend on Tokio, so
+//! that the handshake and the task loop can be run on other futures 0.1
+//! executors.
+
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::Future;
+use futures::sync::oneshot;
+
+
+/// A source of the current time.
+pub trait Clock: Send + Sync + fmt::Debug {
+    /// Return the current point in time.
+    ///
+    /// The returned instants must never go backwards.
+    fn now(&self) -> Instant;
+}
+
+
+/// The default clock, returning `Instant::now()`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+
+/// A clock that only moves when it's told to.
+#[derive(Debug)]
+pub struct ManualClock {
+    now: Mutex<Instant>,
+}
+
+impl ManualClock {
+    /// Create a clock starting at the current system time.
+    pub fn new() -> Self {
+        Self::starting_at(Instant::now())
+    }
+
+    /// Create a clock starting at the specified point in time.
+    pub fn starting_at(start: Instant) -> Self {
+        ManualClock { now: Mutex::new(start) }
+    }
+
+    /// Move the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        let mut now = self.now.lock().expect("Could not lock clock mutex");
+        *now += duration;
+    }
+
+    /// Move the clock forward to the specified point in time.
+    ///
+    /// Points in time before the current time are ignored.
+    pub fn advance_to(&self, instant: Instant) {
+        let mut now = self.now.lock().expect("Could not lock clock mutex");
+        if instant > *now {
+            *now = instant;
+        }
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().expect("Could not lock clock mutex")
+    }
+}
+
+
+/// A future that completes once a [`Sleeper`](trait.Sleeper.html) is done
+/// sleeping.
+pub type Sleep = Box<dyn Future<Item=(), Error=String> + Send>;
+
+/// A source of sleeps, used by the connection to wait for the next timeout.
+pub trait Sleeper: Send + Sync + fmt::Debug {
+    /// Return a future that completes after `duration`.
+    ///
+    /// The future may also complete early: The connection checks the
+    /// deadlines of the client again whenever it wakes up.
+    fn sleep(&self, duration: Duration) -> Sleep;
+}
+
+
+/// The default sleeper, based on the `tokio-timer` wheel.
+#[cfg(feature = "connect-tokio")]
+#[derive(Debug, Default, Clone)]
+pub struct TokioSleeper {
+    timer: tokio_timer::Timer,
+}
+
+#[cfg(feature = "connect-tokio")]
+impl Sleeper for TokioSleeper {
+    fn sleep(&self, duration: Duration) -> Sleep {
+        Box::new(self.timer.sleep(duration).map_err(|e| e.to_string()))
+    }
+}
+
+
+/// A sleeper that does not depend on a runtime.
+///
+/// All sleeps are handled by a single thread, which is started with the
+/// first sleep and stopped once the sleeper is dropped.
+#[derive(Debug, Default)]
+pub struct ThreadSleeper {
+    shared: Arc<SleeperShared>,
+    thread: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+#[derive(Debug, Default)]
+struct SleeperShared {
+    state: Mutex<SleeperState>,
+    changed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct SleeperState {
+    /// The pending sleeps and the point in time at which they end.
+    sleeps: Vec<(Instant, oneshot::Sender<()>)>,
+    stopped: bool,
+}
+
+impl ThreadSleeper {
+    /// Create a sleeper. The thread is only started with the first sleep.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wake up the pending sleeps that are over, until the sleeper is
+    /// dropped.
+    fn run(shared: &SleeperShared) {
+        let mut state = shared.state.lock().expect("Could not lock sleeper mutex");
+        while !state.stopped {
+            let now = Instant::now();
+            let (over, pending): (Vec<_>, Vec<_>) = state.sleeps
+                .drain(..)
+                .partition(|&(end, _)| end <= now);
+            state.sleeps = pending;
+            for (_, tx) in over {
+                // The sleep may have been dropped already
+                let _ = tx.send(());
+            }
+            state = match state.sleeps.iter().map(|&(end, _)| end).min() {
+                Some(end) => shared.changed.wait_timeout(state, end - now)
+                    .expect("Could not lock sleeper mutex").0,
+                None => shared.changed.wait(state).expect("Could not lock sleeper mutex"),
+            };
+        }
+    }
+}
+
+impl Sleeper for ThreadSleeper {
+    fn sleep(&self, duration: Duration) -> Sleep {
+        let mut thread = self.thread.lock().expect("Could not lock sleeper mutex");
+        if thread.is_none() {
+            let shared = Arc::clone(&self.shared);
+            let spawned = thread::Builder::new()
+                .name("saltyrtc-sleeper".into())
+                .spawn(move || Self::run(&shared));
+            match spawned {
+                Ok(handle) => *thread = Some(handle),
+                Err(e) => return Box::new(futures::future::err(format!("Could not start sleeper thread: {}", e))),
+            }
+        }
+        let (tx, rx) = oneshot::channel();
+        {
+            let mut state = self.shared.state.lock().expect("Could not lock sleeper mutex");
+            state.sleeps.retain(|(_, tx)| !tx.is_canceled());
+            state.sleeps.push((Instant::now() + duration, tx));
+        }
+        self.shared.changed.notify_one();
+        Box::new(rx.map_err(|_| "Sleeper thread stopped".to_string()))
+    }
+}
+
+impl Drop for ThreadSleeper {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.shared.state.lock() {
+            state.stopped = true;
+        }
+        self.shared.changed.notify_one();
+        if let Some(handle) = self.thread.get_mut().ok().and_then(Option::take) {
+            let _ = handle.join();
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manual_clock_only_moves_forward() {
+        let start = Instant::now();
+        let clock = ManualClock::starting_at(start);
+        assert_eq!(clock.now(), start);
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(clock.now(), start + Duration::from_secs(5));
+
+        clock.advance_to(start + Duration::from_secs(3));
+        assert_eq!(clock.now(), start + Duration::from_secs(5));
+        clock.advance_to(start + Duration::from_secs(8));
+        assert_eq!(clock.now(), start + Duration::from_secs(8));
+    }
+
+    #[test]
+    fn thread_sleeper() {
+        let sleeper = ThreadSleeper::new();
+        let start = Instant::now();
+        let long = sleeper.sleep(Duration::from_secs(60));
+        let short = sleeper.sleep(Duration::from_millis(20));
+        assert_eq!(short.wait(), Ok(()));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        // Dropping the sleeper stops the thread, without waiting for the
+        // pending sleeps
+        drop(sleeper);
+        assert!(long.wait().is_err());
+        assert!(start.elapsed() < Duration::from_secs(60));
+    }
+}
@@ -71,6 +71,27 @@ impl CloseCode {
             code => Other(code),
         }
     }
+
+    /// Return whether the connection was closed because of a problem.
+    ///
+    /// Normal closures, peers going away and the handover of the signalling
+    /// channel are not considered errors.
+    pub fn is_error(self) -> bool {
+        use self::CloseCode::*;
+        self != WsClosingNormal && self != WsGoingAway && self != Handover
+    }
+}
+
+impl From<u16> for CloseCode {
+    fn from(code: u16) -> Self {
+        CloseCode::from_number(code)
+    }
+}
+
+impl From<CloseCode> for u16 {
+    fn from(code: CloseCode) -> Self {
+        code.as_number()
+    }
 }
 
 impl fmt::Display for CloseCode {
@@ -78,3 +99,84 @@ impl fmt::Display for CloseCode {
         write!(f, "{:?} ({})", self, self.as_number())
     }
 }
+
+
+/// The reason why a connection ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseReason {
+    /// The close code that was sent or received, or `None` if the connection
+    /// ended without a close code (e.g. because of a network error).
+    pub code: Option<CloseCode>,
+    /// Whether the connection was closed by us, as opposed to the server or
+    /// the peer.
+    pub by_us: bool,
+    /// A human readable description of the reason.
+    pub description: String,
+}
+
+impl CloseReason {
+    /// Create a reason for a connection that we closed.
+    pub(crate) fn local<S: Into<String>>(code: Option<CloseCode>, description: S) -> Self {
+        CloseReason { code, by_us: true, description: description.into() }
+    }
+
+    /// Create a reason for a connection that the server or the peer closed.
+    pub(crate) fn remote<S: Into<String>>(code: Option<CloseCode>, description: S) -> Self {
+        CloseReason { code, by_us: false, description: description.into() }
+    }
+
+    /// Return whether the connection was closed because of a problem.
+    ///
+    /// Connections that ended without a close code are considered errors.
+    pub fn is_error(&self) -> bool {
+        match self.code {
+            Some(code) => code.is_error(),
+            None => true,
+        }
+    }
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let by = if self.by_us { "us" } else { "remote" };
+        match self.code {
+            Some(code) => write!(f, "{} (close code {}, closed by {})", self.description, code, by),
+            None => write!(f, "{} (closed by {})", self.description, by),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn number_roundtrip() {
+        for code in &[1000, 1001, 1002, 3000, 3001, 3002, 3003, 3004, 3005, 3006, 3007, 3008, 4000] {
+            let close_code = CloseCode::from(*code);
+            assert_eq!(u16::from(close_code), *code);
+        }
+        assert_eq!(CloseCode::from(4000), CloseCode::Other(4000));
+    }
+
+    #[test]
+    fn is_error() {
+        assert!(!CloseCode::WsClosingNormal.is_error());
+        assert!(!CloseCode::Handover.is_error());
+        assert!(CloseCode::ProtocolError.is_error());
+        assert!(CloseCode::Other(4000).is_error());
+    }
+
+    #[test]
+    fn close_reason_display() {
+        let reason = CloseReason::local(Some(CloseCode::ProtocolError), "Invalid nonce");
+        assert_eq!(reason.to_string(), "Invalid nonce (close code ProtocolError (3001), closed by us)");
+        assert!(reason.is_error());
+
+        let reason = CloseReason::remote(None, "Connection ended");
+        assert_eq!(reason.to_string(), "Connection ended (closed by remote)");
+        assert!(reason.is_error());
+        assert!(!CloseReason::remote(Some(CloseCode::WsGoingAway), "Closed by the peer").is_error());
+    }
+}
@@ -0,0 +1,2388 @@
+//! Connection to the server and the async runtime glue.
+//!
+//! This module drives a [`SaltyClient`](../struct.SaltyClient.html) over a
+//! WebSocket connection using Tokio. It is only available with the
+//! `connect-tokio` feature. Without it, the protocol core can be driven by
+//! an external transport through the
+//! [`LowLevelClient`](../struct.LowLevelClient.html).
+//!
+//! Only `connect` and its variants need the Tokio reactor, to open the TCP
+//! connection. The handshake and the task loop accept a WebSocket client
+//! over any stream implementing `AsyncRead` and `AsyncWrite`, e.g. one
+//! created with `connect_on`. They still use `tokio-timer` for the
+//! timeouts, so they are not independent of Tokio.
+
+use std::collections::VecDeque;
+use std::error::Error;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use data_encoding::HEXLOWER;
+use futures::{stream, try_ready, Async, AsyncSink, Future, Poll, Stream, Sink};
+use futures::executor::{self, NotifyHandle};
+use futures::future::{self, Either, Loop, Shared};
+use futures::stream::StreamFuture;
+use futures::sync::mpsc;
+use futures::sync::oneshot;
+use native_tls::TlsConnector;
+use rmpv::Value;
+use tokio_core::reactor::Handle;
+use tokio_core::net::TcpStream;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_threadpool::{Builder as ThreadPoolBuilder, ThreadPool};
+use websocket::WebSocketError;
+use websocket::client::ClientBuilder;
+use websocket::client::r#async::{Client, TlsStream};
+use websocket::client::builder::Url;
+use websocket::ws::dataframe::DataFrame;
+use websocket::header::WebSocketProtocol;
+#[cfg(feature = "permessage-deflate")]
+use websocket::header::WebSocketExtensions;
+use websocket::message::{OwnedMessage, CloseData};
+
+use crate::{BoxedFuture, Event, SaltyClient, SharedResources, UnboundedChannel};
+use crate::boxes::ByteBox;
+use crate::close_code::{CloseCode, CloseReason};
+use crate::connection_state::ConnectionState;
+use crate::errors::{ConnectError, SaltyResult, SaltyError, SignalingError, SignalingResult};
+#[cfg(feature = "permessage-deflate")]
+use crate::deflate;
+use crate::fragment;
+use crate::helpers::{crypto_init, resolve};
+use crate::protocol::{HandleAction, HandleActions};
+use crate::protocol::decrypt::{DecryptedMessage, PreparedMessage};
+use crate::protocol::state::ServerHandshakeState;
+use crate::proxy::{self, ProxyConfig};
+use crate::response_tap::{self, ResponseTap};
+use crate::send_all;
+use crate::tasks::{TaskMessage, BoxedTask, DeliveryNotifier, Outgoing, SendPath};
+use crate::tls::{self, TlsConfig};
+
+
+// The length of the signaling path, i.e. the hex encoded initiator public key.
+const PATH_LENGTH: usize = 64;
+
+// The longest sleep supported by the `tokio_timer` wheel of the default
+// sleeper is about 409 seconds, so longer timeouts are waited for in several
+// steps.
+const MAX_TIMER_SLEEP: Duration = Duration::from_secs(300);
+
+
+/// A type alias for the async websocket client type.
+pub type WsClient = Client<ResponseTap<TlsStream<TcpStream>>>;
+
+
+/// The sending half of a WebSocket client in the task loop.
+type WsSink = Box<dyn Sink<SinkItem=OwnedMessage, SinkError=WebSocketError>>;
+
+/// The receiving half of a WebSocket client in the task loop.
+type WsStream = Box<dyn Stream<Item=OwnedMessage, Error=WebSocketError>>;
+
+
+/// Wrap future in a box with type erasure.
+macro_rules! boxed {
+    ($future:expr) => {{
+        Box::new($future) as BoxedFuture<_, _>
+    }}
+}
+
+
+/// Wrapper type for decoded form of WebSocket message types that we want to handle.
+#[derive(Debug)]
+enum WsMessageDecoded {
+    /// We got bytes that we decoded into a ByteBox.
+    ByteBox(ByteBox),
+    /// We got a ping message.
+    Ping(Vec<u8>),
+    /// We got a close message.
+    Close(Option<CloseCode>),
+    /// A task message was decrypted on a worker thread.
+    Decrypted(DecryptedMessage),
+    /// A message was handled while preparing it for decryption.
+    Handled(HandleActions),
+    /// We got a message type that we want to ignore.
+    Ignore,
+}
+
+
+/// Connect to the specified SaltyRTC server.
+///
+/// This function returns a future. The future must be run in a Tokio reactor
+/// core for something to actually happen.
+///
+/// The future completes once the server connection is established.
+/// It returns the async websocket client instance.
+///
+/// If `tls_config` is `None`, the connector is created from the
+/// [`TlsConfig`](struct.TlsConfig.html) passed to the client builder (or from
+/// the system defaults). Pinned certificates are verified in both cases.
+///
+/// If the client uses [`SharedResources`](struct.SharedResources.html), the
+/// shared TLS connector is used unless the client has its own TLS
+/// configuration, the server address is looked up in the shared DNS cache,
+/// and the connection waits until the handshake limit allows it to start.
+pub fn connect(
+    host: &str,
+    port: u16,
+    tls_config: Option<TlsConnector>,
+    handle: &Handle,
+    salty: Arc<RwLock<SaltyClient>>,
+) -> SaltyResult<(
+    impl Future<Item=WsClient, Error=SaltyError>,
+    UnboundedChannel<Event>,
+)> {
+    // Initialize the crypto backend
+    crypto_init()?;
+
+    // Parse URL
+    let (ws_url, subprotocol, proxy, client_tls_config) = prepare_connect(host, port, &salty)?;
+
+    let shared = salty.read()
+        .map_err(|e| SaltyError::Crash(format!("Could not read-lock SaltyClient: {}", e)))?
+        .shared
+        .clone();
+
+    // Determine TLS configuration
+    let connector = match (tls_config, &client_tls_config, &shared) {
+        (Some(connector), _, _) => connector,
+        (None, Some(config), _) => config.connector()?,
+        (None, None, Some(shared)) => shared.tls_connector(),
+        (None, None, None) => TlsConnector::new()
+            .map_err(|e| SaltyError::Crypto(format!("Could not create TLS connector: {}", e)))?,
+    };
+    let connector = tokio_tls::TlsConnector::from(connector);
+    let pinned_fingerprints = match (client_tls_config, &shared) {
+        (Some(config), _) => config.pinned_fingerprints().to_vec(),
+        (None, Some(shared)) => shared.pinned_fingerprints().to_vec(),
+        (None, None) => vec![],
+    };
+
+    // Wait for a free handshake slot, if the number of handshakes is limited
+    let permit_salty = Arc::clone(&salty);
+    let permit: BoxedFuture<(), SaltyError> = match shared {
+        Some(ref shared) => boxed!(shared.acquire_handshake().and_then(move |permit| {
+            permit_salty.write()
+                .map_err(|e| SaltyError::Crash(format!("Could not write-lock SaltyClient: {}", e)))?
+                .handshake_permit = permit;
+            Ok(())
+        })),
+        None => boxed!(future::ok(())),
+    };
+
+    // Open TCP connection, either directly or through a proxy
+    let server = format!("{}:{}", host, port);
+    let tcp_stream = permit.and_then({
+        let server = server.clone();
+        let host = host.to_string();
+        let handle = handle.clone();
+        move |()| -> BoxedFuture<TcpStream, SaltyError> {
+            match proxy {
+                None => {
+                    let addr = match shared {
+                        Some(ref shared) => shared.resolve(&host, port),
+                        None => resolve(&host, port),
+                    };
+                    match addr {
+                        Ok(addr) => boxed!(TcpStream::connect(&addr, &handle)
+                            .map_err(move |e| SaltyError::Network(format!("Could not connect to server ({}): {}", server, e)))),
+                        Err(e) => boxed!(future::err(e)),
+                    }
+                },
+                Some(proxy) => proxy::connect(&proxy, &host, port, &handle),
+            }
+        }
+    });
+
+    // Initialize WebSocket client
+    let tls_host = host.to_string();
+    let tls_server = server.clone();
+    let upgrade_salty = Arc::clone(&salty);
+    let ws_connect = tcp_stream
+        .and_then(move |stream| connector
+            .connect(&tls_host, stream)
+            .map_err(move |e| SaltyError::Network(format!("TLS handshake with server ({}) failed: {}", tls_server, e))))
+        .and_then(move |stream| {
+            tls::verify_pinned_certificate(&pinned_fingerprints, stream.get_ref())?;
+            Ok(stream)
+        })
+        .and_then(move |stream| upgrade(stream, ws_url, subprotocol, server, upgrade_salty));
+    let future = close_on_error(ws_connect, salty);
+    debug!("Created WS connect future");
+
+    // Create event channel
+    let event_channel = UnboundedChannel::new();
+    debug!("Created event channel");
+
+    Ok((future, event_channel))
+}
+
+/// Connect to the SaltyRTC server over a stream opened by the application.
+///
+/// This works like [`connect`](fn.connect.html), but instead of opening a
+/// TCP connection and doing the TLS handshake on the Tokio reactor, the
+/// WebSocket handshake is done on `stream`. This allows using any transport
+/// that implements `AsyncRead` and `AsyncWrite`, e.g. a TLS stream created
+/// with a different TLS implementation, or a connection driven by a
+/// different executor. The `host` and `port` are only used for the URL of
+/// the WebSocket handshake.
+///
+/// The application is responsible for securing the stream: The TLS and
+/// proxy configuration of the client, including pinned certificates, are
+/// not applied.
+///
+/// The returned future only needs an executor for the futures 0.1 traits,
+/// and so do [`do_handshake`](fn.do_handshake.html) and
+/// [`task_loop`](fn.task_loop.html), which accept the resulting client.
+pub fn connect_on<S>(
+    stream: S,
+    host: &str,
+    port: u16,
+    salty: Arc<RwLock<SaltyClient>>,
+) -> SaltyResult<(
+    impl Future<Item=Client<impl AsyncRead + AsyncWrite + Send + 'static>, Error=SaltyError>,
+    UnboundedChannel<Event>,
+)> where S: AsyncRead + AsyncWrite + Send + 'static {
+    // Initialize the crypto backend
+    crypto_init()?;
+
+    let (ws_url, subprotocol, _, _) = prepare_connect(host, port, &salty)?;
+    let server = format!("{}:{}", host, port);
+    let ws_connect = upgrade(stream, ws_url, subprotocol, server, Arc::clone(&salty));
+    let future = close_on_error(ws_connect, salty);
+
+    Ok((future, UnboundedChannel::new()))
+}
+
+/// Mark the client as connecting to `host`, and return the WebSocket URL,
+/// the subprotocol, as well as the proxy and TLS configuration.
+fn prepare_connect(
+    host: &str,
+    port: u16,
+    salty: &RwLock<SaltyClient>,
+) -> SaltyResult<(Url, &'static str, Option<ProxyConfig>, Option<TlsConfig>)> {
+    let (path, proxy, client_tls_config, subprotocol) = salty.write()
+        .map(|mut client| {
+            client.state.set(ConnectionState::Connecting);
+            client.last_server = Some((host.to_string(), port));
+            #[cfg(feature = "permessage-deflate")]
+            {
+                client.permessage_deflate_negotiated = false;
+            }
+            let now = client.now();
+            client.signaling.common_mut().stats.connecting(now);
+            (
+                HEXLOWER.encode(&client.initiator_pubkey().0),
+                client.proxy.clone(),
+                client.tls_config.clone(),
+                client.protocol_version().subprotocol(),
+            )
+        })
+        .map_err(|_| SaltyError::Crash("connect: Could not write-lock SaltyClient".into()))?;
+    let url = format!("wss://{}:{}/{}", host, port, path);
+    let ws_url = match Url::parse(&url) {
+        Ok(b) => b,
+        Err(e) => return Err(SaltyError::Decode(format!("Could not parse URL: {}", e))),
+    };
+    validate_path(&ws_url, &path)?;
+    Ok((ws_url, subprotocol, proxy, client_tls_config))
+}
+
+/// Do the WebSocket handshake on `stream` and verify the chosen subprotocol.
+fn upgrade<S>(
+    stream: S,
+    ws_url: Url,
+    subprotocol: &'static str,
+    server: String,
+    salty: Arc<RwLock<SaltyClient>>,
+) -> impl Future<Item=Client<ResponseTap<S>>, Error=SaltyError>
+        where S: AsyncRead + AsyncWrite + Send + 'static {
+    let response_head = Arc::new(Mutex::new(Vec::new()));
+    let builder = ClientBuilder::from_url(&ws_url).add_protocol(subprotocol);
+    let tap = ResponseTap::new(stream, Arc::clone(&response_head));
+
+    // Offer permessage-deflate, if enabled
+    #[cfg(feature = "permessage-deflate")]
+    let (builder, tap) = match salty.read() {
+        Ok(ref s) if s.permessage_deflate => (builder.add_extension(deflate::extension()), tap.inflating()),
+        _ => (builder, tap),
+    };
+
+    #[cfg(feature = "permessage-deflate")]
+    let deflate_salty = Arc::clone(&salty);
+    builder
+        .async_connect_on(tap)
+        .map_err(move |e: WebSocketError| websocket_connect_error(&e, &server, &response_head))
+        .and_then(move |(client, headers)| {
+            // Verify that the correct subprotocol was chosen
+            trace!("Websocket server headers: {:?}", headers);
+            #[cfg(feature = "permessage-deflate")]
+            {
+                let negotiated = match headers.get::<WebSocketExtensions>() {
+                    Some(extensions) => deflate::is_negotiated(extensions.iter()),
+                    None => false,
+                };
+                debug!("Server accepted permessage-deflate: {}", negotiated);
+                deflate_salty.write()
+                    .map_err(|e| SaltyError::Crash(format!("Could not write-lock SaltyClient: {}", e)))?
+                    .permessage_deflate_negotiated = negotiated;
+            }
+            match headers.get::<WebSocketProtocol>() {
+                Some(proto) if proto.len() == 1 && proto[0] == subprotocol => {
+                    Ok(client)
+                },
+                Some(proto) => {
+                    error!("More than one chosen protocol: {:?}", proto);
+                    Err(SaltyError::Protocol("More than one websocket subprotocol chosen by server".into()))
+                },
+                None => {
+                    error!("No protocol chosen by server");
+                    Err(SaltyError::Protocol("Websocket subprotocol not accepted by server".into()))
+                },
+            }
+        })
+        .map(move |client| {
+            debug!("Connected to {}", ws_url);
+            let role = salty
+                .write()
+                .map(|mut s| {
+                    s.state.set(ConnectionState::ServerHandshake);
+                    let now = s.now();
+                    s.signaling.common_mut().stats.connected(now);
+                    s.role().to_string()
+                })
+                .unwrap_or_else(|_| "Unknown".to_string());
+            info!("Connected to server as {}", role);
+            client
+        })
+}
+
+/// Mark the connection as closed if the connect future fails.
+fn close_on_error<F>(future: F, salty: Arc<RwLock<SaltyClient>>) -> impl Future<Item=F::Item, Error=SaltyError>
+        where F: Future<Error=SaltyError> {
+    CloseOnError { inner: future, salty: Some(salty) }
+}
+
+/// A connect future that marks the connection as closed and releases the
+/// handshake permit if it fails, or if it is dropped before it completed.
+struct CloseOnError<F> {
+    inner: F,
+    /// The client, until the future completed.
+    salty: Option<Arc<RwLock<SaltyClient>>>,
+}
+
+impl<F> CloseOnError<F> {
+    fn close(&mut self) {
+        if let Some(salty) = self.salty.take() {
+            if let Ok(mut s) = salty.write() {
+                s.state.closed(None);
+                s.handshake_permit = None;
+            }
+        }
+    }
+}
+
+impl<F> Future for CloseOnError<F> where F: Future<Error=SaltyError> {
+    type Item = F::Item;
+    type Error = SaltyError;
+
+    fn poll(&mut self) -> Poll<F::Item, SaltyError> {
+        match self.inner.poll() {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Ok(Async::Ready(item)) => {
+                self.salty = None;
+                Ok(Async::Ready(item))
+            },
+            Err(e) => {
+                self.close();
+                Err(e)
+            },
+        }
+    }
+}
+
+impl<F> Drop for CloseOnError<F> {
+    fn drop(&mut self) {
+        if self.salty.is_some() {
+            debug!("Connect future dropped before the connection was established");
+            self.close();
+        }
+    }
+}
+
+/// Check that the URL contains the signaling path and nothing else.
+///
+/// The server rejects paths that don't consist of 64 hex characters. A host
+/// that contains a path or query (e.g. `example.org/saltyrtc`) would result
+/// in such a path as well.
+fn validate_path(url: &Url, path: &str) -> Result<(), ConnectError> {
+    if path.len() != PATH_LENGTH || !path.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(ConnectError::InvalidPath(
+            format!("Path must consist of {} hex characters, not {:?}", PATH_LENGTH, path)
+        ));
+    }
+    if url.path() != format!("/{}", path) || url.query().is_some() || url.fragment().is_some() {
+        return Err(ConnectError::InvalidPath(format!("URL {} does not end with the signaling path", url)));
+    }
+    Ok(())
+}
+
+/// Convert an error that occurred during the WebSocket handshake.
+///
+/// If the server answered with an HTTP status other than 101, the status is
+/// reported as `ConnectError::Rejected`.
+fn websocket_connect_error(e: &WebSocketError, server: &str, response_head: &Mutex<Vec<u8>>) -> SaltyError {
+    if let WebSocketError::ResponseError(_) = *e {
+        let status = response_head.lock().ok().and_then(|head| response_tap::http_status(&head));
+        match status {
+            Some(101) | None => {},
+            Some(status) => return ConnectError::Rejected(status).into(),
+        }
+    }
+    // `WebSocketError` only implements the deprecated `Error::cause`, so
+    // neither `Error::source` nor the failure cause chain sees the cause.
+    let cause: Option<&dyn Error> = match *e {
+        WebSocketError::IoError(ref cause) => Some(cause),
+        WebSocketError::HttpError(ref cause) => Some(cause),
+        WebSocketError::UrlError(ref cause) => Some(cause),
+        WebSocketError::TlsError(ref cause) => Some(cause),
+        WebSocketError::Utf8Error(ref cause) => Some(cause),
+        WebSocketError::WebSocketUrlError(ref cause) => Some(cause),
+        _ => None,
+    };
+    SaltyError::Network(match cause {
+        Some(cause) => format!("Could not connect to server ({}): {}: {}", server, e, cause),
+        None => format!("Could not connect to server ({}): {}", server, e),
+    })
+}
+
+/// Connect to the first reachable server and do the handshake.
+///
+/// The servers added with
+/// [`SaltyClientBuilder::add_server`](struct.SaltyClientBuilder.html#method.add_server)
+/// are tried in order. If the connection to a server fails or the handshake
+/// does not finish within the `timeout`, the handshake is reset and the next
+/// server is tried. The permanent keys, the auth token and the tasks are
+/// preserved, so the peer may connect to any of the servers.
+///
+/// Events of the failed attempts (e.g. `ServerHandshakeDone`) are sent
+/// through the event channel as well. Once the handshake succeeded, an
+/// [`Event::ServerSelected`](enum.Event.html#variant.ServerSelected) with the
+/// endpoint that was used follows.
+///
+/// See [`connect`](fn.connect.html) for the meaning of `tls_config`.
+pub fn connect_with_failover(
+    tls_config: Option<TlsConnector>,
+    handle: &Handle,
+    salty: Arc<RwLock<SaltyClient>>,
+    timeout: Option<Duration>,
+) -> SaltyResult<(
+    impl Future<Item=WsClient, Error=SaltyError>,
+    UnboundedChannel<Event>,
+)> {
+    let servers = salty.read()
+        .map(|client| client.servers.clone())
+        .map_err(|_| SaltyError::Crash("connect_with_failover: Could not read-lock SaltyClient".into()))?;
+    if servers.is_empty() {
+        return Err(SaltyError::Network("No server endpoints configured".into()));
+    }
+
+    let event_channel = UnboundedChannel::new();
+    let event_tx = event_channel.clone_tx();
+    let handle = handle.clone();
+    let future = future::loop_fn(0, move |index: usize| {
+        let (host, port) = servers[index].clone();
+        let is_last = index + 1 == servers.len();
+        info!("Connecting to server {}:{} ({}/{})", host, port, index + 1, servers.len());
+
+        let attempt = future::result(connect(&host, port, tls_config.clone(), &handle, Arc::clone(&salty)))
+            .and_then(|(connect_future, _)| connect_future)
+            .and_then({
+                let salty = Arc::clone(&salty);
+                let event_tx = event_tx.clone();
+                move |client| do_handshake(client, salty, event_tx, timeout)
+            });
+
+        let salty = Arc::clone(&salty);
+        let event_tx = event_tx.clone();
+        attempt.then(move |res| match res {
+            Ok(client) => {
+                event_tx
+                    .unbounded_send(Event::ServerSelected(host, port))
+                    .map_err(|_| SaltyError::Crash("Could not send event through channel".into()))?;
+                Ok(Loop::Break(client))
+            },
+            Err(e @ SaltyError::Network(_)) |
+            Err(e @ SaltyError::Connect(ConnectError::Rejected(_))) |
+            Err(e @ SaltyError::Timeout) if !is_last => {
+                warn!("Connection to server {}:{} failed: {}", host, port, e);
+                salty.write()
+                    .map_err(|_| SaltyError::Crash("connect_with_failover: Could not write-lock SaltyClient".into()))?
+                    .reset_handshake()?;
+                Ok(Loop::Continue(index + 1))
+            },
+            Err(e) => Err(e),
+        })
+    });
+
+    Ok((future, event_channel))
+}
+
+/// Continue a suspended session on a new connection to the server of the
+/// last connection.
+///
+/// This calls [`SaltyClient::resume`](struct.SaltyClient.html#method.resume),
+/// connects to the server and does the server and peer handshake with the
+/// trusted permanent key of the peer. Once the future completes, run
+/// `task_loop` to start the task again. The tasks and event handlers of the
+/// application can be kept.
+pub fn reconnect(
+    tls_config: Option<TlsConnector>,
+    handle: &Handle,
+    salty: Arc<RwLock<SaltyClient>>,
+    timeout: Option<Duration>,
+) -> SaltyResult<(
+    impl Future<Item=WsClient, Error=SaltyError>,
+    UnboundedChannel<Event>,
+)> {
+    let (host, port) = {
+        let mut client = salty.write()
+            .map_err(|_| SaltyError::Crash("reconnect: Could not write-lock SaltyClient".into()))?;
+        let server = client.last_server.clone()
+            .ok_or_else(|| SaltyError::Network("No server connection to resume".into()))?;
+        client.resume()?;
+        server
+    };
+    info!("Reconnecting to server {}:{}", host, port);
+
+    let (connect_future, event_channel) = connect(&host, port, tls_config, handle, Arc::clone(&salty))?;
+    let event_tx = event_channel.clone_tx();
+    let future = connect_future
+        .and_then(move |client| do_handshake(client, salty, event_tx, timeout));
+    Ok((future, event_channel))
+}
+
+/// A batch of messages on the raw outgoing channel of the task loop.
+#[derive(Debug)]
+struct OutgoingBatch {
+    messages: Vec<OwnedMessage>,
+    /// Notified once all messages of the batch were written to the WebSocket.
+    delivered: Option<DeliveryNotifier>,
+}
+
+impl OutgoingBatch {
+    /// Split the batch into the items handled by the writer.
+    fn into_items(self) -> Vec<WriterItem> {
+        let mut items: Vec<WriterItem> = self.messages.into_iter().map(WriterItem::Message).collect();
+        items.extend(self.delivered.map(WriterItem::Delivered));
+        items
+    }
+}
+
+impl From<Vec<OwnedMessage>> for OutgoingBatch {
+    fn from(messages: Vec<OwnedMessage>) -> Self {
+        OutgoingBatch { messages, delivered: None }
+    }
+}
+
+/// An item handled by the writer of the task loop.
+enum WriterItem {
+    /// A message to write to the WebSocket.
+    Message(OwnedMessage),
+    /// The messages before have been written.
+    Delivered(DeliveryNotifier),
+}
+
+/// Writes the batches from the raw outgoing channel to the WebSocket.
+///
+/// The senders of tracked messages are notified once the sink has been
+/// flushed after their messages, not when the messages were merely handed
+/// to the sink.
+struct Writer {
+    batches: mpsc::Receiver<OutgoingBatch>,
+    sink: WsSink,
+    /// The items of the current batch that have not been handled yet.
+    queue: VecDeque<WriterItem>,
+    /// The notifiers of messages that were handed to the sink, but not
+    /// flushed yet.
+    unflushed: Vec<DeliveryNotifier>,
+    /// Whether the raw outgoing channel is exhausted.
+    done: bool,
+}
+
+impl Writer {
+    fn new(batches: mpsc::Receiver<OutgoingBatch>, sink: WsSink) -> Self {
+        Writer { batches, sink, queue: VecDeque::new(), unflushed: vec![], done: false }
+    }
+
+    /// Flush the sink and notify the senders of the flushed messages.
+    fn poll_flush(&mut self) -> Poll<(), SaltyError> {
+        let flushed = self.sink
+            .poll_complete()
+            .map_err(|e| SaltyError::Crash(format!("TODO sink error: {:?}", e)))?;
+        if flushed.is_ready() {
+            for notifier in self.unflushed.drain(..) {
+                notifier.notify();
+            }
+        }
+        Ok(flushed)
+    }
+}
+
+impl Future for Writer {
+    type Item = ();
+    type Error = SaltyError;
+
+    fn poll(&mut self) -> Poll<(), SaltyError> {
+        loop {
+            while let Some(item) = self.queue.pop_front() {
+                match item {
+                    WriterItem::Message(msg) => {
+                        let sent = self.sink
+                            .start_send(msg)
+                            .map_err(|e| SaltyError::Crash(format!("TODO sink error: {:?}", e)))?;
+                        if let AsyncSink::NotReady(msg) = sent {
+                            self.queue.push_front(WriterItem::Message(msg));
+                            // Make room in the sink, then try again
+                            try_ready!(self.poll_flush());
+                        }
+                    },
+                    WriterItem::Delivered(notifier) => {
+                        self.unflushed.push(notifier);
+                        // Notify right away if the sink can be flushed
+                        // without waiting
+                        self.poll_flush()?;
+                    },
+                }
+            }
+
+            if self.done {
+                try_ready!(self.poll_flush());
+                return self.sink
+                    .close()
+                    .map_err(|e| SaltyError::Crash(format!("TODO sink error: {:?}", e)));
+            }
+
+            match self.batches.poll() {
+                Ok(Async::Ready(Some(batch))) => self.queue.extend(batch.into_items()),
+                Ok(Async::Ready(None)) => self.done = true,
+                Ok(Async::NotReady) => {
+                    try_ready!(self.poll_flush());
+                    return Ok(Async::NotReady);
+                },
+                Err(()) => return Err(SaltyError::Crash("TODO receiver error".to_string())),
+            }
+        }
+    }
+}
+
+/// The messages sent by the task loop transformer once the session has been
+/// renewed: A WebSocket close message, followed by the end of the transformer.
+fn session_renewal_close() -> Vec<Result<OutgoingBatch, Result<(), ()>>> {
+    debug!("<-- Enqueuing WebSocket close message for session renewal");
+    let reason = CloseCode::WsGoingAway;
+    vec![
+        Ok(vec![OwnedMessage::Close(Some(CloseData {
+            status_code: reason.as_number(),
+            reason: reason.to_string(),
+        }))].into()),
+        Err(Ok(())), // Terminate transformer future
+    ]
+}
+
+/// Fail the handshake because of a signaling error.
+///
+/// If the error requires a specific close code (e.g. a protocol error), a
+/// WebSocket close message with that code is sent to the server first.
+fn close_with_error<S>(client: Client<S>, error: SignalingError) -> BoxedFuture<Loop<Client<S>, Client<S>>, SaltyError>
+        where S: AsyncRead + AsyncWrite + 'static {
+    let reason = match error.close_code() {
+        Some(reason) => reason,
+        None => return boxed!(future::err(error.into())),
+    };
+    debug!("<-- Enqueuing WebSocket close message ({})", reason);
+    let close = OwnedMessage::Close(Some(CloseData {
+        status_code: reason.as_number(),
+        reason: reason.to_string(),
+    }));
+    boxed!(
+        client
+            .send(close)
+            .then(move |res| {
+                if let Err(e) = res {
+                    warn!("Could not send close message: {}", e);
+                }
+                Err(error.into())
+            })
+    )
+}
+
+/// Decode a websocket `OwnedMessage` and wrap it into a `WsMessageDecoded`.
+fn decode_ws_message(msg: OwnedMessage) -> SaltyResult<WsMessageDecoded> {
+    let decoded = match msg {
+        OwnedMessage::Binary(bytes) => {
+            debug!("--> Incoming binary message ({} bytes)", bytes.len());
+
+            // Parse into ByteBox
+            let bbox = ByteBox::parse(bytes)
+                .map_err(|e| SaltyError::Protocol(e.to_string()))?;
+            trace!("ByteBox: {:?}", bbox);
+
+            WsMessageDecoded::ByteBox(bbox)
+        },
+        OwnedMessage::Ping(payload) => {
+            debug!("--> Incoming WS ping message");
+            WsMessageDecoded::Ping(payload)
+        },
+        OwnedMessage::Pong(_) => {
+            debug!("--> Incoming WS pong message (ignored)");
+            WsMessageDecoded::Ignore
+        },
+        OwnedMessage::Close(close_data) => {
+            debug!("--> Incoming WS close message");
+            match close_data {
+                Some(data) => {
+                    let close_code = CloseCode::from_number(data.status_code);
+                    if data.reason.is_empty() {
+                        info!("Server closed connection with close code {}", close_code);
+                    } else {
+                        info!("Server closed connection with close code {} ({})", close_code, data.reason);
+                    }
+                    WsMessageDecoded::Close(Some(close_code))
+                }
+                None => {
+                    info!("Server closed connection without close code");
+                    WsMessageDecoded::Close(None)
+                }
+            }
+        },
+        OwnedMessage::Text(payload) => {
+            warn!("Skipping text message: {:?}", payload);
+            WsMessageDecoded::Ignore
+        },
+    };
+    Ok(decoded)
+}
+
+/// An action in our pipeline.
+///
+/// This is used to enable early-return inside the pipeline. If a step returns a `Future`,
+/// it should be passed directly to the `loop_fn`.
+enum PipelineAction<S> {
+    /// We got a ByteBox to handle.
+    ByteBox((Client<S>, ByteBox)),
+    /// Immediately pass on this future in the next step.
+    Future(BoxedFuture<Loop<Client<S>, Client<S>>, SaltyError>),
+}
+
+/// Preprocess a `WsMessageDecoded`.
+///
+/// Here pings and ignored messages are handled.
+fn preprocess_ws_message<S>((decoded, client): (WsMessageDecoded, Client<S>)) -> SaltyResult<PipelineAction<S>>
+        where S: AsyncRead + AsyncWrite + 'static {
+    // Unwrap byte box, handle ping messages
+    let bbox = match decoded {
+        WsMessageDecoded::ByteBox(bbox) => bbox,
+        WsMessageDecoded::Ping(payload) => {
+            let pong = OwnedMessage::Pong(payload);
+            let outbox = stream::iter_ok::<_, WebSocketError>(vec![pong]);
+            let future = send_all::new(client, outbox)
+                .map_err(move |e| SaltyError::Network(format!("Could not send pong message: {}", e)))
+                .map(|(client, _)| {
+                    debug!("Sent pong message");
+                    Loop::Continue(client)
+                });
+            let action = PipelineAction::Future(boxed!(future));
+            return Ok(action);
+        },
+        WsMessageDecoded::Close(_) => {
+            return Err(SaltyError::Crash("Close message was not handled before preprocessing".into()));
+        },
+        WsMessageDecoded::Decrypted(_) | WsMessageDecoded::Handled(_) => {
+            return Err(SaltyError::Crash("Task message was prepared for decryption during handshake".into()));
+        },
+        WsMessageDecoded::Ignore => {
+            debug!("Ignoring message");
+            let action = PipelineAction::Future(boxed!(future::ok(Loop::Continue(client))));
+            return Ok(action);
+        },
+    };
+    Ok(PipelineAction::ByteBox((client, bbox)))
+}
+
+/// Decode and preprocess the next message received from the server.
+fn receive_ws_message<S>(
+    msg_option: Option<OwnedMessage>,
+    client: Client<S>,
+    salty: &RwLock<SaltyClient>,
+    event_tx: &mpsc::UnboundedSender<Event>,
+) -> SaltyResult<PipelineAction<S>> where S: AsyncRead + AsyncWrite + 'static {
+    let decoded = match msg_option {
+        Some(msg) => decode_ws_message(msg)?,
+        None => return Err(SaltyError::Network("Server message stream ended without close message".into())),
+    };
+    if let WsMessageDecoded::Close(code) = decoded {
+        let (event, reason, awaiting_server_hello) = {
+            let mut salty = salty.write()
+                .map_err(|e| SaltyError::Crash(format!("do_handshake: Could not write-lock SaltyClient: {}", e)))?;
+            let event = salty.closed(code);
+            let awaiting_server_hello = salty.signaling.server_handshake_state() == ServerHandshakeState::New;
+            (event, salty.close_reason().cloned(), awaiting_server_hello)
+        };
+        if let Some(event) = event {
+            event_tx.unbounded_send(event)
+                .map_err(|_| SaltyError::Crash("Could not send event through channel".into()))?;
+        }
+
+        // The server closes with 3007 if it does not have a key pair for
+        // the `your_key` field sent in the 'client-auth' message.
+        if code == Some(CloseCode::InvalidKey) {
+            return Err(SaltyError::Protocol(
+                "Server does not have the expected permanent key (close code 3007)".into()
+            ));
+        }
+
+        // The server sends the 'server-hello' message right away, unless it
+        // does not accept the path.
+        if code == Some(CloseCode::ProtocolError) && awaiting_server_hello {
+            return Err(ConnectError::InvalidPath(
+                "Server closed the connection before the handshake (close code 3001)".into()
+            ).into());
+        }
+        return Err(reason.map_or_else(
+            || SaltyError::Crash("do_handshake: Close reason not set".into()),
+            SaltyError::Closed,
+        ));
+    }
+    preprocess_ws_message((decoded, client))
+}
+
+/// Handle expired handshake timeouts and send the resulting messages.
+fn handle_timeouts<S>(client: Client<S>, salty: &RwLock<SaltyClient>) -> SaltyResult<PipelineAction<S>>
+        where S: AsyncRead + AsyncWrite + 'static {
+    let (replies, handle_actions) = {
+        let mut salty = salty.write()
+            .map_err(|e| SaltyError::Crash(format!("do_handshake: Could not write-lock SaltyClient: {}", e)))?;
+        let now = salty.now();
+        match salty.handle_timeouts(now) {
+            Ok(actions) => actions.split_replies(),
+            Err(e) => {
+                salty.state.closing(CloseReason::local(e.close_code(), e.to_string()));
+                return Ok(PipelineAction::Future(close_with_error(client, e)));
+            },
+        }
+    };
+    if let Some(action) = handle_actions.into_iter().next() {
+        return Err(SaltyError::Crash(format!("Got unexpected {:?} action from timeout", action)));
+    }
+    if replies.is_empty() {
+        return Ok(PipelineAction::Future(boxed!(future::ok(Loop::Continue(client)))));
+    }
+    let messages: Vec<OwnedMessage> = replies
+        .into_iter()
+        .map(|bbox| OwnedMessage::Binary(bbox.into_bytes()))
+        .collect();
+    let outbox = stream::iter_ok::<_, WebSocketError>(messages);
+    let future = send_all::new(client, outbox)
+        .map_err(move |e| SaltyError::Network(format!("Could not send message: {}", e)))
+        .map(|(client, _)| Loop::Continue(client));
+    Ok(PipelineAction::Future(boxed!(future)))
+}
+
+/// Aborts a running handshake.
+///
+/// Returned by [`HandshakeFuture::abort_handle`](struct.HandshakeFuture.html#method.abort_handle),
+/// for applications that pass the handshake future on to the reactor.
+#[derive(Clone)]
+pub struct AbortHandle {
+    tx: Arc<Mutex<Option<oneshot::Sender<()>>>>,
+}
+
+impl AbortHandle {
+    /// Abort the handshake, see [`HandshakeFuture::abort`](struct.HandshakeFuture.html#method.abort).
+    ///
+    /// Aborting a handshake that has already finished has no effect.
+    pub fn abort(&self) {
+        if let Some(tx) = self.tx.lock().ok().and_then(|mut tx| tx.take()) {
+            // The handshake may have finished already
+            let _ = tx.send(());
+        }
+    }
+}
+
+impl ::std::fmt::Debug for AbortHandle {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_struct("AbortHandle").finish()
+    }
+}
+
+/// The future returned by [`do_handshake`](fn.do_handshake.html).
+///
+/// The handshake can be cancelled by calling [`abort`](#method.abort), or
+/// by dropping the future. The WebSocket connection is then closed with
+/// close code 1000, the state of the peers that have not finished the
+/// handshake is discarded, and the future fails with `SaltyError::Closed`.
+///
+/// If the future is dropped, the close message is only sent if that is
+/// possible without waiting. Otherwise, the connection ends without close
+/// message once the client is dropped. Either way, the client is marked as
+/// closed and can be reconnected.
+#[must_use = "futures do nothing unless polled"]
+pub struct HandshakeFuture<S> {
+    /// The handshake, until it has finished.
+    inner: Option<BoxedFuture<Client<S>, SaltyError>>,
+    abort: AbortHandle,
+    salty: Arc<RwLock<SaltyClient>>,
+}
+
+impl<S> HandshakeFuture<S> {
+    /// Abort the handshake.
+    ///
+    /// The handshake is aborted the next time the future is polled while
+    /// it waits for a message from the server.
+    pub fn abort(&self) {
+        self.abort.abort();
+    }
+
+    /// Return a handle that aborts the handshake, even once the future has
+    /// been passed to the reactor.
+    pub fn abort_handle(&self) -> AbortHandle {
+        self.abort.clone()
+    }
+}
+
+impl<S> Future for HandshakeFuture<S> {
+    type Item = Client<S>;
+    type Error = SaltyError;
+
+    fn poll(&mut self) -> Poll<Client<S>, SaltyError> {
+        let result = match self.inner {
+            Some(ref mut inner) => inner.poll(),
+            None => return Err(SaltyError::Crash("Handshake future polled after it finished".into())),
+        };
+        if let Ok(Async::NotReady) = result {
+            return result;
+        }
+        self.inner = None;
+        result
+    }
+}
+
+impl<S> Drop for HandshakeFuture<S> {
+    fn drop(&mut self) {
+        let inner = match self.inner.take() {
+            Some(inner) => inner,
+            None => return,
+        };
+        debug!("Handshake future dropped, aborting handshake");
+        self.abort();
+
+        // Poll the handshake once more, so that it can send the close message
+        let notify = NotifyHandle::from(Arc::new(NoopNotify));
+        if let Ok(Async::NotReady) = executor::spawn(inner).poll_future_notify(&notify, 0) {
+            debug!("Could not send close message, dropping the connection");
+            if let Ok(mut s) = self.salty.write() {
+                abort_handshake_state(&mut s);
+                s.state.closed(None);
+                s.handshake_permit = None;
+            }
+        }
+    }
+}
+
+/// Ignores wakeups of the handshake polled by `HandshakeFuture::drop`.
+struct NoopNotify;
+
+impl executor::Notify for NoopNotify {
+    fn notify(&self, _id: usize) {}
+}
+
+/// The next message from the server, or the client of an aborted handshake.
+enum Received<S> {
+    Message(Option<OwnedMessage>, Client<S>),
+    Aborted(Client<S>),
+}
+
+/// Waits for the next message from the server, unless the handshake is
+/// aborted first.
+struct NextMessage<S> {
+    abort: Shared<oneshot::Receiver<()>>,
+    message: Option<StreamFuture<Client<S>>>,
+}
+
+impl<S> NextMessage<S> {
+    /// Return the client, unless a message was received.
+    fn into_inner(self) -> Option<Client<S>> {
+        self.message.and_then(StreamFuture::into_inner)
+    }
+}
+
+impl<S> Future for NextMessage<S> where S: AsyncRead + AsyncWrite {
+    type Item = Received<S>;
+    type Error = (WebSocketError, Client<S>);
+
+    fn poll(&mut self) -> Poll<Received<S>, (WebSocketError, Client<S>)> {
+        // The abort handle is only dropped together with the handshake
+        // future, so that is treated as abort as well
+        if let Ok(Async::NotReady) = self.abort.poll() {
+            let message = self.message.as_mut().expect("NextMessage polled after it finished");
+            return match message.poll()? {
+                Async::Ready((msg_option, client)) => {
+                    self.message = None;
+                    Ok(Async::Ready(Received::Message(msg_option, client)))
+                },
+                Async::NotReady => Ok(Async::NotReady),
+            };
+        }
+        match self.message.take().and_then(StreamFuture::into_inner) {
+            Some(client) => Ok(Async::Ready(Received::Aborted(client))),
+            None => panic!("NextMessage polled after it finished"),
+        }
+    }
+}
+
+/// Discard the state of the peers and mark the connection as closing,
+/// because the application aborted the handshake.
+fn abort_handshake_state(salty: &mut SaltyClient) {
+    info!("Aborting handshake");
+    salty.state.closing(CloseReason::local(Some(CloseCode::WsClosingNormal), "Handshake aborted"));
+    if let Err(e) = salty.reset_handshake() {
+        warn!("Could not reset handshake: {}", e);
+    }
+}
+
+/// Close the connection of an aborted handshake.
+fn close_aborted<S>(client: Client<S>, salty: &RwLock<SaltyClient>) -> SaltyResult<PipelineAction<S>>
+        where S: AsyncRead + AsyncWrite + 'static {
+    let reason = {
+        let mut salty = salty.write()
+            .map_err(|e| SaltyError::Crash(format!("do_handshake: Could not write-lock SaltyClient: {}", e)))?;
+        abort_handshake_state(&mut salty);
+        CloseReason::local(Some(CloseCode::WsClosingNormal), "Handshake aborted")
+    };
+    debug!("<-- Enqueuing WebSocket close message ({})", CloseCode::WsClosingNormal);
+    let close = OwnedMessage::Close(Some(CloseData {
+        status_code: CloseCode::WsClosingNormal.as_number(),
+        reason: CloseCode::WsClosingNormal.to_string(),
+    }));
+    Ok(PipelineAction::Future(boxed!(
+        client
+            .send(close)
+            .then(move |res| {
+                if let Err(e) = res {
+                    warn!("Could not send close message: {}", e);
+                }
+                Err(SaltyError::Closed(reason))
+            })
+    )))
+}
+
+/// Handle the next message from the server, or close the connection if the
+/// handshake was aborted.
+fn handle_received<S>(
+    received: Received<S>,
+    salty: &RwLock<SaltyClient>,
+    event_tx: &mpsc::UnboundedSender<Event>,
+) -> SaltyResult<PipelineAction<S>> where S: AsyncRead + AsyncWrite + 'static {
+    match received {
+        Received::Message(msg_option, client) => receive_ws_message(msg_option, client, salty, event_tx),
+        Received::Aborted(client) => close_aborted(client, salty),
+    }
+}
+
+/// Do the server and peer handshake.
+///
+/// This function returns a future. The future must be run in a Tokio reactor
+/// core for something to actually happen.
+///
+/// The future completes once the peer handshake is done, or if an error occurs.
+/// It returns the async websocket client instance. If the server closes the
+/// connection before the handshake is done, the future fails with
+/// `SaltyError::Closed`, containing the [`CloseReason`](struct.CloseReason.html).
+///
+/// The handshake can be aborted, see [`HandshakeFuture`](struct.HandshakeFuture.html).
+pub fn do_handshake<S>(
+    client: Client<S>,
+    salty: Arc<RwLock<SaltyClient>>,
+    event_tx: mpsc::UnboundedSender<Event>,
+    timeout: Option<Duration>,
+) -> HandshakeFuture<S> where S: AsyncRead + AsyncWrite + 'static {
+    if let Some(duration) = timeout {
+        if let Ok(mut s) = salty.write() {
+            s.start_handshake_timeout(duration);
+        }
+    }
+    let state_salty = Arc::clone(&salty);
+    let future_salty = Arc::clone(&salty);
+    let (abort_tx, abort_rx) = oneshot::channel();
+    let abort_rx = abort_rx.shared();
+
+    // Main loop
+    let main_loop = future::loop_fn(client, move |client| {
+
+        let salty = Arc::clone(&salty);
+
+        // Wait for pending handshakes to time out, if any
+        let (next_timeout, now, sleeper) = match salty.read() {
+            Ok(s) => (s.next_timeout(), s.now(), Arc::clone(&s.sleeper)),
+            Err(e) => return boxed!(future::err(SaltyError::Crash(
+                format!("do_handshake: Could not read-lock SaltyClient: {}", e)
+            ))),
+        };
+
+        // Take the next incoming message
+        let event_tx = event_tx.clone();
+        let next_message = NextMessage { abort: abort_rx.clone(), message: Some(client.into_future()) };
+        let next_action: BoxedFuture<PipelineAction<S>, SaltyError> = match next_timeout {
+            None => {
+                let salty = Arc::clone(&salty);
+                let event_tx = event_tx.clone();
+                boxed!(next_message
+                    // Map errors to our custom error type
+                    .map_err(|(e, _)| SaltyError::Network(format!("Could not receive message from server: {}", e)))
+
+                    // Decode messages, handle things like ping/pong and ignored messages
+                    .and_then(move |received| handle_received(received, &salty, &event_tx)))
+            },
+            Some(deadline) => {
+                let delay = if deadline > now { deadline - now } else { Duration::from_secs(0) };
+                let sleep = sleeper.sleep(::std::cmp::min(delay, MAX_TIMER_SLEEP));
+                let salty = Arc::clone(&salty);
+                let event_tx = event_tx.clone();
+
+                // An incoming message wins if both are ready
+                boxed!(next_message.select2(sleep).then(move |res| match res {
+                    Ok(Either::A((received, _))) => handle_received(received, &salty, &event_tx),
+                    Ok(Either::B((_, next_message))) => match next_message.into_inner() {
+                        Some(client) => handle_timeouts(client, &salty),
+                        None => Err(SaltyError::Crash("Server connection vanished while waiting for a message".into())),
+                    },
+                    Err(Either::A(((e, _), _))) =>
+                        Err(SaltyError::Network(format!("Could not receive message from server: {}", e))),
+                    Err(Either::B((e, _))) =>
+                        Err(SaltyError::Crash(format!("Handshake timer error: {}", e))),
+                }))
+            },
+        };
+
+        boxed!(next_action
+
+            // Process received signaling message
+            .and_then(move |pipeline_action| {
+                let (client, bbox) = match pipeline_action {
+                    PipelineAction::ByteBox(x) => x,
+                    PipelineAction::Future(f) => return f,
+                };
+
+                // Handle message bytes
+                let handle_actions = match salty.write() {
+                    Ok(mut s) => match s.handle_message(bbox) {
+                        Ok(actions) => actions,
+                        Err(e) => {
+                            s.state.closing(CloseReason::local(e.close_code(), e.to_string()));
+                            return close_with_error(client, e);
+                        },
+                    },
+                    Err(e) => return boxed!(future::err(SaltyError::Crash(
+                        format!("do_handshake: Could not write-lock SaltyClient: {}", e)
+                    ))),
+                };
+
+                // Extract messages that should be sent back to the server
+                let (replies, handle_actions) = handle_actions.split_replies();
+                let messages: Vec<OwnedMessage> = replies
+                    .into_iter()
+                    .map(|bbox| OwnedMessage::Binary(bbox.into_bytes()))
+                    .collect();
+                let mut handshake_done = false;
+                for action in handle_actions {
+                    match action {
+                        HandleAction::Reply(_) => return boxed!(future::err(
+                            SaltyError::Crash("Reply was not split off".into())
+                        )),
+                        HandleAction::StartTimer(..) | HandleAction::CancelTimer(_) => return boxed!(future::err(
+                            SaltyError::Crash("Timer action was not handled by the client".into())
+                        )),
+                        HandleAction::HandshakeDone => handshake_done = true,
+                        HandleAction::TaskMessage(_) => return boxed!(future::err(
+                            SaltyError::Crash("Received task message during handshake".into())
+                        )),
+                        HandleAction::Event(e) => {
+                            // Notify the user about event
+                            if event_tx.unbounded_send(e).is_err() {
+                                return boxed!(future::err(
+                                    SaltyError::Crash("Could not send event through channel".into())
+                                ));
+                            }
+                        },
+                    }
+                }
+
+                macro_rules! loop_action {
+                    ($client:expr) => {
+                        if handshake_done {
+                            Loop::Break($client)
+                        } else {
+                            Loop::Continue($client)
+                        }
+                    }
+                };
+
+                // If there are enqueued messages, send them
+                if messages.is_empty() {
+                    boxed!(future::ok(loop_action!(client)))
+                } else {
+                    for message in &messages {
+                        debug!("Sending {} bytes", message.size());
+                    }
+                    let outbox = stream::iter_ok::<_, WebSocketError>(messages);
+                    let future = send_all::new(client, outbox)
+                        .map_err(move |e| SaltyError::Network(format!("Could not send message: {}", e)))
+                        .map(move |(client, _)| {
+                            trace!("Sent all messages");
+                            loop_action!(client)
+                        });
+                    boxed!(future)
+                }
+            }))
+    });
+
+    // A failed handshake ends the connection. Either way, the next
+    // handshake may start.
+    let handshake = main_loop.then(move |res| {
+        if let Ok(mut s) = state_salty.write() {
+            if res.is_err() {
+                s.state.closed(None);
+            }
+            s.handshake_permit = None;
+        }
+        res
+    });
+
+    HandshakeFuture {
+        inner: Some(boxed!(handshake)),
+        abort: AbortHandle { tx: Arc::new(Mutex::new(Some(abort_tx))) },
+        salty: future_salty,
+    }
+}
+
+/// Hand the decryption of incoming task messages off to a thread pool with
+/// `workers` threads.
+///
+/// The messages are validated in the order they arrive. Since the returned
+/// stream is buffered in order, the decrypted messages are still passed on
+/// in that order.
+fn decrypt_on_pool<S>(
+    decoded: S,
+    salty: Arc<RwLock<SaltyClient>>,
+    pool: Arc<ThreadPool>,
+    workers: usize,
+) -> impl Stream<Item=WsMessageDecoded, Error=SaltyError>
+    where S: Stream<Item=WsMessageDecoded, Error=SaltyError>
+{
+    decoded
+        .map(move |msg| -> BoxedFuture<WsMessageDecoded, SaltyError> {
+            let bbox = match msg {
+                WsMessageDecoded::ByteBox(bbox) => bbox,
+                other => return boxed!(future::ok(other)),
+            };
+            let prepared = match salty.write() {
+                Ok(mut s) => s.prepare_message(bbox),
+                Err(e) => return boxed!(future::err(
+                    SaltyError::Crash(format!("task_loop/decrypt: Could not write-lock SaltyClient: {}", e))
+                )),
+            };
+            match prepared {
+                Ok(PreparedMessage::Sequential(bbox)) => boxed!(future::ok(WsMessageDecoded::ByteBox(bbox))),
+                Ok(PreparedMessage::Decrypt(job)) => boxed!(
+                    pool.spawn_handle(future::lazy(move || Ok(job.run())))
+                        .map(WsMessageDecoded::Decrypted)
+                ),
+                Ok(PreparedMessage::Handled(actions)) => boxed!(future::ok(WsMessageDecoded::Handled(actions))),
+                Err(e) => boxed!(future::err(e.into())),
+            }
+        })
+        .buffered(workers * 2)
+}
+
+/// Handle a message carrying a `ByteBox` in the task loop.
+fn handle_decoded(salty: &mut SaltyClient, msg: WsMessageDecoded) -> SignalingResult<HandleActions> {
+    match msg {
+        WsMessageDecoded::ByteBox(bbox) => salty.handle_message(bbox),
+        WsMessageDecoded::Decrypted(decrypted) => salty.handle_decrypted(decrypted),
+        WsMessageDecoded::Handled(actions) => Ok(actions),
+        other => Err(SignalingError::Crash(format!("Cannot handle {:?} as signaling message", other))),
+    }
+}
+
+/// Start the task loop.
+///
+/// Only call this function once you have finished the handshake!
+///
+/// The returned future resolves with the [`CloseReason`](struct.CloseReason.html)
+/// once the connection has ended. The stream of incoming task messages ends
+/// at the same time.
+#[cfg_attr(feature="cargo-clippy", allow(needless_pass_by_value))]
+pub fn task_loop<S>(
+    client: Client<S>,
+    salty: Arc<RwLock<SaltyClient>>,
+    event_tx: mpsc::UnboundedSender<Event>,
+) -> Result<(
+    Arc<Mutex<BoxedTask>>,
+    impl Future<Item=CloseReason, Error=SaltyError>,
+), SaltyError> where S: AsyncRead + AsyncWrite + 'static {
+    let task_name = salty
+        .read()
+        .ok()
+        .and_then(|salty| salty.task())
+        .and_then(|task| match task.lock() {
+            Ok(t) => Some(t.name()),
+            Err(_) => None,
+        })
+        .unwrap_or_else(|| "Unknown".into());
+    info!("Starting task loop for task {}", task_name);
+
+    let salty = Arc::clone(&salty);
+
+    // Split websocket connection into sink/stream, replacing the codec if
+    // large messages must be fragmented
+    let max_frame_size = salty
+        .read()
+        .map_err(|e| SaltyError::Crash(format!("Could not read-lock SaltyClient: {}", e)))?
+        .max_frame_size;
+    let (ws_sink, ws_stream): (WsSink, WsStream) = match max_frame_size {
+        Some(size) => {
+            let (sink, stream) = fragment::fragmenting(client, size).split();
+            (Box::new(sink), Box::new(stream))
+        },
+        None => {
+            let (sink, stream) = client.split();
+            (Box::new(sink), Box::new(stream))
+        },
+    };
+
+    // Create communication channels
+    //
+    // The outgoing channels are bounded, so that a slow WebSocket connection
+    // applies back-pressure to the task instead of buffering without limit.
+    // Messages on the raw outgoing channel are batched, so that all replies
+    // to a single incoming message are sent without interleaving.
+    let (outgoing_buffer, clock) = {
+        let salty = salty
+            .read()
+            .map_err(|e| SaltyError::Crash(format!("Could not read-lock SaltyClient: {}", e)))?;
+        (salty.outgoing_buffer, Arc::clone(&salty.signaling.common().clock))
+    };
+    let (outgoing_tx, outgoing_rx) = mpsc::channel::<TaskMessage>(outgoing_buffer);
+    let (path_tx, path_rx) = mpsc::channel::<Outgoing>(outgoing_buffer);
+    let (raw_outgoing_tx, raw_outgoing_rx) = mpsc::channel::<OutgoingBatch>(outgoing_buffer);
+    let (incoming_tx, incoming_rx) = mpsc::unbounded::<TaskMessage>();
+    let (disconnect_tx, disconnect_rx) = oneshot::channel::<Option<CloseCode>>();
+
+    // Future for handling the timers of the client, like the heartbeats.
+    // The deadlines are tracked by the client, it notifies this future
+    // through `timers_rx` whenever they change.
+    let (timers_tx, timers_rx) = mpsc::unbounded::<()>();
+    salty
+        .write()
+        .map_err(|e| SaltyError::Crash(format!("Could not write-lock SaltyClient: {}", e)))?
+        .timers_changed = Some(timers_tx);
+    let timers = {
+        let salty = Arc::clone(&salty);
+        let raw_outgoing_tx = raw_outgoing_tx.clone();
+        let event_tx = event_tx.clone();
+        future::loop_fn(timers_rx, move |timers_rx| {
+            let (next_timeout, now, sleeper) = match salty.read() {
+                Ok(s) => (s.next_timeout(), s.now(), Arc::clone(&s.sleeper)),
+                Err(e) => return boxed!(future::err(SaltyError::Crash(
+                    format!("task_loop/timers: Could not read-lock SaltyClient: {}", e)
+                ))),
+            };
+            let changed = timers_rx.into_future();
+            let deadline = match next_timeout {
+                Some(deadline) => deadline,
+                None => return boxed!(changed
+                    .map_err(|_| SaltyError::Crash("Could not read from timers channel".into()))
+                    .map(|(changed, timers_rx)| match changed {
+                    Some(()) => Loop::Continue(timers_rx),
+                    None => Loop::Break(()),
+                })),
+            };
+            let delay = if deadline > now { deadline - now } else { Duration::from_secs(0) };
+            let sleep = sleeper.sleep(::std::cmp::min(delay, MAX_TIMER_SLEEP));
+            let salty = Arc::clone(&salty);
+            let raw_outgoing_tx = raw_outgoing_tx.clone();
+            let event_tx = event_tx.clone();
+            boxed!(changed.select2(sleep).then(move |res| -> BoxedFuture<Loop<(), _>, SaltyError> {
+                let timers_rx = match res {
+                    Ok(Either::A(((Some(()), timers_rx), _))) => return boxed!(future::ok(Loop::Continue(timers_rx))),
+                    Ok(Either::A(((None, _), _))) => return boxed!(future::ok(Loop::Break(()))),
+                    Ok(Either::B((_, changed))) => match changed.into_inner() {
+                        Some(timers_rx) => timers_rx,
+                        None => return boxed!(future::err(SaltyError::Crash("Timers channel vanished".into()))),
+                    },
+                    Err(Either::A(_)) => return boxed!(future::err(
+                        SaltyError::Crash("Could not read from timers channel".into())
+                    )),
+                    Err(Either::B((e, _))) => return boxed!(future::err(
+                        SaltyError::Crash(format!("Timer error: {}", e))
+                    )),
+                };
+                let handled = salty
+                    .write()
+                    .map_err(|e| SaltyError::Crash(format!("task_loop/timers: Could not write-lock SaltyClient: {}", e)))
+                    .and_then(|mut s| {
+                        let now = s.now();
+                        s.handle_timeouts(now).map_err(SaltyError::from)
+                    });
+                let (replies, handle_actions) = match handled {
+                    Ok(actions) => actions.split_replies(),
+                    Err(e) => return boxed!(future::err(e)),
+                };
+                for action in handle_actions {
+                    match action {
+                        HandleAction::Event(e) => if event_tx.unbounded_send(e).is_err() {
+                            return boxed!(future::err(SaltyError::Crash("Could not send event through channel".into())));
+                        },
+                        other => return boxed!(future::err(SaltyError::Crash(
+                            format!("Got unexpected {:?} action from timeout", other)
+                        ))),
+                    }
+                }
+                if replies.is_empty() {
+                    return boxed!(future::ok(Loop::Continue(timers_rx)));
+                }
+                debug!("<-- Enqueuing {} message(s) from timeouts", replies.len());
+                let batch = OutgoingBatch::from(replies.into_iter().map(|bbox| OwnedMessage::Binary(bbox.into_bytes())).collect::<Vec<_>>());
+                boxed!(raw_outgoing_tx
+                    .send(batch)
+                    .map(move |_| Loop::Continue(timers_rx))
+                    .map_err(|e| SaltyError::Network(format!("Could not enqueue message: {}", e))))
+            }))
+        })
+    };
+
+    // Stream future for sending the rekey messages requested with `SaltyClient::rekey`
+    let (rekey_tx, rekey_rx) = mpsc::unbounded();
+    salty
+        .write()
+        .map_err(|e| SaltyError::Crash(format!("Could not write-lock SaltyClient: {}", e)))?
+        .rekey_requests = Some(rekey_tx);
+    let rekeying = {
+        let salty = Arc::clone(&salty);
+        rekey_rx
+            .map_err(|_| SaltyError::Crash("Rekey request channel error".into()))
+            .and_then(move |done| salty
+                .write()
+                .map(|mut s| s.start_rekey(done))
+                .map_err(|e| SaltyError::Crash(format!("task_loop/rekey: Could not write-lock SaltyClient: {}", e))))
+            .filter_map(|bytes| bytes.map(|bytes| {
+                debug!("<-- Enqueuing rekey message to peer");
+                OutgoingBatch::from(vec![OwnedMessage::Binary(bytes)])
+            }))
+            .forward(raw_outgoing_tx.clone().sink_map_err(|e| SaltyError::Network(format!("Could not enqueue rekey message: {}", e))))
+            .and_then(|_| future::empty())
+    };
+
+    // Stream of decoded incoming WebSocket messages
+    let (decryption_workers, shared_pool) = salty
+        .read()
+        .map(|s| (s.decryption_workers, s.shared.as_ref().and_then(SharedResources::decryption_pool)))
+        .map_err(|e| SaltyError::Crash(format!("Could not read-lock SaltyClient: {}", e)))?;
+    let decoded = ws_stream
+
+        // Map errors to our custom error type
+        // TODO: Take a look at `sink_from_err`
+        .map_err(|e| SaltyError::Network(format!("Could not receive message from server: {}", e)))
+
+        // Decode messages
+        .and_then(decode_ws_message);
+    let pool = match shared_pool {
+        Some(pool) => Some(pool),
+        None if decryption_workers == 0 => None,
+        None => Some((Arc::new(ThreadPoolBuilder::new().pool_size(decryption_workers).build()), decryption_workers)),
+    };
+    let decoded: Box<dyn Stream<Item=WsMessageDecoded, Error=SaltyError>> = match pool {
+        Some((pool, workers)) => Box::new(decrypt_on_pool(decoded, Arc::clone(&salty), pool, workers)),
+        None => Box::new(decoded),
+    };
+
+    // Stream future for processing incoming WebSocket messages
+    let reader = decoded
+
+        // Wrap errors in a result type
+        .map_err(Err)
+
+        // Handle each incoming message.
+        //
+        // The closure passed to `for_each` must return:
+        //
+        // * `future::ok(())` to continue processing the stream
+        // * `future::err(Ok(()))` to stop the loop without an error
+        // * `future::err(Err(_))` to stop the loop with an error
+        .for_each({
+            let salty = Arc::clone(&salty);
+            let raw_outgoing_tx = raw_outgoing_tx.clone();
+            let event_tx = event_tx.clone();
+            move |msg: WsMessageDecoded| {
+                let raw_outgoing_tx = raw_outgoing_tx.clone();
+                match msg {
+                    WsMessageDecoded::ByteBox(_) | WsMessageDecoded::Decrypted(_) | WsMessageDecoded::Handled(_) => {
+                        // Handle message bytes
+                        let handle_actions = match salty.write() {
+                            Ok(mut s) => match handle_decoded(&mut s, msg) {
+                                Ok(actions) => actions,
+                                Err(e) => return boxed!(future::err(Err(e.into()))),
+                            },
+                            Err(e) => return boxed!(future::err(Err(
+                                SaltyError::Crash(format!("task_loop/reader: Could not write-lock SaltyClient: {}", e))
+                            ))),
+                        };
+
+                        // Extract messages that should be sent back to the server
+                        let (replies, handle_actions) = handle_actions.split_replies();
+                        let out_messages: Vec<OwnedMessage> = replies
+                            .into_iter()
+                            .map(|bbox| OwnedMessage::Binary(bbox.into_bytes()))
+                            .collect();
+                        let mut in_messages: Vec<TaskMessage> = vec![];
+                        let mut close_stream = false;
+                        for action in handle_actions {
+                            info!("Action: {:?}", action);
+                            match action {
+                                HandleAction::Reply(_) => return boxed!(future::err(Err(
+                                    SaltyError::Crash("Reply was not split off".into())
+                                ))),
+                                HandleAction::StartTimer(..) | HandleAction::CancelTimer(_) => return boxed!(future::err(Err(
+                                    SaltyError::Crash("Timer action was not handled by the client".into())
+                                ))),
+                                HandleAction::TaskMessage(msg) => {
+                                    if let TaskMessage::Close(_) = msg {
+                                        close_stream = true;
+                                    }
+
+                                    // Forward message to user
+                                    in_messages.push(msg);
+                                },
+                                HandleAction::Event(e) => {
+                                    // Notify the user about event
+                                    match event_tx.unbounded_send(e) {
+                                        Ok(_) => {},
+                                        Err(_) => return boxed!(future::err(Err(
+                                            SaltyError::Crash("Could not send event through channel".into())
+                                        ))),
+                                    }
+                                },
+                                HandleAction::HandshakeDone => return boxed!(future::err(Err(
+                                    SaltyError::Crash("Got HandleAction::HandshakeDone in task loop".into())
+                                ))),
+                            }
+                        }
+
+                        // Handle outgoing queued messages
+                        let out_future = if out_messages.is_empty() {
+                            boxed!(future::ok(()))
+                        } else {
+                            let msg_count = out_messages.len();
+                            let future = raw_outgoing_tx
+                                .send(out_messages.into())
+                                .map(move |_| debug!("Sent {} messages", msg_count))
+                                .map_err(|e| Err(SaltyError::Network(format!("Sink error: {}", e))));
+                            boxed!(future)
+                        };
+
+                        // Handle incoming queued messages
+                        let in_future = if in_messages.is_empty() {
+                            boxed!(future::ok(()))
+                        } else {
+                            let msg_count = in_messages.len();
+                            let inbox = stream::iter_ok::<_, Result<(), SaltyError>>(in_messages);
+                            let future = incoming_tx
+                                .clone()
+                                .sink_map_err(|e| Err(SaltyError::Crash(format!("Channel error: {}", e))))
+                                .send_all(inbox)
+                                .map(move |_| debug!("Received {} task messages", msg_count));
+                            boxed!(future)
+                        };
+
+                        boxed!(
+                            out_future
+                                .join(in_future)
+                                .and_then(move |_| if close_stream {
+                                    // Stop processing stream
+                                    Err(Ok(()))
+                                } else {
+                                    // Continue processing stream
+                                    Ok(())
+                                })
+                        )
+                    },
+                    WsMessageDecoded::Ping(payload) => {
+                        let pong = OwnedMessage::Pong(payload);
+                        let future = raw_outgoing_tx
+                            .send(vec![pong].into())
+                            .map(|_| debug!("<-- Enqueuing pong message"))
+                            .map_err(|e| Err(SaltyError::Network(format!("Could not enqueue pong message: {}", e))));
+                        boxed!(future)
+                    },
+                    WsMessageDecoded::Close(code) => {
+                        match salty.write() {
+                            Ok(mut s) => s.state.closed(code),
+                            Err(e) => return boxed!(future::err(Err(
+                                SaltyError::Crash(format!("task_loop/reader: Could not write-lock SaltyClient: {}", e))
+                            ))),
+                        }
+                        boxed!(future::ok(()))
+                    },
+                    WsMessageDecoded::Ignore => boxed!(future::ok(())),
+                }
+            }
+        })
+
+        .or_else(|res| match res {
+            Ok(_) => boxed!(future::ok(())),
+            Err(e) => boxed!(future::err(e))
+        })
+
+        .select(
+            disconnect_rx
+                .and_then({
+                    let outgoing_tx = outgoing_tx.clone();
+                    let salty = Arc::clone(&salty);
+                    move |reason_opt: Option<CloseCode>| {
+                        info!("Disconnecting");
+                        let reason = reason_opt.unwrap_or(CloseCode::WsGoingAway);
+                        if let Ok(mut s) = salty.write() {
+                            s.state.closing(CloseReason::local(Some(reason), "Disconnected by the task"));
+                            s.lifecycle.closing_by_task();
+                        }
+
+                        // Send close message
+                        outgoing_tx
+                            .send(TaskMessage::Close(reason))
+                            .map(|_| ())
+                            .or_else(|e| {
+                                warn!("Could not enqueue close message: {}", e);
+                                future::ok(())
+                            })
+                    }
+                })
+                .or_else(|_| {
+                    warn!("Waiting for disconnect_rx failed");
+                    future::ok(())
+                })
+        )
+
+        .map(|_| ())
+        .map_err(|(e, _next)| e)
+
+        // Stop handling timeouts and sending rekey messages once the reader is done
+        .select(timers.select(rekeying).map(|_| ()).map_err(|(e, _next)| e))
+
+        .map(|_| debug!("† Reader future done"))
+        .map_err(|(e, _next)| e)
+
+        // Close the task once nothing is received anymore, so that it drops
+        // its channels and the transformer and writer futures can finish
+        .then({
+            let salty = Arc::clone(&salty);
+            move |res| {
+                if let Ok(mut s) = salty.write() {
+                    s.tear_down(false);
+                }
+                res
+            }
+        });
+
+    // Transform future that sends values from the outgoing channel to the raw outgoing channel
+    let transformer = outgoing_rx
+
+        // Merge with the messages sent through an attached task handle
+        .map(Outgoing::from)
+        .select(path_rx)
+
+        // Wrap errors in result
+        .map_err(|_| Err(()))
+
+        // Encode and encrypt values.
+        .and_then({
+            let salty = Arc::clone(&salty);
+            let event_tx = event_tx.clone();
+            move |outgoing: Outgoing| {
+                trace!("Transforming outgoing message: {:?}", outgoing);
+                let Outgoing { msg, deadline, delivered } = outgoing;
+
+                // Get reference to SaltyClient
+                // TODO: Can we do something about the errors here?
+                let mut salty_mut = salty.write().map_err(|_| Err(()))?;
+
+                // Drop expired messages
+                let expired = match deadline {
+                    Some(deadline) => salty_mut.now() >= deadline,
+                    None => false,
+                };
+                if expired {
+                    info!("Dropping expired outgoing message of type {:?}", msg.message_type());
+                    if event_tx.unbounded_send(Event::MessageExpired(msg)).is_err() {
+                        warn!("Could not send event through channel");
+                        return Err(Err(()));
+                    }
+                    return Ok(stream::iter_result(vec![]));
+                }
+
+                // When we receive a `Value` message, simply send it as-is.
+                // But when we receive a `Close` message, also insert a WebSocket close message.
+                match msg {
+                    TaskMessage::Value(map) => {
+                        // Create message
+                        let val = Value::Map(
+                            map
+                                .into_iter()
+                                .map(|(k, v)| (Value::from(k), v))
+                                .collect()
+                        );
+                        // Encrypt message
+                        salty_mut
+                            .encrypt_task_message_or_renew(val)
+                            .map(|bytes_opt| match bytes_opt {
+                                Some(bytes) => {
+                                    debug!("<-- Enqueuing task message to peer");
+                                    stream::iter_result::<_, OutgoingBatch, Result<(), ()>>(
+                                        vec![
+                                            Ok(OutgoingBatch { messages: vec![OwnedMessage::Binary(bytes)], delivered })
+                                        ]
+                                    )
+                                },
+                                None => stream::iter_result(session_renewal_close()),
+                            })
+                            .map_err(|e| {
+                                warn!("Could not encrypt task message: {}", e);
+                                Err(())
+                            })
+                    },
+                    TaskMessage::Application(data) => {
+                        let mut map = vec![];
+                        map.push((Value::String("type".into()), Value::String("application".into())));
+                        map.push((Value::String("data".into()), data));
+                        let val = Value::Map(map);
+                        salty_mut
+                            .encrypt_task_message_or_renew(val)
+                            .map(|bytes_opt| match bytes_opt {
+                                Some(bytes) => {
+                                    debug!("<-- Enqueuing application message to peer");
+                                    stream::iter_result::<_, OutgoingBatch, Result<(), ()>>(
+                                        vec![
+                                            Ok(OutgoingBatch { messages: vec![OwnedMessage::Binary(bytes)], delivered })
+                                        ]
+                                    )
+                                },
+                                None => stream::iter_result(session_renewal_close()),
+                            })
+                            .map_err(|e| {
+                                warn!("Could not encrypt task message: {}", e);
+                                Err(())
+                            })
+                    },
+                    TaskMessage::Close(reason) => {
+                        salty_mut.state.closing(CloseReason::local(Some(reason), "Closed by the task"));
+                        salty_mut.lifecycle.closing_by_task();
+
+                        // Drop the responders that are still in the
+                        // handshake, so that they don't wait for a timeout
+                        let mut messages: Vec<OwnedMessage> = match salty_mut.encrypt_drop_pending_peers() {
+                            Ok(drops) => drops.into_iter().map(OwnedMessage::Binary).collect(),
+                            Err(e) => {
+                                warn!("Could not encrypt drop-responder messages: {}", e);
+                                vec![]
+                            },
+                        };
+                        if !messages.is_empty() {
+                            debug!("<-- Enqueuing {} drop-responder messages to server", messages.len());
+                        }
+
+                        // Create and encrypt SaltyRTC close message,
+                        // followed by a WebSocket close message
+                        salty_mut
+                            .encrypt_close_message(reason)
+                            .map(move |bytes| {
+                                debug!("<-- Enqueuing SaltyRTC close message to peer");
+                                debug!("<-- Enqueuing WebSocket close message to peer");
+                                messages.push(OwnedMessage::Binary(bytes));
+                                messages.push(OwnedMessage::Close(Some(CloseData {
+                                    status_code: reason.as_number(),
+                                    reason: reason.to_string(),
+                                })));
+                                stream::iter_result::<_, OutgoingBatch, Result<(), ()>>(
+                                    vec![
+                                        Ok(OutgoingBatch {
+                                            messages,
+                                            delivered,
+                                        }),
+                                        Err(Ok(())), // Terminate transformer future
+                                    ]
+                                )
+                            })
+                            .map_err(|e| {
+                                warn!("Could not encrypt SaltyRTC close message: {}", e);
+                                Err(())
+                            })
+                    },
+                }
+            }
+        })
+
+        .flatten()
+
+        // Forward to raw queue
+        .forward(raw_outgoing_tx.sink_map_err(|_| Err(())))
+
+        // Ignore stream/sink
+        .map(|(_, _)| debug!("† Transformer future done"))
+
+        // Flatten errors
+        .or_else(|e| e.map_err(|_| SaltyError::Crash("Transformer future error (TODO)".into())));
+
+    // Sink future for sending messages from the raw outgoing channel through the WebSocket
+    let writer = Writer::new(raw_outgoing_rx, ws_sink)
+        .map(|_| debug!("† Writer future done"));
+
+    // The task loop is finished when all futures are resolved.
+    let task_loop = boxed!(
+        future::ok(())
+        .and_then(|_| reader.join(transformer).join(writer).map(|_| ()))
+        .and_then(|_| { info!("† Task loop future done"); future::ok(()) })
+        .then({
+            let salty = Arc::clone(&salty);
+            move |res: SaltyResult<()>| {
+                let reason = match salty.write() {
+                    Ok(mut s) => {
+                        s.tear_down(true);
+                        s.close_reason().cloned()
+                    },
+                    Err(_) => None,
+                };
+                res.and_then(|_| reason.ok_or_else(|| SaltyError::Crash("task_loop: Close reason not set".into())))
+            }
+        })
+    );
+
+    // Get reference to task
+    let task = match salty.write() {
+        Ok(mut salty) => {
+            let task = salty
+                .task()
+                .ok_or_else(|| SaltyError::Crash("Task not set".into()))?;
+            salty.lifecycle.started();
+            task
+        },
+        Err(e) => return Err(
+            SaltyError::Crash(format!("task_loop/task: Could not write-lock SaltyClient: {}", e))
+        ),
+    };
+
+    // Notify task that it can now take over
+    {
+        let mut task = task.lock()
+            .map_err(|e| SaltyError::Crash(format!("Could not lock task mutex: {}", e)))?;
+        task.attach(SendPath::new(path_tx, clock));
+        task.start(outgoing_tx, incoming_rx, disconnect_tx);
+    }
+    if let Ok(mut salty) = salty.write() {
+        let now = salty.now();
+        salty.signaling.common_mut().stats.task_started(now);
+    }
+
+    // Return reference to task and the task loop future
+    Ok((task, task_loop))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::collections::HashMap;
+    use std::io::{self, Cursor, Read, Write};
+    use std::time::Instant;
+
+    use bytes::BytesMut;
+    use failure::Error as FailureError;
+    use futures::StartSend;
+    use futures::sync::mpsc::{Sender, UnboundedReceiver};
+    use tokio_io::codec::{Decoder, Encoder};
+    use websocket::codec::ws::{Context, MessageCodec};
+
+    use crate::boxes::OpenBox;
+    use crate::crypto_types::KeyPair;
+    use crate::protocol::{Cookie, Nonce, Signaling};
+    use crate::protocol::csn::CombinedSequenceSnapshot;
+    use crate::protocol::messages::{Message, ServerAuth, ServerHello};
+    use crate::protocol::tests::simulated::{create_peers, handshake, TestServer};
+    use crate::clock::{ManualClock, SystemClock, ThreadSleeper};
+    use crate::protocol::types::Address;
+    use crate::tasks::{MessageId, Task, TaskHandle};
+    use crate::test_helpers::DummyTask;
+
+    use super::*;
+
+    const PATH: &str = "f637d7fff53defe8db111b17b2c445f7888a83c13dc40d7ff8449f700910f01f";
+
+    #[test]
+    fn validate_signaling_path() {
+        let url = Url::parse(&format!("wss://server.example:443/{}", PATH)).unwrap();
+        assert_eq!(validate_path(&url, PATH), Ok(()));
+
+        // The host contains a path
+        let url = Url::parse(&format!("wss://server.example/saltyrtc:443/{}", PATH)).unwrap();
+        assert!(validate_path(&url, PATH).is_err());
+
+        // Overlong path
+        let path = format!("{}00", PATH);
+        let url = Url::parse(&format!("wss://server.example:443/{}", path)).unwrap();
+        match validate_path(&url, &path) {
+            Err(ConnectError::InvalidPath(_)) => {},
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejected_websocket_handshake() {
+        let error = WebSocketError::ResponseError("Status code must be Switching Protocols");
+        let head = Mutex::new(b"HTTP/1.1 404".to_vec());
+        assert_eq!(
+            websocket_connect_error(&error, "server.example:443", &head),
+            SaltyError::Connect(ConnectError::Rejected(404))
+        );
+
+        // Without a status, the error is reported as network error
+        let head = Mutex::new(vec![]);
+        match websocket_connect_error(&error, "server.example:443", &head) {
+            SaltyError::Network(_) => {},
+            other => panic!("Unexpected error: {:?}", other),
+        }
+    }
+
+    /// A stream that answers with a canned HTTP response.
+    struct CannedStream(Cursor<Vec<u8>>);
+
+    impl Read for CannedStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for CannedStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncRead for CannedStream {}
+
+    impl AsyncWrite for CannedStream {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(().into())
+        }
+    }
+
+    #[test]
+    fn connect_on_rejected() {
+        let salty = Arc::new(RwLock::new(SaltyClient::build(KeyPair::new())
+            .add_task(Box::new(DummyTask::new(1)))
+            .initiator()
+            .unwrap()));
+        let stream = CannedStream(Cursor::new(b"HTTP/1.1 403 Forbidden\r\n\r\n".to_vec()));
+        let (future, _events) = connect_on(stream, "server.example", 443, Arc::clone(&salty)).unwrap();
+        assert_eq!(future.wait().err(), Some(SaltyError::Connect(ConnectError::Rejected(403))));
+        assert_eq!(salty.read().unwrap().connection_state(), Some(ConnectionState::Closed(None)));
+    }
+
+    /// An in-memory connection to a fake server.
+    ///
+    /// Reads block until the server sent something.
+    #[derive(Clone, Default)]
+    struct MockStream {
+        incoming: Arc<Mutex<Vec<u8>>>,
+        outgoing: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl MockStream {
+        /// Send a binary message from the server.
+        fn send(&self, bytes: Vec<u8>) {
+            self.send_message(OwnedMessage::Binary(bytes));
+        }
+
+        /// Send a WebSocket message from the server.
+        fn send_message(&self, msg: OwnedMessage) {
+            let mut buf = BytesMut::new();
+            MessageCodec::default(Context::Server).encode(msg, &mut buf).unwrap();
+            self.incoming.lock().unwrap().extend_from_slice(&buf);
+        }
+
+        /// Return the messages sent by the client.
+        fn received(&self) -> Vec<OwnedMessage> {
+            let mut buf = BytesMut::from(self.outgoing.lock().unwrap().split_off(0));
+            let mut codec = MessageCodec::default(Context::Server);
+            let mut messages = vec![];
+            while let Some(msg) = codec.decode(&mut buf).unwrap() {
+                messages.push(msg);
+            }
+            messages
+        }
+    }
+
+    impl Read for MockStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut incoming = self.incoming.lock().unwrap();
+            if incoming.is_empty() {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let len = buf.len().min(incoming.len());
+            buf[..len].copy_from_slice(&incoming[..len]);
+            incoming.drain(..len);
+            Ok(len)
+        }
+    }
+
+    impl Write for MockStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.outgoing.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncRead for MockStream {}
+
+    impl AsyncWrite for MockStream {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(().into())
+        }
+    }
+
+    #[allow(deprecated)]
+    fn mock_client(stream: &MockStream) -> Client<MockStream> {
+        stream.clone().framed(MessageCodec::default(Context::Client))
+    }
+
+    fn poll<F: Future>(future: &mut executor::Spawn<F>) -> Poll<F::Item, F::Error> {
+        future.poll_future_notify(&NotifyHandle::from(Arc::new(NoopNotify)), 0)
+    }
+
+    /// Assert that the client closed the connection with close code 1000.
+    fn assert_aborted(stream: &MockStream, salty: &RwLock<SaltyClient>) {
+        let messages = stream.received();
+        match messages.last() {
+            Some(OwnedMessage::Close(Some(data))) => assert_eq!(data.status_code, 1000),
+            other => panic!("Expected close message, got {:?}", other),
+        }
+        let salty = salty.read().unwrap();
+        assert_eq!(salty.connection_state(), Some(ConnectionState::Closed(Some(CloseCode::WsClosingNormal))));
+        assert!(salty.state_summary().peers.is_empty());
+        assert!(salty.handshake_permit.is_none());
+    }
+
+    #[test]
+    fn abort_during_server_handshake() {
+        let salty = Arc::new(RwLock::new(SaltyClient::build(KeyPair::new())
+            .add_task(Box::new(DummyTask::new(1)))
+            .initiator()
+            .unwrap()));
+        let stream = MockStream::default();
+        let (event_tx, _event_rx) = mpsc::unbounded();
+        let handshake = do_handshake(mock_client(&stream), Arc::clone(&salty), event_tx, None);
+        let abort = handshake.abort_handle();
+        let mut handshake = executor::spawn(handshake);
+        assert!(poll(&mut handshake).unwrap().is_not_ready());
+
+        abort.abort();
+        match poll(&mut handshake) {
+            Err(SaltyError::Closed(reason)) => assert_eq!(reason.code, Some(CloseCode::WsClosingNormal)),
+            other => panic!("Unexpected result: {:?}", other.map(|_| ())),
+        }
+        assert_aborted(&stream, &salty);
+    }
+
+    #[test]
+    fn drop_during_peer_handshake() {
+        let keypair = KeyPair::new();
+        let public_key = *keypair.public_key();
+        let salty = Arc::new(RwLock::new(SaltyClient::build(keypair)
+            .add_task(Box::new(DummyTask::new(1)))
+            .initiator()
+            .unwrap()));
+        let stream = MockStream::default();
+        let (event_tx, _event_rx) = mpsc::unbounded();
+        let mut handshake = executor::spawn(do_handshake(mock_client(&stream), Arc::clone(&salty), event_tx, None));
+        assert!(poll(&mut handshake).unwrap().is_not_ready());
+
+        // Server handshake, announcing one responder
+        let server_keypair = KeyPair::new();
+        let server_cookie = Cookie::new([1; 16]);
+        let msg = ServerHello::new(*server_keypair.public_key()).into_message();
+        let nonce = Nonce::new(server_cookie.clone(), Address(0), Address(0), CombinedSequenceSnapshot::new(0, 1));
+        stream.send(OpenBox::<Message>::new(msg, nonce).encode().into_bytes());
+        assert!(poll(&mut handshake).unwrap().is_not_ready());
+        let client_cookie = match stream.received().pop() {
+            Some(OwnedMessage::Binary(bytes)) => ByteBox::from_vec(bytes).unwrap().nonce.cookie().clone(),
+            other => panic!("Expected client-auth message, got {:?}", other),
+        };
+        let msg = ServerAuth::for_initiator(client_cookie, None, vec![Address(2)]).into_message();
+        let nonce = Nonce::new(server_cookie, Address(0), Address(1), CombinedSequenceSnapshot::new(0, 2));
+        stream.send(OpenBox::<Message>::new(msg, nonce).encrypt(&server_keypair, &public_key).into_bytes());
+        assert!(poll(&mut handshake).unwrap().is_not_ready());
+        assert_eq!(salty.read().unwrap().connection_state(), Some(ConnectionState::PeerHandshake));
+        assert_eq!(salty.read().unwrap().state_summary().peers.len(), 1);
+
+        drop(handshake);
+        assert_aborted(&stream, &salty);
+    }
+
+    #[test]
+    fn initiator_timeout() {
+        let keypair = KeyPair::new();
+        let public_key = *keypair.public_key();
+        let clock = Arc::new(ManualClock::new());
+        let salty = Arc::new(RwLock::new(SaltyClient::build(keypair)
+            .add_task(Box::new(DummyTask::new(1)))
+            .with_clock(clock.clone())
+            .with_initiator_timeout(Some(Duration::from_secs(60)))
+            .responder_trusted(*KeyPair::new().public_key())
+            .unwrap()));
+        let stream = MockStream::default();
+        let (event_tx, event_rx) = mpsc::unbounded();
+        let mut handshake = executor::spawn(do_handshake(mock_client(&stream), Arc::clone(&salty), event_tx, None));
+        assert!(poll(&mut handshake).unwrap().is_not_ready());
+
+        // Server handshake, without an initiator
+        let server_keypair = KeyPair::new();
+        let server_cookie = Cookie::new([1; 16]);
+        let msg = ServerHello::new(*server_keypair.public_key()).into_message();
+        let nonce = Nonce::new(server_cookie.clone(), Address(0), Address(0), CombinedSequenceSnapshot::new(0, 1));
+        stream.send(OpenBox::<Message>::new(msg, nonce).encode().into_bytes());
+        assert!(poll(&mut handshake).unwrap().is_not_ready());
+        let client_cookie = match stream.received().pop() {
+            Some(OwnedMessage::Binary(bytes)) => ByteBox::from_vec(bytes).unwrap().nonce.cookie().clone(),
+            other => panic!("Expected client-auth message, got {:?}", other),
+        };
+        let msg = ServerAuth::for_responder(client_cookie, None, false).into_message();
+        let nonce = Nonce::new(server_cookie, Address(0), Address(2), CombinedSequenceSnapshot::new(0, 2));
+        stream.send(OpenBox::<Message>::new(msg, nonce).encrypt(&server_keypair, &public_key).into_bytes());
+        assert!(poll(&mut handshake).unwrap().is_not_ready());
+        let events: Vec<Event> = event_rx.take(2).collect().wait().unwrap();
+        assert_eq!(events, vec![Event::ServerHandshakeDone(false), Event::WaitingForInitiator]);
+
+        // The timeout is checked once the loop wakes up, e.g. for a ping
+        clock.advance(Duration::from_secs(59));
+        stream.send_message(OwnedMessage::Ping(vec![1]));
+        assert!(poll(&mut handshake).unwrap().is_not_ready());
+        clock.advance(Duration::from_secs(1));
+        stream.send_message(OwnedMessage::Ping(vec![2]));
+        match poll(&mut handshake) {
+            Err(SaltyError::Timeout) => {},
+            other => panic!("Unexpected result: {:?}", other.map(|_| ())),
+        }
+        match stream.received().last() {
+            Some(OwnedMessage::Close(Some(data))) => assert_eq!(data.status_code, 1000),
+            other => panic!("Expected close message, got {:?}", other),
+        }
+        assert_eq!(
+            salty.read().unwrap().connection_state(),
+            Some(ConnectionState::Closed(Some(CloseCode::WsClosingNormal)))
+        );
+    }
+
+    /// With the thread sleeper, the handshake times out without a Tokio
+    /// timer.
+    #[test]
+    fn handshake_timeout_with_thread_sleeper() {
+        let salty = Arc::new(RwLock::new(SaltyClient::build(KeyPair::new())
+            .add_task(Box::new(DummyTask::new(1)))
+            .with_sleeper(Arc::new(ThreadSleeper::new()))
+            .initiator()
+            .unwrap()));
+        let stream = MockStream::default();
+        let (event_tx, _event_rx) = mpsc::unbounded();
+        let timeout = Some(Duration::from_millis(50));
+        match do_handshake(mock_client(&stream), Arc::clone(&salty), event_tx, timeout).wait() {
+            Err(SaltyError::Timeout) => {},
+            other => panic!("Unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+
+    /// A sink that accepts all messages, but only flushes them once allowed.
+    #[derive(Clone, Default)]
+    struct ManualFlushSink {
+        pending: Arc<Mutex<Vec<OwnedMessage>>>,
+        flushed: Arc<Mutex<Vec<OwnedMessage>>>,
+        can_flush: Arc<Mutex<bool>>,
+    }
+
+    impl Sink for ManualFlushSink {
+        type SinkItem = OwnedMessage;
+        type SinkError = WebSocketError;
+
+        fn start_send(&mut self, item: OwnedMessage) -> StartSend<OwnedMessage, WebSocketError> {
+            self.pending.lock().unwrap().push(item);
+            Ok(AsyncSink::Ready)
+        }
+
+        fn poll_complete(&mut self) -> Poll<(), WebSocketError> {
+            if !*self.can_flush.lock().unwrap() {
+                return Ok(Async::NotReady);
+            }
+            let mut pending = self.pending.lock().unwrap();
+            self.flushed.lock().unwrap().extend(pending.drain(..));
+            Ok(Async::Ready(()))
+        }
+    }
+
+    /// Tracked messages are only delivered once the sink has been flushed.
+    #[test]
+    fn writer_notifies_after_flush() {
+        let mut handle = TaskHandle::new();
+        let (outgoing_tx, _outgoing_rx) = mpsc::channel(1);
+        let (disconnect_tx, _disconnect_rx) = oneshot::channel();
+        let (path_tx, path_rx) = mpsc::channel(1);
+        handle.attach(SendPath::new(path_tx, Arc::new(SystemClock)));
+        handle.start(outgoing_tx, disconnect_tx);
+        let mut delivery = executor::spawn(handle.send_tracked(TaskMessage::Application(Value::Nil)).unwrap());
+        let delivered = path_rx.wait().next().unwrap().unwrap().delivered;
+
+        let sink = ManualFlushSink::default();
+        let (mut batch_tx, batch_rx) = mpsc::channel(1);
+        let mut writer = executor::spawn(Writer::new(batch_rx, Box::new(sink.clone())));
+        batch_tx.try_send(OutgoingBatch { messages: vec![OwnedMessage::Binary(vec![1])], delivered }).unwrap();
+        assert!(poll(&mut writer).unwrap().is_not_ready());
+        assert_eq!(sink.pending.lock().unwrap().len(), 1);
+        assert!(poll(&mut delivery).unwrap().is_not_ready());
+
+        *sink.can_flush.lock().unwrap() = true;
+        assert!(poll(&mut writer).unwrap().is_not_ready());
+        assert_eq!(*sink.flushed.lock().unwrap(), vec![OwnedMessage::Binary(vec![1])]);
+        assert_eq!(poll(&mut delivery), Ok(Async::Ready(MessageId(0))));
+    }
+
+    /// A task that passes the incoming messages to the test.
+    #[derive(Debug)]
+    struct ChannelTask {
+        handle: TaskHandle,
+        incoming: Arc<Mutex<Option<UnboundedReceiver<TaskMessage>>>>,
+    }
+
+    impl Task for ChannelTask {
+        fn init(&mut self, _data: &Option<HashMap<String, Value>>) -> Result<(), FailureError> {
+            Ok(())
+        }
+
+        fn start(&mut self,
+                 outgoing_tx: Sender<TaskMessage>,
+                 incoming_rx: UnboundedReceiver<TaskMessage>,
+                 disconnect_tx: oneshot::Sender<Option<CloseCode>>) {
+            self.handle.start(outgoing_tx, disconnect_tx);
+            *self.incoming.lock().unwrap() = Some(incoming_rx);
+        }
+
+        fn supported_types(&self) -> &'static [&'static str] {
+            &["dummy"]
+        }
+
+        fn send_signaling_message(&self, _payload: &[u8]) {
+            unimplemented!()
+        }
+
+        fn name(&self) -> Cow<'static, str> {
+            DummyTask::name_for(42).into()
+        }
+
+        fn data(&self) -> Option<HashMap<String, Value>> {
+            None
+        }
+
+        fn close(&mut self, _reason: CloseCode) {}
+    }
+
+    /// Poll the future until the condition holds, failing after five seconds.
+    fn poll_until<F, C>(future: &mut executor::Spawn<F>, mut condition: C)
+        where F: Future, F::Error: ::std::fmt::Debug, C: FnMut() -> bool
+    {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            match poll(future) {
+                Ok(Async::NotReady) => {},
+                Ok(Async::Ready(_)) => panic!("Future finished early"),
+                Err(e) => panic!("Future failed: {:?}", e),
+            }
+            if condition() {
+                return;
+            }
+            assert!(Instant::now() < deadline, "Condition not met in time");
+            ::std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// Messages that arrive after the rekey answer of the peer are not
+    /// decrypted with the old keys on the decryption workers.
+    #[test]
+    fn rekey_with_decryption_workers() {
+        let (mut initiator, mut responder) = create_peers(0x5a17, false);
+        handshake(&mut TestServer::new(1), &mut initiator, &mut responder, false, false);
+        initiator.common_mut().rekeying = true;
+        responder.common_mut().rekeying = true;
+        let incoming = Arc::new(Mutex::new(None));
+        let task: BoxedTask = Box::new(ChannelTask { handle: TaskHandle::new(), incoming: Arc::clone(&incoming) });
+        initiator.common_mut().task = Some(Arc::new(Mutex::new(task)));
+        let mut client = SaltyClient::build(KeyPair::new())
+            .add_task(Box::new(DummyTask::new(1)))
+            .with_decryption_workers(2)
+            .initiator()
+            .unwrap();
+        client.signaling = Box::new(initiator);
+        let salty = Arc::new(RwLock::new(client));
+
+        let stream = MockStream::default();
+        let (event_tx, _event_rx) = mpsc::unbounded();
+        let (_task, task_loop) = task_loop(mock_client(&stream), Arc::clone(&salty), event_tx).unwrap();
+        let mut task_loop = executor::spawn(task_loop);
+        let mut rekeyed = executor::spawn(salty.write().unwrap().rekey());
+        let mut sent = vec![];
+        poll_until(&mut task_loop, || {
+            sent.extend(stream.received());
+            !sent.is_empty()
+        });
+        let request = match sent.remove(0) {
+            OwnedMessage::Binary(bytes) => ByteBox::from_vec(bytes).unwrap(),
+            other => panic!("Expected rekey message, got {:?}", other),
+        };
+
+        // The peer answers and sends a few messages with the new keys right away
+        let (mut answer, _actions) = responder.handle_message(request).unwrap().split_replies();
+        assert_eq!(answer.len(), 1);
+        stream.send(answer.remove(0).into_bytes());
+        for data in 0..8 {
+            let bbox = responder.encode_task_message(Value::Map(vec![
+                (Value::from("type"), Value::from("application")),
+                (Value::from("data"), Value::from(data)),
+            ])).unwrap();
+            stream.send(bbox.into_bytes());
+        }
+
+        let mut incoming = executor::spawn(incoming.lock().unwrap().take().unwrap());
+        let mut received = vec![];
+        poll_until(&mut task_loop, || {
+            while let Ok(Async::Ready(Some(msg))) = incoming.poll_stream_notify(&NotifyHandle::from(Arc::new(NoopNotify)), 0) {
+                received.push(msg);
+            }
+            received.len() == 8
+        });
+        let expected: Vec<TaskMessage> = (0..8).map(|data| TaskMessage::Application(Value::from(data))).collect();
+        assert_eq!(received, expected);
+        assert_eq!(poll(&mut rekeyed), Ok(Async::Ready(())));
+        assert_eq!(salty.read().unwrap().stats().decryption_failures, 0);
+    }
+}
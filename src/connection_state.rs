@@ -0,0 +1,209 @@
+//! Coarse connection states for user interfaces.
+//!
+//! Subscribe with
+//! [`SaltyClient::state_changes`](../struct.SaltyClient.html#method.state_changes)
+//! to render the progress of a connection, without having to interpret the
+//! individual [`Event`](../enum.Event.html)s.
+
+use std::fmt;
+
+use futures::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use crate::{CloseCode, CloseReason};
+
+
+/// The state of the connection to the server and the peer.
+///
+/// A connection goes through the states in this order. Once the connection
+/// is `Closed`, a new connection starts over with `Connecting`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// The WebSocket connection to the server is being established.
+    Connecting,
+    /// Doing the handshake with the server.
+    ServerHandshake,
+    /// Waiting for the peer, or doing the handshake with the peer.
+    PeerHandshake,
+    /// The peer handshake is done. The string contains the name of the
+    /// negotiated task.
+    Task(String),
+    /// The connection is being closed, waiting for it to end.
+    Closing,
+    /// The connection has ended.
+    ///
+    /// The close code is the one that was sent or received, or `None` if the
+    /// connection ended without a close code (e.g. because of a network
+    /// error).
+    Closed(Option<CloseCode>),
+}
+
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConnectionState::Task(name) => write!(f, "Task ({})", name),
+            ConnectionState::Closed(Some(code)) => write!(f, "Closed: {}", code),
+            ConnectionState::Closed(None) => write!(f, "Closed"),
+            other => write!(f, "{:?}", other),
+        }
+    }
+}
+
+
+/// The current connection state and the subscribers that are notified when
+/// it changes.
+#[derive(Debug, Default)]
+pub(crate) struct StateObservers {
+    current: Option<ConnectionState>,
+    subscribers: Vec<UnboundedSender<ConnectionState>>,
+    closing: Option<CloseReason>,
+    close_reason: Option<CloseReason>,
+}
+
+impl StateObservers {
+    /// Add a subscriber. The current state, if any, is sent right away.
+    pub(crate) fn subscribe(&mut self) -> UnboundedReceiver<ConnectionState> {
+        let (tx, rx) = mpsc::unbounded();
+        if let Some(ref state) = self.current {
+            let _ = tx.unbounded_send(state.clone());
+        }
+        self.subscribers.push(tx);
+        rx
+    }
+
+    /// Return the current state.
+    pub(crate) fn current(&self) -> Option<&ConnectionState> {
+        self.current.as_ref()
+    }
+
+    /// Change the state and notify the subscribers.
+    ///
+    /// Repeated states are ignored. Subscribers that dropped their receiver
+    /// are removed.
+    pub(crate) fn set(&mut self, state: ConnectionState) {
+        if self.current.as_ref() == Some(&state) {
+            return;
+        }
+        debug!("Connection state: {}", state);
+        self.subscribers.retain(|tx| tx.unbounded_send(state.clone()).is_ok());
+        self.current = Some(state);
+    }
+
+    /// Return the reason why the last connection ended.
+    pub(crate) fn close_reason(&self) -> Option<&CloseReason> {
+        self.close_reason.as_ref()
+    }
+
+    /// Return the close code of the connection that is being closed or has
+    /// just been closed, if any.
+    #[cfg(feature = "connect-tokio")]
+    pub(crate) fn close_code(&self) -> Option<CloseCode> {
+        match self.current {
+            Some(ConnectionState::Closing) => self.closing.as_ref().and_then(|reason| reason.code),
+            Some(ConnectionState::Closed(code)) => code,
+            _ => None,
+        }
+    }
+
+    /// Start closing the connection for the specified reason.
+    ///
+    /// If the connection is already being closed, the first reason is kept,
+    /// only a missing close code is filled in.
+    pub(crate) fn closing(&mut self, reason: CloseReason) {
+        match self.closing {
+            Some(ref mut closing) => if closing.code.is_none() {
+                closing.code = reason.code;
+            },
+            None => self.closing = Some(reason),
+        }
+        self.set(ConnectionState::Closing);
+    }
+
+    /// Mark the connection as closed.
+    ///
+    /// The reason passed to `closing` is used, if any. A received close code
+    /// replaces the code of that reason. Once closed, later calls without a
+    /// close code are ignored.
+    pub(crate) fn closed(&mut self, code: Option<CloseCode>) {
+        if code.is_none() {
+            if let Some(ConnectionState::Closed(_)) = self.current {
+                return;
+            }
+        }
+        let reason = match self.closing.take() {
+            Some(mut reason) => {
+                if code.is_some() {
+                    reason.code = code;
+                }
+                reason
+            },
+            None if code.is_some() => CloseReason::remote(code, "Closed by the server"),
+            None => CloseReason::remote(None, "Connection ended without close code"),
+        };
+        self.set(ConnectionState::Closed(reason.code));
+        self.close_reason = Some(reason);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use futures::Stream;
+
+    use super::*;
+
+    #[test]
+    fn subscribers_receive_changes() {
+        let mut observers = StateObservers::default();
+        let early = observers.subscribe();
+        observers.set(ConnectionState::Connecting);
+        observers.set(ConnectionState::ServerHandshake);
+        observers.set(ConnectionState::ServerHandshake);
+
+        // Late subscribers start with the current state
+        let late = observers.subscribe();
+        observers.set(ConnectionState::PeerHandshake);
+        drop(observers);
+
+        assert_eq!(early.wait().collect::<Result<Vec<_>, _>>().unwrap(), vec![
+            ConnectionState::Connecting,
+            ConnectionState::ServerHandshake,
+            ConnectionState::PeerHandshake,
+        ]);
+        assert_eq!(late.wait().collect::<Result<Vec<_>, _>>().unwrap(), vec![
+            ConnectionState::ServerHandshake,
+            ConnectionState::PeerHandshake,
+        ]);
+    }
+
+    #[test]
+    fn dropped_subscribers_are_removed() {
+        let mut observers = StateObservers::default();
+        drop(observers.subscribe());
+        let _rx = observers.subscribe();
+        observers.set(ConnectionState::Connecting);
+        assert_eq!(observers.subscribers.len(), 1);
+    }
+
+    #[test]
+    fn closed_keeps_closing_code() {
+        let mut observers = StateObservers::default();
+        observers.closing(CloseReason::local(Some(CloseCode::WsGoingAway), "Disconnected by the task"));
+        observers.closing(CloseReason::local(None, "Closed again"));
+        observers.closed(None);
+        assert_eq!(observers.current(), Some(&ConnectionState::Closed(Some(CloseCode::WsGoingAway))));
+        assert_eq!(
+            observers.close_reason(),
+            Some(&CloseReason::local(Some(CloseCode::WsGoingAway), "Disconnected by the task"))
+        );
+
+        // A received close code wins, but isn't replaced by a later `None`
+        observers.set(ConnectionState::Connecting);
+        observers.closed(Some(CloseCode::ProtocolError));
+        observers.closed(None);
+        assert_eq!(observers.current(), Some(&ConnectionState::Closed(Some(CloseCode::ProtocolError))));
+        assert_eq!(
+            observers.close_reason(),
+            Some(&CloseReason::remote(Some(CloseCode::ProtocolError), "Closed by the server"))
+        );
+    }
+}
@@ -0,0 +1,582 @@
+//! Pluggable implementation of the NaCl primitives.
+//!
+//! All cryptographic operations of the crate go through a
+//! [`CryptoBackend`](trait.CryptoBackend.html): public key encryption
+//! (`crypto_box`, including the verification of the keys signed by the
+//! server), secret key encryption (`crypto_secretbox`), sealed boxes,
+//! hashing, key derivation and random number generation. The key types of
+//! the [`crypto`](../crypto/index.html) module are plain byte arrays that
+//! don't depend on the backend.
+//!
+//! With the `sodium` feature (enabled by default), libsodium is used unless
+//! another backend is installed. On platforms where libsodium is hard to
+//! ship, disable the feature and install a pure-Rust implementation (e.g.
+//! based on the `crypto_box` crate) once at startup with
+//! [`set_crypto_backend`](fn.set_crypto_backend.html).
+//!
+//! Zeroing secrets and comparing them in constant time does not need a
+//! backend, see [`memzero`](fn.memzero.html) and
+//! [`constant_time_eq`](fn.constant_time_eq.html).
+
+use std::fmt;
+use std::ptr;
+use std::sync::atomic::{self, AtomicPtr, Ordering};
+
+use failure::Fail;
+#[cfg(feature = "sodium")]
+use rust_sodium::crypto::{box_, hash::sha256, pwhash::scryptsalsa208sha256 as pwhash, sealedbox, secretbox};
+#[cfg(feature = "sodium")]
+use rust_sodium::randombytes::randombytes_into;
+#[cfg(feature = "sodium")]
+use rust_sodium_sys::{crypto_generichash_blake2b_salt_personal, crypto_scalarmult_base};
+
+use crate::crypto_types::{PublicKey, PrivateKey, SecretKey};
+
+
+/// The length of a nonce.
+pub const NONCEBYTES: usize = 24;
+
+/// The length of a public, private or secret key.
+pub const KEYBYTES: usize = 32;
+
+/// The length of an authentication tag.
+pub const MACBYTES: usize = 16;
+
+/// The overhead of a sealed box: the ephemeral public key and the
+/// authentication tag.
+pub const SEALBYTES: usize = KEYBYTES + MACBYTES;
+
+/// The length of a SHA-256 hash.
+pub const SHA256BYTES: usize = 32;
+
+/// The length of the salt and of the personalization of a BLAKE2b key
+/// derivation.
+pub const BLAKE2B_SALTBYTES: usize = 16;
+
+/// The length of the salt of a passphrase key derivation.
+pub const PWHASH_SALTBYTES: usize = 32;
+
+
+/// The crypto backend could not be initialized.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct InitError;
+
+impl fmt::Display for InitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Could not initialize the crypto backend")
+    }
+}
+
+impl Fail for InitError {}
+
+/// Decryption failed because the authentication tag did not match.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct DecryptionError;
+
+impl fmt::Display for DecryptionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Decryption failed")
+    }
+}
+
+impl Fail for DecryptionError {}
+
+/// A key could not be derived from a passphrase.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct KeyDerivationError;
+
+impl fmt::Display for KeyDerivationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Key derivation failed")
+    }
+}
+
+impl Fail for KeyDerivationError {}
+
+
+/// An implementation of the NaCl primitives used by SaltyRTC.
+///
+/// The box operations work on detached authentication tags, so that
+/// messages can be encrypted and decrypted in place. Implementations must be
+/// compatible with libsodium, otherwise peers using different backends can't
+/// talk to each other.
+pub trait CryptoBackend: Send + Sync + fmt::Debug {
+    /// Initialize the backend.
+    ///
+    /// This is called before connecting and before key files or resumption
+    /// tokens are read or written. The default implementation does nothing.
+    fn init(&self) -> Result<(), InitError> {
+        Ok(())
+    }
+
+    /// Fill the buffer with cryptographically secure random bytes.
+    fn random_bytes(&self, buf: &mut [u8]);
+
+    /// Derive the Curve25519 public key of a private key
+    /// (`crypto_scalarmult_base`).
+    fn public_key(&self, private_key: &[u8; KEYBYTES]) -> [u8; KEYBYTES];
+
+    /// Encrypt `data` in place for `public_key` with `private_key`
+    /// (`crypto_box_detached`) and return the authentication tag.
+    fn box_seal_detached(
+        &self,
+        data: &mut [u8],
+        nonce: &[u8; NONCEBYTES],
+        public_key: &[u8; KEYBYTES],
+        private_key: &[u8; KEYBYTES],
+    ) -> [u8; MACBYTES];
+
+    /// Verify the tag and decrypt `data` in place (`crypto_box_open_detached`).
+    fn box_open_detached(
+        &self,
+        data: &mut [u8],
+        tag: &[u8; MACBYTES],
+        nonce: &[u8; NONCEBYTES],
+        public_key: &[u8; KEYBYTES],
+        private_key: &[u8; KEYBYTES],
+    ) -> Result<(), DecryptionError>;
+
+    /// Precompute the shared key of a key pair (`crypto_box_beforenm`).
+    ///
+    /// Encrypting with the shared key as `crypto_secretbox` key is the same
+    /// as encrypting with `crypto_box`.
+    fn box_precompute(&self, public_key: &[u8; KEYBYTES], private_key: &[u8; KEYBYTES]) -> [u8; KEYBYTES];
+
+    /// Encrypt `data` in place with `key` (`crypto_secretbox_detached`) and
+    /// return the authentication tag.
+    fn secretbox_seal_detached(
+        &self,
+        data: &mut [u8],
+        nonce: &[u8; NONCEBYTES],
+        key: &[u8; KEYBYTES],
+    ) -> [u8; MACBYTES];
+
+    /// Verify the tag and decrypt `data` in place
+    /// (`crypto_secretbox_open_detached`).
+    fn secretbox_open_detached(
+        &self,
+        data: &mut [u8],
+        tag: &[u8; MACBYTES],
+        nonce: &[u8; NONCEBYTES],
+        key: &[u8; KEYBYTES],
+    ) -> Result<(), DecryptionError>;
+
+    /// Encrypt `data` anonymously for `public_key` (`crypto_box_seal`).
+    ///
+    /// The ciphertext is [`SEALBYTES`](constant.SEALBYTES.html) longer than
+    /// the data.
+    fn sealed_box_seal(&self, data: &[u8], public_key: &[u8; KEYBYTES]) -> Vec<u8>;
+
+    /// Decrypt a sealed box for the key pair (`crypto_box_seal_open`).
+    fn sealed_box_open(
+        &self,
+        ciphertext: &[u8],
+        public_key: &[u8; KEYBYTES],
+        private_key: &[u8; KEYBYTES],
+    ) -> Result<Vec<u8>, DecryptionError>;
+
+    /// Hash `data` with SHA-256 (`crypto_hash_sha256`).
+    fn sha256(&self, data: &[u8]) -> [u8; SHA256BYTES];
+
+    /// Hash `data` with keyed BLAKE2b to a 32 byte key
+    /// (`crypto_generichash_blake2b_salt_personal`).
+    fn blake2b_salt_personal(
+        &self,
+        data: &[u8],
+        key: &[u8; KEYBYTES],
+        salt: &[u8; BLAKE2B_SALTBYTES],
+        personal: &[u8; BLAKE2B_SALTBYTES],
+    ) -> [u8; KEYBYTES];
+
+    /// Derive a key from a passphrase with scrypt, using the interactive
+    /// limits of libsodium (`crypto_pwhash_scryptsalsa208sha256` with
+    /// `OPSLIMIT_INTERACTIVE` and `MEMLIMIT_INTERACTIVE`).
+    ///
+    /// This is only used by the key files of the `persistence` feature. The
+    /// default implementation fails.
+    fn scrypt(
+        &self,
+        passphrase: &[u8],
+        salt: &[u8; PWHASH_SALTBYTES],
+    ) -> Result<[u8; KEYBYTES], KeyDerivationError> {
+        let _ = (passphrase, salt);
+        Err(KeyDerivationError)
+    }
+}
+
+
+/// The default backend, using libsodium.
+///
+/// Only available with the `sodium` feature.
+#[cfg(feature = "sodium")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SodiumBackend;
+
+#[cfg(feature = "sodium")]
+impl CryptoBackend for SodiumBackend {
+    fn init(&self) -> Result<(), InitError> {
+        rust_sodium::init().map_err(|()| InitError)
+    }
+
+    fn random_bytes(&self, buf: &mut [u8]) {
+        // Make sure that libsodium is initialized
+        self.init().expect("Could not initialize libsodium");
+
+        randombytes_into(buf);
+    }
+
+    fn public_key(&self, private_key: &[u8; KEYBYTES]) -> [u8; KEYBYTES] {
+        // Use crypto_scalarmult_base as described here:
+        // https://download.libsodium.org/doc/public-key_cryptography/authenticated_encryption.html#key-pair-generation
+        let mut buf = [0u8; KEYBYTES];
+        unsafe {
+            crypto_scalarmult_base(buf.as_mut_ptr(), private_key.as_ptr());
+        }
+        buf
+    }
+
+    fn box_seal_detached(
+        &self,
+        data: &mut [u8],
+        nonce: &[u8; NONCEBYTES],
+        public_key: &[u8; KEYBYTES],
+        private_key: &[u8; KEYBYTES],
+    ) -> [u8; MACBYTES] {
+        let box_::Tag(tag) = box_::seal_detached(
+            data,
+            &box_::Nonce(*nonce),
+            &box_::PublicKey(*public_key),
+            &box_::SecretKey(*private_key),
+        );
+        tag
+    }
+
+    fn box_open_detached(
+        &self,
+        data: &mut [u8],
+        tag: &[u8; MACBYTES],
+        nonce: &[u8; NONCEBYTES],
+        public_key: &[u8; KEYBYTES],
+        private_key: &[u8; KEYBYTES],
+    ) -> Result<(), DecryptionError> {
+        box_::open_detached(
+            data,
+            &box_::Tag(*tag),
+            &box_::Nonce(*nonce),
+            &box_::PublicKey(*public_key),
+            &box_::SecretKey(*private_key),
+        ).map_err(|_| DecryptionError)
+    }
+
+    fn box_precompute(&self, public_key: &[u8; KEYBYTES], private_key: &[u8; KEYBYTES]) -> [u8; KEYBYTES] {
+        box_::precompute(&box_::PublicKey(*public_key), &box_::SecretKey(*private_key)).0
+    }
+
+    fn secretbox_seal_detached(
+        &self,
+        data: &mut [u8],
+        nonce: &[u8; NONCEBYTES],
+        key: &[u8; KEYBYTES],
+    ) -> [u8; MACBYTES] {
+        let secretbox::Tag(tag) = secretbox::seal_detached(
+            data,
+            &secretbox::Nonce(*nonce),
+            &secretbox::Key(*key),
+        );
+        tag
+    }
+
+    fn secretbox_open_detached(
+        &self,
+        data: &mut [u8],
+        tag: &[u8; MACBYTES],
+        nonce: &[u8; NONCEBYTES],
+        key: &[u8; KEYBYTES],
+    ) -> Result<(), DecryptionError> {
+        secretbox::open_detached(
+            data,
+            &secretbox::Tag(*tag),
+            &secretbox::Nonce(*nonce),
+            &secretbox::Key(*key),
+        ).map_err(|_| DecryptionError)
+    }
+
+    fn sealed_box_seal(&self, data: &[u8], public_key: &[u8; KEYBYTES]) -> Vec<u8> {
+        self.init().expect("Could not initialize libsodium");
+        sealedbox::seal(data, &box_::PublicKey(*public_key))
+    }
+
+    fn sealed_box_open(
+        &self,
+        ciphertext: &[u8],
+        public_key: &[u8; KEYBYTES],
+        private_key: &[u8; KEYBYTES],
+    ) -> Result<Vec<u8>, DecryptionError> {
+        sealedbox::open(ciphertext, &box_::PublicKey(*public_key), &box_::SecretKey(*private_key))
+            .map_err(|_| DecryptionError)
+    }
+
+    fn sha256(&self, data: &[u8]) -> [u8; SHA256BYTES] {
+        self.init().expect("Could not initialize libsodium");
+        sha256::hash(data).0
+    }
+
+    fn blake2b_salt_personal(
+        &self,
+        data: &[u8],
+        key: &[u8; KEYBYTES],
+        salt: &[u8; BLAKE2B_SALTBYTES],
+        personal: &[u8; BLAKE2B_SALTBYTES],
+    ) -> [u8; KEYBYTES] {
+        self.init().expect("Could not initialize libsodium");
+        let mut out = [0u8; KEYBYTES];
+        let result = unsafe {
+            crypto_generichash_blake2b_salt_personal(
+                out.as_mut_ptr(),
+                out.len(),
+                data.as_ptr(),
+                data.len() as u64,
+                key.as_ptr(),
+                key.len(),
+                salt.as_ptr(),
+                personal.as_ptr(),
+            )
+        };
+        // Only fails for invalid output or key lengths
+        assert_eq!(result, 0, "Key derivation failed");
+        out
+    }
+
+    fn scrypt(
+        &self,
+        passphrase: &[u8],
+        salt: &[u8; PWHASH_SALTBYTES],
+    ) -> Result<[u8; KEYBYTES], KeyDerivationError> {
+        let mut key = [0u8; KEYBYTES];
+        pwhash::derive_key(
+            &mut key, passphrase, &pwhash::Salt(*salt), pwhash::OPSLIMIT_INTERACTIVE, pwhash::MEMLIMIT_INTERACTIVE,
+        ).map_err(|_| KeyDerivationError)?;
+        Ok(key)
+    }
+}
+
+
+/// The installed backend. A null pointer means that the default is used.
+static BACKEND: AtomicPtr<Box<dyn CryptoBackend>> = AtomicPtr::new(ptr::null_mut());
+
+/// Install a crypto backend for the whole process.
+///
+/// This must be called before any keys are generated or any connection is
+/// started. The backend can only be installed once, if a backend has already
+/// been installed, the passed backend is returned as error.
+pub fn set_crypto_backend(backend: Box<dyn CryptoBackend>) -> Result<(), Box<dyn CryptoBackend>> {
+    let new = Box::into_raw(Box::new(backend));
+    match BACKEND.compare_exchange(ptr::null_mut(), new, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => Ok(()),
+        Err(_) => {
+            // The pointer was not stored, so we still own it
+            let backend = unsafe { Box::from_raw(new) };
+            Err(*backend)
+        },
+    }
+}
+
+/// Return the installed crypto backend.
+///
+/// ## Panics
+///
+/// Without the `sodium` feature, this panics if no backend has been
+/// installed.
+pub(crate) fn backend() -> &'static dyn CryptoBackend {
+    let installed = BACKEND.load(Ordering::Acquire);
+    if installed.is_null() {
+        default_backend()
+    } else {
+        // The backend is never freed once it has been installed
+        unsafe { &**installed }
+    }
+}
+
+#[cfg(feature = "sodium")]
+fn default_backend() -> &'static dyn CryptoBackend {
+    &SodiumBackend
+}
+
+#[cfg(not(feature = "sodium"))]
+fn default_backend() -> &'static dyn CryptoBackend {
+    panic!("No crypto backend installed. Enable the `sodium` feature or call `set_crypto_backend`.")
+}
+
+/// Initialize the installed crypto backend.
+#[cfg(any(feature = "connect-tokio", feature = "persistence", feature = "resumption"))]
+pub(crate) fn init() -> Result<(), InitError> {
+    backend().init()
+}
+
+
+/// Overwrite the buffer with zeros.
+///
+/// The writes are volatile, so that they are not optimized away even though
+/// the buffer is not read afterwards.
+pub fn memzero(buf: &mut [u8]) {
+    for byte in buf.iter_mut() {
+        unsafe { ptr::write_volatile(byte, 0) };
+    }
+    atomic::compiler_fence(Ordering::SeqCst);
+}
+
+/// Compare two byte slices in constant time.
+///
+/// The time only depends on the length of the slices, not on the number of
+/// matching bytes.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let difference = a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y));
+    // Prevent the compiler from short-circuiting the fold
+    unsafe { ptr::read_volatile(&difference) == 0 }
+}
+
+
+/// Split the authentication tag from the front of a combined ciphertext.
+fn split_tag(ciphertext: &[u8]) -> Result<([u8; MACBYTES], &[u8]), DecryptionError> {
+    if ciphertext.len() < MACBYTES {
+        return Err(DecryptionError);
+    }
+    let (tag_bytes, data) = ciphertext.split_at(MACBYTES);
+    let mut tag = [0u8; MACBYTES];
+    tag.copy_from_slice(tag_bytes);
+    Ok((tag, data))
+}
+
+/// Encrypt data with `crypto_box`. The tag is prepended to the ciphertext.
+pub(crate) fn box_seal(data: &[u8], nonce: &[u8; NONCEBYTES], public_key: &PublicKey, private_key: &PrivateKey) -> Vec<u8> {
+    let mut buf = vec![0u8; MACBYTES + data.len()];
+    buf[MACBYTES..].copy_from_slice(data);
+    let tag = backend().box_seal_detached(&mut buf[MACBYTES..], nonce, &public_key.0, &private_key.0);
+    buf[..MACBYTES].copy_from_slice(&tag);
+    buf
+}
+
+/// Decrypt data encrypted with [`box_seal`](fn.box_seal.html).
+pub(crate) fn box_open(
+    ciphertext: &[u8],
+    nonce: &[u8; NONCEBYTES],
+    public_key: &PublicKey,
+    private_key: &PrivateKey,
+) -> Result<Vec<u8>, DecryptionError> {
+    let (tag, data) = split_tag(ciphertext)?;
+    let mut buf = data.to_vec();
+    backend().box_open_detached(&mut buf, &tag, nonce, &public_key.0, &private_key.0)?;
+    Ok(buf)
+}
+
+/// Encrypt data with `crypto_secretbox`. The tag is prepended to the
+/// ciphertext.
+pub(crate) fn secretbox_seal(data: &[u8], nonce: &[u8; NONCEBYTES], key: &SecretKey) -> Vec<u8> {
+    let mut buf = vec![0u8; MACBYTES + data.len()];
+    buf[MACBYTES..].copy_from_slice(data);
+    let tag = backend().secretbox_seal_detached(&mut buf[MACBYTES..], nonce, &key.0);
+    buf[..MACBYTES].copy_from_slice(&tag);
+    buf
+}
+
+/// Decrypt data encrypted with [`secretbox_seal`](fn.secretbox_seal.html).
+pub(crate) fn secretbox_open(ciphertext: &[u8], nonce: &[u8; NONCEBYTES], key: &SecretKey) -> Result<Vec<u8>, DecryptionError> {
+    let (tag, data) = split_tag(ciphertext)?;
+    let mut buf = data.to_vec();
+    backend().secretbox_open_detached(&mut buf, &tag, nonce, &key.0)?;
+    Ok(buf)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NONCE: [u8; NONCEBYTES] = [7; NONCEBYTES];
+
+    #[test]
+    fn compare_constant_time() {
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"ab"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn zero_memory() {
+        let mut buf = [1, 2, 3];
+        memzero(&mut buf);
+        assert_eq!(buf, [0, 0, 0]);
+    }
+
+    /// The combined helpers produce the same output as libsodium.
+    #[cfg(feature = "sodium")]
+    #[test]
+    fn box_matches_libsodium() {
+        let (pk, sk) = box_::gen_keypair();
+        let (public_key, private_key) = (PublicKey(pk.0), PrivateKey(sk.0));
+        let sealed = box_seal(b"hello", &NONCE, &public_key, &private_key);
+        assert_eq!(sealed, box_::seal(b"hello", &box_::Nonce(NONCE), &pk, &sk));
+        assert_eq!(box_open(&sealed, &NONCE, &public_key, &private_key), Ok(b"hello".to_vec()));
+        assert_eq!(box_open(&sealed[1..], &NONCE, &public_key, &private_key), Err(DecryptionError));
+        assert_eq!(box_open(&sealed[..MACBYTES - 1], &NONCE, &public_key, &private_key), Err(DecryptionError));
+    }
+
+    #[cfg(feature = "sodium")]
+    #[test]
+    fn precomputed_box_matches_box() {
+        let (pk, sk) = box_::gen_keypair();
+        let shared_key = SecretKey(SodiumBackend.box_precompute(&pk.0, &sk.0));
+        assert_eq!(
+            secretbox_seal(b"hello", &NONCE, &shared_key),
+            box_seal(b"hello", &NONCE, &PublicKey(pk.0), &PrivateKey(sk.0)),
+        );
+    }
+
+    #[cfg(feature = "sodium")]
+    #[test]
+    fn secretbox_matches_libsodium() {
+        let key = secretbox::gen_key();
+        let secret_key = SecretKey(key.0);
+        let sealed = secretbox_seal(b"hello", &NONCE, &secret_key);
+        assert_eq!(sealed, secretbox::seal(b"hello", &secretbox::Nonce(NONCE), &key));
+        assert_eq!(secretbox_open(&sealed, &NONCE, &secret_key), Ok(b"hello".to_vec()));
+        let mut tampered = sealed.clone();
+        tampered[MACBYTES] ^= 1;
+        assert_eq!(secretbox_open(&tampered, &NONCE, &secret_key), Err(DecryptionError));
+    }
+
+    #[cfg(feature = "sodium")]
+    #[test]
+    fn public_key_matches_libsodium() {
+        let (pk, sk) = box_::gen_keypair();
+        assert_eq!(SodiumBackend.public_key(&sk.0), pk.0);
+    }
+
+    #[cfg(feature = "sodium")]
+    #[test]
+    fn sealed_box_roundtrip() {
+        let (pk, sk) = box_::gen_keypair();
+        let sealed = SodiumBackend.sealed_box_seal(b"hello", &pk.0);
+        assert_eq!(sealed.len(), 5 + SEALBYTES);
+        assert_eq!(sealedbox::open(&sealed, &pk, &sk), Ok(b"hello".to_vec()));
+        assert_eq!(SodiumBackend.sealed_box_open(&sealed, &pk.0, &sk.0), Ok(b"hello".to_vec()));
+        assert_eq!(SodiumBackend.sealed_box_open(&sealed[1..], &pk.0, &sk.0), Err(DecryptionError));
+    }
+
+    /// SHA-256 of "abc" (FIPS 180-2, appendix B.1).
+    #[cfg(feature = "sodium")]
+    #[test]
+    fn sha256_known_answer() {
+        assert_eq!(
+            &SodiumBackend.sha256(b"abc")[..],
+            &[
+                0xba, 0x78, 0x16, 0xbf, 0x8f, 0x01, 0xcf, 0xea, 0x41, 0x41, 0x40, 0xde, 0x5d, 0xae, 0x22, 0x23,
+                0xb0, 0x03, 0x61, 0xa3, 0x96, 0x17, 0x7a, 0x9c, 0xb4, 0x10, 0xff, 0x61, 0xf2, 0x00, 0x15, 0xad,
+            ][..],
+        );
+    }
+}
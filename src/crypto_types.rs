@@ -1,36 +1,152 @@
-//! Functionality related to Libsodium key management and encryption.
+//! Functionality related to key management and encryption.
 
 #![cfg_attr(feature="cargo-clippy", allow(new_without_default))]
 
 use std::cmp;
 use std::fmt;
+use std::str::FromStr;
 #[cfg(test)]
 use std::io::Write;
 
-use data_encoding::{HEXLOWER, HEXLOWER_PERMISSIVE};
-use rust_sodium::crypto::{box_, secretbox};
-use rust_sodium_sys::crypto_scalarmult_base;
+use data_encoding::{BASE64, HEXLOWER, HEXLOWER_PERMISSIVE};
 use serde::ser::{Serialize, Serializer};
-use serde::de::{Deserialize, Deserializer, Visitor, Error as SerdeError};
+use serde::de::{Deserialize, Deserializer, SeqAccess, Visitor, Error as SerdeError};
 
+use crate::crypto_backend::{self, backend, KEYBYTES};
 use crate::errors::{SaltyResult, SaltyError, SignalingResult, SignalingError};
-use crate::helpers::{libsodium_init_or_panic};
 use crate::protocol::Nonce;
+use crate::protocol::random::RandomSource;
 
 /// A public key used for decrypting data.
 ///
-/// Re-exported from the [`rust_sodium`](../rust_sodium/index.html) crate.
-pub type PublicKey = box_::PublicKey;
+/// Serializes as 32 bytes of binary data.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PublicKey(pub [u8; KEYBYTES]);
 
 /// A private key used for encrypting data.
 ///
-/// Re-exported from the [`rust_sodium`](../rust_sodium/index.html) crate.
-pub type PrivateKey = box_::SecretKey;
+/// The key is zeroed when it is dropped and compared in constant time.
+pub struct PrivateKey(pub [u8; KEYBYTES]);
 
 /// A symmetric key used for both encrypting and decrypting data.
 ///
-/// Re-exported from the [`rust_sodium`](../rust_sodium/index.html) crate.
-pub type SecretKey = secretbox::Key;
+/// The key is zeroed when it is dropped and compared in constant time.
+pub struct SecretKey(pub [u8; KEYBYTES]);
+
+/// The length of the authentication tag in front of an encrypted payload.
+///
+/// This is the same for public key and secret key encryption.
+pub(crate) const MACBYTES: usize = crypto_backend::MACBYTES;
+
+/// Implement the constructor and the serde traits of a key type.
+macro_rules! key_type {
+    ($name:ident) => {
+        impl $name {
+            /// Create a key from a slice of exactly 32 bytes.
+            pub fn from_slice(bytes: &[u8]) -> Option<$name> {
+                if bytes.len() != KEYBYTES {
+                    return None;
+                }
+                let mut key = [0u8; KEYBYTES];
+                key.copy_from_slice(bytes);
+                Some($name(key))
+            }
+        }
+
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_bytes(&self.0)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<$name, D::Error> {
+                deserializer.deserialize_bytes(KeyBytesVisitor).map($name)
+            }
+        }
+    };
+}
+
+/// Implement the traits of a key type that must not leak the key.
+macro_rules! secret_key_type {
+    ($name:ident) => {
+        key_type!($name);
+
+        impl Clone for $name {
+            fn clone(&self) -> $name {
+                $name(self.0)
+            }
+        }
+
+        impl Drop for $name {
+            fn drop(&mut self) {
+                crypto_backend::memzero(&mut self.0);
+            }
+        }
+
+        impl PartialEq for $name {
+            fn eq(&self, other: &$name) -> bool {
+                crypto_backend::constant_time_eq(&self.0, &other.0)
+            }
+        }
+
+        impl Eq for $name {}
+
+        /// Hides the key.
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "{}(****)", stringify!($name))
+            }
+        }
+    };
+}
+
+key_type!(PublicKey);
+secret_key_type!(PrivateKey);
+secret_key_type!(SecretKey);
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PublicKey({:?})", &self.0[..])
+    }
+}
+
+impl AsRef<[u8]> for PublicKey {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// A visitor for the 32 bytes of a key, as binary data or as sequence.
+struct KeyBytesVisitor;
+
+impl<'de> Visitor<'de> for KeyBytesVisitor {
+    type Value = [u8; KEYBYTES];
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "{} bytes", KEYBYTES)
+    }
+
+    fn visit_bytes<E: SerdeError>(self, v: &[u8]) -> Result<Self::Value, E> {
+        if v.len() != KEYBYTES {
+            return Err(E::invalid_length(v.len(), &self));
+        }
+        let mut key = [0u8; KEYBYTES];
+        key.copy_from_slice(v);
+        Ok(key)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut key = [0u8; KEYBYTES];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = seq.next_element()?.ok_or_else(|| SerdeError::invalid_length(i, &self))?;
+        }
+        if seq.next_element::<u8>()?.is_some() {
+            return Err(SerdeError::invalid_length(KEYBYTES + 1, &self));
+        }
+        Ok(key)
+    }
+}
 
 
 /// Create a [`PublicKey`](../type.PublicKey.html) instance from case
@@ -42,6 +158,120 @@ pub fn public_key_from_hex_str(hex_str: &str) -> SaltyResult<PublicKey> {
         .ok_or_else(|| SaltyError::Decode("Invalid public key hex string".to_string()))
 }
 
+/// Create a [`PublicKey`](../type.PublicKey.html) instance from a base64
+/// string (standard alphabet with padding).
+pub fn public_key_from_base64_str(base64_str: &str) -> SaltyResult<PublicKey> {
+    let bytes = BASE64.decode(base64_str.as_bytes())
+        .map_err(|_| SaltyError::Decode("Could not decode public key base64 string".to_string()))?;
+    PublicKey::from_slice(&bytes)
+        .ok_or_else(|| SaltyError::Decode("Invalid public key base64 string".to_string()))
+}
+
+/// Encode a [`PublicKey`](../type.PublicKey.html) as lowercase hex string.
+pub fn public_key_to_hex(key: &PublicKey) -> String {
+    HEXLOWER.encode(&key.0)
+}
+
+/// Encode a [`PublicKey`](../type.PublicKey.html) as base64 string
+/// (standard alphabet with padding).
+pub fn public_key_to_base64(key: &PublicKey) -> String {
+    BASE64.encode(&key.0)
+}
+
+/// Parse a key from a hex string (64 characters) or a base64 string.
+fn public_key_from_str(key_str: &str) -> SaltyResult<PublicKey> {
+    if key_str.len() == 2 * KEYBYTES {
+        public_key_from_hex_str(key_str)
+    } else {
+        public_key_from_base64_str(key_str)
+    }
+}
+
+/// Serde helpers for a [`PublicKey`](../type.PublicKey.html) field.
+///
+/// The `PublicKey` type always serializes as binary data. With
+/// `#[serde(with = "saltyrtc_client::crypto::serde_public_key")]`, the key
+/// is serialized as lowercase hex string in human readable formats like JSON
+/// instead. Hex and base64 strings are accepted when deserializing.
+pub mod serde_public_key {
+    use serde::{Deserializer, Serializer};
+
+    use super::{PublicKey, KeyVisitor, SaltyError, public_key_from_str, public_key_to_hex};
+
+    /// Serialize a public key.
+    pub fn serialize<S: Serializer>(key: &PublicKey, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&public_key_to_hex(key))
+        } else {
+            serializer.serialize_bytes(&key.0)
+        }
+    }
+
+    /// Deserialize a public key.
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PublicKey, D::Error> {
+        let visitor = KeyVisitor {
+            name: "public key",
+            from_str: public_key_from_str,
+            from_bytes: |bytes| PublicKey::from_slice(bytes)
+                .ok_or_else(|| SaltyError::Decode("Invalid public key bytes".into())),
+        };
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_str(visitor)
+        } else {
+            deserializer.deserialize_bytes(visitor)
+        }
+    }
+}
+
+/// Serde helpers for an optional [`PublicKey`](../type.PublicKey.html) field,
+/// encoded like [`serde_public_key`](serde_public_key/index.html).
+pub(crate) mod serde_option_public_key {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::{serde_public_key, PublicKey};
+
+    #[derive(Serialize, Deserialize)]
+    struct Key(#[serde(with = "serde_public_key")] PublicKey);
+
+    /// Serialize an optional public key.
+    pub(crate) fn serialize<S: Serializer>(key: &Option<PublicKey>, serializer: S) -> Result<S::Ok, S::Error> {
+        key.map(Key).serialize(serializer)
+    }
+
+    /// Deserialize an optional public key.
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<PublicKey>, D::Error> {
+        Ok(Option::<Key>::deserialize(deserializer)?.map(|key| key.0))
+    }
+}
+
+/// A visitor for keys encoded as string in human readable formats and as
+/// binary data otherwise.
+struct KeyVisitor<T> {
+    name: &'static str,
+    from_str: fn(&str) -> SaltyResult<T>,
+    from_bytes: fn(&[u8]) -> SaltyResult<T>,
+}
+
+impl<'de, T> Visitor<'de> for KeyVisitor<T> {
+    type Value = T;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "a {} as hex or base64 string or as 32 bytes of binary data", self.name)
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E> where E: SerdeError {
+        (self.from_str)(v).map_err(SerdeError::custom)
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E> where E: SerdeError {
+        (self.from_bytes)(v).map_err(SerdeError::custom)
+    }
+
+    fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Self::Value, E> where E: SerdeError {
+        self.visit_bytes(&v)
+    }
+}
+
 /// Create a [`PrivateKey`](../type.PrivateKey.html) instance from case
 /// insensitive hex bytes.
 #[allow(dead_code)]
@@ -66,34 +296,39 @@ impl KeyPair {
     ///
     /// ## Panics
     ///
-    /// This may panic if libsodium initialization fails.
+    /// This may panic if the crypto backend cannot be initialized.
     pub fn new() -> Self {
         info!("Generating new key pair");
 
-        // Initialize libsodium if it hasn't been initialized already
-        libsodium_init_or_panic();
-
         // Generate key pair
-        let (pk, sk) = box_::gen_keypair();
-        trace!("Public key: {:?}", pk);
+        let mut bytes = [0u8; KEYBYTES];
+        backend().random_bytes(&mut bytes);
+        let keypair = KeyPair::from_private_key(PrivateKey(bytes));
+        trace!("Public key: {:?}", keypair.public_key);
 
-        KeyPair {
-            public_key: pk,
-            private_key: sk,
-        }
+        keypair
+    }
+
+    /// Create a new key pair from the specified random source.
+    pub(crate) fn from_random_source(rng: &mut dyn RandomSource) -> Self {
+        let mut bytes = [0u8; KEYBYTES];
+        rng.fill_bytes(&mut bytes);
+        KeyPair::from_private_key(PrivateKey(bytes))
+    }
+
+    /// Create a new session key pair for the peer handshake from the
+    /// specified random source.
+    pub(crate) fn session_from_random_source(rng: &mut dyn RandomSource) -> Self {
+        let mut bytes = [0u8; KEYBYTES];
+        rng.fill_session_key(&mut bytes);
+        KeyPair::from_private_key(PrivateKey(bytes))
     }
 
     /// Create a new key pair from an existing private key.
     ///
     /// The private key is consumed and transferred into the `KeyPair`.
     pub fn from_private_key(private_key: PrivateKey) -> Self {
-        let public_key = unsafe {
-            // Use crypto_scalarmult_base as described here:
-            // https://download.libsodium.org/doc/public-key_cryptography/authenticated_encryption.html#key-pair-generation
-            let mut buf = [0u8; box_::PUBLICKEYBYTES];
-            crypto_scalarmult_base(buf.as_mut_ptr(), private_key.0.as_ptr());
-            box_::PublicKey(buf)
-        };
+        let public_key = PublicKey(backend().public_key(&private_key.0));
         KeyPair { public_key, private_key }
     }
 
@@ -131,9 +366,9 @@ impl KeyPair {
     }
 
     /// Encrypt data for the specified public key with the private key.
+    #[allow(dead_code)]
     pub(crate) fn encrypt(&self, data: &[u8], nonce: Nonce, other_key: &PublicKey) -> Vec<u8> {
-        let rust_sodium_nonce: box_::Nonce = nonce.into();
-        box_::seal(data, &rust_sodium_nonce, other_key, &self.private_key)
+        crypto_backend::box_seal(data, &nonce.into_bytes(), other_key, &self.private_key)
     }
 
     /// Decrypt data using the specified public key with the own private key.
@@ -141,12 +376,65 @@ impl KeyPair {
     /// If decryption succeeds, the decrypted bytes are returned. Otherwise, a
     /// [`SignalingError::Crypto`](../enum.SignalingError.html#variant.Crypto)
     /// is returned.
+    #[allow(dead_code)]
     pub(crate) fn decrypt(&self, data: &[u8], nonce: Nonce, other_key: &PublicKey) -> SignalingResult<Vec<u8>> {
-        let rust_sodium_nonce: box_::Nonce = nonce.into();
-        box_::open(data, &rust_sodium_nonce, other_key, &self.private_key)
+        crypto_backend::box_open(data, &nonce.into_bytes(), other_key, &self.private_key)
             .map_err(|_| SignalingError::Crypto("Could not decrypt data".to_string()))
     }
 
+    /// Encrypt data in place for the specified public key with the private key.
+    ///
+    /// The first `MACBYTES` bytes of `buf` are
+    /// overwritten with the authentication tag, the rest is encrypted. The
+    /// result is identical to the output of [`encrypt`](#method.encrypt).
+    pub(crate) fn encrypt_in_place(&self, buf: &mut [u8], nonce: Nonce, other_key: &PublicKey) {
+        let (tag, data) = buf.split_at_mut(MACBYTES);
+        let tag_bytes = backend().box_seal_detached(data, &nonce.into_bytes(), &other_key.0, &self.private_key.0);
+        tag.copy_from_slice(&tag_bytes);
+    }
+
+    /// Decrypt data in place using the specified public key with the own
+    /// private key.
+    ///
+    /// If decryption succeeds, the plaintext (`buf` without the
+    /// authentication tag) is returned.
+    pub(crate) fn decrypt_in_place<'a>(
+        &self,
+        buf: &'a mut [u8],
+        nonce: Nonce,
+        other_key: &PublicKey,
+    ) -> SignalingResult<&'a [u8]> {
+        let (tag, data) = split_tag(buf)?;
+        backend().box_open_detached(data, &tag, &nonce.into_bytes(), &other_key.0, &self.private_key.0)
+            .map_err(|_| SignalingError::Crypto("Could not decrypt data".to_string()))?;
+        Ok(data)
+    }
+
+    /// Encrypt data anonymously for the owner of the specified public key.
+    ///
+    /// This uses a sealed box (`crypto_box_seal`), so the ciphertext can be
+    /// opened by any libsodium compatible implementation. It
+    /// can be used to pass secrets like an auth token to a peer with a known
+    /// permanent key, e.g. through a push notification.
+    ///
+    /// ## Panics
+    ///
+    /// This may panic if the crypto backend cannot be initialized.
+    pub fn seal_for(public_key: &PublicKey, data: &[u8]) -> Vec<u8> {
+        backend().sealed_box_seal(data, &public_key.0)
+    }
+
+    /// Decrypt data that was encrypted for our public key with
+    /// [`seal_for`](#method.seal_for) or `crypto_box_seal`.
+    ///
+    /// If decryption fails, a
+    /// [`SaltyError::Crypto`](../errors/enum.SaltyError.html#variant.Crypto)
+    /// is returned.
+    pub fn open_sealed(&self, ciphertext: &[u8]) -> SaltyResult<Vec<u8>> {
+        backend().sealed_box_open(ciphertext, &self.public_key.0, &self.private_key.0)
+            .map_err(|_| SaltyError::Crypto("Could not open sealed box".to_string()))
+    }
+
 }
 
 
@@ -158,17 +446,22 @@ impl AuthToken {
 
     /// Create a new auth token.
     ///
-    /// This can fail only if libsodium initialization fails.
+    /// This can fail only if the crypto backend cannot be initialized.
     pub fn new() -> Self {
         info!("Generating new auth token");
 
-        // Initialize libsodium if it hasn't been initialized already
-        libsodium_init_or_panic();
+        // Generate key
+        let mut bytes = [0u8; KEYBYTES];
+        backend().random_bytes(&mut bytes);
 
-        // Generate key pair
-        let key = secretbox::gen_key();
+        AuthToken(SecretKey(bytes))
+    }
 
-        AuthToken(key)
+    /// Create a new auth token from the specified random source.
+    pub(crate) fn from_random_source(rng: &mut dyn RandomSource) -> Self {
+        let mut bytes = [0u8; KEYBYTES];
+        rng.fill_bytes(&mut bytes);
+        AuthToken(SecretKey(bytes))
     }
 
     /// Create an `AuthToken` instance from hex bytes.
@@ -180,6 +473,24 @@ impl AuthToken {
         Ok(AuthToken(key))
     }
 
+    /// Create an `AuthToken` instance from a base64 string (standard
+    /// alphabet with padding).
+    pub fn from_base64_str(base64_str: &str) -> SaltyResult<Self> {
+        let bytes = BASE64.decode(base64_str.as_bytes())
+            .map_err(|e| SaltyError::Decode(format!("Could not decode auth token base64 string: {}", e)))?;
+        let key = SecretKey::from_slice(&bytes)
+            .ok_or_else(|| SaltyError::Decode("Invalid auth token base64 string".to_string()))?;
+        Ok(AuthToken(key))
+    }
+
+    /// Encode the auth token as base64 string (standard alphabet with
+    /// padding).
+    ///
+    /// Use `to_string()` for the lowercase hex representation.
+    pub fn to_base64(&self) -> String {
+        BASE64.encode(self.secret_key_bytes())
+    }
+
     /// Create an `AuthToken` instance from a 32 byte slice.
     pub fn from_slice(hex_str: &[u8]) -> SaltyResult<Self> {
         if hex_str.len() != 32 {
@@ -205,9 +516,9 @@ impl AuthToken {
     }
 
     /// Encrypt data with the secret key.
+    #[allow(dead_code)]
     pub(crate) fn encrypt(&self, plaintext: &[u8], nonce: Nonce) -> Vec<u8> {
-        let rust_sodium_nonce: secretbox::Nonce = nonce.into();
-        secretbox::seal(plaintext, &rust_sodium_nonce, self.secret_key())
+        crypto_backend::secretbox_seal(plaintext, &nonce.into_bytes(), self.secret_key())
     }
 
     /// Decrypt data with the secret key.
@@ -215,17 +526,211 @@ impl AuthToken {
     /// If decryption succeeds, the decrypted bytes are returned. Otherwise, a
     /// [`SignalingError::Crypto`](../enum.SignalingError.html#variant.Crypto)
     /// is returned.
+    #[allow(dead_code)]
     pub(crate) fn decrypt(&self, ciphertext: &[u8], nonce: Nonce) -> SignalingResult<Vec<u8>> {
-        let rust_sodium_nonce: secretbox::Nonce = nonce.into();
-        secretbox::open(ciphertext, &rust_sodium_nonce, self.secret_key())
+        crypto_backend::secretbox_open(ciphertext, &nonce.into_bytes(), self.secret_key())
             .map_err(|_| SignalingError::Crypto("Could not decrypt data".to_string()))
     }
 
+    /// Encrypt data in place with the secret key.
+    ///
+    /// See [`KeyPair::encrypt_in_place`](struct.KeyPair.html#method.encrypt_in_place).
+    pub(crate) fn encrypt_in_place(&self, buf: &mut [u8], nonce: Nonce) {
+        let (tag, data) = buf.split_at_mut(MACBYTES);
+        let tag_bytes = backend().secretbox_seal_detached(data, &nonce.into_bytes(), &(self.0).0);
+        tag.copy_from_slice(&tag_bytes);
+    }
+
+    /// Decrypt data in place with the secret key.
+    ///
+    /// See [`KeyPair::decrypt_in_place`](struct.KeyPair.html#method.decrypt_in_place).
+    pub(crate) fn decrypt_in_place<'a>(&self, buf: &'a mut [u8], nonce: Nonce) -> SignalingResult<&'a [u8]> {
+        let (tag, data) = split_tag(buf)?;
+        backend().secretbox_open_detached(data, &tag, &nonce.into_bytes(), &(self.0).0)
+            .map_err(|_| SignalingError::Crypto("Could not decrypt data".to_string()))?;
+        Ok(data)
+    }
+
+}
+
+/// Split the authentication tag from the front of an encrypted buffer.
+fn split_tag(buf: &mut [u8]) -> SignalingResult<([u8; MACBYTES], &mut [u8])> {
+    if buf.len() < MACBYTES {
+        return Err(SignalingError::Crypto("Could not decrypt data".to_string()));
+    }
+    let (tag_bytes, data) = buf.split_at_mut(MACBYTES);
+    let mut tag = [0u8; MACBYTES];
+    tag.copy_from_slice(tag_bytes);
+    Ok((tag, data))
+}
+
+
+/// Formats the auth token as lowercase hex string.
+impl fmt::Display for AuthToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::LowerHex::fmt(self, f)
+    }
+}
+
+impl fmt::LowerHex for AuthToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&HEXLOWER.encode(self.secret_key_bytes()))
+    }
+}
+
+/// Parses a hex string (64 characters) or a base64 string.
+impl FromStr for AuthToken {
+    type Err = SaltyError;
+
+    fn from_str(s: &str) -> SaltyResult<Self> {
+        if s.len() == 2 * KEYBYTES {
+            Self::from_hex_str(s)
+        } else {
+            Self::from_base64_str(s)
+        }
+    }
+}
+
+/// Serializes as lowercase hex string in human readable formats and as
+/// binary data otherwise.
+impl Serialize for AuthToken {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+            where S: Serializer {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&self.to_string())
+        } else {
+            serializer.serialize_bytes(self.secret_key_bytes())
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for AuthToken {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+            where D: Deserializer<'de> {
+        let visitor = KeyVisitor {
+            name: "auth token",
+            from_str: AuthToken::from_str,
+            from_bytes: AuthToken::from_slice,
+        };
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_str(visitor)
+        } else {
+            deserializer.deserialize_bytes(visitor)
+        }
+    }
+}
+
+
+/// The format version of the binary pairing data.
+const PAIRING_DATA_VERSION: u8 = 1;
+
+/// The number of bytes in the binary pairing data.
+const PAIRING_DATA_BYTES: usize = 1 + KEYBYTES + KEYBYTES;
+
+
+/// The information a responder needs to pair with an initiator: The initiator
+/// public permanent key and the one-time auth token.
+///
+/// This is usually transferred out of band, either as a hex string or as a
+/// compact binary blob that can be embedded in a QR code.
+///
+/// The hex format is the lowercase hex encoded public key (64 characters)
+/// followed by the hex encoded auth token (64 characters).
+///
+/// The binary format consists of a version byte (currently `1`), followed by
+/// the 32 public key bytes and the 32 auth token bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PairingData {
+    initiator_pubkey: PublicKey,
+    auth_token: AuthToken,
+}
+
+impl PairingData {
+    /// Create a new `PairingData` instance.
+    pub fn new(initiator_pubkey: PublicKey, auth_token: AuthToken) -> Self {
+        PairingData { initiator_pubkey, auth_token }
+    }
+
+    /// Return a reference to the initiator public permanent key.
+    pub fn initiator_pubkey(&self) -> &PublicKey {
+        &self.initiator_pubkey
+    }
+
+    /// Return a reference to the auth token.
+    pub fn auth_token(&self) -> &AuthToken {
+        &self.auth_token
+    }
+
+    /// Consume the pairing data, return the public key and the auth token.
+    pub fn into_parts(self) -> (PublicKey, AuthToken) {
+        (self.initiator_pubkey, self.auth_token)
+    }
+
+    /// Encode the pairing data as a lowercase hex string.
+    pub fn to_hex(&self) -> String {
+        let mut hex = HEXLOWER.encode(&self.initiator_pubkey.0);
+        hex.push_str(&HEXLOWER.encode(self.auth_token.secret_key_bytes()));
+        hex
+    }
+
+    /// Parse a case insensitive hex string created by
+    /// [`to_hex`](#method.to_hex).
+    pub fn from_hex_str(hex_str: &str) -> SaltyResult<Self> {
+        let bytes = HEXLOWER_PERMISSIVE.decode(hex_str.as_bytes())
+            .map_err(|e| SaltyError::Decode(format!("Could not decode pairing data hex string: {}", e)))?;
+        if bytes.len() != PAIRING_DATA_BYTES - 1 {
+            return Err(SaltyError::Decode(format!(
+                "Invalid pairing data hex string: Must contain {} bytes, but contains {}",
+                PAIRING_DATA_BYTES - 1, bytes.len(),
+            )));
+        }
+        Self::from_key_and_token_bytes(&bytes)
+    }
+
+    /// Encode the pairing data into the compact binary format.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(PAIRING_DATA_BYTES);
+        bytes.push(PAIRING_DATA_VERSION);
+        bytes.extend_from_slice(&self.initiator_pubkey.0);
+        bytes.extend_from_slice(self.auth_token.secret_key_bytes());
+        bytes
+    }
+
+    /// Parse the compact binary format created by
+    /// [`to_bytes`](#method.to_bytes).
+    pub fn from_bytes(bytes: &[u8]) -> SaltyResult<Self> {
+        if bytes.len() != PAIRING_DATA_BYTES {
+            return Err(SaltyError::Decode(format!(
+                "Invalid pairing data: Must be {} bytes long, but is {}",
+                PAIRING_DATA_BYTES, bytes.len(),
+            )));
+        }
+        if bytes[0] != PAIRING_DATA_VERSION {
+            return Err(SaltyError::Decode(format!(
+                "Unsupported pairing data version: {}", bytes[0]
+            )));
+        }
+        Self::from_key_and_token_bytes(&bytes[1..])
+    }
+
+    /// Parse the concatenated public key and auth token bytes.
+    fn from_key_and_token_bytes(bytes: &[u8]) -> SaltyResult<Self> {
+        let (key_bytes, token_bytes) = bytes.split_at(KEYBYTES);
+        if key_bytes.iter().all(|b| *b == 0) {
+            return Err(SaltyError::Decode(
+                "Invalid pairing data: Public key must not be all-zero".into()
+            ));
+        }
+        let initiator_pubkey = PublicKey::from_slice(key_bytes)
+            .ok_or_else(|| SaltyError::Decode("Invalid pairing data: Could not create public key".into()))?;
+        let auth_token = AuthToken::from_slice(token_bytes)?;
+        Ok(PairingData { initiator_pubkey, auth_token })
+    }
 }
 
 
 /// The number of bytes in the [`SignedKeys`](struct.SignedKeys.html) array.
-const SIGNED_KEYS_BYTES: usize = 2 * box_::PUBLICKEYBYTES + box_::MACBYTES;
+const SIGNED_KEYS_BYTES: usize = 2 * KEYBYTES + MACBYTES;
 
 
 /// A pair of not-yet-signed keys used in the [`ServerAuth`](../messages/struct.ServerAuth.html)
@@ -254,10 +759,9 @@ impl UnsignedKeys {
         let mut bytes = [0u8; 64];
         (&mut bytes[0..32]).write_all(&self.server_public_session_key.0).unwrap();
         (&mut bytes[32..64]).write_all(&self.client_public_permanent_key.0).unwrap();
-        let rust_sodium_nonce: box_::Nonce = nonce.into();
-        let vec = box_::seal(
+        let vec = crypto_backend::box_seal(
             &bytes,
-            &rust_sodium_nonce,
+            &nonce.into_bytes(),
             client_public_permanent_key,
             server_session_keypair.private_key(),
         );
@@ -285,18 +789,20 @@ impl SignedKeys {
         nonce: Nonce,
     ) -> SignalingResult<UnsignedKeys> {
         // Decrypt bytes
-        let rust_sodium_nonce: box_::Nonce = nonce.into();
-        let decrypted = box_::open(
+        let decrypted = crypto_backend::box_open(
             &self.0,
-            &rust_sodium_nonce,
+            &nonce.into_bytes(),
             server_public_permanent_key,
             permanent_key.private_key(),
         ).map_err(|_| SignalingError::Crypto("Could not decrypt signed keys".to_string()))?;
-        assert_eq!(decrypted.len(), 32 * 2);
-        Ok(UnsignedKeys::new(
-           PublicKey::from_slice(&decrypted[0..32]).unwrap(),
-           PublicKey::from_slice(&decrypted[32..64]).unwrap(),
-        ))
+        if decrypted.len() != 32 * 2 {
+            return Err(SignalingError::Decode(
+                format!("Signed keys have invalid length: {}", decrypted.len())
+            ));
+        }
+        let key = |bytes: &[u8]| PublicKey::from_slice(bytes)
+            .ok_or_else(|| SignalingError::Decode("Invalid public key in signed keys".into()));
+        Ok(UnsignedKeys::new(key(&decrypted[0..32])?, key(&decrypted[32..64])?))
     }
 }
 
@@ -362,6 +868,11 @@ impl<'de> Visitor<'de> for SignedKeysVisitor {
     fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Self::Value, E> where E: SerdeError {
         self.visit_bytes(&v)
     }
+
+    /// Accept signed keys encoded as msgpack `str` as well.
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E> where E: SerdeError {
+        self.visit_bytes(v.as_bytes())
+    }
 }
 
 /// Waiting for https://github.com/3Hren/msgpack-rust/issues/129
@@ -377,11 +888,9 @@ use crate::test_helpers::TestRandom;
 #[cfg(test)]
 impl TestRandom for PublicKey {
     fn random() -> PublicKey {
-        use rust_sodium::randombytes::randombytes_into;
-        libsodium_init_or_panic();
         let mut rand = [0; 32];
-        randombytes_into(&mut rand);
-        PublicKey::from_slice(&rand).unwrap()
+        backend().random_bytes(&mut rand);
+        PublicKey(rand)
     }
 }
 
@@ -389,6 +898,7 @@ impl TestRandom for PublicKey {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::crypto_backend::SEALBYTES;
 
     #[test]
     fn new() {
@@ -495,6 +1005,58 @@ mod tests {
         assert_eq!(format!("{}", error), "Crypto error: Could not decrypt data");
     }
 
+    /// The in-place variants must produce the same output as
+    /// `encrypt_precomputed`.
+    #[test]
+    fn encrypt_decrypt_in_place() {
+        let sk_bytes = HEXLOWER.decode(b"8bb6b6ae1497bf0288e6f82923e8875f2fdeab2ab6833e770182b35936232af9").unwrap();
+        let other_sk_bytes = HEXLOWER.decode(b"717284c21d52489ddd8afa1adda32fa332cb0410b72ef83b415314cb12521bfe").unwrap();
+        let nonce_bytes = HEXLOWER.decode(b"fe381c4bdb8bfc2a27d2c9a6485113e7638613ffb02b3747").unwrap();
+        let ks = KeyPair::from_private_key(PrivateKey::from_slice(&sk_bytes).unwrap());
+        let other_ks = KeyPair::from_private_key(PrivateKey::from_slice(&other_sk_bytes).unwrap());
+
+        let mut buf = vec![0; MACBYTES];
+        buf.extend_from_slice(b"hello");
+        ks.encrypt_in_place(&mut buf, Nonce::from_bytes(&nonce_bytes).unwrap(), other_ks.public_key());
+        assert_eq!(HEXLOWER.encode(&buf), "687f2cb605d80a0660bacb2c6ce6e076591b58f9c9");
+
+        let decrypted = other_ks.decrypt_in_place(&mut buf, Nonce::from_bytes(&nonce_bytes).unwrap(), ks.public_key());
+        assert_eq!(decrypted.unwrap(), b"hello");
+
+        // Too short for the authentication tag
+        let nonce = Nonce::from_bytes(&nonce_bytes).unwrap();
+        assert!(other_ks.decrypt_in_place(&mut [0; 4], nonce, ks.public_key()).is_err());
+
+        // Secret key encryption
+        let token = AuthToken::new();
+        let mut buf = vec![0; MACBYTES];
+        buf.extend_from_slice(b"hello");
+        token.encrypt_in_place(&mut buf, Nonce::from_bytes(&nonce_bytes).unwrap());
+        let nonce = Nonce::from_bytes(&nonce_bytes).unwrap();
+        assert_eq!(token.decrypt(&buf, nonce).unwrap(), b"hello".to_vec());
+        let nonce = Nonce::from_bytes(&nonce_bytes).unwrap();
+        assert_eq!(token.decrypt_in_place(&mut buf, nonce).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn sealed_box_roundtrip() {
+        let ks = KeyPair::new();
+        let token = AuthToken::new();
+        let sealed = KeyPair::seal_for(ks.public_key(), token.secret_key_bytes());
+        assert_eq!(sealed.len(), token.secret_key_bytes().len() + SEALBYTES);
+        assert_eq!(ks.open_sealed(&sealed).unwrap(), token.secret_key_bytes().to_vec());
+
+        // Only the owner of the private key can open the box
+        let error = KeyPair::new().open_sealed(&sealed).unwrap_err();
+        assert_eq!(error, SaltyError::Crypto("Could not open sealed box".into()));
+
+        // Tampering is detected
+        let mut tampered = sealed.clone();
+        tampered[SEALBYTES] ^= 1;
+        assert!(ks.open_sealed(&tampered).is_err());
+        assert!(ks.open_sealed(&sealed[..SEALBYTES - 1]).is_err());
+    }
+
     /// Test the `AuthToken::from_hex_str` method.
     #[test]
     fn auth_token_from_hex_str() {
@@ -511,6 +1073,113 @@ mod tests {
         let _ = res3.unwrap();
     }
 
+    #[test]
+    fn public_key_hex_base64() {
+        let key = PublicKey::from_slice(&[0xab; 32]).unwrap();
+        let hex = public_key_to_hex(&key);
+        assert_eq!(hex, "ab".repeat(32));
+        assert_eq!(public_key_from_hex_str(&hex).unwrap(), key);
+        let base64 = public_key_to_base64(&key);
+        assert_eq!(base64, "q6urq6urq6urq6urq6urq6urq6urq6urq6urq6urq6s=");
+        assert_eq!(public_key_from_base64_str(&base64).unwrap(), key);
+        assert_eq!(public_key_from_str(&base64).unwrap(), key);
+        assert!(public_key_from_base64_str("q6urq6s=").is_err());
+    }
+
+    #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    struct PairingInfo {
+        #[serde(with = "serde_public_key")]
+        key: PublicKey,
+        token: AuthToken,
+    }
+
+    #[test]
+    fn key_serde() {
+        let info = PairingInfo {
+            key: PublicKey::from_slice(&[0xab; 32]).unwrap(),
+            token: AuthToken::from_slice(&[1; 32]).unwrap(),
+        };
+
+        // Human readable formats use hex strings
+        let json = serde_json::to_string(&info).unwrap();
+        assert_eq!(json, format!("{{\"key\":\"{}\",\"token\":\"{}\"}}", "ab".repeat(32), "01".repeat(32)));
+        assert_eq!(serde_json::from_str::<PairingInfo>(&json).unwrap(), info);
+
+        // Base64 is accepted as well
+        let json = format!("{{\"key\":\"{}\",\"token\":\"{}\"}}",
+                           public_key_to_base64(&info.key), info.token.to_base64());
+        assert_eq!(serde_json::from_str::<PairingInfo>(&json).unwrap(), info);
+
+        // Roundtrip through msgpack
+        let msgpack = rmp_serde::to_vec(&info).unwrap();
+        assert_eq!(rmp_serde::from_slice::<PairingInfo>(&msgpack).unwrap(), info);
+
+        assert!(serde_json::from_str::<AuthToken>("\"0102\"").is_err());
+    }
+
+    #[test]
+    fn auth_token_display_parse() {
+        let token = AuthToken::from_slice(&[0x0f; 32]).unwrap();
+        assert_eq!(token.to_string(), "0f".repeat(32));
+        assert_eq!(format!("{:x}", token), token.to_string());
+        assert_eq!(token.to_string().to_uppercase().parse::<AuthToken>().unwrap(), token);
+        assert_eq!(token.to_base64().parse::<AuthToken>().unwrap(), token);
+        assert_eq!(AuthToken::from_base64_str(&token.to_base64()).unwrap(), token);
+        assert!("0f0f".parse::<AuthToken>().is_err());
+    }
+
+    #[test]
+    fn pairing_data_hex_roundtrip() {
+        let data = PairingData::new(*KeyPair::new().public_key(), AuthToken::new());
+        let hex = data.to_hex();
+        assert_eq!(hex.len(), 128);
+        assert_eq!(PairingData::from_hex_str(&hex).unwrap(), data);
+        assert_eq!(PairingData::from_hex_str(&hex.to_uppercase()).unwrap(), data);
+    }
+
+    #[test]
+    fn pairing_data_bytes_roundtrip() {
+        let data = PairingData::new(*KeyPair::new().public_key(), AuthToken::new());
+        let bytes = data.to_bytes();
+        assert_eq!(bytes.len(), 65);
+        assert_eq!(bytes[0], 1);
+        assert_eq!(&bytes[1..33], &data.initiator_pubkey().0[..]);
+        assert_eq!(&bytes[33..], data.auth_token().secret_key_bytes());
+        assert_eq!(PairingData::from_bytes(&bytes).unwrap(), data);
+    }
+
+    #[test]
+    fn pairing_data_invalid() {
+        let data = PairingData::new(*KeyPair::new().public_key(), AuthToken::new());
+
+        let mut bytes = data.to_bytes();
+        bytes[0] = 2;
+        assert_eq!(
+            PairingData::from_bytes(&bytes),
+            Err(SaltyError::Decode("Unsupported pairing data version: 2".into()))
+        );
+
+        let bytes = data.to_bytes();
+        assert_eq!(
+            PairingData::from_bytes(&bytes[..64]),
+            Err(SaltyError::Decode("Invalid pairing data: Must be 65 bytes long, but is 64".into()))
+        );
+
+        let mut bytes = data.to_bytes();
+        for b in &mut bytes[1..33] {
+            *b = 0;
+        }
+        assert_eq!(
+            PairingData::from_bytes(&bytes),
+            Err(SaltyError::Decode("Invalid pairing data: Public key must not be all-zero".into()))
+        );
+
+        assert_eq!(
+            PairingData::from_hex_str(&data.to_hex()[..126]),
+            Err(SaltyError::Decode("Invalid pairing data hex string: Must contain 64 bytes, but contains 63".into()))
+        );
+    }
+
     /// Test the `AuthToken::from_slice` method.
     #[test]
     fn auth_token_from_slice() {
@@ -580,10 +1249,10 @@ mod tests {
             unsafe { nonce.clone() },
         );
 
-        // Decrypt directly with libsodium
-        let decrypted = box_::open(
+        // Decrypt directly with the crypto backend
+        let decrypted = crypto_backend::box_open(
             &signed.0,
-            &{ unsafe { nonce.clone() } }.into(),
+            &unsafe { nonce.clone() }.into_bytes(),
             kp_server.public_key(),
             kp_client.private_key(),
         ).unwrap();
@@ -595,4 +1264,16 @@ mod tests {
         let unsigned2 = signed.decrypt(&kp_client, kp_server.public_key(), nonce).unwrap();
         assert_eq!(unsigned, unsigned2);
     }
+
+    #[test]
+    fn signed_keys_decrypt_garbage() {
+        let kp_server = KeyPair::new();
+        let kp_client = KeyPair::new();
+        let nonce = Nonce::from_bytes(&[1; 24]).unwrap();
+
+        // Random bytes must result in an error, not a panic
+        let signed = SignedKeys::new([0xff; SIGNED_KEYS_BYTES]);
+        let err = signed.decrypt(&kp_client, kp_server.public_key(), nonce).unwrap_err();
+        assert_eq!(err, SignalingError::Crypto("Could not decrypt signed keys".into()));
+    }
 }
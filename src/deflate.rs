@@ -0,0 +1,444 @@
+//! Decompression of WebSocket messages compressed with the
+//! permessage-deflate extension (RFC 7692).
+//!
+//! The codec of the `websocket` crate does not support extensions and
+//! rejects frames with reserved bits set. If permessage-deflate is enabled,
+//! the extension is offered in the WebSocket handshake, and the
+//! [`ResponseTap`](../response_tap/struct.ResponseTap.html) around the server
+//! connection rewrites compressed messages into plain frames with an
+//! [`Inflater`](struct.Inflater.html) before the codec sees them.
+//!
+//! Outgoing messages are never compressed, which the extension allows: The
+//! payloads are encrypted, so compressing them would only waste time.
+//!
+//! This module is only available with the `permessage-deflate` feature.
+
+use std::io::{self, Read};
+
+use flate2::{Decompress, FlushDecompress, Status};
+use websocket::header::extensions::Extension;
+
+use crate::boxes::MAX_FRAME_LEN;
+
+
+/// The name of the extension.
+pub(crate) const EXTENSION_NAME: &str = "permessage-deflate";
+
+/// The bytes removed from the end of every compressed message.
+const TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// The maximum length of the HTTP response head.
+const MAX_HEAD_LEN: usize = 16 * 1024;
+
+
+/// Return the extension offered in the WebSocket handshake.
+///
+/// No parameters are offered, so the server may use any window size and
+/// keep the compression context between messages.
+pub(crate) fn extension() -> Extension {
+    Extension::new(EXTENSION_NAME.to_string())
+}
+
+/// Return whether the extension was accepted in the specified extension
+/// header values.
+pub(crate) fn is_negotiated<'a, I: IntoIterator<Item=&'a Extension>>(extensions: I) -> bool {
+    extensions.into_iter().any(|extension| extension.name == EXTENSION_NAME)
+}
+
+
+/// What the inflater does with the bytes read from the server.
+#[derive(Debug, PartialEq)]
+enum State {
+    /// Collecting the HTTP response head.
+    Head(Vec<u8>),
+    /// The extension was negotiated, frames are parsed.
+    Frames,
+    /// The extension was not negotiated, bytes are passed through.
+    PassThrough,
+}
+
+/// A compressed message whose frames are being collected.
+struct Message {
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Rewrites compressed WebSocket messages read from the server into plain
+/// frames.
+pub(crate) struct Inflater {
+    state: State,
+    /// Received bytes that don't form a complete frame yet.
+    input: Vec<u8>,
+    /// Rewritten bytes, ready to be read.
+    output: Vec<u8>,
+    message: Option<Message>,
+    decompress: Decompress,
+}
+
+impl ::std::fmt::Debug for Inflater {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_struct("Inflater")
+            .field("state", &self.state)
+            .field("input", &self.input.len())
+            .field("output", &self.output.len())
+            .finish()
+    }
+}
+
+impl Inflater {
+    pub(crate) fn new() -> Self {
+        Inflater {
+            state: State::Head(Vec::new()),
+            input: Vec::new(),
+            output: Vec::new(),
+            message: None,
+            // The server compresses raw deflate streams without zlib header
+            decompress: Decompress::new(false),
+        }
+    }
+
+    /// Read from `inner`, rewriting compressed messages.
+    pub(crate) fn read<R: Read>(&mut self, inner: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+        let mut chunk = [0; 8192];
+        loop {
+            if !self.output.is_empty() {
+                let n = buf.len().min(self.output.len());
+                buf[..n].copy_from_slice(&self.output[..n]);
+                self.output.drain(..n);
+                return Ok(n);
+            }
+            if self.state == State::PassThrough {
+                return inner.read(buf);
+            }
+            let n = inner.read(&mut chunk)?;
+            if n == 0 {
+                return Ok(0);
+            }
+            self.process(&chunk[..n])?;
+        }
+    }
+
+    /// Handle bytes received from the server.
+    fn process(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let negotiated = match self.state {
+            State::Frames => {
+                self.input.extend_from_slice(bytes);
+                return self.process_frames();
+            },
+            State::PassThrough => {
+                self.output.extend_from_slice(bytes);
+                return Ok(());
+            },
+            State::Head(ref mut head) => {
+                head.extend_from_slice(bytes);
+                let end = match head.windows(4).position(|window| window == b"\r\n\r\n") {
+                    Some(position) => position + 4,
+                    None if head.len() > MAX_HEAD_LEN => head.len(),
+                    None => return Ok(()),
+                };
+                self.output.extend_from_slice(&head[..end]);
+                self.input.extend_from_slice(&head[end..]);
+                head_negotiated(&head[..end])
+            },
+        };
+        if negotiated {
+            self.state = State::Frames;
+            self.process_frames()
+        } else {
+            self.state = State::PassThrough;
+            self.output.append(&mut self.input);
+            Ok(())
+        }
+    }
+
+    /// Handle the complete frames in the input buffer.
+    fn process_frames(&mut self) -> io::Result<()> {
+        while let Some((header_len, payload_len)) = parse_frame_header(&self.input)? {
+            let frame_len = header_len + payload_len;
+            if self.input.len() < frame_len {
+                break;
+            }
+            let frame: Vec<u8> = self.input.drain(..frame_len).collect();
+            self.process_frame(frame, header_len)?;
+        }
+        Ok(())
+    }
+
+    fn process_frame(&mut self, frame: Vec<u8>, header_len: usize) -> io::Result<()> {
+        let fin = frame[0] & 0x80 != 0;
+        let compressed = frame[0] & 0x40 != 0;
+        let opcode = frame[0] & 0x0f;
+        let mut payload = frame[header_len..].to_vec();
+        if frame[1] & 0x80 != 0 {
+            // Servers must not mask frames, but unmask them anyway
+            let mask = [frame[header_len - 4], frame[header_len - 3], frame[header_len - 2], frame[header_len - 1]];
+            for (i, byte) in payload.iter_mut().enumerate() {
+                *byte ^= mask[i % 4];
+            }
+        }
+
+        // Control frames may be sent within a fragmented message
+        if opcode >= 0x8 {
+            self.output.extend_from_slice(&frame);
+            return Ok(());
+        }
+        match self.message {
+            Some(ref mut message) => {
+                if opcode != 0x0 {
+                    return Err(invalid_data("New message before the compressed message ended"));
+                }
+                if message.payload.len() + payload.len() > MAX_FRAME_LEN {
+                    return Err(invalid_data("Compressed message is too long"));
+                }
+                message.payload.extend_from_slice(&payload);
+            },
+            None if compressed && opcode != 0x0 => self.message = Some(Message { opcode, payload }),
+            None => {
+                self.output.extend_from_slice(&frame);
+                return Ok(());
+            },
+        }
+        if fin {
+            let message = self.message.take().expect("Compressed message vanished");
+            let payload = self.inflate(message.payload)?;
+            write_frame(&mut self.output, message.opcode, &payload);
+        }
+        Ok(())
+    }
+
+    /// Decompress the payload of a message.
+    fn inflate(&mut self, mut compressed: Vec<u8>) -> io::Result<Vec<u8>> {
+        compressed.extend_from_slice(&TRAILER);
+        let mut inflated = Vec::with_capacity(compressed.len() * 2);
+        let mut chunk = [0; 8192];
+        let mut consumed = 0;
+        loop {
+            let (total_in, total_out) = (self.decompress.total_in(), self.decompress.total_out());
+            let status = self.decompress
+                .decompress(&compressed[consumed..], &mut chunk, FlushDecompress::Sync)
+                .map_err(|e| invalid_data(&format!("Could not inflate message: {}", e)))?;
+            let read = (self.decompress.total_in() - total_in) as usize;
+            let written = (self.decompress.total_out() - total_out) as usize;
+            consumed += read;
+            inflated.extend_from_slice(&chunk[..written]);
+            if inflated.len() > MAX_FRAME_LEN {
+                return Err(invalid_data("Inflated message is too long"));
+            }
+            if status == Status::StreamEnd {
+                // The server ended the deflate stream, the next message
+                // starts a new one
+                self.decompress.reset(false);
+                break;
+            }
+            if consumed == compressed.len() && written < chunk.len() {
+                break;
+            }
+            if read == 0 && written == 0 {
+                return Err(invalid_data("Truncated compressed message"));
+            }
+        }
+        Ok(inflated)
+    }
+}
+
+fn invalid_data(description: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, description)
+}
+
+/// Return whether the response head accepts the extension.
+fn head_negotiated(head: &[u8]) -> bool {
+    let head = String::from_utf8_lossy(head);
+    let mut lines = head.split("\r\n");
+    if lines.next().and_then(|status| status.split(' ').nth(1)) != Some("101") {
+        return false;
+    }
+    lines
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) if name.trim().eq_ignore_ascii_case("sec-websocket-extensions") => Some(value),
+                _ => None,
+            }
+        })
+        .flat_map(|value| value.split(','))
+        .any(|extension| extension.split(';').next().map(str::trim) == Some(EXTENSION_NAME))
+}
+
+/// Return the length of the header and of the payload of the frame at the
+/// start of `bytes`, once the header is complete.
+fn parse_frame_header(bytes: &[u8]) -> io::Result<Option<(usize, usize)>> {
+    if bytes.len() < 2 {
+        return Ok(None);
+    }
+    let mask_len = if bytes[1] & 0x80 != 0 { 4 } else { 0 };
+    let (len_len, payload_len) = match bytes[1] & 0x7f {
+        126 if bytes.len() >= 4 => (2, u64::from(bytes[2]) << 8 | u64::from(bytes[3])),
+        127 if bytes.len() >= 10 => (8, bytes[2..10].iter().fold(0, |len, byte| len << 8 | u64::from(*byte))),
+        126 | 127 => return Ok(None),
+        len => (0, u64::from(len)),
+    };
+    if payload_len > MAX_FRAME_LEN as u64 {
+        return Err(invalid_data("Frame is too long"));
+    }
+    let header_len = 2 + len_len + mask_len;
+    if bytes.len() < header_len {
+        return Ok(None);
+    }
+    Ok(Some((header_len, payload_len as usize)))
+}
+
+/// Append an unmasked, unfragmented frame.
+fn write_frame(dst: &mut Vec<u8>, opcode: u8, payload: &[u8]) {
+    dst.push(0x80 | opcode);
+    match payload.len() {
+        len if len < 126 => dst.push(len as u8),
+        len if len <= 0xffff => {
+            dst.push(126);
+            dst.extend_from_slice(&[(len >> 8) as u8, len as u8]);
+        },
+        len => {
+            dst.push(127);
+            dst.extend((0..8).rev().map(|i| (len as u64 >> (i * 8)) as u8));
+        },
+    }
+    dst.extend_from_slice(payload);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use bytes::BytesMut;
+    use flate2::Compression;
+    use flate2::write::DeflateEncoder;
+    use tokio_io::codec::{Decoder, Encoder};
+    use websocket::r#async::{MessageCodec, MsgCodecCtx};
+    use websocket::message::OwnedMessage;
+
+    use super::*;
+
+    const HEAD: &[u8] = b"HTTP/1.1 101 Switching Protocols\r\n\
+        Upgrade: websocket\r\n\
+        Sec-WebSocket-Extensions: permessage-deflate; server_no_context_takeover\r\n\r\n";
+
+    /// Compress a message like the server, and return the frame.
+    fn compressed_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(payload).unwrap();
+        encoder.flush().unwrap();
+        let mut compressed = encoder.finish().unwrap();
+        // Remove the final empty block and the trailer of the sync flush
+        while !compressed.ends_with(&TRAILER) {
+            compressed.pop();
+        }
+        compressed.truncate(compressed.len() - TRAILER.len());
+        let mut frame = vec![];
+        write_frame(&mut frame, opcode, &compressed);
+        frame[0] |= 0x40;
+        frame
+    }
+
+    fn plain_frame(message: OwnedMessage) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        MessageCodec::default(MsgCodecCtx::Server).encode(message, &mut buf).unwrap();
+        buf.to_vec()
+    }
+
+    /// Read everything through an inflater, in chunks of `chunk_size` bytes.
+    fn read_all(bytes: Vec<u8>, chunk_size: usize) -> io::Result<Vec<u8>> {
+        let mut inflater = Inflater::new();
+        let mut inner = Cursor::new(bytes);
+        let mut output = vec![];
+        let mut buf = vec![0; chunk_size];
+        loop {
+            match inflater.read(&mut inner, &mut buf)? {
+                0 => return Ok(output),
+                n => output.extend_from_slice(&buf[..n]),
+            }
+        }
+    }
+
+    fn decode_messages(bytes: &[u8]) -> Vec<OwnedMessage> {
+        let mut codec = MessageCodec::default(MsgCodecCtx::Client);
+        let mut buf = BytesMut::from(bytes);
+        let mut messages = vec![];
+        while let Some(message) = codec.decode(&mut buf).unwrap() {
+            messages.push(message);
+        }
+        assert!(buf.is_empty());
+        messages
+    }
+
+    #[test]
+    fn negotiation() {
+        assert!(head_negotiated(HEAD));
+        assert!(head_negotiated(b"HTTP/1.1 101 OK\r\nsec-websocket-extensions: foo, permessage-deflate\r\n\r\n"));
+        assert!(!head_negotiated(b"HTTP/1.1 101 OK\r\nSec-WebSocket-Extensions: permessage-foo\r\n\r\n"));
+        assert!(!head_negotiated(b"HTTP/1.1 404 Not Found\r\nSec-WebSocket-Extensions: permessage-deflate\r\n\r\n"));
+        assert!(is_negotiated(&[extension()]));
+        assert!(!is_negotiated(&[]));
+    }
+
+    #[test]
+    fn inflate_messages() {
+        let large: Vec<u8> = (0..100_000u32).map(|i| (i % 7) as u8).collect();
+        let mut bytes = HEAD.to_vec();
+        bytes.extend(compressed_frame(0x2, b"hello"));
+        bytes.extend(plain_frame(OwnedMessage::Ping(vec![1, 2])));
+        bytes.extend(plain_frame(OwnedMessage::Binary(vec![3, 4])));
+        bytes.extend(compressed_frame(0x2, &large));
+        bytes.extend(compressed_frame(0x2, b"hello again"));
+
+        for &chunk_size in &[1, 5, 4096] {
+            let output = read_all(bytes.clone(), chunk_size).unwrap();
+            assert!(output.starts_with(HEAD));
+            assert_eq!(decode_messages(&output[HEAD.len()..]), vec![
+                OwnedMessage::Binary(b"hello".to_vec()),
+                OwnedMessage::Ping(vec![1, 2]),
+                OwnedMessage::Binary(vec![3, 4]),
+                OwnedMessage::Binary(large.clone()),
+                OwnedMessage::Binary(b"hello again".to_vec()),
+            ]);
+        }
+    }
+
+    #[test]
+    fn inflate_fragmented_message() {
+        let frame = compressed_frame(0x2, b"fragmented message");
+        let compressed = &frame[2..];
+        let mut bytes = HEAD.to_vec();
+        // The first fragment carries the opcode and the compression bit
+        bytes.extend_from_slice(&[0x42, 4]);
+        bytes.extend_from_slice(&compressed[..4]);
+        bytes.extend(plain_frame(OwnedMessage::Pong(vec![])));
+        bytes.extend_from_slice(&[0x80, (compressed.len() - 4) as u8]);
+        bytes.extend_from_slice(&compressed[4..]);
+
+        let output = read_all(bytes, 3).unwrap();
+        assert_eq!(decode_messages(&output[HEAD.len()..]), vec![
+            OwnedMessage::Pong(vec![]),
+            OwnedMessage::Binary(b"fragmented message".to_vec()),
+        ]);
+    }
+
+    #[test]
+    fn pass_through_without_extension() {
+        let head = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\r\n";
+        let mut bytes = head.to_vec();
+        bytes.extend(plain_frame(OwnedMessage::Binary(vec![1, 2, 3])));
+        assert_eq!(read_all(bytes.clone(), 7).unwrap(), bytes);
+
+        // Compressed frames are left to the codec, which rejects them
+        let mut bytes = head.to_vec();
+        bytes.extend(compressed_frame(0x2, b"hello"));
+        assert_eq!(read_all(bytes.clone(), 7).unwrap(), bytes);
+    }
+
+    #[test]
+    fn invalid_compressed_message() {
+        let mut bytes = HEAD.to_vec();
+        bytes.extend_from_slice(&[0xc2, 3, 0xff, 0xff, 0xff]);
+        assert_eq!(read_all(bytes, 64).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
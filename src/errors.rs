@@ -1,15 +1,27 @@
 //! Error types used in saltyrtc-client.
 //!
 //! The implementation is done using the
-//! [`failure`](https://crates.io/crates/failure) crate.
+//! [`failure`](https://crates.io/crates/failure) crate. Wrapped errors are
+//! available through [`Fail::cause`](../../failure/trait.Fail.html#method.cause).
+//!
+//! Every public error has a numeric code (see e.g.
+//! [`SaltyError::code`](enum.SaltyError.html#method.code)), which is useful
+//! where only a number can be passed on, e.g. over FFI. The codes are stable:
+//! New errors get new codes, and codes of removed errors are not reused.
 
 use std::convert::From;
+use std::fmt;
 use std::sync::TryLockError;
 
 use failure::Fail;
 use rmp_serde::decode::Error as SerdeDecodeError;
+#[cfg(feature = "connect-tokio")]
 use tokio_timer::TimeoutError;
 
+use crate::{CloseCode, CloseReason};
+use crate::protocol::nonce::Nonce;
+use crate::protocol::types::{Address, Identity};
+
 
 /// Re-exported [`Error`](../../failure/struct.Error.html) type from the
 /// [failure crate](https://crates.io/crates/failure).
@@ -27,6 +39,10 @@ pub enum SaltyError {
     #[fail(display = "Decoding error: {}", _0)]
     Decode(String),
 
+    /// A received frame could not be parsed into a byte box.
+    #[fail(display = "Invalid frame: {}", _0)]
+    Frame(#[cause] FrameError),
+
     /// A network related problem.
     #[fail(display = "Network error: {}", _0)]
     Network(String),
@@ -55,6 +71,70 @@ pub enum SaltyError {
     /// A future timed out.
     #[fail(display = "Future timed out")]
     Timeout,
+
+    /// The connection was closed before the handshake was done.
+    #[fail(display = "Connection closed: {}", _0)]
+    Closed(CloseReason),
+
+    /// The server did not accept the connection.
+    #[fail(display = "Could not connect: {}", _0)]
+    Connect(#[cause] ConnectError),
+
+    /// The client could not be built.
+    #[fail(display = "Could not build client: {}", _0)]
+    Builder(#[cause] BuilderError),
+
+    /// A message was received that is not expected in the current state.
+    #[fail(display = "Unexpected message: {}", _0)]
+    UnexpectedMessage(ErrorContext),
+
+    /// The session keys could not be rotated.
+    #[fail(display = "Cannot rotate session keys: {}", _0)]
+    Rekey(String),
+
+    /// The server could not relay a message to a peer, because the
+    /// connection between the server and the peer has been severed.
+    #[fail(display = "Server could not relay {}", _0)]
+    SendError(FailedMessage),
+}
+
+impl SaltyError {
+    /// Return the numeric code of this error.
+    ///
+    /// Wrapped connect, builder and frame errors return the code of the
+    /// wrapped error.
+    pub fn code(&self) -> u32 {
+        match *self {
+            SaltyError::Crypto(_) => 1,
+            SaltyError::Decode(_) => 2,
+            SaltyError::Network(_) => 3,
+            SaltyError::Protocol(_) => 4,
+            SaltyError::NoSharedTask => 5,
+            SaltyError::NoPeer => 6,
+            SaltyError::Task(_) => 7,
+            SaltyError::Crash(_) => 8,
+            SaltyError::Timeout => 9,
+            SaltyError::Closed(_) => 10,
+            SaltyError::Connect(ref e) => e.code(),
+            SaltyError::Builder(ref e) => e.code(),
+            SaltyError::UnexpectedMessage(_) => 11,
+            SaltyError::Rekey(_) => 12,
+            SaltyError::SendError(_) => 13,
+            SaltyError::Frame(ref e) => e.code(),
+        }
+    }
+}
+
+impl From<ConnectError> for SaltyError {
+    fn from(e: ConnectError) -> Self {
+        SaltyError::Connect(e)
+    }
+}
+
+impl From<BuilderError> for SaltyError {
+    fn from(e: BuilderError) -> Self {
+        SaltyError::Builder(e)
+    }
 }
 
 impl From<SignalingError> for SaltyError {
@@ -64,19 +144,28 @@ impl From<SignalingError> for SaltyError {
             SignalingError::Crypto(msg) => SaltyError::Crypto(msg),
             SignalingError::CsnOverflow => SaltyError::Crypto(e.to_string()),
             SignalingError::Decode(msg) => SaltyError::Decode(msg),
+            SignalingError::Frame(e) => SaltyError::Frame(e),
             SignalingError::InitiatorCouldNotDecrypt => SaltyError::Crypto(e.to_string()),
+            SignalingError::InitiatorTimeout => SaltyError::Timeout,
+            SignalingError::HandshakeTimeout => SaltyError::Timeout,
+            SignalingError::InvalidKey(_) => SaltyError::Protocol(e.to_string()),
             SignalingError::InvalidMessage(_) => SaltyError::Protocol(e.to_string()),
             SignalingError::InvalidNonce(_) => SaltyError::Protocol(e.to_string()),
             SignalingError::InvalidStateTransition(_) => SaltyError::Crash(e.to_string()),
             SignalingError::NoSharedTask => SaltyError::NoSharedTask,
             SignalingError::NoPeer => SaltyError::NoPeer,
             SignalingError::Protocol(msg) => SaltyError::Protocol(msg),
-            SignalingError::SendError => SaltyError::Network(e.to_string()),
+            SignalingError::SendError(failed) => SaltyError::SendError(failed),
+            SignalingError::Rekey(msg) => SaltyError::Rekey(msg),
+            SignalingError::ServerSendError => SaltyError::Protocol(e.to_string()),
             SignalingError::TaskInitialization(_) => SaltyError::Task(e.to_string()),
+            SignalingError::UnexpectedMessage(context) => SaltyError::UnexpectedMessage(context),
+            SignalingError::UnknownMessageType(_) => SaltyError::Protocol(e.to_string()),
         }
     }
 }
 
+#[cfg(feature = "connect-tokio")]
 impl<F> From<TimeoutError<F>> for SaltyError {
     fn from(_: TimeoutError<F>) -> Self {
         SaltyError::Timeout
@@ -101,6 +190,10 @@ pub(crate) enum SignalingError {
     #[fail(display = "Decoding error: {}", _0)]
     Decode(String),
 
+    /// A received frame could not be parsed into a byte box.
+    #[fail(display = "Invalid frame: {}", _0)]
+    Frame(#[cause] FrameError),
+
     /// Nonce validation fails.
     #[fail(display = "Invalid nonce: {}", _0)]
     InvalidNonce(String),
@@ -122,6 +215,14 @@ pub(crate) enum SignalingError {
     #[fail(display = "Invalid message: {}", _0)]
     InvalidMessage(String),
 
+    /// A message was received that is not expected in the current state.
+    #[fail(display = "Unexpected message: {}", _0)]
+    UnexpectedMessage(ErrorContext),
+
+    /// A message has a type that this implementation does not know.
+    #[fail(display = "Unknown message type: {}", _0)]
+    UnknownMessageType(String),
+
     /// Something happened that violates the protocol.
     /// This error should mainly be used if the event that happened is outside
     /// of our control (e.g. if the peer sends a message we didn't expect).
@@ -130,11 +231,26 @@ pub(crate) enum SignalingError {
     #[fail(display = "A protocol error occurred: {}", _0)]
     Protocol(String),
 
+    /// A peer sent a public session key that must not be used, e.g. because
+    /// it is equal to one of the permanent keys.
+    #[fail(display = "Invalid key: {}", _0)]
+    InvalidKey(String),
+
     /// The server returned a `SendError` message. This means that a
     /// client-to-client message could not be relayed (the connection between
     /// server and the receiver has been severed).
-    #[fail(display = "Server could not relay message")]
-    SendError,
+    #[fail(display = "Server could not relay {}", _0)]
+    SendError(FailedMessage),
+
+    /// The server returned a `SendError` message that refers to a message
+    /// sent to the server itself. This is a protocol violation by the
+    /// server.
+    #[fail(display = "Server reported a send-error for a message to the server")]
+    ServerSendError,
+
+    /// The session keys cannot be rotated.
+    #[fail(display = "Cannot rotate session keys: {}", _0)]
+    Rekey(String),
 
     /// No shared task was found during the handshake.
     #[fail(display = "No shared task found")]
@@ -152,15 +268,117 @@ pub(crate) enum SignalingError {
     #[fail(display = "Initiator could not decrypt key message")]
     InitiatorCouldNotDecrypt,
 
+    /// No initiator connected to the server within the initiator timeout.
+    #[fail(display = "No initiator connected in time")]
+    InitiatorTimeout,
+
+    /// The server and peer handshake did not finish within the handshake
+    /// timeout.
+    #[fail(display = "Handshake did not finish in time")]
+    HandshakeTimeout,
+
     /// An unexpected error. This should never happen and indicates a bug in
     /// the implementation.
     #[fail(display = "An unexpected error occurred: {}. This indicates a bug and should be reported!", _0)]
     Crash(String),
 }
 
+/// Details about a message that was received in a state where it is not
+/// expected.
+///
+/// Include these in bug reports, they do not contain any secrets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorContext {
+    /// The signaling state, e.g. `PeerHandshake`.
+    pub signaling_state: String,
+    /// The state of the handshake with the sender of the message.
+    pub handshake_state: String,
+    /// The type of the message.
+    pub message_type: String,
+    /// The address of the sender.
+    pub source: u8,
+    /// The address of the receiver.
+    pub destination: u8,
+    /// The combined sequence number of the message.
+    pub csn: u64,
+    /// The first bytes of the cookie of the sender, as hex string.
+    pub cookie_fingerprint: String,
+}
+
+impl ErrorContext {
+    /// Describe a message that was received with the specified nonce.
+    pub(crate) fn new<S, H>(signaling_state: S, handshake_state: H, message_type: &str, nonce: &Nonce) -> Self
+            where S: fmt::Debug, H: fmt::Debug {
+        ErrorContext {
+            signaling_state: format!("{:?}", signaling_state),
+            handshake_state: format!("{:?}", handshake_state),
+            message_type: message_type.to_string(),
+            source: nonce.source().0,
+            destination: nonce.destination().0,
+            csn: nonce.csn().combined_sequence_number(),
+            cookie_fingerprint: nonce.cookie().fingerprint(),
+        }
+    }
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "'{}' message from {} to {} in {} state ({}), CSN {}, cookie {}..",
+            self.message_type,
+            Identity::from(Address(self.source)),
+            Identity::from(Address(self.destination)),
+            self.signaling_state,
+            self.handshake_state,
+            self.csn,
+            self.cookie_fingerprint,
+        )
+    }
+}
+
+/// A client-to-client message that the server could not relay, see
+/// [`SaltyError::SendError`](enum.SaltyError.html#variant.SendError).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FailedMessage {
+    /// The address of the receiver.
+    pub destination: u8,
+    /// The message type, if the message is one of the recently sent messages.
+    pub message_type: Option<String>,
+}
+
+impl fmt::Display for FailedMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let receiver = Identity::from(Address(self.destination));
+        match self.message_type {
+            Some(ref message_type) => write!(f, "'{}' message to {}", message_type, receiver),
+            None => write!(f, "message to {}", receiver),
+        }
+    }
+}
+
 /// A result with [`SignalingError`](enum.SignalingError.html) as error type.
 pub(crate) type SignalingResult<T> = ::std::result::Result<T, SignalingError>;
 
+impl SignalingError {
+    /// Return the close code that must be sent to the server when the
+    /// connection is closed because of this error, if the specification
+    /// mandates one.
+    pub(crate) fn close_code(&self) -> Option<CloseCode> {
+        match *self {
+            SignalingError::InvalidNonce(_) |
+            SignalingError::InvalidMessage(_) |
+            SignalingError::Protocol(_) |
+            SignalingError::UnknownMessageType(_) |
+            SignalingError::ServerSendError => Some(CloseCode::ProtocolError),
+            SignalingError::InvalidKey(_) => Some(CloseCode::InvalidKey),
+            SignalingError::InitiatorTimeout => Some(CloseCode::WsClosingNormal),
+            SignalingError::HandshakeTimeout => Some(CloseCode::WsGoingAway),
+            _ => None,
+        }
+    }
+}
+
 impl From<SerdeDecodeError> for SignalingError {
     fn from(e: SerdeDecodeError) -> Self {
         SignalingError::Decode(format!("Could not decode msgpack data: {}", e))
@@ -180,6 +398,208 @@ pub enum BuilderError {
     /// No task has been added.
     #[fail(display = "No task specified")]
     MissingTask,
+    /// A task with the same name has been added more than once.
+    #[fail(display = "Task with name \"{}\" was added twice", _0)]
+    DuplicateTask(String),
+    /// The resumption token could not be decrypted.
+    #[cfg(feature = "resumption")]
+    #[fail(display = "{}", _0)]
+    Resumption(#[cause] ResumptionError),
+    /// The server session is not valid.
+    #[cfg(feature = "server-session")]
+    #[fail(display = "Invalid server session: {}", _0)]
+    InvalidServerSession(String),
+}
+
+impl BuilderError {
+    /// Return the numeric code of this error.
+    ///
+    /// Wrapped resumption errors return the code of the wrapped error.
+    pub fn code(&self) -> u32 {
+        match *self {
+            BuilderError::MissingTask => 30,
+            BuilderError::DuplicateTask(_) => 31,
+            #[cfg(feature = "resumption")]
+            BuilderError::Resumption(ref e) => e.code(),
+            #[cfg(feature = "server-session")]
+            BuilderError::InvalidServerSession(_) => 32,
+        }
+    }
+}
+
+
+/// Reasons why the server did not accept a connection.
+#[derive(Fail, Debug, PartialEq, Clone)]
+pub enum ConnectError {
+    /// The path is not valid, either because it was rejected before
+    /// connecting, or because the server closed the connection with a
+    /// protocol error right away.
+    #[fail(display = "Invalid path: {}", _0)]
+    InvalidPath(String),
+    /// The server answered the WebSocket handshake with the specified HTTP
+    /// status code instead of upgrading the connection.
+    #[fail(display = "Server rejected the connection with HTTP status {}", _0)]
+    Rejected(u16),
+}
+
+impl ConnectError {
+    /// Return the numeric code of this error.
+    pub fn code(&self) -> u32 {
+        match *self {
+            ConnectError::InvalidPath(_) => 20,
+            ConnectError::Rejected(_) => 21,
+        }
+    }
+}
+
+
+/// Errors that occur when a task sends a message through a
+/// [`TaskHandle`](../tasks/struct.TaskHandle.html).
+#[derive(Fail, Debug, PartialEq, Copy, Clone)]
+pub enum TaskSendError {
+    /// The task has not been started yet.
+    #[fail(display = "Task has not been started")]
+    NotStarted,
+    /// The outgoing buffer is full, try again once messages have been sent.
+    #[fail(display = "Outgoing buffer is full")]
+    Full,
+    /// The task loop has finished.
+    #[fail(display = "Connection closed")]
+    Disconnected,
+}
+
+impl TaskSendError {
+    /// Return the numeric code of this error.
+    pub fn code(self) -> u32 {
+        match self {
+            TaskSendError::NotStarted => 40,
+            TaskSendError::Full => 41,
+            TaskSendError::Disconnected => 42,
+        }
+    }
+}
+
+
+
+/// Errors that occur when saving or loading a
+/// [`KeyFile`](../persistence/struct.KeyFile.html).
+#[cfg(feature = "persistence")]
+#[derive(Fail, Debug, PartialEq)]
+pub enum PersistenceError {
+    /// The key file could not be read or written.
+    #[fail(display = "I/O error: {}", _0)]
+    Io(String),
+    /// The key file is malformed.
+    #[fail(display = "Invalid key file: {}", _0)]
+    Format(String),
+    /// The passphrase is wrong or the key file has been tampered with.
+    #[fail(display = "Could not decrypt key file")]
+    Decrypt,
+    /// A problem with the crypto backend.
+    #[fail(display = "Crypto error: {}", _0)]
+    Crypto(String),
+}
+
+#[cfg(feature = "persistence")]
+impl PersistenceError {
+    /// Return the numeric code of this error.
+    pub fn code(&self) -> u32 {
+        match *self {
+            PersistenceError::Io(_) => 50,
+            PersistenceError::Format(_) => 51,
+            PersistenceError::Decrypt => 52,
+            PersistenceError::Crypto(_) => 53,
+        }
+    }
+}
+
+
+/// Errors that occur when exporting or importing a
+/// resumption token, see the [`resumption`](../resumption/index.html) module.
+#[cfg(feature = "resumption")]
+#[derive(Fail, Debug, PartialEq)]
+pub enum ResumptionError {
+    /// The session cannot be resumed, e.g. because it did not end cleanly.
+    #[fail(display = "Session cannot be resumed: {}", _0)]
+    NotResumable(String),
+    /// The resumption token is malformed.
+    #[fail(display = "Invalid resumption token: {}", _0)]
+    Format(String),
+    /// The token was created with another permanent key or has been
+    /// tampered with.
+    #[fail(display = "Could not decrypt resumption token")]
+    Decrypt,
+    /// A problem with the crypto backend.
+    #[fail(display = "Crypto error: {}", _0)]
+    Crypto(String),
+}
+
+#[cfg(feature = "resumption")]
+impl ResumptionError {
+    /// Return the numeric code of this error.
+    pub fn code(&self) -> u32 {
+        match *self {
+            ResumptionError::NotResumable(_) => 60,
+            ResumptionError::Format(_) => 61,
+            ResumptionError::Decrypt => 62,
+            ResumptionError::Crypto(_) => 63,
+        }
+    }
+}
+
+
+/// Errors that occur when parsing a received frame into a byte box.
+#[derive(Fail, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    /// The frame is shorter than a nonce.
+    #[fail(display = "Frame must start with a {} byte nonce, but has only {} bytes", _0, _1)]
+    TooShort(usize, usize),
+
+    /// The frame contains a nonce, but no payload.
+    #[fail(display = "Frame contains a nonce, but no payload")]
+    NonceOnly,
+
+    /// The frame is longer than the maximum frame length.
+    #[fail(display = "Frame has {} bytes, but must not be longer than {} bytes", _0, _1)]
+    Oversized(usize, usize),
+}
+
+impl FrameError {
+    /// Return the numeric code of this error.
+    pub fn code(&self) -> u32 {
+        match *self {
+            FrameError::TooShort(..) => 70,
+            FrameError::NonceOnly => 71,
+            FrameError::Oversized(..) => 72,
+        }
+    }
+}
+
+impl From<FrameError> for SignalingError {
+    fn from(e: FrameError) -> Self {
+        SignalingError::Frame(e)
+    }
+}
+
+/// Errors that occur when decoding a nonce.
+#[derive(Fail, Debug, PartialEq)]
+pub(crate) enum NonceError {
+    /// The nonce does not have the correct length.
+    #[fail(display = "Byte slice must be exactly 24 bytes, not {}", _0)]
+    InvalidLength(usize),
+
+    /// The cookie consists of zero bytes only.
+    #[fail(display = "Cookie must not be all zeros")]
+    ZeroCookie,
+}
+
+impl From<NonceError> for SignalingError {
+    fn from(e: NonceError) -> Self {
+        match e {
+            NonceError::InvalidLength(_) => SignalingError::Decode(format!("Cannot decode nonce: {}", e)),
+            NonceError::ZeroCookie => SignalingError::InvalidNonce(e.to_string()),
+        }
+    }
 }
 
 
@@ -198,3 +618,64 @@ impl<T> From<TryLockError<T>> for ValidationError {
         ValidationError::Crash(format!("Could not acquire lock: {}", e))
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::cookie::Cookie;
+    use crate::protocol::csn::CombinedSequenceSnapshot;
+    use crate::protocol::state::SignalingState;
+
+    use super::*;
+
+    #[test]
+    fn wrapped_codes() {
+        assert_eq!(SaltyError::Timeout.code(), 9);
+        assert_eq!(SaltyError::from(ConnectError::Rejected(404)).code(), ConnectError::Rejected(404).code());
+        assert_eq!(SaltyError::from(BuilderError::MissingTask).code(), 30);
+        assert_eq!(TaskSendError::Full.code(), 41);
+    }
+
+    #[test]
+    fn unexpected_message_context() {
+        let cookie = Cookie::new([0xab, 0xcd, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16]);
+        let csn = CombinedSequenceSnapshot::new(1, 42);
+        let nonce = Nonce::new(cookie, Address(1), Address(3), csn);
+        let context = ErrorContext::new(SignalingState::PeerHandshake, "KeySent", "token", &nonce);
+        assert_eq!(context.csn, (1 << 32) + 42);
+        let err = SaltyError::from(SignalingError::UnexpectedMessage(context));
+        assert_eq!(err.code(), 11);
+        assert_eq!(
+            err.to_string(),
+            "Unexpected message: 'token' message from initiator to responder 0x03 \
+             in PeerHandshake state (\"KeySent\"), CSN 4294967338, cookie abcd..",
+        );
+    }
+
+    #[test]
+    fn send_error() {
+        let failed = FailedMessage { destination: 3, message_type: Some("key".into()) };
+        let err = SaltyError::from(SignalingError::SendError(failed.clone()));
+        assert_eq!(err, SaltyError::SendError(failed));
+        assert_eq!(err.code(), 13);
+        assert_eq!(err.to_string(), "Server could not relay 'key' message to responder 0x03");
+    }
+
+    #[test]
+    fn cause() {
+        let err = SaltyError::from(BuilderError::DuplicateTask("dummy".into()));
+        assert_eq!(err.to_string(), "Could not build client: Task with name \"dummy\" was added twice");
+        let cause = err.cause().and_then(|cause| cause.downcast_ref::<BuilderError>());
+        assert_eq!(cause, Some(&BuilderError::DuplicateTask("dummy".into())));
+        assert!(SaltyError::Timeout.cause().is_none());
+    }
+
+    #[test]
+    fn frame_error_cause() {
+        let err = SaltyError::from(SignalingError::from(FrameError::NonceOnly));
+        assert_eq!(err, SaltyError::Frame(FrameError::NonceOnly));
+        assert_eq!(err.code(), 71);
+        let cause = err.cause().and_then(|cause| cause.downcast_ref::<FrameError>());
+        assert_eq!(cause, Some(&FrameError::NonceOnly));
+    }
+}
@@ -0,0 +1,178 @@
+//! Short representations of public keys for manual verification.
+//!
+//! To make sure that no one intercepted the pairing, two users may compare
+//! the fingerprints of their permanent keys, e.g. by reading them out over
+//! the phone or by looking at both screens. A
+//! [`KeyFingerprint`](struct.KeyFingerprint.html) consists of the first 16
+//! bytes of the SHA-256 hash of the key, like the fingerprints shown by
+//! Threema. It can be displayed as grouped hex string or as a sequence of
+//! emoji that is easier to compare at a glance.
+
+use std::fmt;
+
+use data_encoding::HEXLOWER;
+
+use crate::crypto_backend::backend;
+use crate::crypto_types::PublicKey;
+
+
+/// The length of a fingerprint in bytes.
+const FINGERPRINT_BYTES: usize = 16;
+
+/// The number of emoji in the emoji representation.
+const EMOJI_COUNT: usize = 8;
+
+/// The emoji table, indexed by six bits of the fingerprint.
+const EMOJI: [char; 64] = [
+    '🐶', '🐱', '🐭', '🐹', '🐰', '🦊', '🐻', '🐼',
+    '🐨', '🐯', '🦁', '🐮', '🐷', '🐸', '🐵', '🐔',
+    '🐧', '🐦', '🐤', '🦆', '🦅', '🦉', '🦇', '🐺',
+    '🐗', '🐴', '🦄', '🐝', '🐛', '🦋', '🐌', '🐞',
+    '🐢', '🐍', '🦎', '🐙', '🦑', '🦀', '🐡', '🐠',
+    '🐟', '🐬', '🐳', '🦈', '🐊', '🐘', '🦏', '🐪',
+    '🦒', '🍎', '🍐', '🍊', '🍋', '🍌', '🍉', '🍇',
+    '🍓', '🍒', '🍑', '🍍', '🥝', '🍅', '🥕', '🌽',
+];
+
+
+/// Compute the fingerprint of a key.
+///
+/// Implemented for [`PublicKey`](../type.PublicKey.html).
+pub trait Fingerprint {
+    /// Return the fingerprint of the key.
+    fn fingerprint(&self) -> KeyFingerprint;
+}
+
+impl Fingerprint for PublicKey {
+    fn fingerprint(&self) -> KeyFingerprint {
+        let hash = backend().sha256(&self.0);
+        let mut bytes = [0; FINGERPRINT_BYTES];
+        bytes.copy_from_slice(&hash[..FINGERPRINT_BYTES]);
+        KeyFingerprint(bytes)
+    }
+}
+
+
+/// The fingerprint of a public key.
+///
+/// The `Display` implementation returns the lowercase hex string in groups
+/// of four characters, e.g. `3c5f 0a1e ...`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct KeyFingerprint([u8; FINGERPRINT_BYTES]);
+
+impl KeyFingerprint {
+    /// Return the fingerprint bytes.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Return the fingerprint as lowercase hex string without spaces.
+    pub fn to_hex(&self) -> String {
+        HEXLOWER.encode(&self.0)
+    }
+
+    /// Return the first 48 bits of the fingerprint as a sequence of eight
+    /// emoji.
+    ///
+    /// This is quicker to compare than the hex string, but does not cover
+    /// the whole fingerprint, see
+    /// [`matches_emoji_weak`](#method.matches_emoji_weak).
+    pub fn to_emoji(&self) -> String {
+        let bits = self.0[..6].iter().fold(0u64, |bits, &byte| bits << 8 | u64::from(byte));
+        (0..EMOJI_COUNT)
+            .map(|i| EMOJI[(bits >> (6 * (EMOJI_COUNT - 1 - i)) & 0x3f) as usize])
+            .collect()
+    }
+
+    /// Return whether the fingerprint matches the hex string entered or
+    /// scanned by the user.
+    ///
+    /// The whole 128 bit fingerprint is compared. Whitespace and the case
+    /// of the hex string are ignored.
+    pub fn matches(&self, representation: &str) -> bool {
+        strip_whitespace(representation).eq_ignore_ascii_case(&self.to_hex())
+    }
+
+    /// Return whether the first 48 bits of the fingerprint match the emoji
+    /// representation entered by the user.
+    ///
+    /// This is a weak check: Finding another key with the same emoji takes
+    /// about 2^48 attempts, which is feasible for an attacker. Only use it
+    /// where the users compare the emoji at a glance, and prefer
+    /// [`matches`](#method.matches) otherwise. Whitespace is ignored.
+    pub fn matches_emoji_weak(&self, representation: &str) -> bool {
+        strip_whitespace(representation) == self.to_emoji()
+    }
+}
+
+/// Remove all whitespace from the string.
+fn strip_whitespace(representation: &str) -> String {
+    representation.chars()
+        .filter(|c| !c.is_whitespace())
+        .collect()
+}
+
+impl fmt::Display for KeyFingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, group) in self.0.chunks(2).enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{}", HEXLOWER.encode(group))?;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto_types::public_key_from_hex_str;
+
+    use super::*;
+
+    fn key() -> PublicKey {
+        public_key_from_hex_str("65e4127c52d6c6bdc894e4e8c5bbd5b7b7ca5a6fca5c8e5a6c66eea6fbc3d5fb").unwrap()
+    }
+
+    #[test]
+    fn representations() {
+        let fingerprint = key().fingerprint();
+        let hex = fingerprint.to_hex();
+        assert_eq!(hex.len(), 32);
+        assert_eq!(fingerprint.to_string().replace(' ', ""), hex);
+        assert_eq!(fingerprint.to_string().split(' ').count(), 8);
+        assert_eq!(fingerprint.to_emoji().chars().count(), EMOJI_COUNT);
+
+        let other = PublicKey::from_slice(&[0; 32]).unwrap().fingerprint();
+        assert_ne!(fingerprint, other);
+        assert_ne!(fingerprint.to_emoji(), other.to_emoji());
+        assert_eq!(fingerprint, key().fingerprint());
+
+        // Known answer: The first 16 bytes of the SHA-256 hash of the key
+        assert_eq!(hex, "77bfbc3b65258eb67f507b038b0c8a99");
+        assert_eq!(fingerprint.to_string(), "77bf bc3b 6525 8eb6 7f50 7b03 8b0c 8a99");
+        assert_eq!(fingerprint.to_emoji(), "🦋🍍🥕🥝🐵🍉🦅🦀");
+    }
+
+    #[test]
+    fn emoji_bits() {
+        let fingerprint = KeyFingerprint([0b0000_0100, 0b0010_0000, 0b1111_1111, 0, 0, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(fingerprint.to_emoji(), "🐱🐭🐹🌽🐶🐶🐶🐱");
+    }
+
+    #[test]
+    fn matches() {
+        let fingerprint = key().fingerprint();
+        assert!(fingerprint.matches(&fingerprint.to_string()));
+        assert!(fingerprint.matches(&fingerprint.to_hex().to_uppercase()));
+        assert!(!fingerprint.matches(&PublicKey::from_slice(&[0; 32]).unwrap().fingerprint().to_hex()));
+        assert!(!fingerprint.matches(""));
+
+        // The emoji only cover 48 bits and are not accepted as full match
+        assert!(!fingerprint.matches(&fingerprint.to_emoji()));
+        assert!(!fingerprint.matches(&fingerprint.to_hex()[..12]));
+        assert!(fingerprint.matches_emoji_weak(&format!(" {} ", fingerprint.to_emoji())));
+        assert!(!fingerprint.matches_emoji_weak(&fingerprint.to_hex()));
+    }
+}
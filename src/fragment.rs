@@ -0,0 +1,106 @@
+//! A WebSocket codec that splits large outgoing messages into several frames.
+//!
+//! The codec of the `websocket` crate writes every message as a single
+//! frame. Some servers and proxies close the connection when a frame exceeds
+//! their limit, so the task loop replaces it with a
+//! [`FragmentingCodec`](struct.FragmentingCodec.html) if a maximum frame size
+//! is configured. Incoming fragmented messages are reassembled by the
+//! wrapped codec.
+
+use bytes::{BufMut, BytesMut};
+// The `Framed` type of tokio-io is deprecated, but the websocket clients
+// are built on it.
+#[allow(deprecated)]
+use tokio_io::codec::{Decoder, Encoder, Framed};
+use tokio_io::{AsyncRead, AsyncWrite};
+use websocket::WebSocketError;
+use websocket::r#async::MessageCodec;
+use websocket::dataframe::{DataFrame, Opcode};
+use websocket::message::OwnedMessage;
+use websocket::ws::dataframe::DataFrame as DataFrameTrait;
+
+
+/// A client message codec that fragments data messages with a payload
+/// larger than `max_frame_size` bytes.
+pub(crate) struct FragmentingCodec {
+    inner: MessageCodec<OwnedMessage>,
+    max_frame_size: usize,
+}
+
+impl FragmentingCodec {
+    /// Wrap a codec. `max_frame_size` must not be 0.
+    pub(crate) fn new(inner: MessageCodec<OwnedMessage>, max_frame_size: usize) -> Self {
+        assert!(max_frame_size > 0, "The maximum frame size must not be 0");
+        FragmentingCodec { inner, max_frame_size }
+    }
+}
+
+/// Replace the codec of a WebSocket client, keeping the buffered data.
+#[allow(deprecated)]
+pub(crate) fn fragmenting<S>(client: Framed<S, MessageCodec<OwnedMessage>>, max_frame_size: usize)
+        -> Framed<S, FragmentingCodec>
+        where S: AsyncRead + AsyncWrite {
+    let (parts, codec) = client.into_parts_and_codec();
+    Framed::from_parts(parts, FragmentingCodec::new(codec, max_frame_size))
+}
+
+impl Decoder for FragmentingCodec {
+    type Item = OwnedMessage;
+    type Error = WebSocketError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<OwnedMessage>, WebSocketError> {
+        self.inner.decode(src)
+    }
+}
+
+impl Encoder for FragmentingCodec {
+    type Item = OwnedMessage;
+    type Error = WebSocketError;
+
+    fn encode(&mut self, item: OwnedMessage, dst: &mut BytesMut) -> Result<(), WebSocketError> {
+        let (opcode, payload) = match item {
+            OwnedMessage::Binary(ref payload) if payload.len() > self.max_frame_size => (Opcode::Binary, &payload[..]),
+            OwnedMessage::Text(ref text) if text.len() > self.max_frame_size => (Opcode::Text, text.as_bytes()),
+            _ => return self.inner.encode(item, dst),
+        };
+        for (i, chunk) in payload.chunks(self.max_frame_size).enumerate() {
+            let opcode = if i == 0 { opcode } else { Opcode::Continuation };
+            let last = (i + 1) * self.max_frame_size >= payload.len();
+            let frame = DataFrame::new(last, opcode, chunk.to_vec());
+            // Client frames must be masked
+            dst.reserve(frame.frame_size(true));
+            frame.write_to(&mut dst.writer(), true)?;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use websocket::r#async::MsgCodecCtx;
+
+    use super::*;
+
+    #[test]
+    fn fragment_and_reassemble() {
+        let mut codec = FragmentingCodec::new(MessageCodec::default(MsgCodecCtx::Client), 4);
+        let mut server = MessageCodec::<OwnedMessage>::default(MsgCodecCtx::Server);
+        let mut buf = BytesMut::new();
+
+        let large = OwnedMessage::Binary((0..10).collect());
+        codec.encode(large.clone(), &mut buf).unwrap();
+        let small = OwnedMessage::Binary(vec![1, 2, 3]);
+        codec.encode(small.clone(), &mut buf).unwrap();
+
+        // Three frames with a payload of 4, 4 and 2 bytes, followed by the
+        // small message, each with two header bytes and a four byte mask
+        assert_eq!(buf.len(), 3 * 6 + 10 + 6 + 3);
+        assert_eq!(buf[0], 0x02); // Binary, not finished
+        assert_eq!(buf[10], 0x00); // Continuation, not finished
+        assert_eq!(buf[20], 0x80); // Continuation, finished
+        assert_eq!(server.decode(&mut buf).unwrap(), Some(large));
+        assert_eq!(server.decode(&mut buf).unwrap(), Some(small));
+        assert!(buf.is_empty());
+    }
+}
@@ -0,0 +1,261 @@
+//! A message passing handle to a shared [`SaltyClient`](../struct.SaltyClient.html).
+//!
+//! The connection futures share the client through an
+//! `Arc<RwLock<SaltyClient>>`, which must not leave the reactor thread. The
+//! [`SignalingHandle`](struct.SignalingHandle.html) instead sends requests to
+//! a small actor future that runs on the reactor next to the connection and
+//! is the only place where the lock is taken. The handle can be cloned and
+//! sent to other threads.
+
+use std::fmt;
+use std::sync::{Arc, RwLock};
+
+use futures::{Future, Stream};
+use futures::future;
+use futures::sync::{mpsc, oneshot};
+use rmpv::Value;
+use tokio_core::reactor::Handle;
+
+use crate::{BoxedFuture, CloseCode, Role, SaltyClient};
+use crate::crypto_types::PairingData;
+use crate::errors::{SaltyError, SaltyResult};
+use crate::protocol::csn::PeerSequenceNumbers;
+use crate::protocol::reorder::Ordered;
+
+
+/// A request sent to the signaling actor, along with the channel for the reply.
+enum Request {
+    Role(oneshot::Sender<SaltyResult<Role>>),
+    PairingData(oneshot::Sender<SaltyResult<Option<PairingData>>>),
+    PeerSequenceNumbers(oneshot::Sender<SaltyResult<Option<PeerSequenceNumbers>>>),
+    EncryptTaskMessage(Value, oneshot::Sender<SaltyResult<Vec<u8>>>),
+    EncryptCloseMessage(CloseCode, oneshot::Sender<SaltyResult<Vec<u8>>>),
+    EncryptForPeer(Vec<u8>, oneshot::Sender<SaltyResult<Vec<u8>>>),
+    DecryptFromPeer(Vec<u8>, oneshot::Sender<SaltyResult<Vec<u8>>>),
+    DecryptFromPeerOrdered(Vec<u8>, oneshot::Sender<SaltyResult<Ordered>>),
+    ReleaseReordered(oneshot::Sender<SaltyResult<Ordered>>),
+    EncryptRaw(Vec<u8>, Vec<u8>, oneshot::Sender<SaltyResult<Vec<u8>>>),
+    DecryptRaw(Vec<u8>, Vec<u8>, oneshot::Sender<SaltyResult<Vec<u8>>>),
+}
+
+impl Request {
+    /// Process the request and send the reply.
+    ///
+    /// If the caller is no longer interested in the reply, it is dropped.
+    fn process(self, salty: &RwLock<SaltyClient>) {
+        macro_rules! reply {
+            ($tx:expr, $salty:ident => $result:expr) => {{
+                let result = match salty.write() {
+                    Ok(mut guard) => {
+                        let $salty: &mut SaltyClient = &mut guard;
+                        $result
+                    },
+                    Err(e) => Err(SaltyError::Crash(format!("SignalingHandle: Could not write-lock SaltyClient: {}", e))),
+                };
+                let _ = $tx.send(result);
+            }}
+        }
+        match self {
+            Request::Role(tx) =>
+                reply!(tx, s => Ok(s.role())),
+            Request::PairingData(tx) =>
+                reply!(tx, s => Ok(s.pairing_data())),
+            Request::PeerSequenceNumbers(tx) =>
+                reply!(tx, s => Ok(s.current_peer_sequence_numbers())),
+            Request::EncryptTaskMessage(val, tx) =>
+                reply!(tx, s => s.encrypt_task_message(val)),
+            Request::EncryptCloseMessage(reason, tx) =>
+                reply!(tx, s => s.encrypt_close_message(reason)),
+            Request::EncryptForPeer(data, tx) =>
+                reply!(tx, s => s.encrypt_for_peer(&data)),
+            Request::DecryptFromPeer(bytes, tx) =>
+                reply!(tx, s => s.decrypt_from_peer(&bytes)),
+            Request::DecryptFromPeerOrdered(bytes, tx) =>
+                reply!(tx, s => s.decrypt_from_peer_ordered(&bytes)),
+            Request::ReleaseReordered(tx) =>
+                reply!(tx, s => s.release_reordered()),
+            Request::EncryptRaw(data, nonce, tx) =>
+                reply!(tx, s => s.encrypt_raw_with_session_keys(&data, &nonce)),
+            Request::DecryptRaw(data, nonce, tx) =>
+                reply!(tx, s => s.decrypt_raw_with_session_keys(&data, &nonce)),
+        }
+    }
+}
+
+
+/// A cloneable, thread-safe handle to a shared
+/// [`SaltyClient`](../struct.SaltyClient.html).
+///
+/// Every method sends a request to the signaling actor and returns a future
+/// that resolves to the reply. Requests are processed in the order in which
+/// they were sent. The actor stops once all handles have been dropped.
+///
+/// Create a handle with
+/// [`SignalingHandle::spawn`](struct.SignalingHandle.html#method.spawn) and
+/// pass the same `Arc<RwLock<SaltyClient>>` to the connection functions.
+#[derive(Clone)]
+pub struct SignalingHandle {
+    tx: mpsc::UnboundedSender<Request>,
+}
+
+impl fmt::Debug for SignalingHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SignalingHandle").finish()
+    }
+}
+
+impl SignalingHandle {
+    /// Spawn the signaling actor on the reactor and return a handle to it.
+    pub fn spawn(salty: Arc<RwLock<SaltyClient>>, handle: &Handle) -> Self {
+        let (tx, rx) = mpsc::unbounded::<Request>();
+        handle.spawn(rx.for_each(move |request| {
+            request.process(&salty);
+            Ok(())
+        }).map(|_| debug!("† Signaling actor done")));
+        SignalingHandle { tx }
+    }
+
+    /// Send a request and wait for the reply.
+    fn request<T, F>(&self, make_request: F) -> BoxedFuture<T, SaltyError>
+        where T: 'static,
+              F: FnOnce(oneshot::Sender<SaltyResult<T>>) -> Request,
+    {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        if self.tx.unbounded_send(make_request(reply_tx)).is_err() {
+            return Box::new(future::err(SaltyError::Crash("Signaling actor is not running".into())));
+        }
+        Box::new(
+            reply_rx
+                .map_err(|_| SaltyError::Crash("Signaling actor dropped the request".into()))
+                .and_then(|result| result)
+        )
+    }
+
+    /// Return the assigned role.
+    pub fn role(&self) -> BoxedFuture<Role, SaltyError> {
+        self.request(Request::Role)
+    }
+
+    /// Return the pairing data that must be passed to the responder.
+    ///
+    /// See [`SaltyClient::pairing_data`](../struct.SaltyClient.html#method.pairing_data).
+    pub fn pairing_data(&self) -> BoxedFuture<Option<PairingData>, SaltyError> {
+        self.request(Request::PairingData)
+    }
+
+    /// If the peer is already determined, return the current incoming and
+    /// outgoing sequence numbers.
+    pub fn current_peer_sequence_numbers(&self) -> BoxedFuture<Option<PeerSequenceNumbers>, SaltyError> {
+        self.request(Request::PeerSequenceNumbers)
+    }
+
+    /// Encrypt a task message.
+    pub fn encrypt_task_message(&self, val: Value) -> BoxedFuture<Vec<u8>, SaltyError> {
+        self.request(|tx| Request::EncryptTaskMessage(val, tx))
+    }
+
+    /// Encrypt a close message for the peer.
+    pub fn encrypt_close_message(&self, reason: CloseCode) -> BoxedFuture<Vec<u8>, SaltyError> {
+        self.request(|tx| Request::EncryptCloseMessage(reason, tx))
+    }
+
+    /// Encrypt an opaque payload for the peer.
+    ///
+    /// See [`SaltyClient::encrypt_for_peer`](../struct.SaltyClient.html#method.encrypt_for_peer).
+    pub fn encrypt_for_peer(&self, data: Vec<u8>) -> BoxedFuture<Vec<u8>, SaltyError> {
+        self.request(|tx| Request::EncryptForPeer(data, tx))
+    }
+
+    /// Decrypt a payload that was encrypted by the peer.
+    ///
+    /// See [`SaltyClient::decrypt_from_peer`](../struct.SaltyClient.html#method.decrypt_from_peer).
+    pub fn decrypt_from_peer(&self, bytes: Vec<u8>) -> BoxedFuture<Vec<u8>, SaltyError> {
+        self.request(|tx| Request::DecryptFromPeer(bytes, tx))
+    }
+
+    /// Decrypt a payload that was encrypted by the peer, restoring the order
+    /// of the payloads.
+    ///
+    /// See [`SaltyClient::decrypt_from_peer_ordered`](../struct.SaltyClient.html#method.decrypt_from_peer_ordered).
+    pub fn decrypt_from_peer_ordered(&self, bytes: Vec<u8>) -> BoxedFuture<Ordered, SaltyError> {
+        self.request(|tx| Request::DecryptFromPeerOrdered(bytes, tx))
+    }
+
+    /// Release the held back payloads that timed out.
+    ///
+    /// See [`SaltyClient::release_reordered`](../struct.SaltyClient.html#method.release_reordered).
+    pub fn release_reordered(&self) -> BoxedFuture<Ordered, SaltyError> {
+        self.request(Request::ReleaseReordered)
+    }
+
+    /// Encrypt raw bytes using the session keys after the handshake has been finished.
+    pub fn encrypt_raw_with_session_keys(&self, data: Vec<u8>, nonce: Vec<u8>) -> BoxedFuture<Vec<u8>, SaltyError> {
+        self.request(|tx| Request::EncryptRaw(data, nonce, tx))
+    }
+
+    /// Decrypt raw bytes using the session keys after the handshake has been finished.
+    pub fn decrypt_raw_with_session_keys(&self, data: Vec<u8>, nonce: Vec<u8>) -> BoxedFuture<Vec<u8>, SaltyError> {
+        self.request(|tx| Request::DecryptRaw(data, nonce, tx))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use tokio_core::reactor::Core;
+
+    use crate::crypto_types::KeyPair;
+    use crate::test_helpers::DummyTask;
+
+    use super::*;
+
+    fn spawn_initiator(core: &Core) -> SignalingHandle {
+        let salty = SaltyClient::build(KeyPair::new())
+            .add_task(Box::new(DummyTask::new(1)))
+            .initiator()
+            .unwrap();
+        SignalingHandle::spawn(Arc::new(RwLock::new(salty)), &core.handle())
+    }
+
+    #[test]
+    fn requests_are_answered() {
+        let mut core = Core::new().unwrap();
+        let handle = spawn_initiator(&core);
+
+        assert_eq!(core.run(handle.role()), Ok(Role::Initiator));
+        assert!(core.run(handle.pairing_data()).unwrap().is_some());
+        assert_eq!(core.run(handle.current_peer_sequence_numbers()), Ok(None));
+
+        // No peer yet, so encryption fails
+        assert!(core.run(handle.encrypt_task_message(Value::Nil)).is_err());
+        assert_eq!(core.run(handle.encrypt_for_peer(vec![1, 2, 3])), Err(SaltyError::NoPeer));
+    }
+
+    #[test]
+    fn request_from_other_thread() {
+        let mut core = Core::new().unwrap();
+        let handle = spawn_initiator(&core);
+
+        let (tx, rx) = oneshot::channel();
+        let remote_handle = handle.clone();
+        thread::spawn(move || {
+            let role = remote_handle.role().wait();
+            tx.send(role).unwrap();
+        });
+        assert_eq!(core.run(rx).unwrap(), Ok(Role::Initiator));
+    }
+
+    #[test]
+    fn actor_stops_with_reactor() {
+        let handle = {
+            let core = Core::new().unwrap();
+            spawn_initiator(&core)
+        };
+        assert_eq!(
+            handle.role().wait(),
+            Err(SaltyError::Crash("Signaling actor is not running".into())),
+        );
+    }
+}
@@ -1,23 +1,20 @@
+use std::net::{SocketAddr, ToSocketAddrs};
+
+use crate::crypto_backend;
 use crate::errors::{SaltyResult, SaltyError};
 
-/// Initialize libsodium. Return an error if initialization failed.
+/// Initialize the crypto backend. Return an error if initialization failed.
 ///
 /// It is safe to call this function multiple times.
-///
-/// See [`rust_sodium::init` docs](https://docs.rs/rust_sodium/0.9.0/rust_sodium/fn.init.html)
-/// for more information.
-pub fn libsodium_init() -> SaltyResult<()> {
-    ::rust_sodium::init().map_err(
-        |()| SaltyError::Crypto("Could not initialize libsodium".into())
-    )
+pub fn crypto_init() -> SaltyResult<()> {
+    crypto_backend::init().map_err(|e| SaltyError::Crypto(e.to_string()))
 }
 
-/// Initialize libsodium. Panic if initialization fails.
-///
-/// It is safe to call this function multiple times.
-///
-/// See [`rust_sodium::init` docs](https://docs.rs/rust_sodium/0.9.0/rust_sodium/fn.init.html)
-/// for more information.
-pub fn libsodium_init_or_panic() {
-    ::rust_sodium::init().expect("Could not initialize libsodium")
+/// Resolve a host name to the first matching socket address.
+pub fn resolve(host: &str, port: u16) -> SaltyResult<SocketAddr> {
+    (host, port)
+        .to_socket_addrs()
+        .map_err(|e| SaltyError::Network(format!("Could not resolve host {}: {}", host, e)))?
+        .next()
+        .ok_or_else(|| SaltyError::Network(format!("Could not resolve host {}", host)))
 }
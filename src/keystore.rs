@@ -0,0 +1,142 @@
+//! Deterministic permanent keys derived from a master seed.
+//!
+//! Applications that manage many pairings don't need to store one permanent
+//! key pair per peer. A [`KeyStore`](struct.KeyStore.html) holds a single
+//! 32 byte master seed, from which a stable key pair is derived for every
+//! context, e.g. the identifier of a peer or of a signaling path.
+//!
+//! The private key is the keyed BLAKE2b-256 hash of the context, with the
+//! seed as key and `saltyrtc-permkey` as personalization. The public key is
+//! calculated from it as usual.
+
+use data_encoding::{HEXLOWER, HEXLOWER_PERMISSIVE};
+
+use crate::crypto_backend::{backend, KEYBYTES};
+use crate::crypto_types::{KeyPair, PrivateKey, SecretKey};
+use crate::errors::{SaltyError, SaltyResult};
+
+
+/// The personalization of the key derivation.
+const PERSONAL: &[u8; 16] = b"saltyrtc-permkey";
+
+/// The salt of the key derivation. Contexts are separated by the hashed
+/// input, so no salt is needed.
+const SALT: [u8; 16] = [0; 16];
+
+
+/// A master seed from which permanent key pairs are derived.
+///
+/// Store the seed (see [`seed_hex`](#method.seed_hex)) securely, e.g. in
+/// the platform key store. The same seed and context always yield the same
+/// key pair.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyStore {
+    seed: SecretKey,
+}
+
+impl KeyStore {
+    /// Create a key store with a new random seed.
+    pub fn new() -> Self {
+        info!("Generating new master seed");
+        let mut bytes = [0u8; KEYBYTES];
+        backend().random_bytes(&mut bytes);
+        KeyStore { seed: SecretKey(bytes) }
+    }
+
+    /// Create a key store from an existing seed.
+    pub fn from_seed(seed: SecretKey) -> Self {
+        KeyStore { seed }
+    }
+
+    /// Create a key store from a hex encoded seed.
+    pub fn from_hex_str(hex_str: &str) -> SaltyResult<Self> {
+        let bytes = HEXLOWER_PERMISSIVE.decode(hex_str.as_bytes())
+            .map_err(|e| SaltyError::Decode(format!("Could not decode seed hex string: {}", e)))?;
+        let seed = SecretKey::from_slice(&bytes)
+            .ok_or_else(|| SaltyError::Decode("Invalid seed hex string".to_string()))?;
+        Ok(KeyStore { seed })
+    }
+
+    /// Return a reference to the seed.
+    pub fn seed(&self) -> &SecretKey {
+        &self.seed
+    }
+
+    /// Return the seed as hex-encoded string.
+    pub fn seed_hex(&self) -> String {
+        HEXLOWER.encode(&self.seed.0)
+    }
+
+    /// Return the key pair for the specified context.
+    pub fn keypair(&self, context: &[u8]) -> KeyPair {
+        KeyStore::derive(&self.seed, context)
+    }
+
+    /// Derive the key pair for the specified context from the seed.
+    ///
+    /// ## Panics
+    ///
+    /// This may panic if the crypto backend cannot be initialized.
+    pub fn derive(seed: &SecretKey, context: &[u8]) -> KeyPair {
+        let private_key = backend().blake2b_salt_personal(context, &seed.0, &SALT, PERSONAL);
+        KeyPair::from_private_key(PrivateKey(private_key))
+    }
+}
+
+impl Default for KeyStore {
+    fn default() -> Self {
+        KeyStore::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_store() -> KeyStore {
+        let seed: Vec<u8> = (0..32).collect();
+        KeyStore::from_seed(SecretKey::from_slice(&seed).unwrap())
+    }
+
+    /// Test the key derivation against precomputed test vectors.
+    #[test]
+    fn derive_precomputed() {
+        let store = test_store();
+        assert_eq!(
+            HEXLOWER.encode(&store.keypair(b"").private_key().0),
+            "f57312f1ed2745f30ad3ca54fd54bfd277483b039cf4f86d8ffc89f4dd6673e0"
+        );
+        assert_eq!(
+            HEXLOWER.encode(&store.keypair(b"peer-2").private_key().0),
+            "76946e0d8ba66fa786ba9b487eb3af94321425f882b93b37281c130c297a92f1"
+        );
+        let keypair = store.keypair(b"peer-1");
+        assert_eq!(
+            HEXLOWER.encode(&keypair.private_key().0),
+            "590213f59314ed2b849c7f25511c9dff3f60a8098ac11dc89d233a31a8dd3522"
+        );
+        assert_eq!(
+            keypair.public_key_hex(),
+            "78880ad9e4c575d82aa089b0226d5553fa0f88b81f3d6b579a6a7b80cc3d432a"
+        );
+    }
+
+    #[test]
+    fn derive_is_stable() {
+        let store = KeyStore::new();
+        assert_eq!(store.keypair(b"peer"), KeyStore::derive(store.seed(), b"peer"));
+        assert_ne!(store.keypair(b"peer"), store.keypair(b"peer2"));
+        assert_ne!(store.keypair(b"peer"), KeyStore::new().keypair(b"peer"));
+    }
+
+    #[test]
+    fn seed_hex_roundtrip() {
+        let store = test_store();
+        let hex = store.seed_hex();
+        assert_eq!(hex, "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f");
+        assert_eq!(KeyStore::from_hex_str(&hex).unwrap(), store);
+        assert!(KeyStore::from_hex_str("0001").is_err());
+        assert!(KeyStore::from_hex_str("zz").is_err());
+    }
+}
@@ -33,6 +33,47 @@
 //! For a real-life example, please take a look at the
 //! [chat example](https://github.com/saltyrtc/saltyrtc-client-rs/tree/master/examples/chat).
 //!
+//! ## Features
+//!
+//! The connection to the server and the Tokio integration (`connect`,
+//! `do_handshake`, `task_loop`, the TLS and proxy configuration, the
+//! [`SignalingHandle`](struct.SignalingHandle.html), the
+//! [`SaltyClientPool`](struct.SaltyClientPool.html) and the
+//! [`SharedResources`](struct.SharedResources.html)) are part of the
+//! `connect-tokio` feature, which is enabled by default. With
+//! `default-features = false`, only the protocol core (state machines,
+//! nonces, messages and boxes) is built, without any I/O or runtime
+//! dependencies.
+//!
+//! Event loops that cannot run futures can drive the client through the
+//! [`LowLevelClient`](struct.LowLevelClient.html), which is available with
+//! and without the `connect-tokio` feature.
+//!
+//! The cryptography is implemented by a pluggable
+//! [`CryptoBackend`](crypto/trait.CryptoBackend.html). The libsodium backend
+//! is part of the `sodium` feature, which is enabled by default. Without it,
+//! a backend must be installed with
+//! [`set_crypto_backend`](crypto/fn.set_crypto_backend.html) before any keys
+//! are generated.
+//!
+//! To use a different transport, open the stream yourself and pass it to
+//! [`connect_on`](fn.connect_on.html). The handshake and the task loop are
+//! futures 0.1 futures that work on any `AsyncRead` and `AsyncWrite`
+//! transport. They wait for the timeouts and heartbeats with a
+//! [`Sleeper`](clock/trait.Sleeper.html), which is based on `tokio-timer`
+//! by default. To run them on another futures 0.1 executor, use the
+//! [`ThreadSleeper`](clock/struct.ThreadSleeper.html), see
+//! [`SaltyClientBuilder::with_sleeper`](struct.SaltyClientBuilder.html#method.with_sleeper).
+//! There is no adapter for `std::future` runtimes (e.g. async-std or smol).
+//! To drive the protocol from such a runtime, use the
+//! [`LowLevelClient`](struct.LowLevelClient.html) with your own transport
+//! and timers.
+//!
+//! The crate requires the standard library. Without the `connect-tokio`
+//! feature, only the protocol core and the crypto types are built, but they
+//! still depend on `std` (through `failure`, `rmp-serde` and the locks in the
+//! session state), so there is no `no_std` build.
+//!
 //! ## Timeouts
 //!
 //! If you want timeouts (e.g. for connecting, for the handshake, etc) combine
@@ -47,69 +88,155 @@ extern crate log;
 /// Re-exports of dependencies that are in the public API.
 pub mod dep {
     pub use futures;
+    #[cfg(feature = "connect-tokio")]
     pub use native_tls;
     pub use rmpv;
 }
 
 // Modules
+pub mod accept;
+#[cfg(feature = "benchmarks")]
+#[doc(hidden)]
+pub mod bench;
 mod boxes;
+pub mod clock;
 mod close_code;
+#[cfg(feature = "connect-tokio")]
+mod connection;
+mod connection_state;
+mod crypto_backend;
 mod crypto_types;
+#[cfg(feature = "permessage-deflate")]
+mod deflate;
 pub mod errors;
+mod fingerprint;
+#[cfg(feature = "connect-tokio")]
+mod fragment;
+#[cfg(feature = "connect-tokio")]
+mod handle;
+#[cfg(feature = "connect-tokio")]
 mod helpers;
+mod keystore;
+#[cfg(feature = "connect-tokio")]
+mod lifecycle;
+mod low_level;
+pub mod observer;
+mod pairing;
+#[cfg(feature = "persistence")]
+pub mod persistence;
+#[cfg(feature = "connect-tokio")]
+mod pool;
 mod protocol;
+#[cfg(feature = "connect-tokio")]
+mod proxy;
+#[cfg(feature = "connect-tokio")]
+mod response_tap;
+#[cfg(feature = "resumption")]
+pub mod resumption;
+#[cfg(feature = "connect-tokio")]
 mod send_all;
+#[cfg(feature = "connect-tokio")]
+mod shared;
+mod stats;
 pub mod tasks;
+#[cfg(feature = "connect-tokio")]
+mod tls;
+pub mod transcript;
 #[cfg(test)]
 mod test_helpers;
 
 // Rust imports
-use std::error::Error;
-use std::sync::{Arc, Mutex, RwLock};
-use std::time::Duration;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 // Third party imports
-use data_encoding::HEXLOWER;
-use futures::{stream, Future, Stream, Sink};
-use futures::future::{self, Loop};
+use futures::Future;
 use futures::sync::mpsc;
+#[cfg(feature = "connect-tokio")]
 use futures::sync::oneshot;
-use native_tls::TlsConnector;
 use rmpv::Value;
-use rust_sodium::crypto::box_;
-use tokio_core::reactor::Handle;
-use tokio_core::net::TcpStream;
-use tokio_timer::Timer;
-use websocket::WebSocketError;
-use websocket::client::ClientBuilder;
-use websocket::client::r#async::{Client, TlsStream};
-use websocket::client::builder::Url;
-use websocket::ws::dataframe::DataFrame;
-use websocket::header::WebSocketProtocol;
-use websocket::message::{OwnedMessage, CloseData};
 
 // Re-exports
-pub use crate::close_code::CloseCode;
-pub use crate::protocol::Role;
+pub use crate::boxes::ByteBox;
+pub use crate::close_code::{CloseCode, CloseReason};
+#[cfg(feature = "connect-tokio")]
+pub use crate::connection::{connect, connect_on, connect_with_failover, do_handshake, reconnect, task_loop, AbortHandle, HandshakeFuture, WsClient};
+pub use crate::connection_state::ConnectionState;
+pub use crate::low_level::{LowLevelClient, Polled};
+pub use crate::pairing::PairingInfo;
+#[cfg(feature = "connect-tokio")]
+pub use crate::handle::SignalingHandle;
+#[cfg(feature = "connect-tokio")]
+pub use crate::pool::{ConnectionId, PoolEvent, SaltyClientPool};
+pub use crate::protocol::{AuthMethod, PeerInfo, ProtocolVersion, ResponderDropReason, Role, TrustedPeer};
+#[cfg(feature = "server-session")]
+pub use crate::protocol::context::ServerSession;
 pub use crate::protocol::csn::PeerSequenceNumbers;
+pub use crate::protocol::messages::ExtraFields;
+pub use crate::protocol::rate_limit::RateLimit;
+pub use crate::protocol::reorder::Ordered;
+pub use crate::protocol::summary::{PeerSummary, StateSummary, state_machine_dot};
+#[cfg(feature = "connect-tokio")]
+pub use crate::proxy::{ProxyConfig, ProxyProtocol};
+#[cfg(feature = "connect-tokio")]
+pub use crate::shared::SharedResources;
+pub use crate::stats::{HandshakeTimings, Stats};
+#[cfg(feature = "connect-tokio")]
+pub use crate::tls::TlsConfig;
 
 /// Cryptography-related types like public/private keys.
 pub mod crypto {
-    pub use crate::crypto_types::{KeyPair, PublicKey, PrivateKey, AuthToken};
+    pub use crate::crypto_types::{KeyPair, PublicKey, PrivateKey, SecretKey, AuthToken, PairingData};
     pub use crate::crypto_types::{public_key_from_hex_str, private_key_from_hex_str};
+    pub use crate::crypto_types::{public_key_from_base64_str, public_key_to_hex, public_key_to_base64};
+    pub use crate::crypto_types::serde_public_key;
+    pub use crate::fingerprint::{Fingerprint, KeyFingerprint};
+    pub use crate::keystore::KeyStore;
+    pub use crate::crypto_backend::{CryptoBackend, DecryptionError, InitError, KeyDerivationError, set_crypto_backend};
+    #[cfg(feature = "sodium")]
+    pub use crate::crypto_backend::SodiumBackend;
+    pub use crate::crypto_backend::{constant_time_eq, memzero};
+    pub use crate::crypto_backend::{NONCEBYTES, KEYBYTES, MACBYTES, SEALBYTES, SHA256BYTES, BLAKE2B_SALTBYTES, PWHASH_SALTBYTES};
 }
 
 // Internal imports
-use crate::boxes::{ByteBox};
-use crate::crypto_types::{KeyPair, PublicKey, AuthToken};
+use crate::connection_state::StateObservers;
+use crate::crypto_backend::NONCEBYTES;
+use crate::crypto_types::{KeyPair, PublicKey, AuthToken, PairingData};
 use crate::errors::{SaltyResult, SaltyError, SignalingResult, SignalingError, BuilderError};
-use crate::helpers::libsodium_init;
-use crate::protocol::{HandleAction, Signaling, InitiatorSignaling, ResponderSignaling};
+#[cfg(feature = "connect-tokio")]
+use crate::lifecycle::{Lifecycle, Step};
+use crate::protocol::{HandleAction, HandleActions, Signaling, InitiatorSignaling, ResponderSignaling, MAX_RESPONDERS};
+#[cfg(feature = "connect-tokio")]
+use crate::protocol::decrypt::{DecryptedMessage, PreparedMessage};
+use crate::protocol::rate_limit::{DEFAULT_PEER_RATE_LIMIT, DEFAULT_SERVER_RATE_LIMIT};
+use crate::protocol::random::{OsRandom, RandomSource};
+#[cfg(feature = "conformance-testing")]
+use crate::protocol::random::PinnedRandom;
+use crate::protocol::reorder::ReorderBuffer;
+use crate::protocol::state::SignalingState;
+use crate::protocol::timer::{TimerId, Timers};
+#[cfg(feature = "server-session")]
+use crate::protocol::state::ServerHandshakeState;
+#[cfg(feature = "server-session")]
+use crate::protocol::types::Address;
+#[cfg(feature = "resumption")]
+use crate::errors::ResumptionError;
+#[cfg(feature = "connect-tokio")]
+use crate::shared::HandshakePermit;
 use crate::tasks::{Tasks, TaskMessage, BoxedTask};
+use crate::accept::AcceptPolicy;
+use crate::clock::{Clock, SystemClock};
+#[cfg(feature = "connect-tokio")]
+use crate::clock::{Sleeper, TokioSleeper};
+use crate::observer::Observer;
+use crate::transcript::TranscriptRecorder;
 
 
 // Constants
-const SUBPROTOCOL: &str = "v1.saltyrtc.org";
+const DEFAULT_OUTGOING_BUFFER: usize = 64;
+const DEFAULT_RESPONDER_TIMEOUT: Duration = Duration::from_secs(60);
 #[cfg(feature = "msgpack-debugging")]
 const DEFAULT_MSGPACK_DEBUG_URL: &'static str = "https://msgpack.dbrgn.ch/#base64=";
 
@@ -117,17 +244,12 @@ const DEFAULT_MSGPACK_DEBUG_URL: &'static str = "https://msgpack.dbrgn.ch/#base6
 /// A type alias for a boxed future.
 pub type BoxedFuture<T, E> = Box<dyn Future<Item = T, Error = E>>;
 
-/// A type alias for the async websocket client type.
-pub type WsClient = Client<TlsStream<TcpStream>>;
+/// Notifies a future returned by `SaltyClient::rekey` once the session keys
+/// have been rotated.
+#[cfg(feature = "connect-tokio")]
+type RekeyNotifier = oneshot::Sender<SaltyResult<()>>;
 
 
-/// Wrap future in a box with type erasure.
-macro_rules! boxed {
-    ($future:expr) => {{
-        Box::new($future) as BoxedFuture<_, _>
-    }}
-}
-
 
 /// The builder instance returned by
 /// [`SaltyClient::build`](struct.SaltyClient.html#method.build). Use this
@@ -137,6 +259,55 @@ pub struct SaltyClientBuilder {
     tasks: Vec<BoxedTask>,
     ping_interval: Option<Duration>,
     server_public_permanent_key: Option<PublicKey>,
+    #[cfg(feature = "connect-tokio")]
+    proxy: Option<ProxyConfig>,
+    #[cfg(feature = "connect-tokio")]
+    tls_config: Option<TlsConfig>,
+    #[cfg(feature = "connect-tokio")]
+    decryption_workers: usize,
+    #[cfg(feature = "connect-tokio")]
+    max_frame_size: Option<usize>,
+    #[cfg(feature = "connect-tokio")]
+    shared: Option<SharedResources>,
+    #[cfg(feature = "connect-tokio")]
+    sleeper: Arc<dyn Sleeper>,
+    #[cfg(feature = "permessage-deflate")]
+    permessage_deflate: bool,
+    heartbeat: Option<(Duration, u32)>,
+    servers: Vec<(String, u16)>,
+    protocol_version: ProtocolVersion,
+    manual_session_renewal: bool,
+    outgoing_buffer: usize,
+    transcript: Option<Box<dyn TranscriptRecorder>>,
+    observer: Option<Box<dyn Observer>>,
+    responder_timeout: Option<Duration>,
+    initiator_timeout: Option<Duration>,
+    accept_policy: Option<Box<dyn AcceptPolicy>>,
+    max_responders: usize,
+    peer_rate_limit: Option<RateLimit>,
+    server_rate_limit: Option<RateLimit>,
+    duplicate_detection: bool,
+    reorder_window: usize,
+    reorder_timeout: Option<Duration>,
+    auth_token_rotation: bool,
+    strict_mode: bool,
+    rekeying: bool,
+    clock: Arc<dyn Clock>,
+    #[cfg(feature = "conformance-testing")]
+    pinned: Option<([u8; 16], u32)>,
+    #[cfg(feature = "conformance-testing")]
+    pinned_session_key: Option<[u8; 32]>,
+    #[cfg(feature = "server-session")]
+    server_session: Option<ServerSession>,
+}
+
+/// The peer that a new client is built for.
+enum PeerConfig {
+    /// An initiator, optionally trusting the permanent key of a responder.
+    Initiator(Option<PublicKey>),
+    /// A responder with the permanent key of the initiator, and the auth
+    /// token unless the initiator is trusted.
+    Responder(PublicKey, Option<AuthToken>),
 }
 
 impl SaltyClientBuilder {
@@ -147,6 +318,46 @@ impl SaltyClientBuilder {
             tasks: vec![],
             ping_interval: None,
             server_public_permanent_key: None,
+            #[cfg(feature = "connect-tokio")]
+            proxy: None,
+            #[cfg(feature = "connect-tokio")]
+            tls_config: None,
+            #[cfg(feature = "connect-tokio")]
+            decryption_workers: 0,
+            #[cfg(feature = "connect-tokio")]
+            max_frame_size: None,
+            #[cfg(feature = "connect-tokio")]
+            shared: None,
+            #[cfg(feature = "connect-tokio")]
+            sleeper: Arc::new(TokioSleeper::default()),
+            #[cfg(feature = "permessage-deflate")]
+            permessage_deflate: false,
+            heartbeat: None,
+            servers: vec![],
+            protocol_version: ProtocolVersion::default(),
+            manual_session_renewal: false,
+            outgoing_buffer: DEFAULT_OUTGOING_BUFFER,
+            transcript: None,
+            observer: None,
+            responder_timeout: Some(DEFAULT_RESPONDER_TIMEOUT),
+            initiator_timeout: None,
+            accept_policy: None,
+            max_responders: MAX_RESPONDERS,
+            peer_rate_limit: Some(DEFAULT_PEER_RATE_LIMIT),
+            server_rate_limit: Some(DEFAULT_SERVER_RATE_LIMIT),
+            duplicate_detection: false,
+            reorder_window: 0,
+            reorder_timeout: None,
+            auth_token_rotation: false,
+            strict_mode: true,
+            rekeying: false,
+            clock: Arc::new(SystemClock),
+            #[cfg(feature = "conformance-testing")]
+            pinned: None,
+            #[cfg(feature = "conformance-testing")]
+            pinned_session_key: None,
+            #[cfg(feature = "server-session")]
+            server_session: None,
         }
     }
 
@@ -161,6 +372,12 @@ impl SaltyClientBuilder {
 
     /// Specify the server public permanent key if you want to use server key
     /// pinning.
+    ///
+    /// The key is sent to the server as `your_key` in the 'client-auth'
+    /// message, so that servers with several permanent keys can pick the
+    /// right one. If the server doesn't have that key, it closes the
+    /// connection with the close code 3007 (Invalid Key) and the handshake
+    /// fails with a [`SaltyError::Protocol`](errors/enum.SaltyError.html).
     pub fn with_server_key(mut self, server_public_permanent_key: PublicKey) -> Self {
         self.server_public_permanent_key = Some(server_public_permanent_key);
         self
@@ -179,67 +396,630 @@ impl SaltyClientBuilder {
         self
     }
 
+    /// Connect to the server through the specified proxy.
+    ///
+    /// By default, the server is connected to directly.
+    #[cfg(feature = "connect-tokio")]
+    pub fn with_proxy(mut self, proxy: ProxyConfig) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Use the specified TLS configuration when connecting to the server.
+    ///
+    /// This allows trusting a private CA, pinning the server certificate or
+    /// presenting a client certificate.
+    #[cfg(feature = "connect-tokio")]
+    pub fn with_tls_config(mut self, tls_config: TlsConfig) -> Self {
+        self.tls_config = Some(tls_config);
+        self
+    }
+
+    /// Use the TLS connector, the DNS cache, the decryption thread pool and
+    /// the handshake limit of the specified
+    /// [`SharedResources`](struct.SharedResources.html).
+    ///
+    /// A TLS configuration set with
+    /// [`with_tls_config`](#method.with_tls_config) takes precedence over
+    /// the shared TLS connector.
+    #[cfg(feature = "connect-tokio")]
+    pub fn with_shared_resources(mut self, shared: SharedResources) -> Self {
+        self.shared = Some(shared);
+        self
+    }
+
+    /// Wait for the handshake timeouts and the heartbeats with the specified
+    /// sleeper.
+    ///
+    /// See the [`clock`](clock/index.html) module for details. By default,
+    /// the [`TokioSleeper`](clock/struct.TokioSleeper.html) is used. To run
+    /// the handshake and the task loop on an executor other than Tokio, use
+    /// the [`ThreadSleeper`](clock/struct.ThreadSleeper.html).
+    #[cfg(feature = "connect-tokio")]
+    pub fn with_sleeper(mut self, sleeper: Arc<dyn Sleeper>) -> Self {
+        self.sleeper = sleeper;
+        self
+    }
+
+    /// Add a server endpoint for [`connect_with_failover`](fn.connect_with_failover.html).
+    ///
+    /// When calling this method multiple times, the servers are tried in the
+    /// order in which they were added.
+    pub fn add_server(mut self, host: &str, port: u16) -> Self {
+        self.servers.push((host.to_string(), port));
+        self
+    }
+
+    /// Send a heartbeat to the authenticated peer at the specified interval
+    /// once the task loop is running.
+    ///
+    /// If `miss_threshold` consecutive heartbeats are not acknowledged, an
+    /// [`Event::PeerUnresponsive`](enum.Event.html#variant.PeerUnresponsive)
+    /// event is emitted after every further unacknowledged heartbeat. Once the
+    /// peer responds again, an
+    /// [`Event::PeerResponsive`](enum.Event.html#variant.PeerResponsive)
+    /// event follows.
+    ///
+    /// Note: Heartbeats are an extension to the SaltyRTC protocol, so only
+    /// enable them if the peer is known to acknowledge them. This client always
+    /// acknowledges incoming heartbeats.
+    ///
+    /// By default, heartbeats are disabled.
+    pub fn with_heartbeat(mut self, interval: Duration, miss_threshold: u32) -> Self {
+        self.heartbeat = Some((interval, miss_threshold));
+        self
+    }
+
+    /// Speak the specified version of the SaltyRTC protocol.
+    ///
+    /// By default, the latest supported version is used.
+    pub fn with_protocol_version(mut self, version: ProtocolVersion) -> Self {
+        self.protocol_version = version;
+        self
+    }
+
+    /// Prepare a session renewal instead of failing once the outgoing
+    /// sequence numbers to the peer are exhausted.
+    ///
+    /// The renewal is not done by the task loop. When enabled, the task loop
+    /// calls [`SaltyClient::renew_session`](struct.SaltyClient.html#method.renew_session),
+    /// closes the server connection and finishes without an error. The
+    /// application must then run `connect`, `do_handshake` and `task_loop`
+    /// again to establish the new session, and the peer must do the same
+    /// once it receives the `Event::Disconnected` event. The task message
+    /// that could not be sent is queued and sent once the renewed session is
+    /// established.
+    ///
+    /// By default, this is disabled and the task loop fails instead.
+    pub fn with_manual_session_renewal(mut self, enabled: bool) -> Self {
+        self.manual_session_renewal = enabled;
+        self
+    }
+
+    /// Set the number of outgoing messages that may be buffered in the task
+    /// loop before the task is asked to back off.
+    ///
+    /// Once the WebSocket connection cannot keep up, the outgoing channel
+    /// passed to [`Task::start`](tasks/trait.Task.html#tymethod.start)
+    /// stops accepting messages until the buffered messages have been sent.
+    ///
+    /// By default, 64 messages are buffered.
+    pub fn with_outgoing_buffer(mut self, size: usize) -> Self {
+        self.outgoing_buffer = size;
+        self
+    }
+
+    /// Record every message that is sent or received.
+    ///
+    /// See the [`transcript`](transcript/index.html) module for details.
+    ///
+    /// By default, no transcript is recorded.
+    pub fn with_transcript_recorder(mut self, recorder: Box<dyn TranscriptRecorder>) -> Self {
+        self.transcript = Some(recorder);
+        self
+    }
+
+    /// Register an observer that may drop or annotate messages.
+    ///
+    /// See the [`observer`](observer/index.html) module for details.
+    ///
+    /// By default, no observer is registered.
+    pub fn with_observer(mut self, observer: Box<dyn Observer>) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Drop responders that did not complete the peer handshake within the
+    /// specified duration after the server announced them.
+    ///
+    /// This only applies to initiators. Stalled responders are dropped with
+    /// the close code 3004 (Dropped by Initiator). Set the `timeout` argument
+    /// to `None` to keep responders until they disconnect.
+    ///
+    /// By default, responders are dropped after 60 seconds.
+    pub fn with_responder_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.responder_timeout = timeout;
+        self
+    }
+
+    /// Give up if no initiator connected to the server within the specified
+    /// duration after the server handshake, or after the initiator
+    /// disconnected.
+    ///
+    /// This only applies to responders. While no initiator is connected, the
+    /// responder stays connected and waits for the server to announce one,
+    /// see [`Event::WaitingForInitiator`](enum.Event.html#variant.WaitingForInitiator).
+    /// Once the timeout expires, the connection is closed and the handshake
+    /// fails with [`SaltyError::Timeout`](enum.SaltyError.html#variant.Timeout).
+    ///
+    /// By default, the responder waits until the connection is closed.
+    pub fn with_initiator_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.initiator_timeout = timeout;
+        self
+    }
+
+    /// Decide which responders are accepted.
+    ///
+    /// This only applies to initiators. See the [`accept`](accept/index.html)
+    /// module for details.
+    ///
+    /// By default, all responders that know the auth token or own the
+    /// trusted key are accepted.
+    pub fn with_accept_policy(mut self, policy: Box<dyn AcceptPolicy>) -> Self {
+        self.accept_policy = Some(policy);
+        self
+    }
+
+    /// Limit the number of responders an initiator keeps track of.
+    ///
+    /// Once a new responder exceeds the limit, the oldest responder that has
+    /// not sent a valid message yet is dropped and an
+    /// [`Event::PathAlmostFull`](enum.Event.html#variant.PathAlmostFull)
+    /// event is emitted. If there is no such responder, the new responder is
+    /// dropped instead, followed by an
+    /// [`Event::PathFull`](enum.Event.html#variant.PathFull) event.
+    ///
+    /// The limit is clamped to the range 1 to 252. This only applies to
+    /// initiators.
+    ///
+    /// By default, up to 252 responders are tracked.
+    pub fn with_max_responders(mut self, max: usize) -> Self {
+        self.max_responders = match max {
+            0 => 1,
+            n => n.min(MAX_RESPONDERS),
+        };
+        self
+    }
+
+    /// Limit the rate of messages from responders that have not been
+    /// authenticated yet.
+    ///
+    /// Every message costs a decryption attempt, so a responder that floods
+    /// the initiator with messages during the peer handshake is dropped with
+    /// the close code 3004 (Dropped by Initiator). Set the `limit` argument
+    /// to `None` to disable the limit. This only applies to initiators.
+    ///
+    /// By default, a burst of 32 messages and 16 messages per second are
+    /// allowed.
+    pub fn with_peer_rate_limit(mut self, limit: Option<RateLimit>) -> Self {
+        self.peer_rate_limit = limit;
+        self
+    }
+
+    /// Limit the rate of messages from the server during the server
+    /// handshake.
+    ///
+    /// If the server exceeds the limit, the connection is closed with a
+    /// protocol error. Set the `limit` argument to `None` to disable the
+    /// limit.
+    ///
+    /// By default, a burst of 16 messages and 8 messages per second are
+    /// allowed.
+    pub fn with_server_rate_limit(mut self, limit: Option<RateLimit>) -> Self {
+        self.server_rate_limit = limit;
+        self
+    }
+
+    /// Emit an [`Event::DuplicateConnection`](enum.Event.html#variant.DuplicateConnection)
+    /// event when the server closes the connection because another instance
+    /// with the same permanent key connected to the path.
+    ///
+    /// This only applies to responders. When a responder with the same
+    /// permanent key connects, the initiator drops the older one with the
+    /// close code 3004 (Dropped by Initiator), so the event is sent whenever
+    /// the server closes the connection with that code. Since the initiator
+    /// may drop responders for other reasons as well, the event is only a
+    /// hint, e.g. to ask the user whether to close the other instance.
+    ///
+    /// By default, duplicate connections are not detected.
+    pub fn with_duplicate_detection(mut self, enabled: bool) -> Self {
+        self.duplicate_detection = enabled;
+        self
+    }
+
+    /// Hold back up to `window` payloads in
+    /// [`SaltyClient::decrypt_from_peer_ordered`](struct.SaltyClient.html#method.decrypt_from_peer_ordered)
+    /// until the payloads the peer sent before them have arrived.
+    ///
+    /// Use this if the task transport may reorder messages. Once more than
+    /// `window` payloads are waiting, the missing ones are skipped. By
+    /// default (or if `window` is 0), payloads are never held back.
+    pub fn with_reorder_window(mut self, window: usize) -> Self {
+        self.reorder_window = window;
+        self
+    }
+
+    /// Hold back payloads in
+    /// [`SaltyClient::decrypt_from_peer_ordered`](struct.SaltyClient.html#method.decrypt_from_peer_ordered)
+    /// for at most `timeout`, see
+    /// [`with_reorder_window`](#method.with_reorder_window).
+    ///
+    /// Once a payload has been waiting for `timeout`, the missing payloads
+    /// before it are skipped. Call
+    /// [`SaltyClient::release_reordered`](struct.SaltyClient.html#method.release_reordered)
+    /// at [`SaltyClient::reorder_deadline`](struct.SaltyClient.html#method.reorder_deadline)
+    /// to release it even if no further payload arrives. By default, payloads
+    /// are held back until the window is full.
+    pub fn with_reorder_timeout(mut self, timeout: Duration) -> Self {
+        self.reorder_timeout = Some(timeout);
+        self
+    }
+
+    /// Generate a new auth token as soon as a responder used the current one.
+    ///
+    /// This only applies to initiators that use an auth token. A token is
+    /// never accepted twice, so without rotation, no other device can be
+    /// paired once a responder sent the token, even if its handshake fails
+    /// later. With rotation enabled, the new token is announced in an
+    /// [`Event::AuthTokenRotated`](enum.Event.html#variant.AuthTokenRotated)
+    /// event, e.g. to show a new QR code. See also
+    /// [`SaltyClient::rotate_auth_token`](struct.SaltyClient.html#method.rotate_auth_token).
+    ///
+    /// By default, the token is not rotated.
+    pub fn with_auth_token_rotation(mut self, enabled: bool) -> Self {
+        self.auth_token_rotation = enabled;
+        self
+    }
+
+    /// Choose how protocol violations of unauthenticated responders are
+    /// handled.
+    ///
+    /// In strict mode, any protocol violation closes the connection, as
+    /// required by the specification. In lenient mode, a responder that
+    /// sends an invalid message or nonce during the peer handshake is only
+    /// dropped with the close code 3001 (Protocol Error) and a warning is
+    /// logged. This helps when interoperating with buggy clients. This only
+    /// applies to initiators, violations by the server or by an
+    /// authenticated peer always close the connection.
+    ///
+    /// By default, strict mode is enabled.
+    pub fn with_strict_mode(mut self, strict: bool) -> Self {
+        self.strict_mode = strict;
+        self
+    }
+
+    /// Allow rotating the session keys with the authenticated peer.
+    ///
+    /// Once enabled, key rotations started by the peer are answered, and
+    /// [`SaltyClient::rekey`](struct.SaltyClient.html#method.rekey) may be
+    /// used to start one. Rekeying is not part of the SaltyRTC
+    /// specification, so the peer must enable it as well. Otherwise, the
+    /// rotation never finishes.
+    ///
+    /// By default, rekeying is disabled.
+    pub fn with_rekeying(mut self, enabled: bool) -> Self {
+        self.rekeying = enabled;
+        self
+    }
+
+    /// Decrypt incoming task messages on a pool of `workers` threads.
+    ///
+    /// Once the peer handshake is done, the task loop validates the
+    /// incoming messages in order and hands the decryption off to the pool.
+    /// The decrypted messages are still passed to the task in the order they
+    /// were received. This is only worth it if the peer sends many large
+    /// messages.
+    ///
+    /// By default (or if `workers` is 0), messages are decrypted on the
+    /// event loop thread.
+    #[cfg(feature = "connect-tokio")]
+    pub fn with_decryption_workers(mut self, workers: usize) -> Self {
+        self.decryption_workers = workers;
+        self
+    }
+
+    /// Split task messages into WebSocket frames with a payload of at most
+    /// `size` bytes.
+    ///
+    /// Use this if the server or a proxy closes the connection when large
+    /// messages are sent. Fragmented incoming messages are always
+    /// reassembled. By default (or if `size` is 0), every message is sent
+    /// as a single frame.
+    #[cfg(feature = "connect-tokio")]
+    pub fn with_max_frame_size(mut self, size: usize) -> Self {
+        self.max_frame_size = if size == 0 { None } else { Some(size) };
+        self
+    }
+
+    /// Offer the permessage-deflate WebSocket extension to the server.
+    ///
+    /// If the server accepts it, compressed messages from the server are
+    /// decompressed. Messages to the server are never compressed, since
+    /// encrypted payloads don't compress. This is mainly useful for interop
+    /// tests with servers that enable compression. By default, the
+    /// extension is not offered.
+    ///
+    /// Whether the server accepted the extension is returned by
+    /// [`SaltyClient::permessage_deflate`](struct.SaltyClient.html#method.permessage_deflate).
+    #[cfg(feature = "permessage-deflate")]
+    pub fn with_permessage_deflate(mut self, enabled: bool) -> Self {
+        self.permessage_deflate = enabled;
+        self
+    }
+
+    /// Use a custom clock for the responder timeouts, the rate limits and
+    /// the heartbeat round trip times.
+    ///
+    /// See the [`clock`](clock/index.html) module for details. By default,
+    /// the [`SystemClock`](clock/struct.SystemClock.html) is used.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Use fixed values for all cookies and initial sequence numbers.
+    ///
+    /// This allows conformance test suites to compare the handshake with
+    /// other implementations byte for byte. Every peer context uses the same
+    /// `cookie` and starts with the sequence number `csn` (the overflow
+    /// number is always 0). To pin the session keys as well, use
+    /// [`with_pinned_session_key`](#method.with_pinned_session_key).
+    ///
+    /// Never use this in production: Pinned cookies and sequence numbers
+    /// are predictable and repeat across connections.
+    ///
+    /// Panics if the cookie consists of zeros only.
+    #[cfg(feature = "conformance-testing")]
+    pub fn with_pinned_cookie_and_csn(mut self, cookie: [u8; 16], csn: u32) -> Self {
+        assert!(cookie.iter().any(|&byte| byte != 0), "Cookie may not be zero");
+        self.pinned = Some((cookie, csn));
+        self
+    }
+
+    /// Use a fixed private key for all session key pairs of the peer
+    /// handshake.
+    ///
+    /// Together with
+    /// [`with_pinned_cookie_and_csn`](#method.with_pinned_cookie_and_csn),
+    /// this makes the whole handshake deterministic. Every peer context uses
+    /// the same session key pair. The keys of a rekeying are still random,
+    /// since the peer rejects a rotation to the same key.
+    ///
+    /// Never use this in production: A pinned session key breaks forward
+    /// secrecy.
+    ///
+    /// Panics if the key consists of zeros only.
+    #[cfg(feature = "conformance-testing")]
+    pub fn with_pinned_session_key(mut self, private_key: [u8; 32]) -> Self {
+        assert!(private_key.iter().any(|&byte| byte != 0), "Session key may not be zero");
+        self.pinned_session_key = Some(private_key);
+        self
+    }
+
+    /// Continue an established session with the server instead of doing
+    /// the server handshake.
+    ///
+    /// This allows tests to start in the middle of a handshake, and relays
+    /// to hand a server connection over to another client instance. If the
+    /// session contains our address, the client starts with the peer
+    /// handshake, otherwise it waits for the `server-auth` message. The
+    /// session can be exported with
+    /// [`SaltyClient::server_session`](struct.SaltyClient.html#method.server_session).
+    ///
+    /// The session must not be used by any other client anymore, otherwise
+    /// nonces are reused. The client must be driven over the existing
+    /// connection, e.g. with a [`LowLevelClient`](struct.LowLevelClient.html),
+    /// since connecting to the server again starts a new session.
+    #[cfg(feature = "server-session")]
+    pub fn with_server_session(mut self, session: ServerSession) -> Self {
+        self.server_session = Some(session);
+        self
+    }
+
+    /// Return the random source for the signaling.
+    fn random_source(&self) -> Box<dyn RandomSource + Send> {
+        #[cfg(feature = "conformance-testing")]
+        {
+            if self.pinned.is_some() || self.pinned_session_key.is_some() {
+                return Box::new(PinnedRandom::new(
+                    self.pinned.map(|(cookie, _)| cookie),
+                    self.pinned.map(|(_, csn)| csn),
+                    self.pinned_session_key,
+                ));
+            }
+        }
+        Box::new(OsRandom)
+    }
+
     /// Create a new SaltyRTC initiator.
     pub fn initiator(self) -> Result<SaltyClient, BuilderError> {
-        let tasks = Tasks::from_vec(self.tasks).map_err(|_| BuilderError::MissingTask)?;
-        let signaling = InitiatorSignaling::new(
-            self.permanent_key,
-            tasks,
-            None,
-            self.server_public_permanent_key,
-            self.ping_interval,
-        );
-        Ok(SaltyClient {
-            signaling: Box::new(signaling),
-        })
+        self.build_role(PeerConfig::Initiator(None))
     }
 
     /// Create a new SaltyRTC initiator with a trusted peer public key.
     pub fn initiator_trusted(self, responder_trusted_pubkey: PublicKey) -> Result<SaltyClient, BuilderError> {
-        let tasks = Tasks::from_vec(self.tasks).map_err(|_| BuilderError::MissingTask)?;
-        let signaling = InitiatorSignaling::new(
-            self.permanent_key,
-            tasks,
-            Some(responder_trusted_pubkey),
-            self.server_public_permanent_key,
-            self.ping_interval,
-        );
-        Ok(SaltyClient {
-            signaling: Box::new(signaling),
-        })
+        self.build_role(PeerConfig::Initiator(Some(responder_trusted_pubkey)))
     }
 
     /// Create a new SaltyRTC responder.
     pub fn responder(self, initiator_pubkey: PublicKey, auth_token: AuthToken) -> Result<SaltyClient, BuilderError> {
-        let tasks = Tasks::from_vec(self.tasks).map_err(|_| BuilderError::MissingTask)?;
-        let signaling = ResponderSignaling::new(
-            self.permanent_key,
-            initiator_pubkey,
-            Some(auth_token),
-            self.server_public_permanent_key,
-            tasks,
-            self.ping_interval,
-        );
-        Ok(SaltyClient {
-            signaling: Box::new(signaling),
-        })
+        self.build_role(PeerConfig::Responder(initiator_pubkey, Some(auth_token)))
     }
 
     /// Create a new SaltyRTC responder with a trusted peer public key.
     pub fn responder_trusted(self, initiator_trusted_pubkey: PublicKey) -> Result<SaltyClient, BuilderError> {
-        let tasks = Tasks::from_vec(self.tasks).map_err(|_| BuilderError::MissingTask)?;
-        let signaling = ResponderSignaling::new(
-            self.permanent_key,
-            initiator_trusted_pubkey,
-            None,
-            self.server_public_permanent_key,
-            tasks,
-            self.ping_interval,
-        );
+        self.build_role(PeerConfig::Responder(initiator_trusted_pubkey, None))
+    }
+
+    /// Create the signaling for the role and the client around it.
+    fn build_role(self, peer: PeerConfig) -> Result<SaltyClient, BuilderError> {
+        let rng = self.random_source();
+        let tasks = Tasks::from_vec(self.tasks)?;
+        let mut signaling: Box<dyn Signaling> = match peer {
+            PeerConfig::Initiator(responder_trusted_pubkey) => {
+                let mut signaling = InitiatorSignaling::with_rng(
+                    self.permanent_key,
+                    tasks,
+                    responder_trusted_pubkey,
+                    self.server_public_permanent_key,
+                    self.ping_interval,
+                    rng,
+                );
+                signaling.responder_timeout = self.responder_timeout;
+                signaling.accept_policy = self.accept_policy;
+                signaling.max_responders = self.max_responders;
+                signaling.peer_rate_limit = self.peer_rate_limit;
+                signaling.auth_token_rotation = self.auth_token_rotation;
+                signaling.strict_mode = self.strict_mode;
+                Box::new(signaling)
+            },
+            PeerConfig::Responder(initiator_pubkey, auth_token) => {
+                let mut signaling = ResponderSignaling::with_rng(
+                    self.permanent_key,
+                    initiator_pubkey,
+                    auth_token,
+                    self.server_public_permanent_key,
+                    tasks,
+                    self.ping_interval,
+                    rng,
+                );
+                signaling.initiator_timeout = self.initiator_timeout;
+                Box::new(signaling)
+            },
+        };
+        signaling.common_mut().protocol_version = self.protocol_version;
+        signaling.common_mut().transcript = self.transcript;
+        signaling.common_mut().observer = self.observer;
+        signaling.common_mut().server_rate_limit = self.server_rate_limit;
+        signaling.common_mut().reorder = ReorderBuffer::new(self.reorder_window, self.reorder_timeout);
+        signaling.common_mut().clock = self.clock;
+        signaling.common_mut().rekeying = self.rekeying;
+        #[cfg(feature = "server-session")]
+        {
+            if let Some(ref session) = self.server_session {
+                signaling.common_mut().restore_server_session(session)
+                    .map_err(|e| BuilderError::InvalidServerSession(e.to_string()))?;
+            }
+        }
         Ok(SaltyClient {
-            signaling: Box::new(signaling),
+            signaling,
+            #[cfg(feature = "connect-tokio")]
+            proxy: self.proxy,
+            #[cfg(feature = "connect-tokio")]
+            tls_config: self.tls_config,
+            #[cfg(feature = "connect-tokio")]
+            decryption_workers: self.decryption_workers,
+            #[cfg(feature = "connect-tokio")]
+            max_frame_size: self.max_frame_size,
+            #[cfg(feature = "connect-tokio")]
+            shared: self.shared,
+            #[cfg(feature = "connect-tokio")]
+            sleeper: self.sleeper,
+            #[cfg(feature = "permessage-deflate")]
+            permessage_deflate: self.permessage_deflate,
+            #[cfg(feature = "permessage-deflate")]
+            permessage_deflate_negotiated: false,
+            #[cfg(feature = "connect-tokio")]
+            handshake_permit: None,
+            heartbeat: self.heartbeat,
+            servers: self.servers,
+            manual_session_renewal: self.manual_session_renewal,
+            outgoing_buffer: self.outgoing_buffer,
+            duplicate_detection: self.duplicate_detection,
+            state: StateObservers::default(),
+            timers: Timers::default(),
+            #[cfg(feature = "connect-tokio")]
+            lifecycle: Lifecycle::default(),
+            #[cfg(feature = "connect-tokio")]
+            last_server: None,
+            suspended_at: None,
+            #[cfg(feature = "connect-tokio")]
+            rekey_requests: None,
+            #[cfg(feature = "connect-tokio")]
+            timers_changed: None,
+            #[cfg(feature = "connect-tokio")]
+            rekey_waiters: vec![],
         })
     }
+
+    /// Create a new SaltyRTC client with the role that matches the pairing
+    /// information.
+    ///
+    /// If the initiator public key is our own permanent public key, an
+    /// initiator is created. It uses the auth token of the pairing
+    /// information (if any), so that a previously shared URI stays valid.
+    /// Otherwise, a responder is created that authenticates with the auth
+    /// token, or trusts the initiator key if there is no token.
+    ///
+    /// The server of the pairing information is tried first by
+    /// [`connect_with_failover`](fn.connect_with_failover.html).
+    pub fn from_pairing_info(mut self, info: &PairingInfo) -> Result<SaltyClient, BuilderError> {
+        self.servers.insert(0, (info.host.clone(), info.port));
+        if self.permanent_key.public_key() == &info.initiator_pubkey {
+            let mut salty = self.initiator()?;
+            if let Some(ref token) = info.auth_token {
+                salty.signaling.common_mut().set_auth_token(token.clone());
+            }
+            return Ok(salty);
+        }
+        match info.auth_token {
+            Some(ref token) => self.responder(info.initiator_pubkey, token.clone()),
+            None => self.responder_trusted(info.initiator_pubkey),
+        }
+    }
+
+    /// Create a new SaltyRTC client that trusts the peer of a previous
+    /// session.
+    ///
+    /// The client has the same role as in that session, see
+    /// [`SaltyClient::trust_peer`](struct.SaltyClient.html#method.trust_peer).
+    /// If no server key was specified, the server key pinned by the previous
+    /// session (if any) is used. The task negotiated before (if any) is
+    /// preferred.
+    pub fn with_trusted_peer(mut self, peer: TrustedPeer) -> Result<SaltyClient, BuilderError> {
+        if self.server_public_permanent_key.is_none() {
+            self.server_public_permanent_key = peer.server_permanent_key;
+        }
+        if let Some(ref name) = peer.task {
+            if let Some(index) = self.tasks.iter().position(|task| task.name() == *name) {
+                let task = self.tasks.remove(index);
+                self.tasks.insert(0, task);
+            }
+        }
+        match peer.role {
+            Role::Initiator => self.initiator_trusted(peer.permanent_key),
+            Role::Responder => self.responder_trusted(peer.permanent_key),
+        }
+    }
+
+    /// Create a new SaltyRTC client that resumes pairing with the peer of a
+    /// previous session.
+    ///
+    /// The token must have been created by
+    /// [`SaltyClient::export_resumption`](struct.SaltyClient.html#method.export_resumption)
+    /// with the same permanent key. The client has the same role as before
+    /// and trusts the permanent key of the peer, so no auth token is needed.
+    /// If no server key was specified, the server key pinned by the previous
+    /// session is used. The task negotiated before is preferred.
+    ///
+    /// This is experimental, see the [`resumption`](resumption/index.html)
+    /// module.
+    #[cfg(feature = "resumption")]
+    pub fn resume(self, token: &[u8]) -> Result<SaltyClient, BuilderError> {
+        let peer = TrustedPeer::from_encrypted_bytes(token, &self.permanent_key)
+            .map_err(BuilderError::Resumption)?;
+        self.with_trusted_peer(peer)
+    }
 }
 
 /// The SaltyRTC Client instance.
@@ -254,6 +1034,95 @@ pub struct SaltyClient {
     /// [`ResponderSignaling`](protocol/struct.ResponderSignaling.html)
     /// instance.
     signaling: Box<dyn Signaling>,
+
+    /// The proxy used to reach the server, if any.
+    #[cfg(feature = "connect-tokio")]
+    proxy: Option<ProxyConfig>,
+
+    /// The TLS configuration for the server connection, if any.
+    #[cfg(feature = "connect-tokio")]
+    tls_config: Option<TlsConfig>,
+
+    /// The number of threads used to decrypt task messages, or 0 to decrypt
+    /// them on the event loop.
+    #[cfg(feature = "connect-tokio")]
+    decryption_workers: usize,
+
+    /// The maximum payload size of outgoing WebSocket frames, if messages
+    /// are fragmented.
+    #[cfg(feature = "connect-tokio")]
+    max_frame_size: Option<usize>,
+
+    /// The resources shared with other clients, if any.
+    #[cfg(feature = "connect-tokio")]
+    shared: Option<SharedResources>,
+
+    /// The sleeper used to wait for the next timeout.
+    #[cfg(feature = "connect-tokio")]
+    sleeper: Arc<dyn Sleeper>,
+
+    /// Whether the permessage-deflate extension is offered to the server.
+    #[cfg(feature = "permessage-deflate")]
+    permessage_deflate: bool,
+
+    /// Whether the server accepted the permessage-deflate extension on the
+    /// current connection.
+    #[cfg(feature = "permessage-deflate")]
+    permessage_deflate_negotiated: bool,
+
+    /// Allows the connection and the server handshake to run while the
+    /// number of concurrent handshakes is limited.
+    #[cfg(feature = "connect-tokio")]
+    handshake_permit: Option<HandshakePermit>,
+
+    /// The heartbeat interval and miss threshold, if enabled.
+    heartbeat: Option<(Duration, u32)>,
+
+    /// The server endpoints used by `connect_with_failover`.
+    #[cfg_attr(not(feature = "connect-tokio"), allow(dead_code))]
+    servers: Vec<(String, u16)>,
+
+    /// Whether to prepare a session renewal when the outgoing sequence
+    /// numbers are exhausted.
+    #[cfg_attr(not(feature = "connect-tokio"), allow(dead_code))]
+    manual_session_renewal: bool,
+
+    /// The number of outgoing messages that are buffered in the task loop.
+    #[cfg_attr(not(feature = "connect-tokio"), allow(dead_code))]
+    outgoing_buffer: usize,
+
+    /// Whether to emit `DuplicateConnection` events.
+    duplicate_detection: bool,
+
+    /// The connection state and its subscribers.
+    state: StateObservers,
+
+    /// The timers requested by the signaling.
+    timers: Timers,
+
+    /// The teardown steps of the task loop that have run.
+    #[cfg(feature = "connect-tokio")]
+    lifecycle: Lifecycle,
+
+    /// The server of the last connection, used by `reconnect`.
+    #[cfg(feature = "connect-tokio")]
+    last_server: Option<(String, u16)>,
+
+    /// The point in time at which the session was suspended, if it is.
+    suspended_at: Option<Instant>,
+
+    /// The channel for key rotation requests to the task loop, while it runs.
+    #[cfg(feature = "connect-tokio")]
+    rekey_requests: Option<mpsc::UnboundedSender<RekeyNotifier>>,
+
+    /// The futures returned by `rekey` that wait for the rotation to finish.
+    #[cfg(feature = "connect-tokio")]
+    rekey_waiters: Vec<RekeyNotifier>,
+
+    /// Notifies the task loop when the next timer deadline changed, while it
+    /// runs.
+    #[cfg(feature = "connect-tokio")]
+    timers_changed: Option<mpsc::UnboundedSender<()>>,
 }
 
 impl SaltyClient {
@@ -268,16 +1137,51 @@ impl SaltyClient {
         self.signaling.role()
     }
 
+    /// Return the protocol version spoken with the server and the peer.
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        self.signaling.common().protocol_version
+    }
+
     /// Return a reference to the auth token.
     pub fn auth_token(&self) -> Option<&AuthToken> {
         self.signaling.auth_token()
     }
 
+    /// Invalidate the auth token, so that no responder can use it anymore.
+    ///
+    /// Tokens are invalidated automatically once a responder used them, so
+    /// this is only needed to revoke a token that has been shown to the
+    /// user but should not be used anymore.
+    pub fn invalidate_auth_token(&mut self) {
+        self.signaling.invalidate_auth_token()
+    }
+
+    /// Replace the auth token with a new one and return the new pairing
+    /// data, e.g. to pair another device.
+    ///
+    /// The previous token is no longer accepted. This only works for
+    /// initiators that use an auth token, before the peer handshake is done.
+    pub fn rotate_auth_token(&mut self) -> SaltyResult<PairingData> {
+        let token = self.signaling.rotate_auth_token()?;
+        Ok(PairingData::new(*self.initiator_pubkey(), token))
+    }
+
     /// Return a reference to the initiator public key.
     pub fn initiator_pubkey(&self) -> &PublicKey {
         self.signaling.initiator_pubkey()
     }
 
+    /// Return the pairing data that must be passed to the responder.
+    ///
+    /// This is only available for initiators that use an auth token.
+    pub fn pairing_data(&self) -> Option<PairingData> {
+        if self.role() != Role::Initiator {
+            return None;
+        }
+        self.auth_token()
+            .map(|token| PairingData::new(*self.initiator_pubkey(), token.clone()))
+    }
+
     /// Return a reference to the selected task.
     pub fn task(&self) -> Option<Arc<Mutex<BoxedTask>>> {
         self.signaling
@@ -286,39 +1190,496 @@ impl SaltyClient {
             .clone()
     }
 
+    /// Return a stream of connection state changes.
+    ///
+    /// The stream starts with the current state, if a connection has been
+    /// started already. Every subscriber gets its own stream, dropping it
+    /// unsubscribes.
+    pub fn state_changes(&mut self) -> mpsc::UnboundedReceiver<ConnectionState> {
+        self.state.subscribe()
+    }
+
+    /// Return the current connection state, or `None` if no connection has
+    /// been started yet.
+    pub fn connection_state(&self) -> Option<ConnectionState> {
+        self.state.current().cloned()
+    }
+
+    /// Return whether the server accepted the permessage-deflate extension
+    /// on the current connection.
+    ///
+    /// This is only possible if the extension was enabled with
+    /// [`SaltyClientBuilder::with_permessage_deflate`](struct.SaltyClientBuilder.html#method.with_permessage_deflate).
+    #[cfg(feature = "permessage-deflate")]
+    pub fn permessage_deflate(&self) -> bool {
+        self.permessage_deflate_negotiated
+    }
+
+    /// Return the reason why the last connection ended, or `None` if no
+    /// connection has ended yet.
+    pub fn close_reason(&self) -> Option<&CloseReason> {
+        self.state.close_reason()
+    }
+
     /// Handle an incoming message.
-    fn handle_message(&mut self, bbox: ByteBox) -> SignalingResult<Vec<HandleAction>> {
-        self.signaling.handle_message(bbox)
+    fn handle_message(&mut self, bbox: ByteBox) -> SignalingResult<HandleActions> {
+        let actions = self.signaling.handle_message(bbox)?;
+        Ok(self.handle_actions(actions))
+    }
+
+    /// Prepare an incoming message for decryption on a worker thread.
+    #[cfg(feature = "connect-tokio")]
+    fn prepare_message(&mut self, bbox: ByteBox) -> SignalingResult<PreparedMessage> {
+        Ok(match self.signaling.prepare_message(bbox)? {
+            PreparedMessage::Handled(actions) => PreparedMessage::Handled(self.handle_actions(actions)),
+            prepared => prepared,
+        })
+    }
+
+    /// Handle a task message that was decrypted on a worker thread.
+    #[cfg(feature = "connect-tokio")]
+    fn handle_decrypted(&mut self, decrypted: DecryptedMessage) -> SignalingResult<HandleActions> {
+        let actions = self.signaling.handle_decrypted(decrypted)?;
+        Ok(self.handle_actions(actions))
+    }
+
+    /// Start and cancel the requested timers and update the connection
+    /// state. Return the remaining actions.
+    fn handle_actions(&mut self, actions: HandleActions) -> HandleActions {
+        let now = self.now();
+        #[cfg(feature = "connect-tokio")]
+        let next_deadline = self.timers.next_deadline();
+        let actions = self.timers.apply(actions, now);
+        if actions.iter().any(|action| *action == HandleAction::HandshakeDone) {
+            self.timers.cancel(TimerId::Handshake);
+            if let Some((interval, _)) = self.heartbeat {
+                self.timers.start(TimerId::Heartbeat, interval, now);
+            }
+        }
+        #[cfg(feature = "connect-tokio")]
+        {
+            if self.timers.next_deadline() != next_deadline {
+                if let Some(ref tx) = self.timers_changed {
+                    let _ = tx.unbounded_send(());
+                }
+            }
+        }
+        self.sync_state(&actions);
+        #[cfg(feature = "connect-tokio")]
+        {
+            if actions.iter().any(|action| *action == HandleAction::Event(Event::Rekeyed)) {
+                for waiter in self.rekey_waiters.drain(..) {
+                    let _ = waiter.send(Ok(()));
+                }
+            }
+        }
+        actions
+    }
+
+    /// Update the connection state after handling a message.
+    fn sync_state(&mut self, actions: &HandleActions) {
+        let peer_closed = actions.iter().find_map(|action| match action {
+            HandleAction::TaskMessage(TaskMessage::Close(reason)) => Some(*reason),
+            _ => None,
+        });
+        if let Some(reason) = peer_closed {
+            self.state.closing(CloseReason::remote(Some(reason), "Closed by the peer"));
+            return;
+        }
+        let state = match self.signaling.common().signaling_state() {
+            SignalingState::ServerHandshake => ConnectionState::ServerHandshake,
+            SignalingState::PeerHandshake => ConnectionState::PeerHandshake,
+            SignalingState::Task => match self.task() {
+                Some(task) => match task.lock() {
+                    Ok(t) => ConnectionState::Task(t.name().into_owned()),
+                    Err(_) => return,
+                },
+                None => return,
+            },
+        };
+        self.state.set(state);
+    }
+
+    /// Update the connection state after the server closed the connection.
+    ///
+    /// Return a `DuplicateConnection` event if the close code indicates
+    /// that another instance with the same permanent key took over.
+    fn closed(&mut self, code: Option<CloseCode>) -> Option<Event> {
+        self.state.closed(code);
+        self.timers = Timers::default();
+        let dropped = code == Some(CloseCode::DroppedByInitiator);
+        if self.duplicate_detection && dropped && self.role() == Role::Responder {
+            Some(Event::DuplicateConnection)
+        } else {
+            None
+        }
+    }
+
+    /// Run the teardown steps of the task loop that have not run yet.
+    ///
+    /// The task is closed with the close code of the connection, or with
+    /// `WsGoingAway` if there is none. The connection is only marked as
+    /// closed once `transport_closed` is set.
+    #[cfg(feature = "connect-tokio")]
+    fn tear_down(&mut self, transport_closed: bool) {
+        while let Some(step) = self.lifecycle.next_step(transport_closed) {
+            match step {
+                Step::Task => if let Some(task) = self.task() {
+                    let reason = self.state.close_code().unwrap_or(CloseCode::WsGoingAway);
+                    debug!("Closing task with reason {}", reason);
+                    match task.lock() {
+                        Ok(mut task) => task.close(reason),
+                        Err(e) => error!("Could not lock task mutex: {}", e),
+                    }
+                },
+                Step::Signaling => {
+                    self.timers = Timers::default();
+                    self.rekey_requests = None;
+                    self.rekey_waiters.clear();
+                    self.timers_changed = None;
+                },
+                Step::Transport => self.state.closed(None),
+            }
+        }
+    }
+
+    /// Reset the handshake before connecting to another server.
+    #[cfg(feature = "connect-tokio")]
+    fn reset_handshake(&mut self) -> SignalingResult<()> {
+        self.timers = Timers::default();
+        self.signaling.reset_handshake()
+    }
+
+    /// Return the current time of the configured clock.
+    fn now(&self) -> Instant {
+        self.signaling.common().clock.now()
+    }
+
+    /// Return the point in time at which the next timer expires, if any.
+    fn next_timeout(&self) -> Option<Instant> {
+        self.timers.next_deadline()
+    }
+
+    /// Fail the handshake if it is not done after `timeout`.
+    #[cfg(feature = "connect-tokio")]
+    fn start_handshake_timeout(&mut self, timeout: Duration) {
+        let now = self.now();
+        self.timers.start(TimerId::Handshake, timeout, now);
+    }
+
+    /// Notify the signaling about the timers that expired, and create the
+    /// next heartbeat if it is due.
+    fn handle_timeouts(&mut self, now: Instant) -> SignalingResult<HandleActions> {
+        let mut actions = HandleActions::new();
+        for id in self.timers.expire(now) {
+            let expired = match (id, self.heartbeat) {
+                (TimerId::Handshake, _) => {
+                    info!("Handshake did not finish in time, giving up");
+                    return Err(SignalingError::HandshakeTimeout);
+                },
+                (TimerId::Heartbeat, Some((interval, miss_threshold))) => {
+                    let mut heartbeat = self.heartbeat_tick(miss_threshold)?;
+                    heartbeat.push(HandleAction::StartTimer(TimerId::Heartbeat, interval));
+                    heartbeat
+                },
+                (TimerId::Heartbeat, None) => HandleActions::new(),
+                (id, _) => {
+                    let mut expired = self.signaling.handle_timeout(id)?;
+                    let common = self.signaling.common_mut();
+                    expired.retain_replies(|reply| common.record_outgoing(reply));
+                    expired
+                },
+            };
+            actions.extend(self.timers.apply(expired, now));
+        }
+        Ok(actions)
+    }
+
+    /// Create the next heartbeat for the peer, unless the session is
+    /// suspended.
+    fn heartbeat_tick(&mut self, miss_threshold: u32) -> SignalingResult<HandleActions> {
+        if self.is_suspended() {
+            return Ok(HandleActions::new());
+        }
+        let mut actions = self.signaling.heartbeat_tick(miss_threshold)?;
+        let common = self.signaling.common_mut();
+        actions.retain_replies(|reply| common.record_outgoing(reply));
+        Ok(actions)
+    }
+
+    /// Encode a task message and record it in the transcript.
+    fn encode_task_message(&mut self, val: Value) -> SignalingResult<ByteBox> {
+        let bbox = self.signaling.encode_task_message(val)?;
+        self.signaling.common_mut().record_outgoing(&bbox);
+        Ok(bbox)
     }
 
     /// Encrypt a task message.
     pub fn encrypt_task_message(&mut self, val: Value) -> SaltyResult<Vec<u8>> {
         trace!("Encrypting task message");
-        self.signaling
-            .encode_task_message(val)
+        self.encode_task_message(val)
             .map(|bbox: ByteBox| bbox.into_bytes())
-            .map_err(|e: SignalingError| match e {
-                SignalingError::Crypto(msg) => SaltyError::Crypto(msg),
-                SignalingError::Decode(msg) => SaltyError::Decode(msg),
-                SignalingError::Protocol(msg) => SaltyError::Protocol(msg),
-                SignalingError::Crash(msg) => SaltyError::Crash(msg),
-                other => SaltyError::Crash(format!("Unexpected signaling error: {}", other)),
-            })
+            .map_err(encode_error)
+    }
+
+    /// Encrypt a task message. If the outgoing sequence numbers are exhausted
+    /// and manual session renewal is enabled, renew the session and return
+    /// `None`.
+    ///
+    /// In that case, the message is queued and sent once the handshake of
+    /// the renewed session is done.
+    #[cfg(feature = "connect-tokio")]
+    fn encrypt_task_message_or_renew(&mut self, val: Value) -> SaltyResult<Option<Vec<u8>>> {
+        trace!("Encrypting task message");
+        if self.manual_session_renewal && self.signaling.outgoing_csn_exhausted() == Ok(true) {
+            info!("Outgoing sequence numbers exhausted, renewing session");
+            self.renew_session()?;
+            self.signaling.common_mut().renewal_queue.push(val);
+            self.state.closing(CloseReason::local(Some(CloseCode::WsGoingAway), "Session renewal"));
+            return Ok(None);
+        }
+        self.encode_task_message(val)
+            .map(|bbox| Some(bbox.into_bytes()))
+            .map_err(encode_error)
+    }
+
+    /// Prepare a new handshake with the peer of the current session.
+    ///
+    /// New session keys, cookies and sequence numbers will be used, the peer
+    /// permanent key is trusted and the chosen task is kept. Run `connect`,
+    /// `do_handshake` and `task_loop` again to establish the new session. The
+    /// task will be started again with new channels, and an
+    /// [`Event::SessionRenewed`](enum.Event.html#variant.SessionRenewed) is
+    /// emitted once the handshake is done.
+    ///
+    /// This fails if the peer handshake has not been finished yet.
+    pub fn renew_session(&mut self) -> SaltyResult<()> {
+        Ok(self.signaling.renew_session()?)
+    }
+
+    /// Suspend the session with the peer, e.g. before a mobile app is moved
+    /// to the background and the system suspends its sockets.
+    ///
+    /// Heartbeats are paused while the session is suspended, so that the
+    /// peer is not considered lost because it cannot answer. The connection
+    /// itself is left alone. Call [`resume`](#method.resume), or use
+    /// [`reconnect`](fn.reconnect.html), to continue with the same peer and
+    /// task.
+    ///
+    /// This fails with `SaltyError::NoPeer` if the peer handshake has not
+    /// been finished yet.
+    pub fn suspend(&mut self) -> SaltyResult<()> {
+        if self.peer_info().is_none() {
+            return Err(SaltyError::NoPeer);
+        }
+        if self.suspended_at.is_none() {
+            info!("Suspending session");
+            self.suspended_at = Some(self.now());
+        }
+        Ok(())
+    }
+
+    /// Return whether the session has been suspended with
+    /// [`suspend`](#method.suspend).
+    pub fn is_suspended(&self) -> bool {
+        self.suspended_at.is_some()
+    }
+
+    /// Prepare to continue a suspended session on a new connection.
+    ///
+    /// Like [`renew_session`](#method.renew_session), this prepares a new
+    /// handshake that trusts the permanent key of the peer and keeps the
+    /// chosen task. Run `connect`, `do_handshake` and `task_loop` again
+    /// afterwards, the task is started again with new channels and an
+    /// `Event::SessionRenewed` is emitted. The future of the previous task
+    /// loop must have been dropped.
+    ///
+    /// This fails with `SaltyError::NoPeer` if the peer handshake has not
+    /// been finished yet.
+    pub fn resume(&mut self) -> SaltyResult<()> {
+        if self.peer_info().is_none() {
+            return Err(SaltyError::NoPeer);
+        }
+        self.renew_session()?;
+        self.timers = Timers::default();
+        if let Some(suspended_at) = self.suspended_at.take() {
+            let now = self.now();
+            let suspended = if now > suspended_at { now - suspended_at } else { Duration::from_secs(0) };
+            info!("Resuming session after {:?}", suspended);
+        }
+        Ok(())
+    }
+
+    /// Encrypt the messages that drop the peers which have not finished the
+    /// handshake yet. These are sent before the connection is closed.
+    #[cfg(feature = "connect-tokio")]
+    pub(crate) fn encrypt_drop_pending_peers(&mut self) -> SaltyResult<Vec<Vec<u8>>> {
+        let actions = self.signaling.drop_pending_peers().map_err(encode_error)?;
+        let now = self.now();
+        let (replies, _) = self.timers.apply(actions, now).split_replies();
+        let common = self.signaling.common_mut();
+        Ok(replies
+            .into_iter()
+            .filter(|bbox| common.record_outgoing(bbox))
+            .map(ByteBox::into_bytes)
+            .collect())
     }
 
     /// Encrypt a close message for the peer.
     pub fn encrypt_close_message(&mut self, reason: CloseCode) -> SaltyResult<Vec<u8>> {
         trace!("Encrypting close message");
-        self.signaling
+        let bbox = self.signaling
             .encode_close_message(reason, None)
-            .map(|bbox: ByteBox| bbox.into_bytes())
-            .map_err(|e: SignalingError| match e {
-                SignalingError::Crypto(msg) => SaltyError::Crypto(msg),
-                SignalingError::Decode(msg) => SaltyError::Decode(msg),
-                SignalingError::Protocol(msg) => SaltyError::Protocol(msg),
-                SignalingError::Crash(msg) => SaltyError::Crash(msg),
-                other => SaltyError::Crash(format!("Unexpected signaling error: {}", other)),
-            })
+            .map_err(encode_error)?;
+        self.signaling.common_mut().record_outgoing(&bbox);
+        Ok(bbox.into_bytes())
+    }
+
+    /// Start rotating the session keys with the peer, and return the
+    /// encrypted rekey message.
+    ///
+    /// This is meant for transports driven by the application. The keys
+    /// have been rotated once an
+    /// [`Event::Rekeyed`](enum.Event.html#variant.Rekeyed) is emitted. See
+    /// [`rekey`](#method.rekey) for the task loop.
+    pub fn encrypt_rekey_message(&mut self) -> SaltyResult<Vec<u8>> {
+        trace!("Encrypting rekey message");
+        let bbox = self.signaling.start_rekey()?;
+        self.signaling.common_mut().record_outgoing(&bbox);
+        Ok(bbox.into_bytes())
+    }
+
+    /// Rotate the session keys with the peer without interrupting the task.
+    ///
+    /// The rekey message is sent by the running task loop. The returned
+    /// future resolves once the peer answered and the new keys are used.
+    /// Rekeying must be enabled on both peers with
+    /// [`SaltyClientBuilder::with_rekeying`](struct.SaltyClientBuilder.html#method.with_rekeying).
+    #[cfg(feature = "connect-tokio")]
+    pub fn rekey(&mut self) -> BoxedFuture<(), SaltyError> {
+        let (done_tx, done_rx) = oneshot::channel();
+        let requested = match self.rekey_requests {
+            Some(ref requests) => requests.unbounded_send(done_tx),
+            None => return Box::new(futures::future::err(SaltyError::NoPeer)),
+        };
+        if requested.is_err() {
+            return Box::new(futures::future::err(SaltyError::NoPeer));
+        }
+        Box::new(done_rx.then(|res| match res {
+            Ok(res) => res,
+            Err(_) => Err(SaltyError::Rekey("Connection closed before the keys were rotated".into())),
+        }))
+    }
+
+    /// Handle a key rotation request of the task loop.
+    ///
+    /// Return the rekey message, or notify the requester about the error.
+    #[cfg(feature = "connect-tokio")]
+    fn start_rekey(&mut self, done: RekeyNotifier) -> Option<Vec<u8>> {
+        match self.encrypt_rekey_message() {
+            Ok(bytes) => {
+                self.rekey_waiters.push(done);
+                Some(bytes)
+            },
+            Err(e) => {
+                let _ = done.send(Err(e));
+                None
+            },
+        }
+    }
+
+    /// Once the peer handshake is done, return the permanent key, address
+    /// and authentication method of the peer.
+    pub fn peer_info(&self) -> Option<PeerInfo> {
+        self.signaling.peer_info()
+    }
+
+    /// Once the peer handshake is done, return the public permanent key of
+    /// the peer.
+    pub fn peer_permanent_key(&self) -> Option<PublicKey> {
+        self.peer_info().map(|peer| peer.permanent_key)
+    }
+
+    /// Once the peer handshake is done, return the peer along with the
+    /// pinned server key, e.g. to be stored by apps that authenticated the
+    /// peer with an auth token.
+    ///
+    /// Pass the result to
+    /// [`SaltyClientBuilder::with_trusted_peer`](struct.SaltyClientBuilder.html#method.with_trusted_peer)
+    /// to start a trusted session with the same peer later.
+    pub fn trust_peer(&self) -> Option<TrustedPeer> {
+        let task = self.task()
+            .and_then(|task| task.lock().ok().map(|task| task.name().into_owned()));
+        self.peer_permanent_key().map(|permanent_key| TrustedPeer {
+            permanent_key,
+            role: self.role(),
+            server_permanent_key: self.signaling.server().permanent_key,
+            task,
+        })
+    }
+
+    /// Export an encrypted token to resume pairing with the current peer
+    /// later, see [`SaltyClientBuilder::resume`](struct.SaltyClientBuilder.html#method.resume).
+    ///
+    /// This is only possible once the session with the peer has ended
+    /// cleanly, i.e. it was closed normally or handed over to the task.
+    ///
+    /// This is experimental, see the [`resumption`](resumption/index.html)
+    /// module.
+    #[cfg(feature = "resumption")]
+    pub fn export_resumption(&self) -> Result<Vec<u8>, ResumptionError> {
+        match self.close_reason().and_then(|reason| reason.code) {
+            Some(CloseCode::WsClosingNormal) | Some(CloseCode::WsGoingAway) | Some(CloseCode::Handover) => {},
+            _ => return Err(ResumptionError::NotResumable("Session did not end cleanly".into())),
+        }
+        let peer = self.trust_peer()
+            .ok_or_else(|| ResumptionError::NotResumable("Peer handshake is not done".into()))?;
+        if peer.task.is_none() {
+            return Err(ResumptionError::NotResumable("No task was negotiated".into()));
+        }
+        peer.to_encrypted_bytes(&self.signaling.common().permanent_keypair)
+    }
+
+    /// Once the peer handshake is done, return the fields of the peer's
+    /// 'auth' message that are not part of the SaltyRTC protocol.
+    ///
+    /// Other implementations may use them for vendor extensions.
+    pub fn peer_extra_fields(&self) -> Option<&ExtraFields> {
+        match self.signaling.common().signaling_state() {
+            SignalingState::Task => Some(&self.signaling.common().peer_extra_fields),
+            _ => None,
+        }
+    }
+
+    /// Return the fields that are not part of the SaltyRTC protocol of the
+    /// last signaling message of the given type (e.g. `"server-auth"`).
+    ///
+    /// Return `None` if no message of that type with unknown fields was
+    /// received in the current session. Task messages are passed to the
+    /// task as they are, so they are not included.
+    pub fn extra_fields(&self, message_type: &str) -> Option<&ExtraFields> {
+        self.signaling.common().received_extra_fields.get(message_type)
+    }
+
+    /// Once the peer handshake is done, return the data of the chosen task
+    /// that the peer sent in its 'auth' message.
+    ///
+    /// The task receives the same data in
+    /// [`Task::init`](tasks/trait.Task.html#tymethod.init). Return `None` if
+    /// the peer did not send any data for the task.
+    pub fn task_data(&self) -> Option<&HashMap<String, Value>> {
+        match self.signaling.common().signaling_state() {
+            SignalingState::Task => self.signaling.common().peer_task_data.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Return a snapshot of the server and peer handshake states.
+    ///
+    /// This is meant for debugging handshakes that don't complete, see
+    /// [`StateSummary`](struct.StateSummary.html).
+    pub fn state_summary(&self) -> StateSummary {
+        self.signaling.state_summary()
     }
 
     /// If the peer is already determined, return the current incoming and
@@ -327,18 +1688,142 @@ impl SaltyClient {
         self.signaling.current_peer_sequence_numbers()
     }
 
+    /// Return the session with the server, once the session key of the
+    /// server is known.
+    ///
+    /// See [`SaltyClientBuilder::with_server_session`](struct.SaltyClientBuilder.html#method.with_server_session).
+    #[cfg(feature = "server-session")]
+    pub fn server_session(&self) -> Option<ServerSession> {
+        let address = match self.signaling.server().handshake_state() {
+            ServerHandshakeState::Done => Some(Address::from(self.signaling.identity()).0),
+            _ => None,
+        };
+        self.signaling.server().session(address)
+    }
+
+    /// Return a snapshot of the message and traffic statistics.
+    ///
+    /// This is meant for diagnostics, e.g. to be attached to support
+    /// requests. See [`Stats`](struct.Stats.html).
+    pub fn stats(&self) -> Stats {
+        self.signaling.common().stats.snapshot(self.now(), self.current_peer_sequence_numbers())
+    }
+
+    /// Return the duration of the steps of the last connection and
+    /// handshake.
+    ///
+    /// This helps to diagnose slow pairings. See
+    /// [`HandshakeTimings`](struct.HandshakeTimings.html).
+    pub fn handshake_timings(&self) -> HandshakeTimings {
+        self.signaling.common().stats.timings()
+    }
+
+    /// Encrypt an opaque payload for the peer after the handshake has been
+    /// finished.
+    ///
+    /// Unlike [`encrypt_raw_with_session_keys`](#method.encrypt_raw_with_session_keys),
+    /// the nonce is created by the client. Payloads have their own sequence
+    /// numbers and an inverted cookie, so they are independent of the task
+    /// messages and never reuse their nonces. The returned bytes start with
+    /// the nonce, followed by the ciphertext. Pass them to
+    /// [`decrypt_from_peer`](#method.decrypt_from_peer) on the other side.
+    pub fn encrypt_for_peer(&mut self, data: &[u8]) -> SaltyResult<Vec<u8>> {
+        trace!("Encrypting payload for peer");
+        Ok(self.signaling.encrypt_for_peer(data)?.into_bytes())
+    }
+
+    /// Decrypt a payload that was encrypted by the peer with
+    /// [`encrypt_for_peer`](#method.encrypt_for_peer).
+    ///
+    /// The nonce is validated against the previous payloads, so payloads
+    /// that were replayed or reordered behind a later payload are rejected
+    /// with a protocol error. Task messages may be received in between.
+    pub fn decrypt_from_peer(&mut self, bytes: &[u8]) -> SaltyResult<Vec<u8>> {
+        self.decrypt_box_from_peer(ByteBox::from_slice(bytes)?)
+    }
+
+    /// Decrypt a payload like [`decrypt_from_peer`](#method.decrypt_from_peer),
+    /// without copying it.
+    ///
+    /// The payload is decrypted in place, the returned bytes reuse the
+    /// buffer of the byte box. Use [`ByteBox::from_vec`](struct.ByteBox.html#method.from_vec)
+    /// to parse a received frame without copying it.
+    pub fn decrypt_box_from_peer(&mut self, bbox: ByteBox) -> SaltyResult<Vec<u8>> {
+        trace!("Decrypting payload from peer");
+        Ok(self.signaling.decrypt_from_peer(bbox)?)
+    }
+
+    /// Decrypt a payload that was encrypted by the peer with
+    /// [`encrypt_for_peer`](#method.encrypt_for_peer), restoring the order in
+    /// which the peer sent the payloads.
+    ///
+    /// A payload that arrives before the preceding ones is held back, see
+    /// [`SaltyClientBuilder::with_reorder_window`](struct.SaltyClientBuilder.html#method.with_reorder_window).
+    /// The returned payloads should be passed to the task in order, along
+    /// with the gaps of payloads that were skipped. Replayed payloads and
+    /// payloads that arrive after they have been skipped are rejected with
+    /// a protocol error. Use either this method or
+    /// [`decrypt_from_peer`](#method.decrypt_from_peer), not both.
+    ///
+    /// The peer picks the sequence number of its first payload at random, so
+    /// the first payload that arrives is never held back. If it overtook an
+    /// earlier payload, that payload is rejected when it arrives.
+    pub fn decrypt_from_peer_ordered(&mut self, bytes: &[u8]) -> SaltyResult<Ordered> {
+        trace!("Decrypting payload from peer");
+        let bbox = ByteBox::from_slice(bytes)?;
+        Ok(self.signaling.decrypt_from_peer_ordered(bbox)?)
+    }
+
+    /// Release the payloads held back by
+    /// [`decrypt_from_peer_ordered`](#method.decrypt_from_peer_ordered) that
+    /// have been waiting for longer than the reorder timeout, along with the
+    /// payloads that follow them.
+    ///
+    /// See [`SaltyClientBuilder::with_reorder_timeout`](struct.SaltyClientBuilder.html#method.with_reorder_timeout).
+    pub fn release_reordered(&mut self) -> SaltyResult<Ordered> {
+        Ok(self.signaling.release_reordered()?)
+    }
+
+    /// Return the point in time at which
+    /// [`release_reordered`](#method.release_reordered) must be called
+    /// next, if payloads are held back and a reorder timeout is configured.
+    ///
+    /// The point in time comes from the clock of the client, see
+    /// [`SaltyClientBuilder::with_clock`](struct.SaltyClientBuilder.html#method.with_clock).
+    pub fn reorder_deadline(&self) -> Option<Instant> {
+        self.signaling.common().reorder.deadline()
+    }
+
     /// Encrypt raw bytes using the session keys after the handshake has been finished.
     pub fn encrypt_raw_with_session_keys(&self, data: &[u8], nonce: &[u8]) -> SaltyResult<Vec<u8>> {
-        let sodium_nonce = box_::Nonce::from_slice(nonce)
-            .ok_or(SaltyError::Crypto("Invalid nonce bytes".into()))?;
-        Ok(self.signaling.encrypt_raw_with_session_keys(data, &sodium_nonce)?)
+        Ok(self.signaling.encrypt_raw_with_session_keys(data, &raw_nonce(nonce)?)?)
     }
 
     /// Decrypt raw bytes using the session keys after the handshake has been finished.
     pub fn decrypt_raw_with_session_keys(&self, data: &[u8], nonce: &[u8]) -> SaltyResult<Vec<u8>> {
-        let sodium_nonce = box_::Nonce::from_slice(nonce)
-            .ok_or(SaltyError::Crypto("Invalid nonce bytes".into()))?;
-        Ok(self.signaling.decrypt_raw_with_session_keys(data, &sodium_nonce)?)
+        Ok(self.signaling.decrypt_raw_with_session_keys(data, &raw_nonce(nonce)?)?)
+    }
+}
+
+/// Convert the nonce bytes passed along with raw data.
+fn raw_nonce(nonce: &[u8]) -> SaltyResult<[u8; NONCEBYTES]> {
+    if nonce.len() != NONCEBYTES {
+        return Err(SaltyError::Crypto("Invalid nonce bytes".into()));
+    }
+    let mut bytes = [0u8; NONCEBYTES];
+    bytes.copy_from_slice(nonce);
+    Ok(bytes)
+}
+
+/// Convert an error that occurred while encoding a message for the peer.
+fn encode_error(e: SignalingError) -> SaltyError {
+    match e {
+        SignalingError::Crypto(msg) => SaltyError::Crypto(msg),
+        SignalingError::Decode(msg) => SaltyError::Decode(msg),
+        SignalingError::Protocol(msg) => SaltyError::Protocol(msg),
+        SignalingError::Crash(msg) => SaltyError::Crash(msg),
+        SignalingError::NoPeer => SaltyError::NoPeer,
+        other => SaltyError::Crash(format!("Unexpected signaling error: {}", other)),
     }
 }
 
@@ -352,25 +1837,112 @@ pub enum Event {
     /// connected + authenticated towards the server.
     ServerHandshakeDone(bool),
 
+    /// No initiator is connected to the server yet.
+    ///
+    /// This is only sent by a responder, after `ServerHandshakeDone(false)`
+    /// or after the initiator disconnected. The responder keeps the
+    /// connection open and starts the peer handshake once the server
+    /// announces a new initiator. See
+    /// [`SaltyClientBuilder::with_initiator_timeout`](struct.SaltyClientBuilder.html#method.with_initiator_timeout)
+    /// to limit the wait.
+    WaitingForInitiator,
+
     /// Peer handshake is done.
-    PeerHandshakeDone,
+    ///
+    /// The string contains the name of the negotiated task.
+    PeerHandshakeDone(String),
 
     /// An authenticated peer disconnected from the server.
     Disconnected(u8),
-}
 
+    /// Several responders were in a peer handshake at the same time, and the
+    /// responder with the specified address authenticated first.
+    ///
+    /// The other responders, whose addresses are listed in ascending order,
+    /// were dropped. This is only sent by an initiator, before
+    /// `PeerHandshakeDone`.
+    HandshakeRaceWon(u8, Vec<u8>),
+
+    /// The responder limit was exceeded, so the inactive responder with the
+    /// specified address was dropped to make room.
+    ///
+    /// See [`SaltyClientBuilder::with_max_responders`](struct.SaltyClientBuilder.html#method.with_max_responders).
+    PathAlmostFull(u8),
+
+    /// The responder limit was exceeded and all other responders are active,
+    /// so the new responder with the specified address was dropped.
+    ///
+    /// See [`SaltyClientBuilder::with_max_responders`](struct.SaltyClientBuilder.html#method.with_max_responders).
+    PathFull(u8),
+
+    /// The peer did not acknowledge the last heartbeats.
+    ///
+    /// The number indicates how many consecutive heartbeats were missed.
+    /// See [`SaltyClientBuilder::with_heartbeat`](struct.SaltyClientBuilder.html#method.with_heartbeat).
+    PeerUnresponsive(u32),
+
+    /// The peer acknowledged a heartbeat after being unresponsive.
+    PeerResponsive,
+
+    /// The round trip time of a heartbeat to the peer.
+    ///
+    /// Emitted for every acknowledged heartbeat, see
+    /// [`SaltyClientBuilder::with_heartbeat`](struct.SaltyClientBuilder.html#method.with_heartbeat).
+    PeerLatency(Duration),
+
+    /// A new session with the same peer has been established.
+    ///
+    /// This replaces `PeerHandshakeDone` for handshakes that follow
+    /// [`SaltyClient::renew_session`](struct.SaltyClient.html#method.renew_session).
+    SessionRenewed,
+
+    /// The session keys with the peer have been rotated.
+    ///
+    /// See [`SaltyClientBuilder::with_rekeying`](struct.SaltyClientBuilder.html#method.with_rekeying).
+    Rekeyed,
+
+    /// The handshake via the server with the specified host and port succeeded.
+    ///
+    /// This is only sent by [`connect_with_failover`](fn.connect_with_failover.html).
+    ServerSelected(String, u16),
+
+    /// The server closed the connection in a way that indicates that another
+    /// instance of this client is connected to the same path with the same
+    /// permanent key.
+    ///
+    /// This is only sent if enabled with
+    /// [`SaltyClientBuilder::with_duplicate_detection`](struct.SaltyClientBuilder.html#method.with_duplicate_detection).
+    DuplicateConnection,
+
+    /// A responder used the auth token, so it was replaced with the new
+    /// token contained in the pairing data.
+    ///
+    /// This is only sent if enabled with
+    /// [`SaltyClientBuilder::with_auth_token_rotation`](struct.SaltyClientBuilder.html#method.with_auth_token_rotation).
+    AuthTokenRotated(PairingData),
+
+    /// An outgoing message was dropped because it expired before it could
+    /// be sent, see [`TaskHandle::send_with_ttl`](tasks/struct.TaskHandle.html#method.send_with_ttl).
+    MessageExpired(TaskMessage),
 
-/// Wrapper type for decoded form of WebSocket message types that we want to handle.
-#[derive(Debug)]
-enum WsMessageDecoded {
-    /// We got bytes that we decoded into a ByteBox.
-    ByteBox(ByteBox),
-    /// We got a ping message.
-    Ping(Vec<u8>),
-    /// We got a close message.
-    Close(Option<CloseCode>),
-    /// We got a message type that we want to ignore.
-    Ignore,
+    /// The server sent a message of the specified type, which is not known
+    /// to this implementation, after the server handshake.
+    ///
+    /// The message was ignored. Servers may send message types that were
+    /// added in later versions of the protocol.
+    UnknownServerMessage(String),
+
+    /// The initiator dropped a responder, e.g. because it failed the peer
+    /// handshake.
+    ///
+    /// This is only sent by an initiator. It is sent in addition to
+    /// `HandshakeRaceWon`, `PathAlmostFull` and `PathFull`.
+    ResponderDropped {
+        /// The address of the dropped responder.
+        address: u8,
+        /// Why the responder was dropped.
+        reason: ResponderDropReason,
+    },
 }
 
 
@@ -384,6 +1956,7 @@ pub struct UnboundedChannel<T> {
 
 impl<T> UnboundedChannel<T> {
     /// Create a new `UnboundedChannel`.
+    #[cfg(feature = "connect-tokio")]
     pub(crate) fn new() -> Self {
         let (tx, rx) = mpsc::unbounded::<T>();
         UnboundedChannel { tx, rx }
@@ -401,629 +1974,340 @@ impl<T> UnboundedChannel<T> {
 }
 
 
-/// Connect to the specified SaltyRTC server.
-///
-/// This function returns a future. The future must be run in a Tokio reactor
-/// core for something to actually happen.
-///
-/// The future completes once the server connection is established.
-/// It returns the async websocket client instance.
-pub fn connect(
-    host: &str,
-    port: u16,
-    tls_config: Option<TlsConnector>,
-    handle: &Handle,
-    salty: Arc<RwLock<SaltyClient>>,
-) -> SaltyResult<(
-    impl Future<Item=WsClient, Error=SaltyError>,
-    UnboundedChannel<Event>,
-)> {
-    // Initialize libsodium
-    libsodium_init()?;
-
-    // Parse URL
-    let path = salty.read()
-        .map(|client| HEXLOWER.encode(&client.initiator_pubkey().0))
-        .map_err(|_| SaltyError::Crash("connect: Could not read-lock SaltyClient".into()))?;
-    let url = format!("wss://{}:{}/{}", host, port, path);
-    let ws_url = match Url::parse(&url) {
-        Ok(b) => b,
-        Err(e) => return Err(SaltyError::Decode(format!("Could not parse URL: {}", e))),
-    };
-
-    // Initialize WebSocket client
-    let server = format!("{}:{}", host, port);
-    let future = ClientBuilder::from_url(&ws_url)
-        .add_protocol(SUBPROTOCOL)
-        .async_connect_secure(tls_config, handle)
-        .map_err(move |e: WebSocketError| SaltyError::Network(match e.cause() {
-            Some(cause) => format!("Could not connect to server ({}): {}: {}", server, e, cause),
-            None => format!("Could not connect to server ({}): {}", server, e),
-        }))
-        .and_then(|(client, headers)| {
-            // Verify that the correct subprotocol was chosen
-            trace!("Websocket server headers: {:?}", headers);
-            match headers.get::<WebSocketProtocol>() {
-                Some(proto) if proto.len() == 1 && proto[0] == SUBPROTOCOL => {
-                    Ok(client)
-                },
-                Some(proto) => {
-                    error!("More than one chosen protocol: {:?}", proto);
-                    Err(SaltyError::Protocol("More than one websocket subprotocol chosen by server".into()))
-                },
-                None => {
-                    error!("No protocol chosen by server");
-                    Err(SaltyError::Protocol("Websocket subprotocol not accepted by server".into()))
-                },
-            }
-        })
-        .map(move |client| {
-            debug!("Connected to {}", ws_url);
-            let role = salty
-                .read()
-                .map(|s| s.role().to_string())
-                .unwrap_or_else(|_| "Unknown".to_string());
-            info!("Connected to server as {}", role);
-            client
-        });
-    debug!("Created WS connect future");
-
-    // Create event channel
-    let event_channel = UnboundedChannel::new();
-    debug!("Created event channel");
-
-    Ok((future, event_channel))
-}
-
-/// Decode a websocket `OwnedMessage` and wrap it into a `WsMessageDecoded`.
-fn decode_ws_message(msg: OwnedMessage) -> SaltyResult<WsMessageDecoded> {
-    let decoded = match msg {
-        OwnedMessage::Binary(bytes) => {
-            debug!("--> Incoming binary message ({} bytes)", bytes.len());
-
-            // Parse into ByteBox
-            let bbox = ByteBox::from_slice(&bytes)
-                .map_err(|e| SaltyError::Protocol(e.to_string()))?;
-            trace!("ByteBox: {:?}", bbox);
-
-            WsMessageDecoded::ByteBox(bbox)
-        },
-        OwnedMessage::Ping(payload) => {
-            debug!("--> Incoming WS ping message");
-            WsMessageDecoded::Ping(payload)
-        },
-        OwnedMessage::Pong(_) => {
-            debug!("--> Incoming WS pong message (ignored)");
-            WsMessageDecoded::Ignore
-        },
-        OwnedMessage::Close(close_data) => {
-            debug!("--> Incoming WS close message");
-            match close_data {
-                Some(data) => {
-                    let close_code = CloseCode::from_number(data.status_code);
-                    if data.reason.is_empty() {
-                        info!("Server closed connection with close code {}", close_code);
-                    } else {
-                        info!("Server closed connection with close code {} ({})", close_code, data.reason);
-                    }
-                    WsMessageDecoded::Close(Some(close_code))
-                }
-                None => {
-                    info!("Server closed connection without close code");
-                    WsMessageDecoded::Close(None)
-                }
-            }
-        },
-        OwnedMessage::Text(payload) => {
-            warn!("Skipping text message: {:?}", payload);
-            WsMessageDecoded::Ignore
-        },
-    };
-    Ok(decoded)
-}
-
-/// An action in our pipeline.
-///
-/// This is used to enable early-return inside the pipeline. If a step returns a `Future`,
-/// it should be passed directly to the `loop_fn`.
-enum PipelineAction {
-    /// We got a ByteBox to handle.
-    ByteBox((WsClient, ByteBox)),
-    /// Immediately pass on this future in the next step.
-    Future(BoxedFuture<Loop<WsClient, WsClient>, SaltyError>),
-}
-
-/// Preprocess a `WsMessageDecoded`.
-///
-/// Here pings and ignored messages are handled.
-fn preprocess_ws_message((decoded, client): (WsMessageDecoded, WsClient)) -> SaltyResult<PipelineAction> {
-    // Unwrap byte box, handle ping messages
-    let bbox = match decoded {
-        WsMessageDecoded::ByteBox(bbox) => bbox,
-        WsMessageDecoded::Ping(payload) => {
-            let pong = OwnedMessage::Pong(payload);
-            let outbox = stream::iter_ok::<_, WebSocketError>(vec![pong]);
-            let future = send_all::new(client, outbox)
-                .map_err(move |e| SaltyError::Network(format!("Could not send pong message: {}", e)))
-                .map(|(client, _)| {
-                    debug!("Sent pong message");
-                    Loop::Continue(client)
-                });
-            let action = PipelineAction::Future(boxed!(future));
-            return Ok(action);
-        },
-        WsMessageDecoded::Close(_code) => {
-            let future = future::ok(Loop::Break(client));
-            let action = PipelineAction::Future(boxed!(future));
-            return Ok(action);
-        },
-        WsMessageDecoded::Ignore => {
-            debug!("Ignoring message");
-            let action = PipelineAction::Future(boxed!(future::ok(Loop::Continue(client))));
-            return Ok(action);
-        },
-    };
-    Ok(PipelineAction::ByteBox((client, bbox)))
-}
-
-/// Do the server and peer handshake.
-///
-/// This function returns a future. The future must be run in a Tokio reactor
-/// core for something to actually happen.
-///
-/// The future completes once the peer handshake is done, or if an error occurs.
-/// It returns the async websocket client instance.
-pub fn do_handshake(
-    client: WsClient,
-    salty: Arc<RwLock<SaltyClient>>,
-    event_tx: mpsc::UnboundedSender<Event>,
-    timeout: Option<Duration>,
-) -> impl Future<Item=WsClient, Error=SaltyError> {
-    // Main loop
-    let main_loop = future::loop_fn(client, move |client| {
-
-        let salty = Arc::clone(&salty);
-
-        // Take the next incoming message
-        let event_tx = event_tx.clone();
-        client.into_future()
-
-            // Map errors to our custom error type
-            .map_err(|(e, _)| SaltyError::Network(format!("Could not receive message from server: {}", e)))
-
-            // Process incoming messages and convert them to a `WsMessageDecoded`.
-            .and_then(|(msg_option, client)| {
-                let decoded = match msg_option {
-                    Some(msg) => decode_ws_message(msg),
-                    None => return Err(SaltyError::Network("Server message stream ended without close message".into())),
-                };
-                decoded.map(|decoded| (decoded, client))
-            })
-
-            // Preprocess messages, handle things like ping/pong and ignored messages
-            .and_then(preprocess_ws_message)
-
-            // Process received signaling message
-            .and_then(move |pipeline_action| {
-                let (client, bbox) = match pipeline_action {
-                    PipelineAction::ByteBox(x) => x,
-                    PipelineAction::Future(f) => return f,
-                };
-
-                // Handle message bytes
-                let handle_actions = match salty.write() {
-                    Ok(mut s) => match s.handle_message(bbox) {
-                        Ok(actions) => actions,
-                        Err(e) => return boxed!(future::err(e.into())),
-                    },
-                    Err(e) => return boxed!(future::err(SaltyError::Crash(
-                        format!("do_handshake: Could not write-lock SaltyClient: {}", e)
-                    ))),
-                };
-
-                // Extract messages that should be sent back to the server
-                let mut messages = vec![];
-                let mut handshake_done = false;
-                let mut late_error: Option<SaltyError> = None;
-                for action in handle_actions {
-                    match action {
-                        HandleAction::Reply(bbox) => messages.push(OwnedMessage::Binary(bbox.into_bytes())),
-                        HandleAction::HandshakeDone => {
-                            handshake_done = true;
-                            if event_tx.unbounded_send(Event::PeerHandshakeDone).is_err() {
-                                return boxed!(future::err(
-                                    SaltyError::Crash("Could not send event through channel".into())
-                                ));
-                            }
-                        },
-                        HandleAction::TaskMessage(_) => return boxed!(future::err(
-                            SaltyError::Crash("Received task message during handshake".into())
-                        )),
-                        HandleAction::Event(e) => {
-                            // Notify the user about event
-                            if event_tx.unbounded_send(e).is_err() {
-                                return boxed!(future::err(
-                                    SaltyError::Crash("Could not send event through channel".into())
-                                ));
-                            }
-                        },
-                        HandleAction::HandshakeError(e) => {
-                            if late_error.is_some() {
-                                error!("Dropping error because another error happened previously: {}", e);
-                            } else {
-                                late_error = Some(e);
-                            }
-                        },
-                    }
-                }
-
-                macro_rules! loop_action {
-                    ($client:expr) => {
-                        if handshake_done {
-                            Loop::Break($client)
-                        } else {
-                            Loop::Continue($client)
-                        }
-                    }
-                };
-
-                // If there are enqueued messages, send them
-                if messages.is_empty() {
-                    boxed!(future::ok(loop_action!(client)))
-                } else {
-                    for message in &messages {
-                        debug!("Sending {} bytes", message.size());
-                    }
-                    let outbox = stream::iter_ok::<_, WebSocketError>(messages);
-                    let future = send_all::new(client, outbox)
-                        .map_err(move |e| SaltyError::Network(format!("Could not send message: {}", e)))
-                        .and_then(move |(client, _)| {
-                            trace!("Sent all messages");
-                            match late_error {
-                                Some(e) => future::err(e),
-                                None => future::ok(loop_action!(client)),
-                            }
-                        });
-                    boxed!(future)
-                }
-            })
-    });
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "connect-tokio")]
+    use crate::tasks::Task;
+    use crate::test_helpers::DummyTask;
+
+    use super::*;
+
+    fn responder(duplicate_detection: bool) -> SaltyClient {
+        SaltyClient::build(KeyPair::new())
+            .add_task(Box::new(DummyTask::new(1)))
+            .with_duplicate_detection(duplicate_detection)
+            .responder(*KeyPair::new().public_key(), AuthToken::new())
+            .unwrap()
+    }
 
-    let timeout_duration = match timeout {
-        Some(duration) => duration,
-        None => return boxed!(main_loop),
-    };
+    #[test]
+    fn duplicate_connection_detected() {
+        let mut salty = responder(true);
+        assert_eq!(salty.closed(Some(CloseCode::ProtocolError)), None);
+        assert_eq!(salty.closed(Some(CloseCode::DroppedByInitiator)), Some(Event::DuplicateConnection));
+        assert_eq!(salty.connection_state(), Some(ConnectionState::Closed(Some(CloseCode::DroppedByInitiator))));
+    }
 
-    let timer = Timer::default();
-    boxed!(timer.timeout(main_loop, timeout_duration))
-}
+    #[test]
+    fn duplicate_detection_disabled() {
+        assert_eq!(responder(false).closed(Some(CloseCode::DroppedByInitiator)), None);
 
-/// Start the task loop.
-///
-/// Only call this function once you have finished the handshake!
-#[cfg_attr(feature="cargo-clippy", allow(needless_pass_by_value))]
-pub fn task_loop(
-    client: WsClient,
-    salty: Arc<RwLock<SaltyClient>>,
-    event_tx: mpsc::UnboundedSender<Event>,
-) -> Result<(
-    Arc<Mutex<BoxedTask>>,
-    impl Future<Item=(), Error=SaltyError>,
-), SaltyError> {
-    let task_name = salty
-        .read()
-        .ok()
-        .and_then(|salty| salty.task())
-        .and_then(|task| match task.lock() {
-            Ok(t) => Some(t.name()),
-            Err(_) => None,
-        })
-        .unwrap_or_else(|| "Unknown".into());
-    info!("Starting task loop for task {}", task_name);
-
-    let salty = Arc::clone(&salty);
-
-    // Split websocket connection into sink/stream
-    let (ws_sink, ws_stream) = client.split();
-
-    // Create communication channels
-    let (outgoing_tx, outgoing_rx) = mpsc::unbounded::<TaskMessage>();
-    let (raw_outgoing_tx, raw_outgoing_rx) = mpsc::unbounded::<OwnedMessage>();
-    let (incoming_tx, incoming_rx) = mpsc::unbounded::<TaskMessage>();
-    let (disconnect_tx, disconnect_rx) = oneshot::channel::<Option<CloseCode>>();
-
-    // Stream future for processing incoming WebSocket messages
-    let reader = ws_stream
-
-        // Map errors to our custom error type
-        // TODO: Take a look at `sink_from_err`
-        .map_err(|e| SaltyError::Network(format!("Could not receive message from server: {}", e)))
-
-        // Decode messages
-        .and_then(decode_ws_message)
-
-        // Wrap errors in a result type
-        .map_err(Err)
-
-        // Handle each incoming message.
-        //
-        // The closure passed to `for_each` must return:
-        //
-        // * `future::ok(())` to continue processing the stream
-        // * `future::err(Ok(()))` to stop the loop without an error
-        // * `future::err(Err(_))` to stop the loop with an error
-        .for_each({
-            let salty = Arc::clone(&salty);
-            let raw_outgoing_tx = raw_outgoing_tx.clone();
-            move |msg: WsMessageDecoded| {
-                let raw_outgoing_tx = raw_outgoing_tx.clone();
-                match msg {
-                    WsMessageDecoded::ByteBox(bbox) => {
-                        // Handle message bytes
-                        let handle_actions = match salty.write() {
-                            Ok(mut s) => match s.handle_message(bbox) {
-                                Ok(actions) => actions,
-                                Err(e) => return boxed!(future::err(Err(e.into()))),
-                            },
-                            Err(e) => return boxed!(future::err(Err(
-                                SaltyError::Crash(format!("task_loop/reader: Could not write-lock SaltyClient: {}", e))
-                            ))),
-                        };
-
-                        // Extract messages that should be sent back to the server
-                        let mut out_messages: Vec<OwnedMessage> = vec![];
-                        let mut in_messages: Vec<TaskMessage> = vec![];
-                        let mut close_stream = false;
-                        for action in handle_actions {
-                            info!("Action: {:?}", action);
-                            match action {
-                                HandleAction::Reply(bbox) => out_messages.push(OwnedMessage::Binary(bbox.into_bytes())),
-                                HandleAction::TaskMessage(msg) => {
-                                    if let TaskMessage::Close(_) = msg {
-                                        close_stream = true;
-                                    }
-
-                                    // Forward message to user
-                                    in_messages.push(msg);
-                                },
-                                HandleAction::Event(e) => {
-                                    // Notify the user about event
-                                    match event_tx.unbounded_send(e) {
-                                        Ok(_) => {},
-                                        Err(_) => return boxed!(future::err(Err(
-                                            SaltyError::Crash("Could not send event through channel".into())
-                                        ))),
-                                    }
-                                },
-                                HandleAction::HandshakeDone => return boxed!(future::err(Err(
-                                    SaltyError::Crash("Got HandleAction::HandshakeDone in task loop".into())
-                                ))),
-                                HandleAction::HandshakeError(_) => return boxed!(future::err(Err(
-                                    SaltyError::Crash("Got HandleAction::HandshakeError in task loop".into())
-                                ))),
-                            }
-                        }
-
-                        // Handle outgoing queued messages
-                        let out_future = if out_messages.is_empty() {
-                            boxed!(future::ok(()))
-                        } else {
-                            let msg_count = out_messages.len();
-                            let outbox = stream::iter_ok::<_, Result<(), SaltyError>>(out_messages);
-                            let future = raw_outgoing_tx
-                                .sink_map_err(|e| Err(SaltyError::Network(format!("Sink error: {}", e))))
-                                .send_all(outbox)
-                                .map(move |_| debug!("Sent {} messages", msg_count));
-                            boxed!(future)
-                        };
-
-                        // Handle incoming queued messages
-                        let in_future = if in_messages.is_empty() {
-                            boxed!(future::ok(()))
-                        } else {
-                            let msg_count = in_messages.len();
-                            let inbox = stream::iter_ok::<_, Result<(), SaltyError>>(in_messages);
-                            let future = incoming_tx
-                                .clone()
-                                .sink_map_err(|e| Err(SaltyError::Crash(format!("Channel error: {}", e))))
-                                .send_all(inbox)
-                                .map(move |_| debug!("Received {} task messages", msg_count));
-                            boxed!(future)
-                        };
-
-                        boxed!(
-                            out_future
-                                .join(in_future)
-                                .and_then(move |_| if close_stream {
-                                    // Stop processing stream
-                                    Err(Ok(()))
-                                } else {
-                                    // Continue processing stream
-                                    Ok(())
-                                })
-                        )
-                    },
-                    WsMessageDecoded::Ping(payload) => {
-                        let pong = OwnedMessage::Pong(payload);
-                        let future = raw_outgoing_tx
-                            .send(pong)
-                            .map(|_| debug!("<-- Enqueuing pong message"))
-                            .map_err(|e| Err(SaltyError::Network(format!("Could not enqueue pong message: {}", e))));
-                        boxed!(future)
-                    },
-                    WsMessageDecoded::Close(_) | WsMessageDecoded::Ignore => boxed!(future::ok(())),
-                }
-            }
-        })
+        let mut initiator = SaltyClient::build(KeyPair::new())
+            .add_task(Box::new(DummyTask::new(1)))
+            .with_duplicate_detection(true)
+            .initiator()
+            .unwrap();
+        assert_eq!(initiator.closed(Some(CloseCode::DroppedByInitiator)), None);
+    }
 
-        .or_else(|res| match res {
-            Ok(_) => boxed!(future::ok(())),
-            Err(e) => boxed!(future::err(e))
-        })
+    /// The task is closed once with the close code of the connection, and
+    /// the connection is only closed once the transport is gone.
+    #[cfg(feature = "connect-tokio")]
+    #[test]
+    fn tear_down_closes_task_once() {
+        let mut salty = responder(false);
+        let task: BoxedTask = Box::new(DummyTask::new(1));
+        salty.signaling.common_mut().task = Some(Arc::new(Mutex::new(task)));
+        salty.lifecycle.started();
+        salty.state.closing(CloseReason::remote(Some(CloseCode::ProtocolError), "Closed by the peer"));
+
+        salty.tear_down(false);
+        salty.tear_down(false);
+        assert_eq!(salty.connection_state(), Some(ConnectionState::Closing));
+        salty.tear_down(true);
+        salty.tear_down(true);
+        assert_eq!(salty.connection_state(), Some(ConnectionState::Closed(Some(CloseCode::ProtocolError))));
+
+        let task = salty.task().unwrap();
+        let task = task.lock().unwrap();
+        assert_eq!((&**task as &dyn Task).downcast_ref::<DummyTask>().unwrap().closed, vec![CloseCode::ProtocolError]);
+    }
 
-        .select(
-            disconnect_rx
-                .and_then({
-                    let outgoing_tx = outgoing_tx.clone();
-                    move |reason_opt: Option<CloseCode>| {
-                        info!("Disconnecting");
-
-                        // Send close message
-                        outgoing_tx
-                            .send(TaskMessage::Close(reason_opt.unwrap_or(CloseCode::WsGoingAway)))
-                            .map(|_| ())
-                            .or_else(|e| {
-                                warn!("Could not enqueue close message: {}", e);
-                                future::ok(())
-                            })
-                    }
-                })
-                .or_else(|_| {
-                    warn!("Waiting for disconnect_rx failed");
-                    future::ok(())
-                })
-        )
-
-        .map(|_| debug!("† Reader future done"))
-        .map_err(|(e, _next)| e);
-
-    // Transform future that sends values from the outgoing channel to the raw outgoing channel
-    let transformer = outgoing_rx
-
-        // Wrap errors in result
-        .map_err(|_| Err(()))
-
-        // Encode and encrypt values.
-        .and_then({
-            let salty = Arc::clone(&salty);
-            move |msg: TaskMessage| {
-                trace!("Transforming outgoing message: {:?}", msg);
-
-                // Get reference to SaltyClient
-                // TODO: Can we do something about the errors here?
-                let mut salty_mut = salty.write().map_err(|_| Err(()))?;
-
-                // When we receive a `Value` message, simply send it as-is.
-                // But when we receive a `Close` message, also insert a WebSocket close message.
-                match msg {
-                    TaskMessage::Value(map) => {
-                        // Create message
-                        let val = Value::Map(
-                            map
-                                .into_iter()
-                                .map(|(k, v)| (Value::from(k), v))
-                                .collect()
-                        );
-                        // Encrypt message
-                        salty_mut
-                            .encrypt_task_message(val)
-                            .map(|bytes| {
-                                debug!("<-- Enqueuing task message to peer");
-                                stream::iter_result::<_, OwnedMessage, Result<(), ()>>(
-                                    vec![
-                                        Ok(OwnedMessage::Binary(bytes))
-                                    ]
-                                )
-                            })
-                            .map_err(|e| {
-                                warn!("Could not encrypt task message: {}", e);
-                                Err(())
-                            })
-                    },
-                    TaskMessage::Application(data) => {
-                        let mut map = vec![];
-                        map.push((Value::String("type".into()), Value::String("application".into())));
-                        map.push((Value::String("data".into()), data));
-                        let val = Value::Map(map);
-                        salty_mut
-                            .encrypt_task_message(val)
-                            .map(|bytes| {
-                                debug!("<-- Enqueuing application message to peer");
-                                stream::iter_result::<_, OwnedMessage, Result<(), ()>>(
-                                    vec![
-                                        Ok(OwnedMessage::Binary(bytes))
-                                    ]
-                                )
-                            })
-                            .map_err(|e| {
-                                warn!("Could not encrypt task message: {}", e);
-                                Err(())
-                            })
-                    },
-                    TaskMessage::Close(reason) => {
-                        // Create and encrypt SaltyRTC close message,
-                        // followed by a WebSocket close message
-                        salty_mut
-                            .encrypt_close_message(reason)
-                            .map(|bytes| {
-                                debug!("<-- Enqueuing SaltyRTC close message to peer");
-                                debug!("<-- Enqueuing WebSocket close message to peer");
-                                stream::iter_result::<_, OwnedMessage, Result<(), ()>>(
-                                    vec![
-                                        Ok(OwnedMessage::Binary(bytes)),
-                                        Ok(OwnedMessage::Close(Some(CloseData {
-                                            status_code: reason.as_number(),
-                                            reason: reason.to_string(),
-                                        }))),
-                                        Err(Ok(())), // Terminate transformer future
-                                    ]
-                                )
-                            })
-                            .map_err(|e| {
-                                warn!("Could not encrypt SaltyRTC close message: {}", e);
-                                Err(())
-                            })
-                    },
-                }
-            }
-        })
+    /// A task that closed the connection itself is not closed again.
+    #[cfg(feature = "connect-tokio")]
+    #[test]
+    fn tear_down_after_task_closed() {
+        let mut salty = responder(false);
+        let task: BoxedTask = Box::new(DummyTask::new(1));
+        salty.signaling.common_mut().task = Some(Arc::new(Mutex::new(task)));
+        salty.lifecycle.started();
+        salty.lifecycle.closing_by_task();
+        salty.tear_down(true);
+
+        let task = salty.task().unwrap();
+        let task = task.lock().unwrap();
+        assert!((&**task as &dyn Task).downcast_ref::<DummyTask>().unwrap().closed.is_empty());
+        assert_eq!(salty.connection_state(), Some(ConnectionState::Closed(None)));
+    }
 
-        .flatten()
+    /// Only sessions with an authenticated peer can be suspended and resumed.
+    #[test]
+    fn suspend_before_handshake() {
+        let mut salty = responder(false);
+        assert_eq!(salty.suspend(), Err(SaltyError::NoPeer));
+        assert_eq!(salty.resume(), Err(SaltyError::NoPeer));
+        assert!(!salty.is_suspended());
+    }
 
-        // Forward to raw queue
-        .forward(raw_outgoing_tx.sink_map_err(|_| Err(())))
+    /// No heartbeats are sent while the session is suspended.
+    #[test]
+    fn suspended_heartbeat() {
+        let mut salty = responder(false);
+        assert!(salty.heartbeat_tick(3).is_err());
+        salty.suspended_at = Some(salty.now());
+        assert!(salty.is_suspended());
+        assert!(salty.heartbeat_tick(3).unwrap().is_empty());
+    }
 
-        // Ignore stream/sink
-        .map(|(_, _)| debug!("† Transformer future done"))
+    /// The handshake timeout is replaced by the heartbeat timer once the
+    /// handshake is done.
+    #[test]
+    #[cfg(feature = "connect-tokio")]
+    fn handshake_and_heartbeat_timers() {
+        let clock = Arc::new(crate::clock::ManualClock::new());
+        let start = clock.now();
+        let mut salty = SaltyClient::build(KeyPair::new())
+            .add_task(Box::new(DummyTask::new(1)))
+            .with_clock(clock.clone())
+            .with_heartbeat(Duration::from_secs(5), 3)
+            .responder(*KeyPair::new().public_key(), AuthToken::new())
+            .unwrap();
+
+        salty.start_handshake_timeout(Duration::from_secs(30));
+        assert_eq!(salty.next_timeout(), Some(start + Duration::from_secs(30)));
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(salty.handle_timeouts(clock.now()), Err(SignalingError::HandshakeTimeout));
+        assert_eq!(salty.next_timeout(), None);
+
+        salty.start_handshake_timeout(Duration::from_secs(30));
+        let actions = salty.handle_actions(HandleAction::HandshakeDone.into());
+        assert_eq!(actions.into_vec(), vec![HandleAction::HandshakeDone]);
+        assert_eq!(salty.next_timeout(), Some(clock.now() + Duration::from_secs(5)));
+    }
 
-        // Flatten errors
-        .or_else(|e| e.map_err(|_| SaltyError::Crash("Transformer future error (TODO)".into())));
+    #[test]
+    fn role_from_pairing_info() {
+        let keypair = KeyPair::new();
+        let pubkey = *keypair.public_key();
+        let token = AuthToken::new();
+        let build = |keypair| SaltyClient::build(keypair).add_task(Box::new(DummyTask::new(1)));
+
+        let info = PairingInfo::new("example.org", 8765, pubkey, Some(token.clone()));
+        let initiator = build(keypair).from_pairing_info(&info).unwrap();
+        assert_eq!(initiator.role(), Role::Initiator);
+        assert_eq!(initiator.auth_token(), Some(&token));
+        assert_eq!(initiator.servers, vec![("example.org".to_string(), 8765)]);
+
+        let responder = build(KeyPair::new()).from_pairing_info(&info).unwrap();
+        assert_eq!(responder.role(), Role::Responder);
+        assert_eq!(responder.auth_token(), Some(&token));
+
+        let info = PairingInfo::new("example.org", 8765, pubkey, None);
+        let responder = build(KeyPair::new()).from_pairing_info(&info).unwrap();
+        assert_eq!(responder.role(), Role::Responder);
+        assert_eq!(responder.auth_token(), None);
+        assert_eq!(responder.signaling.initiator_pubkey(), &pubkey);
+    }
 
-    // Sink future for sending messages from the raw outgoing channel through the WebSocket
-    let writer = raw_outgoing_rx
+    #[test]
+    fn build_with_trusted_peer() {
+        let peer_key = *KeyPair::new().public_key();
+        let server_key = *KeyPair::new().public_key();
+        let build = || SaltyClient::build(KeyPair::new())
+            .add_task(Box::new(DummyTask::new(1)))
+            .add_task(Box::new(DummyTask::new(2)));
+
+        let salty = build()
+            .with_trusted_peer(TrustedPeer {
+                permanent_key: peer_key,
+                role: Role::Responder,
+                server_permanent_key: Some(server_key),
+                task: Some(DummyTask::name_for(2)),
+            })
+            .unwrap();
+        assert_eq!(salty.role(), Role::Responder);
+        assert_eq!(salty.auth_token(), None);
+        assert_eq!(salty.signaling.initiator_pubkey(), &peer_key);
+        assert_eq!(salty.signaling.server().permanent_key, Some(server_key));
+        assert_eq!(salty.signaling.common().tasks.as_ref().unwrap().0[0].name(), DummyTask::name_for(2));
+        assert_eq!(salty.peer_permanent_key(), None);
+        assert_eq!(salty.trust_peer(), None);
+
+        let salty = build()
+            .with_trusted_peer(TrustedPeer { permanent_key: peer_key, role: Role::Initiator, server_permanent_key: None, task: None })
+            .unwrap();
+        assert_eq!(salty.role(), Role::Initiator);
+        assert_eq!(salty.auth_token(), None);
+        assert_eq!(salty.signaling.server().permanent_key, None);
+    }
 
-        .map_err(|_| SaltyError::Crash("TODO receiver error".to_string()))
+    #[cfg(feature = "resumption")]
+    #[test]
+    fn resume_with_trusted_key() {
+        let keypair = KeyPair::new();
+        let peer_key = *KeyPair::new().public_key();
+        let server_key = *KeyPair::new().public_key();
+        let token = TrustedPeer {
+            permanent_key: peer_key,
+            role: Role::Responder,
+            server_permanent_key: Some(server_key),
+            task: Some(DummyTask::name_for(2)),
+        }.to_encrypted_bytes(&keypair).unwrap();
+
+        let salty = SaltyClient::build(KeyPair::from_private_key(keypair.private_key().clone()))
+            .add_task(Box::new(DummyTask::new(1)))
+            .add_task(Box::new(DummyTask::new(2)))
+            .resume(&token)
+            .unwrap();
+        assert_eq!(salty.role(), Role::Responder);
+        assert_eq!(salty.auth_token(), None);
+        assert_eq!(salty.signaling.initiator_pubkey(), &peer_key);
+        assert_eq!(salty.signaling.server().permanent_key, Some(server_key));
+        assert_eq!(salty.signaling.common().tasks.as_ref().unwrap().0[0].name(), DummyTask::name_for(2));
+
+        let result = SaltyClient::build(KeyPair::new())
+            .add_task(Box::new(DummyTask::new(1)))
+            .resume(&token);
+        assert_eq!(result.err(), Some(BuilderError::Resumption(ResumptionError::Decrypt)));
+    }
 
-        // Forward all messages from the channel receiver to the sink
-        .forward(
-            ws_sink.sink_map_err(|e| SaltyError::Crash(format!("TODO sink error: {:?}", e)))
-        )
+    #[cfg(feature = "resumption")]
+    #[test]
+    fn export_resumption_requires_clean_close() {
+        let mut salty = responder(false);
+        assert_eq!(
+            salty.export_resumption(),
+            Err(ResumptionError::NotResumable("Session did not end cleanly".into())),
+        );
+        salty.closed(Some(CloseCode::WsClosingNormal));
+        assert_eq!(
+            salty.export_resumption(),
+            Err(ResumptionError::NotResumable("Peer handshake is not done".into())),
+        );
+    }
 
-        // Ignore sink
-        .map(|_| debug!("† Writer future done"));
+    #[cfg(feature = "server-session")]
+    fn server_session(address: Option<u8>) -> (KeyPair, ServerSession) {
+        let server = KeyPair::new();
+        let session = ServerSession {
+            session_key: *server.public_key(),
+            our_cookie: [1; 16],
+            server_cookie: [2; 16],
+            csn: PeerSequenceNumbers { incoming: 100, outgoing: 200 },
+            address,
+        };
+        (server, session)
+    }
 
-    // The task loop is finished when all futures are resolved.
-    let task_loop = boxed!(
-        future::ok(())
-        .and_then(|_| reader.join(transformer).join(writer).map(|_| ()))
-        .and_then(|_| { info!("† Task loop future done"); future::ok(()) })
-    );
+    #[cfg(feature = "server-session")]
+    #[test]
+    fn restore_server_session() {
+        use crate::boxes::OpenBox;
+        use crate::protocol::cookie::Cookie;
+        use crate::protocol::csn::CombinedSequenceSnapshot;
+        use crate::protocol::messages::{Message, NewResponder};
+        use crate::protocol::Nonce;
+
+        let (server, session) = server_session(Some(1));
+        let ks = KeyPair::new();
+        let pk = *ks.public_key();
+        let mut salty = SaltyClient::build(ks)
+            .add_task(Box::new(DummyTask::new(1)))
+            .with_server_session(session.clone())
+            .initiator()
+            .unwrap();
+        assert_eq!(salty.signaling.common().signaling_state(), SignalingState::PeerHandshake);
+        assert_eq!(salty.server_session(), Some(session.clone()));
+
+        // The server continues with the next CSN
+        let nonce = Nonce::new(
+            Cookie::new(session.server_cookie), Address(0), Address(1), CombinedSequenceSnapshot::new(0, 101),
+        );
+        let msg = NewResponder::new(Address(2)).into_message();
+        let bbox = OpenBox::<Message>::new(msg, nonce).encrypt(&server, &pk);
+        let _ = salty.handle_message(bbox).unwrap();
+        let restored = salty.server_session().unwrap();
+        assert_eq!(restored.csn, PeerSequenceNumbers { incoming: 101, outgoing: 200 });
+        assert_eq!(restored.address, Some(1));
+    }
 
-    // Get reference to task
-    let task = match salty.write() {
-        Ok(salty) => salty
-            .task()
-            .ok_or_else(|| SaltyError::Crash("Task not set".into()))?,
-        Err(e) => return Err(
-            SaltyError::Crash(format!("task_loop/task: Could not write-lock SaltyClient: {}", e))
-        ),
-    };
+    #[cfg(feature = "server-session")]
+    #[test]
+    fn restore_server_session_without_address() {
+        let (_, session) = server_session(None);
+        let salty = SaltyClient::build(KeyPair::new())
+            .add_task(Box::new(DummyTask::new(1)))
+            .with_server_session(session.clone())
+            .responder(*KeyPair::new().public_key(), AuthToken::new())
+            .unwrap();
+        assert_eq!(salty.signaling.common().signaling_state(), SignalingState::ServerHandshake);
+        assert_eq!(salty.server_session(), Some(session));
+    }
 
-    // Notify task that it can now take over
-    task.lock()
-        .map_err(|e| SaltyError::Crash(format!("Could not lock task mutex: {}", e)))?
-        .start(outgoing_tx, incoming_rx, disconnect_tx);
+    #[cfg(feature = "server-session")]
+    #[test]
+    fn restore_invalid_server_session() {
+        let build = |session| SaltyClient::build(KeyPair::new())
+            .add_task(Box::new(DummyTask::new(1)))
+            .with_server_session(session)
+            .initiator()
+            .err();
+
+        let (_, session) = server_session(Some(2));
+        assert_eq!(
+            build(session),
+            Some(BuilderError::InvalidServerSession("Invalid message: Initiator cannot use address 2".into())),
+        );
+        let (_, mut session) = server_session(Some(1));
+        session.server_cookie = session.our_cookie;
+        assert_eq!(
+            build(session),
+            Some(BuilderError::InvalidServerSession(
+                "Invalid message: Our cookie and the server cookie are identical".into(),
+            )),
+        );
+        let (_, mut session) = server_session(None);
+        session.csn.incoming = 1 << 48;
+        assert_eq!(
+            build(session),
+            Some(BuilderError::InvalidServerSession(
+                "Invalid message: Combined sequence number 281474976710656 is too large".into(),
+            )),
+        );
+    }
 
-    // Return reference to task and the task loop future
-    Ok((task, task_loop))
+    /// Once the outgoing sequence numbers are exhausted, the session is
+    /// renewed and the task message is queued.
+    #[cfg(feature = "connect-tokio")]
+    #[test]
+    fn renew_session_on_csn_overflow() {
+        use crate::protocol::csn::CombinedSequence;
+        use crate::protocol::tests::simulated::{create_peers, handshake, TestServer};
+
+        let (mut initiator, mut responder) = create_peers(0x5a17, true);
+        handshake(&mut TestServer::new(1), &mut initiator, &mut responder, true, false);
+        initiator.responder.as_ref().unwrap().csn_pair.write().unwrap().ours = CombinedSequence::new(
+            ::std::u16::MAX, ::std::u32::MAX,
+        );
+        let mut salty = SaltyClient::build(KeyPair::new())
+            .add_task(Box::new(DummyTask::new(1)))
+            .with_manual_session_renewal(true)
+            .initiator()
+            .unwrap();
+        salty.signaling = Box::new(initiator);
+
+        assert_eq!(salty.encrypt_task_message_or_renew(Value::from(1)), Ok(None));
+        assert_eq!(salty.signaling.common().renewal_queue, [Value::from(1)]);
+        assert_eq!(salty.signaling.common().signaling_state(), SignalingState::ServerHandshake);
+    }
 }
@@ -0,0 +1,137 @@
+//! Teardown of a task loop.
+//!
+//! A task loop may end for several reasons at once: the task closes the
+//! connection while the peer sends a close message, or the server closes the
+//! WebSocket while a network error stops the writer. The
+//! [`Lifecycle`](struct.Lifecycle.html) makes sure that every teardown step
+//! runs exactly once per started task, in this order:
+//!
+//! 1. The task is closed, unless it closed the connection itself.
+//! 2. The signaling stops its timers.
+//! 3. The connection is marked as closed once the transport is gone.
+
+
+/// A step of the teardown, named after the part that is closed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Step {
+    /// Call the close hook of the task.
+    Task,
+    /// Shut down the signaling.
+    Signaling,
+    /// The WebSocket connection is gone.
+    Transport,
+}
+
+
+/// How far the teardown of the current task loop has progressed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Stage {
+    /// No task has been started.
+    Idle,
+    /// The task is running.
+    Running,
+    /// The task closed the connection, so it does not need to be closed.
+    ClosingByTask,
+    /// The task has been closed.
+    TaskClosed,
+    /// The signaling has been shut down.
+    SignalingClosed,
+    /// The teardown is complete.
+    Closed,
+}
+
+
+/// Tracks the teardown steps that have already run.
+#[derive(Debug)]
+pub(crate) struct Lifecycle {
+    stage: Stage,
+}
+
+impl Default for Lifecycle {
+    fn default() -> Self {
+        Lifecycle { stage: Stage::Idle }
+    }
+}
+
+impl Lifecycle {
+    /// The task has been started with new channels.
+    pub(crate) fn started(&mut self) {
+        self.stage = Stage::Running;
+    }
+
+    /// The task closed the connection itself.
+    pub(crate) fn closing_by_task(&mut self) {
+        if self.stage == Stage::Running {
+            self.stage = Stage::ClosingByTask;
+        }
+    }
+
+    /// Return the next teardown step that must run, if any.
+    ///
+    /// The connection is only marked as closed once `transport_closed` is
+    /// set. Steps that have run before are not returned again.
+    pub(crate) fn next_step(&mut self, transport_closed: bool) -> Option<Step> {
+        let (stage, step) = match self.stage {
+            Stage::Idle | Stage::Closed => return None,
+            Stage::Running => (Stage::TaskClosed, Step::Task),
+            Stage::ClosingByTask | Stage::TaskClosed => (Stage::SignalingClosed, Step::Signaling),
+            Stage::SignalingClosed if transport_closed => (Stage::Closed, Step::Transport),
+            Stage::SignalingClosed => return None,
+        };
+        self.stage = stage;
+        Some(step)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn steps(lifecycle: &mut Lifecycle, transport_closed: bool) -> Vec<Step> {
+        let mut steps = vec![];
+        while let Some(step) = lifecycle.next_step(transport_closed) {
+            steps.push(step);
+        }
+        steps
+    }
+
+    #[test]
+    fn ordered_teardown() {
+        let mut lifecycle = Lifecycle::default();
+        assert!(steps(&mut lifecycle, true).is_empty());
+
+        lifecycle.started();
+        assert_eq!(steps(&mut lifecycle, false), vec![Step::Task, Step::Signaling]);
+        assert_eq!(steps(&mut lifecycle, true), vec![Step::Transport]);
+        assert!(steps(&mut lifecycle, true).is_empty());
+
+        // A restarted task is torn down again
+        lifecycle.started();
+        assert_eq!(steps(&mut lifecycle, true), vec![Step::Task, Step::Signaling, Step::Transport]);
+    }
+
+    /// The task is not closed again if it closed the connection itself,
+    /// even if the connection is lost at the same time.
+    #[test]
+    fn closed_by_task() {
+        let mut lifecycle = Lifecycle::default();
+        lifecycle.started();
+        lifecycle.closing_by_task();
+        lifecycle.closing_by_task();
+        assert_eq!(steps(&mut lifecycle, false), vec![Step::Signaling]);
+        lifecycle.closing_by_task();
+        assert_eq!(steps(&mut lifecycle, true), vec![Step::Transport]);
+    }
+
+    /// The task may close the connection after the teardown started, but
+    /// its close hook has already been called.
+    #[test]
+    fn close_during_teardown() {
+        let mut lifecycle = Lifecycle::default();
+        lifecycle.started();
+        assert_eq!(lifecycle.next_step(false), Some(Step::Task));
+        lifecycle.closing_by_task();
+        assert_eq!(steps(&mut lifecycle, true), vec![Step::Signaling, Step::Transport]);
+    }
+}
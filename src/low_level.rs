@@ -0,0 +1,269 @@
+//! A poll-based interface for event loops that cannot run futures.
+//!
+//! The [`LowLevelClient`](struct.LowLevelClient.html) does not do any I/O on
+//! its own. The application opens the WebSocket connection to
+//! `wss://<host>:<port>/<hex encoded initiator public key>` with the
+//! `v1.saltyrtc.org` subprotocol, and then shovels the binary WebSocket
+//! messages in and out:
+//!
+//! * Pass every received binary message to `feed_incoming`. If the
+//!   transport delivers messages in several chunks, pass the chunks to
+//!   `feed_incoming_chunk` instead.
+//! * Send every message returned by `poll_outgoing` as binary message.
+//! * Call `tick` regularly, at the latest at `next_deadline`, so that the
+//!   handshake timeouts and the heartbeats work.
+//! * Drain `poll_event` for the signaling events and the task messages.
+//!
+//! The negotiated task is not started, the application handles the task
+//! messages itself.
+
+use std::collections::VecDeque;
+use std::time::Instant;
+
+use rmpv::Value;
+
+use crate::{Event, SaltyClient};
+use crate::boxes::{ByteBox, ByteBoxDecoder};
+use crate::close_code::{CloseCode, CloseReason};
+use crate::errors::{SaltyError, SaltyResult, SignalingResult};
+use crate::protocol::{HandleAction, HandleActions};
+use crate::tasks::TaskMessage;
+
+
+/// Something that happened while handling an incoming message or a tick.
+#[derive(Debug, PartialEq)]
+pub enum Polled {
+    /// A signaling event.
+    Event(Event),
+    /// A task message from the peer.
+    TaskMessage(TaskMessage),
+}
+
+
+/// A [`SaltyClient`](../struct.SaltyClient.html) driven by the application
+/// instead of an async runtime.
+pub struct LowLevelClient {
+    salty: SaltyClient,
+    incoming: ByteBoxDecoder,
+    outgoing: VecDeque<Vec<u8>>,
+    polled: VecDeque<Polled>,
+}
+
+impl LowLevelClient {
+    /// Wrap a client created with the
+    /// [`SaltyClientBuilder`](../struct.SaltyClientBuilder.html).
+    pub fn new(salty: SaltyClient) -> Self {
+        LowLevelClient {
+            salty,
+            incoming: ByteBoxDecoder::new(),
+            outgoing: VecDeque::new(),
+            polled: VecDeque::new(),
+        }
+    }
+
+    /// Return the wrapped client.
+    pub fn salty(&self) -> &SaltyClient {
+        &self.salty
+    }
+
+    /// Return the wrapped client mutably, e.g. to renew the session.
+    pub fn salty_mut(&mut self) -> &mut SaltyClient {
+        &mut self.salty
+    }
+
+    /// Unwrap the client.
+    pub fn into_inner(self) -> SaltyClient {
+        self.salty
+    }
+
+    /// Handle a binary message received from the server.
+    ///
+    /// If this fails, the connection must be closed. If the specification
+    /// requires a close code for the error (e.g. a protocol error), it can
+    /// be found in the [`close_reason`](../struct.SaltyClient.html#method.close_reason)
+    /// of the client.
+    pub fn feed_incoming(&mut self, bytes: &[u8]) -> SaltyResult<()> {
+        let result = ByteBox::parse(bytes.to_vec()).and_then(|bbox| self.salty.handle_message(bbox));
+        self.enqueue(result)
+    }
+
+    /// Handle a binary message received from the server, without copying it.
+    ///
+    /// This is the same as [`feed_incoming`](#method.feed_incoming), but
+    /// the message is decrypted in the buffer that it was received in.
+    pub fn feed_incoming_frame(&mut self, frame: Vec<u8>) -> SaltyResult<()> {
+        let result = ByteBox::parse(frame).and_then(|bbox| self.salty.handle_message(bbox));
+        self.enqueue(result)
+    }
+
+    /// Handle a chunk of a binary message received from the server.
+    ///
+    /// The chunks are buffered until `last` is set, then the complete
+    /// message is handled like in [`feed_incoming`](#method.feed_incoming).
+    /// An oversized message fails once the limit is crossed, and its
+    /// remaining chunks are ignored.
+    pub fn feed_incoming_chunk(&mut self, chunk: &[u8], last: bool) -> SaltyResult<()> {
+        let result = match self.incoming.decode(chunk, last) {
+            Ok(Some(bbox)) => self.salty.handle_message(bbox),
+            Ok(None) => return Ok(()),
+            Err(e) => Err(e),
+        };
+        self.enqueue(result)
+    }
+
+    /// Return the next message to send to the server as binary message.
+    pub fn poll_outgoing(&mut self) -> Option<Vec<u8>> {
+        self.outgoing.pop_front()
+    }
+
+    /// Return the next event or task message.
+    pub fn poll_event(&mut self) -> Option<Polled> {
+        self.polled.pop_front()
+    }
+
+    /// Handle the timers that expired at `now`, like the heartbeats to the
+    /// peer.
+    ///
+    /// `now` must come from the clock of the client, i.e. `Instant::now()`
+    /// unless a custom clock was configured.
+    pub fn tick(&mut self, now: Instant) -> SaltyResult<()> {
+        let result = self.salty.handle_timeouts(now);
+        self.enqueue(result)
+    }
+
+    /// Return the point in time at which `tick` must be called next, if
+    /// anything is scheduled.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.salty.next_timeout()
+    }
+
+    /// Encrypt a task message for the peer and queue it.
+    pub fn send_task_message(&mut self, value: Value) -> SaltyResult<()> {
+        let bytes = self.salty.encrypt_task_message(value)?;
+        self.outgoing.push_back(bytes);
+        Ok(())
+    }
+
+    /// Start rotating the session keys with the peer and queue the rekey
+    /// message.
+    ///
+    /// Once the peer answered, `poll_event` returns an `Event::Rekeyed`.
+    pub fn rekey(&mut self) -> SaltyResult<()> {
+        let bytes = self.salty.encrypt_rekey_message()?;
+        self.outgoing.push_back(bytes);
+        Ok(())
+    }
+
+    /// Queue a 'close' message for the peer.
+    ///
+    /// Close the WebSocket connection once it has been sent.
+    pub fn close(&mut self, reason: CloseCode) -> SaltyResult<()> {
+        let bytes = self.salty.encrypt_close_message(reason)?;
+        self.outgoing.push_back(bytes);
+        self.salty.state.closing(CloseReason::local(Some(reason), "Closed by the application"));
+        Ok(())
+    }
+
+    /// Notify the client that the WebSocket connection was closed, with the
+    /// close code sent by the server, if any.
+    pub fn transport_closed(&mut self, code: Option<CloseCode>) {
+        if let Some(event) = self.salty.closed(code) {
+            self.polled.push_back(Polled::Event(event));
+        }
+    }
+
+    /// Queue the replies, events and task messages.
+    fn enqueue(&mut self, result: SignalingResult<HandleActions>) -> SaltyResult<()> {
+        let actions = match result {
+            Ok(actions) => actions,
+            Err(e) => {
+                if e.close_code().is_some() {
+                    self.salty.state.closing(CloseReason::local(e.close_code(), e.to_string()));
+                }
+                return Err(e.into());
+            },
+        };
+        let (replies, actions) = actions.split_replies();
+        self.outgoing.extend(replies.into_iter().map(ByteBox::into_bytes));
+        for action in actions {
+            match action {
+                HandleAction::Event(event) => self.polled.push_back(Polled::Event(event)),
+                HandleAction::TaskMessage(msg) => self.polled.push_back(Polled::TaskMessage(msg)),
+                HandleAction::HandshakeDone => info!("Handshake done"),
+                HandleAction::Reply(_) => return Err(SaltyError::Crash("Reply was not split off".into())),
+                HandleAction::StartTimer(..) | HandleAction::CancelTimer(_) => return Err(
+                    SaltyError::Crash("Timer action was not handled by the client".into())
+                ),
+            }
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::boxes::OpenBox;
+    use crate::crypto_types::KeyPair;
+    use crate::protocol::{Cookie, Nonce};
+    use crate::protocol::csn::CombinedSequenceSnapshot;
+    use crate::protocol::messages::{Message, ServerHello};
+    use crate::protocol::types::Address;
+    use crate::test_helpers::DummyTask;
+
+    use super::*;
+
+    fn initiator() -> LowLevelClient {
+        LowLevelClient::new(SaltyClient::build(KeyPair::new())
+            .add_task(Box::new(DummyTask::new(1)))
+            .initiator()
+            .unwrap())
+    }
+
+    #[test]
+    fn server_hello() {
+        let mut client = initiator();
+        let server_key = KeyPair::new();
+        let msg = ServerHello::new(*server_key.public_key()).into_message();
+        let nonce = Nonce::new(Cookie::new([1; 16]), Address(0), Address(0), CombinedSequenceSnapshot::new(0, 1));
+        let bytes = OpenBox::<Message>::new(msg, nonce).encode().into_bytes();
+        client.feed_incoming(&bytes).unwrap();
+
+        // The initiator answers with a 'client-auth' message
+        assert!(client.poll_outgoing().is_some());
+        assert_eq!(client.poll_outgoing(), None);
+        assert_eq!(client.poll_event(), None);
+        client.tick(Instant::now()).unwrap();
+        assert_eq!(client.poll_outgoing(), None);
+    }
+
+    #[test]
+    fn server_hello_chunks() {
+        let mut client = initiator();
+        let msg = ServerHello::new(*KeyPair::new().public_key()).into_message();
+        let nonce = Nonce::new(Cookie::new([1; 16]), Address(0), Address(0), CombinedSequenceSnapshot::new(0, 1));
+        let bytes = OpenBox::<Message>::new(msg, nonce).encode().into_bytes();
+        for chunk in bytes[..bytes.len() - 1].chunks(7) {
+            client.feed_incoming_chunk(chunk, false).unwrap();
+            assert_eq!(client.poll_outgoing(), None);
+        }
+        client.feed_incoming_chunk(&bytes[bytes.len() - 1..], true).unwrap();
+        assert!(client.poll_outgoing().is_some());
+    }
+
+    #[test]
+    fn server_hello_frame() {
+        let mut client = initiator();
+        let msg = ServerHello::new(*KeyPair::new().public_key()).into_message();
+        let nonce = Nonce::new(Cookie::new([1; 16]), Address(0), Address(0), CombinedSequenceSnapshot::new(0, 1));
+        client.feed_incoming_frame(OpenBox::<Message>::new(msg, nonce).encode().into_bytes()).unwrap();
+        assert!(client.poll_outgoing().is_some());
+    }
+
+    #[test]
+    fn invalid_message() {
+        let mut client = initiator();
+        assert!(client.feed_incoming(&[0; 8]).is_err());
+        assert_eq!(client.poll_outgoing(), None);
+    }
+}
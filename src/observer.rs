@@ -0,0 +1,106 @@
+//! Middleware for the signaling.
+//!
+//! An [`Observer`](trait.Observer.html) registered with
+//! [`SaltyClientBuilder::with_observer`](../struct.SaltyClientBuilder.html#method.with_observer)
+//! sees every message that is sent or received, before and after
+//! decryption, as well as the changes of the signaling state. This allows
+//! audit logging or chaos testing without changes to the client.
+//!
+//! For every message, the observer returns a [`Verdict`](enum.Verdict.html):
+//! It may pass the message on, attach a note that is logged along with the
+//! message, or drop it. Dropped incoming messages are ignored as if they
+//! never arrived. Dropped outgoing messages are only withheld if the
+//! signaling sends them on its own, e.g. handshake messages, heartbeats and
+//! `drop-responder` messages. Messages that the application encrypts itself
+//! are always returned to the application.
+//!
+//! Note: Observers see the decrypted task messages exchanged with the peer.
+
+use std::fmt;
+
+use rmpv::Value;
+
+pub use crate::protocol::state::SignalingState;
+
+
+/// What to do with an observed message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// Process the message as usual.
+    Pass,
+    /// Process the message, and log the note along with it.
+    Annotate(String),
+    /// Drop the message.
+    Drop,
+}
+
+impl Verdict {
+    /// Log the note or the drop, and return whether the message should be
+    /// processed.
+    pub(crate) fn apply(self, message: fmt::Arguments) -> bool {
+        match self {
+            Verdict::Pass => true,
+            Verdict::Annotate(note) => {
+                info!("{}: {}", message, note);
+                true
+            },
+            Verdict::Drop => {
+                info!("{}: Dropped by the observer", message);
+                false
+            },
+        }
+    }
+}
+
+
+/// A message as it is sent over the WebSocket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame<'a> {
+    /// The source address in the nonce.
+    pub source: u8,
+    /// The destination address in the nonce.
+    pub destination: u8,
+    /// The nonce followed by the (usually encrypted) payload.
+    pub bytes: &'a [u8],
+}
+
+
+/// A decrypted and decoded incoming message.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Decoded<'a> {
+    /// The source address in the nonce.
+    pub source: u8,
+    /// The destination address in the nonce.
+    pub destination: u8,
+    /// The message type, if the message has one.
+    pub message_type: Option<&'a str>,
+    /// The message as msgpack value.
+    pub value: &'a Value,
+}
+
+
+/// Hooks that are called by a [`SaltyClient`](../struct.SaltyClient.html)
+/// for every message and state change.
+///
+/// All hooks do nothing by default.
+pub trait Observer: Send {
+    /// Called for every incoming message, before its nonce is validated.
+    fn on_incoming(&mut self, _frame: &Frame) -> Verdict {
+        Verdict::Pass
+    }
+
+    /// Called for every incoming message that was decrypted and decoded,
+    /// before it is processed.
+    fn on_decrypted(&mut self, _message: &Decoded) -> Verdict {
+        Verdict::Pass
+    }
+
+    /// Called for every outgoing message, after it has been encrypted.
+    fn on_outgoing(&mut self, _frame: &Frame) -> Verdict {
+        Verdict::Pass
+    }
+
+    /// Called when the signaling state changes, including when the
+    /// signaling starts over with the server handshake.
+    fn on_state_change(&mut self, _old: SignalingState, _new: SignalingState) {}
+}
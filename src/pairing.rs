@@ -0,0 +1,205 @@
+//! Pairing information shared through a `saltyrtc://` URI.
+//!
+//! A [`PairingInfo`](struct.PairingInfo.html) contains everything a client
+//! needs to join a path: the server to connect to, the initiator public key
+//! and, for responders without a trusted key, the auth token. It can be
+//! encoded as a URI, e.g. for a QR code:
+//!
+//! ```text
+//! saltyrtc://<host>[:<port>]/<initiator public key>[#<auth token>]
+//! ```
+//!
+//! The key and the token are hex encoded. The port defaults to 443.
+
+use std::fmt;
+use std::str::FromStr;
+
+use data_encoding::HEXLOWER;
+
+use crate::crypto_types::{public_key_from_hex_str, AuthToken, PairingData, PublicKey};
+use crate::errors::{SaltyError, SaltyResult};
+
+
+/// The URI scheme of pairing information.
+const SCHEME: &str = "saltyrtc://";
+
+/// The port used if the URI does not specify one.
+const DEFAULT_PORT: u16 = 443;
+
+
+/// The server and keys needed to pair with an initiator.
+///
+/// Use [`SaltyClientBuilder::from_pairing_info`](../struct.SaltyClientBuilder.html#method.from_pairing_info)
+/// to create a client with the matching role.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PairingInfo {
+    /// The host name or IP address of the server.
+    pub host: String,
+    /// The port of the server.
+    pub port: u16,
+    /// The public permanent key of the initiator.
+    pub initiator_pubkey: PublicKey,
+    /// The auth token, if the responder is not trusted by the initiator yet.
+    pub auth_token: Option<AuthToken>,
+}
+
+impl PairingInfo {
+    /// Create a new `PairingInfo` instance.
+    pub fn new<S: Into<String>>(host: S, port: u16, initiator_pubkey: PublicKey, auth_token: Option<AuthToken>) -> Self {
+        PairingInfo { host: host.into(), port, initiator_pubkey, auth_token }
+    }
+
+    /// Create a `PairingInfo` instance from pairing data and a server.
+    pub fn from_pairing_data<S: Into<String>>(host: S, port: u16, data: PairingData) -> Self {
+        let (initiator_pubkey, auth_token) = data.into_parts();
+        PairingInfo::new(host, port, initiator_pubkey, Some(auth_token))
+    }
+
+    /// Parse a `saltyrtc://` URI created by [`to_uri`](#method.to_uri).
+    ///
+    /// The scheme is case insensitive, IPv6 addresses must be enclosed in
+    /// brackets.
+    pub fn parse(uri: &str) -> SaltyResult<Self> {
+        let invalid = |reason: &str| SaltyError::Decode(format!("Invalid pairing URI: {}", reason));
+
+        if uri.len() < SCHEME.len() || !uri[..SCHEME.len()].eq_ignore_ascii_case(SCHEME) {
+            return Err(invalid("Scheme must be saltyrtc"));
+        }
+        let rest = &uri[SCHEME.len()..];
+        let (rest, token_hex) = match rest.find('#') {
+            Some(i) => (&rest[..i], Some(&rest[i + 1..])),
+            None => (rest, None),
+        };
+        let slash = rest.find('/').ok_or_else(|| invalid("Missing initiator public key"))?;
+        let (authority, key_hex) = (&rest[..slash], &rest[slash + 1..]);
+
+        // Split off the port, taking care of brackets around IPv6 addresses
+        let port_start = if authority.starts_with('[') {
+            let end = authority.find(']').ok_or_else(|| invalid("Unterminated IPv6 address"))?;
+            match authority[end + 1..].chars().next() {
+                None => None,
+                Some(':') => Some(end + 1),
+                Some(_) => return Err(invalid("Unexpected characters after IPv6 address")),
+            }
+        } else {
+            authority.rfind(':')
+        };
+        let (host, port) = match port_start {
+            Some(i) => {
+                let port = authority[i + 1..].parse::<u16>()
+                    .map_err(|_| invalid("Invalid port"))?;
+                (&authority[..i], port)
+            },
+            None => (authority, DEFAULT_PORT),
+        };
+        if host.is_empty() || host == "[]" {
+            return Err(invalid("Missing host"));
+        }
+
+        if key_hex.len() != 64 {
+            return Err(invalid("Initiator public key must be 64 hex characters"));
+        }
+        let initiator_pubkey = public_key_from_hex_str(key_hex)?;
+        if initiator_pubkey.0.iter().all(|b| *b == 0) {
+            return Err(invalid("Initiator public key must not be all-zero"));
+        }
+        let auth_token = match token_hex {
+            Some(hex) if !hex.is_empty() => Some(AuthToken::from_hex_str(hex)?),
+            _ => None,
+        };
+        Ok(PairingInfo::new(host, port, initiator_pubkey, auth_token))
+    }
+
+    /// Encode the pairing information as a `saltyrtc://` URI.
+    ///
+    /// The port is omitted if it is the default port.
+    pub fn to_uri(&self) -> String {
+        let mut uri = format!("{}{}", SCHEME, self.host);
+        if self.port != DEFAULT_PORT {
+            uri.push_str(&format!(":{}", self.port));
+        }
+        uri.push('/');
+        uri.push_str(&HEXLOWER.encode(&self.initiator_pubkey.0));
+        if let Some(ref token) = self.auth_token {
+            uri.push('#');
+            uri.push_str(&HEXLOWER.encode(token.secret_key_bytes()));
+        }
+        uri
+    }
+}
+
+impl FromStr for PairingInfo {
+    type Err = SaltyError;
+
+    fn from_str(s: &str) -> SaltyResult<Self> {
+        Self::parse(s)
+    }
+}
+
+impl fmt::Display for PairingInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_uri())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto_types::KeyPair;
+
+    use super::*;
+
+    const KEY_HEX: &str = "09a59a5fa6b45cb07638a3a6e347ce563a948b756fd22f9527465f7c79c2a864";
+    const TOKEN_HEX: &str = "0e94b54a49e4ec7f4398ec9bec5d4359cca810f7eca31704e6c0afadd54a7818";
+
+    #[test]
+    fn parse_with_token() {
+        let info = PairingInfo::parse(&format!("saltyrtc://example.org:8765/{}#{}", KEY_HEX, TOKEN_HEX)).unwrap();
+        assert_eq!(info.host, "example.org");
+        assert_eq!(info.port, 8765);
+        assert_eq!(info.initiator_pubkey, public_key_from_hex_str(KEY_HEX).unwrap());
+        assert_eq!(info.auth_token, Some(AuthToken::from_hex_str(TOKEN_HEX).unwrap()));
+    }
+
+    #[test]
+    fn parse_without_token() {
+        let info: PairingInfo = format!("SaltyRTC://[::1]/{}", KEY_HEX).parse().unwrap();
+        assert_eq!(info.host, "[::1]");
+        assert_eq!(info.port, DEFAULT_PORT);
+        assert_eq!(info.auth_token, None);
+        let info = PairingInfo::parse(&format!("saltyrtc://[::1]:8765/{}#", KEY_HEX)).unwrap();
+        assert_eq!(info.port, 8765);
+        assert_eq!(info.auth_token, None);
+    }
+
+    #[test]
+    fn uri_roundtrip() {
+        for &(port, ref token) in &[(DEFAULT_PORT, None), (8765, Some(AuthToken::new()))] {
+            let info = PairingInfo::new("localhost", port, *KeyPair::new().public_key(), token.clone());
+            assert_eq!(PairingInfo::parse(&info.to_uri()).unwrap(), info);
+        }
+        let info = PairingInfo::new("localhost", DEFAULT_PORT, public_key_from_hex_str(KEY_HEX).unwrap(), None);
+        assert_eq!(info.to_string(), format!("saltyrtc://localhost/{}", KEY_HEX));
+    }
+
+    #[test]
+    fn parse_invalid() {
+        let cases = vec![
+            (format!("https://localhost/{}", KEY_HEX), "Scheme must be saltyrtc"),
+            ("saltyrtc://localhost".to_string(), "Missing initiator public key"),
+            (format!("saltyrtc://:8765/{}", KEY_HEX), "Missing host"),
+            (format!("saltyrtc://localhost:99999/{}", KEY_HEX), "Invalid port"),
+            (format!("saltyrtc://[::1/{}", KEY_HEX), "Unterminated IPv6 address"),
+            (format!("saltyrtc://[::1]x/{}", KEY_HEX), "Unexpected characters after IPv6 address"),
+            ("saltyrtc://localhost/abcd".to_string(), "Initiator public key must be 64 hex characters"),
+            (format!("saltyrtc://localhost/{}", "00".repeat(32)), "Initiator public key must not be all-zero"),
+        ];
+        for (uri, reason) in cases {
+            assert_eq!(
+                PairingInfo::parse(&uri),
+                Err(SaltyError::Decode(format!("Invalid pairing URI: {}", reason))),
+                "{}", uri,
+            );
+        }
+    }
+}
@@ -0,0 +1,223 @@
+//! Passphrase protected storage of the permanent key pair and trusted peer
+//! keys.
+//!
+//! The keys are encrypted with `crypto_secretbox` using a key that is
+//! derived from the passphrase with scrypt. A key file looks like this:
+//!
+//! ```text
+//! | "SALTYKEY" | version (1) | salt (32) | nonce (24) | ciphertext |
+//! ```
+//!
+//! The plaintext consists of the 32 byte private permanent key followed by
+//! one 32 byte public key for every trusted peer.
+//!
+//! This module is only available with the `persistence` feature.
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+use crate::crypto_backend::{self, backend, memzero, KEYBYTES, NONCEBYTES, PWHASH_SALTBYTES};
+use crate::crypto_types::{KeyPair, PrivateKey, PublicKey, SecretKey};
+use crate::errors::PersistenceError;
+
+
+/// The magic bytes at the start of every key file.
+const MAGIC: &[u8; 8] = b"SALTYKEY";
+
+/// The current key file format version.
+const VERSION: u8 = 1;
+
+/// The length of the unencrypted header.
+const HEADER_BYTES: usize = MAGIC.len() + 1 + PWHASH_SALTBYTES + NONCEBYTES;
+
+
+/// The permanent key pair of a client, along with the public permanent keys
+/// of trusted peers.
+///
+/// Use [`save`](#method.save) and [`load`](#method.load) to store it in an
+/// encrypted key file.
+#[derive(Debug, PartialEq)]
+pub struct KeyFile {
+    /// The permanent key pair.
+    pub keypair: KeyPair,
+    /// The public permanent keys of trusted peers.
+    pub trusted_keys: Vec<PublicKey>,
+}
+
+impl KeyFile {
+    /// Create a new key file without any trusted keys.
+    pub fn new(keypair: KeyPair) -> Self {
+        KeyFile {
+            keypair,
+            trusted_keys: vec![],
+        }
+    }
+
+    /// Add a trusted peer key, unless it is already present.
+    pub fn add_trusted_key(&mut self, key: PublicKey) {
+        if !self.trusted_keys.contains(&key) {
+            self.trusted_keys.push(key);
+        }
+    }
+
+    /// Encrypt the keys with the passphrase.
+    pub fn to_encrypted_bytes(&self, passphrase: &[u8]) -> Result<Vec<u8>, PersistenceError> {
+        crypto_backend::init().map_err(|e| PersistenceError::Crypto(e.to_string()))?;
+
+        let mut salt = [0u8; PWHASH_SALTBYTES];
+        backend().random_bytes(&mut salt);
+        let mut nonce = [0u8; NONCEBYTES];
+        backend().random_bytes(&mut nonce);
+        let key = derive_key(passphrase, &salt)?;
+
+        let mut plaintext = Vec::with_capacity(KEYBYTES + self.trusted_keys.len() * KEYBYTES);
+        plaintext.extend_from_slice(&self.keypair.private_key().0);
+        for trusted_key in &self.trusted_keys {
+            plaintext.extend_from_slice(&trusted_key.0);
+        }
+        let ciphertext = crypto_backend::secretbox_seal(&plaintext, &nonce, &key);
+        memzero(&mut plaintext);
+
+        let mut bytes = Vec::with_capacity(HEADER_BYTES + ciphertext.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        bytes.extend_from_slice(&salt);
+        bytes.extend_from_slice(&nonce);
+        bytes.extend_from_slice(&ciphertext);
+        Ok(bytes)
+    }
+
+    /// Decrypt keys that were encrypted with
+    /// [`to_encrypted_bytes`](#method.to_encrypted_bytes).
+    pub fn from_encrypted_bytes(bytes: &[u8], passphrase: &[u8]) -> Result<Self, PersistenceError> {
+        crypto_backend::init().map_err(|e| PersistenceError::Crypto(e.to_string()))?;
+
+        if bytes.len() < HEADER_BYTES || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(PersistenceError::Format("Not a key file".into()));
+        }
+        let version = bytes[MAGIC.len()];
+        if version != VERSION {
+            return Err(PersistenceError::Format(format!("Unsupported key file version: {}", version)));
+        }
+        let (salt_bytes, rest) = bytes[MAGIC.len() + 1..].split_at(PWHASH_SALTBYTES);
+        let (nonce_bytes, ciphertext) = rest.split_at(NONCEBYTES);
+        let mut salt = [0u8; PWHASH_SALTBYTES];
+        salt.copy_from_slice(salt_bytes);
+        let mut nonce = [0u8; NONCEBYTES];
+        nonce.copy_from_slice(nonce_bytes);
+
+        let key = derive_key(passphrase, &salt)?;
+        let mut plaintext = crypto_backend::secretbox_open(ciphertext, &nonce, &key)
+            .map_err(|_| PersistenceError::Decrypt)?;
+        let result = Self::from_plaintext(&plaintext);
+        memzero(&mut plaintext);
+        result
+    }
+
+    /// Decode the decrypted key file payload.
+    fn from_plaintext(plaintext: &[u8]) -> Result<Self, PersistenceError> {
+        if plaintext.len() < KEYBYTES {
+            return Err(PersistenceError::Format(format!("Invalid payload length: {}", plaintext.len())));
+        }
+        let (private_key_bytes, trusted_key_bytes) = plaintext.split_at(KEYBYTES);
+        let private_key = PrivateKey::from_slice(private_key_bytes)
+            .ok_or_else(|| PersistenceError::Format("Invalid private key".into()))?;
+        // A truncated last chunk is rejected by `PublicKey::from_slice`
+        let trusted_keys = trusted_key_bytes
+            .chunks(KEYBYTES)
+            .map(|chunk| PublicKey::from_slice(chunk)
+                .ok_or_else(|| PersistenceError::Format("Invalid trusted key".into())))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(KeyFile {
+            keypair: KeyPair::from_private_key(private_key),
+            trusted_keys,
+        })
+    }
+
+    /// Encrypt the keys with the passphrase and write them to the specified
+    /// file.
+    ///
+    /// An existing file is replaced. On Unix, the file is only readable by
+    /// the owner.
+    pub fn save<P: AsRef<Path>>(&self, path: P, passphrase: &[u8]) -> Result<(), PersistenceError> {
+        let bytes = self.to_encrypted_bytes(passphrase)?;
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        options.open(path)
+            .and_then(|mut file| file.write_all(&bytes))
+            .map_err(|e| PersistenceError::Io(e.to_string()))
+    }
+
+    /// Read the specified file and decrypt the keys with the passphrase.
+    pub fn load<P: AsRef<Path>>(path: P, passphrase: &[u8]) -> Result<Self, PersistenceError> {
+        let bytes = fs::read(path).map_err(|e| PersistenceError::Io(e.to_string()))?;
+        Self::from_encrypted_bytes(&bytes, passphrase)
+    }
+}
+
+/// Derive the secretbox key from the passphrase.
+fn derive_key(passphrase: &[u8], salt: &[u8; PWHASH_SALTBYTES]) -> Result<SecretKey, PersistenceError> {
+    backend().scrypt(passphrase, salt)
+        .map(SecretKey)
+        .map_err(|_| PersistenceError::Crypto("Could not derive key from passphrase".into()))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+
+    fn key_file() -> KeyFile {
+        let mut key_file = KeyFile::new(KeyPair::new());
+        key_file.add_trusted_key(*KeyPair::new().public_key());
+        key_file.add_trusted_key(*KeyPair::new().public_key());
+        key_file
+    }
+
+    #[test]
+    fn roundtrip() {
+        let key_file = key_file();
+        let bytes = key_file.to_encrypted_bytes(b"correct horse").unwrap();
+        assert_eq!(&bytes[..8], b"SALTYKEY");
+        assert_eq!(KeyFile::from_encrypted_bytes(&bytes, b"correct horse").unwrap(), key_file);
+    }
+
+    #[test]
+    fn wrong_passphrase() {
+        let bytes = key_file().to_encrypted_bytes(b"correct horse").unwrap();
+        assert_eq!(KeyFile::from_encrypted_bytes(&bytes, b"battery staple"), Err(PersistenceError::Decrypt));
+    }
+
+    #[test]
+    fn invalid_format() {
+        let mut bytes = key_file().to_encrypted_bytes(b"pw").unwrap();
+        assert_eq!(
+            KeyFile::from_encrypted_bytes(&bytes[..HEADER_BYTES - 1], b"pw"),
+            Err(PersistenceError::Format("Not a key file".into())),
+        );
+        bytes[8] = 2;
+        assert_eq!(
+            KeyFile::from_encrypted_bytes(&bytes, b"pw"),
+            Err(PersistenceError::Format("Unsupported key file version: 2".into())),
+        );
+    }
+
+    #[test]
+    fn save_and_load() {
+        let path = env::temp_dir().join(format!("saltyrtc-keyfile-test-{}", process::id()));
+        let key_file = key_file();
+        key_file.save(&path, b"pw").unwrap();
+        let loaded = KeyFile::load(&path, b"pw");
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.unwrap(), key_file);
+    }
+}
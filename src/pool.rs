@@ -0,0 +1,460 @@
+//! Manage many independent signaling connections at once.
+//!
+//! Relay or bridge style applications pair with many peers at the same
+//! time, each on its own path with its own keys. A
+//! [`SaltyClientPool`](struct.SaltyClientPool.html) runs the connection,
+//! handshake and task loop futures of every added
+//! [`SaltyClient`](../struct.SaltyClient.html) on one reactor and merges
+//! their events into a single stream, tagged with the
+//! [`ConnectionId`](struct.ConnectionId.html) of the connection.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use futures::{Future, Stream};
+use futures::future::{self, Either};
+use futures::sync::{mpsc, oneshot};
+use tokio_core::reactor::Handle;
+
+use crate::{CloseCode, Event, SaltyClient, ProxyConfig, SharedResources, TlsConfig};
+use crate::errors::{SaltyError, SaltyResult};
+use crate::tasks::BoxedTask;
+
+
+/// Identifies a connection in a [`SaltyClientPool`](struct.SaltyClientPool.html).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ConnectionId(u32);
+
+impl ConnectionId {
+    /// Return the numeric id.
+    pub fn as_number(self) -> u32 {
+        self.0
+    }
+}
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+
+/// An event of one of the connections in a pool.
+#[derive(Debug, PartialEq)]
+pub enum PoolEvent {
+    /// An event emitted by the connection.
+    Event(ConnectionId, Event),
+    /// The connection has ended and was removed from the pool.
+    ///
+    /// Contains the error if the connection failed. This is the last event
+    /// of the connection.
+    Closed(ConnectionId, Option<SaltyError>),
+}
+
+impl PoolEvent {
+    /// Return the id of the connection that emitted the event.
+    pub fn connection(&self) -> ConnectionId {
+        match *self {
+            PoolEvent::Event(id, _) | PoolEvent::Closed(id, _) => id,
+        }
+    }
+}
+
+
+/// The state of a connection that is shared with its future.
+struct Connection {
+    salty: Arc<RwLock<SaltyClient>>,
+    /// The negotiated task, once the task loop is running.
+    task: Arc<Mutex<Option<Arc<Mutex<BoxedTask>>>>>,
+    /// Cancels the connection future.
+    cancel_tx: Option<oneshot::Sender<()>>,
+    /// Resolves once the connection future has ended.
+    done_rx: Option<oneshot::Receiver<()>>,
+}
+
+impl Connection {
+    /// Close the connection.
+    ///
+    /// A running task is closed with the `reason`, so that the peer is
+    /// notified. Otherwise, the connection is cancelled.
+    fn close(&mut self, reason: CloseCode) {
+        let task = self.task.lock().ok().and_then(|task| task.clone());
+        match task {
+            Some(task) => match task.lock() {
+                Ok(mut task) => task.close(reason),
+                Err(e) => error!("Could not lock task mutex: {}", e),
+            },
+            None => if let Some(tx) = self.cancel_tx.take() {
+                let _ = tx.send(());
+            },
+        }
+    }
+}
+
+type Connections = Arc<Mutex<HashMap<ConnectionId, Connection>>>;
+
+
+/// Runs several independent signaling connections on one reactor.
+///
+/// All connections use the same server. The TLS and proxy configuration and
+/// the shared resources of the pool are used for clients that were built
+/// without their own.
+///
+/// The pool does not own the spawned futures. Call
+/// [`shutdown`](struct.SaltyClientPool.html#method.shutdown) to close all
+/// connections before dropping it.
+pub struct SaltyClientPool {
+    handle: Handle,
+    host: String,
+    port: u16,
+    tls_config: Option<TlsConfig>,
+    proxy: Option<ProxyConfig>,
+    shared: Option<SharedResources>,
+    handshake_timeout: Option<Duration>,
+    next_id: u32,
+    connections: Connections,
+    events_tx: mpsc::UnboundedSender<PoolEvent>,
+    events_rx: Option<mpsc::UnboundedReceiver<PoolEvent>>,
+}
+
+impl fmt::Debug for SaltyClientPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SaltyClientPool")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("connections", &self.len())
+            .finish()
+    }
+}
+
+impl SaltyClientPool {
+    /// Create a new pool for connections to the specified server.
+    pub fn new(handle: &Handle, host: &str, port: u16) -> Self {
+        let (events_tx, events_rx) = mpsc::unbounded();
+        SaltyClientPool {
+            handle: handle.clone(),
+            host: host.to_string(),
+            port,
+            tls_config: None,
+            proxy: None,
+            shared: None,
+            handshake_timeout: None,
+            next_id: 0,
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            events_tx,
+            events_rx: Some(events_rx),
+        }
+    }
+
+    /// Use this TLS configuration for clients that don't have their own.
+    pub fn with_tls_config(mut self, tls_config: TlsConfig) -> Self {
+        self.tls_config = Some(tls_config);
+        self
+    }
+
+    /// Use this proxy for clients that don't have their own.
+    pub fn with_proxy(mut self, proxy: ProxyConfig) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Use these shared resources for clients that don't have their own,
+    /// e.g. to limit the number of concurrent handshakes of the pool.
+    pub fn with_shared_resources(mut self, shared: SharedResources) -> Self {
+        self.shared = Some(shared);
+        self
+    }
+
+    /// Fail connections whose handshake does not finish within the
+    /// specified duration.
+    pub fn with_handshake_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// Take the stream of events of all connections.
+    ///
+    /// Return `None` if the stream has been taken before.
+    pub fn events(&mut self) -> Option<mpsc::UnboundedReceiver<PoolEvent>> {
+        self.events_rx.take()
+    }
+
+    /// Add a client to the pool and start connecting.
+    ///
+    /// The connection runs until the peer or the server closes it, until it
+    /// fails or until it is closed through the pool. A
+    /// [`PoolEvent::Closed`](enum.PoolEvent.html#variant.Closed) event is
+    /// emitted at the end.
+    pub fn add(&mut self, mut client: SaltyClient) -> SaltyResult<ConnectionId> {
+        if client.tls_config.is_none() {
+            client.tls_config = self.tls_config.clone();
+        }
+        if client.proxy.is_none() {
+            client.proxy = self.proxy.clone();
+        }
+        if client.shared.is_none() {
+            client.shared = self.shared.clone();
+        }
+        let salty = Arc::new(RwLock::new(client));
+
+        let id = ConnectionId(self.next_id);
+        self.next_id = self.next_id.checked_add(1)
+            .ok_or_else(|| SaltyError::Crash("Connection ids exhausted".into()))?;
+
+        let (connect_future, event_channel) = crate::connect(
+            &self.host, self.port, None, &self.handle, Arc::clone(&salty),
+        )?;
+        let (event_tx, event_rx) = event_channel.split();
+
+        // Connect, do the handshake and run the task loop
+        let task_slot = Arc::new(Mutex::new(None));
+        let connection = {
+            let salty = Arc::clone(&salty);
+            let task_slot = Arc::clone(&task_slot);
+            let timeout = self.handshake_timeout;
+            let handshake_tx = event_tx.clone();
+            connect_future
+                .and_then({
+                    let salty = Arc::clone(&salty);
+                    move |client| crate::do_handshake(client, salty, handshake_tx, timeout)
+                })
+                .and_then(move |client| {
+                    let (task, task_loop) = crate::task_loop(client, salty, event_tx)?;
+                    if let Ok(mut slot) = task_slot.lock() {
+                        *slot = Some(task);
+                    }
+                    Ok(task_loop)
+                })
+                .flatten()
+        };
+
+        // Forward the events, tagged with the connection id
+        let forward = {
+            let events_tx = self.events_tx.clone();
+            event_rx.for_each(move |event| {
+                let _ = events_tx.unbounded_send(PoolEvent::Event(id, event));
+                Ok(())
+            })
+        };
+
+        let (cancel_tx, cancel_rx) = oneshot::channel();
+        let (done_tx, done_rx) = oneshot::channel();
+        let connections = Arc::clone(&self.connections);
+        let events_tx = self.events_tx.clone();
+        let future = connection
+            .select2(cancel_rx)
+            .then(|res| match res {
+                Ok(_) => Ok(None),
+                Err(Either::A((e, _))) => Ok(Some(e)),
+                // The pool only drops the sender after the connection ended
+                Err(Either::B(_)) => Ok(None),
+            })
+            .join(forward)
+            .map(move |(error, _)| {
+                match error {
+                    Some(ref e) => warn!("Connection {} failed: {}", id, e),
+                    None => info!("Connection {} closed", id),
+                }
+                if let Ok(mut connections) = connections.lock() {
+                    connections.remove(&id);
+                }
+                let _ = events_tx.unbounded_send(PoolEvent::Closed(id, error));
+                let _ = done_tx.send(());
+            });
+
+        self.connections
+            .lock()
+            .map_err(|e| SaltyError::Crash(format!("Could not lock connections: {}", e)))?
+            .insert(id, Connection {
+                salty,
+                task: task_slot,
+                cancel_tx: Some(cancel_tx),
+                done_rx: Some(done_rx),
+            });
+        self.handle.spawn(future);
+        debug!("Added connection {}", id);
+        Ok(id)
+    }
+
+    /// Return the client of a connection, if it is still running.
+    pub fn client(&self, id: ConnectionId) -> Option<Arc<RwLock<SaltyClient>>> {
+        self.connections.lock().ok()?
+            .get(&id)
+            .map(|connection| Arc::clone(&connection.salty))
+    }
+
+    /// Return the negotiated task of a connection, once the task loop is
+    /// running.
+    pub fn task(&self, id: ConnectionId) -> Option<Arc<Mutex<BoxedTask>>> {
+        let connections = self.connections.lock().ok()?;
+        let task = connections.get(&id)?.task.lock().ok()?;
+        task.clone()
+    }
+
+    /// Return the ids of all running connections, in ascending order.
+    pub fn connections(&self) -> Vec<ConnectionId> {
+        let mut ids: Vec<ConnectionId> = self.connections.lock()
+            .map(|connections| connections.keys().cloned().collect())
+            .unwrap_or_default();
+        ids.sort();
+        ids
+    }
+
+    /// Return the number of running connections.
+    pub fn len(&self) -> usize {
+        self.connections.lock()
+            .map(|connections| connections.len())
+            .unwrap_or(0)
+    }
+
+    /// Return whether no connections are running.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Close a connection.
+    ///
+    /// If the task loop is running, the task is closed with the specified
+    /// `reason`. Otherwise, the connection is cancelled. Return `false` if
+    /// there is no such connection.
+    pub fn close(&self, id: ConnectionId, reason: CloseCode) -> bool {
+        match self.connections.lock() {
+            Ok(mut connections) => match connections.get_mut(&id) {
+                Some(connection) => {
+                    connection.close(reason);
+                    true
+                },
+                None => false,
+            },
+            Err(e) => {
+                error!("Could not lock connections: {}", e);
+                false
+            },
+        }
+    }
+
+    /// Close all connections.
+    ///
+    /// The returned future resolves once all connections have ended. It must
+    /// be run on the reactor of the pool.
+    pub fn shutdown(&self, reason: CloseCode) -> impl Future<Item=(), Error=()> {
+        let mut done = vec![];
+        if let Ok(mut connections) = self.connections.lock() {
+            info!("Closing {} connections", connections.len());
+            for connection in connections.values_mut() {
+                connection.close(reason);
+                if let Some(rx) = connection.done_rx.take() {
+                    done.push(rx.then(|_| Ok(())));
+                }
+            }
+        }
+        future::join_all(done).map(|_| ())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use tokio_core::reactor::Core;
+
+    use crate::crypto_types::KeyPair;
+    use crate::test_helpers::DummyTask;
+
+    use super::*;
+
+    fn initiator() -> SaltyClient {
+        SaltyClient::build(KeyPair::new())
+            .add_task(Box::new(DummyTask::new(1)))
+            .initiator()
+            .unwrap()
+    }
+
+    /// Wait for the next event that is not a regular connection event.
+    fn next_closed(core: &mut Core, events: mpsc::UnboundedReceiver<PoolEvent>) -> (PoolEvent, mpsc::UnboundedReceiver<PoolEvent>) {
+        let mut events = events;
+        loop {
+            let (event, rest) = core.run(events.into_future()).map_err(|_| ()).unwrap();
+            events = rest;
+            match event {
+                Some(event @ PoolEvent::Closed(..)) => return (event, events),
+                Some(_) => continue,
+                None => panic!("Event stream ended"),
+            }
+        }
+    }
+
+    #[test]
+    fn failed_connection_is_removed() {
+        let mut core = Core::new().unwrap();
+
+        // Nobody listens on the port of a listener that was dropped
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let mut pool = SaltyClientPool::new(&core.handle(), "127.0.0.1", port);
+        let events = pool.events().unwrap();
+        assert!(pool.events().is_none());
+
+        let id = pool.add(initiator()).unwrap();
+        assert_eq!(pool.connections(), vec![id]);
+        assert!(pool.client(id).is_some());
+        assert!(pool.task(id).is_none());
+
+        match next_closed(&mut core, events).0 {
+            PoolEvent::Closed(closed, Some(SaltyError::Network(_))) => assert_eq!(closed, id),
+            other => panic!("Unexpected event: {:?}", other),
+        }
+        assert!(pool.is_empty());
+        assert!(pool.client(id).is_none());
+        assert!(!pool.close(id, CloseCode::WsGoingAway));
+    }
+
+    /// A failed connection hands its handshake slot to the next one.
+    #[test]
+    fn shared_handshake_limit() {
+        let mut core = Core::new().unwrap();
+
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let shared = SharedResources::new(None).unwrap().with_max_concurrent_handshakes(1);
+        let mut pool = SaltyClientPool::new(&core.handle(), "127.0.0.1", port)
+            .with_shared_resources(shared);
+        let events = pool.events().unwrap();
+
+        let first = pool.add(initiator()).unwrap();
+        let second = pool.add(initiator()).unwrap();
+        let (event1, events) = next_closed(&mut core, events);
+        let (event2, _) = next_closed(&mut core, events);
+        let mut closed = [event1.connection(), event2.connection()];
+        closed.sort();
+        assert_eq!(closed, [first, second]);
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn shutdown_cancels_handshakes() {
+        let mut core = Core::new().unwrap();
+
+        // The listener accepts connections, but never answers the TLS
+        // handshake
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut pool = SaltyClientPool::new(&core.handle(), "127.0.0.1", port);
+        let events = pool.events().unwrap();
+
+        let first = pool.add(initiator()).unwrap();
+        let second = pool.add(initiator()).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(pool.len(), 2);
+
+        core.run(pool.shutdown(CloseCode::WsGoingAway)).unwrap();
+        assert!(pool.is_empty());
+
+        let (event1, events) = next_closed(&mut core, events);
+        let (event2, _) = next_closed(&mut core, events);
+        let mut closed = vec![event1, event2];
+        closed.sort_by_key(PoolEvent::connection);
+        assert_eq!(closed, vec![PoolEvent::Closed(first, None), PoolEvent::Closed(second, None)]);
+    }
+}
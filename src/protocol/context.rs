@@ -1,11 +1,26 @@
 //! The context structs hold state used in signaling.
 
 use std::sync::RwLock;
+use std::time::Instant;
 
+use rmpv::Value;
+
+use crate::boxes::{ByteBox, OpenBox};
 use crate::crypto::{PublicKey, KeyPair};
+use crate::errors::{SignalingError, SignalingResult};
 
+#[cfg(feature = "server-session")]
+use super::cookie::Cookie;
 use super::cookie::{CookiePair};
+#[cfg(feature = "server-session")]
+use super::csn::{CombinedSequence, CombinedSequenceSnapshot, PeerSequenceNumbers};
 use super::csn::{CombinedSequencePair};
+use super::messages::{Message};
+use super::nonce::NonceFactory;
+use super::random::RandomSource;
+use super::rate_limit::TokenBucket;
+#[cfg(test)]
+use super::random::OsRandom;
 use super::state::{ServerHandshakeState, InitiatorHandshakeState, ResponderHandshakeState};
 use super::types::{Identity, Address};
 
@@ -27,11 +42,132 @@ pub(crate) trait PeerContext {
     /// The returned reference is inside a RwLock, providing interior mutability.
     fn csn_pair(&self) -> &RwLock<CombinedSequencePair>;
 
+    /// Return our CSN pair for the task payloads exchanged with this peer,
+    /// if any. Payloads are never exchanged with the server.
+    fn payload_csn_pair(&self) -> Option<&RwLock<CombinedSequencePair>> {
+        None
+    }
+
     /// Return our cookie pair with this peer.
     fn cookie_pair(&self) -> &CookiePair;
 
     /// Return our mutable cookie pair with this peer.
     fn cookie_pair_mut(&mut self) -> &mut CookiePair;
+
+    /// Return the factory for the nonces of outgoing messages to this peer.
+    ///
+    /// The `source` is our own address.
+    fn nonce_factory(&self, source: Address) -> NonceFactory<'_> {
+        NonceFactory::new(&self.cookie_pair().ours, self.csn_pair(), source, self.identity().into())
+    }
+
+    /// Return our session keypair and the peer public session key.
+    fn session_keys(&self) -> SignalingResult<(&KeyPair, &PublicKey)> {
+        let keypair = self.keypair()
+            .ok_or_else(|| SignalingError::Crash(
+                format!("Session keypair for {} not available", self.identity())
+            ))?;
+        let session_key = self.session_key()
+            .ok_or_else(|| SignalingError::Crash(
+                format!("Session key of {} not set", self.identity())
+            ))?;
+        Ok((keypair, session_key))
+    }
+
+    /// Return the peer public permanent key.
+    fn require_permanent_key(&self) -> SignalingResult<&PublicKey> {
+        self.permanent_key()
+            .ok_or_else(|| SignalingError::Crash(
+                format!("Permanent key of {} not set", self.identity())
+            ))
+    }
+
+    /// Encrypt a message for this peer using the session keys.
+    fn encrypt_message(&self, message: Message, source: Address) -> SignalingResult<ByteBox> {
+        let (keypair, session_key) = self.session_keys()?;
+        let obox = OpenBox::<Message>::new(message, self.nonce_factory(source).next()?);
+        Ok(obox.encrypt(keypair, session_key))
+    }
+
+    /// Encrypt a task value for this peer using the session keys.
+    ///
+    /// The serialized value is kept in the byte box if `keep_plaintext` is
+    /// set.
+    fn encrypt_value(&self, value: Value, source: Address, keep_plaintext: bool) -> SignalingResult<ByteBox> {
+        let (keypair, session_key) = self.session_keys()?;
+        let obox = OpenBox::<Value>::new(value, self.nonce_factory(source).next()?);
+        Ok(obox.encrypt(keypair, session_key, keep_plaintext))
+    }
+
+    /// Encrypt a message for this peer using our permanent keypair and the
+    /// peer permanent key.
+    fn encrypt_message_permanent(
+        &self,
+        message: Message,
+        source: Address,
+        permanent_keypair: &KeyPair,
+    ) -> SignalingResult<ByteBox> {
+        let permanent_key = self.require_permanent_key()?;
+        let obox = OpenBox::<Message>::new(message, self.nonce_factory(source).next()?);
+        Ok(obox.encrypt(permanent_keypair, permanent_key))
+    }
+
+    /// Decrypt a message of one of the expected types from this peer using
+    /// the session keys.
+    fn decrypt_message(&self, bbox: ByteBox, expected_types: &[&str]) -> SignalingResult<OpenBox<Message>> {
+        let (keypair, session_key) = self.session_keys()?;
+        OpenBox::<Message>::decrypt(bbox, keypair, session_key, expected_types)
+    }
+
+    /// Decrypt a task value from this peer using the session keys.
+    ///
+    /// The decrypted bytes are kept if `keep_plaintext` is set.
+    fn decrypt_value(&self, bbox: ByteBox, keep_plaintext: bool) -> SignalingResult<OpenBox<Value>> {
+        let (keypair, session_key) = self.session_keys()?;
+        OpenBox::<Value>::decrypt(bbox, keypair, session_key, keep_plaintext)
+    }
+
+    /// Decrypt a message of one of the expected types from this peer using
+    /// our permanent keypair and the peer permanent key.
+    fn decrypt_message_permanent(
+        &self,
+        bbox: ByteBox,
+        permanent_keypair: &KeyPair,
+        expected_types: &[&str],
+    ) -> SignalingResult<OpenBox<Message>> {
+        let permanent_key = self.require_permanent_key()?;
+        OpenBox::<Message>::decrypt(bbox, permanent_keypair, permanent_key, expected_types)
+    }
+}
+
+
+/// A session with the server that has been established before, e.g. by
+/// another client instance.
+///
+/// The session does not contain the peers announced by the server. A
+/// restored initiator only knows the responders that the server announces
+/// with a 'new-responder' message afterwards, and a restored responder
+/// waits for a 'new-initiator' message, even if the initiator was already
+/// connected. To hand over a connection without losing any peers, export
+/// the session before the 'server-auth' message has been received.
+///
+/// See [`SaltyClientBuilder::with_server_session`](struct.SaltyClientBuilder.html#method.with_server_session).
+/// This is only available with the `server-session` feature.
+#[cfg(feature = "server-session")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerSession {
+    /// The public session key of the server.
+    pub session_key: PublicKey,
+    /// Our cookie towards the server.
+    pub our_cookie: [u8; 16],
+    /// The cookie of the server.
+    pub server_cookie: [u8; 16],
+    /// The combined sequence numbers of the last messages exchanged with
+    /// the server.
+    pub csn: PeerSequenceNumbers,
+    /// The address assigned by the server, or `None` if the client still
+    /// waits for the `server-auth` message.
+    pub address: Option<u8>,
 }
 
 
@@ -55,13 +191,77 @@ pub(crate) struct ServerContext {
 
 impl ServerContext {
     /// Create a new `ServerContext` instance.
+    #[cfg(test)]
     pub fn new() -> Self {
+        Self::with_rng(&mut OsRandom)
+    }
+
+    /// Create a new `ServerContext` instance using the specified random source.
+    pub fn with_rng(rng: &mut dyn RandomSource) -> Self {
         ServerContext {
             handshake_state: ServerHandshakeState::New,
             permanent_key: None,
             session_key: None,
-            csn_pair: RwLock::new(CombinedSequencePair::new()),
-            cookie_pair: CookiePair::new(),
+            csn_pair: RwLock::new(CombinedSequencePair::with_rng(rng)),
+            cookie_pair: CookiePair::with_rng(rng),
+        }
+    }
+
+    /// Create a `ServerContext` instance for an established session.
+    ///
+    /// The handshake state is `Done` if the session contains our address,
+    /// and `ClientInfoSent` otherwise.
+    #[cfg(feature = "server-session")]
+    pub(crate) fn from_session(session: &ServerSession, permanent_key: Option<PublicKey>) -> SignalingResult<Self> {
+        let csn = |number: u64| if number >> 48 == 0 {
+            Ok(((number >> 32) as u16, number as u32))
+        } else {
+            Err(SignalingError::InvalidMessage(format!("Combined sequence number {} is too large", number)))
+        };
+        let (overflow, sequence) = csn(session.csn.outgoing)?;
+        let ours = CombinedSequence::new(overflow, sequence);
+        let (overflow, sequence) = csn(session.csn.incoming)?;
+        let theirs = CombinedSequenceSnapshot::new(overflow, sequence);
+        let our_cookie = Cookie::new(session.our_cookie);
+        let server_cookie = Cookie::new(session.server_cookie);
+        if our_cookie == server_cookie {
+            return Err(SignalingError::InvalidMessage("Our cookie and the server cookie are identical".into()));
+        }
+        Ok(ServerContext {
+            handshake_state: match session.address {
+                Some(_) => ServerHandshakeState::Done,
+                None => ServerHandshakeState::ClientInfoSent,
+            },
+            permanent_key,
+            session_key: Some(session.session_key),
+            csn_pair: RwLock::new(CombinedSequencePair { ours, theirs: Some(theirs) }),
+            cookie_pair: CookiePair { ours: our_cookie, theirs: Some(server_cookie) },
+        })
+    }
+
+    /// Return the established session, if the session key of the server is
+    /// known.
+    #[cfg(feature = "server-session")]
+    pub(crate) fn session(&self, address: Option<u8>) -> Option<ServerSession> {
+        let csn_pair = self.csn_pair.read().expect("CSN pair rwlock is poisoned");
+        match (self.session_key, &self.cookie_pair.theirs, &csn_pair.theirs) {
+            (Some(session_key), Some(server_cookie), Some(theirs)) => {
+                let mut our_cookie = [0; 16];
+                our_cookie.copy_from_slice(self.cookie_pair.ours.as_bytes());
+                let mut server_cookie_bytes = [0; 16];
+                server_cookie_bytes.copy_from_slice(server_cookie.as_bytes());
+                Some(ServerSession {
+                    session_key,
+                    our_cookie,
+                    server_cookie: server_cookie_bytes,
+                    csn: PeerSequenceNumbers {
+                        incoming: theirs.combined_sequence_number(),
+                        outgoing: csn_pair.ours.combined_sequence_number(),
+                    },
+                    address,
+                })
+            },
+            _ => None,
         }
     }
 
@@ -128,17 +328,28 @@ pub(crate) struct InitiatorContext {
 
     /// The cookie pair between us and the initiator.
     pub(crate) cookie_pair: CookiePair,
+
+    /// The combined sequence number of the task payloads.
+    pub(crate) payload_csn_pair: RwLock<CombinedSequencePair>,
 }
 
 impl InitiatorContext {
+    #[cfg(test)]
     pub fn new(permanent_key: PublicKey) -> Self {
+        Self::with_rng(permanent_key, &mut OsRandom)
+    }
+
+    /// Create a new `InitiatorContext` instance using the specified random
+    /// source.
+    pub fn with_rng(permanent_key: PublicKey, rng: &mut dyn RandomSource) -> Self {
         InitiatorContext {
             handshake_state: InitiatorHandshakeState::New,
             permanent_key,
             session_key: None,
-            keypair: KeyPair::new(),
-            csn_pair: RwLock::new(CombinedSequencePair::new()),
-            cookie_pair: CookiePair::new(),
+            keypair: KeyPair::session_from_random_source(rng),
+            csn_pair: RwLock::new(CombinedSequencePair::with_rng(rng)),
+            cookie_pair: CookiePair::with_rng(rng),
+            payload_csn_pair: RwLock::new(CombinedSequencePair::with_rng(rng)),
         }
     }
 
@@ -176,6 +387,10 @@ impl PeerContext for InitiatorContext {
         &self.csn_pair
     }
 
+    fn payload_csn_pair(&self) -> Option<&RwLock<CombinedSequencePair>> {
+        Some(&self.payload_csn_pair)
+    }
+
     fn cookie_pair(&self) -> &CookiePair {
         &self.cookie_pair
     }
@@ -212,19 +427,39 @@ pub(crate) struct ResponderContext {
 
     /// The cookie pair between us and the responder.
     pub(crate) cookie_pair: CookiePair,
+
+    /// Our combined sequence pair for the task payloads of this responder
+    pub(crate) payload_csn_pair: RwLock<CombinedSequencePair>,
+
+    /// When the responder was registered, used to drop stalled handshakes.
+    pub(crate) created: Instant,
+
+    /// Limits the messages the responder may send before it is
+    /// authenticated.
+    pub(crate) rate_limiter: Option<TokenBucket>,
 }
 
 impl ResponderContext {
+    #[cfg(test)]
     pub fn new(address: Address, counter: u32) -> Self {
+        Self::with_rng(address, counter, &mut OsRandom)
+    }
+
+    /// Create a new `ResponderContext` instance using the specified random
+    /// source.
+    pub fn with_rng(address: Address, counter: u32, rng: &mut dyn RandomSource) -> Self {
         ResponderContext {
             handshake_state: ResponderHandshakeState::New,
             counter,
             address,
             permanent_key: None,
             session_key: None,
-            keypair: KeyPair::new(),
-            csn_pair: RwLock::new(CombinedSequencePair::new()),
-            cookie_pair: CookiePair::new(),
+            keypair: KeyPair::session_from_random_source(rng),
+            csn_pair: RwLock::new(CombinedSequencePair::with_rng(rng)),
+            cookie_pair: CookiePair::with_rng(rng),
+            payload_csn_pair: RwLock::new(CombinedSequencePair::with_rng(rng)),
+            created: Instant::now(),
+            rate_limiter: None,
         }
     }
 
@@ -262,6 +497,10 @@ impl PeerContext for ResponderContext {
         &self.csn_pair
     }
 
+    fn payload_csn_pair(&self) -> Option<&RwLock<CombinedSequencePair>> {
+        Some(&self.payload_csn_pair)
+    }
+
     fn cookie_pair(&self) -> &CookiePair {
         &self.cookie_pair
     }
@@ -275,6 +514,8 @@ impl PeerContext for ResponderContext {
 mod tests {
     use super::*;
 
+    use super::super::messages::Key;
+
     #[test]
     fn server_context_new() {
         let ctx = ServerContext::new();
@@ -282,4 +523,43 @@ mod tests {
         assert_eq!(ctx.permanent_key(), None);
         assert_eq!(ctx.session_key(), None);
     }
+
+    #[test]
+    fn session_encryption_roundtrip() {
+        let initiator_permanent = KeyPair::new();
+        let mut responder_ctx = ResponderContext::new(Address(0x02), 0);
+        let mut initiator_ctx = InitiatorContext::new(*initiator_permanent.public_key());
+        responder_ctx.session_key = Some(*initiator_ctx.keypair.public_key());
+        initiator_ctx.session_key = Some(*responder_ctx.keypair.public_key());
+
+        let msg = Key::new(*initiator_permanent.public_key()).into_message();
+        let bbox = responder_ctx.encrypt_message(msg.clone(), Address(0x01)).unwrap();
+        assert_eq!(bbox.nonce.source(), Address(0x01));
+        assert_eq!(bbox.nonce.destination(), Address(0x02));
+        assert_eq!(bbox.nonce.cookie(), &responder_ctx.cookie_pair.ours);
+
+        let obox = initiator_ctx.decrypt_message(bbox, &["key"]).unwrap();
+        assert_eq!(obox.message, msg);
+    }
+
+    #[test]
+    fn nonce_factory_increments_csn() {
+        let ctx = ResponderContext::new(Address(0x02), 0);
+        let first = ctx.nonce_factory(Address(0x01)).next().unwrap();
+        let second = ctx.nonce_factory(Address(0x01)).next().unwrap();
+        assert_eq!(
+            first.csn().combined_sequence_number() + 1,
+            second.csn().combined_sequence_number()
+        );
+    }
+
+    #[test]
+    fn encrypt_without_session_key() {
+        let ctx = ResponderContext::new(Address(0x02), 0);
+        let msg = Key::new(*KeyPair::new().public_key()).into_message();
+        match ctx.encrypt_message(msg, Address(0x01)) {
+            Err(SignalingError::Crash(_)) => {},
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
 }
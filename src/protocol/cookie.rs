@@ -1,18 +1,29 @@
 //! Cookies.
 
 use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
 
-use rust_sodium::randombytes::randombytes_into;
+use data_encoding::{HEXLOWER, HEXLOWER_PERMISSIVE};
 use serde::ser::{Serialize, Serializer};
 use serde::de::{Deserialize, Deserializer, Visitor, Error as SerdeError};
 
-use crate::helpers::libsodium_init_or_panic;
+use crate::crypto_backend::constant_time_eq;
+use crate::errors::{SaltyError, SaltyResult};
+
+use super::random::RandomSource;
+#[cfg(test)]
+use super::random::OsRandom;
 
 
 const COOKIE_BYTES: usize = 16;
 
 /// Newtype wrapper for the cookie bytes.
-#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+///
+/// Cookies are compared in constant time, and the `Debug` output only shows
+/// the first two bytes. Use the `Display` implementation to get the full
+/// hex string.
+#[derive(Clone)]
 pub(crate) struct Cookie([u8; COOKIE_BYTES]);
 
 impl Cookie {
@@ -23,24 +34,109 @@ impl Cookie {
     }
 
     /// Create a new random `Cookie`.
+    #[cfg(test)]
     pub(crate) fn random() -> Self {
-        // Make sure that libsodium is initialized
-        libsodium_init_or_panic();
+        Self::random_from(&mut OsRandom)
+    }
 
-        // Create 16 bytes of cryptographically secure random data
-        let mut rand = [0; 16];
-        randombytes_into(&mut rand);
+    /// Create a new random `Cookie` using the specified random source.
+    pub(crate) fn random_from(rng: &mut dyn RandomSource) -> Self {
+        // Create 16 bytes of random data
+        let mut rand = [0; COOKIE_BYTES];
+        rng.fill_cookie(&mut rand);
 
         // Make sure that random data was actually generated
-        assert!(!rand.iter().all(|&x| x == 0));
+        let cookie = Cookie(rand);
+        assert!(!cookie.is_zero());
 
-        Cookie(rand)
+        cookie
+    }
+
+    /// Return whether all cookie bytes are zero.
+    ///
+    /// Such a cookie indicates a broken random number generator and must be
+    /// rejected.
+    pub(crate) fn is_zero(&self) -> bool {
+        self.0.iter().all(|&x| x == 0)
     }
 
     /// Return the cookie bytes.
     pub(crate) fn as_bytes(&self) -> &[u8] {
         &self.0
     }
+
+    /// Return the cookie used in the nonces of task payloads.
+    ///
+    /// All bits of the cookie are inverted, so that the nonces of payloads
+    /// never repeat the nonces of messages, even though both use the same
+    /// session keys and their own sequence numbers.
+    pub(crate) fn payload_cookie(&self) -> Cookie {
+        let mut bytes = self.0;
+        for byte in bytes.iter_mut() {
+            *byte = !*byte;
+        }
+        Cookie(bytes)
+    }
+
+    /// Return the first two bytes as hex string, to tell cookies apart in
+    /// logs and error messages.
+    pub(crate) fn fingerprint(&self) -> String {
+        HEXLOWER.encode(&self.0[..2])
+    }
+}
+
+/// Compares the cookie bytes in constant time, so that the time taken to
+/// validate a repeated cookie does not depend on the number of matching bytes.
+impl PartialEq for Cookie {
+    fn eq(&self, other: &Cookie) -> bool {
+        constant_time_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Cookie {}
+
+impl Hash for Cookie {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
+    }
+}
+
+impl fmt::Debug for Cookie {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Cookie({}..)", self.fingerprint())
+    }
+}
+
+/// Formats the cookie as lowercase hex string.
+impl fmt::Display for Cookie {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::LowerHex::fmt(self, f)
+    }
+}
+
+impl fmt::LowerHex for Cookie {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&HEXLOWER.encode(&self.0))
+    }
+}
+
+/// Parses a case insensitive hex string.
+impl FromStr for Cookie {
+    type Err = SaltyError;
+
+    fn from_str(s: &str) -> SaltyResult<Self> {
+        let bytes = HEXLOWER_PERMISSIVE.decode(s.as_bytes())
+            .map_err(|e| SaltyError::Decode(format!("Could not decode cookie hex string: {}", e)))?;
+        if bytes.len() != COOKIE_BYTES {
+            return Err(SaltyError::Decode(format!(
+                "Invalid cookie hex string: Must contain {} bytes, but contains {}",
+                COOKIE_BYTES, bytes.len(),
+            )));
+        }
+        let mut cookie = [0; COOKIE_BYTES];
+        cookie.copy_from_slice(&bytes);
+        Ok(Cookie(cookie))
+    }
 }
 
 /// Waiting for https://github.com/3Hren/msgpack-rust/issues/129
@@ -71,6 +167,12 @@ impl<'de> Visitor<'de> for CookieVisitor {
     fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Self::Value, E> where E: SerdeError {
         self.visit_bytes(&v)
     }
+
+    /// Some implementations encode the cookie as msgpack `str` (formerly
+    /// `raw`) instead of `bin`.
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E> where E: SerdeError {
+        self.visit_bytes(v.as_bytes())
+    }
 }
 
 /// Waiting for https://github.com/3Hren/msgpack-rust/issues/129
@@ -90,10 +192,11 @@ pub(crate) struct CookiePair {
 }
 
 impl CookiePair {
-    /// Create a new [`CookiePair`](struct.CookiePair.html).
-    pub(crate) fn new() -> Self {
+    /// Create a new [`CookiePair`](struct.CookiePair.html) using the
+    /// specified random source.
+    pub(crate) fn with_rng(rng: &mut dyn RandomSource) -> Self {
         CookiePair {
-            ours: Cookie::random(),
+            ours: Cookie::random_from(rng),
             theirs: None,
         }
     }
@@ -133,6 +236,45 @@ mod tests {
         ]);
     }
 
+    #[test]
+    fn cookie_hex() {
+        let cookie = Cookie::new([1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 255]);
+        assert_eq!(cookie.to_string(), "0102030405060708090a0b0c0d0e0fff");
+        assert_eq!(format!("{:x}", cookie), cookie.to_string());
+        assert_eq!("0102030405060708090A0B0C0D0E0FFF".parse::<Cookie>().unwrap(), cookie);
+        assert_eq!(
+            "0102".parse::<Cookie>().unwrap_err().to_string(),
+            "Decoding error: Invalid cookie hex string: Must contain 16 bytes, but contains 2"
+        );
+        assert!("zz".parse::<Cookie>().is_err());
+    }
+
+    #[test]
+    fn cookie_eq() {
+        let cookie = Cookie::new([1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16]);
+        assert_eq!(cookie, cookie.clone());
+        for i in 0..COOKIE_BYTES {
+            let mut bytes = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16];
+            bytes[i] = 0;
+            assert_ne!(cookie, Cookie::new(bytes));
+        }
+    }
+
+    /// The debug output does not contain the whole cookie.
+    #[test]
+    fn payload_cookie() {
+        let cookie = Cookie::new([0x0f; 16]);
+        assert_eq!(cookie.payload_cookie().as_bytes(), &[0xf0; 16]);
+        assert!(cookie.payload_cookie() != cookie);
+    }
+
+    #[test]
+    fn cookie_debug() {
+        let cookie = Cookie::new([1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 255]);
+        assert_eq!(format!("{:?}", cookie), "Cookie(0102..)");
+        assert_eq!(format!("{:?}", Some(cookie)), "Some(Cookie(0102..))");
+    }
+
     /// The cookie deserializes from raw bytes.
     #[test]
     fn cookie_deserialize() {
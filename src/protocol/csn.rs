@@ -6,10 +6,17 @@
 
 use std::cmp;
 
-use rust_sodium::randombytes::randombytes;
+use byteorder::{BigEndian, ByteOrder};
 
 use crate::errors::{SignalingError, SignalingResult};
-use crate::helpers::libsodium_init_or_panic;
+
+use super::random::RandomSource;
+#[cfg(test)]
+use super::random::OsRandom;
+
+
+/// The number of bytes of a serialized combined sequence number.
+pub(crate) const CSN_BYTES: usize = 6;
 
 
 /// This type handles the overflow checking of the 48 bit combined sequence
@@ -35,12 +42,17 @@ impl CombinedSequence {
     ///
     /// The overflow number will be initialized to 0, while a cryptographically
     /// secure random value will be generated for the sequence number.
+    #[cfg(test)]
     pub(crate) fn random() -> Self {
-        // Make sure that libsodium is initialized
-        libsodium_init_or_panic();
+        Self::random_from(&mut OsRandom)
+    }
 
-        // Create 32 bits of cryptographically secure random data
-        let rand = randombytes(4);
+    /// Create a new random `CombinedSequence` using the specified random
+    /// source.
+    pub(crate) fn random_from(rng: &mut dyn RandomSource) -> Self {
+        // Create 32 bits of random data
+        let mut rand = [0; 4];
+        rng.fill_sequence_number(&mut rand);
 
         // Create combined sequence from that data
         let overflow = 0u16;
@@ -57,6 +69,13 @@ impl CombinedSequence {
         (u64::from(self.overflow) << 32) + u64::from(self.sequence)
     }
 
+    /// Return whether the combined sequence number cannot be incremented
+    /// anymore.
+    #[cfg_attr(not(feature = "connect-tokio"), allow(dead_code))]
+    pub(crate) fn is_exhausted(&self) -> bool {
+        self.overflow == u16::max_value() && self.sequence == u32::max_value()
+    }
+
     /// Increment the `CombinedSequence` and return a snapshot.
     ///
     /// This will fail if the overflow number overflows. This is extremely
@@ -140,6 +159,25 @@ impl CombinedSequenceSnapshot {
         (u64::from(self.overflow) << 32) + u64::from(self.sequence)
     }
 
+    /// Parse the combined sequence number from the 6 bytes used in nonces
+    /// and `send-error` ids: the overflow number followed by the sequence
+    /// number, both big endian.
+    ///
+    /// Panics if `bytes` does not contain exactly `CSN_BYTES` bytes.
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Self {
+        assert_eq!(bytes.len(), CSN_BYTES, "Invalid combined sequence number length");
+        CombinedSequenceSnapshot::new(BigEndian::read_u16(&bytes[..2]), BigEndian::read_u32(&bytes[2..]))
+    }
+
+    /// Serialize the combined sequence number, see
+    /// [`from_bytes`](#method.from_bytes).
+    pub(crate) fn to_bytes(&self) -> [u8; CSN_BYTES] {
+        let mut bytes = [0; CSN_BYTES];
+        BigEndian::write_u16(&mut bytes[..2], self.overflow_number());
+        BigEndian::write_u32(&mut bytes[2..], self.sequence_number());
+        bytes
+    }
+
 }
 
 impl<'a> From<&'a CombinedSequence> for CombinedSequenceSnapshot {
@@ -186,9 +224,16 @@ pub(crate) struct CombinedSequencePair {
 
 impl CombinedSequencePair {
     /// Create a new [`CombinedSequencePair`](struct.CombinedSequencePair.html).
+    #[cfg(test)]
     pub(crate) fn new() -> Self {
+        Self::with_rng(&mut OsRandom)
+    }
+
+    /// Create a new [`CombinedSequencePair`](struct.CombinedSequencePair.html)
+    /// using the specified random source.
+    pub(crate) fn with_rng(rng: &mut dyn RandomSource) -> Self {
         CombinedSequencePair {
-            ours: CombinedSequence::random(),
+            ours: CombinedSequence::random_from(rng),
             theirs: None,
         }
     }
@@ -265,6 +310,42 @@ mod tests {
         assert_eq!(new.combined_sequence_number(), (::std::u32::MAX as u64) + 1);
     }
 
+    #[test]
+    fn increment_after_sequence_overflow() {
+        let mut csn = CombinedSequence::new(1, ::std::u32::MAX - 1);
+        let snapshots: Vec<_> = (0..3).map(|_| csn.increment().unwrap()).collect();
+        assert_eq!(snapshots, vec![
+            CombinedSequenceSnapshot::new(1, ::std::u32::MAX),
+            CombinedSequenceSnapshot::new(2, 0),
+            CombinedSequenceSnapshot::new(2, 1),
+        ]);
+        assert_eq!(csn.combined_sequence_number(), (2 << 32) + 1);
+    }
+
+    /// The overflow number is more significant than the sequence number.
+    #[test]
+    fn compare_combined_value() {
+        let before_wrap = CombinedSequenceSnapshot::new(0, ::std::u32::MAX);
+        let after_wrap = CombinedSequenceSnapshot::new(1, 0);
+        assert!(before_wrap < after_wrap);
+        assert!(CombinedSequence::new(0, ::std::u32::MAX) < after_wrap);
+        assert!(CombinedSequence::new(1, 0) == after_wrap);
+        assert!(CombinedSequenceSnapshot::new(1, 5) > CombinedSequenceSnapshot::new(0, 6));
+    }
+
+    /// The overflow number and the sequence number are serialized in network
+    /// byte order, i.e. as a 48 bit big endian number.
+    #[test]
+    fn byte_order() {
+        let csn = CombinedSequenceSnapshot::new(0x0102, 0x0304_0506);
+        assert_eq!(csn.to_bytes(), [0x01, 0x02, 0x03, 0x04, 0x05, 0x06]);
+        assert_eq!(CombinedSequenceSnapshot::from_bytes(&[0x01, 0x02, 0x03, 0x04, 0x05, 0x06]), csn);
+
+        let mut combined = [0; 8];
+        BigEndian::write_u64(&mut combined, csn.combined_sequence_number());
+        assert_eq!(&combined[2..], &csn.to_bytes()[..]);
+    }
+
     #[test]
     fn increment_with_overflow_overflow() {
         let mut old = CombinedSequence::new(::std::u16::MAX, ::std::u32::MAX);
@@ -0,0 +1,78 @@
+//! Decryption of task messages outside of the signaling.
+//!
+//! Once the peer handshake is done, decrypting task messages is the most
+//! expensive part of handling them. To spread this work over several
+//! threads, an incoming message can be split into three steps:
+//!
+//! 1. [`Signaling::prepare_message`](../trait.Signaling.html#method.prepare_message)
+//!    validates the nonce (including the sequence number) in the order the
+//!    messages arrive and returns a [`DecryptJob`](struct.DecryptJob.html).
+//! 2. The job is run on any thread.
+//! 3. [`Signaling::handle_decrypted`](../trait.Signaling.html#method.handle_decrypted)
+//!    handles the decrypted message. The caller must pass the decrypted
+//!    messages in the order they were prepared.
+//!
+//! Since the nonces are validated before decryption in both cases, this
+//! behaves exactly like handling the messages one by one.
+
+use rmpv::Value;
+
+use crate::boxes::{ByteBox, OpenBox};
+use crate::crypto::{KeyPair, PublicKey};
+use crate::errors::SignalingResult;
+use crate::protocol::HandleActions;
+use crate::transcript::TranscriptEntry;
+
+
+/// The result of preparing an incoming message.
+#[derive(Debug)]
+pub(crate) enum PreparedMessage {
+    /// The message is not a task message and must be passed to
+    /// `handle_message`, in order with the decrypted messages.
+    Sequential(ByteBox),
+    /// A validated task message that can be decrypted on any thread.
+    Decrypt(DecryptJob),
+    /// The message was dropped during validation. The actions (if any)
+    /// must be processed in order with the other messages.
+    Handled(HandleActions),
+}
+
+
+/// A validated task message along with the keys needed to decrypt it.
+#[derive(Debug)]
+pub(crate) struct DecryptJob {
+    bbox: ByteBox,
+    keypair: KeyPair,
+    peer_session_key: PublicKey,
+    entry: Option<TranscriptEntry>,
+}
+
+impl DecryptJob {
+    pub(crate) fn new(bbox: ByteBox, keypair: &KeyPair, peer_session_key: PublicKey) -> Self {
+        let keypair = KeyPair::from_keypair(*keypair.public_key(), keypair.private_key().clone());
+        DecryptJob { bbox, keypair, peer_session_key, entry: None }
+    }
+
+    /// Attach the transcript entry of the message, to be completed once the
+    /// decrypted message is handled.
+    pub(crate) fn with_entry(mut self, entry: Option<TranscriptEntry>) -> Self {
+        self.entry = entry;
+        self
+    }
+
+    /// Decrypt and decode the message.
+    pub(crate) fn run(self) -> DecryptedMessage {
+        DecryptedMessage {
+            result: OpenBox::<Value>::decrypt(self.bbox, &self.keypair, &self.peer_session_key, self.entry.is_some()),
+            entry: self.entry,
+        }
+    }
+}
+
+
+/// A task message that was decrypted by a [`DecryptJob`](struct.DecryptJob.html).
+#[derive(Debug)]
+pub(crate) struct DecryptedMessage {
+    pub(crate) result: SignalingResult<OpenBox<Value>>,
+    pub(crate) entry: Option<TranscriptEntry>,
+}
@@ -0,0 +1,207 @@
+//! Application level keepalive for the authenticated peer.
+//!
+//! WebSocket pings only prove that the server is reachable. To detect an
+//! unresponsive peer, a `heartbeat` task message is sent to the peer at a
+//! fixed interval, which the peer answers with a `heartbeat-ack` message
+//! carrying the same id. The round trip time of every acknowledged heartbeat
+//! is reported as latency sample.
+//!
+//! Some peers (like the WebRTC task implementations) send `ping` task
+//! messages instead. Unless the task handles these itself, they are answered
+//! with a `pong` message that echoes all other fields.
+//!
+//! Note: Heartbeats are not part of the SaltyRTC specification. Peers that
+//! don't implement them will never acknowledge a heartbeat, so only enable
+//! sending heartbeats if the peer is known to support them. Incoming
+//! heartbeats are always acknowledged.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::time::{Duration, Instant};
+
+use rmpv::Value;
+
+use crate::Event;
+use crate::errors::{SignalingError, SignalingResult};
+
+
+/// The type of a heartbeat message.
+pub(crate) const TYPE_HEARTBEAT: &str = "heartbeat";
+
+/// The type of a heartbeat acknowledgement message.
+pub(crate) const TYPE_HEARTBEAT_ACK: &str = "heartbeat-ack";
+
+/// The type of a ping message sent by the peer.
+pub(crate) const TYPE_PING: &str = "ping";
+
+/// The type of the answer to a ping message.
+pub(crate) const TYPE_PONG: &str = "pong";
+
+
+/// Heartbeat state, tracking missed acknowledgements.
+#[derive(Debug, Default)]
+pub(crate) struct Heartbeat {
+    /// The id of the next heartbeat.
+    next_id: u32,
+    /// The id and send time of the heartbeat that has not been acknowledged
+    /// yet.
+    pending: Option<(u32, Instant)>,
+    /// The number of consecutive heartbeats that were not acknowledged.
+    missed: u32,
+    /// Whether the peer has been reported as unresponsive.
+    unresponsive: bool,
+}
+
+impl Heartbeat {
+    /// Called when a new heartbeat should be sent at `now`.
+    ///
+    /// Return the heartbeat message and, if the number of consecutive missed
+    /// heartbeats reached the `miss_threshold`, a `PeerUnresponsive` event.
+    pub(crate) fn tick(&mut self, miss_threshold: u32, now: Instant) -> (Value, Option<Event>) {
+        let mut event = None;
+        if self.pending.is_some() {
+            self.missed += 1;
+            if self.missed >= miss_threshold {
+                debug!("Peer missed {} heartbeats", self.missed);
+                self.unresponsive = true;
+                event = Some(Event::PeerUnresponsive(self.missed));
+            }
+        }
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.pending = Some((id, now));
+        (heartbeat_message(TYPE_HEARTBEAT, id), event)
+    }
+
+    /// Called when a heartbeat acknowledgement arrives at `now`.
+    ///
+    /// Return a `PeerResponsive` event if the peer was previously reported
+    /// as unresponsive, followed by a `PeerLatency` event with the round
+    /// trip time.
+    pub(crate) fn handle_ack(&mut self, map: &HashMap<String, Value>, now: Instant) -> SignalingResult<Vec<Event>> {
+        let id = heartbeat_id(map)?;
+        let sent_at = match self.pending {
+            Some((pending_id, sent_at)) if pending_id == id => sent_at,
+            _ => {
+                debug!("Ignoring acknowledgement for outdated heartbeat {}", id);
+                return Ok(vec![]);
+            },
+        };
+        self.pending = None;
+        self.missed = 0;
+        let mut events = vec![];
+        if self.unresponsive {
+            self.unresponsive = false;
+            events.push(Event::PeerResponsive);
+        }
+        let latency = if now > sent_at { now - sent_at } else { Duration::from_secs(0) };
+        events.push(Event::PeerLatency(latency));
+        Ok(events)
+    }
+}
+
+/// Create the acknowledgement for an incoming heartbeat.
+pub(crate) fn ack_message(map: &HashMap<String, Value>) -> SignalingResult<Value> {
+    Ok(heartbeat_message(TYPE_HEARTBEAT_ACK, heartbeat_id(map)?))
+}
+
+/// Create the answer to an incoming ping, echoing all fields except the type.
+pub(crate) fn pong_message(map: HashMap<String, Value>) -> Value {
+    let mut pairs: Vec<(Value, Value)> = map.into_iter()
+        .filter(|(key, _)| key != "type")
+        .map(|(key, value)| (Value::from(key), value))
+        .collect();
+    pairs.sort_by(|a, b| a.0.as_str().cmp(&b.0.as_str()));
+    pairs.insert(0, (Value::from("type"), Value::from(TYPE_PONG)));
+    Value::Map(pairs)
+}
+
+fn heartbeat_message(msg_type: &str, id: u32) -> Value {
+    Value::Map(vec![
+        (Value::from("type"), Value::from(msg_type)),
+        (Value::from("id"), Value::from(id)),
+    ])
+}
+
+fn heartbeat_id(map: &HashMap<String, Value>) -> SignalingResult<u32> {
+    map.get("id")
+        .and_then(Value::as_u64)
+        .and_then(|id| u32::try_from(id).ok())
+        .ok_or_else(|| SignalingError::InvalidMessage("Heartbeat message does not contain a valid id".into()))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_map(value: Value) -> HashMap<String, Value> {
+        match value {
+            Value::Map(pairs) => pairs.into_iter()
+                .map(|(k, v)| (k.as_str().unwrap().to_string(), v))
+                .collect(),
+            other => panic!("Expected map, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn ack_resets_missed() {
+        let now = Instant::now();
+        let mut heartbeat = Heartbeat::default();
+        let (msg, event) = heartbeat.tick(2, now);
+        assert_eq!(event, None);
+        let ack = to_map(ack_message(&to_map(msg)).unwrap());
+        assert_eq!(ack.get("type"), Some(&Value::from(TYPE_HEARTBEAT_ACK)));
+        let events = heartbeat.handle_ack(&ack, now + Duration::from_millis(120)).unwrap();
+        assert_eq!(events, vec![Event::PeerLatency(Duration::from_millis(120))]);
+
+        // A duplicate ack does not produce another latency sample
+        assert_eq!(heartbeat.handle_ack(&ack, now), Ok(vec![]));
+        assert_eq!(heartbeat.tick(2, now).1, None);
+        assert_eq!(heartbeat.tick(2, now).1, None);
+    }
+
+    #[test]
+    fn unresponsive_after_threshold() {
+        let now = Instant::now();
+        let mut heartbeat = Heartbeat::default();
+        assert_eq!(heartbeat.tick(2, now).1, None);
+        assert_eq!(heartbeat.tick(2, now).1, None);
+        assert_eq!(heartbeat.tick(2, now).1, Some(Event::PeerUnresponsive(2)));
+        let (msg, event) = heartbeat.tick(2, now);
+        assert_eq!(event, Some(Event::PeerUnresponsive(3)));
+
+        // Outdated acks are ignored
+        let mut outdated = to_map(ack_message(&to_map(msg.clone())).unwrap());
+        outdated.insert("id".into(), Value::from(0));
+        assert_eq!(heartbeat.handle_ack(&outdated, now), Ok(vec![]));
+
+        let ack = to_map(ack_message(&to_map(msg)).unwrap());
+        let events = heartbeat.handle_ack(&ack, now).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0], Event::PeerResponsive);
+        assert_eq!(heartbeat.tick(2, now).1, None);
+    }
+
+    #[test]
+    fn pong_echoes_fields() {
+        let mut ping = HashMap::new();
+        ping.insert("type".to_string(), Value::from(TYPE_PING));
+        ping.insert("timestamp".to_string(), Value::from(1234));
+        ping.insert("id".to_string(), Value::from("abc"));
+        assert_eq!(pong_message(ping), Value::Map(vec![
+            (Value::from("type"), Value::from(TYPE_PONG)),
+            (Value::from("id"), Value::from("abc")),
+            (Value::from("timestamp"), Value::from(1234)),
+        ]));
+    }
+
+    #[test]
+    fn invalid_id() {
+        let mut map = HashMap::new();
+        map.insert("type".to_string(), Value::from(TYPE_HEARTBEAT));
+        assert!(ack_message(&map).is_err());
+        map.insert("id".to_string(), Value::from("one"));
+        assert!(ack_message(&map).is_err());
+    }
+}
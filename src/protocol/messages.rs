@@ -5,17 +5,25 @@
 //! All message types own their values. This choice was made to simplify the
 //! use and implementation of the library. Some values may be optimized to take
 //! references in a future version.
+//!
+//! The `Debug` and `Display` implementations never print a public key in
+//! full, but only the start of its fingerprint. The key sent in a 'token'
+//! message is redacted entirely. This way, trace logs can be shared
+//! without revealing which keys were used.
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::From;
+use std::fmt;
 
 use rmp_serde as rmps;
-use rmpv::Value;
-use serde::{Deserialize, Serialize};
+use rmpv::{ext, Value};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde::de::{Error as SerdeError, MapAccess, Visitor};
 
 use crate::CloseCode;
 use crate::crypto_types::{PublicKey, SignedKeys};
 use crate::errors::{SignalingError, SignalingResult};
+use crate::fingerprint::Fingerprint;
 use crate::tasks::Tasks;
 
 use super::{Address, Cookie};
@@ -68,9 +76,49 @@ impl Message {
         Ok(rmps::from_slice(bytes)?)
     }
 
+    /// Decode only the `type` field of a message from msgpack bytes.
+    ///
+    /// This allows rejecting unexpected messages without decoding them.
+    pub(crate) fn peek_type(bytes: &[u8]) -> SignalingResult<String> {
+        let tag: MessageType = rmps::from_slice(bytes)?;
+        Ok(tag.msg_type)
+    }
+
     /// Convert this message to msgpack bytes.
+    #[cfg(any(test, feature = "benchmarks"))]
     pub(crate) fn to_msgpack(&self) -> Vec<u8> {
-        rmps::to_vec_named(&self).expect("Serialization failed")
+        rmps::to_vec_named(&self.to_value()).expect("Serialization failed")
+    }
+
+    /// Convert this message to a msgpack value.
+    ///
+    /// Because of the extra fields, messages are serialized as maps of
+    /// unknown length, which `rmp_serde` can't encode directly.
+    pub(crate) fn to_value(&self) -> Value {
+        ext::to_value(self).expect("Serialization failed")
+    }
+
+    /// Return the fields of the message that this implementation does not
+    /// know about.
+    ///
+    /// Other implementations may add vendor specific fields to the messages.
+    /// They are preserved when decoding and included again when encoding.
+    pub(crate) fn extra_fields(&self) -> &ExtraFields {
+        match *self {
+            Message::ClientHello(ref msg) => &msg.extra,
+            Message::ServerHello(ref msg) => &msg.extra,
+            Message::ClientAuth(ref msg) => &msg.extra,
+            Message::ServerAuth(ref msg) => &msg.extra,
+            Message::NewInitiator(ref msg) => &msg.extra,
+            Message::NewResponder(ref msg) => &msg.extra,
+            Message::DropResponder(ref msg) => &msg.extra,
+            Message::SendError(ref msg) => &msg.extra,
+            Message::Disconnected(ref msg) => &msg.extra,
+            Message::Token(ref msg) => &msg.extra,
+            Message::Key(ref msg) => &msg.extra,
+            Message::Auth(ref msg) => &msg.extra,
+            Message::Close(ref msg) => &msg.extra,
+        }
     }
 
     /// Return the type of the contained message.
@@ -94,6 +142,177 @@ impl Message {
             Message::Close(_) => "close",
         }
     }
+
+    /// Return whether a message of this type can be decoded.
+    pub(crate) fn is_known_type(msg_type: &str) -> bool {
+        const KNOWN_TYPES: &[&str] = &[
+            "client-hello", "server-hello", "client-auth", "server-auth",
+            "new-initiator", "new-responder", "drop-responder", "send-error",
+            "disconnected", "token", "key", "auth", "close",
+        ];
+        KNOWN_TYPES.contains(&msg_type)
+    }
+
+    /// Return the contained message for formatting.
+    fn inner(&self) -> &dyn fmt::Debug {
+        match *self {
+            Message::ClientHello(ref msg) => msg,
+            Message::ServerHello(ref msg) => msg,
+            Message::ClientAuth(ref msg) => msg,
+            Message::ServerAuth(ref msg) => msg,
+            Message::NewInitiator(ref msg) => msg,
+            Message::NewResponder(ref msg) => msg,
+            Message::DropResponder(ref msg) => msg,
+            Message::SendError(ref msg) => msg,
+            Message::Disconnected(ref msg) => msg,
+            Message::Token(ref msg) => msg,
+            Message::Key(ref msg) => msg,
+            Message::Auth(ref msg) => msg,
+            Message::Close(ref msg) => msg,
+        }
+    }
+}
+
+/// Formats the message type followed by the fields, e.g.
+/// `server-hello: ServerHello { key: PublicKey(3c5f0a1e..), .. }`.
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {:?}", self.get_type(), self.inner())
+    }
+}
+
+/// Formats a public key as the first four bytes of its fingerprint.
+struct KeyFmt<'a>(&'a PublicKey);
+
+impl<'a> fmt::Debug for KeyFmt<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PublicKey({}..)", &self.0.fingerprint().to_hex()[..8])
+    }
+}
+
+/// Formats a value that must not appear in logs.
+struct Redacted;
+
+impl fmt::Debug for Redacted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+/// The `type` field of a message. All other fields are ignored.
+#[derive(Debug, Deserialize)]
+struct MessageType {
+    #[serde(rename = "type")]
+    msg_type: String,
+}
+
+/// Return the `type` field of a task message value, if present.
+pub(crate) fn value_type(value: &Value) -> Option<&str> {
+    value.as_map()
+        .and_then(|map| map.iter().find(|(k, _)| k.as_str() == Some("type")))
+        .and_then(|(_, v)| v.as_str())
+}
+
+/// The entries of a message that don't correspond to a known field.
+///
+/// Other SaltyRTC implementations may add vendor specific fields to the
+/// messages. They are preserved so that applications and tasks can read
+/// them. Entries with keys that are not strings are dropped.
+#[derive(Debug, Clone, PartialEq, Default, Serialize)]
+pub struct ExtraFields(BTreeMap<String, Value>);
+
+impl ExtraFields {
+    /// Return the value of the field with the specified name.
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.0.get(name)
+    }
+
+    /// Return the number of fields.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Return whether there are no fields.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Add a field. Used in testing.
+    #[cfg(test)]
+    pub(crate) fn insert(&mut self, name: &str, value: Value) {
+        self.0.insert(name.into(), value);
+    }
+
+    /// Iterate over the fields, sorted by name.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Value)> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v))
+    }
+}
+
+struct ExtraFieldsVisitor;
+
+impl<'de> Visitor<'de> for ExtraFieldsVisitor {
+    type Value = ExtraFields;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a map")
+    }
+
+    fn visit_map<A>(self, mut access: A) -> Result<Self::Value, A::Error> where A: MapAccess<'de> {
+        let mut fields = BTreeMap::new();
+        while let Some((key, value)) = access.next_entry::<Value, Value>()? {
+            if let Value::String(key) = key {
+                if let Some(key) = key.into_str() {
+                    fields.insert(key, value);
+                }
+            }
+        }
+        Ok(ExtraFields(fields))
+    }
+}
+
+impl<'de> Deserialize<'de> for ExtraFields {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        deserializer.deserialize_map(ExtraFieldsVisitor)
+    }
+}
+
+/// Deserialization of public keys in messages.
+///
+/// Some implementations encode binary data as msgpack `str` (formerly `raw`)
+/// instead of `bin`. Keys are accepted in both encodings, but are always
+/// encoded as `bin`.
+mod any_bytes_key {
+    use super::*;
+
+    struct KeyVisitor;
+
+    impl<'de> Visitor<'de> for KeyVisitor {
+        type Value = PublicKey;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("32 bytes of binary data")
+        }
+
+        fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E> where E: SerdeError {
+            PublicKey::from_slice(v).ok_or_else(|| SerdeError::invalid_length(v.len(), &self))
+        }
+
+        fn visit_str<E>(self, v: &str) -> Result<Self::Value, E> where E: SerdeError {
+            self.visit_bytes(v.as_bytes())
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct OptionalKey(#[serde(deserialize_with = "deserialize")] PublicKey);
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PublicKey, D::Error> {
+        deserializer.deserialize_bytes(KeyVisitor)
+    }
+
+    pub(super) fn deserialize_option<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<PublicKey>, D::Error> {
+        Ok(Option::<OptionalKey>::deserialize(deserializer)?.map(|key| key.0))
+    }
 }
 
 /// Implement conversion traits to wrap a type in a `Message`.
@@ -122,6 +341,7 @@ impl_message_wrapping!(NewInitiator, Message::NewInitiator);
 impl_message_wrapping!(NewResponder, Message::NewResponder);
 impl_message_wrapping!(DropResponder, Message::DropResponder);
 impl_message_wrapping!(SendError, Message::SendError);
+impl_message_wrapping!(Disconnected, Message::Disconnected);
 impl_message_wrapping!(Token, Message::Token);
 impl_message_wrapping!(Key, Message::Key);
 impl_message_wrapping!(Auth, Message::Auth);
@@ -129,57 +349,94 @@ impl_message_wrapping!(Close, Message::Close);
 
 
 /// The client-hello message.
-#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
 pub(crate) struct ClientHello {
+    #[serde(deserialize_with = "any_bytes_key::deserialize")]
     pub(crate) key: PublicKey,
+    #[serde(flatten)]
+    pub(crate) extra: ExtraFields,
+}
+
+impl fmt::Debug for ClientHello {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ClientHello")
+            .field("key", &KeyFmt(&self.key))
+            .field("extra", &self.extra)
+            .finish()
+    }
 }
 
 impl ClientHello {
     pub(crate) fn new(key: PublicKey) -> Self {
-        Self { key }
+        Self { key, extra: ExtraFields::default() }
     }
 
     /// Create a new instance with dummy data. Used in testing.
     #[cfg(test)]
     pub(crate) fn random() -> Self {
-        crate::helpers::libsodium_init_or_panic();
         let mut bytes = [0u8; 32];
-        ::rust_sodium::randombytes::randombytes_into(&mut bytes);
-        Self { key: PublicKey::from_slice(&bytes).unwrap() }
+        crate::crypto_backend::backend().random_bytes(&mut bytes);
+        Self::new(PublicKey(bytes))
     }
 }
 
 /// The server-hello message.
-#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
 pub(crate) struct ServerHello {
+    #[serde(deserialize_with = "any_bytes_key::deserialize")]
     pub(crate) key: PublicKey,
+    #[serde(flatten)]
+    pub(crate) extra: ExtraFields,
+}
+
+impl fmt::Debug for ServerHello {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ServerHello")
+            .field("key", &KeyFmt(&self.key))
+            .field("extra", &self.extra)
+            .finish()
+    }
 }
 
 impl ServerHello {
-    #[cfg(test)]
+    #[cfg(any(test, feature = "benchmarks"))]
     pub(crate) fn new(key: PublicKey) -> Self {
-        Self { key }
+        Self { key, extra: ExtraFields::default() }
     }
 
     /// Create a new instance with dummy data. Used in testing.
     #[cfg(test)]
     pub(crate) fn random() -> Self {
-        crate::helpers::libsodium_init_or_panic();
         let mut bytes = [0u8; 32];
-        ::rust_sodium::randombytes::randombytes_into(&mut bytes);
-        Self { key: PublicKey::from_slice(&bytes).unwrap() }
+        crate::crypto_backend::backend().random_bytes(&mut bytes);
+        Self::new(PublicKey(bytes))
     }
 }
 
 
 /// The client-auth message.
-#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
 pub(crate) struct ClientAuth {
     pub(crate) your_cookie: Cookie,
     pub(crate) subprotocols: Vec<String>,
     pub(crate) ping_interval: u32,
+    #[serde(default, deserialize_with = "any_bytes_key::deserialize_option")]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) your_key: Option<PublicKey>,
+    #[serde(flatten)]
+    pub(crate) extra: ExtraFields,
+}
+
+impl fmt::Debug for ClientAuth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ClientAuth")
+            .field("your_cookie", &self.your_cookie)
+            .field("subprotocols", &self.subprotocols)
+            .field("ping_interval", &self.ping_interval)
+            .field("your_key", &self.your_key.as_ref().map(KeyFmt))
+            .field("extra", &self.extra)
+            .finish()
+    }
 }
 
 
@@ -193,42 +450,58 @@ pub(crate) struct ServerAuth {
     pub(crate) responders: Option<Vec<Address>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) initiator_connected: Option<bool>,
+    #[serde(flatten)]
+    pub(crate) extra: ExtraFields,
 }
 
 impl ServerAuth {
     /// Create a new ServerAuth message targeted at an initiator.
-    #[cfg(test)]
+    #[cfg(any(test, feature = "benchmarks"))]
     pub(crate) fn for_initiator(your_cookie: Cookie, signed_keys: Option<SignedKeys>, responders: Vec<Address>) -> Self {
         Self {
             your_cookie,
             signed_keys,
             responders: Some(responders),
             initiator_connected: None,
+            extra: ExtraFields::default(),
         }
     }
 
     /// Create a new ServerAuth message targeted at a responder.
-    #[cfg(test)]
+    #[cfg(any(test, feature = "benchmarks"))]
     pub(crate) fn for_responder(your_cookie: Cookie, signed_keys: Option<SignedKeys>, initiator_connected: bool) -> Self {
         Self {
             your_cookie,
             signed_keys,
             responders: None,
             initiator_connected: Some(initiator_connected),
+            extra: ExtraFields::default(),
         }
     }
 }
 
 
 /// Sent by the server to all responders when a new initiator joins.
-#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
-pub(crate) struct NewInitiator;
+#[derive(Debug, Clone, PartialEq, Default, Deserialize, Serialize)]
+pub(crate) struct NewInitiator {
+    #[serde(flatten)]
+    pub(crate) extra: ExtraFields,
+}
 
 
 /// Sent by the server to the initiator when a new responder joins.
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub(crate) struct NewResponder {
     pub(crate) id: Address,
+    #[serde(flatten)]
+    pub(crate) extra: ExtraFields,
+}
+
+impl NewResponder {
+    #[allow(dead_code)]
+    pub(crate) fn new(id: Address) -> Self {
+        Self { id, extra: ExtraFields::default() }
+    }
 }
 
 
@@ -258,12 +531,14 @@ pub(crate) struct DropResponder {
     pub(crate) id: Address,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) reason: Option<u16>,
+    #[serde(flatten)]
+    pub(crate) extra: ExtraFields,
 }
 
 impl DropResponder {
     /// Create a new `DropResponder` message with a reason code.
     pub(crate) fn with_reason(id: Address, reason: DropReason) -> Self {
-        Self { id, reason: Some(reason.into()) }
+        Self { id, reason: Some(reason.into()), extra: ExtraFields::default() }
     }
 }
 
@@ -272,6 +547,15 @@ impl DropResponder {
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub(crate) struct SendError {
     pub(crate) id: SendErrorId,
+    #[serde(flatten)]
+    pub(crate) extra: ExtraFields,
+}
+
+impl SendError {
+    #[allow(dead_code)]
+    pub(crate) fn new(id: SendErrorId) -> Self {
+        Self { id, extra: ExtraFields::default() }
+    }
 }
 
 
@@ -279,50 +563,82 @@ pub(crate) struct SendError {
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub(crate) struct Disconnected {
     pub(crate) id: Address,
+    #[serde(flatten)]
+    pub(crate) extra: ExtraFields,
 }
 
 impl Disconnected {
     #[allow(dead_code)]
     pub(crate) fn new(id: Address) -> Self {
-        Self { id }
+        Self { id, extra: ExtraFields::default() }
     }
 }
 
 
 /// The token message.
-#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
 pub(crate) struct Token {
+    #[serde(deserialize_with = "any_bytes_key::deserialize")]
     pub(crate) key: PublicKey,
+    #[serde(flatten)]
+    pub(crate) extra: ExtraFields,
+}
+
+impl fmt::Debug for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Token")
+            .field("key", &Redacted)
+            .field("extra", &self.extra)
+            .finish()
+    }
 }
 
 impl Token {
+    pub(crate) fn new(key: PublicKey) -> Self {
+        Self { key, extra: ExtraFields::default() }
+    }
+
     /// Create a new instance with dummy data. Used in testing.
     #[cfg(test)]
     pub(crate) fn random() -> Self {
-        crate::helpers::libsodium_init_or_panic();
         let mut bytes = [0u8; 32];
-        ::rust_sodium::randombytes::randombytes_into(&mut bytes);
-        Self { key: PublicKey::from_slice(&bytes).unwrap() }
+        crate::crypto_backend::backend().random_bytes(&mut bytes);
+        Self::new(PublicKey(bytes))
     }
 }
 
 
 /// The key message.
-#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
 pub(crate) struct Key {
     // TODO (#9): Do we want to differentiate between permanent key and session key
     // in the type system?
+    #[serde(deserialize_with = "any_bytes_key::deserialize")]
     pub(crate) key: PublicKey,
+    #[serde(flatten)]
+    pub(crate) extra: ExtraFields,
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Key")
+            .field("key", &KeyFmt(&self.key))
+            .field("extra", &self.extra)
+            .finish()
+    }
 }
 
 impl Key {
+    pub(crate) fn new(key: PublicKey) -> Self {
+        Self { key, extra: ExtraFields::default() }
+    }
+
     /// Create a new instance with dummy data. Used in testing.
     #[cfg(test)]
     pub(crate) fn random() -> Self {
-        crate::helpers::libsodium_init_or_panic();
         let mut bytes = [0u8; 32];
-        ::rust_sodium::randombytes::randombytes_into(&mut bytes);
-        Self { key: PublicKey::from_slice(&bytes).unwrap() }
+        crate::crypto_backend::backend().random_bytes(&mut bytes);
+        Self::new(PublicKey(bytes))
     }
 }
 
@@ -336,6 +652,8 @@ pub(crate) struct Auth {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) task: Option<String>,
     pub(crate) data: HashMap<String, Option<HashMap<String, Value>>>,
+    #[serde(flatten)]
+    pub(crate) extra: ExtraFields,
 }
 
 pub(crate) struct InitiatorAuthBuilder {
@@ -356,6 +674,7 @@ impl InitiatorAuthBuilder {
                 tasks: None,
                 task: None,
                 data: HashMap::new(),
+                extra: ExtraFields::default(),
             }
         }
     }
@@ -393,6 +712,7 @@ impl ResponderAuthBuilder {
                 tasks: Some(vec![]),
                 task: None,
                 data: HashMap::new(),
+                extra: ExtraFields::default(),
             }
         }
     }
@@ -458,17 +778,19 @@ impl ResponderAuthBuilder {
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub(crate) struct Close {
     pub(crate) reason: u16,
+    #[serde(flatten)]
+    pub(crate) extra: ExtraFields,
 }
 
 
 impl Close {
     #[cfg(test)]
     pub(crate) fn new(reason: u16) -> Self {
-        Self { reason  }
+        Self { reason, extra: ExtraFields::default() }
     }
 
     pub(crate) fn from_close_code(close_code: CloseCode) -> Self {
-        Self { reason: close_code.as_number() }
+        Self { reason: close_code.as_number(), extra: ExtraFields::default() }
     }
 }
 
@@ -484,8 +806,8 @@ mod tests {
                                           1, 2, 3, 4, 5, 6, 7, 8, 9, 0,
                                           1, 2, 3, 4, 5, 6, 7, 8, 9, 0,
                                           99, 255]).unwrap();
-        let msg = Message::ServerHello(ServerHello { key: key });
-        let bytes: Vec<u8> = rmps::to_vec_named(&msg).expect("Serialization failed");
+        let msg = Message::ServerHello(ServerHello::new(key));
+        let bytes: Vec<u8> = msg.to_msgpack();
         assert_eq!(bytes, vec![
             // Fixmap with two entries
             0x82,
@@ -512,7 +834,7 @@ mod tests {
                                           1, 2, 3, 4, 5, 6, 7, 8, 9, 0,
                                           1, 2, 3, 4, 5, 6, 7, 8, 9, 0,
                                           99, 255]).unwrap();
-        let server_hello = ServerHello { key: key };
+        let server_hello = ServerHello::new(key);
 
         // The bytes to deserialize
         let bytes = vec![
@@ -541,6 +863,113 @@ mod tests {
         }
     }
 
+    #[test]
+    /// Verify that keys and cookies are accepted both as msgpack `bin` and
+    /// as `str`, and that they are always encoded as `bin`.
+    ///
+    /// The messages are built by hand. They are not captures of the
+    /// JavaScript or Python clients, those still need to be added.
+    fn test_decode_str_encoded_bytes() {
+        fn key_message(header: &[u8], key: &[u8]) -> Vec<u8> {
+            let mut bytes = vec![
+                0x82,
+                0xa4, 0x74, 0x79, 0x70, 0x65, // type
+                0xa3, 0x6b, 0x65, 0x79, // key
+                0xa3, 0x6b, 0x65, 0x79, // key
+            ];
+            bytes.extend_from_slice(header);
+            bytes.extend_from_slice(key);
+            bytes
+        }
+
+        // Valid UTF-8 is passed to the visitors as string, other bytes as
+        // binary data
+        let mut invalid_utf8 = [0xff; 32];
+        invalid_utf8[0] = 0x01;
+        for key in &[*b"0123456789abcdefghijklmnopqrstuv", invalid_utf8] {
+            let expected = Message::Key(Key::new(PublicKey::from_slice(key).unwrap()));
+            let bin = key_message(&[0xc4, 0x20], key);
+            let str8 = key_message(&[0xd9, 0x20], key);
+            assert_eq!(Message::from_msgpack(&bin).unwrap(), expected);
+            assert_eq!(Message::from_msgpack(&str8).unwrap(), expected);
+            assert_eq!(expected.to_msgpack(), bin);
+        }
+
+        // A key of the wrong length is rejected in both encodings
+        assert!(Message::from_msgpack(&key_message(&[0xc4, 0x03], b"abc")).is_err());
+        assert!(Message::from_msgpack(&key_message(&[0xa3], b"abc")).is_err());
+
+        let cookie = *b"0123456789abcdef";
+        let mut bytes = vec![
+            0x83,
+            0xa4, 0x74, 0x79, 0x70, 0x65, // type
+            0xa4, 0x61, 0x75, 0x74, 0x68, // auth
+            0xab, 0x79, 0x6f, 0x75, 0x72, 0x5f, 0x63, 0x6f, 0x6f, 0x6b, 0x69, 0x65, // your_cookie
+            0xb0, // Fixstr with 16 bytes
+        ];
+        bytes.extend_from_slice(&cookie);
+        bytes.extend_from_slice(&[
+            0xa4, 0x64, 0x61, 0x74, 0x61, // data
+            0x80,
+        ]);
+        match Message::from_msgpack(&bytes).unwrap() {
+            Message::Auth(auth) => assert_eq!(auth.your_cookie, Cookie::new(cookie)),
+            other => panic!("Wrong message type: Should be Auth, but is {:?}", other),
+        }
+    }
+
+    #[test]
+    /// Verify that the type can be decoded without decoding other fields.
+    fn test_peek_type() {
+        let msg = Message::SendError(SendError::new(SendErrorId::from_slice(&[0; 8]).unwrap()));
+        assert_eq!(Message::peek_type(&msg.to_msgpack()).unwrap(), "send-error");
+
+        // Unknown fields are ignored
+        let value = Value::Map(vec![
+            (Value::from("type"), Value::from("token")),
+            (Value::from("key"), Value::from(42)),
+        ]);
+        let bytes = rmps::to_vec_named(&value).unwrap();
+        assert_eq!(Message::peek_type(&bytes).unwrap(), "token");
+
+        // A missing type is an error
+        let bytes = rmps::to_vec_named(&Value::Map(vec![])).unwrap();
+        assert!(Message::peek_type(&bytes).is_err());
+    }
+
+    #[test]
+    /// Verify that unknown fields are preserved when decoding and encoding.
+    fn test_extra_fields() {
+        let value = Value::Map(vec![
+            (Value::from("type"), Value::from("close")),
+            (Value::from("reason"), Value::from(3003)),
+            (Value::from("x-vendor"), Value::Array(vec![Value::from(1), Value::from("two")])),
+            (Value::from(7), Value::from("non-string key")),
+        ]);
+        let msg = Message::from_msgpack(&rmps::to_vec_named(&value).unwrap()).unwrap();
+        let extra_fields = msg.extra_fields();
+        assert_eq!(extra_fields.len(), 1);
+        assert_eq!(extra_fields.get("x-vendor"), Some(&Value::Array(vec![Value::from(1), Value::from("two")])));
+        assert_eq!(extra_fields.get("reason"), None);
+        assert_eq!(extra_fields.get("type"), None);
+
+        let encoded: Value = rmps::from_slice(&msg.to_msgpack()).unwrap();
+        assert_eq!(encoded, Value::Map(vec![
+            (Value::from("type"), Value::from("close")),
+            (Value::from("reason"), Value::from(3003)),
+            (Value::from("x-vendor"), Value::Array(vec![Value::from(1), Value::from("two")])),
+        ]));
+
+        // Messages without fields, too
+        let value = Value::Map(vec![
+            (Value::from("type"), Value::from("new-initiator")),
+            (Value::from("x-vendor"), Value::from(true)),
+        ]);
+        let msg = Message::from_msgpack(&rmps::to_vec_named(&value).unwrap()).unwrap();
+        assert_eq!(msg.extra_fields().iter().collect::<Vec<_>>(), vec![("x-vendor", &Value::from(true))]);
+        assert!(NewInitiator::default().into_message().extra_fields().is_empty());
+    }
+
     mod roundtrip {
         use super::*;
 
@@ -657,4 +1086,51 @@ mod tests {
             }
         }
     }
+
+    mod formatting {
+        use super::*;
+
+        fn key() -> PublicKey {
+            PublicKey::from_slice(&[7; 32]).unwrap()
+        }
+
+        /// Keys are shortened to their fingerprint.
+        #[test]
+        fn key_fingerprint() {
+            let hex = key().fingerprint().to_hex();
+            let msg = Message::ServerHello(ServerHello::new(key()));
+            assert_eq!(
+                msg.to_string(),
+                format!("server-hello: ServerHello {{ key: PublicKey({}..), extra: ExtraFields({{}}) }}", &hex[..8])
+            );
+            assert!(!format!("{:?}", msg).contains("7, 7"));
+
+            let msg = Message::Key(Key::new(key()));
+            assert!(msg.to_string().starts_with(&format!("key: Key {{ key: PublicKey({}..)", &hex[..8])));
+        }
+
+        /// The key in a token message is not shown at all.
+        #[test]
+        fn token_redacted() {
+            let msg = Message::Token(Token::new(key()));
+            let formatted = format!("{} {:?}", msg, msg);
+            assert!(formatted.contains("key: <redacted>"));
+            assert!(!formatted.contains(&key().fingerprint().to_hex()[..8]));
+        }
+
+        #[test]
+        fn client_auth() {
+            let msg = Message::ClientAuth(ClientAuth {
+                your_cookie: Cookie::new([1; 16]),
+                subprotocols: vec!["v1.saltyrtc.org".into()],
+                ping_interval: 30,
+                your_key: Some(key()),
+                extra: ExtraFields::default(),
+            });
+            let formatted = msg.to_string();
+            assert!(formatted.starts_with("client-auth: ClientAuth { your_cookie: Cookie(0101..)"));
+            assert!(formatted.contains("ping_interval: 30"));
+            assert!(formatted.contains(&format!("your_key: Some(PublicKey({}..))", &key().fingerprint().to_hex()[..8])));
+        }
+    }
 }
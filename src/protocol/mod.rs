@@ -2,8 +2,9 @@
 //!
 //! Incoming messages can be passed to a [`Signaling`](trait.Signaling.html)
 //! implementation where they will be processed. Instead of sending responses
-//! through the network directly, a [`HandleAction`](types/enum.HandleAction.html)
-//! is returned.
+//! through the network directly, a list of
+//! [`HandleAction`](types/enum.HandleAction.html)s is returned, wrapped in
+//! [`HandleActions`](types/struct.HandleActions.html).
 //!
 //! All peer related state is contained in the [context
 //! structs](context/index.html), depending on the role.
@@ -11,37 +12,68 @@
 use std::collections::{HashMap, HashSet};
 use std::mem;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use crate::accept::{AcceptPolicy, Decision};
 use crate::boxes::{ByteBox, OpenBox};
-use crate::crypto::{KeyPair, AuthToken, PublicKey};
-use crate::errors::{SignalingError, SignalingResult, SaltyError, ValidationError};
+use crate::clock::{Clock, SystemClock};
+use crate::crypto::{KeyPair, AuthToken, PairingData, PublicKey};
+use crate::crypto_backend::{self, NONCEBYTES};
+use crate::observer::{Decoded, Frame, Observer};
+use crate::errors::{ErrorContext, SignalingError, SignalingResult, ValidationError};
 use rmpv::{Value};
-use rust_sodium::crypto::box_;
 
 pub(crate) mod context;
 pub(crate) mod cookie;
 pub(crate) mod csn;
+#[cfg_attr(not(feature = "connect-tokio"), allow(dead_code))]
+pub(crate) mod decrypt;
+pub(crate) mod heartbeat;
 pub(crate) mod messages;
 pub(crate) mod nonce;
+pub(crate) mod random;
+pub(crate) mod rate_limit;
+pub(crate) mod rekey;
+pub(crate) mod reorder;
 pub(crate) mod send_error;
 pub(crate) mod state;
+pub(crate) mod summary;
+pub(crate) mod timer;
 pub(crate) mod types;
 
-#[cfg(test)] mod tests;
+#[cfg(test)] pub(crate) mod tests;
 
 use crate::{Event, CloseCode};
-use crate::tasks::{Tasks, BoxedTask, TaskMessage};
+use crate::stats::StatsCollector;
+use crate::tasks::{self, Tasks, BoxedTask, SharedTask, TaskMessage};
+use crate::transcript::{Direction, TranscriptEntry, TranscriptRecorder};
 use self::context::{PeerContext, ServerContext, InitiatorContext, ResponderContext};
+#[cfg(feature = "server-session")]
+use self::context::ServerSession;
+use self::csn::{CombinedSequencePair, CombinedSequenceSnapshot};
+use self::decrypt::{DecryptJob, DecryptedMessage, PreparedMessage};
+use self::heartbeat::Heartbeat;
 pub(crate) use self::cookie::{Cookie};
 use self::messages::{
     Message, ServerHello, ServerAuth, ClientHello, ClientAuth,
     NewInitiator, NewResponder, DropResponder, DropReason, Disconnected,
     SendError, Token, Key, Auth, InitiatorAuthBuilder, ResponderAuthBuilder, Close,
+    ExtraFields,
+    value_type,
 };
 pub(crate) use self::nonce::{Nonce};
-pub use self::types::Role;
-pub(crate) use self::types::{HandleAction};
+use self::nonce::NonceFactory;
+use self::random::RandomSource;
+#[cfg(test)]
+use self::random::OsRandom;
+use self::rate_limit::{RateLimit, TokenBucket, DEFAULT_PEER_RATE_LIMIT, DEFAULT_SERVER_RATE_LIMIT};
+use self::rekey::Rekey;
+use self::reorder::{Ordered, ReorderBuffer};
+use self::send_error::SentMessages;
+use self::summary::{PeerSummary, StateSummary};
+use self::timer::TimerId;
+pub use self::types::{AuthMethod, PeerInfo, ProtocolVersion, ResponderDropReason, Role, TrustedPeer};
+pub(crate) use self::types::{HandleAction, HandleActions};
 use self::types::{Identity, ClientIdentity, Address};
 use self::state::{
     SignalingState, ServerHandshakeState,
@@ -49,6 +81,13 @@ use self::state::{
 };
 
 
+/// The maximum number of responders an initiator keeps track of.
+///
+/// The path has room for 254 responders, two slots are kept free so that new
+/// responders can still connect while the path is being cleaned.
+pub(crate) const MAX_RESPONDERS: usize = 254 - 2;
+
+
 /// The main signaling trait.
 ///
 /// This is implemented by both the initiator and responder signaling structs.
@@ -89,6 +128,55 @@ pub(crate) trait Signaling {
         }
     }
 
+    /// Invalidate the auth token, so that it is neither accepted nor
+    /// restored when the handshake is reset.
+    fn invalidate_auth_token(&mut self) {
+        self.common_mut().invalidate_auth_token();
+    }
+
+    /// Replace the auth token with a new random token and return it.
+    ///
+    /// This only works for initiators that don't use a trusted key, before
+    /// the peer handshake is done.
+    fn rotate_auth_token(&mut self) -> SignalingResult<AuthToken> {
+        self.common_mut().rotate_auth_token()
+    }
+
+    /// Reset the handshake so that it can be restarted on a new server
+    /// connection.
+    ///
+    /// The permanent keys, the auth provider and the tasks are preserved.
+    /// This fails if the peer handshake has already finished.
+    #[cfg_attr(not(feature = "connect-tokio"), allow(dead_code))]
+    fn reset_handshake(&mut self) -> SignalingResult<()> {
+        self.common_mut().reset()?;
+        self.reset_peers();
+        Ok(())
+    }
+
+    /// Prepare a new handshake with the authenticated peer, to be run on a
+    /// new server connection.
+    ///
+    /// The session keys, cookies and sequence numbers of the current session
+    /// are discarded. The peer permanent key that was verified during the
+    /// handshake becomes the trusted key, and the chosen task is offered
+    /// again. Once the new handshake is done, an `Event::SessionRenewed` is
+    /// emitted instead of `Event::PeerHandshakeDone`.
+    fn renew_session(&mut self) -> SignalingResult<()> {
+        let peer_key = self.peer_info()
+            .map(|info| info.permanent_key)
+            .ok_or_else(|| SignalingError::Crash("Cannot renew session before the peer handshake is done".into()))?;
+        let task = self.common().task.clone()
+            .ok_or_else(|| SignalingError::Crash("Cannot renew session without a chosen task".into()))?;
+        self.common_mut().renew(peer_key, task);
+        self.reset_peers();
+        Ok(())
+    }
+
+    /// Forget the state of all peers. Called from `reset_handshake` and
+    /// `renew_session`.
+    fn reset_peers(&mut self);
+
     /// Return the server handshake state.
     fn server_handshake_state(&self) -> ServerHandshakeState {
         self.server().handshake_state()
@@ -103,6 +191,12 @@ pub(crate) trait Signaling {
         Ok(())
     }
 
+    /// Return the factory for the nonces of outgoing messages to `peer`,
+    /// using our current address as source.
+    fn nonce_factory<'a>(&self, peer: &'a dyn PeerContext) -> NonceFactory<'a> {
+        peer.nonce_factory(self.common().identity.into())
+    }
+
     /// Validate the repeated cookie from the `Auth` message.
     fn validate_repeated_cookie(&self, repeated_cookie: &Cookie,
                                 our_cookie: &Cookie, identity: Identity)
@@ -125,9 +219,45 @@ pub(crate) trait Signaling {
     /// Return the peer context with the specified address.
     fn get_peer_with_address_mut(&mut self, addr: Address) -> Option<&mut dyn PeerContext>;
 
+    /// Replace the session keys used with the authenticated peer, and
+    /// return the previous ones.
+    fn replace_session_keys(&mut self, keypair: KeyPair, session_key: PublicKey) -> SignalingResult<(KeyPair, PublicKey)>;
+
     /// Return the initiator public permanent key.
     fn initiator_pubkey(&self) -> &PublicKey;
 
+    /// Once the peer handshake is done, return information about the
+    /// authenticated peer.
+    fn peer_info(&self) -> Option<PeerInfo> {
+        if self.common().signaling_state() != SignalingState::Task {
+            return None;
+        }
+        let auth_method = self.common().peer_auth_method?;
+        let peer = self.get_peer()?;
+        Some(PeerInfo {
+            permanent_key: *peer.permanent_key()?,
+            address: Address::from(peer.identity()).0,
+            auth_method,
+        })
+    }
+
+    /// Return a snapshot of the signaling state for debugging.
+    fn state_summary(&self) -> StateSummary {
+        let task = self.common().task.as_ref()
+            .map(|task| task.lock().expect("Could not lock task mutex").name().into_owned());
+        StateSummary {
+            role: self.role().to_string(),
+            identity: self.identity().to_string(),
+            signaling_state: format!("{:?}", self.common().signaling_state()),
+            server_handshake_state: format!("{:?}", self.server_handshake_state()),
+            peers: self.peer_summaries(),
+            task,
+        }
+    }
+
+    /// Return a snapshot of every peer, for the state summary.
+    fn peer_summaries(&self) -> Vec<PeerSummary>;
+
     /// If the peer is already determined, return the current incoming and
     /// outgoing sequence numbers.
     fn current_peer_sequence_numbers(&self) -> Option<csn::PeerSequenceNumbers> {
@@ -147,6 +277,12 @@ pub(crate) trait Signaling {
             })
     }
 
+    /// Return the validation error for a message from a sender that has no
+    /// peer context.
+    fn unknown_peer_error(&self, _source: Address) -> ValidationError {
+        ValidationError::Crash("Got message from invalid sender that wasn't dropped".into())
+    }
+
     /// Validate the nonce destination.
     fn validate_nonce_destination(&mut self, nonce: &Nonce) -> Result<(), ValidationError>;
 
@@ -170,47 +306,13 @@ pub(crate) trait Signaling {
         //
         // * MUST check that the combined sequence number of the source peer
         //   has been increased by 1 and has not reset to 0.
-        let role = self.role();
-        let peer: &mut dyn PeerContext = self.get_peer_with_address_mut(nonce.source()).ok_or_else(|| {
-            if role == Role::Initiator && nonce.source().is_responder() {
-                // Note: This can happen since a message from a responder may still be in flight.
-                ValidationError::DropMsg(format!("Could not find responder with address {}", nonce.source()))
-            } else {
-                ValidationError::Crash("Got message from invalid sender that wasn't dropped".into())
-            }
-        })?;
+        let unknown_peer = self.unknown_peer_error(nonce.source());
+        let peer: &mut dyn PeerContext = self.get_peer_with_address_mut(nonce.source())
+            .ok_or(unknown_peer)?;
 
         let peer_identity = peer.identity();
         let mut csn_pair = peer.csn_pair().try_write()?;
-
-        // If we already have the CSN of the peer,
-        // ensure that it has been increased properly.
-        if let Some(ref mut csn) = csn_pair.theirs {
-            let previous = csn;
-            let current = nonce.csn();
-            if current < previous {
-                let msg = format!("The {} CSN is lower than last time", peer_identity);
-                return Err(ValidationError::Fail(msg));
-            } else if current == previous {
-                let msg = format!("The {} CSN hasn't been incremented", peer_identity);
-                return Err(ValidationError::Fail(msg));
-            } else {
-                *previous = current.clone();
-            }
-        }
-
-        // Otherwise, this is the first message from that peer.
-        if csn_pair.theirs.is_none() {
-            // Validate the overflow number...
-            if nonce.csn().overflow_number() != 0 {
-                let msg = format!("First message from {} must have set the overflow number to 0", peer.identity());
-                return Err(ValidationError::Fail(msg));
-            }
-            // ...and store the CSN.
-            csn_pair.theirs = Some(nonce.csn().clone());
-        }
-
-        Ok(())
+        update_their_csn(&mut csn_pair, nonce, peer_identity)
     }
 
     /// Validate the nonce cookie.
@@ -228,15 +330,9 @@ pub(crate) trait Signaling {
         // Otherwise, the peer:
         //
         // * MUST ensure that the 16 byte cookie of the sender has not changed
-        let role = self.role();
-        let peer: &mut dyn PeerContext = self.get_peer_with_address_mut(nonce.source()).ok_or_else(|| {
-            if role == Role::Initiator && nonce.source().is_responder() {
-                // Note: This can happen since a message from a responder may still be in flight.
-                ValidationError::DropMsg(format!("Could not find responder with address {}", nonce.source()))
-            } else {
-                ValidationError::Crash("Got message from invalid sender that wasn't dropped".into())
-            }
-        })?;
+        let unknown_peer = self.unknown_peer_error(nonce.source());
+        let peer: &mut dyn PeerContext = self.get_peer_with_address_mut(nonce.source())
+            .ok_or(unknown_peer)?;
 
         let peer_identity = peer.identity();
         let cookie_pair = peer.cookie_pair_mut();
@@ -269,28 +365,189 @@ pub(crate) trait Signaling {
     }
 
     /// Handle an incoming message.
-    fn handle_message(&mut self, bbox: ByteBox) -> SignalingResult<Vec<HandleAction>> {
+    fn handle_message(&mut self, bbox: ByteBox) -> SignalingResult<HandleActions> {
         trace!("handle_message");
 
+        self.common().check_not_failed()?;
+        self.common_mut().begin_incoming(&bbox);
+        let result = if self.common_mut().observe_incoming(&bbox) {
+            self.handle_message_impl(bbox)
+        } else {
+            Ok(HandleActions::new())
+        };
+        self.finish_message(result)
+    }
+
+    /// Prepare an incoming message for decryption on another thread.
+    ///
+    /// Task messages from the peer are validated right away and returned as
+    /// a job, which must be passed to
+    /// [`handle_decrypted`](#method.handle_decrypted) once it has been run.
+    /// All other messages are returned as they are and must be passed to
+    /// `handle_message`. See the [`decrypt`](decrypt/index.html) module.
+    #[cfg_attr(not(feature = "connect-tokio"), allow(dead_code))]
+    fn prepare_message(&mut self, bbox: ByteBox) -> SignalingResult<PreparedMessage> {
+        self.common().check_not_failed()?;
+        let from_peer = self.common().signaling_state() == SignalingState::Task
+            && self.get_peer().map(|peer| Address::from(peer.identity())) == Some(bbox.nonce.source());
+        if !from_peer {
+            return Ok(PreparedMessage::Sequential(bbox));
+        }
+        // During a key rotation, the key is only known after decrypting. Once
+        // we requested new keys, the answer of the peer must be handled
+        // before the following messages can be decrypted. The messages after
+        // a sequential one wait until it was handled, so that the nonces are
+        // still validated in order.
+        let rekeying = self.common().rekey.is_requested() || self.common().rekey.previous().is_some();
+        if rekeying || self.sequential_pending() {
+            self.common_mut().sequential_csn = Some(bbox.nonce.csn().clone());
+            return Ok(PreparedMessage::Sequential(bbox));
+        }
+
+        self.common_mut().begin_incoming(&bbox);
+        if !self.common_mut().observe_incoming(&bbox) {
+            return self.finish_message(Ok(HandleActions::new())).map(PreparedMessage::Handled);
+        }
+        match self.check_incoming(&bbox) {
+            Ok(None) => {},
+            Ok(Some(actions)) => return self.finish_message(Ok(actions)).map(PreparedMessage::Handled),
+            Err(e) => return self.finish_message(Err(e)).map(PreparedMessage::Handled),
+        }
+        let entry = self.common_mut().incoming_entry.take();
+        let job = self.get_peer()
+            .ok_or_else(|| SignalingError::Crash("Peer not set".into()))
+            .and_then(|peer| peer.session_keys()
+                .map(|(keypair, session_key)| DecryptJob::new(bbox, keypair, *session_key)));
+        match job {
+            Ok(job) => Ok(PreparedMessage::Decrypt(job.with_entry(entry))),
+            Err(e) => {
+                self.common_mut().incoming_entry = entry;
+                self.finish_message(Err(e)).map(PreparedMessage::Handled)
+            },
+        }
+    }
+
+    /// Return whether a peer message that `prepare_message` passed on to be
+    /// handled sequentially has not been handled yet.
+    #[cfg_attr(not(feature = "connect-tokio"), allow(dead_code))]
+    fn sequential_pending(&mut self) -> bool {
+        let pending = match self.common().sequential_csn {
+            Some(ref csn) => csn.clone(),
+            None => return false,
+        };
+        let theirs = self.get_peer()
+            .and_then(|peer| peer.csn_pair().read().ok().and_then(|pair| pair.theirs.clone()));
+        let handled = match theirs {
+            Some(theirs) => theirs >= pending,
+            None => false,
+        };
+        if handled {
+            self.common_mut().sequential_csn = None;
+        }
+        !handled
+    }
+
+    /// Handle a task message that was decrypted by a job returned from
+    /// [`prepare_message`](#method.prepare_message).
+    #[cfg_attr(not(feature = "connect-tokio"), allow(dead_code))]
+    fn handle_decrypted(&mut self, decrypted: DecryptedMessage) -> SignalingResult<HandleActions> {
+        trace!("handle_decrypted");
+
+        self.common_mut().incoming_entry = decrypted.entry;
+        let result = decrypted.result
+            .and_then(|obox| self.handle_task_peer_value(obox));
+        self.finish_message(result)
+    }
+
+    /// Record the transcript entry and the replies of an incoming message.
+    fn finish_message(&mut self, result: SignalingResult<HandleActions>) -> SignalingResult<HandleActions> {
+        self.common_mut().finish_incoming();
+        if result.is_err() {
+            self.common_mut().failed = true;
+        }
+        if let Err(SignalingError::Crypto(_)) = result {
+            self.common_mut().stats.decryption_failed();
+        }
+        let mut result = result.and_then(|mut actions| {
+            self.send_renewal_queue(&mut actions)?;
+            Ok(actions)
+        });
+        if let Ok(ref mut actions) = result {
+            let common = self.common_mut();
+            actions.retain_replies(|reply| common.record_outgoing(reply));
+        }
+        result
+    }
+
+    /// Send the task messages that were queued while the session was
+    /// renewed, once the new peer handshake is done.
+    fn send_renewal_queue(&mut self, actions: &mut HandleActions) -> SignalingResult<()> {
+        if self.common().renewal_queue.is_empty()
+                || !actions.iter().any(|action| *action == HandleAction::Event(Event::SessionRenewed)) {
+            return Ok(());
+        }
+        for value in self.common_mut().renewal_queue.split_off(0) {
+            debug!("<-- Enqueuing task message that was queued during the session renewal");
+            actions.push_reply(self.encode_task_message(value)?);
+        }
+        Ok(())
+    }
+
+    /// Return whether the sequence numbers for outgoing messages to the
+    /// peer are exhausted.
+    #[cfg_attr(not(feature = "connect-tokio"), allow(dead_code))]
+    fn outgoing_csn_exhausted(&self) -> SignalingResult<bool> {
+        let peer = self.get_peer().ok_or(SignalingError::NoPeer)?;
+        let exhausted = peer.csn_pair().try_read()?.ours.is_exhausted();
+        Ok(exhausted)
+    }
+
+    /// Enforce the rate limits and validate the nonce of an incoming
+    /// message.
+    ///
+    /// Return the actions to take instead of processing the message if it
+    /// should be dropped.
+    fn check_incoming(&mut self, bbox: &ByteBox) -> SignalingResult<Option<HandleActions>> {
+        // Enforce the rate limits before spending time on decryption
+        let now = self.common().clock.now();
+        let source = bbox.nonce.source();
+        if source.is_server() {
+            if self.server_handshake_state() != ServerHandshakeState::Done
+                    && !self.common_mut().take_server_token(now) {
+                return Err(SignalingError::Protocol("Server exceeded the message rate limit".into()));
+            }
+        } else if let Some(actions) = self.rate_limit_peer(source, now)? {
+            return Ok(Some(actions));
+        }
+
         // Validate the nonce
         match self.validate_nonce(&bbox.nonce) {
             // It's valid! Carry on.
-            Ok(_) => {},
+            Ok(_) => Ok(None),
 
             // Drop and ignore some of the messages
             Err(ValidationError::DropMsg(warning)) => {
                 warn!("Invalid nonce: {}", warning);
-                return Ok(vec![]);
+                Ok(Some(HandleActions::new()))
             },
 
             // Nonce is invalid, fail the signaling
+            Err(ValidationError::Fail(reason)) if source.is_server() =>
+                Err(SignalingError::InvalidNonce(reason)),
             Err(ValidationError::Fail(reason)) =>
-                return Err(SignalingError::InvalidNonce(reason)),
+                self.handle_peer_error(source, SignalingError::InvalidNonce(reason)).map(Some),
 
             // A critical error occurred
             Err(ValidationError::Crash(reason)) =>
-                return Err(SignalingError::Crash(reason)),
-        };
+                Err(SignalingError::Crash(reason)),
+        }
+    }
+
+    /// Validate, decode and handle an incoming message.
+    fn handle_message_impl(&mut self, bbox: ByteBox) -> SignalingResult<HandleActions> {
+        if let Some(actions) = self.check_incoming(&bbox)? {
+            return Ok(actions);
+        }
 
         if bbox.nonce.source().is_server() {
             // We need to clone the nonce here, in case we need it to verify
@@ -299,8 +556,21 @@ pub(crate) trait Signaling {
             // the message actually is a 'server-auth' message...
             let nonce_unsafe_clone = unsafe { bbox.nonce.clone() };
 
-            // Decode the message from the server
-            let obox: OpenBox<Message> = self.decode_server_message(bbox)?;
+            // Decode the message from the server. Once the server handshake
+            // is done, message types added in later versions of the
+            // protocol are ignored.
+            let obox: OpenBox<Message> = match self.decode_server_message(bbox) {
+                Ok(obox) => obox,
+                Err(SignalingError::UnknownMessageType(msg_type))
+                        if self.server_handshake_state() == ServerHandshakeState::Done => {
+                    warn!("Ignoring message of unknown type '{}' from server", msg_type);
+                    return Ok(HandleActions::from(HandleAction::Event(Event::UnknownServerMessage(msg_type))));
+                },
+                Err(e) => return Err(e),
+            };
+            if !self.common_mut().incoming_message(&obox) {
+                return Ok(HandleActions::new());
+            }
 
             // Only keep the nonce clone if this is a 'server-auth' message
             let nonce_clone_opt = if obox.message.get_type() == "server-auth" {
@@ -313,15 +583,26 @@ pub(crate) trait Signaling {
             self.handle_server_message(obox, nonce_clone_opt)
         } else {
             match self.common().signaling_state() {
-                SignalingState::ServerHandshake => self.handle_handshake_peer_message(bbox),
+                // Peers may only send messages after the server handshake
+                SignalingState::ServerHandshake => Err(SignalingError::Protocol(format!(
+                    "Got message from {} during the server handshake", bbox.nonce.source()
+                ))),
                 SignalingState::PeerHandshake => self.handle_handshake_peer_message(bbox),
                 SignalingState::Task => self.handle_task_peer_message(bbox),
             }
         }
     }
 
+    /// Apply the rate limit to a message from an unauthenticated peer.
+    ///
+    /// Return the actions to take instead of processing the message if the
+    /// peer exceeded the limit.
+    fn rate_limit_peer(&mut self, _source: Address, _now: Instant) -> SignalingResult<Option<HandleActions>> {
+        Ok(None)
+    }
+
     /// Handle an incoming handshake message from a peer.
-    fn handle_handshake_peer_message(&mut self, bbox: ByteBox) -> SignalingResult<Vec<HandleAction>> {
+    fn handle_handshake_peer_message(&mut self, bbox: ByteBox) -> SignalingResult<HandleActions> {
         trace!("handle_handshake_peer_message");
 
         // Sanity check
@@ -336,17 +617,12 @@ pub(crate) trait Signaling {
             let source_address = bbox.nonce.source();
             match self.decode_peer_message(bbox) {
                 Ok(obox) => obox,
-                Err(SignalingError::InitiatorCouldNotDecrypt) => {
-                    let drop_responder = self.send_drop_responder(
-                        source_address,
-                        DropReason::InitiatorCouldNotDecrypt,
-                    )?;
-                    debug!("<-- Enqueuing drop-responder to {}", self.server().identity());
-                    return Ok(vec![drop_responder]);
-                },
-                Err(e) => return Err(e),
+                Err(e) => return self.handle_peer_decode_error(source_address, e),
             }
         };
+        if !self.common_mut().incoming_message(&obox) {
+            return Ok(HandleActions::new());
+        }
 
         // Handle message depending on state
         match self.common().signaling_state() {
@@ -357,8 +633,11 @@ pub(crate) trait Signaling {
             // Peer handshake
             SignalingState::PeerHandshake if obox.nonce.source().is_server() =>
                 self.handle_server_message(obox, None),
-            SignalingState::PeerHandshake =>
-                self.handle_peer_message(obox),
+            SignalingState::PeerHandshake => {
+                let source = obox.nonce.source();
+                self.handle_peer_message(obox)
+                    .or_else(|e| self.handle_peer_error(source, e))
+            },
 
             // Task
             SignalingState::Task =>
@@ -367,7 +646,7 @@ pub(crate) trait Signaling {
     }
 
     /// Handle an incoming task message from a peer.
-    fn handle_task_peer_message(&mut self, bbox: ByteBox) -> SignalingResult<Vec<HandleAction>> {
+    fn handle_task_peer_message(&mut self, bbox: ByteBox) -> SignalingResult<HandleActions> {
         trace!("handle_task_peer_message");
 
         // Sanity check
@@ -377,8 +656,45 @@ pub(crate) trait Signaling {
             ));
         }
 
+        if self.common().peer_session_closed {
+            debug!("Ignoring message from {}, the session has been closed", bbox.nonce.source());
+            return Ok(HandleActions::new());
+        }
+
         // Decode message
-        let obox: OpenBox<Value> = self.decode_task_message(bbox)?;
+        let obox: OpenBox<Value> = self.decode_task_message_rekeying(bbox)?;
+        self.handle_task_peer_value(obox)
+    }
+
+    /// Decode a task message, falling back to the previous session keys
+    /// while the peer may still use them.
+    fn decode_task_message_rekeying(&mut self, bbox: ByteBox) -> SignalingResult<OpenBox<Value>> {
+        if self.common().rekey.previous().is_none() {
+            return self.decode_task_message(bbox);
+        }
+        // Decrypting consumes the byte box, so a copy of the frame is kept
+        // for the attempt with the previous keys.
+        let bytes = bbox.to_bytes();
+        match self.decode_task_message(bbox) {
+            Ok(obox) => {
+                self.common_mut().rekey.confirmed();
+                Ok(obox)
+            },
+            Err(SignalingError::Crypto(_)) => {
+                let (keypair, session_key) = self.common().rekey.previous()
+                    .ok_or_else(|| SignalingError::Crash("Previous session keys vanished".into()))?;
+                let keep_plaintext = self.common().transcript.is_some();
+                OpenBox::<Value>::decrypt(ByteBox::parse(bytes)?, keypair, session_key, keep_plaintext)
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Handle a decrypted task message from the peer.
+    fn handle_task_peer_value(&mut self, obox: OpenBox<Value>) -> SignalingResult<HandleActions> {
+        if !self.common_mut().incoming_value(&obox) {
+            return Ok(HandleActions::new());
+        }
 
         // Convert to HashMap
         let mut map: HashMap<String, Value> = HashMap::new();
@@ -402,12 +718,47 @@ pub(crate) trait Signaling {
             .to_owned();
         debug!("Received {} message from peer", msg_type);
 
+        // Messages of a type that the task claims are passed to the task
+        let task_handles = |msg_type: &str| self.common().task_supported_types
+            .iter()
+            .any(|types| types.contains(&msg_type));
+
+        // Handle heartbeat messages, unless the task handles them itself
+        if msg_type == heartbeat::TYPE_HEARTBEAT && !task_handles(heartbeat::TYPE_HEARTBEAT) {
+            let ack = heartbeat::ack_message(&map)?;
+            return Ok(HandleActions::from(HandleAction::Reply(self.encode_task_message(ack)?)));
+        }
+        if msg_type == heartbeat::TYPE_HEARTBEAT_ACK && !task_handles(heartbeat::TYPE_HEARTBEAT_ACK) {
+            let now = self.common().clock.now();
+            let events = self.common_mut().heartbeat.handle_ack(&map, now)?;
+            return Ok(events.into_iter().map(HandleAction::Event).collect());
+        }
+
+        // Handle key rotation, if enabled
+        if msg_type == rekey::TYPE_REKEY {
+            if self.common().rekeying {
+                return self.handle_rekey(&map);
+            }
+            warn!("Ignoring rekey message, rekeying is not enabled");
+            return Ok(HandleActions::new());
+        }
+
+        // Answer pings, unless the task handles them itself
+        if msg_type == heartbeat::TYPE_PING && !task_handles(heartbeat::TYPE_PING) {
+            let pong = heartbeat::pong_message(map);
+            return Ok(HandleActions::from(HandleAction::Reply(self.encode_task_message(pong)?)));
+        }
+        if msg_type == heartbeat::TYPE_PONG && !task_handles(heartbeat::TYPE_PONG) {
+            debug!("Ignoring unsolicited pong message");
+            return Ok(HandleActions::new());
+        }
+
         // Handle application messages
         if msg_type == "application" {
             let data: Value = map.get("data")
                 .ok_or_else(|| SignalingError::InvalidMessage("Application message does not contain a data field".into()))?
                 .to_owned();
-            return Ok(vec![HandleAction::TaskMessage(TaskMessage::Application(data))]);
+            return Ok(HandleActions::from(HandleAction::TaskMessage(TaskMessage::Application(data))));
         }
 
         // Handle close messages
@@ -424,7 +775,7 @@ pub(crate) trait Signaling {
                     }
                 })
                 .map(CloseCode::from_number)?;
-            return Ok(vec![HandleAction::TaskMessage(TaskMessage::Close(reason))]);
+            return Ok(HandleActions::from(HandleAction::TaskMessage(TaskMessage::Close(reason))));
         }
 
         // Pass supported task message to task
@@ -432,11 +783,11 @@ pub(crate) trait Signaling {
             .task_supported_types
             .ok_or_else(|| SignalingError::Crash("Task supported types not set".into()))?;
         if task_supported_types.iter().any(|t| *t == msg_type) {
-            return Ok(vec![HandleAction::TaskMessage(TaskMessage::Value(map))])
+            return Ok(HandleActions::from(HandleAction::TaskMessage(TaskMessage::Value(map))))
         }
 
-        warn!("Received task message with unsupported type: {}. Ignoring.", msg_type);
-        Ok(vec![])
+        // Neither the signaling nor the task know this type
+        Err(SignalingError::UnknownMessageType(msg_type.to_string()))
     }
 
 
@@ -444,15 +795,23 @@ pub(crate) trait Signaling {
 
     /// Decode or decrypt a binary message coming from the server.
     fn decode_server_message(&self, bbox: ByteBox) -> SignalingResult<OpenBox<Message>> {
+        // Only accept the messages that are valid in the current server
+        // handshake state. The role specific messages are validated later.
+        let expected_types: &[&str] = match self.server_handshake_state() {
+            ServerHandshakeState::New => &["server-hello"],
+            ServerHandshakeState::ClientInfoSent => &["server-auth"],
+            ServerHandshakeState::Done => &["new-initiator", "new-responder", "send-error", "disconnected"],
+        };
+
         // The very first message from the server is unencrypted
         if self.common().signaling_state() == SignalingState::ServerHandshake
         && self.server_handshake_state() == ServerHandshakeState::New {
-            return OpenBox::decode(bbox);
+            return OpenBox::decode(bbox, expected_types);
         }
 
         // Otherwise, decrypt with server key
         match self.server().session_key {
-            Some(ref pubkey) => OpenBox::<Message>::decrypt(bbox, &self.common().permanent_keypair, pubkey),
+            Some(ref pubkey) => OpenBox::<Message>::decrypt(bbox, &self.common().permanent_keypair, pubkey, expected_types),
             None => Err(SignalingError::Crash("Missing server session key".into())),
         }
     }
@@ -460,17 +819,25 @@ pub(crate) trait Signaling {
     /// Decrypt a binary message coming from a peer.
     fn decode_peer_message(&self, bbox: ByteBox) -> SignalingResult<OpenBox<Message>>;
 
+    /// Handle a handshake message from a peer that could not be decoded.
+    ///
+    /// By default, the error is propagated.
+    fn handle_peer_decode_error(&mut self, _source: Address, error: SignalingError) -> SignalingResult<HandleActions> {
+        Err(error)
+    }
+
+    /// Handle a protocol violation of a peer during the peer handshake.
+    ///
+    /// By default, the error is propagated.
+    fn handle_peer_error(&mut self, _source: Address, error: SignalingError) -> SignalingResult<HandleActions> {
+        Err(error)
+    }
+
     /// Decrypt a binary message after the handshake has been finished.
     fn decode_task_message(&self, bbox: ByteBox) -> SignalingResult<OpenBox<Value>> {
         let peer = self.get_peer()
             .ok_or_else(|| SignalingError::Crash("Peer not set".into()))?;
-        let session_key = peer.session_key()
-            .ok_or_else(|| SignalingError::Crash("Peer session key not set".into()))?;
-        OpenBox::<Value>::decrypt(
-            bbox,
-            peer.keypair().ok_or_else(|| SignalingError::Crash("Peer session keypair not available".into()))?,
-            session_key,
-        )
+        peer.decrypt_value(bbox, self.common().transcript.is_some())
     }
 
 
@@ -485,31 +852,252 @@ pub(crate) trait Signaling {
                 format!("Called encode_task_message in state {:?}", signaling_state)
             ));
         }
+        self.common().check_peer_session()?;
 
         // Get peer
         let peer = self.get_peer()
             .ok_or_else(|| SignalingError::Crash("Peer not set".into()))?;
 
         // Create and encrypt message
-        let nonce = Nonce::new(
-            // Cookie
-            peer.cookie_pair().ours.clone(),
-            // Src
-            self.common().identity.into(),
-            // Dst
-            peer.identity().into(),
-            // Csn
-            peer.csn_pair().try_write()?.ours.increment()?,
-        );
-        let obox = OpenBox::<Value>::new(value, nonce);
-        let bbox = obox.encrypt(
-            peer.keypair().ok_or_else(|| SignalingError::Crash("Session keypair not available".into()))?,
-            peer.session_key().ok_or_else(|| SignalingError::Crash("Peer session key not set".into()))?,
-        );
+        peer.encrypt_value(value, self.common().identity.into(), self.common().transcript.is_some())
+    }
+
+    /// Encrypt an opaque task payload for the chosen peer.
+    ///
+    /// Payloads have their own sequence numbers, so they can be sent and
+    /// received independently of the task messages. To never reuse a nonce
+    /// of a message, they use the inverted cookie (see
+    /// [`Cookie::payload_cookie`](cookie/struct.Cookie.html#method.payload_cookie)).
+    /// Before the peer handshake is done, this returns a
+    /// `SignalingError::NoPeer`.
+    fn encrypt_for_peer(&self, data: &[u8]) -> SignalingResult<ByteBox> {
+        if self.common().signaling_state() != SignalingState::Task {
+            return Err(SignalingError::NoPeer);
+        }
+        self.common().check_peer_session()?;
+        let peer = self.get_peer().ok_or(SignalingError::NoPeer)?;
+        let (keypair, session_key) = peer.session_keys()?;
+        let csn_pair = peer.payload_csn_pair().ok_or(SignalingError::NoPeer)?;
+        let cookie = peer.cookie_pair().ours.payload_cookie();
+        let nonce = NonceFactory::new(&cookie, csn_pair, self.common().identity.into(), peer.identity().into())
+            .next()?;
+        Ok(ByteBox::seal(data, nonce, keypair, session_key))
+    }
+
+    /// Decrypt a task payload from the chosen peer.
+    ///
+    /// The payload is decrypted first, so that forged payloads don't touch
+    /// the peer state. The nonce is then validated against the sequence
+    /// numbers of the previous payloads, which rejects replayed payloads.
+    fn decrypt_from_peer(&mut self, bbox: ByteBox) -> SignalingResult<Vec<u8>> {
+        let (nonce, data) = self.open_from_peer(bbox)?;
+        self.validate_nonce_destination(&nonce)
+            .and_then(|_| self.validate_nonce_source(&nonce))
+            .and_then(|_| self.validate_payload_cookie(&nonce))
+            .and_then(|_| self.validate_payload_csn(&nonce))
+            .map_err(payload_nonce_error)?;
+        Ok(data)
+    }
+
+    /// Validate that a task payload uses the payload cookie of the peer.
+    fn validate_payload_cookie(&self, nonce: &Nonce) -> Result<(), ValidationError> {
+        let peer = self.get_peer()
+            .ok_or_else(|| ValidationError::Crash("Peer not set".into()))?;
+        let cookie = peer.cookie_pair().theirs.as_ref()
+            .ok_or_else(|| ValidationError::Crash(format!("Cookie of {} not set", peer.identity())))?;
+        if *nonce.cookie() != cookie.payload_cookie() {
+            return Err(ValidationError::Fail(format!("Payload cookie from {} is invalid", peer.identity())));
+        }
+        Ok(())
+    }
+
+    /// Validate the CSN of a task payload against the previous payloads and
+    /// store it.
+    fn validate_payload_csn(&self, nonce: &Nonce) -> Result<(), ValidationError> {
+        let peer = self.get_peer()
+            .ok_or_else(|| ValidationError::Crash("Peer not set".into()))?;
+        let csn_pair = peer.payload_csn_pair()
+            .ok_or_else(|| ValidationError::Crash(format!("No payloads with {}", peer.identity())))?;
+        let mut csn_pair = csn_pair.try_write()?;
+        update_their_csn(&mut csn_pair, nonce, peer.identity())
+    }
+
+    /// Decrypt a task payload from the chosen peer, holding it back if it
+    /// arrived before the preceding payloads. See the
+    /// [`reorder`](reorder/index.html) module.
+    fn decrypt_from_peer_ordered(&mut self, bbox: ByteBox) -> SignalingResult<Ordered> {
+        let (nonce, data) = self.open_from_peer(bbox)?;
+        self.validate_nonce_destination(&nonce)
+            .and_then(|_| self.validate_nonce_source(&nonce))
+            .and_then(|_| self.validate_payload_cookie(&nonce))
+            .map_err(payload_nonce_error)?;
+
+        // The sequence number is validated by the reorder buffer instead,
+        // unless this is the first payload from the peer. The initial CSN of
+        // the peer is random, so it can't be held back.
+        let last = match self.last_payload_csn()? {
+            Some(last) => last,
+            None => {
+                self.validate_payload_csn(&nonce).map_err(payload_nonce_error)?;
+                return Ok(Ordered { payloads: vec![data], gaps: vec![] });
+            },
+        };
+        let csn = nonce.csn().combined_sequence_number();
+        let now = self.common().clock.now();
+        let (ordered, last) = self.common_mut().reorder.insert(last, csn, data, now)
+            .map_err(SignalingError::InvalidNonce)?;
+        self.set_last_payload_csn(last)?;
+        Ok(ordered)
+    }
+
+    /// Release the held back task payloads that timed out, see the
+    /// [`reorder`](reorder/index.html) module.
+    fn release_reordered(&mut self) -> SignalingResult<Ordered> {
+        let last = match self.last_payload_csn()? {
+            Some(last) => last,
+            None => return Ok(Ordered::default()),
+        };
+        let now = self.common().clock.now();
+        let (ordered, last) = self.common_mut().reorder.release(last, now);
+        self.set_last_payload_csn(last)?;
+        Ok(ordered)
+    }
+
+    /// Return the combined sequence number of the last task payload that
+    /// was accepted from the peer, if any.
+    fn last_payload_csn(&self) -> SignalingResult<Option<u64>> {
+        Ok(self.get_peer()
+            .and_then(|peer| peer.payload_csn_pair())
+            .ok_or(SignalingError::NoPeer)?
+            .try_read()?
+            .theirs.as_ref()
+            .map(CombinedSequenceSnapshot::combined_sequence_number))
+    }
+
+    /// Store the combined sequence number of the last task payload that was
+    /// accepted from the peer.
+    fn set_last_payload_csn(&self, last: u64) -> SignalingResult<()> {
+        self.get_peer()
+            .and_then(|peer| peer.payload_csn_pair())
+            .ok_or(SignalingError::NoPeer)?
+            .try_write()?
+            .theirs = Some(CombinedSequenceSnapshot::new((last >> 32) as u16, last as u32));
+        Ok(())
+    }
+
+    /// Decrypt a task payload from the chosen peer without validating the
+    /// nonce, and return the nonce along with the payload.
+    fn open_from_peer(&self, bbox: ByteBox) -> SignalingResult<(Nonce, Vec<u8>)> {
+        if self.common().signaling_state() != SignalingState::Task {
+            return Err(SignalingError::NoPeer);
+        }
+        self.common().check_peer_session()?;
+        // The unsafe call to `clone()` is required because the nonce is
+        // validated after the byte box has been consumed by decrypting it.
+        let nonce = unsafe { bbox.nonce.clone() };
+        let peer = self.get_peer().ok_or(SignalingError::NoPeer)?;
+        let peer_address: Address = peer.identity().into();
+        if nonce.source() != peer_address {
+            return Err(SignalingError::InvalidNonce(
+                format!("Payload was sent by {} instead of the peer", Identity::from(nonce.source()))
+            ));
+        }
+        let (keypair, session_key) = peer.session_keys()?;
+        let data = match self.common().rekey.previous() {
+            None => bbox.open(keypair, session_key)?,
+            Some((previous_keypair, previous_session_key)) => {
+                // Opening consumes the byte box, so a copy of the frame is
+                // kept for the attempt with the previous keys.
+                let bytes = bbox.to_bytes();
+                bbox.open(keypair, session_key)
+                    .or_else(|_| ByteBox::parse(bytes)?.open(previous_keypair, previous_session_key))?
+            },
+        };
+        Ok((nonce, data))
+    }
 
+    /// Start rotating the session keys with the chosen peer, see the
+    /// [`rekey`](rekey/index.html) module.
+    ///
+    /// Return the rekey message for the peer.
+    fn start_rekey(&mut self) -> SignalingResult<ByteBox> {
+        if !self.common().rekeying {
+            return Err(SignalingError::Rekey("Rekeying is not enabled".into()));
+        }
+        if self.common().signaling_state() != SignalingState::Task {
+            return Err(SignalingError::NoPeer);
+        }
+        if self.common().rekey.is_requested() {
+            return Err(SignalingError::Rekey("Rekeying is already in progress".into()));
+        }
+        let keypair = KeyPair::from_random_source(&mut *self.common_mut().rng);
+        let bbox = self.encode_task_message(rekey::rekey_message(keypair.public_key()))?;
+        self.common_mut().rekey.request(keypair);
         Ok(bbox)
     }
 
+    /// Handle a rekey message from the chosen peer.
+    ///
+    /// Unless we requested the rotation ourselves, answer with our own new
+    /// key. Then switch to the new keys.
+    fn handle_rekey(&mut self, map: &HashMap<String, Value>) -> SignalingResult<HandleActions> {
+        let key = rekey::rekey_key(map)?;
+        let mut actions = HandleActions::new();
+        let keypair = match self.common_mut().rekey.take_requested() {
+            Some(keypair) => keypair,
+            None => {
+                let keypair = KeyPair::from_random_source(&mut *self.common_mut().rng);
+                let bbox = self.encode_task_message(rekey::rekey_message(keypair.public_key()))?;
+                actions.push_reply(bbox);
+                keypair
+            },
+        };
+        let (peer_permanent_key, peer_session_key) = {
+            let peer = self.get_peer().ok_or(SignalingError::NoPeer)?;
+            (*peer.require_permanent_key()?, peer.session_keys()?.1 == &key)
+        };
+        if peer_session_key {
+            return Err(SignalingError::InvalidKey("Peer did not rotate its session key".into()));
+        }
+        self.common().validate_peer_session_key(&key, &peer_permanent_key, keypair.public_key())?;
+        let (previous_keypair, previous_session_key) = self.replace_session_keys(keypair, key)?;
+        self.common_mut().rekey.switched(previous_keypair, previous_session_key);
+        info!("Rotated the session keys with the peer");
+        actions.push_event(Event::Rekeyed);
+        Ok(actions)
+    }
+
+    /// Create a heartbeat message for the chosen peer.
+    ///
+    /// If the previous heartbeat was not acknowledged, it counts as missed.
+    /// Once `miss_threshold` consecutive heartbeats were missed, a
+    /// `PeerUnresponsive` event is returned along with the heartbeat.
+    fn heartbeat_tick(&mut self, miss_threshold: u32) -> SignalingResult<HandleActions> {
+        if self.common().peer_session_closed {
+            return Ok(HandleActions::new());
+        }
+        let now = self.common().clock.now();
+        let (msg, event) = self.common_mut().heartbeat.tick(miss_threshold, now);
+        let mut actions: HandleActions = event.into_iter().map(HandleAction::Event).collect();
+        let bbox = self.encode_task_message(msg)?;
+        actions.push_reply(bbox);
+        Ok(actions)
+    }
+
+    /// Handle the expiry of a timer that was started with a
+    /// `HandleAction::StartTimer` action, see the [`timer`](timer/index.html)
+    /// module.
+    fn handle_timeout(&mut self, _id: TimerId) -> SignalingResult<HandleActions> {
+        Ok(HandleActions::new())
+    }
+
+    /// Drop all peers that have not finished the peer handshake, before the
+    /// connection is closed.
+    #[cfg_attr(not(feature = "connect-tokio"), allow(dead_code))]
+    fn drop_pending_peers(&mut self) -> SignalingResult<HandleActions> {
+        Ok(HandleActions::new())
+    }
+
     /// Encode and encrypt a close message for the chosen peer.
     ///
     /// The `peer_ctx` parameter must only be provided during handshake.
@@ -536,24 +1124,8 @@ pub(crate) trait Signaling {
         };
 
         // Create and encrypt message
-        let nonce = Nonce::new(
-            // Cookie
-            peer.cookie_pair().ours.clone(),
-            // Src
-            self.common().identity.into(),
-            // Dst
-            peer.identity().into(),
-            // Csn
-            peer.csn_pair().try_write()?.ours.increment()?,
-        );
         let msg = Close::from_close_code(reason).into_message();
-        let obox = OpenBox::<Message>::new(msg, nonce);
-        let bbox = obox.encrypt(
-            peer.keypair().ok_or_else(|| SignalingError::Crash("Session keypair not available".into()))?,
-            peer.session_key().ok_or_else(|| SignalingError::Crash("Peer session key not set".into()))?,
-        );
-
-        Ok(bbox)
+        peer.encrypt_message(msg, self.common().identity.into())
     }
 
 
@@ -568,7 +1140,7 @@ pub(crate) trait Signaling {
     /// Note: The `nonce_clone` parameter is only set to a value if needed to
     /// verify the signed keys inside the `server-auth` message. Otherwise it's
     /// `None`.
-    fn handle_server_message(&mut self, obox: OpenBox<Message>, nonce_clone: Option<Nonce>) -> SignalingResult<Vec<HandleAction>> {
+    fn handle_server_message(&mut self, obox: OpenBox<Message>, nonce_clone: Option<Nonce>) -> SignalingResult<HandleActions> {
         let old_state = self.server_handshake_state();
         match (old_state, obox.message) {
             // Valid state transitions
@@ -576,21 +1148,26 @@ pub(crate) trait Signaling {
                 self.handle_server_hello(msg),
             (ServerHandshakeState::ClientInfoSent, Message::ServerAuth(msg)) =>
                 self.handle_server_auth(msg, nonce_clone),
-            (ServerHandshakeState::Done, Message::NewInitiator(msg)) =>
-                self.handle_new_initiator(msg),
-            (ServerHandshakeState::Done, Message::NewResponder(msg)) =>
-                self.handle_new_responder(msg),
+            (ServerHandshakeState::Done, msg @ Message::NewInitiator(_)) =>
+                self.handle_role_server_message(msg),
+            (ServerHandshakeState::Done, msg @ Message::NewResponder(_)) =>
+                self.handle_role_server_message(msg),
             (ServerHandshakeState::Done, Message::DropResponder(_msg)) =>
                 unimplemented!("TODO (#36): Handling DropResponder messages not yet implemented"),
             (ServerHandshakeState::Done, Message::SendError(msg)) =>
                 self.handle_send_error(msg),
+            (ServerHandshakeState::Done, Message::Disconnected(_)) if !self.common().protocol_version.supports_disconnected() =>
+                Err(SignalingError::Protocol(format!(
+                    "Got 'disconnected' message, but it is not part of protocol version {}",
+                    self.common().protocol_version,
+                ))),
             (ServerHandshakeState::Done, Message::Disconnected(msg)) =>
                 self.handle_disconnected(msg),
 
             // Any undefined state transition results in an error
-            (s, message) => Err(SignalingError::InvalidStateTransition(
-                format!("Got '{}' message from server in {:?} state", message.get_type(), s)
-            )),
+            (s, message) => Err(SignalingError::UnexpectedMessage(ErrorContext::new(
+                self.common().signaling_state(), s, message.get_type(), &obox.nonce,
+            ))),
         }
     }
 
@@ -599,45 +1176,40 @@ pub(crate) trait Signaling {
     ///
     /// This method call may have some side effects, like updates in the peer
     /// context (cookie, CSN, etc).
-    fn handle_peer_message(&mut self, obox: OpenBox<Message>) -> SignalingResult<Vec<HandleAction>>;
+    fn handle_peer_message(&mut self, obox: OpenBox<Message>) -> SignalingResult<HandleActions>;
 
 
     // Message handling: Handling
 
+    /// Build the [`ClientHello`](messages/struct.ClientHello.html) message
+    /// that is sent in reply to the `server-hello` message.
+    ///
+    /// Only responders send this message.
+    fn client_hello(&self) -> SignalingResult<Option<HandleAction>> {
+        Ok(None)
+    }
+
     /// Handle an incoming [`ServerHello`](messages/struct.ServerHello.html) message.
-    fn handle_server_hello(&mut self, msg: ServerHello) -> SignalingResult<Vec<HandleAction>> {
+    fn handle_server_hello(&mut self, msg: ServerHello) -> SignalingResult<HandleActions> {
         debug!("--> Received server-hello from server");
+        let now = self.common().clock.now();
+        self.common_mut().stats.server_hello(now);
 
-        let mut actions = Vec::with_capacity(2);
+        let mut actions = HandleActions::new();
 
         // Set the server public session key
-        trace!("Server session key is {:?}", msg.key);
         if self.server().session_key.is_some() {
             return Err(SignalingError::Protocol(
                 "Got a server-hello message, but server session key is already set".to_string()
             ));
         }
+        self.common().validate_server_session_key(&msg.key)?;
         self.common_mut().server.session_key = Some(msg.key);
 
         // Reply with client-hello message if we're a responder
-        if self.role() == Role::Responder {
-            let client_hello = {
-                let key = self.common().permanent_keypair.public_key();
-                ClientHello::new(*key).into_message()
-            };
-            let client_hello_nonce = Nonce::new(
-                // Cookie
-                self.server().cookie_pair().ours.clone(),
-                // Src
-                self.common().identity.into(),
-                // Dst
-                self.server().identity().into(),
-                // Csn
-                self.server().csn_pair().try_write()?.ours.increment()?,
-            );
-            let reply = OpenBox::<Message>::new(client_hello, client_hello_nonce);
+        if let Some(client_hello) = self.client_hello()? {
             debug!("<-- Enqueuing client-hello to server");
-            actions.push(HandleAction::Reply(reply.encode()));
+            actions.push(client_hello);
         }
 
         // Send client-auth message
@@ -656,22 +1228,19 @@ pub(crate) trait Signaling {
             n => debug!("Requesting WebSocket ping messages every {}s", n),
         };
         let client_auth = ClientAuth {
-            your_cookie: self.server().cookie_pair().theirs.clone().unwrap(),
-            subprotocols: vec![crate::SUBPROTOCOL.into()],
+            your_cookie: self.server().cookie_pair().theirs.clone()
+                .ok_or_else(|| SignalingError::Crash("Server cookie not set".into()))?,
+            subprotocols: vec![self.common().protocol_version.subprotocol().into()],
             ping_interval,
             your_key: self.server().permanent_key().cloned(),
+            extra: ExtraFields::default(),
         }.into_message();
-        let client_auth_nonce = Nonce::new(
-            self.server().cookie_pair().ours.clone(),
-            self.identity().into(),
-            self.server().identity().into(),
-            self.server().csn_pair().try_write()?.ours.increment()?,
-        );
+        let client_auth_nonce = self.nonce_factory(self.server()).next()?;
         let reply = OpenBox::<Message>::new(client_auth, client_auth_nonce);
         match self.server().session_key {
             Some(ref pubkey) => {
                 debug!("<-- Enqueuing client-auth to server");
-                actions.push(HandleAction::Reply(reply.encrypt(&self.common().permanent_keypair, pubkey)));
+                actions.push_reply(reply.encrypt(&self.common().permanent_keypair, pubkey));
             },
             None => return Err(SignalingError::Crash("Missing server permanent key".into())),
         };
@@ -682,7 +1251,7 @@ pub(crate) trait Signaling {
     }
 
     /// Handle an incoming [`ServerAuth`](messages/struct.ServerAuth.html) message.
-    fn handle_server_auth(&mut self, msg: ServerAuth, nonce_clone: Option<Nonce>) -> SignalingResult<Vec<HandleAction>> {
+    fn handle_server_auth(&mut self, msg: ServerAuth, nonce_clone: Option<Nonce>) -> SignalingResult<HandleActions> {
         debug!("--> Received server-auth from server");
 
         // When the client receives a 'server-auth' message, it MUST
@@ -748,58 +1317,28 @@ pub(crate) trait Signaling {
     }
 
     /// Role-specific handling of an incoming [`ServerAuth`](messages/struct.ServerAuth.html) message.
-    fn handle_server_auth_impl(&mut self, msg: &ServerAuth) -> SignalingResult<Vec<HandleAction>>;
-
-    /// Handle an incoming [`NewInitiator`](messages/struct.NewInitiator.html) message.
-    fn handle_new_initiator(&mut self, msg: NewInitiator) -> SignalingResult<Vec<HandleAction>>;
+    fn handle_server_auth_impl(&mut self, msg: &ServerAuth) -> SignalingResult<HandleActions>;
 
-    /// Handle an incoming [`NewResponder`](messages/struct.NewResponder.html) message.
-    fn handle_new_responder(&mut self, msg: NewResponder) -> SignalingResult<Vec<HandleAction>>;
+    /// Handle an incoming server message that is only valid for one of the
+    /// two roles, like [`NewInitiator`](messages/struct.NewInitiator.html) or
+    /// [`NewResponder`](messages/struct.NewResponder.html).
+    fn handle_role_server_message(&mut self, msg: Message) -> SignalingResult<HandleActions>;
 
-    /// Handle an incoming [`SendError`](messages/struct.ServerAuth.html) message.
-    fn handle_send_error(&mut self, msg: SendError) -> SignalingResult<Vec<HandleAction>> {
+    /// Handle an incoming [`SendError`](messages/struct.SendError.html) message.
+    ///
+    /// The server only relays client-to-client messages, so a send-error
+    /// for a message to the server is a protocol error.
+    fn handle_send_error(&mut self, msg: SendError) -> SignalingResult<HandleActions> {
         warn!("--> Received send-error from server");
         debug!("Message that could not be relayed: {:#?}", msg.id);
-        Err(SignalingError::SendError)
+        if msg.id.destination.is_server() {
+            return Err(SignalingError::ServerSendError);
+        }
+        Err(SignalingError::SendError(self.common().sent_messages.failed_message(&msg.id)))
     }
 
     /// Handle an incoming [`Disconnected`](messages/struct.Disconnected.html) message.
-    fn handle_disconnected(&mut self, msg: Disconnected) -> SignalingResult<Vec<HandleAction>>;
-
-    // Helper methods
-
-    /// Encode and return a DropResponder message.
-    fn send_drop_responder(&self, addr: Address, reason: DropReason) -> SignalingResult<HandleAction> {
-        // Note: We need to define this method here instead of in the
-        // `InitiatorSignaling` impl because the `handle_handshake_peer_message`
-        // method on the `Signaling` trait needs to be able to call it.
-
-        // Sanity check
-        if self.role() != Role::Initiator {
-            return Err(SignalingError::Crash(
-                "Non-initiator should never need to encode a DropResponder message".into()
-            ));
-        }
-
-        // Create message and nonce
-        let drop = DropResponder::with_reason(addr, reason).into_message();
-        let drop_nonce = Nonce::new(
-            self.server().cookie_pair.ours.clone(),
-            self.common().identity.into(),
-            self.server().identity().into(),
-            self.server().csn_pair().try_write()?.ours.increment()?,
-        );
-
-        // Encrypt message
-        let obox = OpenBox::<Message>::new(drop, drop_nonce);
-        let bbox = obox.encrypt(
-            &self.common().permanent_keypair,
-            self.server().session_key()
-                .ok_or_else(|| SignalingError::Crash("Server session key not set".into()))?
-        );
-
-        Ok(HandleAction::Reply(bbox))
-    }
+    fn handle_disconnected(&mut self, msg: Disconnected) -> SignalingResult<HandleActions>;
 
     // Raw encryption / decryption
 
@@ -807,34 +1346,77 @@ pub(crate) trait Signaling {
     ///
     /// If this function is called before the peer has been established,
     /// it will return a `SignalingError::NoPeer`.
-    fn encrypt_raw_with_session_keys(&self, data: &[u8], nonce: &box_::Nonce) -> SignalingResult<Vec<u8>> {
+    fn encrypt_raw_with_session_keys(&self, data: &[u8], nonce: &[u8; NONCEBYTES]) -> SignalingResult<Vec<u8>> {
         let peer = self.get_peer()
             .ok_or_else(|| SignalingError::NoPeer)?;
-        let peer_session_public_key = peer.session_key()
-            .ok_or_else(|| SignalingError::Crash("Peer session public key not set".into()))?;
-        let our_session_private_key = peer.keypair()
-            .map(|keypair: &KeyPair| keypair.private_key())
-            .ok_or_else(|| SignalingError::Crash("Our session private key not set".into()))?;
-        Ok(box_::seal(data, nonce, peer_session_public_key, our_session_private_key))
+        let (our_session_keypair, peer_session_public_key) = peer.session_keys()?;
+        let our_session_private_key = our_session_keypair.private_key();
+        Ok(crypto_backend::box_seal(data, nonce, peer_session_public_key, our_session_private_key))
     }
 
     /// Decrypt raw bytes for the peer using the session keys.
     ///
     /// If this function is called before the peer has been established,
     /// it will return a `SignalingError::NoPeer`.
-    fn decrypt_raw_with_session_keys(&self, data: &[u8], nonce: &box_::Nonce) -> SignalingResult<Vec<u8>> {
+    fn decrypt_raw_with_session_keys(&self, data: &[u8], nonce: &[u8; NONCEBYTES]) -> SignalingResult<Vec<u8>> {
         let peer = self.get_peer()
             .ok_or_else(|| SignalingError::NoPeer)?;
-        let peer_session_public_key = peer.session_key()
-            .ok_or_else(|| SignalingError::Crash("Peer session public key not set".into()))?;
-        let our_session_private_key = peer.keypair()
-            .map(|keypair: &KeyPair| keypair.private_key())
-            .ok_or_else(|| SignalingError::Crash("Our session private key not set".into()))?;
-        box_::open(data, nonce, peer_session_public_key, our_session_private_key)
+        let (our_session_keypair, peer_session_public_key) = peer.session_keys()?;
+        let our_session_private_key = our_session_keypair.private_key();
+        crypto_backend::box_open(data, nonce, peer_session_public_key, our_session_private_key)
             .map_err(|_| SignalingError::Crypto("Could not decrypt bytes".into()))
     }
 }
 
+/// Validate the CSN of a nonce from a peer and store it.
+///
+/// The CSN must have been increased since the last message from the peer.
+/// For the first message, the overflow number must be 0.
+fn update_their_csn(
+    csn_pair: &mut CombinedSequencePair,
+    nonce: &Nonce,
+    peer_identity: Identity,
+) -> Result<(), ValidationError> {
+    // If we already have the CSN of the peer,
+    // ensure that it has been increased properly.
+    if let Some(ref mut csn) = csn_pair.theirs {
+        let previous = csn;
+        let current = nonce.csn();
+        if current < previous {
+            let msg = format!("The {} CSN is lower than last time", peer_identity);
+            return Err(ValidationError::Fail(msg));
+        } else if current == previous {
+            let msg = format!("The {} CSN hasn't been incremented", peer_identity);
+            return Err(ValidationError::Fail(msg));
+        } else {
+            *previous = current.clone();
+        }
+    }
+
+    // Otherwise, this is the first message from that peer.
+    if csn_pair.theirs.is_none() {
+        // Validate the overflow number...
+        if nonce.csn().overflow_number() != 0 {
+            let msg = format!("First message from {} must have set the overflow number to 0", peer_identity);
+            return Err(ValidationError::Fail(msg));
+        }
+        // ...and store the CSN.
+        csn_pair.theirs = Some(nonce.csn().clone());
+    }
+
+    Ok(())
+}
+
+/// Convert the error of a nonce that was sent along with a task payload.
+///
+/// Unlike for messages, invalid nonces are never ignored.
+fn payload_nonce_error(error: ValidationError) -> SignalingError {
+    match error {
+        ValidationError::DropMsg(reason) | ValidationError::Fail(reason) => SignalingError::InvalidNonce(reason),
+        ValidationError::Crash(reason) => SignalingError::Crash(reason),
+    }
+}
+
 
 /// A peer can be authenticated either through a one-time auth token, or
 /// through a trusted peer public key.
@@ -844,6 +1426,16 @@ pub(crate) enum AuthProvider {
     TrustedKey(PublicKey)
 }
 
+impl AuthProvider {
+    /// Return the authentication method provided by this auth provider.
+    fn method(&self) -> AuthMethod {
+        match *self {
+            AuthProvider::Token(_) => AuthMethod::Token,
+            AuthProvider::TrustedKey(_) => AuthMethod::TrustedKey,
+        }
+    }
+}
+
 /// Common functionality and state for all signaling types.
 pub(crate) struct Common {
     /// The signaling state.
@@ -856,6 +1448,28 @@ pub(crate) struct Common {
     /// key (for trusted sessions).
     pub(crate) auth_provider: Option<AuthProvider>,
 
+    /// A copy of the auth provider as it was before the handshake started.
+    ///
+    /// This is required to restart the handshake with another server. It is
+    /// cleared once the peer handshake is done.
+    pub(crate) initial_auth_provider: Option<AuthProvider>,
+
+    /// How the peer was authenticated, set once the peer handshake is done.
+    pub(crate) peer_auth_method: Option<AuthMethod>,
+
+    /// The unknown fields of the 'auth' message sent by the peer.
+    pub(crate) peer_extra_fields: ExtraFields,
+
+    /// The unknown fields of the last signaling message of each type that
+    /// contained any.
+    pub(crate) received_extra_fields: HashMap<&'static str, ExtraFields>,
+
+    /// The data of the chosen task sent by the peer in its 'auth' message.
+    pub(crate) peer_task_data: Option<HashMap<String, Value>>,
+
+    /// Whether the current handshake renews a previous session.
+    pub(crate) renewing: bool,
+
     /// The assigned role.
     pub(crate) role: Role,
 
@@ -880,11 +1494,75 @@ pub(crate) struct Common {
 
     /// The interval at which the server should send WebSocket ping messages.
     pub(crate) ping_interval: Option<Duration>,
+
+    /// The protocol version spoken with the server and the peer.
+    pub(crate) protocol_version: ProtocolVersion,
+
+    /// The source for random values (cookies, sequence numbers, session keys).
+    pub(crate) rng: Box<dyn RandomSource + Send>,
+
+    /// The clock used for timeouts, rate limits and heartbeats.
+    pub(crate) clock: Arc<dyn Clock>,
+
+    /// The heartbeat state for the authenticated peer.
+    pub(crate) heartbeat: Heartbeat,
+
+    /// Whether rotating the session keys is enabled.
+    pub(crate) rekeying: bool,
+
+    /// The state of the key rotation with the authenticated peer.
+    pub(crate) rekey: Rekey,
+
+    /// The recorder that is called for every sent and received message.
+    pub(crate) transcript: Option<Box<dyn TranscriptRecorder>>,
+
+    /// The observer that may drop or annotate messages.
+    pub(crate) observer: Option<Box<dyn Observer>>,
+
+    /// The transcript entry for the incoming message that is being handled.
+    incoming_entry: Option<TranscriptEntry>,
+
+    /// The CSN of the last peer message that was passed on to be handled
+    /// sequentially while the messages are decrypted in parallel.
+    #[cfg_attr(not(feature = "connect-tokio"), allow(dead_code))]
+    sequential_csn: Option<CombinedSequenceSnapshot>,
+
+    /// The message and traffic counters.
+    pub(crate) stats: StatsCollector,
+
+    /// The recently sent client-to-client messages, to look up the message
+    /// referenced by a `send-error`.
+    pub(crate) sent_messages: SentMessages,
+
+    /// The payloads from the peer that arrived before the preceding ones.
+    pub(crate) reorder: ReorderBuffer,
+
+    /// The task messages that could not be sent because the outgoing
+    /// sequence numbers were exhausted. They are sent once the renewed
+    /// session is established.
+    pub(crate) renewal_queue: Vec<Value>,
+
+    /// Whether an incoming message failed. The connection must be closed
+    /// after the first error, no further messages are handled.
+    failed: bool,
+
+    /// Whether the session with the peer ended without closing the
+    /// connection, e.g. because the initiator was replaced after the peer
+    /// handshake. The task has been closed, no more task messages are sent
+    /// or received.
+    peer_session_closed: bool,
+
+    /// The rate limit for server messages before the server handshake is
+    /// done.
+    pub(crate) server_rate_limit: Option<RateLimit>,
+
+    /// The token bucket for server messages, created on the first message.
+    server_rate_limiter: Option<TokenBucket>,
 }
 
 impl Common {
     /// Return the current signaling state.
-    fn signaling_state(&self) -> SignalingState {
+    pub(crate) fn signaling_state(&self) -> SignalingState {
         self.signaling_state
     }
 
@@ -900,12 +1578,329 @@ impl Common {
             ));
         }
         trace!("Signaling state transition: {:?} -> {:?}", self.signaling_state(), state);
-        self.signaling_state = state;
+        self.change_signaling_state(state);
+        if state == SignalingState::Task {
+            if let Some(provider) = self.initial_auth_provider.take() {
+                self.peer_auth_method = Some(provider.method());
+            }
+        }
         Ok(())
     }
 
-    /// Set the current signaling state.
-    #[cfg(test)]
+    /// Continue an established session with the server.
+    ///
+    /// If the session contains our address, the server handshake is done
+    /// and the signaling continues with the peer handshake.
+    #[cfg(feature = "server-session")]
+    pub(crate) fn restore_server_session(&mut self, session: &ServerSession) -> SignalingResult<()> {
+        let identity = match (self.role, session.address) {
+            (_, None) => None,
+            (Role::Initiator, Some(0x01)) => Some(ClientIdentity::Initiator),
+            (Role::Responder, Some(address @ 0x02..=0xff)) => Some(ClientIdentity::Responder(address)),
+            (role, Some(address)) => return Err(SignalingError::InvalidMessage(
+                format!("{} cannot use address {}", role, address)
+            )),
+        };
+        self.server = ServerContext::from_session(session, self.server.permanent_key)?;
+        if let Some(identity) = identity {
+            debug!("Assigned identity: {}", identity);
+            self.identity = identity;
+            self.set_signaling_state(SignalingState::PeerHandshake)?;
+        }
+        Ok(())
+    }
+
+    /// Change the signaling state and notify the observer.
+    fn change_signaling_state(&mut self, state: SignalingState) {
+        let old = mem::replace(&mut self.signaling_state, state);
+        let now = self.clock.now();
+        match state {
+            SignalingState::PeerHandshake => self.stats.server_handshake_done(now),
+            SignalingState::Task => self.stats.peer_handshake_done(now),
+            SignalingState::ServerHandshake => {},
+        }
+        if let Some(ref mut observer) = self.observer {
+            if old != state {
+                observer.on_state_change(old, state);
+            }
+        }
+    }
+
+    /// Validate the public session key sent by the server in its
+    /// 'server-hello' message.
+    ///
+    /// The key must neither be equal to our permanent key, nor to the pinned
+    /// permanent key of the server.
+    fn validate_server_session_key(&self, key: &PublicKey) -> SignalingResult<()> {
+        if key == self.permanent_keypair.public_key() {
+            return Err(SignalingError::InvalidKey("Server session key is equal to our permanent key".into()));
+        }
+        if self.server.permanent_key() == Some(key) {
+            return Err(SignalingError::InvalidKey("Server session key and permanent key are equal".into()));
+        }
+        Ok(())
+    }
+
+    /// Validate the public session key sent by a peer in its 'key' message.
+    ///
+    /// The key must neither be equal to the permanent key of the peer, nor
+    /// to our own session or permanent key.
+    fn validate_peer_session_key(&self, key: &PublicKey, peer_permanent_key: &PublicKey,
+                                 our_session_key: &PublicKey) -> SignalingResult<()> {
+        if key == peer_permanent_key {
+            return Err(SignalingError::InvalidKey("Peer session key and permanent key are equal".into()));
+        }
+        if key == our_session_key || key == self.permanent_keypair.public_key() {
+            return Err(SignalingError::InvalidKey("Peer session key is equal to one of our keys".into()));
+        }
+        Ok(())
+    }
+
+    /// Reset the server handshake state, keeping the permanent keys, the
+    /// initial auth provider and the tasks.
+    #[cfg_attr(not(feature = "connect-tokio"), allow(dead_code))]
+    fn reset(&mut self) -> SignalingResult<()> {
+        if self.signaling_state == SignalingState::Task {
+            return Err(SignalingError::Crash("Cannot reset signaling after the handshake is done".into()));
+        }
+        self.auth_provider = self.initial_auth_provider.clone();
+        self.reset_server();
+        Ok(())
+    }
+
+    /// Prepare a trusted handshake with the peer of the current session,
+    /// offering the chosen task again.
+    fn renew(&mut self, peer_key: PublicKey, task: Arc<Mutex<BoxedTask>>) {
+        let auth_provider = AuthProvider::TrustedKey(peer_key);
+        self.initial_auth_provider = Some(auth_provider.clone());
+        self.auth_provider = Some(auth_provider);
+        self.tasks = Some(Tasks::new(Box::new(SharedTask(task))));
+        self.task = None;
+        self.task_supported_types = None;
+        self.peer_auth_method = None;
+        self.peer_extra_fields = ExtraFields::default();
+        self.peer_task_data = None;
+        self.renewing = true;
+        self.reset_server();
+    }
+
+    /// Forget the auth token for good.
+    fn invalidate_auth_token(&mut self) {
+        if let Some(AuthProvider::Token(_)) = self.auth_provider {
+            self.auth_provider = None;
+        }
+        if let Some(AuthProvider::Token(_)) = self.initial_auth_provider {
+            self.initial_auth_provider = None;
+        }
+    }
+
+    /// Replace the auth token with a new random token.
+    fn rotate_auth_token(&mut self) -> SignalingResult<AuthToken> {
+        if self.role != Role::Initiator {
+            return Err(SignalingError::Crash("Only initiators can rotate the auth token".into()));
+        }
+        if self.signaling_state == SignalingState::Task {
+            return Err(SignalingError::Crash("Cannot rotate the auth token after the handshake is done".into()));
+        }
+        if let Some(AuthProvider::TrustedKey(_)) = self.initial_auth_provider {
+            return Err(SignalingError::Crash("Cannot rotate the auth token when using a trusted key".into()));
+        }
+        info!("Generating new auth token");
+        let token = AuthToken::from_random_source(&mut *self.rng);
+        self.set_auth_token(token.clone());
+        Ok(token)
+    }
+
+    /// Authenticate the peer with the specified auth token from now on.
+    pub(crate) fn set_auth_token(&mut self, token: AuthToken) {
+        let auth_provider = AuthProvider::Token(token);
+        self.initial_auth_provider = Some(auth_provider.clone());
+        self.auth_provider = Some(auth_provider);
+    }
+
+    /// Forget the state related to the previous peer and restore the auth
+    /// provider, so that the peer handshake can be started again.
+    fn reset_peer_state(&mut self) {
+        if self.initial_auth_provider.is_some() {
+            self.auth_provider = self.initial_auth_provider.clone();
+        }
+        self.heartbeat = Heartbeat::default();
+        self.rekey = Rekey::default();
+        self.sent_messages.clear();
+        self.reorder.clear();
+        self.server_rate_limiter = None;
+        self.peer_extra_fields = ExtraFields::default();
+        self.received_extra_fields.clear();
+        self.peer_task_data = None;
+    }
+
+    /// Start over with a new server context, keeping the server permanent key.
+    fn reset_server(&mut self) {
+        self.change_signaling_state(SignalingState::ServerHandshake);
+        self.identity = ClientIdentity::Unknown;
+        let server_permanent_key = self.server.permanent_key;
+        self.server = ServerContext::with_rng(&mut *self.rng);
+        self.server.permanent_key = server_permanent_key;
+        self.failed = false;
+        self.peer_session_closed = false;
+        self.heartbeat = Heartbeat::default();
+        self.rekey = Rekey::default();
+        self.sent_messages.clear();
+        self.reorder.clear();
+    }
+
+    /// Fail with `SignalingError::NoPeer` if the session with the peer has
+    /// been closed.
+    fn check_peer_session(&self) -> SignalingResult<()> {
+        if self.peer_session_closed {
+            return Err(SignalingError::NoPeer);
+        }
+        Ok(())
+    }
+
+    /// Fail if a previous message failed.
+    fn check_not_failed(&self) -> SignalingResult<()> {
+        if self.failed {
+            return Err(SignalingError::Crash("Cannot handle messages after a signaling error".into()));
+        }
+        Ok(())
+    }
+
+    /// Take a token from the server rate limiter.
+    ///
+    /// Return `false` if the server exceeded the rate limit.
+    fn take_server_token(&mut self, now: Instant) -> bool {
+        let limit = match self.server_rate_limit {
+            Some(limit) => limit,
+            None => return true,
+        };
+        self.server_rate_limiter
+            .get_or_insert_with(|| TokenBucket::new(limit, now))
+            .try_take(now)
+    }
+
+    /// Return the event that announces the end of the peer handshake.
+    fn handshake_done_event(&mut self, task_name: String) -> Event {
+        if mem::replace(&mut self.renewing, false) {
+            Event::SessionRenewed
+        } else {
+            Event::PeerHandshakeDone(task_name)
+        }
+    }
+
+    /// Count an incoming message and start its transcript entry, if a
+    /// transcript recorder is registered.
+    fn begin_incoming(&mut self, bbox: &ByteBox) {
+        self.stats.incoming(bbox.frame_len());
+        if self.transcript.is_some() {
+            self.incoming_entry = Some(TranscriptEntry::new(Direction::Incoming, bbox));
+        }
+    }
+
+    /// Pass an incoming message to the observer before its nonce is
+    /// validated.
+    ///
+    /// Return whether the message should be processed.
+    fn observe_incoming(&mut self, bbox: &ByteBox) -> bool {
+        let observer = match self.observer {
+            Some(ref mut observer) => observer,
+            None => return true,
+        };
+        let bytes = bbox.to_bytes();
+        let frame = Frame { source: bbox.nonce.source().0, destination: bbox.nonce.destination().0, bytes: &bytes };
+        observer.on_incoming(&frame)
+            .apply(format_args!("Incoming message from {}", Identity::from(bbox.nonce.source())))
+    }
+
+    /// Pass a decoded incoming message to the observer.
+    ///
+    /// Return whether the message should be processed.
+    fn observe_decrypted(&mut self, nonce: &Nonce, message_type: Option<&str>, value: &Value) -> bool {
+        let observer = match self.observer {
+            Some(ref mut observer) => observer,
+            None => return true,
+        };
+        let decoded = Decoded {
+            source: nonce.source().0,
+            destination: nonce.destination().0,
+            message_type,
+            value,
+        };
+        observer.on_decrypted(&decoded).apply(format_args!(
+            "Incoming '{}' message from {}", message_type.unwrap_or("?"), Identity::from(nonce.source()),
+        ))
+    }
+
+    /// Add the decoded signaling message to the incoming transcript entry
+    /// and pass it to the observer.
+    ///
+    /// Return whether the message should be processed.
+    fn incoming_message(&mut self, obox: &OpenBox<Message>) -> bool {
+        let message = &obox.message;
+        let extra_fields = message.extra_fields();
+        if !extra_fields.is_empty() {
+            debug!("Message '{}' contains {} unknown fields", message.get_type(), extra_fields.len());
+            self.received_extra_fields.insert(message.get_type(), extra_fields.clone());
+        }
+        self.stats.incoming_type(message.get_type());
+        self.incoming_entry = self.incoming_entry.take().map(|entry| entry.with_plaintext(obox.plaintext.as_ref()));
+        self.observer.is_none() || self.observe_decrypted(&obox.nonce, Some(message.get_type()), &message.to_value())
+    }
+
+    /// Add the decoded task message to the incoming transcript entry and
+    /// pass it to the observer.
+    ///
+    /// Return whether the message should be processed.
+    fn incoming_value(&mut self, obox: &OpenBox<Value>) -> bool {
+        let value = &obox.message;
+        if let Some(message_type) = value_type(value) {
+            self.stats.incoming_type(message_type);
+        }
+        self.incoming_entry = self.incoming_entry.take().map(|entry| entry.with_plaintext(obox.plaintext.as_ref()));
+        self.observe_decrypted(&obox.nonce, value_type(value), value)
+    }
+
+    /// Record the incoming transcript entry.
+    fn finish_incoming(&mut self) {
+        if let Some(entry) = self.incoming_entry.take() {
+            self.record(entry);
+        }
+    }
+
+    /// Pass an outgoing message to the observer, then count it, remember it
+    /// for `send-error` messages and record it, if a transcript recorder is
+    /// registered.
+    ///
+    /// Every message sent to the server or a peer must pass through here.
+    /// Return whether the message should be sent. Messages that are dropped
+    /// by the observer are neither counted nor recorded.
+    pub(crate) fn record_outgoing(&mut self, bbox: &ByteBox) -> bool {
+        if let Some(ref mut observer) = self.observer {
+            let bytes = bbox.to_bytes();
+            let frame = Frame { source: bbox.nonce.source().0, destination: bbox.nonce.destination().0, bytes: &bytes };
+            let send = observer.on_outgoing(&frame)
+                .apply(format_args!("Outgoing message to {}", Identity::from(bbox.nonce.destination())));
+            if !send {
+                return false;
+            }
+        }
+        self.stats.outgoing(bbox.frame_len(), bbox.message_type());
+        self.sent_messages.track(&bbox.nonce, bbox.message_type().cloned());
+        if self.transcript.is_some() {
+            let entry = TranscriptEntry::new(Direction::Outgoing, bbox).with_plaintext(bbox.plaintext());
+            self.record(entry);
+        }
+        true
+    }
+
+    fn record(&mut self, entry: TranscriptEntry) {
+        if let Some(ref mut transcript) = self.transcript {
+            transcript.record(entry);
+        }
+    }
+
+    /// Set the current signaling state.
+    #[cfg(test)]
     fn set_signaling_state_forced(&mut self, state: SignalingState) -> SignalingResult<()> {
         trace!("Setting signaling state to {:?} for tests", state);
         self.signaling_state = state;
@@ -947,6 +1942,27 @@ pub(crate) struct InitiatorSignaling {
     // The responder counter, used to give every responder
     // an incrementing serial.
     pub(crate) responder_counter: ResponderCounter,
+
+    // The time after which responders that have not finished the peer
+    // handshake are dropped.
+    pub(crate) responder_timeout: Option<Duration>,
+
+    // The policy that decides which responders are accepted.
+    pub(crate) accept_policy: Option<Box<dyn AcceptPolicy>>,
+
+    // The number of responders above which the path is cleaned.
+    pub(crate) max_responders: usize,
+
+    // The rate limit for responders that haven't been authenticated.
+    pub(crate) peer_rate_limit: Option<RateLimit>,
+
+    // Whether a new auth token is generated once a responder used the
+    // current one.
+    pub(crate) auth_token_rotation: bool,
+
+    // Whether protocol violations of unauthenticated responders close the
+    // connection instead of only dropping the responder.
+    pub(crate) strict_mode: bool,
 }
 
 impl Signaling for InitiatorSignaling {
@@ -964,6 +1980,25 @@ impl Signaling for InitiatorSignaling {
         self.responder.as_ref().map(|p| p as &dyn PeerContext)
     }
 
+    fn peer_summaries(&self) -> Vec<PeerSummary> {
+        let mut responders: Vec<&ResponderContext> = self.responders.values().collect();
+        responders.sort_by_key(|responder| responder.address.0);
+        let mut summaries: Vec<PeerSummary> = responders.into_iter()
+            .map(|responder| PeerSummary::new(responder, &responder.handshake_state(), false))
+            .collect();
+        if let Some(ref responder) = self.responder {
+            summaries.push(PeerSummary::new(responder, &responder.handshake_state(), true));
+        }
+        summaries
+    }
+
+    fn replace_session_keys(&mut self, keypair: KeyPair, session_key: PublicKey) -> SignalingResult<(KeyPair, PublicKey)> {
+        let responder = self.responder.as_mut().ok_or(SignalingError::NoPeer)?;
+        let previous_session_key = responder.session_key.replace(session_key)
+            .ok_or_else(|| SignalingError::Crash("Responder session key not set".into()))?;
+        Ok((mem::replace(&mut responder.keypair, keypair), previous_session_key))
+    }
+
     fn get_peer_with_address_mut(&mut self, addr: Address) -> Option<&mut dyn PeerContext> {
         let identity: Identity = addr.into();
         match identity {
@@ -1004,6 +2039,24 @@ impl Signaling for InitiatorSignaling {
         self.common().permanent_keypair.public_key()
     }
 
+    fn reset_peers(&mut self) {
+        self.responders.clear();
+        self.responder = None;
+        self.responder_counter = ResponderCounter::new();
+    }
+
+    fn rate_limit_peer(&mut self, source: Address, now: Instant) -> SignalingResult<Option<HandleActions>> {
+        let exceeded = match self.responders.get_mut(&source).and_then(|r| r.rate_limiter.as_mut()) {
+            Some(bucket) => !bucket.try_take(now),
+            None => false,
+        };
+        if !exceeded {
+            return Ok(None);
+        }
+        warn!("Responder {} exceeded the message rate limit, dropping", source);
+        self.drop_responder(source, ResponderDropReason::RateLimited).map(Some)
+    }
+
     fn validate_nonce_destination(&mut self, nonce: &Nonce) -> Result<(), ValidationError> {
         // A client MUST check that the destination address targets its
         // assigned identity (or `0x00` during authentication).
@@ -1060,6 +2113,15 @@ impl Signaling for InitiatorSignaling {
         }
     }
 
+    fn unknown_peer_error(&self, source: Address) -> ValidationError {
+        if source.is_responder() {
+            // Note: This can happen since a message from a responder may still be in flight.
+            ValidationError::DropMsg(format!("Could not find responder with address {}", source))
+        } else {
+            ValidationError::Crash("Got message from invalid sender that wasn't dropped".into())
+        }
+    }
+
     fn decode_peer_message(&self, bbox: ByteBox) -> SignalingResult<OpenBox<Message>> {
         // Validate source again
         if !bbox.nonce.source().is_responder() {
@@ -1075,27 +2137,14 @@ impl Signaling for InitiatorSignaling {
             )),
         };
 
-        // Helper functions
-        fn responder_permanent_key(responder: &ResponderContext) -> SignalingResult<&PublicKey> {
-            responder.permanent_key.as_ref()
-                .ok_or_else(|| SignalingError::Crash(
-                    format!("Did not find public permanent key for responder {}", responder.address.0)
-                ))
-        }
-        fn responder_session_key(responder: &ResponderContext) -> SignalingResult<&PublicKey> {
-            responder.session_key.as_ref()
-                .ok_or_else(|| SignalingError::Crash(
-                    format!("Did not find public session key for responder {}", responder.address.0)
-                ))
-        }
-
         // Decrypt depending on state
-        match responder.handshake_state() {
+        let handshake_state = responder.handshake_state();
+        let decrypted = match handshake_state {
             ResponderHandshakeState::New => {
                 // Expect token message, encrypted with authentication token.
                 debug!("Expect token message");
                 match self.common.auth_provider {
-                    Some(AuthProvider::Token(ref token)) => OpenBox::decrypt_token(bbox, token),
+                    Some(AuthProvider::Token(ref token)) => OpenBox::decrypt_token(bbox, token, &["token"]),
                     Some(AuthProvider::TrustedKey(_)) => Err(SignalingError::Crash(
                         "Handshake state is \"New\" even though a trusted key is available".into()
                     )),
@@ -1106,30 +2155,62 @@ impl Signaling for InitiatorSignaling {
             },
             ResponderHandshakeState::TokenReceived => {
                 // Expect key message, encrypted with our public permanent key
-                // and responder private permanent key
+                // and responder private permanent key. A token message is
+                // decoded as well, so that the responder can be dropped.
                 debug!("Expect key message");
-                OpenBox::<Message>::decrypt(
-                    bbox,
-                    &self.common.permanent_keypair,
-                    responder_permanent_key(&responder)?
-                ).map_err(|e| match e {
-                    SignalingError::Decode(_) => {
-                        warn!("Could not decrypt key message");
-                        SignalingError::InitiatorCouldNotDecrypt
-                    },
-                    e => e,
-                })
+                responder.decrypt_message_permanent(bbox, &self.common.permanent_keypair, &["key", "token"])
             },
             ResponderHandshakeState::KeySent => {
                 // Expect auth message, encrypted with our public session key
                 // and responder private session key
-                OpenBox::<Message>::decrypt(bbox, &responder.keypair, responder_session_key(&responder)?)
+                debug!("Expect auth message");
+                responder.decrypt_message(bbox, &["auth"])
             },
             other => {
                 // TODO (#14): Maybe remove these states?
-                Err(SignalingError::Crash(format!("Invalid responder handshake state: {:?}", other)))
+                return Err(SignalingError::Crash(format!("Invalid responder handshake state: {:?}", other)));
+            },
+        };
+
+        // The responder is not authenticated yet, so a message that cannot
+        // be decrypted must not fail the whole connection. Instead, only
+        // this responder will be dropped.
+        decrypted.map_err(|e| match e {
+            SignalingError::Crypto(_) => {
+                warn!("Could not decrypt message from {} in state {:?}", responder.identity(), handshake_state);
+                SignalingError::InitiatorCouldNotDecrypt
+            },
+            e => e,
+        })
+    }
+
+    fn handle_peer_decode_error(&mut self, source: Address, error: SignalingError) -> SignalingResult<HandleActions> {
+        match error {
+            SignalingError::InitiatorCouldNotDecrypt => {
+                // Forget about the responder, the handshakes with other
+                // responders continue.
+                self.common.stats.decryption_failed();
+                self.drop_responder(source, ResponderDropReason::DecryptionFailed)
             },
+            e => self.handle_peer_error(source, e),
+        }
+    }
+
+    fn handle_peer_error(&mut self, source: Address, error: SignalingError) -> SignalingResult<HandleActions> {
+        // In lenient mode, only the offending responder is dropped
+        match error {
+            SignalingError::Decode(_)
+            | SignalingError::InvalidMessage(_)
+            | SignalingError::InvalidNonce(_)
+            | SignalingError::InvalidStateTransition(_)
+            | SignalingError::UnexpectedMessage(_)
+            | SignalingError::InvalidKey(_)
+            | SignalingError::Protocol(_)
+                if !self.strict_mode && self.responders.contains_key(&source) => {},
+            e => return Err(e),
         }
+        warn!("Dropping responder {} after a protocol violation: {}", source, error);
+        self.drop_responder(source, ResponderDropReason::ProtocolError)
     }
 
     /// Determine the next peer handshake state based on the incoming
@@ -1137,7 +2218,7 @@ impl Signaling for InitiatorSignaling {
     ///
     /// This method call may have some side effects, like updates in the peer
     /// context (cookie, CSN, etc).
-    fn handle_peer_message(&mut self, obox: OpenBox<Message>) -> SignalingResult<Vec<HandleAction>> {
+    fn handle_peer_message(&mut self, obox: OpenBox<Message>) -> SignalingResult<HandleActions> {
         let source = obox.nonce.source();
         let old_state = {
             let responder = self.responders.get(&source)
@@ -1152,16 +2233,17 @@ impl Signaling for InitiatorSignaling {
             // Valid state transitions
             (ResponderHandshakeState::New, Message::Token(msg)) => self.handle_token(msg, source),
             (ResponderHandshakeState::TokenReceived, Message::Key(msg)) => self.handle_key(msg, source),
+            (ResponderHandshakeState::TokenReceived, Message::Token(_)) => self.handle_unexpected_token(source),
             (ResponderHandshakeState::KeySent, Message::Auth(msg)) => self.handle_auth(msg, source),
 
             // Any undefined state transition results in an error
-            (s, message) => Err(SignalingError::InvalidStateTransition(
-                format!("Got {} message from responder {} in {:?} state", message.get_type(), obox.nonce.source().0, s)
-            )),
+            (s, message) => Err(SignalingError::UnexpectedMessage(ErrorContext::new(
+                self.common().signaling_state(), s, message.get_type(), &obox.nonce,
+            ))),
         }
     }
 
-    fn handle_server_auth_impl(&mut self, msg: &ServerAuth) -> SignalingResult<Vec<HandleAction>> {
+    fn handle_server_auth_impl(&mut self, msg: &ServerAuth) -> SignalingResult<HandleActions> {
         // In case the client is the initiator, it SHALL check that the
         // responders field is set and contains an Array of responder
         // identities.
@@ -1200,43 +2282,26 @@ impl Signaling for InitiatorSignaling {
         // It SHOULD store the responder's identities in its internal list of
         // responders. Additionally, the initiator MUST keep its path clean by
         // following the procedure described in the Path Cleaning section.
-        let mut actions = vec![];
+        let mut actions = HandleActions::new();
         for address in responders_set {
-            if let Some(drop_responder) = self.process_new_responder(address)? {
-                actions.push(drop_responder);
-            }
+            actions.merge(self.process_new_responder(address)?);
         }
 
-        actions.push(HandleAction::Event(Event::ServerHandshakeDone(responders.is_empty())));
+        actions.push_event(Event::ServerHandshakeDone(responders.is_empty()));
         Ok(actions)
     }
 
-    /// Handle an incoming [`NewInitiator`](messages/struct.Initiator.html) message.
-    fn handle_new_initiator(&mut self, _msg: NewInitiator) -> SignalingResult<Vec<HandleAction>> {
-        Err(SignalingError::Protocol("Received 'new-responder' message as initiator".into()))
-    }
-
-    /// Handle an incoming [`NewResponder`](messages/struct.NewResponder.html) message.
-    fn handle_new_responder(&mut self, msg: NewResponder) -> SignalingResult<Vec<HandleAction>> {
-        debug!("--> Received new-responder ({}) from server", msg.id);
-
-        // An initiator who receives a 'new-responder' message SHALL validate
-        // that the id field contains a valid responder address (0x02..0xff).
-        if !msg.id.is_responder() {
-            return Err(SignalingError::InvalidMessage(
-                "`id` field in new-responder message is not a valid responder address".into()
-            ));
-        }
-
-        // Process responder
-        match self.process_new_responder(msg.id)? {
-            Some(drop_responder) => Ok(vec![drop_responder]),
-            None => Ok(vec![]),
+    fn handle_role_server_message(&mut self, msg: Message) -> SignalingResult<HandleActions> {
+        match msg {
+            Message::NewResponder(msg) => self.handle_new_responder(msg),
+            other => Err(SignalingError::Protocol(
+                format!("Received '{}' message as initiator", other.get_type())
+            )),
         }
     }
 
     /// Handle an incoming [`Disconnected`](messages/struct.Disconnected.html) message.
-    fn handle_disconnected(&mut self, msg: Disconnected) -> SignalingResult<Vec<HandleAction>> {
+    fn handle_disconnected(&mut self, msg: Disconnected) -> SignalingResult<HandleActions> {
         debug!("--> Received disconnected from server");
 
         // An initiator who receives a 'disconnected' message SHALL validate
@@ -1247,28 +2312,83 @@ impl Signaling for InitiatorSignaling {
             ));
         }
 
-        Ok(vec![HandleAction::Event(Event::Disconnected(msg.id.0))])
+        Ok(HandleActions::from(HandleAction::Event(Event::Disconnected(msg.id.0))))
+    }
+
+    /// Drop the responder if it did not complete the peer handshake within
+    /// the responder timeout.
+    fn handle_timeout(&mut self, id: TimerId) -> SignalingResult<HandleActions> {
+        match id {
+            TimerId::Responder(addr) => {
+                if !self.responders.contains_key(&addr) {
+                    return Ok(HandleActions::new());
+                }
+                info!("Responder {} did not complete the handshake in time, dropping it", Identity::from(addr));
+                self.drop_responder(addr, ResponderDropReason::Timeout)
+            },
+            TimerId::Initiator | TimerId::Heartbeat | TimerId::Handshake => Ok(HandleActions::new()),
+        }
+    }
+
+    /// Drop the responders that are still in the handshake, so that they
+    /// don't have to wait for a timeout.
+    #[cfg_attr(not(feature = "connect-tokio"), allow(dead_code))]
+    fn drop_pending_peers(&mut self) -> SignalingResult<HandleActions> {
+        let mut pending: Vec<Address> = self.responders.keys().cloned().collect();
+        pending.sort();
+        let mut actions = HandleActions::new();
+        for addr in pending {
+            info!("Dropping responder {} before closing the connection", Identity::from(addr));
+            actions.merge(self.drop_responder(addr, ResponderDropReason::Closing)?);
+        }
+        Ok(actions)
     }
 }
 
 impl InitiatorSignaling {
+    #[cfg(test)]
     pub(crate) fn new(permanent_keypair: KeyPair,
                       tasks: Tasks,
                       responder_trusted_pubkey: Option<PublicKey>,
                       server_public_permanent_key: Option<PublicKey>,
                       ping_interval: Option<Duration>) -> Self {
+        Self::with_rng(
+            permanent_keypair,
+            tasks,
+            responder_trusted_pubkey,
+            server_public_permanent_key,
+            ping_interval,
+            Box::new(OsRandom),
+        )
+    }
+
+    /// Create a new `InitiatorSignaling` instance that draws all random
+    /// values from the specified random source.
+    pub(crate) fn with_rng(permanent_keypair: KeyPair,
+                           tasks: Tasks,
+                           responder_trusted_pubkey: Option<PublicKey>,
+                           server_public_permanent_key: Option<PublicKey>,
+                           ping_interval: Option<Duration>,
+                           mut rng: Box<dyn RandomSource + Send>) -> Self {
+        let auth_provider = match responder_trusted_pubkey {
+            Some(key) => AuthProvider::TrustedKey(key),
+            None => AuthProvider::Token(AuthToken::from_random_source(&mut *rng)),
+        };
         InitiatorSignaling {
             common: Common {
                 signaling_state: SignalingState::ServerHandshake,
                 role: Role::Initiator,
                 identity: ClientIdentity::Unknown,
                 permanent_keypair,
-                auth_provider: Some(match responder_trusted_pubkey {
-                    Some(key) => AuthProvider::TrustedKey(key),
-                    None => AuthProvider::Token(AuthToken::new()),
-                }),
+                initial_auth_provider: Some(auth_provider.clone()),
+                peer_auth_method: None,
+                peer_extra_fields: ExtraFields::default(),
+                received_extra_fields: HashMap::new(),
+                peer_task_data: None,
+                renewing: false,
+                auth_provider: Some(auth_provider),
                 server: {
-                    let mut ctx = ServerContext::new();
+                    let mut ctx = ServerContext::with_rng(&mut *rng);
                     ctx.permanent_key = server_public_permanent_key;
                     ctx
                 },
@@ -1276,16 +2396,40 @@ impl InitiatorSignaling {
                 task: None,
                 task_supported_types: None,
                 ping_interval,
+                protocol_version: ProtocolVersion::default(),
+                rng,
+                heartbeat: Heartbeat::default(),
+                rekeying: false,
+                rekey: Rekey::default(),
+                clock: Arc::new(SystemClock),
+                transcript: None,
+                observer: None,
+                incoming_entry: None,
+                sequential_csn: None,
+                stats: StatsCollector::default(),
+                sent_messages: SentMessages::default(),
+                reorder: ReorderBuffer::default(),
+                renewal_queue: vec![],
+                failed: false,
+                peer_session_closed: false,
+                server_rate_limit: Some(DEFAULT_SERVER_RATE_LIMIT),
+                server_rate_limiter: None,
             },
             responders: HashMap::new(),
             responder: None,
             responder_counter: ResponderCounter::new(),
+            responder_timeout: None,
+            accept_policy: None,
+            max_responders: MAX_RESPONDERS,
+            peer_rate_limit: Some(DEFAULT_PEER_RATE_LIMIT),
+            auth_token_rotation: false,
+            strict_mode: true,
         }
     }
 
     /// Handle an incoming [`Token`](messages/struct.Token.html) message.
     #[cfg_attr(feature="cargo-clippy", allow(needless_pass_by_value))]
-    fn handle_token(&mut self, msg: Token, source: Address) -> SignalingResult<Vec<HandleAction>> {
+    fn handle_token(&mut self, msg: Token, source: Address) -> SignalingResult<HandleActions> {
         debug!("--> Received token from {}", Identity::from(source));
 
         {
@@ -1308,19 +2452,37 @@ impl InitiatorSignaling {
 
         } // Waiting for NLL
 
-        // Invalidate auth token
+        // Invalidate auth token. It must not be restored when the handshake
+        // is reset, since the responder (or whoever sent the token) knows it.
         match self.common().auth_provider {
             Some(AuthProvider::Token(_)) => {},
             _ => return Err(SignalingError::Crash("Auth provider is not a token".into())),
         }
-        self.common_mut().auth_provider = None;
+        self.common_mut().invalidate_auth_token();
+        self.common_mut().peer_auth_method = Some(AuthMethod::Token);
+
+        let mut actions = HandleActions::new();
+        if self.auth_token_rotation {
+            let token = self.common_mut().rotate_auth_token()?;
+            let pairing_data = PairingData::new(*self.common.permanent_keypair.public_key(), token);
+            actions.push_event(Event::AuthTokenRotated(pairing_data));
+        }
+        Ok(actions)
+    }
 
-        Ok(vec![])
+    /// Handle a [`Token`](messages/struct.Token.html) message from a
+    /// responder whose permanent key is already known, e.g. because it is
+    /// trusted.
+    ///
+    /// This is a protocol error, so the responder is dropped.
+    fn handle_unexpected_token(&mut self, source: Address) -> SignalingResult<HandleActions> {
+        warn!("Received token from {} even though its permanent key is known, dropping it", Identity::from(source));
+        self.drop_responder(source, ResponderDropReason::ProtocolError)
     }
 
     /// Handle an incoming [`Key`](messages/struct.Key.html) message.
     #[cfg_attr(feature="cargo-clippy", allow(needless_pass_by_value))]
-    fn handle_key(&mut self, msg: Key, source: Address) -> SignalingResult<Vec<HandleAction>> {
+    fn handle_key(&mut self, msg: Key, source: Address) -> SignalingResult<HandleActions> {
         let source_identity = Identity::from(source);
         debug!("--> Received key from {}", source_identity);
 
@@ -1335,17 +2497,26 @@ impl InitiatorSignaling {
             return Err(SignalingError::Crash("Responder already has a session key set!".into()));
         }
 
-        // Ensure that session key != permanent key
+        // Ensure that the session key is not a key we already know
         match responder.permanent_key {
-            Some(pk) if pk == msg.key => {
-                return Err(SignalingError::Protocol("Responder session key and permanent key are equal".into()));
-            },
-            Some(_) => {},
+            Some(ref pk) => self.common.validate_peer_session_key(&msg.key, pk, responder.keypair.public_key())?,
             None => {
                 return Err(SignalingError::Crash("Responder permanent key not set".into()));
             }
         };
 
+        // Now that the responder has proven that it owns its permanent key,
+        // ask the application whether it should be accepted.
+        if let (Some(policy), Some(permanent_key)) = (self.accept_policy.as_mut(), responder.permanent_key.as_ref()) {
+            // The auth token is invalidated after use, so check for the
+            // trusted key.
+            let used_token = self.common.auth_provider.as_ref().map(AuthProvider::method) != Some(AuthMethod::TrustedKey);
+            if policy.accept(permanent_key, used_token) == Decision::Reject {
+                info!("Responder {} rejected by accept policy, dropping it", source_identity);
+                return self.drop_responder(source, ResponderDropReason::RejectedByPolicy);
+            }
+        }
+
         // Set public session key
         responder.session_key = Some(msg.key);
 
@@ -1353,39 +2524,31 @@ impl InitiatorSignaling {
         responder.set_handshake_state(ResponderHandshakeState::KeyReceived);
 
         // Reply with our own key msg
-        let key: Message = Key { key: *responder.keypair.public_key() }.into_message();
-        let key_nonce = Nonce::new(
-            responder.cookie_pair().ours.clone(),
+        let key: Message = Key::new(*responder.keypair.public_key()).into_message();
+        let bbox = responder.encrypt_message_permanent(
+            key,
             self.common.identity.into(),
-            responder.identity().into(),
-            responder.csn_pair().try_write()?.ours.increment()?,
-        );
-        let obox = OpenBox::<Message>::new(key, key_nonce);
-        let bbox = obox.encrypt(
             &self.common.permanent_keypair,
-            responder.permanent_key.as_ref()
-                .ok_or_else(|| SignalingError::Crash("Responder permanent key not set".into()))?,
-        );
+        )?;
 
         // State transition
         responder.set_handshake_state(ResponderHandshakeState::KeySent);
 
+        // The key message proves that the responder owns its permanent key,
+        // so older contexts with the same key can be cleaned up now.
+        let permanent_key = responder.permanent_key;
+        let mut actions = match permanent_key {
+            Some(ref key) => self.drop_stale_responders(source, key)?,
+            None => HandleActions::new(),
+        };
+
         debug!("<-- Enqueuing key to {}", source_identity);
-        Ok(vec![HandleAction::Reply(bbox)])
+        actions.push(HandleAction::Reply(bbox));
+        Ok(actions)
     }
 
-    /// Handle an incoming [`Auth`](messages/struct.Auth.html) message.
-    fn handle_auth(&mut self, msg: Auth, source: Address) -> SignalingResult<Vec<HandleAction>> {
-        debug!("--> Received auth from {}", Identity::from(source));
-
-        let mut actions = vec![];
-
-        // Find responder instance
-        let mut responder = self.responders.remove(&source)
-            .ok_or_else(|| SignalingError::Crash(
-                format!("Did not find responder with address {}", source)
-            ))?;
-
+    /// Validate an incoming [`Auth`](messages/struct.Auth.html) message.
+    fn validate_auth(&self, msg: &Auth, responder: &ResponderContext) -> SignalingResult<()> {
         // The cookie provided in the `your_cookie` field SHALL contain the cookie
         // we have used in our previous messages to the responder.
         self.validate_repeated_cookie(
@@ -1401,7 +2564,7 @@ impl InitiatorSignaling {
         let proposed_tasks = match msg.tasks {
             None => return Err(SignalingError::InvalidMessage("The `tasks` field in the auth message is not set".into())),
             Some(ref tasks) if tasks.is_empty() => return Err(SignalingError::InvalidMessage("The `tasks` field in the auth message is empty".into())),
-            Some(tasks) => tasks,
+            Some(ref tasks) => tasks,
         };
 
         // Each element in the Array SHALL be a string.
@@ -1411,40 +2574,67 @@ impl InitiatorSignaling {
         if msg.data.len() != proposed_tasks.len() {
             return Err(SignalingError::InvalidMessage("The `tasks` and `data` fields in the auth message have a different number of entries".into()));
         };
-        for task in &proposed_tasks {
+        for task in proposed_tasks {
             if !msg.data.contains_key(task) {
                 return Err(SignalingError::InvalidMessage(format!("The task \"{}\" in the auth message does not have a corresponding data entry", task)));
             }
         }
+        Ok(())
+    }
+
+    /// Handle an incoming [`Auth`](messages/struct.Auth.html) message.
+    ///
+    /// If the message is invalid or if there is no shared task, only this
+    /// responder is dropped. The handshakes with the other responders
+    /// continue.
+    fn handle_auth(&mut self, mut msg: Auth, source: Address) -> SignalingResult<HandleActions> {
+        debug!("--> Received auth from {}", Identity::from(source));
+
+        let mut actions = HandleActions::new();
+
+        // Find responder instance
+        let mut responder = self.responders.remove(&source)
+            .ok_or_else(|| SignalingError::Crash(
+                format!("Did not find responder with address {}", source)
+            ))?;
+
+        if let Err(e) = self.validate_auth(&msg, &responder) {
+            warn!("Invalid auth message from {}, dropping it: {}", responder.identity(), e);
+            return self.drop_responder(source, ResponderDropReason::ProtocolError);
+        }
+        let proposed_tasks = msg.tasks.take()
+            .ok_or_else(|| SignalingError::Crash("Tasks not set in validated auth message".into()))?;
 
         // The initiator SHALL continue by comparing the provided tasks
         // to its own array of supported tasks.
+        let has_shared_task = self.common().tasks.as_ref()
+            .ok_or_else(|| SignalingError::Crash("No tasks defined".into()))?
+            .has_shared_task(&proposed_tasks);
+        if !has_shared_task {
+            // In case no common task could be found, the initiator SHALL
+            // send a 'close' message to the responder containing the close
+            // code 3006 (No Shared Task Found) as reason. Other responders
+            // may still support one of our tasks, so the connection is kept
+            // open and this responder is dropped.
+            warn!("No shared task with {}, dropping it", responder.identity());
+            match self.encode_close_message(CloseCode::NoSharedTask, Some(&responder)) {
+                Ok(bbox) => {
+                    actions.push(HandleAction::Reply(bbox));
+                },
+                Err(e) => error!("Could not encode close message: {}", e),
+            };
+            actions.merge(self.drop_responder(source, ResponderDropReason::NoSharedTask)?);
+            return Ok(actions);
+        }
+
         // It MUST choose the first task in its own list of supported tasks
         // that is also contained in the list of supported tasks provided by the responder.
-        // In case no common task could be found, the initiator SHALL send a 'close' message
-        // to the responder containing the close code 3006 (No Shared Task Found) as reason
-        // and raise an error event indicating that no common signalling task could be found.
         let our_tasks = mem::replace(&mut self.common_mut().tasks, None)
             .ok_or_else(|| SignalingError::Crash("No tasks defined".into()))?;
         trace!("Our tasks: {:?}", &our_tasks);
         trace!("Proposed tasks: {:?}", &proposed_tasks);
-        let mut chosen_task: BoxedTask = match our_tasks.choose_shared_task(&proposed_tasks) {
-            Some(task) => task,
-            None => {
-                // In case no common task could be found, the initiator SHALL
-                // send a 'close' message to the responder containing the close
-                // code 3006 (No Shared Task Found) as reason and raise an
-                // error event indicating that no common signalling task could
-                // be found.
-                let mut actions = vec![];
-                match self.encode_close_message(CloseCode::NoSharedTask, Some(&responder)) {
-                    Ok(bbox) => actions.push(HandleAction::Reply(bbox)),
-                    Err(e) => error!("Could not encode close message: {}", e),
-                };
-                actions.push(HandleAction::HandshakeError(SaltyError::NoSharedTask));
-                return Ok(actions);
-            },
-        };
+        let mut chosen_task: BoxedTask = our_tasks.choose_shared_task(&proposed_tasks)
+            .ok_or_else(|| SignalingError::Crash("No shared task found".into()))?;
 
         // Both initiator an responder SHALL verify that the data field contains a Map
         // and SHALL look up the chosen task's data value.
@@ -1460,16 +2650,22 @@ impl InitiatorSignaling {
         // authenticated it towards the client. The other client's public key MAY be stored
         // as trusted for that path if the application desires it.
         info!("Responder {:#04x} authenticated", source.0);
+        actions.extend(self.cancel_responder_timer(source));
+        self.common_mut().peer_extra_fields = msg.extra;
+        self.common_mut().peer_task_data = task_data.clone();
 
         // The initiator MUST drop all other connected responders with a 'drop-responder'
         // message containing the close code 3004 (Dropped by Initiator) in the reason field.
+        // The first responder to send a valid auth message wins, no matter how far
+        // the handshakes with the others have progressed.
         if !self.responders.is_empty() {
             info!("Dropping {} other responders", self.responders.len());
-            for addr in self.responders.keys() {
-                let drop_responder = self.send_drop_responder(*addr, DropReason::DroppedByInitiator)?;
-                debug!("<-- Enqueuing drop-responder to {}", self.server().identity());
-                actions.push(drop_responder);
+            let mut others: Vec<Address> = self.responders.keys().cloned().collect();
+            others.sort();
+            for &addr in &others {
+                actions.merge(self.drop_responder(addr, ResponderDropReason::LostRace)?);
             }
+            actions.push_event(Event::HandshakeRaceWon(source.0, others.iter().map(|addr| addr.0).collect()));
 
             // Remove responders
             self.responders.clear();
@@ -1488,36 +2684,43 @@ impl InitiatorSignaling {
             .set_task(chosen_task.name(), chosen_task.data())
             .build()?
             .into_message();
-        let auth_nonce = Nonce::new(
-            responder.cookie_pair().ours.clone(),
-            self.common.identity.into(),
-            responder.address,
-            responder.csn_pair().try_write()?.ours.increment()?,
-        );
-        let obox = OpenBox::<Message>::new(auth, auth_nonce);
-        let bbox = obox.encrypt(
-            &responder.keypair,
-            responder.session_key.as_ref()
-                .ok_or_else(|| SignalingError::Crash("Responder session key not set".into()))?,
-        );
+        let bbox = responder.encrypt_message(auth, self.common.identity.into())?;
         debug!("<-- Enqueuing auth to {}", &responder.identity());
-        actions.push(HandleAction::Reply(bbox));
+        actions.push_reply(bbox);
 
         // Store chosen task
+        let task_name = chosen_task.name().into_owned();
         self.common_mut().task_supported_types = Some(chosen_task.supported_types());
-        self.common_mut().task = Some(Arc::new(Mutex::new(chosen_task)));
+        self.common_mut().task = Some(tasks::into_shared(chosen_task));
 
         // State transitions
         responder.set_handshake_state(ResponderHandshakeState::AuthSent);
         self.common.set_signaling_state(SignalingState::Task)?;
-        info!("Peer handshake completed");
+        info!("Peer handshake completed (task: {})", task_name);
+        actions.push_event(self.common.handshake_done_event(task_name));
         actions.push(HandleAction::HandshakeDone);
 
         self.responder = Some(responder);
         Ok(actions)
     }
 
-    fn process_new_responder(&mut self, address: Address) -> SignalingResult<Option<HandleAction>> {
+    /// Handle an incoming [`NewResponder`](messages/struct.NewResponder.html) message.
+    fn handle_new_responder(&mut self, msg: NewResponder) -> SignalingResult<HandleActions> {
+        debug!("--> Received new-responder ({}) from server", msg.id);
+
+        // An initiator who receives a 'new-responder' message SHALL validate
+        // that the id field contains a valid responder address (0x02..0xff).
+        if !msg.id.is_responder() {
+            return Err(SignalingError::InvalidMessage(
+                "`id` field in new-responder message is not a valid responder address".into()
+            ));
+        }
+
+        // Process responder
+        self.process_new_responder(msg.id)
+    }
+
+    fn process_new_responder(&mut self, address: Address) -> SignalingResult<HandleActions> {
         // If a responder with the same id already exists,
         // all currently cached information about and for the previous responder
         // (such as cookies and the sequence number) MUST be deleted first.
@@ -1529,7 +2732,11 @@ impl InitiatorSignaling {
         }
 
         // Create responder context
-        let mut responder = ResponderContext::new(address, self.responder_counter.increment()?);
+        let counter = self.responder_counter.increment()?;
+        let mut responder = ResponderContext::with_rng(address, counter, &mut *self.common.rng);
+        responder.created = self.common.clock.now();
+        responder.rate_limiter = self.peer_rate_limit
+            .map(|limit| TokenBucket::new(limit, responder.created));
 
         // If we trust the responder…
         if let Some(AuthProvider::TrustedKey(key)) = self.common.auth_provider {
@@ -1546,53 +2753,113 @@ impl InitiatorSignaling {
         // The initiator SHOULD store the responder's identity in its internal
         // list of responders.
         self.responders.insert(address, responder);
-
-        let mut action = None;
+        let mut actions = HandleActions::new();
+        if let Some(timeout) = self.responder_timeout {
+            actions.push(HandleAction::StartTimer(TimerId::Responder(address), timeout));
+        }
 
         // Furthermore, the initiator MUST keep its path clean by following the
         // procedure described in the Path Cleaning section.
-        // To implement this requirement, if we almost reached the responder limit,
+        // To implement this requirement, if we reached the responder limit,
         // drop the oldest responder that hasn't sent any valid data so far.
-        if self.responders.len() > (254 - 2) {
-            if let Some(drop_action) = self.drop_oldest_inactive_responder()? {
-                debug!("<-- Enqueuing drop-responder to {}", self.server().identity());
-                action = Some(drop_action);
-            }
+        if self.responders.len() > self.max_responders {
+            actions.merge(self.clean_path(address)?);
         }
 
-        Ok(action)
+        Ok(actions)
     }
 
-    /// Drop the oldest responder that hasn't sent any valid data so far.
-    /// Return a result with a 'drop-responder' handle action if a drop
-    /// candidate has been found.
-    fn drop_oldest_inactive_responder(&mut self) -> SignalingResult<Option<HandleAction>> {
-        debug!("Path almost full, dropping the oldest inactive responder.");
-
-        // Find address of drop candidate
-        let address = self.responders
-            .values()
-            .filter(|r| r.handshake_state() == ResponderHandshakeState::New)
-            .min_by_key(|r| r.counter)
-            .map(|r| r.address);
-
-        // Remove responder from internal list of responders
-        let responder: ResponderContext = match address {
-            Some(ref addr) => {
-                self.responders
-                    .remove(addr)
-                    .ok_or_else(|| SignalingError::Crash("Inactive responder not found anymore in responders list".into()))?
+    /// Drop a responder after the newly registered responder at `address`
+    /// exceeded the responder limit.
+    ///
+    /// The oldest responder that hasn't sent any valid data so far is
+    /// dropped. If all other responders are active, the new responder is
+    /// dropped instead.
+    fn clean_path(&mut self, address: Address) -> SignalingResult<HandleActions> {
+        let (dropped, event) = match self.oldest_inactive_responder(address) {
+            Some(candidate) => {
+                debug!("Path almost full, dropping the oldest inactive responder {}", Identity::from(candidate));
+                (candidate, Event::PathAlmostFull(candidate.0))
             },
             None => {
-                warn!("Did not find a valid responder candidate to drop!");
-                return Ok(None);
-            }
+                warn!("Path full, dropping new responder {}", Identity::from(address));
+                (address, Event::PathFull(address.0))
+            },
         };
 
+        if !self.responders.contains_key(&dropped) {
+            return Err(SignalingError::Crash("Dropped responder not found anymore in responders list".into()));
+        }
+
         // Enqueue a drop-responder message
-        self
-            .send_drop_responder(responder.address, DropReason::DroppedByInitiator)
-            .map(Option::Some)
+        let mut actions = self.drop_responder(dropped, ResponderDropReason::PathFull)?;
+        actions.push_event(event);
+        Ok(actions)
+    }
+
+    /// Return the address of the oldest responder (other than the one at
+    /// `new_address`) that hasn't sent any valid data so far.
+    fn oldest_inactive_responder(&self, new_address: Address) -> Option<Address> {
+        self.responders
+            .values()
+            .filter(|r| r.address != new_address && r.handshake_state() == ResponderHandshakeState::New)
+            .min_by_key(|r| r.counter)
+            .map(|r| r.address)
+    }
+
+    /// Drop all responders except the one at `address` that use the
+    /// specified permanent key.
+    ///
+    /// When a responder reconnects, the server may assign it a new address
+    /// while the context for its old address still exists. Without cleaning
+    /// up, these stale contexts would accumulate until the path is full.
+    fn drop_stale_responders(&mut self, address: Address, permanent_key: &PublicKey) -> SignalingResult<HandleActions> {
+        let mut stale: Vec<Address> = self.responders
+            .values()
+            .filter(|r| r.address != address && r.permanent_key.as_ref() == Some(permanent_key))
+            .map(|r| r.address)
+            .collect();
+        stale.sort_by_key(|addr| addr.0);
+
+        let mut actions = HandleActions::new();
+        for addr in stale {
+            info!("Responder {} reconnected as {}, dropping stale context", Identity::from(addr), Identity::from(address));
+            actions.merge(self.drop_responder(addr, ResponderDropReason::Reconnected)?);
+        }
+        Ok(actions)
+    }
+
+    /// Forget about the responder at `addr` and drop it.
+    fn drop_responder(&mut self, addr: Address, reason: ResponderDropReason) -> SignalingResult<HandleActions> {
+        self.responders.remove(&addr);
+        let mut actions = HandleActions::from(self.send_drop_responder(addr, reason.wire_reason())?);
+        debug!("<-- Enqueuing drop-responder to {}", self.server().identity());
+        actions.extend(self.cancel_responder_timer(addr));
+        actions.push_event(Event::ResponderDropped { address: addr.0, reason });
+        Ok(actions)
+    }
+
+    /// Return the action that cancels the handshake timeout of the responder
+    /// at `addr`, if responders time out.
+    fn cancel_responder_timer(&self, addr: Address) -> Option<HandleAction> {
+        self.responder_timeout.map(|_| HandleAction::CancelTimer(TimerId::Responder(addr)))
+    }
+
+    /// Encode and return a DropResponder message.
+    fn send_drop_responder(&self, addr: Address, reason: DropReason) -> SignalingResult<HandleAction> {
+        // Create message and nonce
+        let drop = DropResponder::with_reason(addr, reason).into_message();
+        let drop_nonce = self.nonce_factory(self.server()).next()?;
+
+        // Encrypt message
+        let obox = OpenBox::<Message>::new(drop, drop_nonce);
+        let bbox = obox.encrypt(
+            &self.common().permanent_keypair,
+            self.server().session_key()
+                .ok_or_else(|| SignalingError::Crash("Server session key not set".into()))?
+        );
+
+        Ok(HandleAction::Reply(bbox))
     }
 }
 
@@ -1604,6 +2871,14 @@ pub(crate) struct ResponderSignaling {
 
     // The initiator context
     pub(crate) initiator: InitiatorContext,
+
+    // The time after which the responder gives up if no initiator has
+    // connected to the server.
+    pub(crate) initiator_timeout: Option<Duration>,
+
+    // Whether no initiator is connected to the server, either since the
+    // server handshake or since the initiator disconnected.
+    pub(crate) waiting_for_initiator: bool,
 }
 
 impl Signaling for ResponderSignaling {
@@ -1621,6 +2896,17 @@ impl Signaling for ResponderSignaling {
         Some(&self.initiator as &dyn PeerContext)
     }
 
+    fn peer_summaries(&self) -> Vec<PeerSummary> {
+        let authenticated = self.common().signaling_state() == SignalingState::Task;
+        vec![PeerSummary::new(&self.initiator, &self.initiator.handshake_state(), authenticated)]
+    }
+
+    fn replace_session_keys(&mut self, keypair: KeyPair, session_key: PublicKey) -> SignalingResult<(KeyPair, PublicKey)> {
+        let previous_session_key = self.initiator.session_key.replace(session_key)
+            .ok_or_else(|| SignalingError::Crash("Initiator session key not set".into()))?;
+        Ok((mem::replace(&mut self.initiator.keypair, keypair), previous_session_key))
+    }
+
     fn get_peer_with_address_mut(&mut self, addr: Address) -> Option<&mut dyn PeerContext> {
         let identity: Identity = addr.into();
         match identity {
@@ -1634,6 +2920,10 @@ impl Signaling for ResponderSignaling {
         &self.initiator.permanent_key
     }
 
+    fn reset_peers(&mut self) {
+        self.initiator = InitiatorContext::with_rng(self.initiator.permanent_key, &mut *self.common.rng);
+    }
+
     fn validate_nonce_destination(&mut self, nonce: &Nonce) -> Result<(), ValidationError> {
         // A client MUST check that the destination address targets its
         // assigned identity (or `0x00` during authentication).
@@ -1702,14 +2992,12 @@ impl Signaling for ResponderSignaling {
             InitiatorHandshakeState::KeySent => {
                 // Expect key message, encrypted with our public permanent key
                 // and initiator private permanent key
-                OpenBox::<Message>::decrypt(bbox, &self.common.permanent_keypair, &self.initiator.permanent_key)
+                self.initiator.decrypt_message_permanent(bbox, &self.common.permanent_keypair, &["key"])
             },
             InitiatorHandshakeState::AuthSent => {
-                // Expect an auth message, encrypted with our public session
-                // key and initiator private session key
-                let initiator_session_key = self.initiator.session_key.as_ref()
-                    .ok_or_else(|| SignalingError::Crash("Initiator session key not set".into()))?;
-                OpenBox::<Message>::decrypt(bbox, &self.initiator.keypair, initiator_session_key)
+                // Expect an auth or close message, encrypted with our public
+                // session key and initiator private session key
+                self.initiator.decrypt_message(bbox, &["auth", "close"])
             },
             other => {
                 // TODO (#14): Maybe remove these states?
@@ -1718,12 +3006,22 @@ impl Signaling for ResponderSignaling {
         }
     }
 
+    fn client_hello(&self) -> SignalingResult<Option<HandleAction>> {
+        let client_hello = {
+            let key = self.common().permanent_keypair.public_key();
+            ClientHello::new(*key).into_message()
+        };
+        let client_hello_nonce = self.nonce_factory(self.server()).next()?;
+        let reply = OpenBox::<Message>::new(client_hello, client_hello_nonce);
+        Ok(Some(HandleAction::Reply(reply.encode())))
+    }
+
     /// Determine the next peer handshake state based on the incoming
     /// client-to-client message and the current state.
     ///
     /// This method call may have some side effects, like updates in the peer
     /// context (cookie, CSN, etc).
-    fn handle_peer_message(&mut self, obox: OpenBox<Message>) -> SignalingResult<Vec<HandleAction>> {
+    fn handle_peer_message(&mut self, obox: OpenBox<Message>) -> SignalingResult<HandleActions> {
         let old_state = self.initiator.handshake_state();
         match (old_state, obox.message) {
             // Valid state transitions
@@ -1732,13 +3030,13 @@ impl Signaling for ResponderSignaling {
             (InitiatorHandshakeState::AuthSent, Message::Close(msg)) => self.handle_peer_handshake_close(msg),
 
             // Any undefined state transition results in an error
-            (s, message) => Err(SignalingError::InvalidStateTransition(
-                format!("Got {} message from initiator in {:?} state", message.get_type(), s)
-            )),
+            (s, message) => Err(SignalingError::UnexpectedMessage(ErrorContext::new(
+                self.common().signaling_state(), s, message.get_type(), &obox.nonce,
+            ))),
         }
     }
 
-    fn handle_server_auth_impl(&mut self, msg: &ServerAuth) -> SignalingResult<Vec<HandleAction>> {
+    fn handle_server_auth_impl(&mut self, msg: &ServerAuth) -> SignalingResult<HandleActions> {
         // In case the client is the responder, it SHALL check
         // that the initiator_connected field contains a
         // boolean value.
@@ -1747,9 +3045,10 @@ impl Signaling for ResponderSignaling {
                 "We're a responder, but the `responders` field in the server-auth message is set".into()
             ));
         }
-        let mut actions: Vec<HandleAction> = vec![];
+        let mut actions = HandleActions::new();
         match msg.initiator_connected {
             Some(true) => {
+                self.waiting_for_initiator = false;
                 let mut send_token = false;
                 match self.common().auth_provider {
                     Some(AuthProvider::Token(_)) => {
@@ -1771,12 +3070,13 @@ impl Signaling for ResponderSignaling {
                     }
                 }
                 actions.push(self.send_key()?);
-                actions.push(HandleAction::Event(Event::ServerHandshakeDone(true)));
+                actions.push_event(Event::ServerHandshakeDone(true));
                 self.initiator.set_handshake_state(InitiatorHandshakeState::KeySent);
             },
             Some(false) => {
                 debug!("No initiator connected so far");
-                actions.push(HandleAction::Event(Event::ServerHandshakeDone(false)));
+                actions.push_event(Event::ServerHandshakeDone(false));
+                self.wait_for_initiator(&mut actions);
             },
             None => return Err(SignalingError::InvalidMessage(
                 "We're a responder, but the `initiator_connected` field in the server-auth message is not set".into()
@@ -1785,50 +3085,17 @@ impl Signaling for ResponderSignaling {
         Ok(actions)
     }
 
-    fn handle_new_initiator(&mut self, _msg: NewInitiator) -> SignalingResult<Vec<HandleAction>> {
-        debug!("--> Received new-initiator from server");
-
-        let mut actions: Vec<HandleAction> = vec![];
-
-        // A responder who receives a 'new-initiator' message MUST proceed by
-        // deleting all currently cached information about and for the previous
-        // initiator (such as cookies and the sequence numbers)...
-        self.initiator = InitiatorContext::new(self.initiator.permanent_key);
-
-        // ...and continue by sending a 'token' or 'key' client-to-client
-        // message described in the Client-to-Client Messages section.
-        let mut send_token = false;
-        match self.common().auth_provider {
-            Some(AuthProvider::Token(_)) => {
-                send_token = true;
-            },
-            Some(AuthProvider::TrustedKey(_)) => {
-                debug!("Trusted key available, skipping token message");
-            },
-            None => {
-                return Err(SignalingError::Crash("No auth provider set".into()));
-            },
-        }
-        if send_token {
-            let old_auth_provider = mem::replace(&mut self.common_mut().auth_provider, None);
-            if let Some(AuthProvider::Token(token)) = old_auth_provider {
-                actions.push(self.send_token(token)?);
-            } else {
-                return Err(SignalingError::Crash("Auth provider is not a token".into()));
-            }
+    fn handle_role_server_message(&mut self, msg: Message) -> SignalingResult<HandleActions> {
+        match msg {
+            Message::NewInitiator(msg) => self.handle_new_initiator(msg),
+            other => Err(SignalingError::Protocol(
+                format!("Received '{}' message as responder", other.get_type())
+            )),
         }
-        actions.push(self.send_key()?);
-        self.initiator.set_handshake_state(InitiatorHandshakeState::KeySent);
-
-        Ok(actions)
-    }
-
-    fn handle_new_responder(&mut self, _msg: NewResponder) -> SignalingResult<Vec<HandleAction>> {
-        Err(SignalingError::Protocol("Received 'new-responder' message as responder".into()))
     }
 
     /// Handle an incoming [`Disconnected`](messages/struct.Disconnected.html) message.
-    fn handle_disconnected(&mut self, msg: Disconnected) -> SignalingResult<Vec<HandleAction>> {
+    fn handle_disconnected(&mut self, msg: Disconnected) -> SignalingResult<HandleActions> {
         debug!("--> Received disconnected from server");
 
         // A responder who receives a 'disconnected' message SHALL validate
@@ -1839,57 +3106,182 @@ impl Signaling for ResponderSignaling {
             ));
         }
 
-        Ok(vec![HandleAction::Event(Event::Disconnected(msg.id.0))])
+        // Wait for the server to announce the next initiator
+        let mut actions = HandleActions::from(HandleAction::Event(Event::Disconnected(msg.id.0)));
+        self.wait_for_initiator(&mut actions);
+        Ok(actions)
+    }
+
+    /// Give up if no initiator connected to the server within the initiator
+    /// timeout.
+    fn handle_timeout(&mut self, id: TimerId) -> SignalingResult<HandleActions> {
+        match id {
+            TimerId::Initiator => {
+                if !self.waiting_for_initiator {
+                    return Ok(HandleActions::new());
+                }
+                info!("No initiator connected in time, giving up");
+                Err(SignalingError::InitiatorTimeout)
+            },
+            TimerId::Responder(_) | TimerId::Heartbeat | TimerId::Handshake => Ok(HandleActions::new()),
+        }
     }
 }
 
 impl ResponderSignaling {
+    #[cfg(test)]
     pub(crate) fn new(permanent_keypair: KeyPair,
                       initiator_pubkey: PublicKey,
                       auth_token: Option<AuthToken>,
                       server_public_permanent_key: Option<PublicKey>,
                       tasks: Tasks,
                       ping_interval: Option<Duration>) -> Self {
+        Self::with_rng(
+            permanent_keypair,
+            initiator_pubkey,
+            auth_token,
+            server_public_permanent_key,
+            tasks,
+            ping_interval,
+            Box::new(OsRandom),
+        )
+    }
+
+    /// Create a new `ResponderSignaling` instance that draws all random
+    /// values from the specified random source.
+    pub(crate) fn with_rng(permanent_keypair: KeyPair,
+                           initiator_pubkey: PublicKey,
+                           auth_token: Option<AuthToken>,
+                           server_public_permanent_key: Option<PublicKey>,
+                           tasks: Tasks,
+                           ping_interval: Option<Duration>,
+                           mut rng: Box<dyn RandomSource + Send>) -> Self {
+        let server = {
+            let mut ctx = ServerContext::with_rng(&mut *rng);
+            ctx.permanent_key = server_public_permanent_key;
+            ctx
+        };
+        let initiator = InitiatorContext::with_rng(initiator_pubkey, &mut *rng);
+        let auth_provider = match auth_token {
+            Some(token) => AuthProvider::Token(token),
+            None => AuthProvider::TrustedKey(initiator_pubkey),
+        };
         ResponderSignaling {
             common: Common {
                 signaling_state: SignalingState::ServerHandshake,
                 role: Role::Responder,
                 identity: ClientIdentity::Unknown,
                 permanent_keypair,
-                auth_provider: Some(match auth_token {
-                    Some(token) => AuthProvider::Token(token),
-                    None => AuthProvider::TrustedKey(initiator_pubkey),
-                }),
-                server: {
-                    let mut ctx = ServerContext::new();
-                    ctx.permanent_key = server_public_permanent_key;
-                    ctx
-                },
+                initial_auth_provider: Some(auth_provider.clone()),
+                peer_auth_method: None,
+                peer_extra_fields: ExtraFields::default(),
+                received_extra_fields: HashMap::new(),
+                peer_task_data: None,
+                renewing: false,
+                auth_provider: Some(auth_provider),
+                server,
                 tasks: Some(tasks),
                 task: None,
                 task_supported_types: None,
                 ping_interval,
+                protocol_version: ProtocolVersion::default(),
+                rng,
+                heartbeat: Heartbeat::default(),
+                rekeying: false,
+                rekey: Rekey::default(),
+                clock: Arc::new(SystemClock),
+                transcript: None,
+                observer: None,
+                incoming_entry: None,
+                sequential_csn: None,
+                stats: StatsCollector::default(),
+                sent_messages: SentMessages::default(),
+                reorder: ReorderBuffer::default(),
+                renewal_queue: vec![],
+                failed: false,
+                peer_session_closed: false,
+                server_rate_limit: Some(DEFAULT_SERVER_RATE_LIMIT),
+                server_rate_limiter: None,
             },
-            initiator: InitiatorContext::new(initiator_pubkey),
+            initiator,
+            initiator_timeout: None,
+            waiting_for_initiator: false,
+        }
+    }
+
+    /// Notify about the missing initiator and start the initiator timeout,
+    /// if any.
+    fn wait_for_initiator(&mut self, actions: &mut HandleActions) {
+        self.waiting_for_initiator = true;
+        actions.push_event(Event::WaitingForInitiator);
+        if let Some(timeout) = self.initiator_timeout {
+            actions.push(HandleAction::StartTimer(TimerId::Initiator, timeout));
         }
     }
 
+    /// Handle an incoming [`NewInitiator`](messages/struct.NewInitiator.html) message.
+    fn handle_new_initiator(&mut self, _msg: NewInitiator) -> SignalingResult<HandleActions> {
+        debug!("--> Received new-initiator from server");
+
+        let mut actions = HandleActions::new();
+        self.waiting_for_initiator = false;
+        if self.initiator_timeout.is_some() {
+            actions.push(HandleAction::CancelTimer(TimerId::Initiator));
+        }
+
+        // A responder who receives a 'new-initiator' message MUST proceed by
+        // deleting all currently cached information about and for the previous
+        // initiator (such as cookies and the sequence numbers)...
+        self.reset_peers();
+        self.common_mut().reset_peer_state();
+
+        // After the peer handshake, the session is bound to the previous
+        // initiator. Without its session key, the task cannot send any more
+        // messages, so it is closed instead of starting a new handshake.
+        if self.common().signaling_state() == SignalingState::Task {
+            info!("Initiator reconnected after the peer handshake, closing task");
+            self.common_mut().peer_session_closed = true;
+            actions.push(HandleAction::TaskMessage(TaskMessage::Close(CloseCode::WsGoingAway)));
+            return Ok(actions);
+        }
+
+        // ...and continue by sending a 'token' or 'key' client-to-client
+        // message described in the Client-to-Client Messages section.
+        let mut send_token = false;
+        match self.common().auth_provider {
+            Some(AuthProvider::Token(_)) => {
+                send_token = true;
+            },
+            Some(AuthProvider::TrustedKey(_)) => {
+                debug!("Trusted key available, skipping token message");
+            },
+            None => {
+                return Err(SignalingError::Crash("No auth provider set".into()));
+            },
+        }
+        if send_token {
+            let old_auth_provider = mem::replace(&mut self.common_mut().auth_provider, None);
+            if let Some(AuthProvider::Token(token)) = old_auth_provider {
+                actions.push(self.send_token(token)?);
+            } else {
+                return Err(SignalingError::Crash("Auth provider is not a token".into()));
+            }
+        }
+        actions.push(self.send_key()?);
+        self.initiator.set_handshake_state(InitiatorHandshakeState::KeySent);
+
+        Ok(actions)
+    }
+
     /// Build a `Token` message.
     ///
     /// The token is consumed to avoid accidentally reusing it.
     #[cfg_attr(feature="cargo-clippy", allow(needless_pass_by_value))]
-    fn send_token(&self, token: AuthToken) -> SignalingResult<HandleAction> {
+    fn send_token(&mut self, token: AuthToken) -> SignalingResult<HandleAction> {
         // The responder MUST set the public key (32 bytes) of the permanent
         // key pair in the key field of this message.
-        let msg: Message = Token {
-            key: self.common().permanent_keypair.public_key().to_owned(),
-        }.into_message();
-        let nonce = Nonce::new(
-            self.initiator.cookie_pair().ours.clone(),
-            self.identity().into(),
-            self.initiator.identity().into(),
-            self.initiator.csn_pair().try_write()?.ours.increment()?,
-        );
+        let msg: Message = Token::new(self.common().permanent_keypair.public_key().to_owned()).into_message();
+        let nonce = self.nonce_factory(&self.initiator).next()?;
         let obox = OpenBox::<Message>::new(msg, nonce);
 
         // The message SHALL be NaCl secret key encrypted by the token the
@@ -1901,22 +3293,17 @@ impl ResponderSignaling {
     }
 
     /// Build a `Key` message.
-    fn send_key(&self) -> SignalingResult<HandleAction> {
+    fn send_key(&mut self) -> SignalingResult<HandleAction> {
         // It MUST set the public key (32 bytes) of that key pair in the key field.
-        let msg: Message = Key {
-            key: self.initiator.keypair.public_key().to_owned(),
-        }.into_message();
-        let nonce = Nonce::new(
-            self.initiator.cookie_pair().ours.clone(),
-            self.identity().into(),
-            self.initiator.identity().into(),
-            self.initiator.csn_pair().try_write()?.ours.increment()?,
-        );
-        let obox = OpenBox::<Message>::new(msg, nonce);
+        let msg: Message = Key::new(self.initiator.keypair.public_key().to_owned()).into_message();
 
         // The message SHALL be NaCl public-key encrypted by the client's
         // permanent key pair and the other client's permanent key pair.
-        let bbox = obox.encrypt(&self.common().permanent_keypair, &self.initiator.permanent_key);
+        let bbox = self.initiator.encrypt_message_permanent(
+            msg,
+            self.identity().into(),
+            &self.common().permanent_keypair,
+        )?;
 
         debug!("<-- Enqueuing key to {}", self.initiator.identity());
         Ok(HandleAction::Reply(bbox))
@@ -1924,7 +3311,7 @@ impl ResponderSignaling {
 
     /// Handle an incoming [`Key`](messages/struct.Key.html) message.
     #[cfg_attr(feature="cargo-clippy", allow(needless_pass_by_value))]
-    fn handle_key(&mut self, msg: Key, nonce: &Nonce) -> SignalingResult<Vec<HandleAction>> {
+    fn handle_key(&mut self, msg: Key, nonce: &Nonce) -> SignalingResult<HandleActions> {
         debug!("--> Received key from {}", nonce.source_identity());
 
         // Sanity check
@@ -1932,10 +3319,12 @@ impl ResponderSignaling {
             return Err(SignalingError::Crash("Initiator already has a session key set!".into()));
         }
 
-        // Ensure that session key != permanent key
-        if msg.key == self.initiator.permanent_key {
-            return Err(SignalingError::Protocol("Responder session key and permanent key are equal".into()));
-        }
+        // Ensure that the session key is not a key we already know
+        self.common.validate_peer_session_key(
+            &msg.key,
+            &self.initiator.permanent_key,
+            self.initiator.keypair.public_key(),
+        )?;
 
         // Set public session key
         self.initiator.session_key = Some(msg.key);
@@ -1953,28 +3342,17 @@ impl ResponderSignaling {
             )
             .build()?
             .into_message();
-        let auth_nonce = Nonce::new(
-            self.initiator.cookie_pair().ours.clone(),
-            self.common().identity.into(),
-            self.initiator.identity().into(),
-            self.initiator.csn_pair().try_write()?.ours.increment()?,
-        );
-        let obox = OpenBox::<Message>::new(auth, auth_nonce);
-        let bbox = obox.encrypt(
-            &self.initiator.keypair,
-            self.initiator.session_key.as_ref()
-                .ok_or_else(|| SignalingError::Crash("Initiator session key not set".into()))?,
-        );
+        let bbox = self.initiator.encrypt_message(auth, self.common().identity.into())?;
 
         // State transition
         self.initiator.set_handshake_state(InitiatorHandshakeState::AuthSent);
 
         debug!("<-- Enqueuing auth to {}", self.initiator.identity());
-        Ok(vec![HandleAction::Reply(bbox)])
+        Ok(HandleActions::from(HandleAction::Reply(bbox)))
     }
 
     /// Handle an incoming [`Auth`](messages/struct.Auth.html) message.
-    fn handle_auth(&mut self, msg: Auth, source: Address) -> SignalingResult<Vec<HandleAction>> {
+    fn handle_auth(&mut self, msg: Auth, source: Address) -> SignalingResult<HandleActions> {
         debug!("--> Received auth from {}", Identity::from(source));
 
         // The cookie provided in the `your_cookie` field SHALL contain the cookie
@@ -2033,22 +3411,28 @@ impl ResponderSignaling {
         // authenticated it towards the client. The other client's public key MAY be stored
         // as trusted for that path if the application desires it.
         info!("Initiator authenticated");
+        self.common_mut().peer_extra_fields = msg.extra;
+        self.common_mut().peer_task_data = task_data.clone();
 
         // Store chosen task
+        let task_name = chosen_task.name().into_owned();
         self.common_mut().task_supported_types = Some(chosen_task.supported_types());
-        self.common_mut().task = Some(Arc::new(Mutex::new(chosen_task)));
+        self.common_mut().task = Some(tasks::into_shared(chosen_task));
 
         // State transitions
         self.initiator.set_handshake_state(InitiatorHandshakeState::AuthReceived);
         self.common.set_signaling_state(SignalingState::Task)?;
-        info!("Peer handshake completed");
+        info!("Peer handshake completed (task: {})", task_name);
 
-        Ok(vec![HandleAction::HandshakeDone])
+        Ok(HandleActions::from(vec![
+            HandleAction::Event(self.common.handshake_done_event(task_name)),
+            HandleAction::HandshakeDone,
+        ]))
     }
 
     /// Handle an incoming [`Close`](messages/struct.Close.html) message during peer handshake.
     #[cfg_attr(feature="cargo-clippy", allow(needless_pass_by_value))]
-    fn handle_peer_handshake_close(&mut self, msg: Close) -> SignalingResult<Vec<HandleAction>> {
+    fn handle_peer_handshake_close(&mut self, msg: Close) -> SignalingResult<HandleActions> {
         let close_code = CloseCode::from_number(msg.reason);
         match close_code {
             CloseCode::NoSharedTask => Err(SignalingError::NoSharedTask),
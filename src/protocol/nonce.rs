@@ -2,16 +2,16 @@
 //!
 //! This includes serialization and deserialization.
 
-use std::convert::Into;
 use std::io::Write;
+use std::sync::RwLock;
 
-use byteorder::{BigEndian, ByteOrder};
+#[cfg(feature = "sodium")]
 use rust_sodium::crypto::{box_, secretbox};
 
-use crate::errors::{SignalingError, SignalingResult};
+use crate::errors::{NonceError, SignalingError, SignalingResult};
 
 use super::cookie::Cookie;
-use super::csn::CombinedSequenceSnapshot;
+use super::csn::{CombinedSequencePair, CombinedSequenceSnapshot};
 use super::types::{Address, Identity};
 
 
@@ -30,6 +30,7 @@ pub(crate) struct Nonce {
 
 impl Nonce {
     pub(crate) fn new(cookie: Cookie, source: Address, destination: Address, csn: CombinedSequenceSnapshot) -> Self {
+        debug_assert!(!cookie.is_zero(), "Nonce created with all-zero cookie");
         Nonce {
             cookie,
             source,
@@ -41,21 +42,26 @@ impl Nonce {
     /// Parse bytes, return a Nonce.
     ///
     /// This will fail if the byte slice does not contain exactly 24 bytes of
-    /// data.
-    pub(crate) fn from_bytes(bytes: &[u8]) -> SignalingResult<Self> {
+    /// data, or if the cookie consists of zero bytes only.
+    ///
+    /// The overflow number and the sequence number occupy exactly 16 and 32
+    /// bits, so every value is in range. Whether the overflow number of the
+    /// first message from a peer is 0 can only be checked during nonce
+    /// validation.
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Result<Self, NonceError> {
         if bytes.len() != 24 {
-            return Err(SignalingError::Decode(
-                format!("Byte slice must be exactly 24 bytes, not {}", bytes.len())
-            ));
+            return Err(NonceError::InvalidLength(bytes.len()));
         }
-        let overflow = BigEndian::read_u16(&bytes[18..20]);
-        let sequence = BigEndian::read_u32(&bytes[20..24]);
-        let csn = CombinedSequenceSnapshot::new(overflow, sequence);
+        let cookie = Cookie::new([
+            bytes[0], bytes[1], bytes[2],  bytes[3],  bytes[4],  bytes[5],  bytes[6],  bytes[7],
+            bytes[8], bytes[9], bytes[10], bytes[11], bytes[12], bytes[13], bytes[14], bytes[15],
+        ]);
+        if cookie.is_zero() {
+            return Err(NonceError::ZeroCookie);
+        }
+        let csn = CombinedSequenceSnapshot::from_bytes(&bytes[18..24]);
         Ok(Self {
-            cookie: Cookie::new([
-                bytes[0], bytes[1], bytes[2],  bytes[3],  bytes[4],  bytes[5],  bytes[6],  bytes[7],
-                bytes[8], bytes[9], bytes[10], bytes[11], bytes[12], bytes[13], bytes[14], bytes[15],
-            ]),
+            cookie,
             source: Address(bytes[16]),
             destination: Address(bytes[17]),
             csn,
@@ -71,8 +77,7 @@ impl Nonce {
         (&mut bytes[0..16]).write_all(self.cookie.as_bytes()).expect("Writing cookie to nonce failed");
         bytes[16] = self.source.0;
         bytes[17] = self.destination.0;
-        BigEndian::write_u16(&mut bytes[18..20], self.csn.overflow_number());
-        BigEndian::write_u32(&mut bytes[20..24], self.csn.sequence_number());
+        bytes[18..24].copy_from_slice(&self.csn.to_bytes());
         bytes
     }
 
@@ -117,6 +122,44 @@ impl Nonce {
     }
 }
 
+/// Creates the nonces for outgoing messages to one peer.
+///
+/// The factory borrows our cookie and CSN pair from the peer context, so
+/// every nonce carries the current cookie and the next sequence number. All
+/// outgoing nonces must be created through a factory.
+pub(crate) struct NonceFactory<'a> {
+    cookie: &'a Cookie,
+    csn_pair: &'a RwLock<CombinedSequencePair>,
+    source: Address,
+    destination: Address,
+}
+
+impl<'a> NonceFactory<'a> {
+    pub(crate) fn new(
+        cookie: &'a Cookie,
+        csn_pair: &'a RwLock<CombinedSequencePair>,
+        source: Address,
+        destination: Address,
+    ) -> Self {
+        NonceFactory { cookie, csn_pair, source, destination }
+    }
+
+    /// Create the nonce for the next message.
+    ///
+    /// This increments our outgoing CSN. Messages to another client may
+    /// only be sent once the server assigned an address to us.
+    pub(crate) fn next(&self) -> SignalingResult<Nonce> {
+        if self.source.is_unknown() && !self.destination.is_server() {
+            return Err(SignalingError::Crash(
+                format!("Cannot send a message to {} before our address is assigned", Identity::from(self.destination))
+            ));
+        }
+        let csn = self.csn_pair.try_write()?.ours.increment()?;
+        Ok(Nonce::new(self.cookie.clone(), self.source, self.destination, csn))
+    }
+}
+
+#[cfg(feature = "sodium")]
 impl Into<box_::Nonce> for Nonce {
     fn into(self) -> box_::Nonce {
         let bytes = self.into_bytes();
@@ -124,6 +167,7 @@ impl Into<box_::Nonce> for Nonce {
     }
 }
 
+#[cfg(feature = "sodium")]
 impl Into<secretbox::Nonce> for Nonce {
     fn into(self) -> secretbox::Nonce {
         let bytes = self.into_bytes();
@@ -165,6 +209,26 @@ mod tests {
         assert_eq!(Nonce::from_bytes(&bytes).unwrap(), create_test_nonce());
     }
 
+    #[test]
+    fn parse_nonce_invalid_length() {
+        let bytes = create_test_nonce_bytes();
+        assert_eq!(Nonce::from_bytes(&bytes[..23]), Err(NonceError::InvalidLength(23)));
+        assert_eq!(Nonce::from_bytes(&[0; 25]), Err(NonceError::InvalidLength(25)));
+    }
+
+    #[test]
+    fn parse_nonce_zero_cookie() {
+        let mut bytes = create_test_nonce_bytes();
+        for byte in &mut bytes[..16] {
+            *byte = 0;
+        }
+        assert_eq!(Nonce::from_bytes(&bytes), Err(NonceError::ZeroCookie));
+
+        // A single non-zero byte is enough
+        bytes[15] = 1;
+        assert!(Nonce::from_bytes(&bytes).is_ok());
+    }
+
     #[test]
     fn nonce_methods() {
         let nonce = create_test_nonce();
@@ -182,6 +246,7 @@ mod tests {
     }
 
     /// Test conversion from a saltyrtc `Nonce` to a rust sodium `Nonce`.
+    #[cfg(feature = "sodium")]
     #[test]
     fn nonce_into_nonce() {
         let nonce: Nonce = create_test_nonce();
@@ -189,4 +254,31 @@ mod tests {
         let rust_sodium_nonce: box_::Nonce = nonce.into();
         assert_eq!(rust_sodium_nonce.0, nonce_bytes);
     }
+
+    #[test]
+    fn factory_increments_csn() {
+        let cookie = Cookie::random();
+        let csn_pair = RwLock::new(CombinedSequencePair::new());
+        let factory = NonceFactory::new(&cookie, &csn_pair, Address(1), Address(2));
+        let first = factory.next().unwrap();
+        let second = factory.next().unwrap();
+        assert_eq!(first.cookie(), &cookie);
+        assert_eq!(first.source(), Address(1));
+        assert_eq!(first.destination(), Address(2));
+        assert_eq!(
+            first.csn().combined_sequence_number() + 1,
+            second.csn().combined_sequence_number()
+        );
+    }
+
+    #[test]
+    fn factory_requires_address_for_peers() {
+        let cookie = Cookie::random();
+        let csn_pair = RwLock::new(CombinedSequencePair::new());
+        assert!(NonceFactory::new(&cookie, &csn_pair, Address(0), Address(0)).next().is_ok());
+        assert_eq!(
+            NonceFactory::new(&cookie, &csn_pair, Address(0), Address(1)).next(),
+            Err(SignalingError::Crash("Cannot send a message to initiator before our address is assigned".into())),
+        );
+    }
 }
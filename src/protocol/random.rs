@@ -0,0 +1,189 @@
+//! Sources of randomness.
+//!
+//! All random values used by the signaling (cookies, initial sequence
+//! numbers and session keys) are drawn from a
+//! [`RandomSource`](trait.RandomSource.html). By default, the cryptographically
+//! secure generator of the crypto backend is used. Tests may inject a
+//! deterministic source instead to be able to compare exact wire bytes.
+//!
+//! With the `conformance-testing` feature, the cookies, initial sequence
+//! numbers and session keys can be pinned to fixed values as well.
+
+use crate::crypto_backend::backend;
+
+
+/// A source of random bytes.
+pub(crate) trait RandomSource {
+    /// Fill the buffer with random bytes.
+    fn fill_bytes(&mut self, buf: &mut [u8]);
+
+    /// Fill the buffer with the bytes of a new cookie.
+    fn fill_cookie(&mut self, buf: &mut [u8]) {
+        self.fill_bytes(buf);
+    }
+
+    /// Fill the buffer with the bytes of a new initial sequence number.
+    fn fill_sequence_number(&mut self, buf: &mut [u8]) {
+        self.fill_bytes(buf);
+    }
+
+    /// Fill the buffer with the private key of a new session key pair.
+    ///
+    /// This is only used for the session keys exchanged during the peer
+    /// handshake, not for the keys of a rekeying.
+    fn fill_session_key(&mut self, buf: &mut [u8]) {
+        self.fill_bytes(buf);
+    }
+}
+
+
+/// The default random source, backed by the
+/// [`CryptoBackend`](../../crypto_backend/trait.CryptoBackend.html).
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct OsRandom;
+
+impl RandomSource for OsRandom {
+    fn fill_bytes(&mut self, buf: &mut [u8]) {
+        backend().random_bytes(buf);
+    }
+}
+
+
+/// A random source that returns the same cookie, initial sequence number
+/// and session key every time, for conformance tests.
+///
+/// Values that are not pinned, as well as all other values, are drawn from
+/// [`OsRandom`](struct.OsRandom.html).
+#[cfg(feature = "conformance-testing")]
+#[derive(Debug, Clone)]
+pub(crate) struct PinnedRandom {
+    cookie: Option<[u8; 16]>,
+    sequence_number: Option<u32>,
+    session_key: Option<[u8; 32]>,
+}
+
+#[cfg(feature = "conformance-testing")]
+impl PinnedRandom {
+    pub(crate) fn new(cookie: Option<[u8; 16]>,
+                      sequence_number: Option<u32>,
+                      session_key: Option<[u8; 32]>) -> Self {
+        PinnedRandom { cookie, sequence_number, session_key }
+    }
+}
+
+#[cfg(feature = "conformance-testing")]
+impl RandomSource for PinnedRandom {
+    fn fill_bytes(&mut self, buf: &mut [u8]) {
+        OsRandom.fill_bytes(buf);
+    }
+
+    fn fill_cookie(&mut self, buf: &mut [u8]) {
+        match self.cookie {
+            Some(ref cookie) => buf.copy_from_slice(cookie),
+            None => self.fill_bytes(buf),
+        }
+    }
+
+    fn fill_sequence_number(&mut self, buf: &mut [u8]) {
+        match self.sequence_number {
+            Some(sequence_number) => buf.copy_from_slice(&sequence_number.to_be_bytes()),
+            None => self.fill_bytes(buf),
+        }
+    }
+
+    fn fill_session_key(&mut self, buf: &mut [u8]) {
+        match self.session_key {
+            Some(ref session_key) => buf.copy_from_slice(session_key),
+            None => self.fill_bytes(buf),
+        }
+    }
+}
+
+
+/// A deterministic random source for tests.
+///
+/// This is a simple xorshift generator. It is NOT cryptographically secure.
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub(crate) struct SeededRandom(u64);
+
+#[cfg(test)]
+impl SeededRandom {
+    /// Create a new instance from a non-zero seed.
+    pub(crate) fn new(seed: u64) -> Self {
+        assert_ne!(seed, 0, "Seed may not be zero");
+        SeededRandom(seed)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+}
+
+#[cfg(test)]
+impl RandomSource for SeededRandom {
+    fn fill_bytes(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            let bytes = self.next_u64().to_be_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeded_is_deterministic() {
+        let mut a = SeededRandom::new(42);
+        let mut b = SeededRandom::new(42);
+        let mut buf_a = [0; 21];
+        let mut buf_b = [0; 21];
+        a.fill_bytes(&mut buf_a);
+        b.fill_bytes(&mut buf_b);
+        assert_eq!(buf_a, buf_b);
+        assert!(!buf_a.iter().all(|b| *b == 0));
+
+        let mut c = SeededRandom::new(43);
+        let mut buf_c = [0; 21];
+        c.fill_bytes(&mut buf_c);
+        assert_ne!(buf_a, buf_c);
+    }
+    #[cfg(feature = "conformance-testing")]
+    #[test]
+    fn pinned_cookie_and_csn() {
+        use crate::protocol::context::ServerContext;
+
+        let mut rng = PinnedRandom::new(Some([7; 16]), Some(0xdead_beef), None);
+        let first = ServerContext::with_rng(&mut rng);
+        let second = ServerContext::with_rng(&mut rng);
+        for ctx in &[first, second] {
+            assert_eq!(ctx.cookie_pair.ours.as_bytes(), &[7; 16]);
+            assert_eq!(ctx.csn_pair.read().unwrap().ours.combined_sequence_number(), 0xdead_beef);
+        }
+    }
+
+    #[cfg(feature = "conformance-testing")]
+    #[test]
+    fn pinned_session_key() {
+        use crate::crypto_types::{KeyPair, PrivateKey};
+        use crate::protocol::context::InitiatorContext;
+
+        let mut rng = PinnedRandom::new(None, None, Some([3; 32]));
+        let expected = KeyPair::from_private_key(PrivateKey([3; 32]));
+        let peer = *KeyPair::new().public_key();
+        let first = InitiatorContext::with_rng(peer, &mut rng);
+        let second = InitiatorContext::with_rng(peer, &mut rng);
+        for ctx in &[first, second] {
+            assert_eq!(ctx.keypair.public_key(), expected.public_key());
+        }
+        assert_ne!(KeyPair::from_random_source(&mut rng).public_key(), expected.public_key());
+    }
+}
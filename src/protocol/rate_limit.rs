@@ -0,0 +1,149 @@
+//! Rate limiting for messages from unauthenticated senders.
+//!
+//! Every message from a responder that has not finished the peer handshake
+//! (and every message from the server before the server handshake is done)
+//! costs a decryption attempt. To prevent a flood of garbage frames from
+//! burning CPU, these messages pass through a token bucket first. A
+//! responder that exceeds its limit is dropped, a server that exceeds its
+//! limit fails the signaling.
+
+use std::time::{Duration, Instant};
+
+
+/// The rate limit applied to messages from an unauthenticated sender.
+///
+/// Up to `burst` messages are accepted at once. After that, the sender may
+/// send `per_second` messages per second.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    /// The number of messages that may be sent in a burst.
+    pub burst: u32,
+    /// The number of messages per second that may be sent once the burst is
+    /// used up.
+    pub per_second: u32,
+}
+
+impl RateLimit {
+    /// Create a new rate limit.
+    pub fn new(burst: u32, per_second: u32) -> Self {
+        RateLimit { burst, per_second }
+    }
+}
+
+/// The default rate limit for responders that haven't been authenticated.
+///
+/// The peer handshake needs at most three messages (`token`, `key` and
+/// `auth`), so this leaves plenty of room for retransmissions.
+pub(crate) const DEFAULT_PEER_RATE_LIMIT: RateLimit = RateLimit { burst: 32, per_second: 16 };
+
+/// The default rate limit for the server during the server handshake.
+pub(crate) const DEFAULT_SERVER_RATE_LIMIT: RateLimit = RateLimit { burst: 16, per_second: 8 };
+
+
+/// A token bucket enforcing a [`RateLimit`](struct.RateLimit.html).
+#[derive(Debug, Clone)]
+pub(crate) struct TokenBucket {
+    limit: RateLimit,
+    tokens: u32,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Create a full bucket.
+    pub(crate) fn new(limit: RateLimit, now: Instant) -> Self {
+        TokenBucket {
+            limit,
+            tokens: limit.burst,
+            last_refill: now,
+        }
+    }
+
+    /// Take a token from the bucket.
+    ///
+    /// Return `false` if the bucket is empty, i.e. if the message should be
+    /// rejected.
+    pub(crate) fn try_take(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens == 0 {
+            return false;
+        }
+        self.tokens -= 1;
+        true
+    }
+
+    /// Add the tokens that accumulated since the last refill.
+    fn refill(&mut self, now: Instant) {
+        if self.tokens >= self.limit.burst || self.limit.per_second == 0 {
+            self.last_refill = now;
+            return;
+        }
+        let elapsed = if now > self.last_refill {
+            (now - self.last_refill).as_nanos()
+        } else {
+            0
+        };
+        let per_second = u128::from(self.limit.per_second);
+        let new_tokens = elapsed * per_second / 1_000_000_000;
+        if new_tokens == 0 {
+            return;
+        }
+        let missing = u128::from(self.limit.burst - self.tokens);
+        if new_tokens >= missing {
+            self.tokens = self.limit.burst;
+            self.last_refill = now;
+        } else {
+            // Keep the fraction of the next token
+            self.tokens += new_tokens as u32;
+            self.last_refill += Duration::from_nanos((new_tokens * 1_000_000_000 / per_second) as u64);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burst_then_refill() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(RateLimit::new(3, 2), start);
+        assert!(bucket.try_take(start));
+        assert!(bucket.try_take(start));
+        assert!(bucket.try_take(start));
+        assert!(!bucket.try_take(start));
+
+        // Half a second later, one token is available
+        let later = start + Duration::from_millis(500);
+        assert!(bucket.try_take(later));
+        assert!(!bucket.try_take(later));
+
+        // The bucket never holds more than the burst size
+        let much_later = later + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(bucket.try_take(much_later));
+        }
+        assert!(!bucket.try_take(much_later));
+    }
+
+    #[test]
+    fn fractions_are_kept() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(RateLimit::new(2, 2), start);
+        assert!(bucket.try_take(start));
+        assert!(bucket.try_take(start));
+
+        // 750 ms yield one token, the remaining 250 ms count towards the next
+        assert!(bucket.try_take(start + Duration::from_millis(750)));
+        assert!(bucket.try_take(start + Duration::from_millis(1000)));
+        assert!(!bucket.try_take(start + Duration::from_millis(1200)));
+    }
+
+    #[test]
+    fn no_refill() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(RateLimit::new(1, 0), start);
+        assert!(bucket.try_take(start));
+        assert!(!bucket.try_take(start + Duration::from_secs(3600)));
+    }
+}
@@ -0,0 +1,137 @@
+//! Rotation of the session keys with the authenticated peer.
+//!
+//! For long-lived connections, both peers may agree to replace the session
+//! keys without a new peer handshake. A peer that wants to rotate the keys
+//! creates a new session key pair and sends its public key in a `rekey`
+//! task message. The other peer answers with a `rekey` message carrying its
+//! own new public key, and switches to the new keys right away. The first
+//! peer switches when the answer arrives. If both peers start at the same
+//! time, the messages cross and neither answers.
+//!
+//! Both `rekey` messages are encrypted with the old keys. Messages that were
+//! sent before the other peer switched are still encrypted with the old
+//! keys as well, so the old keys are kept for decryption until the first
+//! message encrypted with the new keys arrives. The nonces (cookies and
+//! sequence numbers) are not affected.
+//!
+//! Note: Rekeying is not part of the SaltyRTC specification. It must be
+//! enabled on both peers with
+//! [`SaltyClientBuilder::with_rekeying`](../../struct.SaltyClientBuilder.html#method.with_rekeying).
+
+use std::collections::HashMap;
+
+use rmpv::Value;
+
+use crate::crypto_types::{KeyPair, PublicKey};
+use crate::errors::{SignalingError, SignalingResult};
+
+
+/// The type of a rekey message.
+pub(crate) const TYPE_REKEY: &str = "rekey";
+
+
+/// The state of the key rotation with the authenticated peer.
+#[derive(Debug, Default)]
+pub(crate) struct Rekey {
+    /// Our new session key pair, until the peer sent its new key.
+    requested: Option<KeyPair>,
+    /// Our previous session key pair and the previous session key of the
+    /// peer, until the first message with the new keys arrived.
+    previous: Option<(KeyPair, PublicKey)>,
+}
+
+impl Rekey {
+    /// Return whether we are waiting for the new key of the peer.
+    pub(crate) fn is_requested(&self) -> bool {
+        self.requested.is_some()
+    }
+
+    /// Remember the key pair that we sent to the peer.
+    pub(crate) fn request(&mut self, keypair: KeyPair) {
+        self.requested = Some(keypair);
+    }
+
+    /// Take the key pair that we sent to the peer, if any.
+    pub(crate) fn take_requested(&mut self) -> Option<KeyPair> {
+        self.requested.take()
+    }
+
+    /// Keep the previous keys until the peer uses the new ones.
+    pub(crate) fn switched(&mut self, keypair: KeyPair, session_key: PublicKey) {
+        self.previous = Some((keypair, session_key));
+    }
+
+    /// Return the previous keys, if the peer may still use them.
+    pub(crate) fn previous(&self) -> Option<(&KeyPair, &PublicKey)> {
+        self.previous.as_ref().map(|(keypair, session_key)| (keypair, session_key))
+    }
+
+    /// Forget the previous keys once the peer used the new ones.
+    pub(crate) fn confirmed(&mut self) {
+        if self.previous.take().is_some() {
+            debug!("Peer uses the new session keys");
+        }
+    }
+}
+
+/// Create a rekey message with our new public session key.
+pub(crate) fn rekey_message(key: &PublicKey) -> Value {
+    Value::Map(vec![
+        (Value::from("type"), Value::from(TYPE_REKEY)),
+        (Value::from("key"), Value::Binary(key.0.to_vec())),
+    ])
+}
+
+/// Return the new public session key contained in a rekey message.
+pub(crate) fn rekey_key(map: &HashMap<String, Value>) -> SignalingResult<PublicKey> {
+    map.get("key")
+        .and_then(Value::as_slice)
+        .and_then(PublicKey::from_slice)
+        .ok_or_else(|| SignalingError::InvalidMessage("Rekey message does not contain a valid key".into()))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_map(value: Value) -> HashMap<String, Value> {
+        match value {
+            Value::Map(pairs) => pairs.into_iter()
+                .map(|(key, value)| (key.as_str().unwrap().to_string(), value))
+                .collect(),
+            other => panic!("Expected map, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn message_roundtrip() {
+        let keypair = KeyPair::new();
+        let map = to_map(rekey_message(keypair.public_key()));
+        assert_eq!(map.get("type"), Some(&Value::from(TYPE_REKEY)));
+        assert_eq!(rekey_key(&map).unwrap(), *keypair.public_key());
+
+        let mut map = map;
+        map.insert("key".into(), Value::Binary(vec![1; 16]));
+        assert!(rekey_key(&map).is_err());
+        map.remove("key");
+        assert!(rekey_key(&map).is_err());
+    }
+
+    #[test]
+    fn transition() {
+        let mut rekey = Rekey::default();
+        assert!(!rekey.is_requested());
+        rekey.request(KeyPair::new());
+        assert!(rekey.is_requested());
+        assert!(rekey.take_requested().is_some());
+        assert!(!rekey.is_requested());
+
+        let old = KeyPair::new();
+        let peer_key = *KeyPair::new().public_key();
+        rekey.switched(old, peer_key);
+        assert_eq!(rekey.previous().map(|(_, key)| *key), Some(peer_key));
+        rekey.confirmed();
+        assert!(rekey.previous().is_none());
+    }
+}
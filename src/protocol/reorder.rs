@@ -0,0 +1,207 @@
+//! Reordering of task payloads by their combined sequence number.
+//!
+//! Payloads that the peer encrypted with
+//! [`SaltyClient::encrypt_for_peer`](../../struct.SaltyClient.html#method.encrypt_for_peer)
+//! may be sent over a task transport that does not preserve the order of
+//! messages. [`SaltyClient::decrypt_from_peer_ordered`](../../struct.SaltyClient.html#method.decrypt_from_peer_ordered)
+//! holds back a payload that arrives before the preceding ones, until they
+//! have arrived as well, until more than `window` payloads are waiting, or
+//! until a payload has been waiting for longer than the reorder timeout.
+//! In the latter cases, the missing payloads are skipped and reported as a
+//! gap. Payloads that timed out are also released by
+//! [`SaltyClient::release_reordered`](../../struct.SaltyClient.html#method.release_reordered),
+//! without waiting for the next payload.
+//!
+//! Payloads have their own sequence numbers, separate from the task
+//! messages sent through the server, so every gap is a missing payload.
+//! The sequence number of the first payload is random and not known in
+//! advance, so the first payload that arrives is released right away and
+//! the payloads it overtook are rejected.
+
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+
+/// The payloads released by the reorder buffer.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Ordered {
+    /// The payloads that can be passed to the task, in the order they were
+    /// sent by the peer.
+    pub payloads: Vec<Vec<u8>>,
+    /// The combined sequence numbers of the payloads that were skipped
+    /// because they did not arrive in time.
+    pub gaps: Vec<Range<u64>>,
+}
+
+
+/// A buffer for payloads that arrived before the preceding ones.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct ReorderBuffer {
+    /// The maximum number of payloads that are held back.
+    window: usize,
+    /// The maximum time that a payload is held back, if limited.
+    timeout: Option<Duration>,
+    /// The held back payloads and the time they arrived, by combined
+    /// sequence number.
+    pending: BTreeMap<u64, (Vec<u8>, Instant)>,
+}
+
+impl ReorderBuffer {
+    /// Create a buffer holding back at most `window` payloads, each for at
+    /// most `timeout`.
+    pub(crate) fn new(window: usize, timeout: Option<Duration>) -> Self {
+        ReorderBuffer { window, timeout, pending: BTreeMap::new() }
+    }
+
+    /// Forget the held back payloads.
+    pub(crate) fn clear(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Add the payload with the combined sequence number `csn`, and return
+    /// the payloads that can be released along with the combined sequence
+    /// number of the last released payload.
+    ///
+    /// `last` is the combined sequence number of the last payload that was
+    /// accepted from the peer. Payloads that are not newer than that, or
+    /// that are held back already, are rejected.
+    pub(crate) fn insert(&mut self, last: u64, csn: u64, payload: Vec<u8>, now: Instant) -> Result<(Ordered, u64), String> {
+        if csn <= last || self.pending.contains_key(&csn) {
+            return Err(format!("Payload with CSN {} has already been received", csn));
+        }
+        self.pending.insert(csn, (payload, now));
+        Ok(self.release(last, now))
+    }
+
+    /// Return the point in time at which the oldest held back payload times
+    /// out, if any.
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        let timeout = self.timeout?;
+        self.pending.values().map(|&(_, arrived)| arrived + timeout).min()
+    }
+
+    /// Return the payloads that can be released at `now`, along with the
+    /// combined sequence number of the last released payload.
+    pub(crate) fn release(&mut self, last: u64, now: Instant) -> (Ordered, u64) {
+        let mut ordered = Ordered::default();
+        let mut next = last + 1;
+        loop {
+            // Release the payloads that directly follow the last one. If
+            // `last` moved past held back payloads, they are released too.
+            while let Some(&first) = self.pending.keys().next() {
+                if first > next {
+                    break;
+                }
+                ordered.payloads.push(self.pending.remove(&first).expect("Pending payload vanished").0);
+                next = next.max(first + 1);
+            }
+            let timed_out = match self.deadline() {
+                Some(deadline) => deadline <= now,
+                None => false,
+            };
+            if self.pending.len() <= self.window && !timed_out {
+                break;
+            }
+            let first = *self.pending.keys().next().expect("No pending payload");
+            warn!("Skipping payloads with CSN {} to {}", next, first - 1);
+            ordered.gaps.push(next..first);
+            next = first;
+        }
+        (ordered, next - 1)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payloads(ordered: &Ordered) -> Vec<u8> {
+        ordered.payloads.iter().map(|payload| payload[0]).collect()
+    }
+
+    #[test]
+    fn in_order() {
+        let now = Instant::now();
+        let mut buffer = ReorderBuffer::new(2, None);
+        let (ordered, last) = buffer.insert(10, 11, vec![11], now).unwrap();
+        assert_eq!((payloads(&ordered), last), (vec![11], 11));
+        assert!(ordered.gaps.is_empty());
+        assert!(buffer.insert(11, 11, vec![11], now).is_err());
+        assert!(buffer.insert(11, 9, vec![9], now).is_err());
+    }
+
+    #[test]
+    fn reorder_within_window() {
+        let now = Instant::now();
+        let mut buffer = ReorderBuffer::new(2, None);
+        let (ordered, last) = buffer.insert(10, 13, vec![13], now).unwrap();
+        assert_eq!((payloads(&ordered), last), (vec![], 10));
+        let (ordered, last) = buffer.insert(last, 12, vec![12], now).unwrap();
+        assert_eq!((payloads(&ordered), last), (vec![], 10));
+        assert!(buffer.insert(last, 12, vec![12], now).is_err());
+        let (ordered, last) = buffer.insert(last, 11, vec![11], now).unwrap();
+        assert_eq!((payloads(&ordered), last), (vec![11, 12, 13], 13));
+        assert!(ordered.gaps.is_empty());
+    }
+
+    #[test]
+    fn gap_beyond_window() {
+        let now = Instant::now();
+        let mut buffer = ReorderBuffer::new(1, None);
+        let (ordered, last) = buffer.insert(10, 13, vec![13], now).unwrap();
+        assert_eq!((payloads(&ordered), last), (vec![], 10));
+        let (ordered, last) = buffer.insert(last, 15, vec![15], now).unwrap();
+        assert_eq!((payloads(&ordered), last), (vec![13], 13));
+        assert_eq!(ordered.gaps, vec![11..13]);
+
+        // The missing payload is too late now
+        assert!(buffer.insert(last, 12, vec![12], now).is_err());
+
+        // Without a window, payloads are never held back
+        let mut buffer = ReorderBuffer::new(0, None);
+        let (ordered, last) = buffer.insert(10, 12, vec![12], now).unwrap();
+        assert_eq!((payloads(&ordered), last), (vec![12], 12));
+        assert_eq!(ordered.gaps, vec![11..12]);
+    }
+
+    /// Held back payloads that the last accepted payload moved past are
+    /// released first.
+    #[test]
+    fn released_behind_last() {
+        let now = Instant::now();
+        let mut buffer = ReorderBuffer::new(2, None);
+        let (_, last) = buffer.insert(10, 12, vec![12], now).unwrap();
+        assert_eq!(last, 10);
+        let (ordered, last) = buffer.insert(13, 14, vec![14], now).unwrap();
+        assert_eq!((payloads(&ordered), last), (vec![12, 14], 14));
+        assert!(ordered.gaps.is_empty());
+    }
+
+    #[test]
+    fn timeout() {
+        let start = Instant::now();
+        let mut buffer = ReorderBuffer::new(3, Some(Duration::from_secs(2)));
+        assert_eq!(buffer.deadline(), None);
+        let (_, last) = buffer.insert(10, 13, vec![13], start).unwrap();
+        let (_, last) = buffer.insert(last, 15, vec![15], start + Duration::from_secs(1)).unwrap();
+        assert_eq!(buffer.deadline(), Some(start + Duration::from_secs(2)));
+
+        // Nothing timed out yet
+        let (ordered, last) = buffer.release(last, start + Duration::from_secs(1));
+        assert_eq!((payloads(&ordered), last), (vec![], 10));
+
+        // The first payload timed out, the second one is still held back
+        let (ordered, last) = buffer.release(last, start + Duration::from_secs(2));
+        assert_eq!((payloads(&ordered), last), (vec![13], 13));
+        assert_eq!(ordered.gaps, vec![11..13]);
+        assert_eq!(buffer.deadline(), Some(start + Duration::from_secs(3)));
+
+        // A late payload releases the second one as well
+        let (ordered, last) = buffer.insert(last, 16, vec![16], start + Duration::from_secs(4)).unwrap();
+        assert_eq!((payloads(&ordered), last), (vec![15, 16], 16));
+        assert_eq!(ordered.gaps, vec![14..15]);
+        assert_eq!(buffer.deadline(), None);
+    }
+}
@@ -1,18 +1,23 @@
-//! Wrapper type for the `id` field of the `send-error` message.
+//! Wrapper type for the `id` field of the `send-error` message, and tracking
+//! of the sent messages it may refer to.
 
+use std::collections::VecDeque;
 use std::fmt;
 
-use byteorder::{BigEndian, ByteOrder};
 use serde::ser::{Serialize, Serializer};
 use serde::de::{Deserialize, Deserializer, Visitor, Unexpected, Error as SerdeError};
 
-use crate::errors::{SignalingError, SignalingResult};
+use crate::errors::{FailedMessage, SignalingError, SignalingResult};
 
 use super::Address;
 use super::csn::CombinedSequenceSnapshot;
+use super::nonce::Nonce;
 
 const SEND_ERROR_ID_BYTES: usize = 8;
 
+/// The number of sent messages that are remembered.
+const SENT_MESSAGES_CAPACITY: usize = 64;
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub(crate) struct SendErrorId {
     pub(crate) source: Address,
@@ -26,8 +31,7 @@ impl SendErrorId {
         let mut bytes = [0u8; 8];
         bytes[0] = self.source.0;
         bytes[1] = self.destination.0;
-        BigEndian::write_u16(&mut bytes[2..4], self.csn.overflow_number());
-        BigEndian::write_u32(&mut bytes[4..8], self.csn.sequence_number());
+        bytes[2..8].copy_from_slice(&self.csn.to_bytes());
         bytes
     }
 
@@ -43,13 +47,21 @@ impl SendErrorId {
         };
         let source = Address(bytes[0]);
         let destination = Address(bytes[1]);
-        let overflow = BigEndian::read_u16(&bytes[2..4]);
-        let sequence = BigEndian::read_u32(&bytes[4..8]);
-        let csn = CombinedSequenceSnapshot::new(overflow, sequence);
+        let csn = CombinedSequenceSnapshot::from_bytes(&bytes[2..8]);
         Ok(SendErrorId { source, destination, csn })
     }
 }
 
+impl<'a> From<&'a Nonce> for SendErrorId {
+    fn from(nonce: &'a Nonce) -> Self {
+        SendErrorId {
+            source: nonce.source(),
+            destination: nonce.destination(),
+            csn: nonce.csn().clone(),
+        }
+    }
+}
+
 impl Serialize for SendErrorId {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
             where S: Serializer {
@@ -77,6 +89,11 @@ impl<'de> Visitor<'de> for SendErrorIdVisitor {
     fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Self::Value, E> where E: SerdeError {
         self.visit_bytes(&v)
     }
+
+    /// Some servers send the id as msgpack `str` instead of `bin`.
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E> where E: SerdeError {
+        self.visit_bytes(v.as_bytes())
+    }
 }
 
 impl<'de> Deserialize<'de> for SendErrorId {
@@ -85,3 +102,95 @@ impl<'de> Deserialize<'de> for SendErrorId {
         deserializer.deserialize_bytes(SendErrorIdVisitor)
     }
 }
+
+
+/// The most recent client-to-client messages, so that a `send-error` can be
+/// traced back to the message that could not be relayed.
+///
+/// Only the last `SENT_MESSAGES_CAPACITY` messages are kept.
+#[derive(Debug, Default)]
+pub(crate) struct SentMessages {
+    messages: VecDeque<(SendErrorId, Option<String>)>,
+}
+
+impl SentMessages {
+    /// Remember a sent message. Messages to the server are ignored.
+    pub(crate) fn track(&mut self, nonce: &Nonce, message_type: Option<String>) {
+        if nonce.destination().is_server() {
+            return;
+        }
+        if self.messages.len() == SENT_MESSAGES_CAPACITY {
+            self.messages.pop_front();
+        }
+        self.messages.push_back((SendErrorId::from(nonce), message_type));
+    }
+
+    /// Describe the message referenced by a `send-error`.
+    ///
+    /// The message type is only known if the message is one of the
+    /// recently sent messages.
+    pub(crate) fn failed_message(&self, id: &SendErrorId) -> FailedMessage {
+        let message_type = self.messages
+            .iter()
+            .rev()
+            .find(|(sent_id, _)| sent_id == id)
+            .and_then(|(_, message_type)| message_type.clone());
+        FailedMessage {
+            destination: id.destination.0,
+            message_type,
+        }
+    }
+
+    /// Forget all sent messages.
+    pub(crate) fn clear(&mut self) {
+        self.messages.clear();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::cookie::Cookie;
+
+    use super::*;
+
+    fn nonce(destination: u8, sequence: u32) -> Nonce {
+        let csn = CombinedSequenceSnapshot::new(0, sequence);
+        Nonce::new(Cookie::random(), Address(1), Address(destination), csn)
+    }
+
+    #[test]
+    fn send_error_id_roundtrip() {
+        let id = SendErrorId::from(&nonce(3, 1234));
+        assert_eq!(SendErrorId::from_slice(&id.as_bytes()).unwrap(), id);
+    }
+
+    #[test]
+    fn failed_message() {
+        let mut sent = SentMessages::default();
+        sent.track(&nonce(0, 1), Some("drop-responder".into()));
+        sent.track(&nonce(3, 2), Some("key".into()));
+        sent.track(&nonce(3, 3), None);
+        assert_eq!(sent.messages.len(), 2);
+
+        let failed = sent.failed_message(&SendErrorId::from(&nonce(3, 2)));
+        assert_eq!(failed, FailedMessage { destination: 3, message_type: Some("key".into()) });
+        assert_eq!(failed.to_string(), "'key' message to responder 0x03");
+
+        let failed = sent.failed_message(&SendErrorId::from(&nonce(3, 3)));
+        assert_eq!(failed.message_type, None);
+        assert_eq!(failed.to_string(), "message to responder 0x03");
+    }
+
+    #[test]
+    fn capacity() {
+        let mut sent = SentMessages::default();
+        for i in 0..(SENT_MESSAGES_CAPACITY as u32 + 1) {
+            sent.track(&nonce(1, i), Some("application".into()));
+        }
+        assert_eq!(sent.messages.len(), SENT_MESSAGES_CAPACITY);
+        assert_eq!(sent.failed_message(&SendErrorId::from(&nonce(1, 0))).message_type, None);
+        let last = SendErrorId::from(&nonce(1, SENT_MESSAGES_CAPACITY as u32));
+        assert_eq!(sent.failed_message(&last).message_type, Some("application".into()));
+    }
+}
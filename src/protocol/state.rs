@@ -13,6 +13,7 @@ pub enum SignalingState {
 }
 
 impl SignalingState {
+    /// Return whether the signaling may move on from this state to `new_state`.
     pub fn may_transition_to(self, new_state: Self) -> bool {
         match (self, new_state) {
             (SignalingState::ServerHandshake, SignalingState::PeerHandshake) => true,
@@ -0,0 +1,170 @@
+//! Introspection of the signaling state.
+//!
+//! A [`StateSummary`](struct.StateSummary.html) is a snapshot of the server
+//! and peer handshake states, intended for debugging handshakes that don't
+//! complete. It contains no key material and can be serialized, e.g. to be
+//! attached to a bug report.
+//!
+//! The states and transitions themselves can be exported as Graphviz dot
+//! graph with [`state_machine_dot`](fn.state_machine_dot.html).
+
+use std::fmt::{self, Write};
+
+use serde::Serialize;
+
+use super::context::PeerContext;
+use super::types::Address;
+
+
+/// A snapshot of the signaling state.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StateSummary {
+    /// Our role (`Initiator` or `Responder`).
+    pub role: String,
+    /// Our identity as assigned by the server, e.g. `responder 0x03`.
+    pub identity: String,
+    /// The signaling state (`ServerHandshake`, `PeerHandshake` or `Task`).
+    pub signaling_state: String,
+    /// The state of the handshake with the server.
+    pub server_handshake_state: String,
+    /// The peers we're currently doing a handshake with, or the
+    /// authenticated peer.
+    ///
+    /// Initiators list all known responders, ordered by address.
+    pub peers: Vec<PeerSummary>,
+    /// The name of the chosen task, once the peer handshake is done.
+    pub task: Option<String>,
+}
+
+/// A snapshot of the state of a peer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PeerSummary {
+    /// The address of the peer.
+    pub address: u8,
+    /// The state of the handshake with this peer.
+    pub handshake_state: String,
+    /// Whether this is the authenticated peer.
+    pub authenticated: bool,
+    /// Our combined sequence number towards this peer.
+    pub outgoing_csn: u64,
+    /// The last combined sequence number received from this peer.
+    pub incoming_csn: Option<u64>,
+}
+
+impl PeerSummary {
+    pub(crate) fn new(peer: &dyn PeerContext, handshake_state: &dyn fmt::Debug, authenticated: bool) -> Self {
+        let csn_pair = peer.csn_pair().read().expect("CSN pair rwlock is poisoned");
+        PeerSummary {
+            address: Address::from(peer.identity()).0,
+            handshake_state: format!("{:?}", handshake_state),
+            authenticated,
+            outgoing_csn: csn_pair.ours.combined_sequence_number(),
+            incoming_csn: csn_pair.theirs.as_ref().map(|csn| csn.combined_sequence_number()),
+        }
+    }
+}
+
+
+/// A group of states with the transitions between them.
+struct StateGroup {
+    name: &'static str,
+    label: &'static str,
+    transitions: &'static [(&'static str, &'static str, &'static str)],
+}
+
+const STATE_GROUPS: &[StateGroup] = &[
+    StateGroup {
+        name: "signaling",
+        label: "Signaling state",
+        transitions: &[
+            ("ServerHandshake", "PeerHandshake", "server-auth"),
+            ("PeerHandshake", "Task", "auth"),
+        ],
+    },
+    StateGroup {
+        name: "server",
+        label: "Server handshake",
+        transitions: &[
+            ("New", "ClientInfoSent", "server-hello / client-hello (responder), client-auth"),
+            ("ClientInfoSent", "Done", "server-auth"),
+        ],
+    },
+    StateGroup {
+        name: "responder",
+        label: "Initiator: Handshake with a responder",
+        transitions: &[
+            ("New", "TokenReceived", "token"),
+            ("TokenReceived", "KeyReceived", "key"),
+            ("KeyReceived", "KeySent", "/ key"),
+            ("KeySent", "AuthReceived", "auth"),
+            ("AuthReceived", "AuthSent", "/ auth"),
+        ],
+    },
+    StateGroup {
+        name: "initiator",
+        label: "Responder: Handshake with the initiator",
+        transitions: &[
+            ("New", "KeySent", "/ token (untrusted), key"),
+            ("KeySent", "KeyReceived", "key"),
+            ("KeyReceived", "AuthSent", "/ auth"),
+            ("AuthSent", "AuthReceived", "auth"),
+        ],
+    },
+];
+
+/// Return the signaling state machine as Graphviz dot graph.
+///
+/// The graph contains one cluster for the signaling state, the server
+/// handshake, and the peer handshake of each role. Edges are labelled with
+/// the message that triggers the transition, messages sent in response are
+/// prefixed with a slash.
+pub fn state_machine_dot() -> String {
+    let mut dot = String::from("digraph saltyrtc {\n    rankdir=LR;\n");
+    for group in STATE_GROUPS {
+        // Writing to a string cannot fail
+        let _ = writeln!(dot, "    subgraph cluster_{} {{", group.name);
+        let _ = writeln!(dot, "        label=\"{}\";", group.label);
+        for &(from, to, label) in group.transitions {
+            let _ = writeln!(
+                dot, "        {0}_{1} -> {0}_{2} [label=\"{3}\"];",
+                group.name, from, to, label,
+            );
+        }
+        for state in group_states(group) {
+            let _ = writeln!(dot, "        {0}_{1} [label=\"{1}\"];", group.name, state);
+        }
+        dot.push_str("    }\n");
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+/// Return the states of a group in the order of their first appearance.
+fn group_states(group: &StateGroup) -> Vec<&'static str> {
+    let mut states = vec![];
+    for &(from, to, _) in group.transitions {
+        for state in &[from, to] {
+            if !states.contains(state) {
+                states.push(*state);
+            }
+        }
+    }
+    states
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dot_contains_all_transitions() {
+        let dot = state_machine_dot();
+        assert!(dot.starts_with("digraph saltyrtc {"));
+        assert!(dot.contains("signaling_PeerHandshake -> signaling_Task [label=\"auth\"];"));
+        assert!(dot.contains("responder_New -> responder_TokenReceived [label=\"token\"];"));
+        assert!(dot.contains("initiator_AuthReceived [label=\"AuthReceived\"];"));
+        assert_eq!(dot.matches("subgraph").count(), STATE_GROUPS.len());
+        assert_eq!(dot.matches(" -> ").count(), 13);
+    }
+}
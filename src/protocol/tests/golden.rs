@@ -0,0 +1,147 @@
+//! Golden tests for the wire format of the signaling messages.
+//!
+//! Every message type is encoded with fixed keys and cookies and compared
+//! against a hard-coded msgpack encoding. These tests fail on any change to
+//! field names, field order or msgpack types, any of which would break
+//! interoperability with the JavaScript and Java implementations. If one of
+//! them fails, the change is most likely a bug, not the golden value.
+use data_encoding::HEXLOWER;
+
+use crate::crypto_types::{PublicKey, SignedKeys};
+
+use super::*;
+use super::cookie::Cookie;
+use super::messages::*;
+use super::send_error::SendErrorId;
+
+
+const TASK: &str = "v1.dummy.tasks";
+
+fn public_key(byte: u8) -> PublicKey {
+    PublicKey::from_slice(&[byte; 32]).unwrap()
+}
+
+fn cookie() -> Cookie {
+    Cookie::new([0xcc; 16])
+}
+
+/// Compare the encoding of a message with the concatenated hex strings, and
+/// decode the hex strings back to the message.
+macro_rules! golden {
+    ($name:ident, $msg:expr, [$($hex:expr),* $(,)?]) => {
+        #[test]
+        fn $name() {
+            let msg: Message = $msg.into();
+            let expected = concat!($($hex),*);
+            assert_eq!(HEXLOWER.encode(&msg.to_msgpack()), expected);
+            let bytes = HEXLOWER.decode(expected.as_bytes()).unwrap();
+            assert_eq!(Message::from_msgpack(&bytes).unwrap(), msg);
+        }
+    }
+}
+
+golden!(client_hello, ClientHello::new(public_key(0x11)), [
+    "82", // map with 2 entries
+    "a474797065", "ac636c69656e742d68656c6c6f", // type: client-hello
+    "a36b6579", "c4201111111111111111111111111111111111111111111111111111111111111111", // key: 32 bytes
+]);
+
+golden!(server_hello, ServerHello::new(public_key(0x22)), [
+    "82", // map with 2 entries
+    "a474797065", "ac7365727665722d68656c6c6f", // type: server-hello
+    "a36b6579", "c4202222222222222222222222222222222222222222222222222222222222222222", // key: 32 bytes
+]);
+
+golden!(client_auth, ClientAuth {
+        your_cookie: cookie(),
+        subprotocols: vec!["v1.saltyrtc.org".into()],
+        ping_interval: 30,
+        your_key: Some(public_key(0x33)),
+        extra: ExtraFields::default(),
+    }, [
+    "85", // map with 5 entries
+    "a474797065", "ab636c69656e742d61757468", // type: client-auth
+    "ab796f75725f636f6f6b6965", "c410cccccccccccccccccccccccccccccccc", // your_cookie: 16 bytes
+    "ac73756270726f746f636f6c73", "91af76312e73616c74797274632e6f7267", // subprotocols: [v1.saltyrtc.org]
+    "ad70696e675f696e74657276616c", "1e", // ping_interval: 30
+    "a8796f75725f6b6579", "c4203333333333333333333333333333333333333333333333333333333333333333", // your_key: 32 bytes
+]);
+
+golden!(server_auth_initiator, ServerAuth::for_initiator(cookie(), Some(SignedKeys::new([0x44; 80])), vec![Address(2), Address(3)]), [
+    "84", // map with 4 entries
+    "a474797065", "ab7365727665722d61757468", // type: server-auth
+    "ab796f75725f636f6f6b6965", "c410cccccccccccccccccccccccccccccccc", // your_cookie: 16 bytes
+    "ab7369676e65645f6b657973", "c4504444444444444444444444444444444444444444444444444444444444444444444444444444444444444444444444444444444444444444444444444444444444444444444444444444444444444444", // signed_keys: 80 bytes
+    "aa726573706f6e64657273", "920203", // responders: [2, 3]
+]);
+
+golden!(server_auth_responder, ServerAuth::for_responder(cookie(), None, true), [
+    "83", // map with 3 entries
+    "a474797065", "ab7365727665722d61757468", // type: server-auth
+    "ab796f75725f636f6f6b6965", "c410cccccccccccccccccccccccccccccccc", // your_cookie: 16 bytes
+    "b3696e69746961746f725f636f6e6e6563746564", "c3", // initiator_connected: true
+]);
+
+golden!(new_initiator, NewInitiator::default(), [
+    "81", // map with 1 entries
+    "a474797065", "ad6e65772d696e69746961746f72", // type: new-initiator
+]);
+
+golden!(new_responder, NewResponder::new(Address(2)), [
+    "82", // map with 2 entries
+    "a474797065", "ad6e65772d726573706f6e646572", // type: new-responder
+    "a26964", "02", // id: 2
+]);
+
+golden!(drop_responder, DropResponder::with_reason(Address(2), DropReason::DroppedByInitiator), [
+    "83", // map with 3 entries
+    "a474797065", "ae64726f702d726573706f6e646572", // type: drop-responder
+    "a26964", "02", // id: 2
+    "a6726561736f6e", "cd0bbc", // reason: 3004
+]);
+
+golden!(send_error, SendError::new(SendErrorId::from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]).unwrap()), [
+    "82", // map with 2 entries
+    "a474797065", "aa73656e642d6572726f72", // type: send-error
+    "a26964", "c4080102030405060708", // id: 8 bytes
+]);
+
+golden!(disconnected, Disconnected::new(Address(3)), [
+    "82", // map with 2 entries
+    "a474797065", "ac646973636f6e6e6563746564", // type: disconnected
+    "a26964", "03", // id: 3
+]);
+
+golden!(token, Token::new(public_key(0x55)), [
+    "82", // map with 2 entries
+    "a474797065", "a5746f6b656e", // type: token
+    "a36b6579", "c4205555555555555555555555555555555555555555555555555555555555555555", // key: 32 bytes
+]);
+
+golden!(key, Key::new(public_key(0x66)), [
+    "82", // map with 2 entries
+    "a474797065", "a36b6579", // type: key
+    "a36b6579", "c4206666666666666666666666666666666666666666666666666666666666666666", // key: 32 bytes
+]);
+
+golden!(auth_initiator, InitiatorAuthBuilder::new(cookie()).set_task(TASK, None).build().unwrap(), [
+    "84", // map with 4 entries
+    "a474797065", "a461757468", // type: auth
+    "ab796f75725f636f6f6b6965", "c410cccccccccccccccccccccccccccccccc", // your_cookie: 16 bytes
+    "a47461736b", "ae76312e64756d6d792e7461736b73", // task: v1.dummy.tasks
+    "a464617461", "81ae76312e64756d6d792e7461736b73c0", // data: {v1.dummy.tasks: nil}
+]);
+
+golden!(auth_responder, ResponderAuthBuilder::new(cookie()).add_task(TASK, None).build().unwrap(), [
+    "84", // map with 4 entries
+    "a474797065", "a461757468", // type: auth
+    "ab796f75725f636f6f6b6965", "c410cccccccccccccccccccccccccccccccc", // your_cookie: 16 bytes
+    "a57461736b73", "91ae76312e64756d6d792e7461736b73", // tasks: [v1.dummy.tasks]
+    "a464617461", "81ae76312e64756d6d792e7461736b73c0", // data: {v1.dummy.tasks: nil}
+]);
+
+golden!(close, Close::new(3003), [
+    "82", // map with 2 entries
+    "a474797065", "a5636c6f7365", // type: close
+    "a6726561736f6e", "cd0bbb", // reason: 3003
+]);
@@ -1,14 +1,21 @@
 //! Protocol tests.
-use rust_sodium::crypto::box_;
 
 use crate::crypto::PrivateKey;
 use crate::test_helpers::{DummyTask, TestRandom};
 
 use super::*;
+use super::cookie::Cookie;
 use super::csn::CombinedSequenceSnapshot;
+use super::messages::{Message, ServerHello};
+use super::random::SeededRandom;
 
 mod validate_nonce;
 mod signaling_messages;
+mod state_machine;
+pub(crate) mod simulated;
+mod transcript;
+mod replay;
+mod golden;
 
 #[test]
 fn test_responder_counter() {
@@ -47,6 +54,12 @@ impl MockSignaling {
                 signaling_state,
                 permanent_keypair: KeyPair::new(),
                 auth_provider: None,
+                initial_auth_provider: None,
+                peer_auth_method: None,
+                peer_extra_fields: ExtraFields::default(),
+                received_extra_fields: HashMap::new(),
+                peer_task_data: None,
+                renewing: false,
                 role,
                 identity,
                 server: ServerContext::new(),
@@ -54,6 +67,24 @@ impl MockSignaling {
                 task: None,
                 task_supported_types: None,
                 ping_interval: None,
+                protocol_version: ProtocolVersion::default(),
+                rng: Box::new(OsRandom),
+                heartbeat: Heartbeat::default(),
+                rekeying: false,
+                rekey: Rekey::default(),
+                clock: Arc::new(SystemClock),
+                transcript: None,
+                observer: None,
+                incoming_entry: None,
+                sequential_csn: None,
+                stats: StatsCollector::default(),
+                sent_messages: SentMessages::default(),
+                reorder: ReorderBuffer::default(),
+                renewal_queue: vec![],
+                failed: false,
+                peer_session_closed: false,
+                server_rate_limit: None,
+                server_rate_limiter: None,
             },
             peer: None,
             initiator_pubkey: PublicKey::random(),
@@ -82,10 +113,22 @@ impl Signaling for MockSignaling {
         None
     }
 
+    fn replace_session_keys(&mut self, _keypair: KeyPair, _session_key: PublicKey) -> SignalingResult<(KeyPair, PublicKey)> {
+        Err(SignalingError::Crash("Not implemented in mock".into()))
+    }
+
+    fn peer_summaries(&self) -> Vec<PeerSummary> {
+        vec![]
+    }
+
     fn initiator_pubkey(&self) -> &PublicKey {
         &self.initiator_pubkey
     }
 
+    fn reset_peers(&mut self) {
+        self.peer = None;
+    }
+
     fn validate_nonce_destination(&mut self, _nonce: &Nonce) -> Result<(), ValidationError> {
         Ok(())
     }
@@ -98,23 +141,19 @@ impl Signaling for MockSignaling {
         Err(SignalingError::Crash("Not implemented in mock".into()))
     }
 
-    fn handle_peer_message(&mut self, _obox: OpenBox<Message>) -> SignalingResult<Vec<HandleAction>> {
+    fn handle_peer_message(&mut self, _obox: OpenBox<Message>) -> SignalingResult<HandleActions> {
         Err(SignalingError::Crash("Not implemented in mock".into()))
     }
 
-    fn handle_server_auth_impl(&mut self, _msg: &ServerAuth) -> SignalingResult<Vec<HandleAction>> {
+    fn handle_server_auth_impl(&mut self, _msg: &ServerAuth) -> SignalingResult<HandleActions> {
         Err(SignalingError::Crash("Not implemented in mock".into()))
     }
 
-    fn handle_new_initiator(&mut self, _msg: NewInitiator) -> SignalingResult<Vec<HandleAction>> {
+    fn handle_role_server_message(&mut self, _msg: Message) -> SignalingResult<HandleActions> {
         Err(SignalingError::Crash("Not implemented in mock".into()))
     }
 
-    fn handle_new_responder(&mut self, _msg: NewResponder) -> SignalingResult<Vec<HandleAction>> {
-        Err(SignalingError::Crash("Not implemented in mock".into()))
-    }
-
-    fn handle_disconnected(&mut self, _msg: Disconnected) -> SignalingResult<Vec<HandleAction>> {
+    fn handle_disconnected(&mut self, _msg: Disconnected) -> SignalingResult<HandleActions> {
         Err(SignalingError::Crash("Not implemented in mock".into()))
     }
 }
@@ -158,6 +197,13 @@ fn test_peer_sequence_number_with_peer() {
     }));
 }
 
+/// Create a random nonce for raw encryption.
+fn random_nonce() -> [u8; NONCEBYTES] {
+    let mut nonce = [0u8; NONCEBYTES];
+    crypto_backend::backend().random_bytes(&mut nonce);
+    nonce
+}
+
 /// If there's no peer, raw encrypting and decrypting should fail.
 #[test]
 fn test_encrypt_decrypt_raw_with_session_keys_no_peer() {
@@ -168,7 +214,7 @@ fn test_encrypt_decrypt_raw_with_session_keys_no_peer() {
         None,
         None,
     );
-    let nonce = box_::gen_nonce();
+    let nonce = random_nonce();
     assert_eq!(
         signaling.encrypt_raw_with_session_keys(&[1, 2, 3], &nonce),
         Err(SignalingError::NoPeer)
@@ -186,7 +232,7 @@ fn test_encrypt_raw_with_session_keys_with_peer() {
     let peer_kp = KeyPair::new();
     let our_kp = KeyPair::new();
     let our_private_key_clone = our_kp.private_key().clone();
-    let nonce = box_::gen_nonce();
+    let nonce = random_nonce();
 
     // Create signaling instance
     let mut signaling = MockSignaling::new(
@@ -206,7 +252,7 @@ fn test_encrypt_raw_with_session_keys_with_peer() {
 
     // Verify
     assert_eq!(
-        box_::open(&ciphertext, &nonce, peer_kp.public_key(), &our_private_key_clone),
+        crypto_backend::box_open(&ciphertext, &nonce, peer_kp.public_key(), &our_private_key_clone),
         Ok(vec![2, 3, 4, 5])
     );
 }
@@ -240,7 +286,7 @@ fn test_encrypt_raw_with_session_keys_with_peer_known_result() {
 
     // Encrypt data
     let data = [];
-    let nonce = box_::Nonce::from_slice(b"connectionidconnectionid").unwrap();
+    let nonce = *b"connectionidconnectionid";
     let ciphertext = signaling.encrypt_raw_with_session_keys(&data, &nonce).unwrap();
 
     // Verify
@@ -256,11 +302,11 @@ fn test_decrypt_raw_with_session_keys_with_peer() {
     // Generate keypairs and nonce
     let peer_kp = KeyPair::new();
     let our_kp = KeyPair::new();
-    let nonce = box_::gen_nonce();
+    let nonce = random_nonce();
 
     // Encrypt data
     let data = [1, 2, 3, 4];
-    let ciphertext = box_::seal(&data, &nonce, peer_kp.public_key(), our_kp.private_key());
+    let ciphertext = crypto_backend::box_seal(&data, &nonce, peer_kp.public_key(), our_kp.private_key());
 
     // Create signaling instance
     let mut signaling = MockSignaling::new(
@@ -275,7 +321,7 @@ fn test_decrypt_raw_with_session_keys_with_peer() {
 
     // Decrypt with wrong nonce
     assert_eq!(
-        signaling.decrypt_raw_with_session_keys(&ciphertext, &box_::gen_nonce()),
+        signaling.decrypt_raw_with_session_keys(&ciphertext, &random_nonce()),
         Err(SignalingError::Crypto("Could not decrypt bytes".into()))
     );
 
@@ -285,3 +331,39 @@ fn test_decrypt_raw_with_session_keys_with_peer() {
         Ok(vec![1, 2, 3, 4])
     );
 }
+
+/// Handle a server-hello with an initiator that uses a seeded random source
+/// and return the resulting client-auth bytes.
+fn _seeded_client_auth(seed: u64, permanent_key: &PrivateKey, server_hello: &[u8]) -> Vec<u8> {
+    let mut signaling = InitiatorSignaling::with_rng(
+        KeyPair::from_private_key(permanent_key.clone()),
+        Tasks::new(Box::new(DummyTask::new(42))),
+        None,
+        None,
+        None,
+        Box::new(SeededRandom::new(seed)),
+    );
+    let mut actions = signaling.handle_message(ByteBox::from_slice(server_hello).unwrap()).unwrap().into_vec();
+    assert_eq!(actions.len(), 1);
+    match actions.remove(0) {
+        HandleAction::Reply(bbox) => bbox.into_bytes(),
+        other => panic!("Expected reply, got {:?}", other),
+    }
+}
+
+/// With the same seed, the outgoing bytes must be identical.
+#[test]
+fn test_seeded_rng_deterministic() {
+    let permanent_key = KeyPair::new().private_key().clone();
+    let server_hello = {
+        let msg = ServerHello::new(PublicKey::random()).into_message();
+        let nonce = Nonce::new(Cookie::random(), Address(0), Address(0), CombinedSequenceSnapshot::random());
+        OpenBox::<Message>::new(msg, nonce).encode().into_bytes()
+    };
+
+    let first = _seeded_client_auth(1234, &permanent_key, &server_hello);
+    let second = _seeded_client_auth(1234, &permanent_key, &server_hello);
+    let other = _seeded_client_auth(4321, &permanent_key, &server_hello);
+    assert_eq!(first, second);
+    assert_ne!(first, other);
+}
@@ -0,0 +1,324 @@
+//! Recording and replay of signaling sessions.
+//!
+//! A [`Recording`](struct.Recording.html) is a list of timestamped frames in
+//! both directions, along with the other actions and errors that the
+//! signaling produced while handling them. Replaying it feeds the incoming
+//! frames into a fresh `Signaling` instance and asserts that everything it
+//! emits matches the recording.
+//!
+//! To turn a bug report into a regression test, construct the signaling with
+//! the same permanent key, a seeded random source and a manual clock, feed it
+//! the frames from the report through
+//! [`Recording::feed`](struct.Recording.html#method.feed) once, check that
+//! the outcome is the expected one and store the result of
+//! [`Recording::to_msgpack`](struct.Recording.html#method.to_msgpack). The
+//! clock is advanced to the timestamp of every frame before it is handled,
+//! so replays fire the same timeouts as the recorded session.
+//! Recordings are msgpack arrays of maps with the keys `at` (milliseconds
+//! since the start of the session), `kind` (`"incoming"`, `"outgoing"`,
+//! `"action"` or `"error"`) and `data` (the frame bytes or the debug
+//! representation of the action or error).
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rmp_serde as rmps;
+use rmpv::Value;
+
+use crate::clock::{Clock, ManualClock};
+use crate::protocol::timer::Timers;
+use crate::test_helpers::DummyTask;
+
+use super::*;
+use super::cookie::Cookie;
+use super::csn::CombinedSequence;
+use super::messages::*;
+use super::random::SeededRandom;
+
+
+/// Something that happened during a recorded session.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Recorded {
+    /// A frame received by the signaling.
+    Incoming(Vec<u8>),
+    /// A frame sent by the signaling.
+    Outgoing(Vec<u8>),
+    /// Any other action, as debug representation.
+    Action(String),
+    /// An error returned by the signaling, as debug representation.
+    Error(String),
+}
+
+impl Recorded {
+    fn kind(&self) -> &'static str {
+        match *self {
+            Recorded::Incoming(_) => "incoming",
+            Recorded::Outgoing(_) => "outgoing",
+            Recorded::Action(_) => "action",
+            Recorded::Error(_) => "error",
+        }
+    }
+}
+
+/// A recorded entry and the time it happened, relative to the start of the
+/// session.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct RecordedEntry {
+    pub(crate) at: Duration,
+    pub(crate) recorded: Recorded,
+}
+
+/// The first entry where a replay did not match the recording.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Mismatch {
+    pub(crate) index: usize,
+    pub(crate) expected: Option<Recorded>,
+    pub(crate) actual: Option<Recorded>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Replay differs at entry {}: expected {:?}, got {:?}", self.index, self.expected, self.actual)
+    }
+}
+
+/// A recorded signaling session.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub(crate) struct Recording {
+    pub(crate) entries: Vec<RecordedEntry>,
+    /// The timers started while recording.
+    timers: Timers,
+}
+
+/// Convert the result of handling a frame to recorded entries.
+fn to_recorded(result: &SignalingResult<HandleActions>) -> Vec<Recorded> {
+    match *result {
+        Ok(ref actions) => actions.iter().map(|action| match action {
+            HandleAction::Reply(bbox) => Recorded::Outgoing(bbox.to_bytes()),
+            other => Recorded::Action(format!("{:?}", other)),
+        }).collect(),
+        Err(ref e) => vec![Recorded::Error(format!("{:?}", e))],
+    }
+}
+
+/// Advance the clock from the time of the previous entry to `at`, fire the
+/// timers that expired and handle the incoming frame.
+fn step(
+    signaling: &mut dyn Signaling,
+    clock: &ManualClock,
+    timers: &mut Timers,
+    previous: Duration,
+    at: Duration,
+    bytes: &[u8],
+) -> (Vec<Recorded>, SignalingResult<HandleActions>) {
+    if at > previous {
+        clock.advance(at - previous);
+    }
+    let now = clock.now();
+    let mut recorded = vec![];
+    for id in timers.expire(now) {
+        let result = signaling.handle_timeout(id);
+        recorded.extend(to_recorded(&result));
+        if let Ok(actions) = result {
+            let _ = timers.apply(actions, now);
+        }
+    }
+    let result = ByteBox::parse(bytes.to_vec()).and_then(|bbox| signaling.handle_message(bbox));
+    recorded.extend(to_recorded(&result));
+    (recorded, result.map(|actions| timers.apply(actions, now)))
+}
+
+impl Recording {
+    pub(crate) fn new() -> Self {
+        Recording::default()
+    }
+
+    /// Let the signaling handle an incoming frame at `at` and record the
+    /// frame along with the outcome.
+    ///
+    /// The `clock` must be the clock of the signaling.
+    pub(crate) fn feed(
+        &mut self,
+        signaling: &mut dyn Signaling,
+        clock: &ManualClock,
+        at: Duration,
+        bytes: &[u8],
+    ) -> SignalingResult<HandleActions> {
+        let previous = self.entries.last().map_or(Duration::from_secs(0), |entry| entry.at);
+        let (recorded, result) = step(signaling, clock, &mut self.timers, previous, at, bytes);
+        self.entries.push(RecordedEntry { at, recorded: Recorded::Incoming(bytes.to_vec()) });
+        self.entries.extend(recorded.into_iter().map(|recorded| RecordedEntry { at, recorded }));
+        result
+    }
+
+    /// Feed all incoming frames into the signaling and compare everything it
+    /// emits against the recording.
+    pub(crate) fn replay(&self, signaling: &mut dyn Signaling) -> Result<(), Mismatch> {
+        let clock = Arc::new(ManualClock::new());
+        signaling.common_mut().clock = clock.clone();
+        let mut timers = Timers::default();
+        let mut previous = Duration::from_secs(0);
+        let mut index = 0;
+        while index < self.entries.len() {
+            let entry = &self.entries[index];
+            let bytes = match entry.recorded {
+                Recorded::Incoming(ref bytes) => bytes,
+                ref other => return Err(Mismatch { index, expected: Some(other.clone()), actual: None }),
+            };
+            index += 1;
+            let (recorded, _) = step(signaling, &clock, &mut timers, previous, entry.at, bytes);
+            previous = entry.at;
+            for actual in recorded {
+                let expected = self.entries.get(index).map(|entry| entry.recorded.clone());
+                if expected.as_ref() != Some(&actual) {
+                    return Err(Mismatch { index, expected, actual: Some(actual) });
+                }
+                index += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Encode the recording as msgpack.
+    pub(crate) fn to_msgpack(&self) -> Vec<u8> {
+        let entries = self.entries.iter().map(|entry| {
+            let data = match entry.recorded {
+                Recorded::Incoming(ref bytes) | Recorded::Outgoing(ref bytes) => Value::Binary(bytes.clone()),
+                Recorded::Action(ref text) | Recorded::Error(ref text) => Value::from(text.as_str()),
+            };
+            Value::Map(vec![
+                (Value::from("at"), Value::from(entry.at.as_millis() as u64)),
+                (Value::from("kind"), Value::from(entry.recorded.kind())),
+                (Value::from("data"), data),
+            ])
+        }).collect();
+        rmps::to_vec_named(&Value::Array(entries)).expect("Serialization failed")
+    }
+
+    /// Decode a recording encoded with
+    /// [`to_msgpack`](#method.to_msgpack).
+    pub(crate) fn from_msgpack(bytes: &[u8]) -> Result<Self, String> {
+        let value: Value = rmps::from_slice(bytes).map_err(|e| format!("Invalid msgpack: {}", e))?;
+        let array = value.as_array().ok_or("Recording is not an array")?;
+        let entries = array.iter().map(|entry| {
+            let field = |name: &str| entry.as_map()
+                .and_then(|map| map.iter().find(|&(k, _)| k.as_str() == Some(name)))
+                .map(|(_, v)| v)
+                .ok_or_else(|| format!("Entry is missing the {} field", name));
+            let at = field("at")?.as_u64().ok_or("Invalid timestamp")?;
+            let data = field("data")?;
+            let bytes = || match *data {
+                Value::Binary(ref bytes) => Ok(bytes.clone()),
+                _ => Err("Data must be binary"),
+            };
+            let text = || data.as_str().map(ToString::to_string).ok_or("Data must be a string");
+            let recorded = match field("kind")?.as_str() {
+                Some("incoming") => Recorded::Incoming(bytes()?),
+                Some("outgoing") => Recorded::Outgoing(bytes()?),
+                Some("action") => Recorded::Action(text()?),
+                Some("error") => Recorded::Error(text()?),
+                other => return Err(format!("Unknown entry kind: {:?}", other)),
+            };
+            Ok(RecordedEntry { at: Duration::from_millis(at), recorded })
+        }).collect::<Result<_, String>>()?;
+        Ok(Recording { entries, timers: Timers::default() })
+    }
+}
+
+
+/// Create an initiator whose output only depends on the seed.
+fn seeded_initiator(seed: u64) -> InitiatorSignaling {
+    InitiatorSignaling::with_rng(
+        KeyPair::from_random_source(&mut SeededRandom::new(1)),
+        Tasks::new(Box::new(DummyTask::new(42))),
+        None,
+        None,
+        None,
+        Box::new(SeededRandom::new(seed)),
+    )
+}
+
+/// Record the server handshake of a seeded initiator, followed by a garbage
+/// frame from the server.
+fn record_server_handshake() -> Recording {
+    let mut rng = SeededRandom::new(2);
+    let server_ks = KeyPair::from_random_source(&mut rng);
+    let cookie = Cookie::random_from(&mut rng);
+    let mut csn = CombinedSequence::random_from(&mut rng);
+    let mut initiator = seeded_initiator(3);
+    let clock = Arc::new(ManualClock::new());
+    initiator.common_mut().clock = clock.clone();
+    let initiator_pk = *initiator.common().permanent_keypair.public_key();
+    let mut recording = Recording::new();
+
+    let nonce = Nonce::new(cookie.clone(), Address(0), Address(0), csn.increment().unwrap());
+    let server_hello = OpenBox::<Message>::new(ServerHello::new(*server_ks.public_key()).into_message(), nonce).encode();
+    let mut replies = recording.feed(&mut initiator, &clock, Duration::from_millis(0), &server_hello.into_bytes())
+        .unwrap().split_replies().0;
+    assert_eq!(replies.len(), 1);
+    let client_cookie = replies.remove(0).nonce.cookie().clone();
+
+    let nonce = Nonce::new(cookie.clone(), Address(0), Address(1), csn.increment().unwrap());
+    let server_auth = ServerAuth::for_initiator(client_cookie, None, vec![]).into_message();
+    let bbox = OpenBox::<Message>::new(server_auth, nonce).encrypt(&server_ks, &initiator_pk);
+    let actions = recording.feed(&mut initiator, &clock, Duration::from_millis(20), &bbox.into_bytes()).unwrap();
+    assert_eq!(actions.replies().count(), 0);
+    assert_eq!(initiator.common().signaling_state(), SignalingState::PeerHandshake);
+
+    let nonce = Nonce::new(cookie, Address(0), Address(1), csn.increment().unwrap());
+    let garbage = ByteBox::new(vec![1, 2, 3], nonce);
+    assert!(recording.feed(&mut initiator, &clock, Duration::from_millis(45), &garbage.into_bytes()).is_err());
+    recording
+}
+
+#[test]
+fn replay_matches_recording() {
+    let recording = record_server_handshake();
+    let kinds: Vec<_> = recording.entries.iter().map(|entry| entry.recorded.kind()).collect();
+    assert_eq!(kinds, vec![
+        "incoming", "outgoing",
+        "incoming", "action",
+        "incoming", "error",
+    ]);
+    assert_eq!(recording.replay(&mut seeded_initiator(3)), Ok(()));
+}
+
+#[test]
+fn msgpack_roundtrip() {
+    let recording = record_server_handshake();
+    let decoded = Recording::from_msgpack(&recording.to_msgpack()).unwrap();
+    assert_eq!(decoded, recording);
+    assert_eq!(decoded.entries[4].at, Duration::from_millis(45));
+    assert_eq!(decoded.replay(&mut seeded_initiator(3)), Ok(()));
+}
+
+/// With another seed, the client-auth message differs.
+#[test]
+fn replay_detects_mismatch() {
+    let recording = record_server_handshake();
+    let mismatch = recording.replay(&mut seeded_initiator(4)).unwrap_err();
+    assert_eq!(mismatch.index, 1);
+    assert_eq!(mismatch.expected, Some(recording.entries[1].recorded.clone()));
+    match mismatch.actual {
+        Some(Recorded::Outgoing(_)) => {},
+        other => panic!("Expected outgoing frame, got {:?}", other),
+    }
+}
+
+#[test]
+fn invalid_recordings() {
+    assert!(Recording::from_msgpack(&[0xc1]).is_err());
+    let entry = |kind: &str, data: Value| rmps::to_vec_named(&Value::Array(vec![Value::Map(vec![
+        (Value::from("at"), Value::from(0)),
+        (Value::from("kind"), Value::from(kind)),
+        (Value::from("data"), data),
+    ])])).unwrap();
+    assert_eq!(
+        Recording::from_msgpack(&entry("outgoing", Value::from("text"))),
+        Err("Data must be binary".to_string()),
+    );
+    assert_eq!(
+        Recording::from_msgpack(&entry("sideways", Value::Binary(vec![]))),
+        Err("Unknown entry kind: Some(\"sideways\")".to_string()),
+    );
+}
@@ -5,6 +5,7 @@ use super::*;
 use super::cookie::{Cookie, CookiePair};
 use super::csn::{CombinedSequence, CombinedSequenceSnapshot};
 use super::messages::*;
+use super::simulated::{create_peers, handshake, replies, TestServer};
 
 struct TestContext<S: Signaling> {
     /// Our permanent keypair.
@@ -149,6 +150,40 @@ impl TestMsgBuilder {
     }
 }
 
+/// Remove the `ResponderDropped` event that follows a drop-responder
+/// message, checking the address and the reason.
+fn pop_dropped_event(actions: &mut Vec<HandleAction>, address: u8, reason: ResponderDropReason) {
+    assert_eq!(actions.pop(), Some(HandleAction::Event(Event::ResponderDropped { address, reason })));
+}
+
+/// Create an initiator and a responder that finished the handshake with
+/// each other.
+fn connected_peers() -> (TestContext<InitiatorSignaling>, TestContext<ResponderSignaling>) {
+    let mut initiator = TestContext::initiator(
+        ClientIdentity::Initiator, None,
+        SignalingState::Task, ServerHandshakeState::Done,
+    );
+    let mut responder = TestContext::responder(
+        ClientIdentity::Responder(3),
+        SignalingState::Task, ServerHandshakeState::Done,
+        None, None,
+    );
+    let initiator_session_ks = KeyPair::new();
+    let responder_session_ks = KeyPair::new();
+
+    let mut responder_ctx = ResponderContext::new(Address(3), 0);
+    responder_ctx.session_key = Some(*responder_session_ks.public_key());
+    responder_ctx.keypair = KeyPair::from_private_key(initiator_session_ks.private_key().clone());
+    responder_ctx.cookie_pair.theirs = Some(responder.signaling.initiator.cookie_pair.ours.clone());
+    responder.signaling.initiator.cookie_pair.theirs = Some(responder_ctx.cookie_pair.ours.clone());
+    initiator.signaling.responder = Some(responder_ctx);
+
+    responder.signaling.initiator.session_key = Some(*initiator_session_ks.public_key());
+    responder.signaling.initiator.keypair = responder_session_ks;
+
+    (initiator, responder)
+}
+
 mod server_auth {
     use super::*;
 
@@ -235,6 +270,7 @@ mod server_auth {
             signed_keys: None,
             responders: None,
             initiator_connected: None,
+            extra: ExtraFields::default(),
         }.into_message();
         let bbox = TestMsgBuilder::new(msg).from(0).to(1).build_from_server(&ctx);
 
@@ -320,6 +356,7 @@ mod server_auth {
             signed_keys: None,
             responders: None,
             initiator_connected: None,
+            extra: ExtraFields::default(),
         }.into_message();
         let bbox = TestMsgBuilder::new(msg).from(0).to(4).build_from_server(&ctx);
 
@@ -329,6 +366,32 @@ mod server_auth {
                                          "We're a responder, but the `initiator_connected` field in the server-auth message is not set".into()));
     }
 
+    /// Unknown fields of signaling messages are kept for the application.
+    #[test]
+    fn server_auth_extra_fields() {
+        let ctx = TestContext::responder(
+            ClientIdentity::Responder(7),
+            SignalingState::ServerHandshake, ServerHandshakeState::ClientInfoSent,
+            None, None,
+        );
+        let mut extra = ExtraFields::default();
+        extra.insert("x-vendor", Value::from("hello"));
+        let msg = ServerAuth {
+            your_cookie: ctx.our_cookie.clone(),
+            signed_keys: None,
+            responders: None,
+            initiator_connected: Some(false),
+            extra: extra.clone(),
+        }.into_message();
+        let bbox = TestMsgBuilder::new(msg).from(0).to(7).build_from_server(&ctx);
+
+        let mut s = ctx.signaling;
+        assert!(s.common().received_extra_fields.is_empty());
+        let _actions = s.handle_message(bbox).unwrap();
+        assert_eq!(s.common().received_extra_fields.get("server-auth"), Some(&extra));
+        assert_eq!(s.common().received_extra_fields.get("server-hello"), None);
+    }
+
     /// In case the client is the responder, it SHALL check that the
     /// initiator_connected field contains a boolean value. In case the
     /// field's value is true, the responder MUST proceed with sending a
@@ -341,6 +404,7 @@ mod server_auth {
             signed_keys: None,
             responders: None,
             initiator_connected: Some(true),
+            extra: ExtraFields::default(),
         }.into_message();
         let bbox = TestMsgBuilder::new(msg).from(0).to(7).build_from_server(&ctx);
 
@@ -354,7 +418,7 @@ mod server_auth {
         assert_eq!(s.server().handshake_state(), ServerHandshakeState::Done);
         assert_eq!(s.initiator.handshake_state(), InitiatorHandshakeState::KeySent);
 
-        actions
+        actions.into_vec()
     }
 
     #[test]
@@ -397,6 +461,7 @@ mod server_auth {
             signed_keys: None,
             responders: None,
             initiator_connected: Some(false),
+            extra: ExtraFields::default(),
         }.into_message();
         let bbox = TestMsgBuilder::new(msg).from(0).to(7).build_from_server(&ctx);
 
@@ -411,7 +476,40 @@ mod server_auth {
         assert_eq!(s.common().signaling_state(), SignalingState::PeerHandshake);
         assert_eq!(actions, vec![
             HandleAction::Event(Event::ServerHandshakeDone(false)),
+            HandleAction::Event(Event::WaitingForInitiator),
+        ]);
+    }
+
+    /// With an initiator timeout, a responder that has to wait for the
+    /// initiator starts a timer and gives up when it expires.
+    #[test]
+    fn initiator_timeout() {
+        let mut ctx = TestContext::responder(
+            ClientIdentity::Responder(7),
+            SignalingState::ServerHandshake, ServerHandshakeState::ClientInfoSent,
+            None, None,
+        );
+        let timeout = Duration::from_secs(120);
+        ctx.signaling.initiator_timeout = Some(timeout);
+
+        let msg = ServerAuth {
+            your_cookie: ctx.our_cookie.clone(),
+            signed_keys: None,
+            responders: None,
+            initiator_connected: Some(false),
+            extra: ExtraFields::default(),
+        }.into_message();
+        let bbox = TestMsgBuilder::new(msg).from(0).to(7).build_from_server(&ctx);
+        let actions = ctx.signaling.handle_message(bbox).unwrap();
+        assert_eq!(actions, vec![
+            HandleAction::Event(Event::ServerHandshakeDone(false)),
+            HandleAction::Event(Event::WaitingForInitiator),
+            HandleAction::StartTimer(TimerId::Initiator, timeout),
         ]);
+
+        let err = ctx.signaling.handle_timeout(TimerId::Initiator).unwrap_err();
+        assert_eq!(err, SignalingError::InitiatorTimeout);
+        assert_eq!(err.close_code(), Some(CloseCode::WsClosingNormal));
     }
 
     // Helper function for server permanent key tests.
@@ -495,16 +593,69 @@ mod server_auth {
     }
 }
 
+mod server_hello {
+    use super::*;
+
+    fn _test_server_hello(server_session_key: Option<PublicKey>, server_permanent_key: Option<PublicKey>)
+                          -> SignalingResult<HandleActions> {
+        let kp = KeyPair::new();
+        let our_key = *kp.public_key();
+        let mut s = InitiatorSignaling::new(
+            kp,
+            Tasks::new(Box::new(DummyTask::new(123))),
+            None,
+            server_permanent_key,
+            None,
+        );
+        let msg = ServerHello::new(server_session_key.unwrap_or(our_key)).into_message();
+        let nonce = Nonce::new(Cookie::random(), Address(0), Address(0), CombinedSequenceSnapshot::random());
+        let result = s.handle_message(OpenBox::<Message>::new(msg, nonce).encode());
+        if result.is_err() {
+            assert_eq!(s.server().session_key, None);
+            assert_eq!(s.server().handshake_state(), ServerHandshakeState::New);
+        }
+        result
+    }
+
+    /// The server session key must not be equal to our permanent key.
+    #[test]
+    fn key_equals_our_permanent_key() {
+        let err = _test_server_hello(None, None).unwrap_err();
+        assert_eq!(err, SignalingError::InvalidKey("Server session key is equal to our permanent key".into()));
+        assert_eq!(err.close_code(), Some(CloseCode::InvalidKey));
+    }
+
+    /// The server session key must not be equal to the pinned server
+    /// permanent key.
+    #[test]
+    fn key_equals_server_permanent_key() {
+        let server_permanent_key = PublicKey::random();
+        assert_eq!(
+            _test_server_hello(Some(server_permanent_key), Some(server_permanent_key)).unwrap_err(),
+            SignalingError::InvalidKey("Server session key and permanent key are equal".into())
+        );
+    }
+
+    #[test]
+    fn key_valid() {
+        assert!(_test_server_hello(Some(PublicKey::random()), Some(PublicKey::random())).is_ok());
+    }
+}
+
 mod client_auth {
     use super::*;
 
     fn _test_ping_interval(interval: Option<Duration>) -> ClientAuth {
+        _test_client_auth(interval, None)
+    }
+
+    fn _test_client_auth(interval: Option<Duration>, server_permanent_key: Option<PublicKey>) -> ClientAuth {
         let kp = KeyPair::new();
         let mut s = InitiatorSignaling::new(
             kp,
             Tasks::new(Box::new(DummyTask::new(123))),
             None,
-            None,
+            server_permanent_key,
             interval,
         );
 
@@ -518,7 +669,7 @@ mod client_auth {
 
         // Handle message
         assert_eq!(s.server().handshake_state(), ServerHandshakeState::New);
-        let mut actions = s.handle_message(bbox).unwrap();
+        let mut actions = s.handle_message(bbox).unwrap().into_vec();
         assert_eq!(s.server().handshake_state(), ServerHandshakeState::ClientInfoSent);
         assert_eq!(actions.len(), 1); // Reply with client-auth
 
@@ -528,13 +679,13 @@ mod client_auth {
         let bytes: ByteBox = match action {
             HandleAction::Reply(bbox) => bbox,
             HandleAction::HandshakeDone => panic!("Unexpected HandshakeDone"),
-            HandleAction::HandshakeError(_) => panic!("Unexpected HandshakeError"),
             HandleAction::TaskMessage(_) => panic!("Unexpected TaskMessage"),
             HandleAction::Event(_) => panic!("Unexpected Event"),
+            HandleAction::StartTimer(..) | HandleAction::CancelTimer(_) => panic!("Unexpected timer action"),
         };
 
         let decrypted = OpenBox::<Message>::decrypt(
-            bytes, &s.common().permanent_keypair, &server_pubkey
+            bytes, &s.common().permanent_keypair, &server_pubkey, &["client-auth"]
         ).unwrap();
         match decrypted.message {
             Message::ClientAuth(client_auth) => client_auth,
@@ -569,6 +720,33 @@ mod client_auth {
         let client_auth = _test_ping_interval(Some(Duration::new(123, 45)));
         assert_eq!(client_auth.ping_interval, 123);
     }
+
+    /// If the server permanent key is pinned, send it as `your_key`, so that
+    /// the server can pick the matching key pair.
+    #[test]
+    fn your_key_pinned() {
+        let server_permanent_key = PublicKey::random();
+        let client_auth = _test_client_auth(None, Some(server_permanent_key));
+        assert_eq!(client_auth.your_key, Some(server_permanent_key));
+
+        // The key is encoded as binary field
+        let encoded = Message::ClientAuth(client_auth).to_msgpack();
+        let decoded: Value = rmpv::decode::read_value(&mut &encoded[..]).unwrap();
+        let your_key = decoded.as_map().unwrap().iter()
+            .find(|(k, _)| k.as_str() == Some("your_key"))
+            .map(|(_, v)| v.clone());
+        assert_eq!(your_key, Some(Value::Binary(server_permanent_key.0.to_vec())));
+    }
+
+    /// Without a pinned key, the `your_key` field is omitted.
+    #[test]
+    fn your_key_not_pinned() {
+        let client_auth = _test_client_auth(None, None);
+        assert_eq!(client_auth.your_key, None);
+        let encoded = Message::ClientAuth(client_auth).to_msgpack();
+        let decoded: Value = rmpv::decode::read_value(&mut &encoded[..]).unwrap();
+        assert!(decoded.as_map().unwrap().iter().all(|(k, _)| k.as_str() != Some("your_key")));
+    }
 }
 
 mod token {
@@ -626,7 +804,166 @@ mod token {
     /// field.
     #[test]
     fn token_initiator_cannot_decrypt() {
-        // TODO (#19)!
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+        );
+
+        // Create two responder contexts
+        ctx.signaling.responders.insert(Address(3), ResponderContext::new(Address(3), 0));
+        ctx.signaling.responders.insert(Address(4), ResponderContext::new(Address(4), 1));
+
+        // Encrypt a token message with the wrong auth token
+        let msg: Message = Token::new(PublicKey::random()).into_message();
+        let nonce = Nonce::new(Cookie::random(), Address(3), Address(1),
+                               CombinedSequenceSnapshot::random());
+        let encrypted = AuthToken::new().encrypt(&msg.to_msgpack(), unsafe { nonce.clone() });
+        let bbox = ByteBox::new(encrypted, nonce);
+
+        // Handle message. The responder should be dropped.
+        let mut actions = ctx.signaling.handle_message(bbox).unwrap().into_vec();
+        pop_dropped_event(&mut actions, 3, ResponderDropReason::DecryptionFailed);
+        assert_eq!(actions.len(), 1);
+        let drop_responder = match actions.remove(0) {
+            HandleAction::Reply(bbox) => OpenBox::<Message>::decrypt(
+                bbox, &ctx.server_ks, ctx.our_ks.public_key(), &["drop-responder"],
+            ).unwrap(),
+            other => panic!("Unexpected action: {:?}", other),
+        };
+        assert_eq!(
+            drop_responder.message,
+            DropResponder::with_reason(Address(3), DropReason::InitiatorCouldNotDecrypt).into_message()
+        );
+
+        // The other responder is kept
+        assert!(!ctx.signaling.responders.contains_key(&Address(3)));
+        assert!(ctx.signaling.responders.contains_key(&Address(4)));
+    }
+
+    /// In lenient mode, a responder that sends an unexpected message is
+    /// dropped with a protocol error instead of failing the signaling.
+    #[test]
+    fn lenient_initiator_drops_invalid_responder() {
+        for &strict in &[true, false] {
+            let mut ctx = TestContext::initiator(
+                ClientIdentity::Initiator, None,
+                SignalingState::PeerHandshake, ServerHandshakeState::Done,
+            );
+            ctx.signaling.strict_mode = strict;
+            ctx.signaling.responders.insert(Address(3), ResponderContext::new(Address(3), 0));
+
+            // Send a key message instead of the token message
+            let msg: Message = Key::new(PublicKey::random()).into_message();
+            let nonce = Nonce::new(Cookie::random(), Address(3), Address(1),
+                                   CombinedSequenceSnapshot::random());
+            let encrypted = ctx.signaling
+                .auth_token().expect("Could not get auth token")
+                .encrypt(&msg.to_msgpack(), unsafe { nonce.clone() });
+            let result = ctx.signaling.handle_message(ByteBox::new(encrypted, nonce));
+
+            if strict {
+                assert!(result.is_err());
+                assert!(ctx.signaling.responders.contains_key(&Address(3)));
+                continue;
+            }
+            let mut actions = result.unwrap().into_vec();
+            pop_dropped_event(&mut actions, 3, ResponderDropReason::ProtocolError);
+            assert_eq!(actions.len(), 1);
+            let drop_responder = match actions.remove(0) {
+                HandleAction::Reply(bbox) => OpenBox::<Message>::decrypt(
+                    bbox, &ctx.server_ks, ctx.our_ks.public_key(), &["drop-responder"],
+                ).unwrap(),
+                other => panic!("Unexpected action: {:?}", other),
+            };
+            assert_eq!(
+                drop_responder.message,
+                DropResponder::with_reason(Address(3), DropReason::ProtocolError).into_message()
+            );
+            assert!(!ctx.signaling.responders.contains_key(&Address(3)));
+        }
+    }
+
+    /// A responder that exceeds the rate limit before being authenticated is
+    /// dropped without trying to decrypt its messages.
+    #[test]
+    fn initiator_drops_flooding_responder() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+        );
+        ctx.signaling.peer_rate_limit = Some(RateLimit::new(1, 0));
+        assert_eq!(ctx.signaling.process_new_responder(Address(3)), Ok(HandleActions::new()));
+        assert_eq!(ctx.signaling.process_new_responder(Address(4)), Ok(HandleActions::new()));
+
+        // The first message is processed
+        let msg: Message = Token::new(PublicKey::random()).into_message();
+        let cookie = Cookie::random();
+        let csn = CombinedSequenceSnapshot::random();
+        let nonce = Nonce::new(cookie.clone(), Address(3), Address(1), csn);
+        let encrypted = ctx.signaling
+            .auth_token().expect("Could not get auth token")
+            .encrypt(&msg.to_msgpack(), unsafe { nonce.clone() });
+        let actions = ctx.signaling.handle_message(ByteBox::new(encrypted, nonce)).unwrap();
+        assert_eq!(actions, HandleActions::new());
+        assert_eq!(
+            ctx.signaling.responders.get(&Address(3)).unwrap().handshake_state(),
+            ResponderHandshakeState::TokenReceived
+        );
+
+        // The second one exceeds the limit. It would not decrypt either,
+        // but the responder is dropped before trying.
+        let nonce = Nonce::new(cookie, Address(3), Address(1), CombinedSequenceSnapshot::random());
+        let bbox = ByteBox::new(vec![0; 40], nonce);
+        let mut actions = ctx.signaling.handle_message(bbox).unwrap().into_vec();
+        pop_dropped_event(&mut actions, 3, ResponderDropReason::RateLimited);
+        assert_eq!(actions.len(), 1);
+        let drop_responder = match actions.remove(0) {
+            HandleAction::Reply(bbox) => OpenBox::<Message>::decrypt(
+                bbox, &ctx.server_ks, ctx.our_ks.public_key(), &["drop-responder"],
+            ).unwrap(),
+            other => panic!("Unexpected action: {:?}", other),
+        };
+        assert_eq!(
+            drop_responder.message,
+            DropResponder::with_reason(Address(3), DropReason::DroppedByInitiator).into_message()
+        );
+        assert!(!ctx.signaling.responders.contains_key(&Address(3)));
+        assert!(ctx.signaling.responders.contains_key(&Address(4)));
+    }
+
+    /// If the initiator trusts the responder key, a token message is a
+    /// protocol error and the responder is dropped.
+    #[test]
+    fn token_initiator_trusted_key() {
+        let responder_ks = KeyPair::new();
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, Some(*responder_ks.public_key()),
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+        );
+        assert_eq!(ctx.signaling.process_new_responder(Address(3)), Ok(HandleActions::new()));
+        assert_eq!(ctx.signaling.process_new_responder(Address(4)), Ok(HandleActions::new()));
+
+        // The token message is encrypted with the permanent keys
+        let msg: Message = Token::new(*responder_ks.public_key()).into_message();
+        let bbox = TestMsgBuilder::new(msg).from(3).to(1)
+            .build(Cookie::random(), &responder_ks, ctx.our_ks.public_key());
+
+        // Handle message. The responder should be dropped.
+        let mut actions = ctx.signaling.handle_message(bbox).unwrap().into_vec();
+        pop_dropped_event(&mut actions, 3, ResponderDropReason::ProtocolError);
+        assert_eq!(actions.len(), 1);
+        let drop_responder = match actions.remove(0) {
+            HandleAction::Reply(bbox) => OpenBox::<Message>::decrypt(
+                bbox, &ctx.server_ks, ctx.our_ks.public_key(), &["drop-responder"],
+            ).unwrap(),
+            other => panic!("Unexpected action: {:?}", other),
+        };
+        assert_eq!(
+            drop_responder.message,
+            DropResponder::with_reason(Address(3), DropReason::ProtocolError).into_message()
+        );
+        assert!(!ctx.signaling.responders.contains_key(&Address(3)));
+        assert!(ctx.signaling.responders.contains_key(&Address(4)));
     }
 
     /// If a token message is valid, set the responder permanent key.
@@ -646,7 +983,7 @@ mod token {
         let pk = PublicKey::random();
 
         // Prepare a token message
-        let msg: Message = Token { key: pk }.into_message();
+        let msg: Message = Token::new(pk).into_message();
         let msg_bytes = msg.to_msgpack();
 
         // The token message is encrypted with the auth token,
@@ -673,6 +1010,86 @@ mod token {
             assert_eq!(actions, vec![]);
         }
     }
+
+    /// Encrypt a token message from responder 3 with the initiator's auth
+    /// token.
+    fn token_from_responder(ctx: &TestContext<InitiatorSignaling>) -> ByteBox {
+        ctx.signaling.responders.get(&Address(3)).expect("Responder 3 does not exist");
+        let msg_bytes = Token::new(PublicKey::random()).into_message().to_msgpack();
+        let nonce = Nonce::new(Cookie::random(), Address(3), Address(1),
+                               CombinedSequenceSnapshot::random());
+        let encrypted = ctx.signaling
+            .auth_token().expect("Could not get auth token")
+            .encrypt(&msg_bytes, unsafe { nonce.clone() });
+        ByteBox::new(encrypted, nonce)
+    }
+
+    /// A used auth token is not restored when the handshake is reset.
+    #[test]
+    fn token_initiator_not_reused() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+        );
+        ctx.signaling.responders.insert(Address(3), ResponderContext::new(Address(3), 0));
+        let bbox = token_from_responder(&ctx);
+        assert_eq!(ctx.signaling.handle_message(bbox), Ok(HandleActions::new()));
+        assert!(ctx.signaling.auth_token().is_none());
+
+        ctx.signaling.reset_handshake().unwrap();
+        assert!(ctx.signaling.auth_token().is_none());
+    }
+
+    /// With token rotation enabled, a new token is generated and announced
+    /// once a responder used the current one.
+    #[test]
+    fn token_initiator_rotation() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+        );
+        ctx.signaling.auth_token_rotation = true;
+        ctx.signaling.responders.insert(Address(3), ResponderContext::new(Address(3), 0));
+        let old_token = ctx.signaling.auth_token().cloned().unwrap();
+        let bbox = token_from_responder(&ctx);
+
+        let actions = ctx.signaling.handle_message(bbox).unwrap().into_vec();
+        let new_token = ctx.signaling.auth_token().cloned().unwrap();
+        assert_ne!(new_token, old_token);
+        assert_eq!(actions, vec![HandleAction::Event(Event::AuthTokenRotated(
+            PairingData::new(*ctx.our_ks.public_key(), new_token.clone())
+        ))]);
+
+        // The new token survives a reset
+        ctx.signaling.reset_handshake().unwrap();
+        assert_eq!(ctx.signaling.auth_token(), Some(&new_token));
+    }
+
+    /// The auth token can be rotated and invalidated explicitly.
+    #[test]
+    fn token_initiator_rotate_and_invalidate() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::ServerHandshake, ServerHandshakeState::New,
+        );
+        let old_token = ctx.signaling.auth_token().cloned().unwrap();
+        let new_token = ctx.signaling.rotate_auth_token().unwrap();
+        assert_ne!(new_token, old_token);
+        assert_eq!(ctx.signaling.auth_token(), Some(&new_token));
+
+        ctx.signaling.invalidate_auth_token();
+        assert!(ctx.signaling.auth_token().is_none());
+        ctx.signaling.reset_handshake().unwrap();
+        assert!(ctx.signaling.auth_token().is_none());
+
+        // Tokens can't be rotated when using a trusted key
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, Some(PublicKey::random()),
+            SignalingState::ServerHandshake, ServerHandshakeState::New,
+        );
+        assert!(ctx.signaling.rotate_auth_token().is_err());
+        assert!(ctx.signaling.auth_token().is_none());
+    }
 }
 
 mod key {
@@ -702,9 +1119,7 @@ mod key {
         responder.permanent_key = Some(peer_permanent_pk.clone());
 
         // Prepare a key message
-        let msg: Message = Key {
-            key: peer_session_pk.clone(),
-        }.into_message();
+        let msg: Message = Key::new(peer_session_pk.clone()).into_message();
 
         // Encrypt message
         let bbox = TestMsgBuilder::new(msg).from(3).to(1).build(cookie, &ctx.our_ks, &peer_permanent_pk);
@@ -727,6 +1142,152 @@ mod key {
         }
     }
 
+    /// If the initiator cannot decrypt a key message, only the responder
+    /// that sent it is dropped.
+    #[test]
+    fn key_initiator_cannot_decrypt() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+        );
+
+        let addr = Address(3);
+        let mut responder = ResponderContext::new(addr, 0);
+        responder.set_handshake_state(ResponderHandshakeState::TokenReceived);
+        responder.permanent_key = Some(PublicKey::random());
+        ctx.signaling.responders.insert(addr, responder);
+
+        // Encrypt message with an unrelated key
+        let msg: Message = Key::new(PublicKey::random()).into_message();
+        let bbox = TestMsgBuilder::new(msg).from(3).to(1)
+            .build(Cookie::random(), &KeyPair::new(), ctx.our_ks.public_key());
+
+        let mut actions = ctx.signaling.handle_message(bbox).unwrap().into_vec();
+        pop_dropped_event(&mut actions, 3, ResponderDropReason::DecryptionFailed);
+        assert_eq!(actions.len(), 1);
+        assert!(ctx.signaling.responders.is_empty());
+        assert_eq!(ctx.signaling.common().signaling_state(), SignalingState::PeerHandshake);
+    }
+
+    /// A session key that is equal to the responder's permanent key or to
+    /// one of our keys is rejected with the close code 3007.
+    #[test]
+    fn key_initiator_repeated_key() {
+        for &repeated in &["peer-permanent", "our-session", "our-permanent"] {
+            let mut ctx = TestContext::initiator(
+                ClientIdentity::Initiator, None,
+                SignalingState::PeerHandshake, ServerHandshakeState::Done,
+            );
+            let peer_permanent_pk = PublicKey::random();
+            let addr = Address(3);
+            let mut responder = ResponderContext::new(addr, 0);
+            responder.set_handshake_state(ResponderHandshakeState::TokenReceived);
+            responder.permanent_key = Some(peer_permanent_pk);
+            let key = match repeated {
+                "peer-permanent" => peer_permanent_pk,
+                "our-session" => *responder.keypair.public_key(),
+                _ => *ctx.our_ks.public_key(),
+            };
+            ctx.signaling.responders.insert(addr, responder);
+
+            let msg: Message = Key::new(key).into_message();
+            let bbox = TestMsgBuilder::new(msg).from(3).to(1)
+                .build(Cookie::random(), &ctx.our_ks, &peer_permanent_pk);
+            let err = ctx.signaling.handle_message(bbox).unwrap_err();
+            assert_eq!(err.close_code(), Some(CloseCode::InvalidKey), "{}", repeated);
+            assert!(ctx.signaling.responders.get(&addr).unwrap().session_key.is_none());
+        }
+    }
+
+    /// If a responder reconnects with a new address, the context for its
+    /// old address is dropped once the key message proves its identity.
+    #[test]
+    fn key_initiator_drops_stale_responder() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+        );
+        let peer_permanent_pk = PublicKey::random();
+
+        // Stale context of the previous connection, and an unrelated responder
+        let mut stale = ResponderContext::new(Address(3), 0);
+        stale.set_handshake_state(ResponderHandshakeState::TokenReceived);
+        stale.permanent_key = Some(peer_permanent_pk);
+        ctx.signaling.responders.insert(Address(3), stale);
+        let mut other = ResponderContext::new(Address(4), 1);
+        other.set_handshake_state(ResponderHandshakeState::TokenReceived);
+        other.permanent_key = Some(PublicKey::random());
+        ctx.signaling.responders.insert(Address(4), other);
+
+        // The same responder reconnected with a new address
+        let mut responder = ResponderContext::new(Address(5), 2);
+        responder.set_handshake_state(ResponderHandshakeState::TokenReceived);
+        responder.permanent_key = Some(peer_permanent_pk);
+        ctx.signaling.responders.insert(Address(5), responder);
+
+        let msg: Message = Key::new(PublicKey::random()).into_message();
+        let bbox = TestMsgBuilder::new(msg).from(5).to(1)
+            .build(Cookie::random(), &ctx.our_ks, &peer_permanent_pk);
+        let actions = ctx.signaling.handle_message(bbox).unwrap().into_vec();
+
+        // Drop-responder for the stale context, its event and key reply
+        assert_eq!(actions.len(), 3);
+        assert_eq!(
+            actions[1],
+            HandleAction::Event(Event::ResponderDropped { address: 3, reason: ResponderDropReason::Reconnected }),
+        );
+        assert!(!ctx.signaling.responders.contains_key(&Address(3)));
+        assert!(ctx.signaling.responders.contains_key(&Address(4)));
+        assert_eq!(
+            ctx.signaling.responders.get(&Address(5)).unwrap().handshake_state(),
+            ResponderHandshakeState::KeySent,
+        );
+    }
+
+    /// The accept policy is consulted once the responder proved that it
+    /// owns its permanent key. Rejected responders are dropped.
+    #[test]
+    fn key_initiator_accept_policy() {
+        let allowed_pk = PublicKey::random();
+        let calls = Arc::new(Mutex::new(vec![]));
+        for &(peer_permanent_pk, accepted) in &[(PublicKey::random(), false), (allowed_pk, true)] {
+            let mut ctx = TestContext::initiator(
+                ClientIdentity::Initiator, None,
+                SignalingState::PeerHandshake, ServerHandshakeState::Done,
+            );
+            let policy_calls = calls.clone();
+            ctx.signaling.accept_policy = Some(Box::new(move |key: &PublicKey, used_token: bool| {
+                policy_calls.lock().unwrap().push(used_token);
+                if *key == allowed_pk { Decision::Accept } else { Decision::Reject }
+            }));
+
+            let mut responder = ResponderContext::new(Address(3), 0);
+            responder.set_handshake_state(ResponderHandshakeState::TokenReceived);
+            responder.permanent_key = Some(peer_permanent_pk);
+            ctx.signaling.responders.insert(Address(3), responder);
+
+            let msg: Message = Key::new(PublicKey::random()).into_message();
+            let bbox = TestMsgBuilder::new(msg).from(3).to(1)
+                .build(Cookie::random(), &ctx.our_ks, &peer_permanent_pk);
+            let mut actions = ctx.signaling.handle_message(bbox).unwrap().into_vec();
+
+            // Either the key reply or a drop-responder message
+            if !accepted {
+                pop_dropped_event(&mut actions, 3, ResponderDropReason::RejectedByPolicy);
+            }
+            assert_eq!(actions.len(), 1);
+            assert_eq!(ctx.signaling.responders.contains_key(&Address(3)), accepted);
+            if !accepted {
+                let bbox = match actions[0] {
+                    HandleAction::Reply(ref bbox) => bbox,
+                    ref other => panic!("Unexpected action: {:?}", other),
+                };
+                assert_eq!(bbox.nonce.destination(), Address(0));
+            }
+        }
+        assert_eq!(*calls.lock().unwrap(), vec![true, true]);
+    }
+
     /// The client MUST generate a session key pair (a new NaCl key pair
     /// for public key authenticated encryption) for further communication
     /// with the other client. The client's session key pair SHALL NOT be
@@ -749,9 +1310,7 @@ mod key {
         ctx.signaling.initiator.set_handshake_state(InitiatorHandshakeState::KeySent);
 
         // Prepare a key message
-        let msg: Message = Key {
-            key: peer_session_pk.clone(),
-        }.into_message();
+        let msg: Message = Key::new(peer_session_pk.clone()).into_message();
 
         // Encrypt message
         let bbox = TestMsgBuilder::new(msg).from(1).to(6).build(cookie, &ctx.our_ks, &peer_permanent_pk);
@@ -763,6 +1322,29 @@ mod key {
         assert_eq!(ctx.signaling.initiator.session_key, Some(peer_session_pk));
         assert_eq!(actions.len(), 1); // Reply with auth msg
     }
+
+    /// The responder rejects a session key that is equal to the initiator's
+    /// permanent key or to its own session key.
+    #[test]
+    fn key_responder_repeated_key() {
+        let peer_permanent_pk = PublicKey::random();
+        for &permanent in &[true, false] {
+            let mut ctx = TestContext::responder(
+                ClientIdentity::Responder(6),
+                SignalingState::PeerHandshake, ServerHandshakeState::Done,
+                Some(peer_permanent_pk), None,
+            );
+            ctx.signaling.initiator.set_handshake_state(InitiatorHandshakeState::KeySent);
+            let key = if permanent { peer_permanent_pk } else { *ctx.signaling.initiator.keypair.public_key() };
+
+            let msg: Message = Key::new(key).into_message();
+            let bbox = TestMsgBuilder::new(msg).from(1).to(6)
+                .build(Cookie::random(), &ctx.our_ks, &peer_permanent_pk);
+            let err = ctx.signaling.handle_message(bbox).unwrap_err();
+            assert_eq!(err.close_code(), Some(CloseCode::InvalidKey));
+            assert_eq!(ctx.signaling.initiator.session_key, None);
+        }
+    }
 }
 
 mod auth {
@@ -795,6 +1377,28 @@ mod auth {
         (ctx, responder)
     }
 
+    /// Assert that an invalid auth message from responder 0x03 only drops
+    /// that responder.
+    fn _assert_responder_dropped(ctx: &TestContext<InitiatorSignaling>, actions: HandleActions) {
+        let mut actions = actions.into_vec();
+        pop_dropped_event(&mut actions, 3, ResponderDropReason::ProtocolError);
+        assert_eq!(actions.len(), 1);
+        let drop_responder = match actions.remove(0) {
+            HandleAction::Reply(bbox) => OpenBox::<Message>::decrypt(
+                bbox, &ctx.server_ks, ctx.our_ks.public_key(), &["drop-responder"],
+            ).unwrap(),
+            other => panic!("Unexpected action: {:?}", other),
+        };
+        assert_eq!(
+            drop_responder.message,
+            DropResponder::with_reason(Address(3), DropReason::ProtocolError).into_message()
+        );
+        assert!(!ctx.signaling.responders.contains_key(&Address(3)));
+        assert_eq!(ctx.signaling.responders.len(), 2);
+        assert_eq!(ctx.signaling.common().signaling_state(), SignalingState::PeerHandshake);
+        assert!(ctx.signaling.common().tasks.is_some());
+    }
+
     /// Prepare context and initiator for auth message validation tests.
     fn _auth_msg_prepare_responder() -> TestContext<ResponderSignaling> {
         let mut ctx = TestContext::responder(
@@ -815,7 +1419,7 @@ mod auth {
     fn _auth_msg_handle_initiator(msg: Message,
                                   ctx: &mut TestContext<InitiatorSignaling>,
                                   responder: ResponderContext)
-                                  -> SignalingResult<Vec<HandleAction>> {
+                                  -> SignalingResult<HandleActions> {
         // Encrypt message
         let bbox = TestMsgBuilder::new(msg).from(3).to(1)
             .build(Cookie::random(), &responder.keypair, responder.session_key.as_ref().unwrap());
@@ -830,7 +1434,7 @@ mod auth {
     /// Handle a message for auth message validation tests.
     fn _auth_msg_handle_responder(msg: Message,
                                   ctx: &mut TestContext<ResponderSignaling>)
-                                  -> SignalingResult<Vec<HandleAction>> {
+                                  -> SignalingResult<HandleActions> {
         // Encrypt message
         let bbox = TestMsgBuilder::new(msg).from(1).to(3)
             .build(Cookie::random(),
@@ -852,8 +1456,8 @@ mod auth {
             .unwrap()
             .into_message();
 
-        let err = _auth_msg_handle_initiator(msg, &mut ctx, responder).unwrap_err();
-        assert_eq!(err, SignalingError::Protocol("Repeated cookie in auth message from responder 0x03 does not match our cookie".into()));
+        let actions = _auth_msg_handle_initiator(msg, &mut ctx, responder).unwrap();
+        _assert_responder_dropped(&ctx, actions);
     }
 
     /// The cookie provided in the your_cookie field SHALL contain the cookie it has used in its previous messages to the other client.
@@ -881,10 +1485,11 @@ mod auth {
             task: Some("foo".into()),
             tasks: None,
             data: HashMap::new(),
+            extra: ExtraFields::default(),
         }.into_message();
 
-        let err = _auth_msg_handle_initiator(msg, &mut ctx, responder).unwrap_err();
-        assert_eq!(err, SignalingError::InvalidMessage("We're an initiator, but the `task` field in the auth message is set".into()));
+        let actions = _auth_msg_handle_initiator(msg, &mut ctx, responder).unwrap();
+        _assert_responder_dropped(&ctx, actions);
     }
 
     /// An initiator SHALL validate that the tasks field contains an array with at least one element.
@@ -897,10 +1502,11 @@ mod auth {
             task: None,
             tasks: None,
             data: HashMap::new(),
+            extra: ExtraFields::default(),
         }.into_message();
 
-        let err = _auth_msg_handle_initiator(msg, &mut ctx, responder).unwrap_err();
-        assert_eq!(err, SignalingError::InvalidMessage("The `tasks` field in the auth message is not set".into()));
+        let actions = _auth_msg_handle_initiator(msg, &mut ctx, responder).unwrap();
+        _assert_responder_dropped(&ctx, actions);
     }
 
     /// An initiator SHALL validate that the tasks field contains an array with at least one element.
@@ -913,10 +1519,11 @@ mod auth {
             task: None,
             tasks: Some(vec![]),
             data: HashMap::new(),
+            extra: ExtraFields::default(),
         }.into_message();
 
-        let err = _auth_msg_handle_initiator(msg, &mut ctx, responder).unwrap_err();
-        assert_eq!(err, SignalingError::InvalidMessage("The `tasks` field in the auth message is empty".into()));
+        let actions = _auth_msg_handle_initiator(msg, &mut ctx, responder).unwrap();
+        _assert_responder_dropped(&ctx, actions);
     }
 
 
@@ -930,6 +1537,7 @@ mod auth {
             task: None,
             tasks: Some(vec!["asjk".into()]),
             data: HashMap::new(),
+            extra: ExtraFields::default(),
         }.into_message();
 
         let err = _auth_msg_handle_responder(msg, &mut ctx).unwrap_err();
@@ -946,6 +1554,7 @@ mod auth {
             task: None,
             tasks: None,
             data: HashMap::new(),
+            extra: ExtraFields::default(),
         }.into_message();
 
         let err = _auth_msg_handle_responder(msg, &mut ctx).unwrap_err();
@@ -962,6 +1571,7 @@ mod auth {
             task: Some("unknown".into()),
             tasks: None,
             data: HashMap::new(),
+            extra: ExtraFields::default(),
         }.into_message();
 
         let err = _auth_msg_handle_responder(msg, &mut ctx).unwrap_err();
@@ -978,11 +1588,11 @@ mod auth {
             task: None,
             tasks: Some(vec!["a".into(), "b".into()]),
             data: { let mut m = HashMap::new(); m.insert("a".into(), None); m },
+            extra: ExtraFields::default(),
         }.into_message();
 
-        let err = _auth_msg_handle_initiator(msg, &mut ctx, responder).unwrap_err();
-        assert_eq!(err, SignalingError::InvalidMessage(
-            "The `tasks` and `data` fields in the auth message have a different number of entries".into()));
+        let actions = _auth_msg_handle_initiator(msg, &mut ctx, responder).unwrap();
+        _assert_responder_dropped(&ctx, actions);
     }
 
     /// Validate that there is exactly one data entry
@@ -995,6 +1605,7 @@ mod auth {
             task: Some("dummy.42".into()),
             tasks: None,
             data: HashMap::new(),
+            extra: ExtraFields::default(),
         }.into_message();
         let err = _auth_msg_handle_responder(msg, &mut ctx).unwrap_err();
         assert_eq!(err, SignalingError::Protocol("The `data` field in the auth message is empty".into()));
@@ -1010,6 +1621,7 @@ mod auth {
             task: Some("dummy.42".into()),
             tasks: None,
             data: { let mut m = HashMap::new(); m.insert("a".into(), None); m.insert("b".into(), None); m },
+            extra: ExtraFields::default(),
         }.into_message();
         let err = _auth_msg_handle_responder(msg, &mut ctx).unwrap_err();
         assert_eq!(err, SignalingError::Protocol("The `data` field in the auth message contains more than one entry".into()));
@@ -1025,11 +1637,11 @@ mod auth {
             task: None,
             tasks: Some(vec!["a".into(), "b".into()]),
             data: { let mut m = HashMap::new(); m.insert("a".into(), None); m.insert("c".into(), None); m },
+            extra: ExtraFields::default(),
         }.into_message();
 
-        let err = _auth_msg_handle_initiator(msg, &mut ctx, responder).unwrap_err();
-        assert_eq!(err, SignalingError::InvalidMessage(
-            "The task \"b\" in the auth message does not have a corresponding data entry".into()));
+        let actions = _auth_msg_handle_initiator(msg, &mut ctx, responder).unwrap();
+        _assert_responder_dropped(&ctx, actions);
     }
 
     /// Validate that the task has a corresponding data entry.
@@ -1042,46 +1654,126 @@ mod auth {
             task: Some(DummyTask::name_for(42)),
             tasks: None,
             data: { let mut m = HashMap::new(); m.insert("a".into(), None); m },
+            extra: ExtraFields::default(),
         }.into_message();
         let err = _auth_msg_handle_responder(msg, &mut ctx).unwrap_err();
         assert_eq!(err, SignalingError::Protocol("The task in the auth message does not have a corresponding data entry".into()));
     }
 
+    /// Unknown fields of the auth message are kept for the application.
     #[test]
-    fn initiator_choose_task() {
+    fn initiator_auth_extra_fields() {
         let (mut ctx, responder) = _auth_msg_prepare_initiator();
 
+        let mut extra = ExtraFields::default();
+        extra.insert("x-vendor", Value::from("hello"));
         let msg: Message = Auth {
             your_cookie: responder.cookie_pair.ours.clone(),
             task: None,
-            tasks: Some(vec!["a".into(), DummyTask::name_for(42)]),
+            tasks: Some(vec![DummyTask::name_for(42)]),
             data: {
                 let mut m = HashMap::new();
-                m.insert("a".into(), None);
                 m.insert(DummyTask::name_for(42), None);
                 m
             },
+            extra,
         }.into_message();
 
-        // No task set so far
-        assert!(ctx.signaling.common().task.is_none());
+        assert!(ctx.signaling.common().peer_extra_fields.is_empty());
+        let actions = _auth_msg_handle_initiator(msg, &mut ctx, responder).unwrap();
+        assert!(actions.iter().any(|action| match action {
+            HandleAction::HandshakeDone => true,
+            _ => false,
+        }));
+        assert_eq!(ctx.signaling.common().signaling_state(), SignalingState::Task);
+        assert_eq!(ctx.signaling.common().peer_extra_fields.get("x-vendor"), Some(&Value::from("hello")));
+    }
 
-        // List of valid tasks contains 1 entry
-        assert_eq!(ctx.signaling.common().tasks.as_ref().unwrap().len(), 1);
+    /// The task data of the peer is kept for the application.
+    #[test]
+    fn initiator_auth_task_data() {
+        let (mut ctx, responder) = _auth_msg_prepare_initiator();
 
-        // Signaling state is peer handshake
-        assert_eq!(ctx.signaling.common().signaling_state(), SignalingState::PeerHandshake);
+        let mut data = HashMap::new();
+        data.insert("exclude".to_string(), Value::Array(vec![Value::from(1)]));
+        let msg: Message = Auth {
+            your_cookie: responder.cookie_pair.ours.clone(),
+            task: None,
+            tasks: Some(vec![DummyTask::name_for(42)]),
+            data: {
+                let mut m = HashMap::new();
+                m.insert(DummyTask::name_for(42), Some(data.clone()));
+                m
+            },
+            extra: ExtraFields::default(),
+        }.into_message();
 
-        let actions = _auth_msg_handle_initiator(msg, &mut ctx, responder).unwrap();
+        assert_eq!(ctx.signaling.common().peer_task_data, None);
+        let _actions = _auth_msg_handle_initiator(msg, &mut ctx, responder).unwrap();
+        assert_eq!(ctx.signaling.common().peer_task_data, Some(data));
 
-        // Task was set!
-        assert!(ctx.signaling.common().task.is_some());
-        assert_eq!(ctx.signaling.common().task.as_ref().unwrap().lock().unwrap().name(), DummyTask::name_for(42));
+        ctx.signaling.common_mut().reset_peer_state();
+        assert_eq!(ctx.signaling.common().peer_task_data, None);
+    }
 
-        // Tasks list was removed
-        assert!(ctx.signaling.common().tasks.is_none());
+    #[test]
+    fn responder_auth_task_data() {
+        let mut ctx = _auth_msg_prepare_responder();
 
-        // Responders are being dropped
+        let mut data = HashMap::new();
+        data.insert("config".to_string(), Value::Binary(vec![1, 2, 3]));
+        let msg: Message = Auth {
+            your_cookie: ctx.signaling.initiator.cookie_pair.ours.clone(),
+            task: Some(DummyTask::name_for(42)),
+            tasks: None,
+            data: {
+                let mut m = HashMap::new();
+                m.insert(DummyTask::name_for(42), Some(data.clone()));
+                m
+            },
+            extra: ExtraFields::default(),
+        }.into_message();
+
+        let _actions = _auth_msg_handle_responder(msg, &mut ctx).unwrap();
+        assert_eq!(ctx.signaling.common().peer_task_data, Some(data));
+    }
+
+    #[test]
+    fn initiator_choose_task() {
+        let (mut ctx, responder) = _auth_msg_prepare_initiator();
+
+        let msg: Message = Auth {
+            your_cookie: responder.cookie_pair.ours.clone(),
+            task: None,
+            tasks: Some(vec!["a".into(), DummyTask::name_for(42)]),
+            data: {
+                let mut m = HashMap::new();
+                m.insert("a".into(), None);
+                m.insert(DummyTask::name_for(42), None);
+                m
+            },
+            extra: ExtraFields::default(),
+        }.into_message();
+
+        // No task set so far
+        assert!(ctx.signaling.common().task.is_none());
+
+        // List of valid tasks contains 1 entry
+        assert_eq!(ctx.signaling.common().tasks.as_ref().unwrap().len(), 1);
+
+        // Signaling state is peer handshake
+        assert_eq!(ctx.signaling.common().signaling_state(), SignalingState::PeerHandshake);
+
+        let actions = _auth_msg_handle_initiator(msg, &mut ctx, responder).unwrap();
+
+        // Task was set!
+        assert!(ctx.signaling.common().task.is_some());
+        assert_eq!(ctx.signaling.common().task.as_ref().unwrap().lock().unwrap().name(), DummyTask::name_for(42));
+
+        // Tasks list was removed
+        assert!(ctx.signaling.common().tasks.is_none());
+
+        // Responders are being dropped
         assert_eq!(ctx.signaling.responders.len(), 0);
 
         // Peer was set
@@ -1089,13 +1781,128 @@ mod auth {
         assert_eq!(ctx.signaling.get_peer().as_ref().unwrap().identity(), ctx.signaling.responder.as_ref().unwrap().identity());
 
         // Number of reply messages
-        assert_eq!(actions.len(), 4); // auth + drop-responder(5) + drop-responder(7) + HandshakeDone
+        assert_eq!(actions.len(), 8); // 2 * (drop-responder + drop event) + race event + auth + event + HandshakeDone
+        assert_eq!(actions[4], HandleAction::Event(Event::HandshakeRaceWon(3, vec![4, 7])));
+        assert_eq!(actions[6], HandleAction::Event(Event::PeerHandshakeDone(DummyTask::name_for(42))));
+        assert_eq!(actions[7], HandleAction::HandshakeDone);
 
         // State transitions
         assert_eq!(ctx.signaling.common().signaling_state(), SignalingState::Task);
         assert_eq!(ctx.signaling.responder.unwrap().handshake_state(), ResponderHandshakeState::AuthSent);
     }
 
+    /// The first responder to authenticate wins, the other responders are
+    /// dropped, even if their handshake is almost done.
+    #[test]
+    fn initiator_first_auth_wins() {
+        let (mut ctx, responder) = _auth_msg_prepare_initiator();
+        let msg: Message = ResponderAuthBuilder::new(responder.cookie_pair.ours.clone())
+            .add_task(DummyTask::name_for(42), None)
+            .build()
+            .unwrap()
+            .into_message();
+        let mut actions = _auth_msg_handle_initiator(msg, &mut ctx, responder).unwrap().into_vec();
+
+        for &addr in &[4, 7] {
+            let drop_responder = match actions.remove(0) {
+                HandleAction::Reply(bbox) => OpenBox::<Message>::decrypt(
+                    bbox, &ctx.server_ks, ctx.our_ks.public_key(), &["drop-responder"],
+                ).unwrap(),
+                other => panic!("Unexpected action: {:?}", other),
+            };
+            assert_eq!(
+                drop_responder.message,
+                DropResponder::with_reason(Address(addr), DropReason::DroppedByInitiator).into_message()
+            );
+            assert_eq!(
+                actions.remove(0),
+                HandleAction::Event(Event::ResponderDropped { address: addr, reason: ResponderDropReason::LostRace }),
+            );
+        }
+        assert_eq!(actions.remove(0), HandleAction::Event(Event::HandshakeRaceWon(3, vec![4, 7])));
+        assert_eq!(ctx.signaling.common().signaling_state(), SignalingState::Task);
+
+        // A late auth message from a dropped responder is ignored
+        let late: Message = ResponderAuthBuilder::new(Cookie::random())
+            .add_task(DummyTask::name_for(42), None)
+            .build()
+            .unwrap()
+            .into_message();
+        let bbox = TestMsgBuilder::new(late).from(7).to(1)
+            .build(Cookie::random(), &KeyPair::new(), &PublicKey::random());
+        assert_eq!(ctx.signaling.handle_message(bbox), Ok(HandleActions::new()));
+        assert_eq!(ctx.signaling.get_peer().unwrap().identity(), Identity::Responder(3));
+    }
+
+    #[test]
+    fn initiator_choose_task_priority() {
+        let (mut ctx, responder) = _auth_msg_prepare_initiator();
+        ctx.signaling.common_mut().tasks = Some(Tasks::from_vec(vec![
+            Box::new(DummyTask::new(23)),
+            Box::new(DummyTask::new(42)),
+        ]).unwrap());
+
+        // The responder prefers 42, but our own priority order wins
+        let msg: Message = Auth {
+            your_cookie: responder.cookie_pair.ours.clone(),
+            task: None,
+            tasks: Some(vec![DummyTask::name_for(42), DummyTask::name_for(23)]),
+            data: {
+                let mut m = HashMap::new();
+                m.insert(DummyTask::name_for(42), None);
+                m.insert(DummyTask::name_for(23), None);
+                m
+            },
+            extra: ExtraFields::default(),
+        }.into_message();
+
+        let actions = _auth_msg_handle_initiator(msg, &mut ctx, responder).unwrap();
+        assert_eq!(ctx.signaling.common().task.as_ref().unwrap().lock().unwrap().name(), DummyTask::name_for(23));
+        assert!(actions.contains(&HandleAction::Event(Event::PeerHandshakeDone(DummyTask::name_for(23)))));
+    }
+
+    /// If there is no shared task, the initiator closes the connection to
+    /// the responder and drops it, the other responders are kept.
+    #[test]
+    fn initiator_no_shared_task() {
+        let (mut ctx, responder) = _auth_msg_prepare_initiator();
+        let session_ks = KeyPair::from_private_key(responder.keypair.private_key().clone());
+        let peer_session_pk = responder.session_key.unwrap();
+
+        let msg: Message = Auth {
+            your_cookie: responder.cookie_pair.ours.clone(),
+            task: None,
+            tasks: Some(vec!["a".into()]),
+            data: { let mut m = HashMap::new(); m.insert("a".into(), None); m },
+            extra: ExtraFields::default(),
+        }.into_message();
+
+        let mut actions = _auth_msg_handle_initiator(msg, &mut ctx, responder).unwrap().into_vec();
+        pop_dropped_event(&mut actions, 3, ResponderDropReason::NoSharedTask);
+        assert_eq!(actions.len(), 2);
+        let close = match actions.remove(0) {
+            HandleAction::Reply(bbox) => OpenBox::<Message>::decrypt(bbox, &session_ks, &peer_session_pk, &["close"]).unwrap(),
+            other => panic!("Unexpected action: {:?}", other),
+        };
+        assert_eq!(close.nonce.destination(), Address(3));
+        assert_eq!(close.message, Message::Close(Close::from_close_code(CloseCode::NoSharedTask)));
+        let drop_responder = match actions.remove(0) {
+            HandleAction::Reply(bbox) => OpenBox::<Message>::decrypt(
+                bbox, &ctx.server_ks, ctx.our_ks.public_key(), &["drop-responder"],
+            ).unwrap(),
+            other => panic!("Unexpected action: {:?}", other),
+        };
+        assert_eq!(
+            drop_responder.message,
+            DropResponder::with_reason(Address(3), DropReason::DroppedByInitiator).into_message()
+        );
+
+        // Our tasks are kept for the other responders
+        assert_eq!(ctx.signaling.responders.len(), 2);
+        assert_eq!(ctx.signaling.common().tasks.as_ref().map(Tasks::len), Some(1));
+        assert_eq!(ctx.signaling.common().signaling_state(), SignalingState::PeerHandshake);
+    }
+
     #[test]
     fn responder_choose_task() {
         let mut ctx = _auth_msg_prepare_responder();
@@ -1109,6 +1916,7 @@ mod auth {
                 m.insert(DummyTask::name_for(42), None);
                 m
             },
+            extra: ExtraFields::default(),
         }.into_message();
 
         // No task set so far
@@ -1134,7 +1942,10 @@ mod auth {
         assert_eq!(ctx.signaling.get_peer().as_ref().unwrap().identity(), ctx.signaling.initiator.identity());
 
         // Number of actionsmessages
-        assert_eq!(actions, vec![HandleAction::HandshakeDone]);
+        assert_eq!(actions, vec![
+            HandleAction::Event(Event::PeerHandshakeDone(DummyTask::name_for(42))),
+            HandleAction::HandshakeDone,
+        ]);
 
         // State transitions
         assert_eq!(ctx.signaling.common().signaling_state(), SignalingState::Task);
@@ -1188,7 +1999,7 @@ mod new_initiator {
         );
 
         // Encrypt message
-        let msg = Message::NewInitiator(NewInitiator);
+        let msg = Message::NewInitiator(NewInitiator::default());
         let bbox = TestMsgBuilder::new(msg).from(0).to(1)
             .build(ctx.server_cookie.clone(),
                    &ctx.server_ks,
@@ -1196,7 +2007,7 @@ mod new_initiator {
 
         // Handle message
         let err = ctx.signaling.handle_message(bbox).unwrap_err();
-        let msg = "Received \'new-responder\' message as initiator".into();
+        let msg = "Received \'new-initiator\' message as initiator".into();
         assert_eq!(err, SignalingError::Protocol(msg))
     }
 
@@ -1211,7 +2022,7 @@ mod new_initiator {
         );
 
         // Encrypt message
-        let msg = Message::NewInitiator(NewInitiator);
+        let msg = Message::NewInitiator(NewInitiator::default());
         let bbox = TestMsgBuilder::new(msg).from(0).to(7)
             .build(ctx.server_cookie.clone(),
                    &ctx.server_ks,
@@ -1239,11 +2050,157 @@ mod new_initiator {
         assert_eq!(actions.len(), 1);
         assert_eq!(ctx.signaling.initiator.handshake_state(), InitiatorHandshakeState::KeySent);
     }
+
+    /// A new initiator cancels the initiator timeout, so a stale timeout is
+    /// ignored.
+    #[test]
+    fn handle_as_responder_cancels_initiator_timeout() {
+        let mut ctx = TestContext::responder(
+            ClientIdentity::Responder(7),
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+            None,
+            None,
+        );
+        ctx.signaling.initiator_timeout = Some(Duration::from_secs(120));
+
+        let bbox = TestMsgBuilder::new(Message::NewInitiator(NewInitiator::default())).from(0).to(7)
+            .build(ctx.server_cookie.clone(),
+                   &ctx.server_ks,
+                   ctx.our_ks.public_key());
+        let actions = ctx.signaling.handle_message(bbox).unwrap().into_vec();
+        assert_eq!(actions.len(), 2);
+        assert_eq!(actions[0], HandleAction::CancelTimer(TimerId::Initiator));
+        assert_eq!(ctx.signaling.handle_timeout(TimerId::Initiator), Ok(HandleActions::new()));
+    }
+
+    /// If the initiator disconnects again, the responder waits for the next
+    /// one and restarts the initiator timeout.
+    #[test]
+    fn handle_as_responder_restarts_initiator_timeout() {
+        let mut ctx = TestContext::responder(
+            ClientIdentity::Responder(7),
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+            None,
+            None,
+        );
+        let timeout = Duration::from_secs(120);
+        ctx.signaling.initiator_timeout = Some(timeout);
+
+        let mut csn = CombinedSequence::random();
+        let bbox = TestMsgBuilder::new(Message::NewInitiator(NewInitiator::default())).from(0).to(7)
+            .build_with_csn(ctx.server_cookie.clone(),
+                            &ctx.server_ks,
+                            ctx.our_ks.public_key(),
+                            csn.increment().unwrap());
+        let _actions = ctx.signaling.handle_message(bbox).unwrap();
+
+        let msg = Message::Disconnected(Disconnected::new(ClientIdentity::Initiator.into()));
+        let bbox = TestMsgBuilder::new(msg).from(0).to(7)
+            .build_with_csn(ctx.server_cookie.clone(),
+                            &ctx.server_ks,
+                            ctx.our_ks.public_key(),
+                            csn.increment().unwrap());
+        let actions = ctx.signaling.handle_message(bbox).unwrap();
+        assert_eq!(actions, vec![
+            HandleAction::Event(Event::Disconnected(1)),
+            HandleAction::Event(Event::WaitingForInitiator),
+            HandleAction::StartTimer(TimerId::Initiator, timeout),
+        ]);
+        assert_eq!(ctx.signaling.handle_timeout(TimerId::Initiator), Err(SignalingError::InitiatorTimeout));
+    }
+
+    /// The auth token must be sent again to every new initiator.
+    #[test]
+    fn handle_as_responder_repeated() {
+        let mut ctx = TestContext::responder(
+            ClientIdentity::Responder(7),
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+            None,
+            Some(AuthToken::new()),
+        );
+
+        let mut csn = CombinedSequence::random();
+        for _ in 0..2 {
+            let bbox = TestMsgBuilder::new(Message::NewInitiator(NewInitiator::default())).from(0).to(7)
+                .build_with_csn(ctx.server_cookie.clone(),
+                                &ctx.server_ks,
+                                ctx.our_ks.public_key(),
+                                csn.increment().unwrap());
+            let actions = ctx.signaling.handle_message(bbox).unwrap();
+
+            // Token and key
+            assert_eq!(actions.len(), 2);
+            assert_eq!(ctx.signaling.initiator.handshake_state(), InitiatorHandshakeState::KeySent);
+        }
+    }
+
+    /// After the peer handshake, the task should be closed and the session
+    /// with the previous initiator discarded.
+    #[test]
+    fn handle_as_responder_in_task_state() {
+        let mut ctx = TestContext::responder(
+            ClientIdentity::Responder(7),
+            SignalingState::Task, ServerHandshakeState::Done,
+            None,
+            None,
+        );
+        ctx.signaling.initiator.session_key = Some(*KeyPair::new().public_key());
+        ctx.signaling.initiator.set_handshake_state(InitiatorHandshakeState::AuthReceived);
+        ctx.signaling.initiator_timeout = Some(Duration::from_secs(120));
+
+        let bbox = TestMsgBuilder::new(Message::NewInitiator(NewInitiator::default())).from(0).to(7)
+            .build(ctx.server_cookie.clone(),
+                   &ctx.server_ks,
+                   ctx.our_ks.public_key());
+        let actions = ctx.signaling.handle_message(bbox).unwrap().into_vec();
+
+        assert_eq!(actions, vec![
+            HandleAction::CancelTimer(TimerId::Initiator),
+            HandleAction::TaskMessage(TaskMessage::Close(CloseCode::WsGoingAway)),
+        ]);
+        assert_eq!(ctx.signaling.initiator.session_key, None);
+        assert_eq!(ctx.signaling.initiator.handshake_state(), InitiatorHandshakeState::New);
+        assert_eq!(ctx.signaling.common().signaling_state(), SignalingState::Task);
+
+        // The task can no longer send messages to the peer
+        let value = Value::Map(vec![(Value::from("type"), Value::from("application"))]);
+        assert_eq!(ctx.signaling.encode_task_message(value), Err(SignalingError::NoPeer));
+        assert_eq!(ctx.signaling.encrypt_for_peer(&[1, 2, 3]).err(), Some(SignalingError::NoPeer));
+        assert_eq!(ctx.signaling.heartbeat_tick(3), Ok(HandleActions::new()));
+
+        // Messages from the new initiator are ignored until the connection
+        // is closed
+        let bbox = TestMsgBuilder::new(Message::Close(Close::from_close_code(CloseCode::WsGoingAway))).from(1).to(7)
+            .build(Cookie::random(), &KeyPair::new(), ctx.our_ks.public_key());
+        assert_eq!(ctx.signaling.handle_message(bbox), Ok(HandleActions::new()));
+    }
 }
 
 mod new_responder {
     use super::*;
 
+    /// A responder should reject `NewResponder` messages.
+    #[test]
+    fn handle_as_responder() {
+        let mut ctx = TestContext::responder(
+            ClientIdentity::Responder(7),
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+            None, None,
+        );
+
+        // Encrypt message
+        let msg = Message::NewResponder(NewResponder::new(Address(3)));
+        let bbox = TestMsgBuilder::new(msg).from(0).to(7)
+            .build(ctx.server_cookie.clone(),
+                   &ctx.server_ks,
+                   ctx.our_ks.public_key());
+
+        // Handle message
+        let err = ctx.signaling.handle_message(bbox).unwrap_err();
+        let msg = "Received \'new-responder\' message as responder".into();
+        assert_eq!(err, SignalingError::Protocol(msg))
+    }
+
     /// When a trusted key is available, the client should not expect a token
     /// message.
     #[test]
@@ -1256,7 +2213,7 @@ mod new_responder {
 
         // Encrypt new-responder message
         let address = Address::from(7);
-        let msg = Message::NewResponder(NewResponder { id: address.clone() });
+        let msg = Message::NewResponder(NewResponder::new(address.clone()));
         let bbox = TestMsgBuilder::new(msg).from(0).to(1)
             .build(ctx.server_cookie.clone(),
                    &ctx.server_ks,
@@ -1271,7 +2228,7 @@ mod new_responder {
             let responder_cookie = Cookie::random();
             let responder: &mut ResponderContext = ctx.signaling.responders.get_mut(&address).unwrap();
             responder.cookie_pair_mut().theirs = Some(responder_cookie.clone());
-            let msg = Message::Token(Token { key: peer_trusted_pk });
+            let msg = Message::Token(Token::new(peer_trusted_pk));
             TestMsgBuilder::new(msg).from(7).to(1)
                 .build(responder_cookie,
                        &responder.keypair().expect("No responder keypair"),
@@ -1279,7 +2236,8 @@ mod new_responder {
         }; // Waiting for NLL
 
         // Handle message
-        let actions = ctx.signaling.handle_message(bbox).unwrap();
+        let mut actions = ctx.signaling.handle_message(bbox).unwrap().into_vec();
+        pop_dropped_event(&mut actions, 7, ResponderDropReason::DecryptionFailed);
         assert_eq!(actions.len(), 1); // Drop responder
     }
 
@@ -1294,7 +2252,7 @@ mod new_responder {
         let mut csn = CombinedSequence::random();
 
         let mut handle_message = |css: CombinedSequenceSnapshot, i: u8| {
-            let msg = Message::NewResponder(NewResponder { id: i.into() });
+            let msg = Message::NewResponder(NewResponder::new(i.into()));
             let bbox = TestMsgBuilder::new(msg).from(0).to(1)
                 .build_with_csn(
                     ctx.server_cookie.clone(),
@@ -1312,10 +2270,194 @@ mod new_responder {
         }
 
         // The 253rd responder should result in a drop-responder message
-        let actions = handle_message(csn.increment().unwrap(), 255);
+        let mut actions = handle_message(csn.increment().unwrap(), 255).into_vec();
+        assert_eq!(actions.pop(), Some(HandleAction::Event(Event::PathAlmostFull(2))));
+        pop_dropped_event(&mut actions, 2, ResponderDropReason::PathFull);
         assert_eq!(actions.len(), 1);
     }
 
+    /// With a lower responder limit, the oldest inactive responder is dropped.
+    /// If all responders are active, the new responder is dropped.
+    #[test]
+    fn path_cleaning_max_responders() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+        );
+        ctx.signaling.max_responders = 2;
+
+        // Return the event that follows the drop-responder message
+        let drop_responder = |actions: HandleActions, ctx: &TestContext<InitiatorSignaling>| {
+            let mut actions = actions.into_vec();
+            assert_eq!(actions.len(), 3);
+            match actions.remove(0) {
+                HandleAction::Reply(bbox) => OpenBox::<Message>::decrypt(
+                    bbox, &ctx.server_ks, ctx.our_ks.public_key(), &["drop-responder"],
+                ).unwrap().message,
+                other => panic!("Unexpected action: {:?}", other),
+            };
+            match actions.remove(0) {
+                HandleAction::Event(Event::ResponderDropped { reason: ResponderDropReason::PathFull, .. }) => {},
+                other => panic!("Unexpected action: {:?}", other),
+            };
+            actions.remove(0)
+        };
+
+        assert_eq!(ctx.signaling.process_new_responder(Address(3)), Ok(HandleActions::new()));
+        assert_eq!(ctx.signaling.process_new_responder(Address(4)), Ok(HandleActions::new()));
+
+        // The oldest inactive responder is dropped
+        let actions = ctx.signaling.process_new_responder(Address(5)).unwrap();
+        assert_eq!(drop_responder(actions, &ctx), HandleAction::Event(Event::PathAlmostFull(3)));
+        assert!(!ctx.signaling.responders.contains_key(&Address(3)));
+
+        // Responders that sent a token are not dropped
+        for address in &[4, 5] {
+            ctx.signaling.responders.get_mut(&Address(*address)).unwrap()
+                .set_handshake_state(ResponderHandshakeState::TokenReceived);
+        }
+        let actions = ctx.signaling.process_new_responder(Address(6)).unwrap();
+        assert_eq!(drop_responder(actions, &ctx), HandleAction::Event(Event::PathFull(6)));
+        let mut addresses: Vec<u8> = ctx.signaling.responders.keys().map(|a| a.0).collect();
+        addresses.sort();
+        assert_eq!(addresses, vec![4, 5]);
+    }
+
+    /// Responders that don't complete the handshake in time should be
+    /// dropped.
+    #[test]
+    fn responder_timeout() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+        );
+
+        // Without a timeout, no timer is started
+        assert_eq!(ctx.signaling.process_new_responder(Address(3)), Ok(HandleActions::new()));
+
+        let timeout = Duration::from_secs(30);
+        ctx.signaling.responder_timeout = Some(timeout);
+        assert_eq!(
+            ctx.signaling.process_new_responder(Address(4)).map(HandleActions::into_vec),
+            Ok(vec![HandleAction::StartTimer(TimerId::Responder(Address(4)), timeout)])
+        );
+
+        // The responder should be dropped and its timer cancelled
+        let mut actions = ctx.signaling.handle_timeout(TimerId::Responder(Address(4))).unwrap().into_vec();
+        pop_dropped_event(&mut actions, 4, ResponderDropReason::Timeout);
+        assert_eq!(actions.len(), 2);
+        let drop_responder = match actions.remove(0) {
+            HandleAction::Reply(bbox) => OpenBox::<Message>::decrypt(
+                bbox, &ctx.server_ks, ctx.our_ks.public_key(), &["drop-responder"],
+            ).unwrap(),
+            other => panic!("Unexpected action: {:?}", other),
+        };
+        assert_eq!(
+            drop_responder.message,
+            DropResponder::with_reason(Address(4), DropReason::DroppedByInitiator).into_message()
+        );
+        assert_eq!(actions, vec![HandleAction::CancelTimer(TimerId::Responder(Address(4)))]);
+        assert!(!ctx.signaling.responders.contains_key(&Address(4)));
+        assert!(ctx.signaling.responders.contains_key(&Address(3)));
+
+        // A stale timeout is ignored
+        assert_eq!(ctx.signaling.handle_timeout(TimerId::Responder(Address(4))), Ok(HandleActions::new()));
+    }
+
+    /// Before the connection is closed, the responders that are still in the
+    /// handshake are dropped.
+    #[test]
+    fn drop_pending_responders() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::Task, ServerHandshakeState::Done,
+        );
+        ctx.signaling.responder_timeout = Some(Duration::from_secs(30));
+        for &address in &[4, 3] {
+            assert!(ctx.signaling.process_new_responder(Address(address)).is_ok());
+        }
+
+        let actions = ctx.signaling.drop_pending_peers().unwrap();
+        let (replies, others) = actions.split_replies();
+        let dropped: Vec<Message> = replies
+            .into_iter()
+            .map(|bbox| OpenBox::<Message>::decrypt(
+                bbox, &ctx.server_ks, ctx.our_ks.public_key(), &["drop-responder"],
+            ).unwrap().message)
+            .collect();
+        assert_eq!(dropped, vec![
+            DropResponder::with_reason(Address(3), DropReason::DroppedByInitiator).into_message(),
+            DropResponder::with_reason(Address(4), DropReason::DroppedByInitiator).into_message(),
+        ]);
+        assert_eq!(others, vec![
+            HandleAction::CancelTimer(TimerId::Responder(Address(3))),
+            HandleAction::Event(Event::ResponderDropped { address: 3, reason: ResponderDropReason::Closing }),
+            HandleAction::CancelTimer(TimerId::Responder(Address(4))),
+            HandleAction::Event(Event::ResponderDropped { address: 4, reason: ResponderDropReason::Closing }),
+        ]);
+        assert!(ctx.signaling.responders.is_empty());
+        assert!(ctx.signaling.drop_pending_peers().unwrap().is_empty());
+    }
+
+}
+
+mod send_error {
+    use super::*;
+    use crate::errors::FailedMessage;
+    use crate::protocol::send_error::SendErrorId;
+
+    /// A send-error should name the message that could not be relayed.
+    #[test]
+    fn send_error_names_failed_message() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+        );
+        let peer_permanent_pk = PublicKey::random();
+        let mut responder = ResponderContext::new(Address(3), 0);
+        responder.set_handshake_state(ResponderHandshakeState::TokenReceived);
+        responder.permanent_key = Some(peer_permanent_pk);
+        ctx.signaling.responders.insert(Address(3), responder);
+
+        // Handle key message, the initiator replies with its own key
+        let msg: Message = Key::new(PublicKey::random()).into_message();
+        let bbox = TestMsgBuilder::new(msg).from(3).to(1)
+            .build(Cookie::random(), &ctx.our_ks, &peer_permanent_pk);
+        let mut actions = ctx.signaling.handle_message(bbox).unwrap().into_vec();
+        let key_nonce = match actions.remove(0) {
+            HandleAction::Reply(bbox) => bbox.nonce,
+            other => panic!("Unexpected action: {:?}", other),
+        };
+
+        // The server cannot relay the key message
+        let send_error = |id: SendErrorId| {
+            TestMsgBuilder::new(Message::SendError(SendError::new(id))).from(0).to(1)
+                .build(ctx.server_cookie.clone(), &ctx.server_ks, ctx.our_ks.public_key())
+        };
+        let bbox = send_error(SendErrorId::from(&key_nonce));
+        let err = ctx.signaling.handle_message(bbox).unwrap_err();
+        assert_eq!(err, SignalingError::SendError(FailedMessage {
+            destination: 3,
+            message_type: Some("key".into()),
+        }));
+        assert_eq!(err.to_string(), "Server could not relay 'key' message to responder 0x03");
+        assert_eq!(err.close_code(), None);
+    }
+
+    /// A send-error for a message to the server itself is fatal.
+    #[test]
+    fn send_error_for_server_message() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+        );
+        let id = SendErrorId::from_slice(&[1, 0, 0, 0, 0, 0, 0, 1]).unwrap();
+        let bbox = TestMsgBuilder::new(Message::SendError(SendError::new(id))).from(0).to(1)
+            .build(ctx.server_cookie.clone(), &ctx.server_ks, ctx.our_ks.public_key());
+        let err = ctx.signaling.handle_message(bbox).unwrap_err();
+        assert_eq!(err, SignalingError::ServerSendError);
+        assert_eq!(err.close_code(), Some(CloseCode::ProtocolError));
+    }
 }
 
 mod disconnected {
@@ -1338,8 +2480,8 @@ mod disconnected {
 
         // Handle message
         let err = ctx.signaling.handle_message(bbox).unwrap_err();
-        let msg = "Got \'disconnected\' message from server in ClientInfoSent state".into();
-        assert_eq!(err, SignalingError::InvalidStateTransition(msg))
+        let msg = "Unexpected \'disconnected\' message (expected \'server-auth\')".into();
+        assert_eq!(err, SignalingError::Protocol(msg))
     }
 
     /// An initiator who receives a 'disconnected' message SHALL validate
@@ -1430,6 +2572,189 @@ mod disconnected {
         assert_eq!(actions.len(), 1);
         assert_eq!(actions[0], HandleAction::Event(Event::Disconnected(7)));
     }
+
+    /// The 'disconnected' message was introduced in protocol version 1.1,
+    /// so a client speaking version 1.0 must reject it.
+    #[test]
+    fn disconnected_protocol_v1_0() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+        );
+        ctx.signaling.common_mut().protocol_version = ProtocolVersion::V1_0;
+
+        // Encrypt message
+        let msg = Message::Disconnected(Disconnected::new(ClientIdentity::Responder(7).into()));
+        let bbox = TestMsgBuilder::new(msg).from(0).to(1)
+            .build(ctx.server_cookie.clone(),
+                   &ctx.server_ks,
+                   ctx.our_ks.public_key());
+
+        // Handle message
+        let err = ctx.signaling.handle_message(bbox).unwrap_err();
+        let msg = "Got \'disconnected\' message, but it is not part of protocol version 1.0".into();
+        assert_eq!(err, SignalingError::Protocol(msg))
+    }
+}
+
+mod unexpected_messages {
+    use super::*;
+
+    /// A 'server-hello' after the server handshake is rejected before it is
+    /// decoded.
+    #[test]
+    fn server_hello_during_task() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::Task, ServerHandshakeState::Done,
+        );
+        let msg = ServerHello::new(PublicKey::random()).into_message();
+        let bbox = TestMsgBuilder::new(msg).from(0).to(1).build_from_server(&ctx);
+
+        let err = ctx.signaling.handle_message(bbox).unwrap_err();
+        assert_eq!(err, SignalingError::Protocol(
+            "Unexpected 'server-hello' message (expected 'new-initiator' or 'new-responder' \
+             or 'send-error' or 'disconnected')".into()
+        ));
+        assert_eq!(err.close_code(), Some(CloseCode::ProtocolError));
+        assert!(ctx.signaling.server().session_key.is_some());
+    }
+
+    /// Build a message of an unknown type from the server.
+    fn unknown_server_message<S: Signaling>(ctx: &TestContext<S>) -> ByteBox {
+        let value = Value::Map(vec![(Value::from("type"), Value::from("future-message"))]);
+        let nonce = Nonce::new(ctx.server_cookie.clone(), Address(0), Address(1), CombinedSequenceSnapshot::random());
+        OpenBox::<Value>::new(value, nonce).encrypt(&ctx.server_ks, ctx.our_ks.public_key(), false)
+    }
+
+    /// A message of an unknown type from the server is ignored once the
+    /// server handshake is done.
+    #[test]
+    fn unknown_server_message_during_task() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::Task, ServerHandshakeState::Done,
+        );
+        let bbox = unknown_server_message(&ctx);
+        let actions = ctx.signaling.handle_message(bbox).unwrap();
+        assert_eq!(actions.into_vec(), vec![
+            HandleAction::Event(Event::UnknownServerMessage("future-message".into())),
+        ]);
+        assert_eq!(ctx.signaling.common().signaling_state(), SignalingState::Task);
+    }
+
+    /// During the server handshake, a message of an unknown type from the
+    /// server is a protocol error.
+    #[test]
+    fn unknown_server_message_during_server_handshake() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Unknown, None,
+            SignalingState::ServerHandshake, ServerHandshakeState::ClientInfoSent,
+        );
+        let bbox = unknown_server_message(&ctx);
+        let err = ctx.signaling.handle_message(bbox).unwrap_err();
+        assert_eq!(err, SignalingError::UnknownMessageType("future-message".into()));
+        assert_eq!(err.close_code(), Some(CloseCode::ProtocolError));
+    }
+
+    /// Messages from the server are rate limited until the server
+    /// handshake is done.
+    #[test]
+    fn server_exceeds_rate_limit() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Unknown, None,
+            SignalingState::ServerHandshake, ServerHandshakeState::New,
+        );
+        ctx.signaling.common_mut().server_rate_limit = Some(RateLimit::new(1, 0));
+        ctx.signaling.common_mut().server.session_key = None;
+        let msg = ServerHello::new(*ctx.server_ks.public_key()).into_message();
+        let nonce = Nonce::new(ctx.server_cookie.clone(), Address(0), Address(0), CombinedSequenceSnapshot::random());
+        let actions = ctx.signaling.handle_message(OpenBox::<Message>::new(msg, nonce).encode()).unwrap();
+        assert_eq!(actions.into_vec().len(), 1); // Reply with client-auth
+        assert_eq!(ctx.signaling.server().handshake_state(), ServerHandshakeState::ClientInfoSent);
+
+        let nonce = Nonce::new(ctx.server_cookie.clone(), Address(0), Address(1), CombinedSequenceSnapshot::random());
+        let err = ctx.signaling.handle_message(ByteBox::new(vec![0; 40], nonce)).unwrap_err();
+        assert_eq!(err, SignalingError::Protocol("Server exceeded the message rate limit".into()));
+    }
+
+    /// A failed state transition is reported along with the states and the
+    /// nonce of the message.
+    #[test]
+    fn unexpected_peer_message_context() {
+        let mut ctx = TestContext::responder(
+            ClientIdentity::Responder(6),
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+            Some(PublicKey::random()), None,
+        );
+        ctx.signaling.initiator.set_handshake_state(InitiatorHandshakeState::KeySent);
+        let cookie = Cookie::new([0xab, 0xcd, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16]);
+        let nonce = Nonce::new(cookie, Address(1), Address(6), CombinedSequenceSnapshot::random());
+        let obox = OpenBox::<Message>::new(Token::new(PublicKey::random()).into_message(), nonce);
+
+        // The type of the message is usually checked before the state
+        // transition, so the transition is driven directly
+        match ctx.signaling.handle_peer_message(obox).unwrap_err() {
+            SignalingError::UnexpectedMessage(context) => {
+                assert_eq!(context.signaling_state, "PeerHandshake");
+                assert_eq!(context.handshake_state, "KeySent");
+                assert_eq!(context.message_type, "token");
+                assert_eq!((context.source, context.destination), (1, 6));
+                assert_eq!(context.cookie_fingerprint, "abcd");
+            },
+            other => panic!("Unexpected error: {:?}", other),
+        }
+    }
+
+    /// The server never sends 'drop-responder' messages to clients.
+    #[test]
+    fn drop_responder_from_server() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+        );
+        let msg = DropResponder::with_reason(Address(3), DropReason::DroppedByInitiator).into_message();
+        let bbox = TestMsgBuilder::new(msg).from(0).to(1).build_from_server(&ctx);
+
+        let err = ctx.signaling.handle_message(bbox).unwrap_err();
+        assert_eq!(err.close_code(), Some(CloseCode::ProtocolError));
+    }
+
+    /// Messages from the initiator are rejected during the server handshake.
+    #[test]
+    fn token_during_server_handshake() {
+        let initiator_pk = PublicKey::random();
+        let mut ctx = TestContext::responder(
+            ClientIdentity::Responder(6),
+            SignalingState::ServerHandshake, ServerHandshakeState::ClientInfoSent,
+            Some(initiator_pk), None,
+        );
+        let msg = Token::new(PublicKey::random()).into_message();
+        let bbox = TestMsgBuilder::new(msg).from(1).to(6).build(Cookie::random(), &ctx.our_ks, &initiator_pk);
+
+        let err = ctx.signaling.handle_message(bbox).unwrap_err();
+        assert_eq!(err, SignalingError::Protocol("Got message from 0x01 during the server handshake".into()));
+        assert_eq!(ctx.signaling.initiator.handshake_state(), InitiatorHandshakeState::New);
+    }
+
+    /// A peer message that is not valid in the peer handshake state is
+    /// rejected before it is decoded.
+    #[test]
+    fn token_instead_of_key() {
+        let initiator_pk = PublicKey::random();
+        let mut ctx = TestContext::responder(
+            ClientIdentity::Responder(6),
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+            Some(initiator_pk), None,
+        );
+        ctx.signaling.initiator.set_handshake_state(InitiatorHandshakeState::KeySent);
+        let msg = Token::new(PublicKey::random()).into_message();
+        let bbox = TestMsgBuilder::new(msg).from(1).to(6).build(Cookie::random(), &ctx.our_ks, &initiator_pk);
+
+        let err = ctx.signaling.handle_message(bbox).unwrap_err();
+        assert_eq!(err, SignalingError::Protocol("Unexpected 'token' message (expected 'key')".into()));
+        assert_eq!(ctx.signaling.initiator.session_key, None);
+    }
 }
 
 mod regressions {
@@ -1459,3 +2784,568 @@ mod regressions {
         assert_eq!(actions.len(), 0);
     }
 }
+
+mod state_summary {
+    use super::*;
+
+    #[test]
+    fn initiator_lists_responders() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+        );
+        let mut responder = ResponderContext::new(Address(4), 1);
+        responder.set_handshake_state(ResponderHandshakeState::TokenReceived);
+        ctx.signaling.responders.insert(Address(4), responder);
+        ctx.signaling.responders.insert(Address(3), ResponderContext::new(Address(3), 0));
+
+        let summary = ctx.signaling.state_summary();
+        assert_eq!(summary.role, "Initiator");
+        assert_eq!(summary.identity, "initiator");
+        assert_eq!(summary.signaling_state, "PeerHandshake");
+        assert_eq!(summary.server_handshake_state, "Done");
+        assert_eq!(summary.task, None);
+        let states: Vec<(u8, &str, bool)> = summary.peers.iter()
+            .map(|peer| (peer.address, peer.handshake_state.as_str(), peer.authenticated))
+            .collect();
+        assert_eq!(states, vec![(3, "New", false), (4, "TokenReceived", false)]);
+        assert_eq!(summary.peers[0].incoming_csn, None);
+    }
+
+    #[test]
+    fn responder_lists_initiator() {
+        let ctx = TestContext::responder(
+            ClientIdentity::Responder(7),
+            SignalingState::ServerHandshake, ServerHandshakeState::ClientInfoSent,
+            None, None,
+        );
+        let summary = ctx.signaling.state_summary();
+        assert_eq!(summary.role, "Responder");
+        assert_eq!(summary.identity, "responder 0x07");
+        assert_eq!(summary.server_handshake_state, "ClientInfoSent");
+        assert_eq!(summary.peers.len(), 1);
+        assert_eq!(summary.peers[0].address, 1);
+        assert_eq!(summary.peers[0].handshake_state, "New");
+        assert!(!summary.peers[0].authenticated);
+    }
+}
+
+mod peer_payloads {
+    use crate::clock::ManualClock;
+
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let (mut initiator, mut responder) = connected_peers();
+
+        let first = initiator.signaling.encrypt_for_peer(b"hello").unwrap();
+        let second = initiator.signaling.encrypt_for_peer(b"").unwrap();
+        assert_eq!(first.nonce.source(), Address(1));
+        assert_eq!(first.nonce.destination(), Address(3));
+        assert!(second.nonce.csn() > first.nonce.csn());
+
+        assert_eq!(responder.signaling.decrypt_from_peer(first), Ok(b"hello".to_vec()));
+        assert_eq!(responder.signaling.decrypt_from_peer(second), Ok(vec![]));
+
+        // And the other way around
+        let reply = responder.signaling.encrypt_for_peer(&[1, 2, 3]).unwrap();
+        assert_eq!(initiator.signaling.decrypt_from_peer(reply), Ok(vec![1, 2, 3]));
+    }
+
+    /// Payloads and task messages have their own sequence numbers, so they
+    /// can overtake each other. Their nonces use different cookies and never
+    /// collide.
+    #[test]
+    fn interleaved_with_task_messages() {
+        let (initiator, mut responder) = connected_peers();
+        responder.signaling.common_mut().task_supported_types = Some(&["dummy"]);
+        let message = || Value::Map(vec![(Value::from("type"), Value::from("dummy"))]);
+
+        let first = initiator.signaling.encrypt_for_peer(b"first").unwrap();
+        let msg1 = initiator.signaling.encode_task_message(message()).unwrap();
+        let second = initiator.signaling.encrypt_for_peer(b"second").unwrap();
+        let msg2 = initiator.signaling.encode_task_message(message()).unwrap();
+        assert_ne!(first.nonce.cookie(), msg1.nonce.cookie());
+        assert_eq!(msg2.nonce.csn().combined_sequence_number(), msg1.nonce.csn().combined_sequence_number() + 1);
+
+        assert_eq!(responder.signaling.handle_message(msg1).unwrap().into_vec().len(), 1);
+        assert_eq!(responder.signaling.handle_message(msg2).unwrap().into_vec().len(), 1);
+        assert_eq!(responder.signaling.decrypt_from_peer(first), Ok(b"first".to_vec()));
+        let third = initiator.signaling.encrypt_for_peer(b"third").unwrap();
+        let msg3 = initiator.signaling.encode_task_message(message()).unwrap();
+        assert_eq!(responder.signaling.decrypt_from_peer(second), Ok(b"second".to_vec()));
+        assert_eq!(responder.signaling.handle_message(msg3).unwrap().into_vec().len(), 1);
+        assert_eq!(responder.signaling.decrypt_from_peer(third), Ok(b"third".to_vec()));
+
+        // A payload is not accepted as task message
+        let payload = initiator.signaling.encrypt_for_peer(b"payload").unwrap();
+        assert!(responder.signaling.handle_message(payload).is_err());
+    }
+
+    /// Replayed payloads are rejected.
+    #[test]
+    fn replay() {
+        let (initiator, mut responder) = connected_peers();
+        let bytes = initiator.signaling.encrypt_for_peer(b"once").unwrap().into_bytes();
+        assert!(responder.signaling.decrypt_from_peer(ByteBox::from_slice(&bytes).unwrap()).is_ok());
+        assert_eq!(
+            responder.signaling.decrypt_from_peer(ByteBox::from_slice(&bytes).unwrap()),
+            Err(SignalingError::InvalidNonce("The initiator CSN hasn't been incremented".into()))
+        );
+    }
+
+    /// A payload that does not decrypt leaves the sequence numbers alone.
+    #[test]
+    fn forged_payload() {
+        let (initiator, mut responder) = connected_peers();
+        let genuine = initiator.signaling.encrypt_for_peer(b"genuine").unwrap();
+        let mut forged = genuine.to_bytes();
+        let last = forged.len() - 1;
+        forged[last] ^= 1;
+        match responder.signaling.decrypt_from_peer(ByteBox::from_slice(&forged).unwrap()) {
+            Err(SignalingError::Crypto(_)) => {},
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert_eq!(responder.signaling.decrypt_from_peer(genuine), Ok(b"genuine".to_vec()));
+    }
+
+    /// Payloads that arrive early are held back until the preceding ones
+    /// arrived, or until the reorder window is full.
+    #[test]
+    fn ordered() {
+        let (initiator, mut responder) = connected_peers();
+        responder.signaling.common_mut().reorder = ReorderBuffer::new(1, None);
+        let bboxes: Vec<Vec<u8>> = (0..6)
+            .map(|i| initiator.signaling.encrypt_for_peer(&[i]).unwrap().into_bytes())
+            .collect();
+        let mut decrypt = |i: usize| responder.signaling
+            .decrypt_from_peer_ordered(ByteBox::from_slice(&bboxes[i]).unwrap())
+            .map(|ordered| {
+                let payloads: Vec<u8> = ordered.payloads.into_iter().map(|payload| payload[0]).collect();
+                (payloads, ordered.gaps)
+            });
+
+        assert_eq!(decrypt(0), Ok((vec![0], vec![])));
+        assert_eq!(decrypt(2), Ok((vec![], vec![])));
+        assert_eq!(decrypt(1), Ok((vec![1, 2], vec![])));
+        assert!(decrypt(2).is_err());
+
+        // The payload at index 3 did not arrive in time
+        let csn = ByteBox::from_slice(&bboxes[3]).unwrap().nonce.csn().combined_sequence_number();
+        let gap = csn..csn + 1;
+        assert_eq!(decrypt(4), Ok((vec![], vec![])));
+        assert_eq!(decrypt(5), Ok((vec![4, 5], vec![gap])));
+        assert!(decrypt(3).is_err());
+    }
+
+    /// The first payload that arrives is never held back, because the
+    /// initial sequence number of the peer is not known.
+    #[test]
+    fn ordered_first_payload() {
+        let (initiator, mut responder) = connected_peers();
+        let first = initiator.signaling.encrypt_for_peer(&[0]).unwrap();
+        let second = initiator.signaling.encrypt_for_peer(&[1]).unwrap();
+
+        let ordered = responder.signaling.decrypt_from_peer_ordered(second).unwrap();
+        assert_eq!(ordered, Ordered { payloads: vec![vec![1]], gaps: vec![] });
+        assert!(responder.signaling.decrypt_from_peer_ordered(first).is_err());
+    }
+
+    /// Payloads that waited for longer than the reorder timeout are
+    /// released without waiting for the next payload.
+    #[test]
+    fn ordered_timeout() {
+        let (initiator, mut responder) = connected_peers();
+        let clock = Arc::new(ManualClock::new());
+        responder.signaling.common_mut().clock = clock.clone();
+        responder.signaling.common_mut().reorder = ReorderBuffer::new(4, Some(Duration::from_secs(1)));
+        let bboxes: Vec<Vec<u8>> = (0..3)
+            .map(|i| initiator.signaling.encrypt_for_peer(&[i]).unwrap().into_bytes())
+            .collect();
+        let decrypt = |responder: &mut TestContext<ResponderSignaling>, i: usize| responder.signaling
+            .decrypt_from_peer_ordered(ByteBox::from_slice(&bboxes[i]).unwrap());
+
+        assert_eq!(decrypt(&mut responder, 0).unwrap().payloads, vec![vec![0]]);
+        assert_eq!(decrypt(&mut responder, 2), Ok(Ordered::default()));
+        assert_eq!(responder.signaling.release_reordered(), Ok(Ordered::default()));
+        let deadline = responder.signaling.common().reorder.deadline();
+        assert_eq!(deadline, Some(clock.now() + Duration::from_secs(1)));
+
+        clock.advance(Duration::from_secs(1));
+        let csn = ByteBox::from_slice(&bboxes[1]).unwrap().nonce.csn().combined_sequence_number();
+        let gap = csn..csn + 1;
+        let released = responder.signaling.release_reordered().unwrap();
+        assert_eq!(released, Ordered { payloads: vec![vec![2]], gaps: vec![gap] });
+        assert_eq!(responder.signaling.common().reorder.deadline(), None);
+        assert!(decrypt(&mut responder, 1).is_err());
+    }
+
+    #[test]
+    fn before_handshake() {
+        let mut ctx = TestContext::initiator(
+            ClientIdentity::Initiator, None,
+            SignalingState::PeerHandshake, ServerHandshakeState::Done,
+        );
+        assert_eq!(ctx.signaling.encrypt_for_peer(b"data").unwrap_err(), SignalingError::NoPeer);
+        let nonce = Nonce::new(Cookie::random(), Address(3), Address(1), CombinedSequenceSnapshot::random());
+        assert_eq!(
+            ctx.signaling.decrypt_from_peer(ByteBox::new(vec![0; 20], nonce)),
+            Err(SignalingError::NoPeer)
+        );
+    }
+}
+
+mod task_messages {
+    use super::*;
+
+    fn message(msg_type: &str) -> Value {
+        Value::Map(vec![
+            (Value::from("type"), Value::from(msg_type)),
+            (Value::from("id"), Value::from(7)),
+        ])
+    }
+
+    /// Heartbeats are answered by the signaling.
+    #[test]
+    fn heartbeat() {
+        let (initiator, mut responder) = connected_peers();
+        responder.signaling.common_mut().task_supported_types = Some(&["dummy"]);
+        let bbox = initiator.signaling.encode_task_message(message("heartbeat")).unwrap();
+        let (replies, actions) = responder.signaling.handle_message(bbox).unwrap().split_replies();
+        assert_eq!(replies.len(), 1);
+        assert!(actions.is_empty());
+    }
+
+    /// Heartbeats are passed to a task that claims them.
+    #[test]
+    fn heartbeat_claimed_by_task() {
+        let (initiator, mut responder) = connected_peers();
+        responder.signaling.common_mut().task_supported_types = Some(&["heartbeat", "heartbeat-ack"]);
+        for msg_type in &["heartbeat", "heartbeat-ack"] {
+            let bbox = initiator.signaling.encode_task_message(message(msg_type)).unwrap();
+            let actions = responder.signaling.handle_message(bbox).unwrap().into_vec();
+            assert_eq!(actions.len(), 1);
+            match actions[0] {
+                HandleAction::TaskMessage(TaskMessage::Value(ref map)) => {
+                    assert_eq!(map.get("type"), Some(&Value::from(*msg_type)));
+                },
+                ref other => panic!("Expected task message, got {:?}", other),
+            }
+        }
+    }
+
+    /// Task messages of a type that the task claims are passed to the task,
+    /// other unknown types are a protocol error.
+    #[test]
+    fn unknown_task_message() {
+        let (mut initiator, mut responder) = create_peers(0x5a17, false);
+        handshake(&mut TestServer::new(1), &mut initiator, &mut responder, false, false);
+        let message = |msg_type: &str| Value::Map(vec![(Value::from("type"), Value::from(msg_type))]);
+
+        let bbox = initiator.encode_task_message(message("dummy")).unwrap();
+        let actions = responder.handle_message(bbox).unwrap().into_vec();
+        assert_eq!(actions.len(), 1);
+        match actions[0] {
+            HandleAction::TaskMessage(TaskMessage::Value(ref map)) => assert_eq!(map.get("type"), Some(&Value::from("dummy"))),
+            ref other => panic!("Expected task message, got {:?}", other),
+        }
+
+        let bbox = initiator.encode_task_message(message("unknown")).unwrap();
+        let err = responder.handle_message(bbox).unwrap_err();
+        assert_eq!(err, SignalingError::UnknownMessageType("unknown".into()));
+        assert_eq!(err.close_code(), Some(CloseCode::ProtocolError));
+    }
+}
+
+mod observer_hooks {
+    use std::sync::{Arc, Mutex};
+
+    use crate::observer::{Decoded, Frame, Observer, Verdict};
+
+    use super::*;
+
+    /// What the test observer has seen, and which messages it drops.
+    #[derive(Default)]
+    struct ObserverLog {
+        states: Vec<(SignalingState, SignalingState)>,
+        incoming: usize,
+        outgoing: usize,
+        drop_incoming: bool,
+        drop_type: Option<&'static str>,
+    }
+
+    struct TestObserver(Arc<Mutex<ObserverLog>>);
+
+    impl Observer for TestObserver {
+        fn on_incoming(&mut self, _frame: &Frame) -> Verdict {
+            let mut log = self.0.lock().unwrap();
+            log.incoming += 1;
+            if log.drop_incoming { Verdict::Drop } else { Verdict::Pass }
+        }
+
+        fn on_decrypted(&mut self, message: &Decoded) -> Verdict {
+            match self.0.lock().unwrap().drop_type {
+                Some(message_type) if message.message_type == Some(message_type) => Verdict::Drop,
+                _ => Verdict::Annotate(format!("{:?}", message.message_type)),
+            }
+        }
+
+        fn on_outgoing(&mut self, _frame: &Frame) -> Verdict {
+            self.0.lock().unwrap().outgoing += 1;
+            Verdict::Pass
+        }
+
+        fn on_state_change(&mut self, old: SignalingState, new: SignalingState) {
+            self.0.lock().unwrap().states.push((old, new));
+        }
+    }
+
+    /// An observer sees every frame and state change, and messages it drops
+    /// are ignored before their nonce is validated.
+    #[test]
+    fn observer() {
+        let log = Arc::new(Mutex::new(ObserverLog::default()));
+        let (mut initiator, mut responder) = create_peers(0x5a17, true);
+        initiator.common_mut().observer = Some(Box::new(TestObserver(log.clone())));
+        handshake(&mut TestServer::new(0x5a17), &mut initiator, &mut responder, true, false);
+        {
+            let log = log.lock().unwrap();
+            assert_eq!(log.states, vec![
+                (SignalingState::ServerHandshake, SignalingState::PeerHandshake),
+                (SignalingState::PeerHandshake, SignalingState::Task),
+            ]);
+            assert_eq!((log.incoming, log.outgoing), (6, 3));
+        }
+
+        let application = |data: u8| Value::Map(vec![
+            (Value::from("type"), Value::from("application")),
+            (Value::from("data"), Value::from(data)),
+        ]);
+        let bytes = responder.encode_task_message(application(1)).unwrap().into_bytes();
+
+        // A message dropped on arrival can still be processed later
+        log.lock().unwrap().drop_incoming = true;
+        assert_eq!(initiator.handle_message(ByteBox::from_vec(bytes.clone()).unwrap()).unwrap().into_iter().count(), 0);
+        log.lock().unwrap().drop_incoming = false;
+        assert_eq!(
+            initiator.handle_message(ByteBox::from_vec(bytes).unwrap()).unwrap().into_iter().collect::<Vec<_>>(),
+            vec![HandleAction::TaskMessage(TaskMessage::Application(Value::from(1)))],
+        );
+
+        // A message dropped after decryption is consumed
+        log.lock().unwrap().drop_type = Some("application");
+        let bbox = responder.encode_task_message(application(2)).unwrap();
+        assert_eq!(initiator.handle_message(bbox).unwrap().into_iter().count(), 0);
+        let bbox = responder.encode_task_message(Value::Map(vec![
+            (Value::from("type"), Value::from("close")),
+            (Value::from("reason"), Value::from(3000)),
+        ])).unwrap();
+        assert_ne!(initiator.handle_message(bbox).unwrap().into_iter().count(), 0);
+        assert_eq!(log.lock().unwrap().incoming, 10);
+    }
+}
+
+mod worker_pool {
+    use std::thread;
+
+    use crate::protocol::decrypt::{DecryptJob, DecryptedMessage, PreparedMessage};
+    use crate::transcript::MemoryTranscript;
+
+    use super::*;
+
+    /// Task messages decrypted out of order on other threads are still handled
+    /// in the order they were prepared.
+    #[test]
+    fn decrypt_task_messages_on_threads() {
+        let recorder = MemoryTranscript::new();
+        let (mut initiator, mut responder) = create_peers(0x5a17, true);
+        handshake(&mut TestServer::new(0x5a17), &mut initiator, &mut responder, true, false);
+        responder.common_mut().transcript = Some(Box::new(recorder.clone()));
+
+        let messages: Vec<Vec<u8>> = (0..3u8)
+            .map(|i| initiator.encode_task_message(Value::Map(vec![
+                (Value::from("type"), Value::from("application")),
+                (Value::from("data"), Value::from(i)),
+            ])).unwrap().into_bytes())
+            .collect();
+        let first = messages[0].clone();
+
+        let jobs: Vec<DecryptJob> = messages.into_iter()
+            .map(|bytes| match responder.prepare_message(ByteBox::from_vec(bytes).unwrap()).unwrap() {
+                PreparedMessage::Decrypt(job) => job,
+                other => panic!("Unexpected prepared message: {:?}", other),
+            })
+            .collect();
+
+        // A replayed message is rejected before decryption
+        match responder.prepare_message(ByteBox::from_vec(first).unwrap()) {
+            Err(SignalingError::InvalidNonce(_)) => {},
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        let handles: Vec<_> = jobs.into_iter().rev()
+            .map(|job| thread::spawn(move || job.run()))
+            .collect();
+        let mut decrypted: Vec<DecryptedMessage> = handles.into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect();
+        decrypted.reverse();
+
+        for (i, msg) in decrypted.into_iter().enumerate() {
+            let actions = responder.handle_decrypted(msg).unwrap();
+            assert_eq!(
+                actions.into_iter().collect::<Vec<_>>(),
+                vec![HandleAction::TaskMessage(TaskMessage::Application(Value::from(i)))],
+            );
+        }
+        let entries = recorder.entries();
+        assert_eq!(entries.len(), 4);
+        assert!(entries[0].plaintext.is_none());
+        assert!(entries[1..].iter().all(|entry| entry.plaintext.is_some()));
+    }
+}
+
+mod statistics {
+    use super::*;
+
+    /// The statistics count the handshake and task messages, whether or not a
+    /// transcript recorder is registered.
+    #[test]
+    fn stats() {
+        let (mut initiator, mut responder) = create_peers(0x5a17, true);
+        let transcript = handshake(&mut TestServer::new(0x5a17), &mut initiator, &mut responder, true, false);
+
+        let bytes = |direction: &str| transcript.iter()
+            .filter(|(label, _)| label.contains(direction))
+            .map(|(_, bytes)| bytes.len() as u64)
+            .sum::<u64>();
+        let (bytes_out, bytes_in) = (bytes("initiator ->"), bytes("-> initiator"));
+        let stats = initiator.common().stats.snapshot(Instant::now(), None);
+        assert_eq!((stats.bytes_out, stats.bytes_in), (bytes_out, bytes_in));
+        assert_eq!((stats.messages_out, stats.messages_in), (3, 6));
+        let types: Vec<&str> = stats.message_types_in.keys().map(String::as_str).collect();
+        assert_eq!(types, vec!["auth", "key", "new-responder", "server-auth", "server-hello", "token"]);
+        let types: Vec<&str> = stats.message_types_out.keys().map(String::as_str).collect();
+        assert_eq!(types, vec!["auth", "client-auth", "key"]);
+        assert_eq!(stats.decryption_failures, 0);
+        let timings = initiator.common().stats.timings();
+        assert!(timings.server_handshake.is_some() && timings.peer_handshake.is_some());
+        assert_eq!((timings.ws_connect, timings.task_init), (None, None));
+
+        let bbox = responder.encode_task_message(Value::Map(vec![
+            (Value::from("type"), Value::from("application")),
+            (Value::from("data"), Value::from(1)),
+        ])).unwrap();
+        assert!(replies(initiator.handle_message(bbox).unwrap()).is_empty());
+        let stats = initiator.common().stats.snapshot(Instant::now(), None);
+        assert_eq!(stats.messages_in, 7);
+        assert_eq!(stats.message_types_in.get("application"), Some(&1));
+
+        let mut bytes = responder.encode_task_message(Value::Nil).unwrap().into_bytes();
+        *bytes.last_mut().unwrap() ^= 1;
+        assert!(initiator.handle_message(ByteBox::from_vec(bytes).unwrap()).is_err());
+        assert_eq!(initiator.common().stats.snapshot(Instant::now(), None).decryption_failures, 1);
+    }
+}
+
+mod key_rotation {
+    use super::*;
+
+    /// Create an application message with the specified data.
+    fn application(data: i64) -> Value {
+        Value::Map(vec![
+            (Value::from("type"), Value::from("application")),
+            (Value::from("data"), Value::from(data)),
+        ])
+    }
+
+    /// Assert that the actions contain the application message with `data`.
+    fn assert_application(actions: HandleActions, data: i64) {
+        let actions = actions.into_vec();
+        assert!(actions.contains(&HandleAction::TaskMessage(TaskMessage::Application(Value::from(data)))), "{:?}", actions);
+    }
+
+    /// Handle a rekey message, returning the replies after asserting that the
+    /// keys were rotated.
+    fn assert_rekeyed(actions: HandleActions) -> Vec<ByteBox> {
+        let (replies, actions) = actions.split_replies();
+        assert_eq!(actions, vec![HandleAction::Event(Event::Rekeyed)]);
+        replies
+    }
+
+    /// The peer that is asked to rotate the keys answers and switches right
+    /// away, while messages encrypted with the old keys are still accepted.
+    #[test]
+    fn rekey() {
+        let (mut initiator, mut responder) = create_peers(0x5a17, true);
+        handshake(&mut TestServer::new(1), &mut initiator, &mut responder, true, false);
+        initiator.common_mut().rekeying = true;
+        responder.common_mut().rekeying = true;
+        let session_key = *initiator.responder.as_ref().unwrap().keypair.public_key();
+
+        let request = initiator.start_rekey().unwrap();
+        assert_eq!(initiator.start_rekey().err(), Some(SignalingError::Rekey("Rekeying is already in progress".into())));
+        let in_flight = initiator.encode_task_message(application(1)).unwrap();
+        let mut out = assert_rekeyed(responder.handle_message(request).unwrap());
+        assert_eq!(out.len(), 1);
+
+        // The answer and the messages before the switch use the old keys
+        let in_flight_answer = responder.encode_task_message(application(2)).unwrap();
+        assert_application(responder.handle_message(in_flight).unwrap(), 1);
+        assert!(assert_rekeyed(initiator.handle_message(out.remove(0)).unwrap()).is_empty());
+        assert_application(initiator.handle_message(in_flight_answer).unwrap(), 2);
+        assert_ne!(initiator.responder.as_ref().unwrap().keypair.public_key(), &session_key);
+        assert_eq!(
+            initiator.responder.as_ref().unwrap().session_key.as_ref(),
+            Some(responder.initiator.keypair.public_key()),
+        );
+        assert_eq!(
+            responder.initiator.session_key.as_ref(),
+            Some(initiator.responder.as_ref().unwrap().keypair.public_key()),
+        );
+
+        // Once the new keys arrived, the old keys are forgotten
+        let bbox = initiator.encode_task_message(application(3)).unwrap();
+        assert_application(responder.handle_message(bbox).unwrap(), 3);
+        assert!(responder.common().rekey.previous().is_none());
+        let bbox = responder.encode_task_message(application(4)).unwrap();
+        assert_application(initiator.handle_message(bbox).unwrap(), 4);
+        assert!(initiator.common().rekey.previous().is_none());
+    }
+
+    /// If both peers start rotating the keys at the same time, neither answers.
+    #[test]
+    fn rekey_crossing() {
+        let (mut initiator, mut responder) = create_peers(0x5a17, false);
+        handshake(&mut TestServer::new(1), &mut initiator, &mut responder, false, false);
+        initiator.common_mut().rekeying = true;
+        responder.common_mut().rekeying = true;
+
+        let initiator_request = initiator.start_rekey().unwrap();
+        let responder_request = responder.start_rekey().unwrap();
+        assert!(assert_rekeyed(responder.handle_message(initiator_request).unwrap()).is_empty());
+        assert!(assert_rekeyed(initiator.handle_message(responder_request).unwrap()).is_empty());
+
+        let bbox = initiator.encode_task_message(application(1)).unwrap();
+        assert_application(responder.handle_message(bbox).unwrap(), 1);
+        let bbox = responder.encode_task_message(application(2)).unwrap();
+        assert_application(initiator.handle_message(bbox).unwrap(), 2);
+    }
+
+    /// Without rekeying enabled, no rotation can be started and rekey messages
+    /// of the peer are ignored.
+    #[test]
+    fn rekey_disabled() {
+        let (mut initiator, mut responder) = create_peers(0x5a17, true);
+        assert_eq!(initiator.start_rekey().err(), Some(SignalingError::Rekey("Rekeying is not enabled".into())));
+        initiator.common_mut().rekeying = true;
+        assert_eq!(initiator.start_rekey().err(), Some(SignalingError::NoPeer));
+
+        handshake(&mut TestServer::new(1), &mut initiator, &mut responder, true, false);
+        let session_key = responder.initiator.session_key;
+        let request = initiator.start_rekey().unwrap();
+        assert!(responder.handle_message(request).unwrap().into_vec().is_empty());
+        assert_eq!(responder.initiator.session_key, session_key);
+    }
+}
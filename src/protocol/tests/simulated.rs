@@ -0,0 +1,246 @@
+//! A simulated server, and helpers to run complete handshakes through it.
+use crate::test_helpers::DummyTask;
+
+use super::*;
+use super::cookie::Cookie;
+use super::csn::CombinedSequence;
+use super::messages::*;
+use super::random::SeededRandom;
+
+pub(crate) const INITIATOR_ADDR: u8 = 0x01;
+pub(crate) const RESPONDER_ADDR: u8 = 0x02;
+
+/// The server side of a single client connection.
+pub(crate) struct ServerPath {
+    pub(crate) address: Address,
+    pub(crate) client_key: PublicKey,
+    pub(crate) cookie: Cookie,
+    pub(crate) csn: CombinedSequence,
+    pub(crate) client_cookie: Option<Cookie>,
+}
+
+/// A minimal server that performs the server handshake with its clients.
+pub(crate) struct TestServer {
+    keypair: KeyPair,
+    rng: SeededRandom,
+}
+
+impl TestServer {
+    pub(crate) fn new(seed: u64) -> Self {
+        let mut rng = SeededRandom::new(seed);
+        let keypair = KeyPair::from_random_source(&mut rng);
+        TestServer { keypair, rng }
+    }
+
+    pub(crate) fn path(&mut self, address: u8, client_key: PublicKey) -> ServerPath {
+        ServerPath {
+            address: Address(address),
+            client_key,
+            cookie: Cookie::random_from(&mut self.rng),
+            csn: CombinedSequence::random_from(&mut self.rng),
+            client_cookie: None,
+        }
+    }
+
+    pub(crate) fn nonce(&self, path: &mut ServerPath, destination: Address) -> Nonce {
+        let csn = path.csn.increment().unwrap();
+        Nonce::new(path.cookie.clone(), Address(0), destination, csn)
+    }
+
+    pub(crate) fn server_hello(&self, path: &mut ServerPath) -> ByteBox {
+        let msg = ServerHello::new(*self.keypair.public_key()).into_message();
+        let nonce = self.nonce(path, Address(0));
+        OpenBox::<Message>::new(msg, nonce).encode()
+    }
+
+    pub(crate) fn encrypt(&self, path: &mut ServerPath, msg: Message) -> ByteBox {
+        let nonce = self.nonce(path, path.address);
+        OpenBox::<Message>::new(msg, nonce).encrypt(&self.keypair, &path.client_key)
+    }
+
+    pub(crate) fn client_hello(&self, path: &ServerPath, bbox: ByteBox) {
+        match OpenBox::<Message>::decode(bbox, &["client-hello"]).unwrap().message {
+            Message::ClientHello(hello) => assert_eq!(hello.key, path.client_key),
+            other => panic!("Expected client-hello, got {:?}", other),
+        }
+    }
+
+    pub(crate) fn client_auth(&self, path: &mut ServerPath, bbox: ByteBox) {
+        let obox = OpenBox::<Message>::decrypt(bbox, &self.keypair, &path.client_key, &["client-auth"]).unwrap();
+        assert_eq!(obox.nonce.destination(), Address(0));
+        path.client_cookie = Some(obox.nonce.cookie().clone());
+        match obox.message {
+            Message::ClientAuth(auth) => {
+                assert_eq!(auth.your_cookie, path.cookie);
+                assert_eq!(auth.subprotocols, vec![ProtocolVersion::default().subprotocol().to_string()]);
+            },
+            other => panic!("Expected client-auth, got {:?}", other),
+        }
+    }
+}
+
+/// A recorded frame, labelled with its direction and message type.
+pub(crate) type Frame = (&'static str, Vec<u8>);
+
+/// Record a frame and return it again.
+pub(crate) fn record(transcript: &mut Vec<Frame>, label: &'static str, bbox: ByteBox) -> ByteBox {
+    let bytes = bbox.into_bytes();
+    transcript.push((label, bytes.clone()));
+    ByteBox::from_vec(bytes).unwrap()
+}
+
+/// Return all byte boxes that should be sent.
+pub(crate) fn replies(actions: HandleActions) -> Vec<ByteBox> {
+    actions.split_replies().0
+}
+
+/// Relay a peer message, asserting that it was addressed correctly.
+pub(crate) fn relay(transcript: &mut Vec<Frame>, label: &'static str, bbox: ByteBox, from: u8, to: u8) -> ByteBox {
+    assert_eq!(bbox.nonce.source(), Address(from));
+    assert_eq!(bbox.nonce.destination(), Address(to));
+    record(transcript, label, bbox)
+}
+
+/// Assert that the actions finish the peer handshake.
+///
+/// The handshake emits `SessionRenewed` instead of `PeerHandshakeDone` if
+/// `renewed` is set. Task messages queued during the renewal may follow as
+/// replies.
+pub(crate) fn assert_handshake_done(actions: &[HandleAction], renewed: bool) {
+    let event = if renewed { Event::SessionRenewed } else { Event::PeerHandshakeDone(DummyTask::name_for(42)) };
+    assert!(actions.contains(&HandleAction::Event(event)));
+    let last = actions.iter().rev().find(|action| match action {
+        HandleAction::Reply(_) => false,
+        _ => true,
+    });
+    assert_eq!(last, Some(&HandleAction::HandshakeDone));
+}
+
+/// Create an initiator and a responder.
+///
+/// If `use_token` is set, the responder authenticates with the auth token of
+/// the initiator. Otherwise both peers trust each other's permanent key.
+pub(crate) fn create_peers(seed: u64, use_token: bool) -> (InitiatorSignaling, ResponderSignaling) {
+    let initiator_ks = KeyPair::from_random_source(&mut SeededRandom::new(seed + 1));
+    let responder_ks = KeyPair::from_random_source(&mut SeededRandom::new(seed + 2));
+    let initiator_pk = *initiator_ks.public_key();
+    let responder_pk = *responder_ks.public_key();
+
+    let initiator = InitiatorSignaling::with_rng(
+        initiator_ks,
+        Tasks::new(Box::new(DummyTask::new(42))),
+        if use_token { None } else { Some(responder_pk) },
+        None,
+        None,
+        Box::new(SeededRandom::new(seed + 3)),
+    );
+    let auth_token = match initiator.common().auth_provider {
+        Some(AuthProvider::Token(ref token)) => Some(token.clone()),
+        _ => None,
+    };
+    assert_eq!(auth_token.is_some(), use_token);
+    let responder = ResponderSignaling::with_rng(
+        responder_ks,
+        initiator_pk,
+        auth_token,
+        None,
+        Tasks::new(Box::new(DummyTask::new(42))),
+        None,
+        Box::new(SeededRandom::new(seed + 4)),
+    );
+    (initiator, responder)
+}
+
+/// Run the server and peer handshakes of both peers and return the recorded
+/// frames.
+///
+/// If `use_token` is set, the responder is expected to send a token message.
+pub(crate) fn handshake(
+    server: &mut TestServer,
+    initiator: &mut InitiatorSignaling,
+    responder: &mut ResponderSignaling,
+    use_token: bool,
+    renewed: bool,
+) -> Vec<Frame> {
+    let mut transcript = vec![];
+    let initiator_pk = *initiator.common().permanent_keypair.public_key();
+    let responder_pk = *responder.common().permanent_keypair.public_key();
+
+    // Server handshake of the initiator
+    let mut initiator_path = server.path(INITIATOR_ADDR, initiator_pk);
+    let bbox = record(&mut transcript, "server -> initiator: server-hello", server.server_hello(&mut initiator_path));
+    let mut out = replies(initiator.handle_message(bbox).unwrap());
+    assert_eq!(out.len(), 1);
+    let bbox = record(&mut transcript, "initiator -> server: client-auth", out.remove(0));
+    server.client_auth(&mut initiator_path, bbox);
+    let msg = ServerAuth::for_initiator(initiator_path.client_cookie.clone().unwrap(), None, vec![]).into_message();
+    let bbox = record(&mut transcript, "server -> initiator: server-auth", server.encrypt(&mut initiator_path, msg));
+    assert!(replies(initiator.handle_message(bbox).unwrap()).is_empty());
+    assert_eq!(initiator.common().signaling_state(), SignalingState::PeerHandshake);
+    assert_eq!(initiator.common().identity, ClientIdentity::Initiator);
+
+    // Server handshake of the responder
+    let mut responder_path = server.path(RESPONDER_ADDR, responder_pk);
+    let bbox = record(&mut transcript, "server -> responder: server-hello", server.server_hello(&mut responder_path));
+    let mut out = replies(responder.handle_message(bbox).unwrap());
+    assert_eq!(out.len(), 2);
+    let bbox = record(&mut transcript, "responder -> server: client-hello", out.remove(0));
+    server.client_hello(&responder_path, bbox);
+    let bbox = record(&mut transcript, "responder -> server: client-auth", out.remove(0));
+    server.client_auth(&mut responder_path, bbox);
+    let msg = ServerAuth::for_responder(responder_path.client_cookie.clone().unwrap(), None, true).into_message();
+    let bbox = record(&mut transcript, "server -> responder: server-auth", server.encrypt(&mut responder_path, msg));
+    let mut out = replies(responder.handle_message(bbox).unwrap());
+    assert_eq!(responder.common().signaling_state(), SignalingState::PeerHandshake);
+    assert_eq!(responder.common().identity, ClientIdentity::Responder(RESPONDER_ADDR));
+
+    // The initiator is notified about the responder
+    let msg = NewResponder::new(Address(RESPONDER_ADDR)).into_message();
+    let bbox = record(&mut transcript, "server -> initiator: new-responder", server.encrypt(&mut initiator_path, msg));
+    assert!(replies(initiator.handle_message(bbox).unwrap()).is_empty());
+
+    // Peer handshake
+    assert_eq!(initiator.peer_info(), None);
+    if use_token {
+        assert_eq!(out.len(), 2);
+        let bbox = relay(&mut transcript, "responder -> initiator: token", out.remove(0), RESPONDER_ADDR, INITIATOR_ADDR);
+        assert!(replies(initiator.handle_message(bbox).unwrap()).is_empty());
+    }
+    assert_eq!(out.len(), 1);
+    let bbox = relay(&mut transcript, "responder -> initiator: key", out.remove(0), RESPONDER_ADDR, INITIATOR_ADDR);
+    let mut out = replies(initiator.handle_message(bbox).unwrap());
+    assert_eq!(out.len(), 1);
+    let bbox = relay(&mut transcript, "initiator -> responder: key", out.remove(0), INITIATOR_ADDR, RESPONDER_ADDR);
+    let mut out = replies(responder.handle_message(bbox).unwrap());
+    assert_eq!(out.len(), 1);
+    let bbox = relay(&mut transcript, "responder -> initiator: auth", out.remove(0), RESPONDER_ADDR, INITIATOR_ADDR);
+    let actions = initiator.handle_message(bbox).unwrap();
+    assert_handshake_done(&actions, renewed);
+    let mut out = replies(actions);
+    assert_eq!(out.len(), 1);
+    let bbox = relay(&mut transcript, "initiator -> responder: auth", out.remove(0), INITIATOR_ADDR, RESPONDER_ADDR);
+    let actions = responder.handle_message(bbox).unwrap();
+    assert_handshake_done(&actions, renewed);
+    // Only the task messages queued during a session renewal follow
+    for bbox in replies(actions) {
+        assert!(renewed);
+        relay(&mut transcript, "responder -> initiator: task", bbox, RESPONDER_ADDR, INITIATOR_ADDR);
+    }
+
+    assert_eq!(initiator.common().signaling_state(), SignalingState::Task);
+    assert_eq!(responder.common().signaling_state(), SignalingState::Task);
+
+    // Both peers know who they are talking to
+    let auth_method = if use_token { AuthMethod::Token } else { AuthMethod::TrustedKey };
+    assert_eq!(initiator.peer_info(), Some(PeerInfo {
+        permanent_key: responder_pk,
+        address: RESPONDER_ADDR,
+        auth_method,
+    }));
+    assert_eq!(responder.peer_info(), Some(PeerInfo {
+        permanent_key: initiator_pk,
+        address: INITIATOR_ADDR,
+        auth_method,
+    }));
+    transcript
+}
@@ -0,0 +1,454 @@
+//! Property based tests for the signaling state machine.
+//!
+//! Arbitrary sequences of server and peer messages are fed into initiators
+//! and responders that start in arbitrary phases. Most of the messages are
+//! built so that they pass the nonce and encryption checks, the rest are
+//! invalid in some way (wrong key, wrong destination, repeated CSN, garbage).
+//!
+//! The first error closes the connection, see `do_handshake`. After that
+//! error, the state machine is failed: It rejects all messages, without
+//! replying or changing its state. Messages are still fed after an error to
+//! check this.
+
+use std::collections::HashMap;
+
+use proptest::collection::vec;
+use proptest::prelude::*;
+
+use crate::test_helpers::DummyTask;
+
+use super::*;
+use super::cookie::Cookie;
+use super::csn::CombinedSequence;
+use super::messages::*;
+use super::send_error::SendErrorId;
+
+
+/// The phase in which the state machine starts.
+#[derive(Debug, Clone, Copy)]
+enum Phase {
+    /// Before the server-hello.
+    New,
+    /// The server handshake is done, the peer handshake is in progress.
+    PeerHandshake,
+}
+
+/// The signaling instance under test.
+#[derive(Debug, Clone, Copy)]
+enum Setup {
+    Initiator { trusted: bool },
+    Responder { trusted: bool, address: u8 },
+}
+
+/// The message to send.
+#[derive(Debug, Clone)]
+enum Kind {
+    ServerHello,
+    ServerAuth { responders: Vec<u8>, initiator_connected: bool },
+    NewInitiator,
+    NewResponder(u8),
+    DropResponder(u8),
+    SendError,
+    Disconnected(u8),
+    Token,
+    Key,
+    Auth,
+    Close(u16),
+}
+
+/// How the message is encrypted.
+#[derive(Debug, Clone, Copy)]
+enum Encryption {
+    /// With the keys the receiver expects.
+    Valid,
+    /// With a random key.
+    WrongKey,
+    /// Not encrypted at all.
+    Plain,
+    /// Truncated after a few bytes of the payload.
+    Garbage,
+}
+
+/// A message sent to the state machine.
+#[derive(Debug, Clone)]
+struct Input {
+    /// The source address, the server if `None`.
+    sender: Option<u8>,
+    kind: Kind,
+    encryption: Encryption,
+    /// Send to the wrong destination address.
+    wrong_destination: bool,
+    /// Don't increment the CSN of the sender.
+    repeat_csn: bool,
+}
+
+impl Input {
+    fn valid(sender: Option<u8>, kind: Kind) -> Self {
+        Input { sender, kind, encryption: Encryption::Valid, wrong_destination: false, repeat_csn: false }
+    }
+}
+
+/// A step in a test case.
+#[derive(Debug, Clone)]
+enum Step {
+    /// Send the next message of a successful handshake.
+    Handshake,
+    /// Send an arbitrary message.
+    Random(Input),
+}
+
+
+/// The state machine along with the keys of the server and the peers.
+struct Harness {
+    setup: Setup,
+    role: Role,
+    initiator: Option<InitiatorSignaling>,
+    responder: Option<ResponderSignaling>,
+    our_permanent_key: PublicKey,
+    server_ks: KeyPair,
+    server_cookie: Cookie,
+    /// The permanent key pair of the initiator or of all responders.
+    peer_permanent_ks: KeyPair,
+    peer_session_keys: HashMap<u8, KeyPair>,
+    peer_cookies: HashMap<u8, Cookie>,
+    csns: HashMap<u8, (CombinedSequence, CombinedSequenceSnapshot)>,
+    auth_token: AuthToken,
+    /// The remaining messages of a successful handshake, in reverse order.
+    handshake: Vec<Input>,
+}
+
+impl Harness {
+    fn new(setup: Setup, phase: Phase) -> Self {
+        let our_ks = KeyPair::new();
+        let our_permanent_key = *our_ks.public_key();
+        let peer_permanent_ks = KeyPair::new();
+        let auth_token = AuthToken::new();
+        let (role, initiator, responder) = match setup {
+            Setup::Initiator { trusted } => {
+                let tasks = Tasks::new(Box::new(DummyTask::new(42)));
+                let trusted_key = if trusted { Some(*peer_permanent_ks.public_key()) } else { None };
+                let signaling = InitiatorSignaling::new(our_ks, tasks, trusted_key, None, None);
+                (Role::Initiator, Some(signaling), None)
+            },
+            Setup::Responder { trusted, .. } => {
+                let mut tasks = Tasks::new(Box::new(DummyTask::new(23)));
+                tasks.add_task(Box::new(DummyTask::new(42))).unwrap();
+                let token = if trusted { None } else { Some(AuthToken::from_slice(auth_token.secret_key_bytes()).unwrap()) };
+                let initiator_pubkey = *peer_permanent_ks.public_key();
+                let signaling = ResponderSignaling::new(our_ks, initiator_pubkey, token, None, tasks, None);
+                (Role::Responder, None, Some(signaling))
+            },
+        };
+        let mut harness = Harness {
+            setup,
+            role,
+            initiator,
+            responder,
+            our_permanent_key,
+            server_ks: KeyPair::new(),
+            server_cookie: Cookie::random(),
+            peer_permanent_ks,
+            peer_session_keys: HashMap::new(),
+            peer_cookies: HashMap::new(),
+            csns: HashMap::new(),
+            auth_token,
+            handshake: vec![],
+        };
+        let server_handshake = vec![
+            Input::valid(None, Kind::ServerHello),
+            Input::valid(None, Kind::ServerAuth { responders: vec![], initiator_connected: false }),
+        ];
+        let peer_handshake = match setup {
+            Setup::Initiator { trusted } => {
+                let mut inputs = vec![Input::valid(None, Kind::NewResponder(2))];
+                if !trusted {
+                    inputs.push(Input::valid(Some(2), Kind::Token));
+                }
+                inputs.push(Input::valid(Some(2), Kind::Key));
+                inputs.push(Input::valid(Some(2), Kind::Auth));
+                inputs
+            },
+            Setup::Responder { .. } => vec![
+                Input::valid(None, Kind::NewInitiator),
+                Input::valid(Some(1), Kind::Key),
+                Input::valid(Some(1), Kind::Auth),
+            ],
+        };
+        if let Phase::New = phase {
+            harness.handshake.extend(server_handshake);
+        }
+        harness.handshake.extend(peer_handshake);
+        harness.handshake.reverse();
+        if let Phase::PeerHandshake = phase {
+            let identity = match setup {
+                Setup::Initiator { .. } => ClientIdentity::Initiator,
+                Setup::Responder { address, .. } => ClientIdentity::Responder(address),
+            };
+            let server_ks_pk = *harness.server_ks.public_key();
+            let server_cookie = harness.server_cookie.clone();
+            let signaling = harness.signaling();
+            signaling.common_mut().identity = identity;
+            signaling.server_mut().set_handshake_state(ServerHandshakeState::Done);
+            signaling.server_mut().cookie_pair.theirs = Some(server_cookie);
+            signaling.server_mut().session_key = Some(server_ks_pk);
+            signaling.common_mut().set_signaling_state_forced(SignalingState::PeerHandshake).unwrap();
+        }
+        harness
+    }
+
+    fn signaling(&mut self) -> &mut dyn Signaling {
+        match (self.initiator.as_mut(), self.responder.as_mut()) {
+            (Some(initiator), _) => initiator,
+            (None, Some(responder)) => responder,
+            (None, None) => unreachable!(),
+        }
+    }
+
+    /// Return the address that the server assigns to us.
+    fn our_address(&self) -> u8 {
+        match self.setup {
+            Setup::Initiator { .. } => 1,
+            Setup::Responder { address, .. } => address,
+        }
+    }
+
+    /// Return our session key and cookie towards the peer with the specified
+    /// address, if the state machine knows the peer.
+    fn our_peer_keys(&self, addr: u8) -> Option<(PublicKey, Cookie)> {
+        if let Some(ref initiator) = self.initiator {
+            let responder = initiator.responders.get(&Address(addr))?;
+            return Some((*responder.keypair.public_key(), responder.cookie_pair.ours.clone()));
+        }
+        self.responder.as_ref().map(|r| (*r.initiator.keypair.public_key(), r.initiator.cookie_pair.ours.clone()))
+    }
+
+    fn next_csn(&mut self, source: u8, repeat: bool) -> CombinedSequenceSnapshot {
+        let entry = self.csns.entry(source).or_insert_with(|| {
+            let mut csn = CombinedSequence::random();
+            let first = csn.increment().unwrap();
+            (csn, first)
+        });
+        if !repeat {
+            entry.1 = entry.0.increment().unwrap();
+        }
+        entry.1.clone()
+    }
+
+    /// Build an auth message that the peer would send.
+    fn auth_message(&self, your_cookie: Cookie) -> Message {
+        match self.role {
+            Role::Initiator => ResponderAuthBuilder::new(your_cookie)
+                .add_tasks(&Tasks::new(Box::new(DummyTask::new(42))))
+                .build().unwrap().into_message(),
+            Role::Responder => InitiatorAuthBuilder::new(your_cookie)
+                .set_task(DummyTask::name_for(42), None)
+                .build().unwrap().into_message(),
+        }
+    }
+
+    fn message(&mut self, kind: &Kind, source: u8) -> Message {
+        match *kind {
+            Kind::ServerHello => ServerHello::new(*self.server_ks.public_key()).into_message(),
+            Kind::ServerAuth { ref responders, initiator_connected } => {
+                let your_cookie = self.signaling().server().cookie_pair.ours.clone();
+                let (responders, initiator_connected) = match self.role {
+                    Role::Initiator => (Some(responders.iter().map(|addr| Address(*addr)).collect()), None),
+                    Role::Responder => (None, Some(initiator_connected)),
+                };
+                Message::ServerAuth(ServerAuth { your_cookie, signed_keys: None, responders, initiator_connected, extra: ExtraFields::default() })
+            },
+            Kind::NewInitiator => NewInitiator::default().into_message(),
+            Kind::NewResponder(id) => NewResponder::new(Address(id)).into_message(),
+            Kind::DropResponder(id) => DropResponder::with_reason(Address(id), DropReason::ProtocolError).into_message(),
+            Kind::SendError => {
+                let id = SendErrorId::from_slice(&[1, 2, 0, 0, 0, 0, 0, 1]).unwrap();
+                Message::SendError(SendError::new(id))
+            },
+            Kind::Disconnected(id) => Message::Disconnected(Disconnected::new(Address(id))),
+            Kind::Token => Token::new(*self.peer_permanent_ks.public_key()).into_message(),
+            Kind::Key => {
+                let session_ks = self.peer_session_keys.entry(source).or_insert_with(KeyPair::new);
+                Key::new(*session_ks.public_key()).into_message()
+            },
+            Kind::Auth => match self.our_peer_keys(source) {
+                Some((_, cookie)) => self.auth_message(cookie),
+                None => self.auth_message(Cookie::random()),
+            },
+            Kind::Close(reason) => Message::Close(Close::new(reason)),
+        }
+    }
+
+    fn build(&mut self, input: &Input) -> ByteBox {
+        let our_address = self.our_address();
+        let source = input.sender.unwrap_or(0);
+        let destination = match (&input.kind, input.wrong_destination) {
+            (Kind::ServerHello, false) => 0,
+            (_, false) => our_address,
+            (_, true) => our_address.wrapping_add(1),
+        };
+        let cookie = if source == 0 {
+            self.server_cookie.clone()
+        } else {
+            self.peer_cookies.entry(source).or_insert_with(Cookie::random).clone()
+        };
+        let csn = self.next_csn(source, input.repeat_csn);
+        let nonce = Nonce::new(cookie, Address(source), Address(destination), csn);
+
+        let message = self.message(&input.kind, source);
+        let obox = OpenBox::<Message>::new(message, nonce);
+
+        match input.encryption {
+            Encryption::Plain => obox.encode(),
+            Encryption::Garbage => {
+                let mut bytes = obox.encode().into_bytes();
+                bytes.truncate(30);
+                ByteBox::from_slice(&bytes).unwrap_or_else(|_| ByteBox::from_slice(&[0; 24]).unwrap())
+            },
+            Encryption::WrongKey => obox.encrypt(&KeyPair::new(), &self.our_permanent_key),
+            Encryption::Valid if source == 0 => match input.kind {
+                Kind::ServerHello => obox.encode(),
+                _ => obox.encrypt(&self.server_ks, &self.our_permanent_key),
+            },
+            Encryption::Valid => match input.kind {
+                Kind::Token => obox.encrypt_token(&self.auth_token()),
+                Kind::Key => obox.encrypt(&self.peer_permanent_ks, &self.our_permanent_key),
+                _ => {
+                    let our_session_key = self.our_peer_keys(source).map(|(key, _)| key).unwrap_or(self.our_permanent_key);
+                    let session_ks = self.peer_session_keys.entry(source).or_insert_with(KeyPair::new);
+                    obox.encrypt(session_ks, &our_session_key)
+                },
+            },
+        }
+    }
+
+    /// The auth token that an initiator expects in the token message.
+    fn auth_token(&mut self) -> AuthToken {
+        match self.signaling().common().auth_provider {
+            Some(AuthProvider::Token(ref token)) => AuthToken::from_slice(token.secret_key_bytes()).unwrap(),
+            _ => AuthToken::from_slice(self.auth_token.secret_key_bytes()).unwrap(),
+        }
+    }
+
+    /// Return the signaling state and the server handshake state.
+    fn states(&mut self) -> (SignalingState, ServerHandshakeState) {
+        let signaling = self.signaling();
+        (signaling.common().signaling_state(), signaling.server_handshake_state())
+    }
+}
+
+fn signaling_rank(state: SignalingState) -> u8 {
+    match state {
+        SignalingState::ServerHandshake => 0,
+        SignalingState::PeerHandshake => 1,
+        SignalingState::Task => 2,
+    }
+}
+
+fn server_rank(state: ServerHandshakeState) -> u8 {
+    match state {
+        ServerHandshakeState::New => 0,
+        ServerHandshakeState::ClientInfoSent => 1,
+        ServerHandshakeState::Done => 2,
+    }
+}
+
+/// Feed the messages into the state machine and check the invariants after
+/// every message.
+fn check_invariants(setup: Setup, phase: Phase, steps: &[Step]) -> Result<(), TestCaseError> {
+    let mut harness = Harness::new(setup, phase);
+    let mut failed = false;
+    for step in steps {
+        let input = match *step {
+            Step::Handshake => match harness.handshake.pop() {
+                Some(input) => input,
+                None => continue,
+            },
+            Step::Random(ref input) => input.clone(),
+        };
+        let bbox = harness.build(&input);
+        let before = harness.states();
+        let result = harness.signaling().handle_message(bbox);
+        let after = harness.states();
+
+        // The state machine never goes back to an earlier phase
+        prop_assert!(signaling_rank(after.0) >= signaling_rank(before.0), "{:?} -> {:?}", before.0, after.0);
+        prop_assert!(server_rank(after.1) >= server_rank(before.1), "{:?} -> {:?}", before.1, after.1);
+
+        // A rejected message never completes a phase
+        if result.is_err() {
+            prop_assert_eq!(after.0, before.0);
+        }
+
+        // The state machine never leaves the failure state, and never
+        // replies once it failed
+        if failed {
+            let replies = result.as_ref().map(|actions| actions.replies().count()).unwrap_or(0);
+            prop_assert!(result.is_err(), "Message handled after failure: {:?}", input);
+            prop_assert_eq!(replies, 0);
+            prop_assert_eq!(after, before);
+        }
+        failed |= result.is_err();
+    }
+    Ok(())
+}
+
+
+fn setup() -> impl Strategy<Value = Setup> {
+    prop_oneof![
+        any::<bool>().prop_map(|trusted| Setup::Initiator { trusted }),
+        (any::<bool>(), 2..=255u8).prop_map(|(trusted, address)| Setup::Responder { trusted, address }),
+    ]
+}
+
+fn phase() -> impl Strategy<Value = Phase> {
+    prop_oneof![Just(Phase::New), Just(Phase::PeerHandshake)]
+}
+
+fn address() -> impl Strategy<Value = u8> {
+    prop_oneof![4 => 1..=4u8, 1 => any::<u8>()]
+}
+
+fn kind() -> impl Strategy<Value = Kind> {
+    prop_oneof![
+        Just(Kind::ServerHello),
+        (vec(2..=4u8, 0..3), any::<bool>())
+            .prop_map(|(responders, initiator_connected)| Kind::ServerAuth { responders, initiator_connected }),
+        Just(Kind::NewInitiator),
+        address().prop_map(Kind::NewResponder),
+        address().prop_map(Kind::DropResponder),
+        Just(Kind::SendError),
+        address().prop_map(Kind::Disconnected),
+        Just(Kind::Token),
+        Just(Kind::Key),
+        Just(Kind::Auth),
+        prop_oneof![Just(3001u16), Just(3003u16), any::<u16>()].prop_map(Kind::Close),
+    ]
+}
+
+fn encryption() -> impl Strategy<Value = Encryption> {
+    prop_oneof![
+        6 => Just(Encryption::Valid),
+        1 => Just(Encryption::WrongKey),
+        1 => Just(Encryption::Plain),
+        1 => Just(Encryption::Garbage),
+    ]
+}
+
+fn input() -> impl Strategy<Value = Input> {
+    let sender = prop_oneof![1 => Just(None), 1 => address().prop_map(Some)];
+    (sender, kind(), encryption(), prop::bool::weighted(0.1), prop::bool::weighted(0.1))
+        .prop_map(|(sender, kind, encryption, wrong_destination, repeat_csn)| Input {
+            sender, kind, encryption, wrong_destination, repeat_csn,
+        })
+}
+
+fn step() -> impl Strategy<Value = Step> {
+    prop_oneof![1 => Just(Step::Handshake), 1 => input().prop_map(Step::Random)]
+}
+
+proptest! {
+    #[test]
+    fn signaling_invariants(setup in setup(), phase in phase(), steps in vec(step(), 1..24)) {
+        check_invariants(setup, phase, &steps)?;
+    }
+}
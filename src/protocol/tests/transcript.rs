@@ -0,0 +1,212 @@
+//! Replay of complete handshakes through a simulated server.
+//!
+//! All keys, cookies and sequence numbers are drawn from seeded random
+//! sources, which makes every frame on the wire reproducible. The simulated
+//! server decodes and validates every frame it receives, which checks the
+//! message flow described in the SaltyRTC specification.
+//!
+//! These are not interoperability tests: the transcripts are produced by
+//! this crate and only compared against themselves. The reference vectors
+//! of the JavaScript and Python implementations have not been vendored yet.
+use crate::test_helpers::DummyTask;
+use crate::transcript::{Direction, MemoryTranscript};
+
+use super::*;
+use super::messages::*;
+use super::random::SeededRandom;
+use super::simulated::{create_peers, handshake, replies, Frame, TestServer, INITIATOR_ADDR, RESPONDER_ADDR};
+
+/// Run a full handshake between an initiator and a responder and return the
+/// recorded frames.
+fn run_handshake(seed: u64, use_token: bool) -> Vec<Frame> {
+    let (mut initiator, mut responder) = create_peers(seed, use_token);
+    handshake(&mut TestServer::new(seed), &mut initiator, &mut responder, use_token, false)
+}
+
+/// Return the labels of a transcript.
+fn labels(transcript: &[Frame]) -> Vec<&'static str> {
+    transcript.iter().map(|&(label, _)| label).collect()
+}
+
+#[test]
+fn handshake_with_token() {
+    let transcript = run_handshake(0x5a17, true);
+    assert_eq!(labels(&transcript), vec![
+        "server -> initiator: server-hello",
+        "initiator -> server: client-auth",
+        "server -> initiator: server-auth",
+        "server -> responder: server-hello",
+        "responder -> server: client-hello",
+        "responder -> server: client-auth",
+        "server -> responder: server-auth",
+        "server -> initiator: new-responder",
+        "responder -> initiator: token",
+        "responder -> initiator: key",
+        "initiator -> responder: key",
+        "responder -> initiator: auth",
+        "initiator -> responder: auth",
+    ]);
+}
+
+#[test]
+fn handshake_with_trusted_key() {
+    let transcript = run_handshake(0x5a17, false);
+    assert_eq!(transcript.len(), 12);
+    assert!(!labels(&transcript).contains(&"responder -> initiator: token"));
+}
+
+/// The same seed must produce the same frames, byte by byte.
+#[test]
+fn handshake_is_reproducible() {
+    for &use_token in &[true, false] {
+        let first = run_handshake(0x5a17, use_token);
+        let second = run_handshake(0x5a17, use_token);
+        assert_eq!(first, second);
+
+        let other = run_handshake(0x0dd, use_token);
+        assert_eq!(labels(&first), labels(&other));
+        for (a, b) in first.iter().zip(other.iter()) {
+            assert_ne!(a.1, b.1, "Frame '{}' does not depend on the seed", a.0);
+        }
+    }
+}
+
+/// Do the server handshake of a responder and return the peer messages that
+/// it wants to send afterwards.
+fn responder_server_handshake(server: &mut TestServer, responder: &mut ResponderSignaling, responder_pk: PublicKey) -> Vec<ByteBox> {
+    let mut path = server.path(RESPONDER_ADDR, responder_pk);
+    let mut out = replies(responder.handle_message(server.server_hello(&mut path)).unwrap());
+    assert_eq!(out.len(), 2);
+    server.client_hello(&path, out.remove(0));
+    server.client_auth(&mut path, out.remove(0));
+    let msg = ServerAuth::for_responder(path.client_cookie.clone().unwrap(), None, true).into_message();
+    replies(responder.handle_message(server.encrypt(&mut path, msg)).unwrap())
+}
+
+/// After a reset, the handshake can be repeated with another server using
+/// the same keys and auth token.
+#[test]
+fn reset_handshake_for_new_server() {
+    let responder_ks = KeyPair::from_random_source(&mut SeededRandom::new(1));
+    let responder_pk = *responder_ks.public_key();
+    let initiator_pk = *KeyPair::from_random_source(&mut SeededRandom::new(2)).public_key();
+    let auth_token = AuthToken::from_random_source(&mut SeededRandom::new(3));
+    let mut responder = ResponderSignaling::with_rng(
+        responder_ks,
+        initiator_pk,
+        Some(auth_token.clone()),
+        None,
+        Tasks::new(Box::new(DummyTask::new(42))),
+        None,
+        Box::new(SeededRandom::new(4)),
+    );
+
+    // The token is consumed during the first attempt
+    let out = responder_server_handshake(&mut TestServer::new(5), &mut responder, responder_pk);
+    assert_eq!(out.len(), 2);
+    assert_eq!(responder.auth_token(), None);
+
+    responder.reset_handshake().unwrap();
+    assert_eq!(responder.common().signaling_state(), SignalingState::ServerHandshake);
+    assert_eq!(responder.server_handshake_state(), ServerHandshakeState::New);
+    assert_eq!(responder.common().identity, ClientIdentity::Unknown);
+    assert_eq!(responder.auth_token(), Some(&auth_token));
+    assert_eq!(responder.common().permanent_keypair.public_key(), &responder_pk);
+
+    // The second attempt sends the token again
+    let out = responder_server_handshake(&mut TestServer::new(6), &mut responder, responder_pk);
+    assert_eq!(out.len(), 2);
+    assert_eq!(out[0].nonce.destination(), Address(INITIATOR_ADDR));
+}
+
+/// A finished handshake cannot be reset.
+#[test]
+fn reset_handshake_after_task_fails() {
+    let mut signaling = MockSignaling::new(Role::Initiator, ClientIdentity::Initiator, SignalingState::Task);
+    assert_eq!(
+        signaling.reset_handshake(),
+        Err(SignalingError::Crash("Cannot reset signaling after the handshake is done".into())),
+    );
+}
+
+/// After renewing the session, both peers do a trusted handshake with new
+/// session keys and cookies, keeping the chosen task.
+#[test]
+fn renew_session() {
+    let (mut initiator, mut responder) = create_peers(0x5a17, true);
+    handshake(&mut TestServer::new(1), &mut initiator, &mut responder, true, false);
+    let task = initiator.common().task.clone().unwrap();
+    let session_key = *initiator.responder.as_ref().unwrap().keypair.public_key();
+    let cookie = initiator.responder.as_ref().unwrap().cookie_pair().ours.clone();
+
+    initiator.renew_session().unwrap();
+    responder.renew_session().unwrap();
+    assert_eq!(initiator.common().signaling_state(), SignalingState::ServerHandshake);
+    assert!(initiator.common().task.is_none());
+    assert_eq!(responder.auth_token(), None);
+
+    // No token is sent during the second handshake
+    let transcript = handshake(&mut TestServer::new(2), &mut initiator, &mut responder, false, true);
+    assert!(!labels(&transcript).contains(&"responder -> initiator: token"));
+    assert!(Arc::ptr_eq(initiator.common().task.as_ref().unwrap(), &task));
+    assert_ne!(initiator.responder.as_ref().unwrap().keypair.public_key(), &session_key);
+    assert_ne!(initiator.responder.as_ref().unwrap().cookie_pair().ours, cookie);
+}
+
+/// Task messages that could not be sent before the renewal are sent once
+/// the renewed session is established.
+#[test]
+fn renew_session_sends_queued_messages() {
+    let (mut initiator, mut responder) = create_peers(0x5a17, true);
+    handshake(&mut TestServer::new(1), &mut initiator, &mut responder, true, false);
+    initiator.renew_session().unwrap();
+    responder.renew_session().unwrap();
+    responder.common_mut().renewal_queue.push(Value::Map(vec![
+        (Value::from("type"), Value::from("application")),
+        (Value::from("data"), Value::from(1)),
+    ]));
+
+    let transcript = handshake(&mut TestServer::new(2), &mut initiator, &mut responder, false, true);
+    assert_eq!(labels(&transcript).last(), Some(&"responder -> initiator: task"));
+    assert!(responder.common().renewal_queue.is_empty());
+    let bbox = ByteBox::from_vec(transcript.last().unwrap().1.clone()).unwrap();
+    assert_eq!(
+        initiator.handle_message(bbox).unwrap().into_vec(),
+        vec![HandleAction::TaskMessage(TaskMessage::Application(Value::from(1)))],
+    );
+}
+
+/// A session can only be renewed after the peer handshake.
+#[test]
+fn renew_session_before_handshake_fails() {
+    let (mut initiator, _) = create_peers(0x5a17, true);
+    assert_eq!(
+        initiator.renew_session(),
+        Err(SignalingError::Crash("Cannot renew session before the peer handshake is done".into())),
+    );
+}
+
+/// A transcript recorder sees every frame sent and received by the client,
+/// along with the payload of the messages in both directions.
+#[test]
+fn transcript_recorder() {
+    let recorder = MemoryTranscript::new();
+    let (mut initiator, mut responder) = create_peers(0x5a17, true);
+    initiator.common_mut().transcript = Some(Box::new(recorder.clone()));
+    let transcript = handshake(&mut TestServer::new(0x5a17), &mut initiator, &mut responder, true, false);
+
+    let frames: Vec<&Frame> = transcript.iter()
+        .filter(|(label, _)| label.contains("initiator"))
+        .collect();
+    let entries = recorder.entries();
+    assert_eq!(entries.len(), frames.len());
+    for (entry, (label, bytes)) in entries.iter().zip(frames) {
+        assert_eq!(&entry.bytes, bytes, "{}", label);
+        let message_type = label.rsplit(": ").next().unwrap();
+        let direction = if label.starts_with("initiator") { Direction::Outgoing } else { Direction::Incoming };
+        assert_eq!(entry.direction, direction, "{}", label);
+        assert_eq!(entry.message_type, Some(message_type.to_string()), "{}", label);
+        let plaintext = entry.plaintext.as_ref().unwrap();
+        assert_eq!(Message::peek_type(plaintext).unwrap(), message_type);
+    }
+}
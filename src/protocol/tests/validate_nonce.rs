@@ -129,13 +129,14 @@ fn first_message_bad_overflow_number() {
 
 fn _test_sequence_number(first: CombinedSequenceSnapshot,
                          second: CombinedSequenceSnapshot)
-                         -> SignalingResult<Vec<HandleAction>> {
+                         -> SignalingResult<HandleActions> {
     let ks = KeyPair::new();
     let mut s = InitiatorSignaling::new(ks, Tasks(vec![]), None, None, None);
+    let cookie = Cookie::random();
 
     // Process ServerHello
     let msg = ServerHello::random().into_message();
-    let nonce = Nonce::new(Cookie::random(), Address(0), Address(0), first);
+    let nonce = Nonce::new(cookie.clone(), Address(0), Address(0), first);
     let obox = OpenBox::<Message>::new(msg, nonce);
     let bbox = obox.encode();
     assert_eq!(s.server().handshake_state(), ServerHandshakeState::New);
@@ -144,7 +145,7 @@ fn _test_sequence_number(first: CombinedSequenceSnapshot,
 
     // Process ServerAuth
     let msg = ServerAuth::for_initiator(s.server().cookie_pair().ours.clone(), None, vec![]).into_message();
-    let nonce = Nonce::new(Cookie::random(), Address(0), Address(0), second);
+    let nonce = Nonce::new(cookie, Address(0), Address(0), second);
     let obox = OpenBox::<Message>::new(msg, nonce);
     let bbox = obox.encode();
     assert_eq!(s.server().handshake_state(), ServerHandshakeState::ClientInfoSent);
@@ -184,6 +185,21 @@ fn sequence_number_reset() {
     assert_eq!(err, SignalingError::InvalidNonce("The server CSN is lower than last time".into()));
 }
 
+/// When the sequence number wraps, the overflow number is incremented and
+/// the combined sequence number keeps increasing.
+#[test]
+fn sequence_number_wraps() {
+    // The nonce is valid, but the unencrypted message cannot be decoded
+    let result = _test_sequence_number(
+        CombinedSequenceSnapshot::new(0, ::std::u32::MAX),
+        CombinedSequenceSnapshot::new(1, 0),
+    );
+    match result {
+        Err(SignalingError::InvalidNonce(reason)) => panic!("Nonce rejected: {}", reason),
+        other => assert!(other.is_err()),
+    }
+}
+
 /// In case this is the first message received from the sender, the
 /// peer MUST check that the sender's cookie is different than its own
 /// cookie.
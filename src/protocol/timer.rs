@@ -0,0 +1,130 @@
+//! Timeouts of the signaling.
+//!
+//! The signaling does not wait for anything itself. When it needs a timeout,
+//! it returns a [`HandleAction::StartTimer`](../enum.HandleAction.html)
+//! action with the duration after which
+//! [`Signaling::handle_timeout`](../trait.Signaling.html#method.handle_timeout)
+//! should be called with the timer id. Once the timeout is not needed
+//! anymore, it returns a `HandleAction::CancelTimer` action.
+//!
+//! Starting a timer that is already running restarts it, and a timeout that
+//! fires after the reason for it is gone is ignored by the signaling. The
+//! [`Timers`](struct.Timers.html) type keeps track of the deadlines for the
+//! connection layer, including the heartbeats and the handshake timeout of
+//! the client.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use super::types::{Address, HandleAction, HandleActions};
+
+
+/// The id of a timer started by the signaling.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) enum TimerId {
+    /// The responder at this address must finish the peer handshake before
+    /// the timer expires.
+    Responder(Address),
+    /// The initiator must connect to the server before the timer expires.
+    Initiator,
+    /// The next heartbeat is due. Started and handled by the client, not the
+    /// signaling.
+    Heartbeat,
+    /// The server and peer handshake must be done before the timer expires.
+    /// Started and handled by the client, not the signaling.
+    Handshake,
+}
+
+
+/// The deadlines of the running timers.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Timers {
+    deadlines: HashMap<TimerId, Instant>,
+}
+
+impl Timers {
+    /// Start and cancel the timers requested by the actions, and return the
+    /// remaining actions in their original order.
+    pub(crate) fn apply(&mut self, actions: HandleActions, now: Instant) -> HandleActions {
+        actions.into_iter()
+            .filter_map(|action| match action {
+                HandleAction::StartTimer(id, duration) => {
+                    self.start(id, duration, now);
+                    None
+                },
+                HandleAction::CancelTimer(id) => {
+                    self.cancel(id);
+                    None
+                },
+                other => Some(other),
+            })
+            .collect()
+    }
+
+    /// Start or restart the timer, so that it expires `duration` after `now`.
+    pub(crate) fn start(&mut self, id: TimerId, duration: Duration, now: Instant) {
+        self.deadlines.insert(id, now + duration);
+    }
+
+    /// Stop the timer, if it is running.
+    pub(crate) fn cancel(&mut self, id: TimerId) {
+        self.deadlines.remove(&id);
+    }
+
+    /// Return the point in time at which the next timer expires, if any.
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        self.deadlines.values().min().cloned()
+    }
+
+    /// Remove the timers that expired at `now` and return their ids, ordered
+    /// by deadline.
+    pub(crate) fn expire(&mut self, now: Instant) -> Vec<TimerId> {
+        let mut expired: Vec<(Instant, TimerId)> = self.deadlines
+            .iter()
+            .filter(|&(_, deadline)| *deadline <= now)
+            .map(|(id, deadline)| (*deadline, *id))
+            .collect();
+        expired.sort();
+        for &(_, id) in &expired {
+            self.deadlines.remove(&id);
+        }
+        expired.into_iter().map(|(_, id)| id).collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn start_cancel_expire() {
+        let now = Instant::now();
+        let mut timers = Timers::default();
+        assert_eq!(timers.next_deadline(), None);
+
+        let actions = timers.apply(vec![
+            HandleAction::StartTimer(TimerId::Responder(Address(3)), Duration::from_secs(20)),
+            HandleAction::HandshakeDone,
+            HandleAction::StartTimer(TimerId::Responder(Address(4)), Duration::from_secs(10)),
+            HandleAction::StartTimer(TimerId::Responder(Address(5)), Duration::from_secs(10)),
+            HandleAction::CancelTimer(TimerId::Responder(Address(5))),
+        ].into(), now);
+        assert_eq!(actions.into_vec(), vec![HandleAction::HandshakeDone]);
+        assert_eq!(timers.next_deadline(), Some(now + Duration::from_secs(10)));
+
+        // Restarting a timer moves its deadline
+        let actions = timers.apply(
+            HandleAction::StartTimer(TimerId::Responder(Address(4)), Duration::from_secs(30)).into(),
+            now,
+        );
+        assert!(actions.is_empty());
+
+        assert!(timers.expire(now + Duration::from_secs(19)).is_empty());
+        assert_eq!(timers.expire(now + Duration::from_secs(30)), vec![
+            TimerId::Responder(Address(3)),
+            TimerId::Responder(Address(4)),
+        ]);
+        assert_eq!(timers.next_deadline(), None);
+    }
+}
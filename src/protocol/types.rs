@@ -1,18 +1,24 @@
 use std::convert::From;
 use std::fmt;
 use std::result::Result as StdResult;
+use std::time::Duration;
 
-use serde::ser::{Serialize, Serializer};
-use serde::de::{Deserialize, Deserializer, Visitor, Error as SerdeError};
+use serde::{Deserialize, Serialize};
+use serde::ser::Serializer;
+use serde::de::{Deserializer, Visitor, Error as SerdeError};
 
 use crate::Event;
 use crate::boxes::ByteBox;
-use crate::errors::SaltyError;
+use crate::crypto_types::{serde_option_public_key, serde_public_key, PublicKey};
 use crate::tasks::TaskMessage;
 
+use super::messages::DropReason;
+use super::timer::TimerId;
+
 
 /// The role of a peer.
-#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Role {
     /// A SaltyRTC compliant client who wants to establish a WebRTC or ORTC
     /// peer-to-peer connection to a responder.
@@ -44,6 +50,140 @@ impl fmt::Display for Role {
 }
 
 
+/// A revision of the SaltyRTC signalling protocol.
+///
+/// All revisions of the major version 1 share the same WebSocket subprotocol,
+/// but differ in the messages that may be exchanged.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Copy, Clone)]
+pub enum ProtocolVersion {
+    /// Protocol version 1.0.
+    V1_0,
+    /// Protocol version 1.1, which introduced the `disconnected` message.
+    V1_1,
+}
+
+impl ProtocolVersion {
+    /// Return the WebSocket subprotocol name.
+    pub fn subprotocol(self) -> &'static str {
+        match self {
+            ProtocolVersion::V1_0 | ProtocolVersion::V1_1 => "v1.saltyrtc.org",
+        }
+    }
+
+    /// Return whether the server may send `disconnected` messages.
+    pub(crate) fn supports_disconnected(self) -> bool {
+        self >= ProtocolVersion::V1_1
+    }
+}
+
+/// The latest supported protocol version is used by default.
+impl Default for ProtocolVersion {
+    fn default() -> Self {
+        ProtocolVersion::V1_1
+    }
+}
+
+impl fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ProtocolVersion::V1_0 => write!(f, "1.0"),
+            ProtocolVersion::V1_1 => write!(f, "1.1"),
+        }
+    }
+}
+
+
+/// How the peer was authenticated during the peer handshake.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum AuthMethod {
+    /// The peer proved its identity with the one-time auth token.
+    Token,
+    /// The peer permanent key was already trusted.
+    TrustedKey,
+}
+
+
+/// Why the initiator dropped a responder, see
+/// [`Event::ResponderDropped`](../enum.Event.html#variant.ResponderDropped).
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum ResponderDropReason {
+    /// The responder limit was exceeded.
+    PathFull,
+    /// The responder violated the protocol, e.g. with an invalid 'auth'
+    /// message.
+    ProtocolError,
+    /// A message of the responder could not be decrypted, usually because
+    /// it used the wrong auth token.
+    DecryptionFailed,
+    /// The responder did not complete the peer handshake in time.
+    Timeout,
+    /// The responder was rejected by the accept policy.
+    RejectedByPolicy,
+    /// The responder sent too many messages.
+    RateLimited,
+    /// The responder does not support any of our tasks.
+    NoSharedTask,
+    /// Another responder completed the peer handshake first.
+    LostRace,
+    /// The responder reconnected with a new address.
+    Reconnected,
+    /// The connection is being closed.
+    Closing,
+}
+
+impl ResponderDropReason {
+    /// Return the reason code sent in the 'drop-responder' message.
+    pub(crate) fn wire_reason(self) -> DropReason {
+        match self {
+            ResponderDropReason::ProtocolError => DropReason::ProtocolError,
+            ResponderDropReason::DecryptionFailed => DropReason::InitiatorCouldNotDecrypt,
+            _ => DropReason::DroppedByInitiator,
+        }
+    }
+}
+
+
+/// Information about the authenticated peer.
+///
+/// Applications that authenticated the peer with an auth token may store the
+/// `permanent_key` to start trusted sessions with the same peer later.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct PeerInfo {
+    /// The public permanent key of the peer.
+    pub permanent_key: PublicKey,
+    /// The address assigned to the peer by the server.
+    pub address: u8,
+    /// How the peer was authenticated.
+    pub auth_method: AuthMethod,
+}
+
+
+/// A peer to trust in future sessions, see
+/// [`SaltyClient::trust_peer`](../struct.SaltyClient.html#method.trust_peer).
+///
+/// Pass it to
+/// [`SaltyClientBuilder::with_trusted_peer`](../struct.SaltyClientBuilder.html#method.with_trusted_peer)
+/// to start a session with the same peer without an auth token. It can be
+/// stored with any serde format (keys are hex strings in human readable
+/// formats), or encrypted with the permanent key pair using the
+/// [`resumption`](../resumption/index.html) module.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct TrustedPeer {
+    /// The public permanent key of the peer.
+    #[serde(with = "serde_public_key")]
+    pub permanent_key: PublicKey,
+    /// Our role in the session with the peer.
+    pub role: Role,
+    /// The public permanent key of the server, if it was pinned.
+    #[serde(default, with = "serde_option_public_key")]
+    pub server_permanent_key: Option<PublicKey>,
+    /// The name of the task negotiated with the peer, if any. It is preferred
+    /// over the other tasks in the next session.
+    #[serde(default)]
+    pub task: Option<String>,
+}
+
+
 /// A peer identity.
 ///
 /// On the network level, this is encoded as a single unsigned byte.
@@ -108,7 +248,7 @@ impl fmt::Display for ClientIdentity {
 ///
 /// This is an unsigned byte like the [`Identity`](enum.Identity.html),
 /// but without any semantic information attached.
-#[derive(PartialEq, Eq, Copy, Clone, Hash)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Copy, Clone, Hash)]
 pub(crate) struct Address(pub(crate) u8);
 
 impl Address {
@@ -227,16 +367,150 @@ impl<'de> Deserialize<'de> for Address {
 pub(crate) enum HandleAction {
     /// Send the specified message through the websocket.
     Reply(ByteBox),
-    /// Raise an error during the handshake.
-    /// This is only needed when having to handle an error condition with a
-    /// message (e.g. the 'close' message on NoSharedTask).
-    HandshakeError(SaltyError),
     /// The server and peer handshake are done.
     HandshakeDone,
     /// An event happened.
     Event(Event),
     /// A task message was received and decoded.
     TaskMessage(TaskMessage),
+    /// Call `handle_timeout` with the timer id once the duration has
+    /// elapsed. A running timer with the same id is restarted.
+    StartTimer(TimerId, Duration),
+    /// The timer with the specified id is not needed anymore.
+    CancelTimer(TimerId),
+}
+
+
+/// The actions returned when an incoming message is handled.
+///
+/// The actions are kept in the order in which they were created. Replies
+/// must be sent in exactly this order: Each reply uses the next combined
+/// sequence number towards its receiver, and a `client-hello` must reach the
+/// server before the `client-auth` that follows it.
+///
+/// To prevent other messages from being interleaved with the replies, the
+/// connection layer should take them with
+/// [`split_replies`](#method.split_replies) and enqueue them as a single
+/// batch.
+#[must_use]
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct HandleActions(Vec<HandleAction>);
+
+impl HandleActions {
+    /// Create an empty list of actions.
+    pub(crate) fn new() -> Self {
+        HandleActions(vec![])
+    }
+
+    /// Append an action.
+    pub(crate) fn push(&mut self, action: HandleAction) {
+        self.0.push(action);
+    }
+
+    /// Append a reply.
+    pub(crate) fn push_reply(&mut self, bbox: ByteBox) {
+        self.0.push(HandleAction::Reply(bbox));
+    }
+
+    /// Append an event.
+    pub(crate) fn push_event(&mut self, event: Event) {
+        self.0.push(HandleAction::Event(event));
+    }
+
+    /// Append all actions of `other`, keeping their order.
+    pub(crate) fn merge(&mut self, other: HandleActions) {
+        self.0.extend(other.0);
+    }
+
+    /// Return the actions as a vector.
+    #[cfg(test)]
+    pub(crate) fn into_vec(self) -> Vec<HandleAction> {
+        self.0
+    }
+
+    /// Return an iterator over all actions.
+    pub(crate) fn iter(&self) -> impl Iterator<Item=&HandleAction> {
+        self.0.iter()
+    }
+
+    /// Return an iterator over the replies.
+    #[cfg(test)]
+    pub(crate) fn replies(&self) -> impl Iterator<Item=&ByteBox> {
+        self.0.iter().filter_map(|action| match action {
+            HandleAction::Reply(bbox) => Some(bbox),
+            _ => None,
+        })
+    }
+
+    /// Keep only the replies for which `keep` returns `true`.
+    pub(crate) fn retain_replies<F: FnMut(&ByteBox) -> bool>(&mut self, mut keep: F) {
+        self.0.retain(|action| match action {
+            HandleAction::Reply(bbox) => keep(bbox),
+            _ => true,
+        });
+    }
+
+    /// Split the actions into the replies and all other actions.
+    ///
+    /// Both lists keep the original order.
+    pub(crate) fn split_replies(self) -> (Vec<ByteBox>, Vec<HandleAction>) {
+        let mut replies = vec![];
+        let mut others = vec![];
+        for action in self.0 {
+            match action {
+                HandleAction::Reply(bbox) => replies.push(bbox),
+                other => others.push(other),
+            }
+        }
+        (replies, others)
+    }
+}
+
+impl From<HandleAction> for HandleActions {
+    fn from(action: HandleAction) -> Self {
+        HandleActions(vec![action])
+    }
+}
+
+impl From<Vec<HandleAction>> for HandleActions {
+    fn from(actions: Vec<HandleAction>) -> Self {
+        HandleActions(actions)
+    }
+}
+
+impl ::std::iter::FromIterator<HandleAction> for HandleActions {
+    fn from_iter<I: IntoIterator<Item=HandleAction>>(iter: I) -> Self {
+        HandleActions(iter.into_iter().collect())
+    }
+}
+
+impl Extend<HandleAction> for HandleActions {
+    fn extend<I: IntoIterator<Item=HandleAction>>(&mut self, iter: I) {
+        self.0.extend(iter);
+    }
+}
+
+impl IntoIterator for HandleActions {
+    type Item = HandleAction;
+    type IntoIter = ::std::vec::IntoIter<HandleAction>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl PartialEq<Vec<HandleAction>> for HandleActions {
+    fn eq(&self, other: &Vec<HandleAction>) -> bool {
+        &self.0 == other
+    }
+}
+
+impl ::std::ops::Deref for HandleActions {
+    type Target = [HandleAction];
+
+    fn deref(&self) -> &[HandleAction] {
+        &self.0
+    }
 }
 
 
@@ -263,6 +537,16 @@ mod tests {
         let _: Address = responder_invalid.into();
     }
 
+    #[test]
+    fn protocol_version() {
+        assert_eq!(ProtocolVersion::default(), ProtocolVersion::V1_1);
+        assert_eq!(ProtocolVersion::V1_0.subprotocol(), "v1.saltyrtc.org");
+        assert_eq!(ProtocolVersion::V1_1.subprotocol(), "v1.saltyrtc.org");
+        assert!(!ProtocolVersion::V1_0.supports_disconnected());
+        assert!(ProtocolVersion::V1_1.supports_disconnected());
+        assert_eq!(ProtocolVersion::V1_0.to_string(), "1.0");
+    }
+
     #[test]
     fn address_display() {
         assert_eq!(format!("{}", Address(0)), "0x00");
@@ -280,4 +564,51 @@ mod tests {
         assert_eq!(format!("{}", initiator), "initiator");
         assert_eq!(format!("{}", responder), "responder 0x0a");
     }
+
+    fn bbox(byte: u8) -> ByteBox {
+        ByteBox::from_slice(&[byte; 30]).unwrap()
+    }
+
+    #[test]
+    fn handle_actions_keep_order() {
+        let mut actions = HandleActions::new();
+        actions.push_reply(bbox(1));
+        actions.push_event(Event::ServerHandshakeDone(false));
+        let mut other = HandleActions::from(HandleAction::Reply(bbox(2)));
+        other.push(HandleAction::HandshakeDone);
+        actions.merge(other);
+        assert_eq!(actions.len(), 4);
+
+        let (replies, others) = actions.split_replies();
+        assert_eq!(replies, vec![bbox(1), bbox(2)]);
+        assert_eq!(others, vec![
+            HandleAction::Event(Event::ServerHandshakeDone(false)),
+            HandleAction::HandshakeDone,
+        ]);
+    }
+
+    #[test]
+    fn trusted_peer_serde() {
+        let peer = TrustedPeer {
+            permanent_key: PublicKey::from_slice(&[0xab; 32]).unwrap(),
+            role: Role::Initiator,
+            server_permanent_key: None,
+            task: Some("v1.dummy.tasks.saltyrtc.org".into()),
+        };
+        let json = serde_json::to_string(&peer).unwrap();
+        assert_eq!(json, format!(
+            "{{\"permanent_key\":\"{}\",\"role\":\"initiator\",\"server_permanent_key\":null,\"task\":\"{}\"}}",
+            "ab".repeat(32), "v1.dummy.tasks.saltyrtc.org",
+        ));
+        assert_eq!(serde_json::from_str::<TrustedPeer>(&json).unwrap(), peer);
+
+        // The task may be omitted
+        let json = format!(
+            "{{\"permanent_key\":\"{}\",\"role\":\"responder\",\"server_permanent_key\":\"{}\"}}",
+            "ab".repeat(32), "cd".repeat(32),
+        );
+        let server_permanent_key = PublicKey::from_slice(&[0xcd; 32]);
+        let peer = TrustedPeer { role: Role::Responder, server_permanent_key, task: None, ..peer };
+        assert_eq!(serde_json::from_str::<TrustedPeer>(&json).unwrap(), peer);
+    }
 }
@@ -0,0 +1,502 @@
+//! Proxy support for the server connection.
+//!
+//! The TCP connection to the SaltyRTC server can be tunneled through an HTTP
+//! proxy (using the `CONNECT` method) or through a SOCKS5 proxy. Once the
+//! tunnel is established, the TLS and WebSocket handshakes are done through
+//! it as if the connection had been made to the server directly.
+
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+
+use data_encoding::BASE64;
+use futures::Future;
+use futures::future::{self, Loop};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use tokio_io::io::{read_exact, write_all};
+
+use crate::BoxedFuture;
+use crate::errors::{SaltyError, SaltyResult};
+use crate::helpers::resolve;
+
+
+/// The maximum size of the response head sent by an HTTP proxy.
+const MAX_HTTP_RESPONSE_HEAD: usize = 8192;
+
+const SOCKS5_VERSION: u8 = 0x05;
+const SOCKS5_AUTH_NONE: u8 = 0x00;
+const SOCKS5_AUTH_PASSWORD: u8 = 0x02;
+const SOCKS5_AUTH_UNACCEPTABLE: u8 = 0xff;
+const SOCKS5_AUTH_PASSWORD_VERSION: u8 = 0x01;
+const SOCKS5_CMD_CONNECT: u8 = 0x01;
+const SOCKS5_ATYP_IPV4: u8 = 0x01;
+const SOCKS5_ATYP_DOMAIN: u8 = 0x03;
+const SOCKS5_ATYP_IPV6: u8 = 0x04;
+
+
+/// The protocol spoken by a proxy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyProtocol {
+    /// An HTTP proxy supporting the `CONNECT` method.
+    Http,
+    /// A SOCKS5 proxy.
+    Socks5,
+}
+
+/// The configuration of a proxy that is used to reach the SaltyRTC server.
+///
+/// Pass it to
+/// [`SaltyClientBuilder::with_proxy`](struct.SaltyClientBuilder.html#method.with_proxy).
+#[derive(Clone, PartialEq, Eq)]
+pub struct ProxyConfig {
+    protocol: ProxyProtocol,
+    host: String,
+    port: u16,
+    credentials: Option<(String, String)>,
+}
+
+impl ProxyConfig {
+    /// Use an HTTP proxy.
+    pub fn http<H: Into<String>>(host: H, port: u16) -> Self {
+        Self::new(ProxyProtocol::Http, host.into(), port)
+    }
+
+    /// Use a SOCKS5 proxy.
+    pub fn socks5<H: Into<String>>(host: H, port: u16) -> Self {
+        Self::new(ProxyProtocol::Socks5, host.into(), port)
+    }
+
+    fn new(protocol: ProxyProtocol, host: String, port: u16) -> Self {
+        ProxyConfig { protocol, host, port, credentials: None }
+    }
+
+    /// Authenticate towards the proxy with the specified username and password.
+    ///
+    /// HTTP proxies receive the credentials using basic authentication,
+    /// SOCKS5 proxies using the username/password method (RFC 1929).
+    pub fn with_credentials<U, P>(mut self, username: U, password: P) -> Self
+        where U: Into<String>, P: Into<String>
+    {
+        self.credentials = Some((username.into(), password.into()));
+        self
+    }
+
+    /// Return the proxy protocol.
+    pub fn protocol(&self) -> ProxyProtocol {
+        self.protocol
+    }
+
+    /// Return the proxy host.
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Return the proxy port.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+/// Implementation required to avoid logging the proxy password.
+impl fmt::Debug for ProxyConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ProxyConfig")
+            .field("protocol", &self.protocol)
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("username", &self.credentials.as_ref().map(|(user, _)| user))
+            .finish()
+    }
+}
+
+
+/// Open a TCP connection to `host:port` through the specified proxy.
+///
+/// The returned stream is connected to the target host.
+pub(crate) fn connect(
+    proxy: &ProxyConfig,
+    host: &str,
+    port: u16,
+    handle: &Handle,
+) -> BoxedFuture<TcpStream, SaltyError> {
+    let proxy_name = format!("{}:{}", proxy.host, proxy.port);
+    let addr = match resolve(&proxy.host, proxy.port) {
+        Ok(addr) => addr,
+        Err(e) => return Box::new(future::err(e)),
+    };
+    debug!("Connecting to {:?} proxy at {}", proxy.protocol, proxy_name);
+
+    let io_error = {
+        let proxy_name = proxy_name.clone();
+        move |e: io::Error| SaltyError::Network(format!("Could not communicate with proxy ({}): {}", proxy_name, e))
+    };
+    let tcp_stream = TcpStream::connect(&addr, handle)
+        .map_err(move |e| SaltyError::Network(format!("Could not connect to proxy ({}): {}", proxy_name, e)));
+
+    let host = host.to_string();
+    let credentials = proxy.credentials.clone();
+    match proxy.protocol {
+        ProxyProtocol::Http => Box::new(tcp_stream.and_then(move |stream| {
+            let request = http_connect_request(&host, port, credentials.as_ref());
+            write_all(stream, request)
+                .and_then(|(stream, _)| read_http_response_head(stream))
+                .map_err(io_error)
+                .and_then(|(stream, head)| {
+                    check_http_connect_response(&head)?;
+                    trace!("HTTP proxy tunnel established");
+                    Ok(stream)
+                })
+        })),
+        ProxyProtocol::Socks5 => {
+            let request = match socks5_connect_request(&host, port) {
+                Ok(request) => request,
+                Err(e) => return Box::new(future::err(e)),
+            };
+            Box::new(tcp_stream.and_then(move |stream| {
+                socks5_authenticate(stream, credentials, io_error.clone())
+                    .and_then(move |stream| {
+                        write_all(stream, request)
+                            .and_then(|(stream, _)| read_exact(stream, [0u8; 4]))
+                            .map_err(io_error.clone())
+                            .and_then(move |(stream, reply)| {
+                                check_socks5_reply(&reply)?;
+                                Ok((stream, reply[3]))
+                            })
+                            .and_then(move |(stream, atyp)| skip_socks5_address(stream, atyp, io_error))
+                    })
+                    .map(|stream| {
+                        trace!("SOCKS5 proxy tunnel established");
+                        stream
+                    })
+            }))
+        },
+    }
+}
+
+/// Create an HTTP `CONNECT` request.
+fn http_connect_request(host: &str, port: u16, credentials: Option<&(String, String)>) -> Vec<u8> {
+    let mut request = format!("CONNECT {0}:{1} HTTP/1.1\r\nHost: {0}:{1}\r\n", host, port);
+    if let Some((username, password)) = credentials {
+        let encoded = BASE64.encode(format!("{}:{}", username, password).as_bytes());
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", encoded));
+    }
+    request.push_str("\r\n");
+    request.into_bytes()
+}
+
+/// Read the response head (status line and headers) of an HTTP proxy.
+///
+/// The stream is read byte by byte to avoid consuming any data that already
+/// belongs to the tunneled connection.
+fn read_http_response_head(stream: TcpStream) -> impl Future<Item=(TcpStream, Vec<u8>), Error=io::Error> {
+    future::loop_fn((stream, Vec::new()), |(stream, mut head)| {
+        read_exact(stream, [0u8; 1]).and_then(move |(stream, byte)| {
+            head.push(byte[0]);
+            if head.ends_with(b"\r\n\r\n") {
+                Ok(Loop::Break((stream, head)))
+            } else if head.len() >= MAX_HTTP_RESPONSE_HEAD {
+                Err(io::Error::new(io::ErrorKind::InvalidData, "Response head too large"))
+            } else {
+                Ok(Loop::Continue((stream, head)))
+            }
+        })
+    })
+}
+
+/// Validate the response head of an HTTP proxy.
+fn check_http_connect_response(head: &[u8]) -> SaltyResult<()> {
+    let head = String::from_utf8_lossy(head);
+    let status_line = head.lines().next().unwrap_or("");
+    let mut parts = status_line.splitn(3, ' ');
+    let version = parts.next().unwrap_or("");
+    let status = parts.next().and_then(|status| status.parse::<u16>().ok());
+    match status {
+        _ if !version.starts_with("HTTP/") => Err(SaltyError::Network(
+            format!("Invalid response from HTTP proxy: {:?}", status_line)
+        )),
+        Some(200..=299) => Ok(()),
+        Some(407) => Err(SaltyError::Network("HTTP proxy requires authentication".into())),
+        Some(_) => Err(SaltyError::Network(format!("HTTP proxy refused connection: {}", status_line))),
+        None => Err(SaltyError::Network(format!("Invalid response from HTTP proxy: {:?}", status_line))),
+    }
+}
+
+/// Create the SOCKS5 greeting, announcing the supported auth methods.
+fn socks5_greeting(with_credentials: bool) -> Vec<u8> {
+    if with_credentials {
+        vec![SOCKS5_VERSION, 2, SOCKS5_AUTH_NONE, SOCKS5_AUTH_PASSWORD]
+    } else {
+        vec![SOCKS5_VERSION, 1, SOCKS5_AUTH_NONE]
+    }
+}
+
+/// Create a SOCKS5 username/password auth request.
+fn socks5_password_request(username: &str, password: &str) -> SaltyResult<Vec<u8>> {
+    if username.len() > 255 || password.len() > 255 {
+        return Err(SaltyError::Network("SOCKS5 username and password may not be longer than 255 bytes".into()));
+    }
+    let mut request = Vec::with_capacity(3 + username.len() + password.len());
+    request.push(SOCKS5_AUTH_PASSWORD_VERSION);
+    request.push(username.len() as u8);
+    request.extend_from_slice(username.as_bytes());
+    request.push(password.len() as u8);
+    request.extend_from_slice(password.as_bytes());
+    Ok(request)
+}
+
+/// Create a SOCKS5 connect request.
+fn socks5_connect_request(host: &str, port: u16) -> SaltyResult<Vec<u8>> {
+    let mut request = vec![SOCKS5_VERSION, SOCKS5_CMD_CONNECT, 0x00];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(SOCKS5_ATYP_IPV4);
+            request.extend_from_slice(&ip.octets());
+        },
+        Ok(IpAddr::V6(ip)) => {
+            request.push(SOCKS5_ATYP_IPV6);
+            request.extend_from_slice(&ip.octets());
+        },
+        Err(_) => {
+            if host.len() > 255 {
+                return Err(SaltyError::Network("Host name too long for SOCKS5 proxy".into()));
+            }
+            request.push(SOCKS5_ATYP_DOMAIN);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+        },
+    }
+    request.push((port >> 8) as u8);
+    request.push(port as u8);
+    Ok(request)
+}
+
+/// Negotiate the auth method with a SOCKS5 proxy and authenticate if required.
+fn socks5_authenticate<E>(
+    stream: TcpStream,
+    credentials: Option<(String, String)>,
+    io_error: E,
+) -> BoxedFuture<TcpStream, SaltyError>
+    where E: Fn(io::Error) -> SaltyError + Clone + 'static
+{
+    let greeting = socks5_greeting(credentials.is_some());
+    let future = write_all(stream, greeting)
+        .and_then(|(stream, _)| read_exact(stream, [0u8; 2]))
+        .map_err(io_error.clone())
+        .and_then(move |(stream, reply)| -> BoxedFuture<TcpStream, SaltyError> {
+            if reply[0] != SOCKS5_VERSION {
+                return Box::new(future::err(SaltyError::Network("Invalid reply from SOCKS5 proxy".into())));
+            }
+            match (reply[1], credentials) {
+                (SOCKS5_AUTH_NONE, _) => Box::new(future::ok(stream)),
+                (SOCKS5_AUTH_PASSWORD, Some((username, password))) => {
+                    let request = match socks5_password_request(&username, &password) {
+                        Ok(request) => request,
+                        Err(e) => return Box::new(future::err(e)),
+                    };
+                    Box::new(write_all(stream, request)
+                        .and_then(|(stream, _)| read_exact(stream, [0u8; 2]))
+                        .map_err(io_error)
+                        .and_then(|(stream, reply)| match reply[1] {
+                            0x00 => Ok(stream),
+                            _ => Err(SaltyError::Network("SOCKS5 proxy rejected credentials".into())),
+                        }))
+                },
+                (SOCKS5_AUTH_UNACCEPTABLE, _) => Box::new(future::err(
+                    SaltyError::Network("SOCKS5 proxy did not accept any auth method".into())
+                )),
+                (method, _) => Box::new(future::err(
+                    SaltyError::Network(format!("SOCKS5 proxy chose unsupported auth method {}", method))
+                )),
+            }
+        });
+    Box::new(future)
+}
+
+/// Validate the first four bytes of a SOCKS5 connect reply.
+fn check_socks5_reply(reply: &[u8; 4]) -> SaltyResult<()> {
+    if reply[0] != SOCKS5_VERSION {
+        return Err(SaltyError::Network("Invalid reply from SOCKS5 proxy".into()));
+    }
+    let reason = match reply[1] {
+        0x00 => return Ok(()),
+        0x01 => "general failure",
+        0x02 => "connection not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown error",
+    };
+    Err(SaltyError::Network(format!("SOCKS5 proxy refused connection: {}", reason)))
+}
+
+/// Skip the bound address and port at the end of a SOCKS5 connect reply.
+fn skip_socks5_address<E>(stream: TcpStream, atyp: u8, io_error: E) -> BoxedFuture<TcpStream, SaltyError>
+    where E: Fn(io::Error) -> SaltyError + 'static
+{
+    let length = match atyp {
+        SOCKS5_ATYP_IPV4 => 4 + 2,
+        SOCKS5_ATYP_IPV6 => 16 + 2,
+        SOCKS5_ATYP_DOMAIN => {
+            let future = read_exact(stream, [0u8; 1])
+                .and_then(|(stream, len)| read_exact(stream, vec![0u8; len[0] as usize + 2]))
+                .map(|(stream, _)| stream)
+                .map_err(io_error);
+            return Box::new(future);
+        },
+        _ => return Box::new(future::err(
+            SaltyError::Network(format!("Invalid address type in SOCKS5 reply: {}", atyp))
+        )),
+    };
+    Box::new(read_exact(stream, vec![0u8; length]).map(|(stream, _)| stream).map_err(io_error))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use tokio_core::reactor::Core;
+
+    use super::*;
+
+    #[test]
+    fn http_request() {
+        let request = http_connect_request("server.example", 443, None);
+        assert_eq!(
+            request,
+            b"CONNECT server.example:443 HTTP/1.1\r\nHost: server.example:443\r\n\r\n".to_vec(),
+        );
+
+        let credentials = ("user".to_string(), "pass".to_string());
+        let request = http_connect_request("server.example", 443, Some(&credentials));
+        assert_eq!(
+            request,
+            b"CONNECT server.example:443 HTTP/1.1\r\nHost: server.example:443\r\n\
+              Proxy-Authorization: Basic dXNlcjpwYXNz\r\n\r\n".to_vec(),
+        );
+    }
+
+    #[test]
+    fn http_response() {
+        assert_eq!(check_http_connect_response(b"HTTP/1.1 200 Connection established\r\n\r\n"), Ok(()));
+        assert_eq!(check_http_connect_response(b"HTTP/1.0 200\r\n\r\n"), Ok(()));
+        assert_eq!(
+            check_http_connect_response(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n"),
+            Err(SaltyError::Network("HTTP proxy requires authentication".into())),
+        );
+        assert_eq!(
+            check_http_connect_response(b"HTTP/1.1 403 Forbidden\r\n\r\n"),
+            Err(SaltyError::Network("HTTP proxy refused connection: HTTP/1.1 403 Forbidden".into())),
+        );
+        assert!(check_http_connect_response(b"SSH-2.0-OpenSSH\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn socks5_requests() {
+        assert_eq!(socks5_greeting(false), vec![5, 1, 0]);
+        assert_eq!(socks5_greeting(true), vec![5, 2, 0, 2]);
+        assert_eq!(socks5_password_request("ab", "c").unwrap(), vec![1, 2, b'a', b'b', 1, b'c']);
+        assert_eq!(
+            socks5_connect_request("10.0.0.1", 443).unwrap(),
+            vec![5, 1, 0, 1, 10, 0, 0, 1, 0x01, 0xbb],
+        );
+        assert_eq!(
+            socks5_connect_request("a.ch", 8765).unwrap(),
+            vec![5, 1, 0, 3, 4, b'a', b'.', b'c', b'h', 0x22, 0x3d],
+        );
+        assert!(socks5_connect_request(&"a".repeat(256), 443).is_err());
+    }
+
+    #[test]
+    fn socks5_reply() {
+        assert_eq!(check_socks5_reply(&[5, 0, 0, 1]), Ok(()));
+        assert_eq!(
+            check_socks5_reply(&[5, 5, 0, 1]),
+            Err(SaltyError::Network("SOCKS5 proxy refused connection: connection refused".into())),
+        );
+        assert!(check_socks5_reply(&[4, 0, 0, 1]).is_err());
+    }
+
+    #[test]
+    fn debug_hides_password() {
+        let config = ProxyConfig::http("proxy", 3128).with_credentials("user", "secret");
+        let debug = format!("{:?}", config);
+        assert!(debug.contains("user"));
+        assert!(!debug.contains("secret"));
+    }
+
+    /// Run a fake proxy that handles a single connection with the specified
+    /// function and then sends "hello" through the tunnel.
+    fn run_proxy<F>(handler: F) -> u16 where F: FnOnce(&mut std::net::TcpStream) + Send + 'static {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            handler(&mut stream);
+            let _ = stream.write_all(b"hello");
+        });
+        port
+    }
+
+    /// Connect through the proxy and return the first bytes from the tunnel.
+    fn read_tunnel(proxy: ProxyConfig) -> SaltyResult<[u8; 5]> {
+        let mut core = Core::new().unwrap();
+        let future = connect(&proxy, "server.example", 443, &core.handle())
+            .and_then(|stream| read_exact(stream, [0u8; 5]).map_err(|e| SaltyError::Network(e.to_string())))
+            .map(|(_, bytes)| bytes);
+        core.run(future)
+    }
+
+    #[test]
+    fn http_tunnel() {
+        let port = run_proxy(|stream| {
+            let mut buf = [0u8; 256];
+            let n = stream.read(&mut buf).unwrap();
+            assert!(buf[..n].starts_with(b"CONNECT server.example:443 HTTP/1.1\r\n"));
+            stream.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").unwrap();
+        });
+        assert_eq!(read_tunnel(ProxyConfig::http("127.0.0.1", port)).unwrap(), *b"hello");
+    }
+
+    #[test]
+    fn socks5_tunnel() {
+        let port = run_proxy(|stream| {
+            let mut greeting = [0u8; 4];
+            stream.read_exact(&mut greeting).unwrap();
+            assert_eq!(greeting, [5, 2, 0, 2]);
+            stream.write_all(&[5, 2]).unwrap();
+            let mut auth = [0u8; 7];
+            stream.read_exact(&mut auth).unwrap();
+            assert_eq!(&auth, &[1, 2, b'u', b'1', 2, b'p', b'1']);
+            stream.write_all(&[1, 0]).unwrap();
+            let mut request = vec![0u8; 5 + "server.example".len() + 2];
+            stream.read_exact(&mut request).unwrap();
+            assert_eq!(socks5_connect_request("server.example", 443).unwrap(), request);
+            stream.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0x12, 0x34]).unwrap();
+        });
+        let proxy = ProxyConfig::socks5("127.0.0.1", port).with_credentials("u1", "p1");
+        assert_eq!(read_tunnel(proxy).unwrap(), *b"hello");
+    }
+
+    #[test]
+    fn socks5_refused() {
+        let port = run_proxy(|stream| {
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).unwrap();
+            stream.write_all(&[5, 0]).unwrap();
+            let mut request = vec![0u8; 5 + "server.example".len() + 2];
+            stream.read_exact(&mut request).unwrap();
+            stream.write_all(&[5, 2, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+        });
+        assert_eq!(
+            read_tunnel(ProxyConfig::socks5("127.0.0.1", port)),
+            Err(SaltyError::Network("SOCKS5 proxy refused connection: connection not allowed by ruleset".into())),
+        );
+    }
+}
@@ -0,0 +1,155 @@
+//! Capture the HTTP status of the WebSocket handshake response.
+//!
+//! When the server does not upgrade the connection (e.g. because it rejects
+//! the path), the `websocket` crate only reports that the status code wasn't
+//! `101 Switching Protocols`. To tell the user what the server actually
+//! answered, the TLS stream is wrapped in a [`ResponseTap`](struct.ResponseTap.html)
+//! that remembers the first bytes received from the server.
+
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+
+use futures::Poll;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+#[cfg(feature = "permessage-deflate")]
+use crate::deflate::Inflater;
+
+
+/// The number of bytes needed for the status code, e.g. `HTTP/1.1 404`.
+const STATUS_LINE_PREFIX: usize = 12;
+
+
+/// A stream that records the start of the data read from it.
+///
+/// Once enough bytes have been recorded, reads and writes are passed
+/// through without any overhead apart from a flag check.
+#[derive(Debug)]
+pub struct ResponseTap<S> {
+    inner: S,
+    head: Arc<Mutex<Vec<u8>>>,
+    done: bool,
+    /// Decompresses messages, if permessage-deflate is offered.
+    #[cfg(feature = "permessage-deflate")]
+    inflater: Option<Inflater>,
+}
+
+impl<S> ResponseTap<S> {
+    /// Wrap the stream. The recorded bytes are appended to `head`.
+    pub(crate) fn new(inner: S, head: Arc<Mutex<Vec<u8>>>) -> Self {
+        ResponseTap {
+            inner,
+            head,
+            done: false,
+            #[cfg(feature = "permessage-deflate")]
+            inflater: None,
+        }
+    }
+
+    /// Decompress messages from the server if it accepts the
+    /// permessage-deflate extension.
+    #[cfg(feature = "permessage-deflate")]
+    pub(crate) fn inflating(mut self) -> Self {
+        self.inflater = Some(Inflater::new());
+        self
+    }
+
+    fn record(&mut self, bytes: &[u8]) {
+        let mut head = match self.head.lock() {
+            Ok(head) => head,
+            Err(_) => {
+                self.done = true;
+                return;
+            },
+        };
+        let missing = STATUS_LINE_PREFIX.saturating_sub(head.len());
+        head.extend_from_slice(&bytes[..missing.min(bytes.len())]);
+        if head.len() >= STATUS_LINE_PREFIX || bytes.is_empty() {
+            self.done = true;
+        }
+    }
+}
+
+impl<S: Read> Read for ResponseTap<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        #[cfg(feature = "permessage-deflate")]
+        let n = match self.inflater {
+            Some(ref mut inflater) => inflater.read(&mut self.inner, buf)?,
+            None => self.inner.read(buf)?,
+        };
+        #[cfg(not(feature = "permessage-deflate"))]
+        let n = self.inner.read(buf)?;
+        if !self.done {
+            self.record(&buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for ResponseTap<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for ResponseTap<S> {}
+
+impl<S: AsyncWrite> AsyncWrite for ResponseTap<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.inner.shutdown()
+    }
+}
+
+/// Parse the status code from the start of an HTTP response.
+pub(crate) fn http_status(head: &[u8]) -> Option<u16> {
+    let head = std::str::from_utf8(head).ok()?;
+    let mut parts = head.splitn(3, ' ');
+    if !parts.next()?.starts_with("HTTP/") {
+        return None;
+    }
+    let status = parts.next()?;
+    if status.len() != 3 {
+        return None;
+    }
+    status.parse().ok()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_status_line_prefix() {
+        let head = Arc::new(Mutex::new(vec![]));
+        let response = &b"HTTP/1.1 404 Not Found\r\n\r\n"[..];
+        let mut tap = ResponseTap::new(response, Arc::clone(&head));
+
+        // Read in small chunks to check that the prefix is assembled
+        let mut buf = [0; 5];
+        let mut received = vec![];
+        loop {
+            let n = tap.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            received.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(received, response);
+        assert_eq!(&*head.lock().unwrap(), b"HTTP/1.1 404");
+        assert_eq!(http_status(&head.lock().unwrap()), Some(404));
+    }
+
+    #[test]
+    fn parse_http_status() {
+        assert_eq!(http_status(b"HTTP/1.1 400"), Some(400));
+        assert_eq!(http_status(b"HTTP/1.0 101 Switching Protocols"), Some(101));
+        assert_eq!(http_status(b"HTTP/1.1 40"), None);
+        assert_eq!(http_status(b"SSH-2.0-OpenSSH"), None);
+        assert_eq!(http_status(b""), None);
+    }
+}
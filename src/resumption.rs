@@ -0,0 +1,195 @@
+//! Encrypted resumption tokens to reconnect to a known peer (experimental).
+//!
+//! After a session with a peer ended cleanly,
+//! [`SaltyClient::export_resumption`](../struct.SaltyClient.html#method.export_resumption)
+//! returns a blob with everything needed to start a trusted session with the
+//! same peer: the [`TrustedPeer`](../struct.TrustedPeer.html) returned by
+//! [`SaltyClient::trust_peer`](../struct.SaltyClient.html#method.trust_peer),
+//! encrypted with our permanent key pair. Passing the blob to
+//! [`SaltyClientBuilder::resume`](../struct.SaltyClientBuilder.html#method.resume)
+//! creates a client that skips the token exchange and prefers the same task.
+//!
+//! Session keys and cookies are not part of the blob, since the protocol
+//! requires fresh ones for every session.
+//!
+//! The blob is encrypted with `crypto_box` from our permanent key to itself,
+//! so it can only be read with the same permanent key pair:
+//!
+//! ```text
+//! | "SALTYRES" | version (1) | nonce (24) | ciphertext |
+//! ```
+//!
+//! The plaintext consists of the role (1 byte, 0 for initiators), the 32 byte
+//! public permanent key of the peer, a flag byte followed by the 32 byte
+//! server key if the flag is 1, and the UTF-8 encoded task name (empty if
+//! there is none).
+//!
+//! This module is only available with the `resumption` feature. The format
+//! may change in future releases.
+
+use crate::crypto_backend::{self, backend, memzero, KEYBYTES, NONCEBYTES};
+use crate::crypto_types::{KeyPair, PublicKey};
+use crate::errors::ResumptionError;
+use crate::protocol::{Role, TrustedPeer};
+
+
+/// The magic bytes at the start of every resumption token.
+const MAGIC: &[u8; 8] = b"SALTYRES";
+
+/// The current resumption token format version.
+const VERSION: u8 = 1;
+
+/// The length of the unencrypted header.
+const HEADER_BYTES: usize = MAGIC.len() + 1 + NONCEBYTES;
+
+
+impl TrustedPeer {
+    /// Encrypt the peer as a resumption token for the owner of the
+    /// permanent key pair.
+    pub fn to_encrypted_bytes(&self, permanent_keypair: &KeyPair) -> Result<Vec<u8>, ResumptionError> {
+        crypto_backend::init().map_err(|e| ResumptionError::Crypto(e.to_string()))?;
+
+        let task = self.task.as_ref().map(String::as_str).unwrap_or("");
+        let mut plaintext = Vec::with_capacity(2 * (1 + KEYBYTES) + task.len());
+        plaintext.push(match self.role {
+            Role::Initiator => 0,
+            Role::Responder => 1,
+        });
+        plaintext.extend_from_slice(&self.permanent_key.0);
+        match self.server_permanent_key {
+            Some(ref key) => {
+                plaintext.push(1);
+                plaintext.extend_from_slice(&key.0);
+            },
+            None => plaintext.push(0),
+        }
+        plaintext.extend_from_slice(task.as_bytes());
+
+        let mut nonce = [0u8; NONCEBYTES];
+        backend().random_bytes(&mut nonce);
+        let ciphertext = crypto_backend::box_seal(
+            &plaintext, &nonce, permanent_keypair.public_key(), permanent_keypair.private_key(),
+        );
+        memzero(&mut plaintext);
+
+        let mut bytes = Vec::with_capacity(HEADER_BYTES + ciphertext.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        bytes.extend_from_slice(&nonce);
+        bytes.extend_from_slice(&ciphertext);
+        Ok(bytes)
+    }
+
+    /// Decrypt a resumption token that was encrypted with
+    /// [`to_encrypted_bytes`](#method.to_encrypted_bytes).
+    pub fn from_encrypted_bytes(bytes: &[u8], permanent_keypair: &KeyPair) -> Result<Self, ResumptionError> {
+        crypto_backend::init().map_err(|e| ResumptionError::Crypto(e.to_string()))?;
+
+        if bytes.len() < HEADER_BYTES || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(ResumptionError::Format("Not a resumption token".into()));
+        }
+        let version = bytes[MAGIC.len()];
+        if version != VERSION {
+            return Err(ResumptionError::Format(format!("Unsupported resumption token version: {}", version)));
+        }
+        let (nonce_bytes, ciphertext) = bytes[MAGIC.len() + 1..].split_at(NONCEBYTES);
+        let mut nonce = [0u8; NONCEBYTES];
+        nonce.copy_from_slice(nonce_bytes);
+
+        let mut plaintext = crypto_backend::box_open(
+            ciphertext, &nonce, permanent_keypair.public_key(), permanent_keypair.private_key(),
+        ).map_err(|_| ResumptionError::Decrypt)?;
+        let result = Self::from_plaintext(&plaintext);
+        memzero(&mut plaintext);
+        result
+    }
+
+    /// Decode the decrypted token payload.
+    fn from_plaintext(plaintext: &[u8]) -> Result<Self, ResumptionError> {
+        let invalid_length = || ResumptionError::Format(format!("Invalid payload length: {}", plaintext.len()));
+        if plaintext.len() < 2 + KEYBYTES {
+            return Err(invalid_length());
+        }
+        let role = match plaintext[0] {
+            0 => Role::Initiator,
+            1 => Role::Responder,
+            other => return Err(ResumptionError::Format(format!("Invalid role: {}", other))),
+        };
+        let (peer_key_bytes, rest) = plaintext[1..].split_at(KEYBYTES);
+        let permanent_key = PublicKey::from_slice(peer_key_bytes)
+            .ok_or_else(|| ResumptionError::Format("Invalid peer key".into()))?;
+        let (server_permanent_key, task_bytes) = match rest[0] {
+            0 => (None, &rest[1..]),
+            1 if rest.len() > KEYBYTES => {
+                let (server_key_bytes, task_bytes) = rest[1..].split_at(KEYBYTES);
+                let key = PublicKey::from_slice(server_key_bytes)
+                    .ok_or_else(|| ResumptionError::Format("Invalid server key".into()))?;
+                (Some(key), task_bytes)
+            },
+            1 => return Err(invalid_length()),
+            other => return Err(ResumptionError::Format(format!("Invalid server key flag: {}", other))),
+        };
+        let task = String::from_utf8(task_bytes.to_vec())
+            .map_err(|_| ResumptionError::Format("Task name is not valid UTF-8".into()))?;
+        let task = if task.is_empty() { None } else { Some(task) };
+        Ok(TrustedPeer { permanent_key, role, server_permanent_key, task })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(server_permanent_key: Option<PublicKey>, task: Option<&str>) -> TrustedPeer {
+        TrustedPeer {
+            permanent_key: *KeyPair::new().public_key(),
+            role: Role::Responder,
+            server_permanent_key,
+            task: task.map(String::from),
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let keypair = KeyPair::new();
+        let server_key = Some(*KeyPair::new().public_key());
+        for token in &[token(None, None), token(server_key, Some("v1.dummy.tasks.saltyrtc.org"))] {
+            let bytes = token.to_encrypted_bytes(&keypair).unwrap();
+            assert_eq!(&bytes[..8], b"SALTYRES");
+            assert_eq!(&TrustedPeer::from_encrypted_bytes(&bytes, &keypair).unwrap(), token);
+        }
+    }
+
+    #[test]
+    fn wrong_keypair() {
+        let bytes = token(None, None).to_encrypted_bytes(&KeyPair::new()).unwrap();
+        assert_eq!(TrustedPeer::from_encrypted_bytes(&bytes, &KeyPair::new()), Err(ResumptionError::Decrypt));
+    }
+
+    #[test]
+    fn invalid_format() {
+        let keypair = KeyPair::new();
+        let mut bytes = token(None, None).to_encrypted_bytes(&keypair).unwrap();
+        assert_eq!(
+            TrustedPeer::from_encrypted_bytes(&bytes[..HEADER_BYTES - 1], &keypair),
+            Err(ResumptionError::Format("Not a resumption token".into())),
+        );
+        bytes[8] = 2;
+        assert_eq!(
+            TrustedPeer::from_encrypted_bytes(&bytes, &keypair),
+            Err(ResumptionError::Format("Unsupported resumption token version: 2".into())),
+        );
+    }
+
+    #[test]
+    fn truncated_server_key() {
+        let mut plaintext = vec![0];
+        plaintext.extend_from_slice(&KeyPair::new().public_key().0);
+        plaintext.extend_from_slice(&[1, 0xff, 0xff]);
+        assert_eq!(
+            TrustedPeer::from_plaintext(&plaintext),
+            Err(ResumptionError::Format("Invalid payload length: 36".into())),
+        );
+    }
+}
@@ -0,0 +1,304 @@
+//! Resources shared by many clients in one process.
+//!
+//! Applications that pair with many peers at once, e.g. bots or relays,
+//! create one [`SharedResources`](struct.SharedResources.html) instance and
+//! pass it to every client, either with
+//! [`SaltyClientBuilder::with_shared_resources`](../struct.SaltyClientBuilder.html#method.with_shared_resources)
+//! or for all clients of a
+//! [`SaltyClientPool`](../struct.SaltyClientPool.html). The clients then
+//! share the TLS connector, cached DNS lookups and the decryption thread
+//! pool, instead of creating their own for every connection. Optionally,
+//! the number of connections that connect to the server and do the server
+//! handshake at the same time is capped.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::Future;
+use futures::future;
+use futures::sync::oneshot;
+use native_tls::TlsConnector;
+use tokio_threadpool::{Builder as ThreadPoolBuilder, ThreadPool};
+
+use crate::{BoxedFuture, TlsConfig};
+use crate::errors::{SaltyError, SaltyResult};
+use crate::helpers::resolve;
+
+
+/// How long a resolved server address is reused.
+const DNS_CACHE_TTL: Duration = Duration::from_secs(300);
+
+
+/// A TLS connector, a DNS cache, a decryption thread pool and a limit for
+/// concurrent handshakes, shared by many clients.
+///
+/// Cloning is cheap, all clones share the same resources.
+#[derive(Clone)]
+pub struct SharedResources {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    tls_connector: TlsConnector,
+    pinned_fingerprints: Vec<[u8; 32]>,
+    dns_cache: Mutex<HashMap<(String, u16), (SocketAddr, Instant)>>,
+    decryption_pool: Option<(Arc<ThreadPool>, usize)>,
+    handshake_limit: Option<Arc<HandshakeLimit>>,
+}
+
+impl SharedResources {
+    /// Create the shared resources.
+    ///
+    /// The TLS connector is built once from the TLS configuration, or with
+    /// the system defaults. Clients with their own TLS configuration don't
+    /// use it.
+    pub fn new(tls_config: Option<TlsConfig>) -> SaltyResult<Self> {
+        let tls_connector = match tls_config {
+            Some(ref config) => config.connector()?,
+            None => TlsConnector::new()
+                .map_err(|e| SaltyError::Crypto(format!("Could not create TLS connector: {}", e)))?,
+        };
+        let pinned_fingerprints = tls_config
+            .map(|config| config.pinned_fingerprints().to_vec())
+            .unwrap_or_default();
+        Ok(SharedResources {
+            inner: Arc::new(Inner {
+                tls_connector,
+                pinned_fingerprints,
+                dns_cache: Mutex::new(HashMap::new()),
+                decryption_pool: None,
+                handshake_limit: None,
+            }),
+        })
+    }
+
+    /// Decrypt the incoming task messages of all clients on one pool of
+    /// `workers` threads.
+    ///
+    /// This overrides the number of decryption workers of the clients. If
+    /// `workers` is 0, every client decrypts as configured in its builder.
+    ///
+    /// Must be called before the resources are cloned or passed to a client.
+    pub fn with_decryption_workers(mut self, workers: usize) -> Self {
+        self.inner_mut().decryption_pool = match workers {
+            0 => None,
+            n => Some((Arc::new(ThreadPoolBuilder::new().pool_size(n).build()), n)),
+        };
+        self
+    }
+
+    /// Allow at most `max` clients to connect to the server and do the
+    /// server handshake at the same time.
+    ///
+    /// Further clients wait before opening the connection, until one of the
+    /// running handshakes has finished or failed. By default, the number of
+    /// concurrent handshakes is not limited.
+    ///
+    /// Must be called before the resources are cloned or passed to a client.
+    pub fn with_max_concurrent_handshakes(mut self, max: usize) -> Self {
+        assert!(max > 0, "The number of concurrent handshakes must not be 0");
+        self.inner_mut().handshake_limit = Some(Arc::new(HandshakeLimit::new(max)));
+        self
+    }
+
+    fn inner_mut(&mut self) -> &mut Inner {
+        Arc::get_mut(&mut self.inner)
+            .expect("Shared resources must be configured before they are shared")
+    }
+
+    /// Return the shared TLS connector.
+    pub(crate) fn tls_connector(&self) -> TlsConnector {
+        self.inner.tls_connector.clone()
+    }
+
+    /// Return the certificate fingerprints pinned in the TLS configuration.
+    pub(crate) fn pinned_fingerprints(&self) -> &[[u8; 32]] {
+        &self.inner.pinned_fingerprints
+    }
+
+    /// Resolve the server address, reusing a recent result.
+    pub(crate) fn resolve(&self, host: &str, port: u16) -> SaltyResult<SocketAddr> {
+        let key = (host.to_string(), port);
+        let now = Instant::now();
+        if let Ok(cache) = self.inner.dns_cache.lock() {
+            if let Some(&(addr, resolved_at)) = cache.get(&key) {
+                if now.duration_since(resolved_at) < DNS_CACHE_TTL {
+                    trace!("Using cached address {} for {}:{}", addr, host, port);
+                    return Ok(addr);
+                }
+            }
+        }
+        let addr = resolve(host, port)?;
+        if let Ok(mut cache) = self.inner.dns_cache.lock() {
+            cache.insert(key, (addr, now));
+        }
+        Ok(addr)
+    }
+
+    /// Return the shared decryption thread pool and its number of threads.
+    pub(crate) fn decryption_pool(&self) -> Option<(Arc<ThreadPool>, usize)> {
+        self.inner.decryption_pool.as_ref().map(|(pool, workers)| (Arc::clone(pool), *workers))
+    }
+
+    /// Wait until a handshake may start.
+    ///
+    /// The permit must be kept until the server handshake has finished.
+    pub(crate) fn acquire_handshake(&self) -> BoxedFuture<Option<HandshakePermit>, SaltyError> {
+        match self.inner.handshake_limit {
+            Some(ref limit) => Box::new(HandshakeLimit::acquire(limit).map(Some)),
+            None => Box::new(future::ok(None)),
+        }
+    }
+}
+
+impl fmt::Debug for SharedResources {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SharedResources")
+            .field("pinned_fingerprints", &self.inner.pinned_fingerprints.len())
+            .field("decryption_workers", &self.inner.decryption_pool.as_ref().map(|(_, workers)| workers))
+            .field("max_concurrent_handshakes", &self.inner.handshake_limit.as_ref().map(|limit| limit.max))
+            .finish()
+    }
+}
+
+
+/// A counting semaphore for handshakes.
+struct HandshakeLimit {
+    max: usize,
+    state: Mutex<LimitState>,
+}
+
+struct LimitState {
+    available: usize,
+    waiters: VecDeque<oneshot::Sender<HandshakePermit>>,
+}
+
+impl HandshakeLimit {
+    fn new(max: usize) -> Self {
+        HandshakeLimit {
+            max,
+            state: Mutex::new(LimitState { available: max, waiters: VecDeque::new() }),
+        }
+    }
+
+    fn acquire(limit: &Arc<HandshakeLimit>) -> impl Future<Item=HandshakePermit, Error=SaltyError> {
+        let mut state = match limit.state.lock() {
+            Ok(state) => state,
+            Err(e) => return future::Either::A(future::err(
+                SaltyError::Crash(format!("Could not lock handshake limit: {}", e))
+            )),
+        };
+        if state.available > 0 {
+            state.available -= 1;
+            return future::Either::A(future::ok(HandshakePermit { limit: Some(Arc::clone(limit)) }));
+        }
+        debug!("Waiting for one of {} running handshakes to finish", limit.max);
+        let (tx, rx) = oneshot::channel();
+        state.waiters.push_back(tx);
+        future::Either::B(rx.map_err(|_| SaltyError::Crash("Handshake limit was dropped".into())))
+    }
+
+    /// Hand the permit over to the next waiter, or make it available again.
+    fn release(limit: &Arc<HandshakeLimit>) {
+        loop {
+            let waiter = match limit.state.lock() {
+                Ok(mut state) => match state.waiters.pop_front() {
+                    Some(waiter) => waiter,
+                    None => {
+                        state.available += 1;
+                        return;
+                    },
+                },
+                Err(e) => {
+                    error!("Could not lock handshake limit: {}", e);
+                    return;
+                },
+            };
+            match waiter.send(HandshakePermit { limit: Some(Arc::clone(limit)) }) {
+                Ok(()) => return,
+                // The waiting client is gone, try the next one
+                Err(mut permit) => { permit.limit.take(); },
+            }
+        }
+    }
+}
+
+/// Allows one client to do a handshake. Released on drop.
+pub(crate) struct HandshakePermit {
+    limit: Option<Arc<HandshakeLimit>>,
+}
+
+impl Drop for HandshakePermit {
+    fn drop(&mut self) {
+        if let Some(limit) = self.limit.take() {
+            HandshakeLimit::release(&limit);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use futures::Async;
+    use futures::executor;
+
+    use super::*;
+
+    #[test]
+    fn handshake_limit() {
+        let shared = SharedResources::new(None).unwrap().with_max_concurrent_handshakes(1);
+        let first = shared.acquire_handshake().wait().unwrap();
+        assert!(first.is_some());
+
+        let mut second = executor::spawn(shared.acquire_handshake());
+        let mut third = executor::spawn(shared.acquire_handshake());
+        let notify = executor::NotifyHandle::from(Arc::new(NoopNotify));
+        assert!(second.poll_future_notify(&notify, 0).unwrap().is_not_ready());
+
+        // A waiter that gave up does not get the permit
+        drop(third);
+        drop(first);
+        let second = match second.poll_future_notify(&notify, 0).unwrap() {
+            Async::Ready(permit) => permit,
+            Async::NotReady => panic!("Permit was not handed over"),
+        };
+        third = executor::spawn(shared.acquire_handshake());
+        assert!(third.poll_future_notify(&notify, 0).unwrap().is_not_ready());
+        drop(second);
+        assert!(third.poll_future_notify(&notify, 0).unwrap().is_ready());
+    }
+
+    #[test]
+    fn no_handshake_limit() {
+        let shared = SharedResources::new(None).unwrap();
+        let permits: Vec<_> = (0..3).map(|_| shared.acquire_handshake().wait().unwrap()).collect();
+        assert!(permits.iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn dns_cache() {
+        let shared = SharedResources::new(None).unwrap();
+        let addr = shared.resolve("127.0.0.1", 8765).unwrap();
+        assert_eq!(addr, "127.0.0.1:8765".parse().unwrap());
+        assert_eq!(shared.inner.dns_cache.lock().unwrap().len(), 1);
+        assert_eq!(shared.clone().resolve("127.0.0.1", 8765).unwrap(), addr);
+        assert_eq!(shared.inner.dns_cache.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "configured before they are shared")]
+    fn configure_after_clone() {
+        let shared = SharedResources::new(None).unwrap();
+        let _clone = shared.clone();
+        shared.with_decryption_workers(2);
+    }
+
+    struct NoopNotify;
+
+    impl executor::Notify for NoopNotify {
+        fn notify(&self, _id: usize) {}
+    }
+}
@@ -0,0 +1,234 @@
+//! Message and traffic statistics for diagnostics.
+//!
+//! The signaling counts every message it handles or creates. Use
+//! [`SaltyClient::stats`](../struct.SaltyClient.html#method.stats) to get a
+//! snapshot, and
+//! [`SaltyClient::handshake_timings`](../struct.SaltyClient.html#method.handshake_timings)
+//! for the duration of the steps of the last handshake.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use crate::protocol::csn::PeerSequenceNumbers;
+
+
+/// A snapshot of the statistics of a client.
+///
+/// All counters are cumulative over the lifetime of the client, including
+/// previous connections.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    /// The number of bytes received, including the nonces.
+    pub bytes_in: u64,
+    /// The number of bytes sent, including the nonces.
+    pub bytes_out: u64,
+    /// The number of messages received.
+    pub messages_in: u64,
+    /// The number of messages sent.
+    pub messages_out: u64,
+    /// The number of received messages that could be decoded, by type.
+    pub message_types_in: BTreeMap<String, u64>,
+    /// The number of sent messages of a known type, by type.
+    pub message_types_out: BTreeMap<String, u64>,
+    /// The number of received messages that could not be decrypted.
+    pub decryption_failures: u64,
+    /// The sequence numbers exchanged with the authenticated peer, if any.
+    pub peer_sequence_numbers: Option<PeerSequenceNumbers>,
+    /// The time since the last connection to a server was established, if
+    /// any.
+    pub uptime: Option<Duration>,
+}
+
+
+/// The duration of the steps of the last connection and handshake.
+///
+/// A step that has not finished (yet) is `None`. All durations are reset
+/// when a new connection to a server is opened.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HandshakeTimings {
+    /// From the start of the connection until the WebSocket handshake with
+    /// the server was done, including the DNS lookup and the TLS handshake.
+    pub ws_connect: Option<Duration>,
+    /// From the 'server-hello' message until the 'server-auth' message was
+    /// processed.
+    pub server_handshake: Option<Duration>,
+    /// From the end of the server handshake until the peer handshake was
+    /// done.
+    ///
+    /// This includes the time spent waiting for the peer to connect.
+    pub peer_handshake: Option<Duration>,
+    /// From the end of the peer handshake until the task was started.
+    pub task_init: Option<Duration>,
+}
+
+
+/// The points in time at which the steps of a handshake finished.
+#[derive(Debug, Default)]
+struct HandshakeMarks {
+    connecting: Option<Instant>,
+    connected: Option<Instant>,
+    server_hello: Option<Instant>,
+    server_handshake_done: Option<Instant>,
+    peer_handshake_done: Option<Instant>,
+    task_started: Option<Instant>,
+}
+
+
+/// The counters that are updated by the signaling.
+#[derive(Debug, Default)]
+pub(crate) struct StatsCollector {
+    stats: Stats,
+    connected_at: Option<Instant>,
+    marks: HandshakeMarks,
+}
+
+impl StatsCollector {
+    /// Count an incoming message with the specified length.
+    pub(crate) fn incoming(&mut self, bytes: usize) {
+        self.stats.bytes_in += bytes as u64;
+        self.stats.messages_in += 1;
+    }
+
+    /// Count the type of a decoded incoming message.
+    pub(crate) fn incoming_type(&mut self, message_type: &str) {
+        count(&mut self.stats.message_types_in, message_type);
+    }
+
+    /// Count an outgoing message with the specified length and type.
+    pub(crate) fn outgoing<S: AsRef<str>>(&mut self, bytes: usize, message_type: Option<S>) {
+        self.stats.bytes_out += bytes as u64;
+        self.stats.messages_out += 1;
+        if let Some(message_type) = message_type {
+            count(&mut self.stats.message_types_out, message_type.as_ref());
+        }
+    }
+
+    /// Count an incoming message that could not be decrypted.
+    pub(crate) fn decryption_failed(&mut self) {
+        self.stats.decryption_failures += 1;
+    }
+
+    /// Start measuring the handshake timings of a new server connection.
+    #[cfg_attr(not(feature = "connect-tokio"), allow(dead_code))]
+    pub(crate) fn connecting(&mut self, now: Instant) {
+        self.marks = HandshakeMarks { connecting: Some(now), ..HandshakeMarks::default() };
+    }
+
+    /// Start measuring the uptime of a new server connection.
+    #[cfg_attr(not(feature = "connect-tokio"), allow(dead_code))]
+    pub(crate) fn connected(&mut self, now: Instant) {
+        self.connected_at = Some(now);
+        self.marks.connected = Some(now);
+    }
+
+    /// The 'server-hello' message has been received.
+    pub(crate) fn server_hello(&mut self, now: Instant) {
+        self.marks.server_hello = Some(now);
+    }
+
+    /// The server handshake is done.
+    pub(crate) fn server_handshake_done(&mut self, now: Instant) {
+        self.marks.server_handshake_done = Some(now);
+    }
+
+    /// The peer handshake is done.
+    pub(crate) fn peer_handshake_done(&mut self, now: Instant) {
+        self.marks.peer_handshake_done = Some(now);
+    }
+
+    /// The task has been started.
+    #[cfg_attr(not(feature = "connect-tokio"), allow(dead_code))]
+    pub(crate) fn task_started(&mut self, now: Instant) {
+        self.marks.task_started = Some(now);
+    }
+
+    /// Return the duration of the steps of the last handshake.
+    pub(crate) fn timings(&self) -> HandshakeTimings {
+        let marks = &self.marks;
+        HandshakeTimings {
+            ws_connect: between(marks.connecting, marks.connected),
+            server_handshake: between(marks.server_hello, marks.server_handshake_done),
+            peer_handshake: between(marks.server_handshake_done, marks.peer_handshake_done),
+            task_init: between(marks.peer_handshake_done, marks.task_started),
+        }
+    }
+
+    /// Return a snapshot of the statistics.
+    pub(crate) fn snapshot(&self, now: Instant, peer_sequence_numbers: Option<PeerSequenceNumbers>) -> Stats {
+        Stats {
+            peer_sequence_numbers,
+            uptime: self.connected_at.map(|connected_at| {
+                if now > connected_at { now - connected_at } else { Duration::from_secs(0) }
+            }),
+            ..self.stats.clone()
+        }
+    }
+}
+
+fn between(start: Option<Instant>, end: Option<Instant>) -> Option<Duration> {
+    match (start, end) {
+        (Some(start), Some(end)) => Some(if end > start { end - start } else { Duration::from_secs(0) }),
+        _ => None,
+    }
+}
+
+fn count(counters: &mut BTreeMap<String, u64>, message_type: &str) {
+    *counters.entry(message_type.to_string()).or_insert(0) += 1;
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot() {
+        let start = Instant::now();
+        let mut collector = StatsCollector::default();
+        assert_eq!(collector.snapshot(start, None), Stats::default());
+
+        collector.connected(start);
+        collector.incoming(100);
+        collector.incoming_type("application");
+        collector.incoming(50);
+        collector.decryption_failed();
+        collector.outgoing(80, Some("application"));
+        collector.outgoing(40, None::<&str>);
+        let stats = collector.snapshot(start + Duration::from_secs(3), None);
+        assert_eq!(stats.bytes_in, 150);
+        assert_eq!(stats.bytes_out, 120);
+        assert_eq!(stats.messages_in, 2);
+        assert_eq!(stats.messages_out, 2);
+        assert_eq!(stats.message_types_in.get("application"), Some(&1));
+        assert_eq!(stats.message_types_out.get("application"), Some(&1));
+        assert_eq!(stats.decryption_failures, 1);
+        assert_eq!(stats.uptime, Some(Duration::from_secs(3)));
+    }
+    #[test]
+    fn timings() {
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        let mut collector = StatsCollector::default();
+        assert_eq!(collector.timings(), HandshakeTimings::default());
+
+        collector.connecting(at(0));
+        collector.connected(at(100));
+        collector.server_hello(at(150));
+        collector.server_handshake_done(at(180));
+        assert_eq!(collector.timings(), HandshakeTimings {
+            ws_connect: Some(Duration::from_millis(100)),
+            server_handshake: Some(Duration::from_millis(30)),
+            peer_handshake: None,
+            task_init: None,
+        });
+        collector.peer_handshake_done(at(1180));
+        collector.task_started(at(1200));
+        let timings = collector.timings();
+        assert_eq!(timings.peer_handshake, Some(Duration::from_secs(1)));
+        assert_eq!(timings.task_init, Some(Duration::from_millis(20)));
+
+        // A new connection starts over
+        collector.connecting(at(2000));
+        assert_eq!(collector.timings(), HandshakeTimings::default());
+    }
+}
@@ -5,20 +5,29 @@
 //! has been negotiated and the authentication is complete, the task protocol
 //! defines further procedures, messages, etc.
 //!
-//! All tasks need to implement the [`Task`](trait.Task.html) trait.
+//! All tasks need to implement the [`Task`](trait.Task.html) trait. Task
+//! implementations can use a [`TaskHandle`](struct.TaskHandle.html) to keep
+//! the channels passed to `start` and
+//! [`TaskMessage::builder`](enum.TaskMessage.html#method.builder) to
+//! construct messages. See the `echo-task` example for a complete task.
 
 use std::borrow::Cow;
 use std::collections::HashMap;
-use std::fmt::Debug;
+use std::fmt::{self, Debug};
 use std::iter::IntoIterator;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
 
 use failure::Error;
-use futures::sync::mpsc::{UnboundedSender, UnboundedReceiver};
-use futures::sync::oneshot::Sender as OneshotSender;
+use futures::{Future, Poll};
+use futures::sync::mpsc::{Sender, UnboundedReceiver};
+use futures::sync::oneshot::{self, Canceled, Receiver as OneshotReceiver, Sender as OneshotSender};
 use mopa::{Any, mopafy};
 use rmpv::Value;
 
 use crate::CloseCode;
+use crate::clock::Clock;
+use crate::errors::{BuilderError, TaskSendError};
 
 
 /// A type alias for a boxed task.
@@ -38,6 +47,11 @@ pub type BoxedTask = Box<dyn Task + Send>;
 /// - `outgoing_tx`: This is the sending end for outgoing task / application /
 ///   close messages. The task should put messages that the user wants to send
 ///   through the established connection into this outgoing channel sender.
+///   The channel is bounded (see
+///   [`SaltyClientBuilder::with_outgoing_buffer`](../struct.SaltyClientBuilder.html#method.with_outgoing_buffer)):
+///   Once the WebSocket cannot keep up, `try_send` fails with an error whose
+///   `is_full()` method returns `true`. Use the `Sink` implementation to wait
+///   until there is room for more messages.
 /// - `incoming_rx`: This is the receiving end for incoming task / application /
 ///   close messages. The task should take messages from this incoming channel
 ///   receiver and pass them to the user.
@@ -57,7 +71,7 @@ pub trait Task : Debug + Any {
     ///
     /// This is the point where the task can take over.
     fn start(&mut self,
-             outgoing_tx: UnboundedSender<TaskMessage>,
+             outgoing_tx: Sender<TaskMessage>,
              incoming_rx: UnboundedReceiver<TaskMessage>,
              disconnect_tx: OneshotSender<Option<CloseCode>>);
 
@@ -84,7 +98,20 @@ pub trait Task : Debug + Any {
     fn data(&self) -> Option<HashMap<String, Value>>;
 
     /// This method can be called by the user to close the connection.
+    ///
+    /// The task loop also calls it once when the connection or the peer is
+    /// lost, unless the task closed the connection itself. The task should
+    /// then drop the channels passed to `start`, so that the task loop can
+    /// finish.
     fn close(&mut self, reason: CloseCode);
+
+    /// Called by the task loop before `start` with the send path for a
+    /// [`TaskHandle`](struct.TaskHandle.html).
+    ///
+    /// Tasks that send messages through a `TaskHandle` should pass it to
+    /// [`TaskHandle::attach`](struct.TaskHandle.html#method.attach), which
+    /// is required for messages with a TTL. By default, it is dropped.
+    fn attach(&mut self, _path: SendPath) {}
 }
 
 mopafy!(Task);
@@ -97,17 +124,23 @@ mopafy!(Task);
 pub(crate) struct Tasks(pub(crate) Vec<BoxedTask>);
 
 impl Tasks {
-    #[allow(dead_code)]
     pub(crate) fn new(task: BoxedTask) -> Self {
         Tasks(vec![task])
     }
 
     /// Create a `Tasks` instance from a vector.
     ///
-    /// This may fail if the tasks vector is empty.
-    pub(crate) fn from_vec(tasks: Vec<BoxedTask>) -> Result<Tasks, &'static str> {
+    /// The order of the vector determines the task priority. This may fail
+    /// if the tasks vector is empty or if it contains two tasks with the same
+    /// `.name()`.
+    pub(crate) fn from_vec(tasks: Vec<BoxedTask>) -> Result<Tasks, BuilderError> {
         if tasks.is_empty() {
-            return Err("Tasks vector may not be empty");
+            return Err(BuilderError::MissingTask);
+        }
+        for (i, task) in tasks.iter().enumerate() {
+            if tasks[..i].iter().any(|t| t.name() == task.name()) {
+                return Err(BuilderError::DuplicateTask(task.name().into_owned()));
+            }
         }
         Ok(Tasks(tasks))
     }
@@ -130,6 +163,12 @@ impl Tasks {
         self.0.len()
     }
 
+    /// Return whether any of our tasks is contained in the list of supported tasks provided by the
+    /// peer.
+    pub(crate) fn has_shared_task<S: AsRef<str>>(&self, tasks: &[S]) -> bool {
+        self.0.iter().any(|task| tasks.iter().any(|p| p.as_ref() == &*task.name()))
+    }
+
     /// Choose the first task in our own list of supported tasks that is also contained in the list
     /// of supported tasks provided by the peer.
     pub(crate) fn choose_shared_task<S: AsRef<str>>(self, tasks: &[S]) -> Option<BoxedTask> {
@@ -153,6 +192,69 @@ impl IntoIterator for Tasks {
 }
 
 
+/// A task that was negotiated in a previous session.
+///
+/// The chosen task is shared with the application once the handshake is
+/// done, so it cannot be moved into a new `Tasks` instance. When the session
+/// is renewed, this wrapper offers the same instance again.
+#[derive(Debug)]
+pub(crate) struct SharedTask(pub(crate) Arc<Mutex<BoxedTask>>);
+
+impl SharedTask {
+    fn lock(&self) -> MutexGuard<'_, BoxedTask> {
+        self.0.lock().expect("Task mutex is poisoned")
+    }
+}
+
+impl Task for SharedTask {
+    fn init(&mut self, data: &Option<HashMap<String, Value>>) -> Result<(), Error> {
+        self.lock().init(data)
+    }
+
+    fn start(&mut self,
+             outgoing_tx: Sender<TaskMessage>,
+             incoming_rx: UnboundedReceiver<TaskMessage>,
+             disconnect_tx: OneshotSender<Option<CloseCode>>) {
+        self.lock().start(outgoing_tx, incoming_rx, disconnect_tx)
+    }
+
+    fn supported_types(&self) -> &'static [&'static str] {
+        self.lock().supported_types()
+    }
+
+    fn send_signaling_message(&self, payload: &[u8]) {
+        self.lock().send_signaling_message(payload)
+    }
+
+    fn name(&self) -> Cow<'static, str> {
+        self.lock().name()
+    }
+
+    fn data(&self) -> Option<HashMap<String, Value>> {
+        self.lock().data()
+    }
+
+    fn close(&mut self, reason: CloseCode) {
+        self.lock().close(reason)
+    }
+
+    fn attach(&mut self, path: SendPath) {
+        self.lock().attach(path)
+    }
+}
+
+/// Wrap the chosen task so that it can be shared with the application.
+///
+/// If the task is a [`SharedTask`](struct.SharedTask.html), the instance
+/// that is already shared is returned.
+pub(crate) fn into_shared(task: BoxedTask) -> Arc<Mutex<BoxedTask>> {
+    if let Some(shared) = (&*task as &dyn Task).downcast_ref::<SharedTask>() {
+        return Arc::clone(&shared.0);
+    }
+    Arc::new(Mutex::new(task))
+}
+
+
 /// A task may either send an arbitrary value, an `Application` message or a `Close` message.
 #[derive(Debug, Clone, PartialEq)]
 pub enum TaskMessage {
@@ -170,10 +272,290 @@ pub enum TaskMessage {
     Close(CloseCode),
 }
 
+impl TaskMessage {
+    /// Start building a `Value` message with the specified type.
+    pub fn builder(msg_type: &str) -> TaskMessageBuilder {
+        let mut map = HashMap::new();
+        map.insert("type".to_string(), Value::String(msg_type.into()));
+        TaskMessageBuilder { map }
+    }
+
+    /// Return the type of a `Value` message.
+    pub fn message_type(&self) -> Option<&str> {
+        self.field("type").and_then(Value::as_str)
+    }
+
+    /// Return a field of a `Value` message.
+    pub fn field(&self, key: &str) -> Option<&Value> {
+        match self {
+            TaskMessage::Value(map) => map.get(key),
+            _ => None,
+        }
+    }
+}
+
+
+/// A message on the send path of a task handle.
+#[derive(Debug)]
+pub(crate) struct Outgoing {
+    pub(crate) msg: TaskMessage,
+    /// The message is dropped instead of being sent from this point in time.
+    #[cfg_attr(not(feature = "connect-tokio"), allow(dead_code))]
+    pub(crate) deadline: Option<Instant>,
+    /// Notified once the message was flushed to the WebSocket.
+    #[cfg_attr(not(feature = "connect-tokio"), allow(dead_code))]
+    pub(crate) delivered: Option<DeliveryNotifier>,
+}
+
+impl From<TaskMessage> for Outgoing {
+    fn from(msg: TaskMessage) -> Self {
+        Outgoing { msg, deadline: None, delivered: None }
+    }
+}
+
+/// The channel that a [`TaskHandle`](struct.TaskHandle.html) sends its
+/// messages through once it is attached to the task loop, see
+/// [`Task::attach`](trait.Task.html#method.attach).
+///
+/// The channel has the same capacity as the outgoing channel passed to
+/// `start`.
+#[derive(Debug)]
+pub struct SendPath {
+    tx: Sender<Outgoing>,
+    clock: Arc<dyn Clock>,
+}
+
+impl SendPath {
+    #[cfg(any(test, feature = "connect-tokio"))]
+    pub(crate) fn new(tx: Sender<Outgoing>, clock: Arc<dyn Clock>) -> Self {
+        SendPath { tx, clock }
+    }
+}
+
+
+/// The id of a message sent with
+/// [`TaskHandle::send_tracked`](struct.TaskHandle.html#method.send_tracked).
+///
+/// The ids are assigned in ascending order per task handle.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MessageId(pub u64);
+
+/// Notifies the [`Delivery`](struct.Delivery.html) of a tracked message.
+#[derive(Clone)]
+pub(crate) struct DeliveryNotifier {
+    id: MessageId,
+    tx: Arc<Mutex<Option<OneshotSender<MessageId>>>>,
+}
+
+impl DeliveryNotifier {
+    /// Resolve the delivery future.
+    #[cfg_attr(not(feature = "connect-tokio"), allow(dead_code))]
+    pub(crate) fn notify(&self) {
+        let tx = match self.tx.lock() {
+            Ok(mut tx) => tx.take(),
+            Err(_) => None,
+        };
+        if let Some(tx) = tx {
+            // The receiver may have been dropped, that's fine
+            let _ = tx.send(self.id);
+        }
+    }
+}
+
+impl Debug for DeliveryNotifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("DeliveryNotifier").field(&self.id.0).finish()
+    }
+}
+
+impl PartialEq for DeliveryNotifier {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.tx, &other.tx)
+    }
+}
+
+/// A future that resolves with the id of a tracked message once the
+/// message was flushed to the WebSocket.
+///
+/// The future fails with `Canceled` if the message was dropped instead, e.g.
+/// because it expired or because the connection was closed.
+#[derive(Debug)]
+pub struct Delivery {
+    id: MessageId,
+    rx: OneshotReceiver<MessageId>,
+}
+
+impl Delivery {
+    /// Return the id of the tracked message.
+    pub fn id(&self) -> MessageId {
+        self.id
+    }
+}
+
+impl Future for Delivery {
+    type Item = MessageId;
+    type Error = Canceled;
+
+    fn poll(&mut self) -> Poll<MessageId, Canceled> {
+        self.rx.poll()
+    }
+}
+
+/// A builder for [`TaskMessage::Value`](enum.TaskMessage.html#variant.Value)
+/// messages, created with
+/// [`TaskMessage::builder`](enum.TaskMessage.html#method.builder).
+#[derive(Debug, Clone, PartialEq)]
+pub struct TaskMessageBuilder {
+    map: HashMap<String, Value>,
+}
+
+impl TaskMessageBuilder {
+    /// Add a field. Adding the `type` field replaces the message type.
+    pub fn field<V: Into<Value>>(mut self, key: &str, value: V) -> Self {
+        self.map.insert(key.to_string(), value.into());
+        self
+    }
+
+    /// Return the message.
+    pub fn build(self) -> TaskMessage {
+        TaskMessage::Value(self.map)
+    }
+}
+
+
+/// The channels passed to [`Task::start`](trait.Task.html#tymethod.start)
+/// that a task uses to send messages and to close the connection.
+///
+/// Create an empty handle when creating the task, and store the channels once
+/// the task is started.
+#[derive(Debug, Default)]
+pub struct TaskHandle {
+    outgoing_tx: Option<Sender<TaskMessage>>,
+    path: Option<SendPath>,
+    disconnect_tx: Option<OneshotSender<Option<CloseCode>>>,
+    next_message_id: u64,
+    closed: bool,
+}
+
+impl TaskHandle {
+    /// Create a handle for a task that has not been started yet.
+    pub fn new() -> Self {
+        TaskHandle::default()
+    }
+
+    /// Store the send path passed to `Task::attach`.
+    ///
+    /// Once attached, all messages of the handle are sent through it.
+    pub fn attach(&mut self, path: SendPath) {
+        self.path = Some(path);
+    }
+
+    /// Store the channels passed to `Task::start`.
+    pub fn start(&mut self, outgoing_tx: Sender<TaskMessage>, disconnect_tx: OneshotSender<Option<CloseCode>>) {
+        self.outgoing_tx = Some(outgoing_tx);
+        self.disconnect_tx = Some(disconnect_tx);
+        self.closed = false;
+    }
+
+    /// Return whether the task has been started.
+    pub fn is_started(&self) -> bool {
+        self.outgoing_tx.is_some() || self.closed
+    }
+
+    /// Return a clone of the outgoing channel sender.
+    ///
+    /// Use its `Sink` implementation to wait until there is room in the
+    /// outgoing buffer. Once the handle is attached, messages sent through
+    /// it are not ordered relative to the ones sent through the handle.
+    pub fn outgoing_tx(&self) -> Option<Sender<TaskMessage>> {
+        self.outgoing_tx.clone()
+    }
+
+    /// Send a message to the peer without waiting.
+    ///
+    /// Fails with `TaskSendError::Full` until the messages sent before have
+    /// left the outgoing buffer.
+    pub fn send(&mut self, msg: TaskMessage) -> Result<(), TaskSendError> {
+        self.send_outgoing(Outgoing::from(msg))
+    }
+
+    /// Send a message to the peer without waiting, and drop it instead if
+    /// it has not been sent within `ttl`.
+    ///
+    /// Stale messages, e.g. ICE candidates, may pile up in the outgoing
+    /// buffer while the connection is slow. The TTL is measured with the
+    /// clock passed to
+    /// [`SaltyClientBuilder::with_clock`](../struct.SaltyClientBuilder.html#method.with_clock),
+    /// and expired messages are reported with an
+    /// [`Event::MessageExpired`](../enum.Event.html#variant.MessageExpired)
+    /// event. Fails with `TaskSendError::NotStarted` if the handle is not
+    /// attached, otherwise like [`send`](#method.send).
+    pub fn send_with_ttl(&mut self, msg: TaskMessage, ttl: Duration) -> Result<(), TaskSendError> {
+        let path = self.path.as_ref().ok_or(TaskSendError::NotStarted)?;
+        let deadline = path.clock.now() + ttl;
+        self.send_outgoing(Outgoing { msg, deadline: Some(deadline), delivered: None })
+    }
+
+    /// Send a message through the send path if attached, or through the
+    /// outgoing channel.
+    fn send_outgoing(&mut self, outgoing: Outgoing) -> Result<(), TaskSendError> {
+        let not_started = if self.closed { TaskSendError::Disconnected } else { TaskSendError::NotStarted };
+        let tx = self.outgoing_tx.as_mut().ok_or(not_started)?;
+        let result = match self.path.as_mut() {
+            Some(path) => path.tx.try_send(outgoing).map_err(|e| e.is_full()),
+            None => tx.try_send(outgoing.msg).map_err(|e| e.is_full()),
+        };
+        result.map_err(|is_full| if is_full { TaskSendError::Full } else { TaskSendError::Disconnected })
+    }
+
+    /// Send a message to the peer without waiting, and return a future that
+    /// resolves once the message was flushed to the WebSocket.
+    ///
+    /// This allows e.g. tracking the progress of a file transfer. Fails with
+    /// `TaskSendError::NotStarted` if the handle is not attached, otherwise
+    /// like [`send`](#method.send).
+    pub fn send_tracked(&mut self, msg: TaskMessage) -> Result<Delivery, TaskSendError> {
+        if self.path.is_none() {
+            return Err(TaskSendError::NotStarted);
+        }
+        let id = MessageId(self.next_message_id);
+        let (tx, rx) = oneshot::channel();
+        let notifier = DeliveryNotifier { id, tx: Arc::new(Mutex::new(Some(tx))) };
+        self.send_outgoing(Outgoing { msg, deadline: None, delivered: Some(notifier) })?;
+        self.next_message_id += 1;
+        Ok(Delivery { id, rx })
+    }
+
+    /// Send an application message to the peer without waiting.
+    pub fn send_application(&mut self, data: Value) -> Result<(), TaskSendError> {
+        self.send(TaskMessage::Application(data))
+    }
+
+    /// Close the connection with the specified reason, and drop the
+    /// channels. Messages cannot be sent anymore afterwards.
+    ///
+    /// Return `false` if the task was not started, has already been closed,
+    /// or if the connection is already gone.
+    pub fn close(&mut self, reason: CloseCode) -> bool {
+        if self.outgoing_tx.take().is_some() {
+            self.closed = true;
+        }
+        self.path = None;
+        match self.disconnect_tx.take() {
+            Some(tx) => tx.send(Some(reason)).is_ok(),
+            None => false,
+        }
+    }
+}
+
 
 #[cfg(test)]
 mod tests {
+    use futures::{Future, Stream};
+    use futures::sync::{mpsc, oneshot};
+
     use super::*;
+    use crate::clock::ManualClock;
     use crate::test_helpers::DummyTask;
 
     #[test]
@@ -210,6 +592,7 @@ mod tests {
         assert_eq!(chosen.name(), "dummy.2");
 
         // Return `None` if no common task is present
+        assert!(!make_tasks().has_shared_task(&["dummy.3"]));
         let chosen = make_tasks().choose_shared_task(&vec!["dummy.3".to_string()]);
         assert!(chosen.is_none());
 
@@ -217,4 +600,141 @@ mod tests {
         let chosen = make_tasks().choose_shared_task(&["dummy.2", "dummy.1"]).expect("No shared task found (3)");
         assert_eq!(chosen.name(), "dummy.1");
     }
+
+    #[test]
+    fn tasks_from_vec() {
+        assert_eq!(Tasks::from_vec(vec![]).unwrap_err(), BuilderError::MissingTask);
+
+        let tasks = Tasks::from_vec(vec![
+            Box::new(DummyTask::new(1)),
+            Box::new(DummyTask::new(2)),
+        ]).unwrap();
+        assert_eq!(tasks.len(), 2);
+
+        let err = Tasks::from_vec(vec![
+            Box::new(DummyTask::new(1)),
+            Box::new(DummyTask::new(2)),
+            Box::new(DummyTask::new(1)),
+        ]).unwrap_err();
+        assert_eq!(err, BuilderError::DuplicateTask("dummy.1".into()));
+    }
+
+    #[test]
+    fn task_message_builder() {
+        let msg = TaskMessage::builder("echo")
+            .field("text", "hello")
+            .field("count", 3)
+            .build();
+        assert_eq!(msg.message_type(), Some("echo"));
+        assert_eq!(msg.field("text"), Some(&Value::from("hello")));
+        assert_eq!(msg.field("count"), Some(&Value::from(3)));
+        assert_eq!(msg.field("missing"), None);
+        assert_eq!(TaskMessage::Close(CloseCode::WsGoingAway).message_type(), None);
+    }
+
+    #[test]
+    fn task_handle_send_with_ttl() {
+        let clock = Arc::new(ManualClock::new());
+        let start = clock.now();
+        let mut handle = TaskHandle::new();
+        let (outgoing_tx, _outgoing_rx) = mpsc::channel(1);
+        let (disconnect_tx, _disconnect_rx) = oneshot::channel();
+        handle.start(outgoing_tx, disconnect_tx);
+
+        // Without a send path, there is no clock
+        let msg = TaskMessage::Application(Value::Nil);
+        assert_eq!(handle.send_with_ttl(msg.clone(), Duration::from_secs(1)), Err(TaskSendError::NotStarted));
+
+        let (path_tx, path_rx) = mpsc::channel(1);
+        handle.attach(SendPath::new(path_tx, clock.clone()));
+        clock.advance(Duration::from_secs(3));
+        handle.send_with_ttl(msg.clone(), Duration::from_secs(2)).unwrap();
+        handle.send(msg.clone()).unwrap();
+        let mut received = path_rx.wait();
+        let outgoing = received.next().unwrap().unwrap();
+        assert_eq!(outgoing.msg, msg);
+        assert_eq!(outgoing.deadline, Some(start + Duration::from_secs(5)));
+        assert_eq!(received.next().unwrap().unwrap().deadline, None);
+
+        // Closing the handle drops the send path as well
+        assert!(handle.close(CloseCode::WsGoingAway));
+        assert!(received.next().is_none());
+    }
+
+    #[test]
+    fn task_handle() {
+        let mut handle = TaskHandle::new();
+        assert!(!handle.is_started());
+        assert_eq!(handle.send_application(Value::Nil), Err(TaskSendError::NotStarted));
+        assert!(!handle.close(CloseCode::WsGoingAway));
+
+        let (outgoing_tx, outgoing_rx) = mpsc::channel(0);
+        let (disconnect_tx, disconnect_rx) = oneshot::channel();
+        handle.start(outgoing_tx, disconnect_tx);
+        assert!(handle.is_started());
+
+        // Without a buffer, the sender may only send a single message until
+        // it has been received
+        assert_eq!(handle.send_application(Value::Nil), Ok(()));
+        assert_eq!(handle.send_application(Value::Nil), Err(TaskSendError::Full));
+        let mut received = outgoing_rx.wait();
+        assert_eq!(received.next(), Some(Ok(TaskMessage::Application(Value::Nil))));
+        assert_eq!(handle.send_application(Value::from(1)), Ok(()));
+
+        assert!(handle.close(CloseCode::ProtocolError));
+        assert!(!handle.close(CloseCode::ProtocolError));
+        assert_eq!(disconnect_rx.wait(), Ok(Some(CloseCode::ProtocolError)));
+
+        drop(received);
+        assert_eq!(handle.send_application(Value::Nil), Err(TaskSendError::Disconnected));
+    }
+
+    /// Closing the handle drops the outgoing channel, so that the task loop
+    /// can finish.
+    #[test]
+    fn task_handle_close_drops_channels() {
+        let mut handle = TaskHandle::new();
+        let (outgoing_tx, outgoing_rx) = mpsc::channel(1);
+        let (disconnect_tx, disconnect_rx) = oneshot::channel();
+        handle.start(outgoing_tx, disconnect_tx);
+
+        // The connection is already gone
+        drop(disconnect_rx);
+        assert!(!handle.close(CloseCode::WsGoingAway));
+        assert!(handle.is_started());
+        assert_eq!(outgoing_rx.wait().next(), None);
+        assert_eq!(handle.send_application(Value::Nil), Err(TaskSendError::Disconnected));
+    }
+
+    #[test]
+    fn tracked_task_message() {
+        let mut handle = TaskHandle::new();
+        assert!(handle.send_tracked(TaskMessage::Application(Value::Nil)).is_err());
+
+        let (outgoing_tx, _outgoing_rx) = mpsc::channel(1);
+        let (disconnect_tx, _disconnect_rx) = oneshot::channel();
+        handle.start(outgoing_tx, disconnect_tx);
+
+        // Without a send path, the message cannot be tracked
+        let msg = TaskMessage::Application(Value::from(1));
+        assert_eq!(handle.send_tracked(msg.clone()).unwrap_err(), TaskSendError::NotStarted);
+
+        let (path_tx, path_rx) = mpsc::channel(1);
+        handle.attach(SendPath::new(path_tx, Arc::new(ManualClock::new())));
+        let first = handle.send_tracked(msg.clone()).unwrap();
+        let second = handle.send_tracked(TaskMessage::Application(Value::from(2))).unwrap();
+        assert_eq!((first.id(), second.id()), (MessageId(0), MessageId(1)));
+
+        let mut received = path_rx.wait();
+        let outgoing = received.next().unwrap().unwrap();
+        assert_eq!(outgoing.msg, msg);
+        let notifier = outgoing.delivered.unwrap();
+        assert_eq!(notifier.id, MessageId(0));
+        notifier.notify();
+        assert_eq!(first.wait(), Ok(MessageId(0)));
+
+        // Dropping the message cancels the delivery
+        drop(received.next());
+        assert_eq!(second.wait(), Err(Canceled));
+    }
 }
@@ -6,7 +6,7 @@ use std::borrow::Cow;
 use std::collections::HashMap;
 
 use failure::Error;
-use futures::sync::mpsc::{UnboundedSender, UnboundedReceiver};
+use futures::sync::mpsc::{Sender, UnboundedReceiver};
 use futures::sync::oneshot::Sender as OneshotSender;
 use rmpv::Value;
 
@@ -18,6 +18,7 @@ use crate::tasks::{Task, TaskMessage};
 pub(crate) struct DummyTask {
     pub id: u8,
     pub initialized: bool,
+    pub closed: Vec<CloseCode>,
 }
 
 impl DummyTask {
@@ -25,6 +26,7 @@ impl DummyTask {
         DummyTask {
             id,
             initialized: false,
+            closed: vec![],
         }
     }
 
@@ -39,7 +41,7 @@ impl Task for DummyTask {
         Ok(())
     }
 
-    fn start(&mut self, _: UnboundedSender<TaskMessage>, _: UnboundedReceiver<TaskMessage>, _: OneshotSender<Option<CloseCode>>) {
+    fn start(&mut self, _: Sender<TaskMessage>, _: UnboundedReceiver<TaskMessage>, _: OneshotSender<Option<CloseCode>>) {
         unimplemented!()
     }
 
@@ -59,8 +61,8 @@ impl Task for DummyTask {
         None
     }
 
-    fn close(&mut self, _reason: CloseCode) {
-        unimplemented!()
+    fn close(&mut self, reason: CloseCode) {
+        self.closed.push(reason);
     }
 }
 
@@ -0,0 +1,147 @@
+//! TLS configuration for the server connection.
+//!
+//! Self-hosted SaltyRTC servers often use certificates issued by a private
+//! CA. The [`TlsConfig`](struct.TlsConfig.html) allows trusting such a CA,
+//! pinning the server certificate and presenting a client certificate.
+
+use std::fmt;
+use std::io::{Read, Write};
+
+use native_tls::{Certificate, Identity, TlsConnector, TlsStream};
+use crate::crypto_backend::backend;
+use crate::errors::{SaltyError, SaltyResult};
+
+
+/// The TLS configuration used when connecting to the SaltyRTC server.
+///
+/// Pass it to
+/// [`SaltyClientBuilder::with_tls_config`](struct.SaltyClientBuilder.html#method.with_tls_config).
+#[derive(Clone, Default)]
+pub struct TlsConfig {
+    root_certificates: Vec<Certificate>,
+    disable_built_in_roots: bool,
+    identity: Option<Identity>,
+    pinned_fingerprints: Vec<[u8; 32]>,
+}
+
+impl TlsConfig {
+    /// Create a new TLS configuration with the system defaults.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trust the specified root CA certificate in addition to the system roots.
+    pub fn add_root_certificate(mut self, certificate: Certificate) -> Self {
+        self.root_certificates.push(certificate);
+        self
+    }
+
+    /// Do not trust the system root certificates.
+    ///
+    /// Only the certificates added with
+    /// [`add_root_certificate`](#method.add_root_certificate) will be trusted.
+    pub fn disable_built_in_roots(mut self, disable: bool) -> Self {
+        self.disable_built_in_roots = disable;
+        self
+    }
+
+    /// Present the specified client certificate to the server.
+    pub fn with_client_identity(mut self, identity: Identity) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    /// Pin the server certificate by the SHA-256 fingerprint of its DER
+    /// encoding.
+    ///
+    /// When at least one fingerprint is pinned, the connection is aborted
+    /// before the WebSocket handshake unless the server certificate matches
+    /// one of them. The regular certificate validation is still done.
+    pub fn pin_certificate_sha256(mut self, fingerprint: [u8; 32]) -> Self {
+        self.pinned_fingerprints.push(fingerprint);
+        self
+    }
+
+    /// Build the TLS connector.
+    pub(crate) fn connector(&self) -> SaltyResult<TlsConnector> {
+        let mut builder = TlsConnector::builder();
+        for certificate in &self.root_certificates {
+            builder.add_root_certificate(certificate.clone());
+        }
+        builder.disable_built_in_roots(self.disable_built_in_roots);
+        if let Some(ref identity) = self.identity {
+            builder.identity(identity.clone());
+        }
+        builder.build()
+            .map_err(|e| SaltyError::Crypto(format!("Could not create TLS connector: {}", e)))
+    }
+
+    /// Return the pinned certificate fingerprints.
+    pub(crate) fn pinned_fingerprints(&self) -> &[[u8; 32]] {
+        &self.pinned_fingerprints
+    }
+}
+
+/// Implementation required because `Certificate` and `Identity` don't
+/// implement Debug.
+impl fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TlsConfig")
+            .field("root_certificates", &self.root_certificates.len())
+            .field("disable_built_in_roots", &self.disable_built_in_roots)
+            .field("identity", &self.identity.is_some())
+            .field("pinned_fingerprints", &self.pinned_fingerprints.len())
+            .finish()
+    }
+}
+
+
+/// Verify that the server certificate matches one of the pinned fingerprints.
+///
+/// If no fingerprints are pinned, any certificate is accepted.
+pub(crate) fn verify_pinned_certificate<S>(pins: &[[u8; 32]], stream: &TlsStream<S>) -> SaltyResult<()>
+    where S: Read + Write
+{
+    if pins.is_empty() {
+        return Ok(());
+    }
+    let der = stream.peer_certificate()
+        .and_then(|cert| cert.map(|cert| cert.to_der()).transpose())
+        .map_err(|e| SaltyError::Crypto(format!("Could not read server certificate: {}", e)))?
+        .ok_or_else(|| SaltyError::Crypto("Server did not present a certificate".into()))?;
+    check_fingerprint(pins, &der)
+}
+
+/// Check whether the SHA-256 fingerprint of the DER encoded certificate is pinned.
+fn check_fingerprint(pins: &[[u8; 32]], der: &[u8]) -> SaltyResult<()> {
+    let fingerprint = backend().sha256(der);
+    if pins.contains(&fingerprint) {
+        Ok(())
+    } else {
+        Err(SaltyError::Crypto("Server certificate does not match any pinned fingerprint".into()))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprint_matches() {
+        let der = b"not really a certificate";
+        let fingerprint = backend().sha256(der);
+        assert_eq!(check_fingerprint(&[[0; 32], fingerprint], der), Ok(()));
+        assert_eq!(
+            check_fingerprint(&[[0; 32]], der),
+            Err(SaltyError::Crypto("Server certificate does not match any pinned fingerprint".into())),
+        );
+    }
+
+    #[test]
+    fn default_connector() {
+        let config = TlsConfig::new().pin_certificate_sha256([1; 32]);
+        assert!(config.connector().is_ok());
+        assert_eq!(config.pinned_fingerprints(), &[[1; 32]]);
+    }
+}
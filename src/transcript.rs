@@ -0,0 +1,243 @@
+//! Recording of the messages exchanged with the server and the peer.
+//!
+//! To debug interop issues with other SaltyRTC implementations, register a
+//! [`TranscriptRecorder`](trait.TranscriptRecorder.html) with
+//! [`SaltyClientBuilder::with_transcript_recorder`](../struct.SaltyClientBuilder.html#method.with_transcript_recorder).
+//! It is called with a [`TranscriptEntry`](struct.TranscriptEntry.html) for
+//! every message that is sent or received, containing the raw bytes and, if
+//! available, the decrypted payload.
+//!
+//! Note: Transcripts contain the decrypted task messages exchanged with the
+//! peer. Treat them as confidential.
+
+use std::fmt;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use rmp_serde as rmps;
+use rmpv::Value;
+
+use crate::boxes::ByteBox;
+use crate::protocol::messages::Message;
+
+
+/// Whether a message was sent or received.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Direction {
+    /// The message was received from the server or the peer.
+    Incoming,
+    /// The message was sent to the server or the peer.
+    Outgoing,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Direction::Incoming => write!(f, "incoming"),
+            Direction::Outgoing => write!(f, "outgoing"),
+        }
+    }
+}
+
+
+/// A message that was sent or received.
+#[derive(Debug, PartialEq, Clone)]
+pub struct TranscriptEntry {
+    /// Whether the message was sent or received.
+    pub direction: Direction,
+    /// The source address in the nonce.
+    pub source: u8,
+    /// The destination address in the nonce.
+    pub destination: u8,
+    /// The message bytes as sent over the WebSocket (nonce and payload).
+    pub bytes: Vec<u8>,
+    /// The message type, if the message could be decoded.
+    pub message_type: Option<String>,
+    /// The decrypted msgpack payload, if available.
+    ///
+    /// Incoming messages that could not be decrypted don't include the
+    /// payload.
+    pub plaintext: Option<Vec<u8>>,
+}
+
+impl TranscriptEntry {
+    /// Create an entry for a byte box, without the decrypted payload.
+    pub(crate) fn new(direction: Direction, bbox: &ByteBox) -> Self {
+        TranscriptEntry {
+            direction,
+            source: bbox.nonce.source().0,
+            destination: bbox.nonce.destination().0,
+            bytes: bbox.to_bytes(),
+            message_type: None,
+            plaintext: None,
+        }
+    }
+
+    /// Add the msgpack payload and its message type, if available.
+    ///
+    /// These are the exact bytes that were encrypted or decrypted, not a
+    /// re-serialization of the decoded message.
+    pub(crate) fn with_plaintext<P: AsRef<[u8]>>(mut self, plaintext: Option<P>) -> Self {
+        if let Some(plaintext) = plaintext {
+            let plaintext = plaintext.as_ref();
+            self.message_type = Message::peek_type(plaintext).ok();
+            self.plaintext = Some(plaintext.to_vec());
+        }
+        self
+    }
+
+    /// Encode the entry as msgpack map.
+    ///
+    /// The keys are `direction` (`"incoming"` or `"outgoing"`), `source`,
+    /// `destination`, `bytes`, `type` and `plaintext`. The last two are nil
+    /// if not available.
+    pub fn to_msgpack(&self) -> Vec<u8> {
+        let optional = |val: Option<Value>| val.unwrap_or(Value::Nil);
+        let value = Value::Map(vec![
+            (Value::from("direction"), Value::from(self.direction.to_string())),
+            (Value::from("source"), Value::from(self.source)),
+            (Value::from("destination"), Value::from(self.destination)),
+            (Value::from("bytes"), Value::Binary(self.bytes.clone())),
+            (Value::from("type"), optional(self.message_type.as_ref().map(|t| Value::from(t.as_str())))),
+            (Value::from("plaintext"), optional(self.plaintext.clone().map(Value::Binary))),
+        ]);
+        rmps::to_vec_named(&value).expect("Serialization failed")
+    }
+}
+
+
+/// A hook that is called for every message sent or received by a
+/// [`SaltyClient`](../struct.SaltyClient.html).
+pub trait TranscriptRecorder: Send {
+    /// Record a message.
+    fn record(&mut self, entry: TranscriptEntry);
+}
+
+
+/// A recorder that writes every entry as msgpack map (see
+/// [`TranscriptEntry::to_msgpack`](struct.TranscriptEntry.html#method.to_msgpack))
+/// to a writer, e.g. a file.
+#[derive(Debug)]
+pub struct MsgpackWriter<W: Write + Send> {
+    writer: W,
+}
+
+impl<W: Write + Send> MsgpackWriter<W> {
+    /// Create a new recorder writing to `writer`.
+    pub fn new(writer: W) -> Self {
+        MsgpackWriter { writer }
+    }
+
+    /// Return the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write + Send> TranscriptRecorder for MsgpackWriter<W> {
+    fn record(&mut self, entry: TranscriptEntry) {
+        if let Err(e) = self.writer.write_all(&entry.to_msgpack()) {
+            warn!("Could not write transcript entry: {}", e);
+        }
+    }
+}
+
+
+/// A recorder that keeps all entries in memory.
+///
+/// Clones share the same entries, so a clone can be passed to the builder
+/// while the original is used to read the transcript.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryTranscript {
+    entries: Arc<Mutex<Vec<TranscriptEntry>>>,
+}
+
+impl MemoryTranscript {
+    /// Create an empty transcript.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Return a copy of all recorded entries.
+    pub fn entries(&self) -> Vec<TranscriptEntry> {
+        match self.entries.lock() {
+            Ok(entries) => entries.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+}
+
+impl TranscriptRecorder for MemoryTranscript {
+    fn record(&mut self, entry: TranscriptEntry) {
+        match self.entries.lock() {
+            Ok(mut entries) => entries.push(entry),
+            Err(poisoned) => poisoned.into_inner().push(entry),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::Nonce;
+    use crate::protocol::cookie::Cookie;
+    use crate::protocol::csn::CombinedSequenceSnapshot;
+    use crate::protocol::messages::NewInitiator;
+    use crate::protocol::types::Address;
+
+    use super::*;
+
+    fn bbox() -> ByteBox {
+        let nonce = Nonce::new(Cookie::random(), Address(0), Address(3), CombinedSequenceSnapshot::random());
+        ByteBox::new(vec![1, 2, 3], nonce)
+    }
+
+    #[test]
+    fn entry_with_plaintext() {
+        let bbox = bbox();
+        let plaintext = NewInitiator::default().into_message().to_msgpack();
+        let entry = TranscriptEntry::new(Direction::Incoming, &bbox).with_plaintext(Some(&plaintext));
+        assert_eq!(entry.source, 0);
+        assert_eq!(entry.destination, 3);
+        assert_eq!(entry.bytes, bbox.into_bytes());
+        assert_eq!(entry.message_type, Some("new-initiator".to_string()));
+        assert_eq!(entry.plaintext, Some(plaintext));
+    }
+
+    /// The payload is recorded as is, even if it would be serialized
+    /// differently after decoding.
+    #[test]
+    fn entry_keeps_plaintext_bytes() {
+        // {"type": "offer", "n": 1}, with 1 encoded as uint32
+        let plaintext = [
+            0x82, 0xa4, b't', b'y', b'p', b'e', 0xa5, b'o', b'f', b'f', b'e', b'r',
+            0xa1, b'n', 0xce, 0x00, 0x00, 0x00, 0x01,
+        ];
+        let entry = TranscriptEntry::new(Direction::Outgoing, &bbox()).with_plaintext(Some(&plaintext[..]));
+        assert_eq!(entry.message_type, Some("offer".to_string()));
+        assert_eq!(entry.plaintext, Some(plaintext.to_vec()));
+    }
+
+    #[test]
+    fn msgpack_writer() {
+        let entry = TranscriptEntry::new(Direction::Outgoing, &bbox());
+        let mut recorder = MsgpackWriter::new(vec![]);
+        recorder.record(entry.clone());
+        let written = recorder.into_inner();
+        assert_eq!(written, entry.to_msgpack());
+
+        let decoded: Value = rmps::from_slice(&written).unwrap();
+        let map = decoded.as_map().unwrap();
+        assert_eq!(map[0], (Value::from("direction"), Value::from("outgoing")));
+        assert_eq!(map[3], (Value::from("bytes"), Value::Binary(entry.bytes)));
+        assert_eq!(map[4], (Value::from("type"), Value::Nil));
+    }
+
+    #[test]
+    fn memory_transcript_is_shared() {
+        let transcript = MemoryTranscript::new();
+        let mut recorder = transcript.clone();
+        recorder.record(TranscriptEntry::new(Direction::Incoming, &bbox()));
+        assert_eq!(transcript.entries().len(), 1);
+    }
+}
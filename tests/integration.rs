@@ -25,7 +25,7 @@ use saltyrtc_client::crypto::KeyPair;
 use saltyrtc_client::errors::SaltyError;
 use saltyrtc_client::dep::futures::Future;
 use saltyrtc_client::dep::native_tls::{Certificate, TlsConnector, Protocol};
-use saltyrtc_client::dep::futures::sync::mpsc::{UnboundedSender, UnboundedReceiver};
+use saltyrtc_client::dep::futures::sync::mpsc::{Sender, UnboundedReceiver};
 use saltyrtc_client::dep::futures::sync::oneshot::Sender as OneshotSender;
 use saltyrtc_client::dep::rmpv::Value;
 use saltyrtc_client::tasks::{Task, TaskMessage};
@@ -211,7 +211,7 @@ impl Task for DummyTask {
         Ok(())
     }
 
-    fn start(&mut self, _: UnboundedSender<TaskMessage>, _: UnboundedReceiver<TaskMessage>, _: OneshotSender<Option<CloseCode>>) {
+    fn start(&mut self, _: Sender<TaskMessage>, _: UnboundedReceiver<TaskMessage>, _: OneshotSender<Option<CloseCode>>) {
         unimplemented!()
     }
 
@@ -0,0 +1,391 @@
+//! Interoperability tests against the reference SaltyRTC server.
+//!
+//! These tests start the [Python server][server] in a docker container, connect
+//! an initiator and a responder of this crate and relay messages between them.
+//! They are ignored by default, run them with:
+//!
+//!     cargo test --test interop -- --ignored
+//!
+//! Requirements:
+//!
+//! * A running docker daemon, accessible by the current user.
+//! * The `saltyrtc.crt` certificate and the `saltyrtc.key` private key in the
+//!   repository root directory (see README). They are mounted into the
+//!   container.
+//!
+//! The image can be overridden with the `SALTYRTC_INTEROP_IMAGE` env var and
+//! the first port used on localhost with `SALTYRTC_INTEROP_PORT`.
+//!
+//! [server]: https://github.com/saltyrtc/saltyrtc-server-python/
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::net::TcpStream;
+use std::process::Command;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use failure::Error;
+use saltyrtc_client::{SaltyClient, AuthMethod, CloseCode, ConnectionState, Event, WsClient};
+use saltyrtc_client::crypto::{KeyPair, public_key_from_hex_str};
+use saltyrtc_client::errors::SaltyError;
+use saltyrtc_client::dep::futures::{stream, Future, Sink, Stream};
+use saltyrtc_client::dep::futures::sync::mpsc::{self, Sender, UnboundedReceiver};
+use saltyrtc_client::dep::futures::sync::oneshot::Sender as OneshotSender;
+use saltyrtc_client::dep::native_tls::{Certificate, TlsConnector};
+use saltyrtc_client::dep::rmpv::Value;
+use saltyrtc_client::tasks::{Task, TaskMessage};
+use tokio_core::reactor::{Core, Handle};
+use tokio_timer::Timer;
+
+
+const DEFAULT_IMAGE: &str = "saltyrtc/saltyrtc-server-python:latest";
+const DEFAULT_PORT: u16 = 18765;
+const SERVER_PRIVATE_KEY: &str = "0919b266ce1855419e4066fc076b39855e728768e3afa773105edd2e37037c20";
+const SERVER_PUBLIC_KEY: &str = "09a59a5fa6b45cb07638a3a6e347ce563a948b756fd22f9527465f7c79c2a864";
+const TASK_NAME: &str = "v0.interop.tests.saltyrtc.org";
+const TYPE_TEXT: &str = "text";
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+
+/// A SaltyRTC server running in a docker container.
+///
+/// The container is stopped (and removed) when this is dropped.
+struct ServerContainer {
+    id: String,
+    port: u16,
+}
+
+impl ServerContainer {
+    /// Start a server container.
+    ///
+    /// Every test uses a different `index`, so that tests may run in parallel.
+    fn start(index: u16) -> Self {
+        let image = env::var("SALTYRTC_INTEROP_IMAGE").unwrap_or_else(|_| DEFAULT_IMAGE.into());
+        let port = env::var("SALTYRTC_INTEROP_PORT")
+            .ok()
+            .and_then(|port| port.parse::<u16>().ok())
+            .unwrap_or(DEFAULT_PORT) + index;
+        let certs = env::current_dir().expect("Could not determine current directory");
+        let output = Command::new("docker")
+            .args(&["run", "--rm", "--detach"])
+            .arg("--publish").arg(format!("127.0.0.1:{}:8765", port))
+            .arg("--volume").arg(format!("{}:/certs:ro", certs.display()))
+            .arg(&image)
+            .args(&["serve", "-p", "8765"])
+            .args(&["-sc", "/certs/saltyrtc.crt", "-sk", "/certs/saltyrtc.key"])
+            .args(&["-k", SERVER_PRIVATE_KEY])
+            .output()
+            .unwrap_or_else(|e| panic!("Could not run docker: {}", e));
+        assert!(
+            output.status.success(),
+            "Could not start server container ({}): {}", image, String::from_utf8_lossy(&output.stderr),
+        );
+        let container = ServerContainer {
+            id: String::from_utf8_lossy(&output.stdout).trim().to_string(),
+            port,
+        };
+        container.wait_until_ready();
+        container
+    }
+
+    /// Wait until the server accepts TCP connections.
+    fn wait_until_ready(&self) {
+        for _ in 0..60 {
+            if TcpStream::connect(("localhost", self.port)).is_ok() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(500));
+        }
+        panic!("Server container did not start listening:\n{}", self.logs());
+    }
+
+    /// Return the server log.
+    fn logs(&self) -> String {
+        Command::new("docker")
+            .args(&["logs", &self.id])
+            .output()
+            .map(|output| String::from_utf8_lossy(&output.stderr).into_owned())
+            .unwrap_or_else(|e| format!("Could not read server log: {}", e))
+    }
+}
+
+impl Drop for ServerContainer {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            println!("Server log:\n{}", self.logs());
+        }
+        let _ = Command::new("docker").args(&["stop", &self.id]).output();
+    }
+}
+
+
+/// The channels passed to a started task.
+#[derive(Debug)]
+struct TaskChannels {
+    outgoing_tx: Sender<TaskMessage>,
+    incoming_rx: UnboundedReceiver<TaskMessage>,
+    disconnect_tx: OneshotSender<Option<CloseCode>>,
+}
+
+/// A task that hands its channels over to the test.
+#[derive(Debug)]
+struct InteropTask {
+    channels: Arc<Mutex<Option<TaskChannels>>>,
+}
+
+impl InteropTask {
+    fn new() -> (Self, Arc<Mutex<Option<TaskChannels>>>) {
+        let channels = Arc::new(Mutex::new(None));
+        (InteropTask { channels: Arc::clone(&channels) }, channels)
+    }
+}
+
+impl Task for InteropTask {
+    fn init(&mut self, _data: &Option<HashMap<String, Value>>) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn start(
+        &mut self,
+        outgoing_tx: Sender<TaskMessage>,
+        incoming_rx: UnboundedReceiver<TaskMessage>,
+        disconnect_tx: OneshotSender<Option<CloseCode>>,
+    ) {
+        *self.channels.lock().unwrap() = Some(TaskChannels { outgoing_tx, incoming_rx, disconnect_tx });
+    }
+
+    fn supported_types(&self) -> &'static [&'static str] {
+        &[TYPE_TEXT]
+    }
+
+    fn send_signaling_message(&self, _payload: &[u8]) {
+        unimplemented!()
+    }
+
+    fn name(&self) -> Cow<'static, str> {
+        TASK_NAME.into()
+    }
+
+    fn data(&self) -> Option<HashMap<String, Value>> {
+        None
+    }
+
+    fn close(&mut self, _reason: CloseCode) {}
+}
+
+
+fn get_tls_connector() -> TlsConnector {
+    let mut server_cert_bytes: Vec<u8> = vec![];
+    File::open("saltyrtc.crt")
+        .and_then(|mut file| file.read_to_end(&mut server_cert_bytes))
+        .expect("Could not read saltyrtc.crt");
+    let server_cert = Certificate::from_pem(&server_cert_bytes)
+        .unwrap_or_else(|e| panic!("Problem with CA cert: {}", e));
+    TlsConnector::builder()
+        .add_root_certificate(server_cert)
+        .build()
+        .unwrap_or_else(|e| panic!("Could not initialize TlsConnector: {}", e))
+}
+
+/// Connect to the server and return the handshake future and the event receiver.
+fn handshake(
+    salty: &Arc<RwLock<SaltyClient>>,
+    port: u16,
+    handle: &Handle,
+) -> (impl Future<Item=WsClient, Error=SaltyError>, mpsc::UnboundedReceiver<Event>) {
+    let (connect_future, event_channel) = saltyrtc_client::connect(
+            "localhost",
+            port,
+            Some(get_tls_connector()),
+            handle,
+            Arc::clone(salty),
+        )
+        .unwrap_or_else(|e| panic!("Could not connect: {}", e));
+    let (event_tx, event_rx) = event_channel.split();
+    let salty = Arc::clone(salty);
+    let future = connect_future
+        .and_then(move |client| saltyrtc_client::do_handshake(client, salty, event_tx, Some(TIMEOUT)));
+    (future, event_rx)
+}
+
+/// A connected initiator and responder with a finished peer handshake.
+struct Peers {
+    core: Core,
+    initiator: Arc<RwLock<SaltyClient>>,
+    responder: Arc<RwLock<SaltyClient>>,
+    initiator_channels: TaskChannels,
+    responder_channels: TaskChannels,
+    /// The task loops fail if their event receivers are dropped.
+    _events: (mpsc::UnboundedReceiver<Event>, mpsc::UnboundedReceiver<Event>),
+}
+
+/// Connect an initiator and a responder through the server and start their
+/// task loops.
+fn connect_peers(server: &ServerContainer) -> Peers {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let server_key = public_key_from_hex_str(SERVER_PUBLIC_KEY).unwrap();
+
+    let (initiator_task, initiator_channels) = InteropTask::new();
+    let initiator = SaltyClient::build(KeyPair::new())
+        .add_task(Box::new(initiator_task))
+        .with_server_key(server_key)
+        .initiator()
+        .expect("Could not create initiator");
+    let (responder_task, responder_channels) = InteropTask::new();
+    let responder = SaltyClient::build(KeyPair::new())
+        .add_task(Box::new(responder_task))
+        .with_server_key(server_key)
+        .responder(*initiator.initiator_pubkey(), initiator.auth_token().cloned().unwrap())
+        .expect("Could not create responder");
+    let initiator = Arc::new(RwLock::new(initiator));
+    let responder = Arc::new(RwLock::new(responder));
+
+    // Do both handshakes at the same time
+    let (initiator_handshake, initiator_events) = handshake(&initiator, server.port, &handle);
+    let (responder_handshake, responder_events) = handshake(&responder, server.port, &handle);
+    let (initiator_client, responder_client) = core.run(initiator_handshake.join(responder_handshake))
+        .unwrap_or_else(|e| panic!("Handshake failed: {}", e));
+
+    // Both peers must have been notified about the finished handshakes
+    assert_handshake_events(initiator_events);
+    assert_handshake_events(responder_events);
+
+    let initiator_events = start_task_loop(&initiator, initiator_client, &handle);
+    let responder_events = start_task_loop(&responder, responder_client, &handle);
+
+    let take = |channels: Arc<Mutex<Option<TaskChannels>>>| channels
+        .lock()
+        .unwrap()
+        .take()
+        .expect("Task was not started");
+    Peers {
+        core,
+        initiator,
+        responder,
+        initiator_channels: take(initiator_channels),
+        responder_channels: take(responder_channels),
+        _events: (initiator_events, responder_events),
+    }
+}
+
+fn assert_handshake_events(events: mpsc::UnboundedReceiver<Event>) {
+    let events: Vec<Event> = events.take(2).collect().wait().unwrap();
+    match events[0] {
+        Event::ServerHandshakeDone(_) => {},
+        ref other => panic!("Expected ServerHandshakeDone event, got {:?}", other),
+    }
+    assert_eq!(events[1], Event::PeerHandshakeDone(TASK_NAME.into()));
+}
+
+/// Spawn the task loop and return the event receiver.
+fn start_task_loop(salty: &Arc<RwLock<SaltyClient>>, client: WsClient, handle: &Handle) -> mpsc::UnboundedReceiver<Event> {
+    let (event_tx, event_rx) = mpsc::unbounded::<Event>();
+    let (_task, task_loop) = saltyrtc_client::task_loop(client, Arc::clone(salty), event_tx)
+        .unwrap_or_else(|e| panic!("Could not start task loop: {}", e));
+    handle.spawn(task_loop.map(|_| ()).map_err(|e| panic!("Task loop failed: {}", e)));
+    event_rx
+}
+
+/// Run a future with a timeout.
+fn run<F: Future<Error=SaltyError>>(core: &mut Core, future: F) -> F::Item {
+    core.run(Timer::default().timeout(future, TIMEOUT))
+        .unwrap_or_else(|e| panic!("Test future failed: {}", e))
+}
+
+/// Send a message and wait for the peer to receive it.
+fn relay(
+    core: &mut Core,
+    from: Sender<TaskMessage>,
+    to: UnboundedReceiver<TaskMessage>,
+    msg: TaskMessage,
+) -> (Sender<TaskMessage>, UnboundedReceiver<TaskMessage>, TaskMessage) {
+    let future = from
+        .send(msg)
+        .map_err(|e| SaltyError::Crash(format!("Could not send task message: {}", e)))
+        .and_then(|from| to
+            .into_future()
+            .map_err(|_| SaltyError::Crash("Could not receive task message".into()))
+            .map(|(received, to)| (from, to, received.expect("Incoming channel closed"))));
+    run(core, future)
+}
+
+fn text_message(text: &str) -> TaskMessage {
+    TaskMessage::builder(TYPE_TEXT).field("text", text).build()
+}
+
+
+/// Both peers finish the handshake and exchange task messages in both
+/// directions.
+#[test]
+#[ignore]
+fn handshake_and_relay_task_messages() {
+    let server = ServerContainer::start(0);
+    let peers = connect_peers(&server);
+    let Peers { mut core, initiator, responder, initiator_channels, responder_channels, .. } = peers;
+
+    for salty in &[&initiator, &responder] {
+        let peer_info = salty.read().unwrap().peer_info().expect("Peer info not available");
+        assert_eq!(peer_info.auth_method, AuthMethod::Token);
+    }
+
+    let (to_responder, from_initiator, received) = relay(
+        &mut core, initiator_channels.outgoing_tx, responder_channels.incoming_rx, text_message("ping"),
+    );
+    assert_eq!(received, text_message("ping"));
+    let (_, _, received) = relay(
+        &mut core, responder_channels.outgoing_tx, initiator_channels.incoming_rx, text_message("pong"),
+    );
+    assert_eq!(received, text_message("pong"));
+
+    // Several messages keep their order
+    let texts: Vec<String> = (0..50).map(|i| format!("message {}", i)).collect();
+    let messages: Vec<TaskMessage> = texts.iter().map(|text| text_message(text)).collect();
+    let future = to_responder
+        .send_all(stream::iter_ok(messages.clone()))
+        .map_err(|e| SaltyError::Crash(format!("Could not send task messages: {}", e)))
+        .and_then(move |_| from_initiator
+            .take(50)
+            .collect()
+            .map_err(|_| SaltyError::Crash("Could not receive task messages".into())));
+    assert_eq!(run(&mut core, future), messages);
+}
+
+/// Application messages are relayed, and closing the connection on one side
+/// closes the task of the peer.
+#[test]
+#[ignore]
+fn relay_application_message_and_close() {
+    let server = ServerContainer::start(1);
+    let peers = connect_peers(&server);
+    let Peers { mut core, responder, initiator_channels, responder_channels, .. } = peers;
+    let mut responder_states = responder.write().unwrap().state_changes();
+
+    let (_, incoming_rx, received) = relay(
+        &mut core,
+        initiator_channels.outgoing_tx,
+        responder_channels.incoming_rx,
+        TaskMessage::Application(Value::from("early data")),
+    );
+    assert_eq!(received, TaskMessage::Application(Value::from("early data")));
+
+    initiator_channels.disconnect_tx.send(Some(CloseCode::WsGoingAway)).unwrap();
+    let future = incoming_rx
+        .into_future()
+        .map_err(|_| SaltyError::Crash("Could not receive close message".into()));
+    let (received, _) = run(&mut core, future);
+    assert_eq!(received, Some(TaskMessage::Close(CloseCode::WsGoingAway)));
+
+    let future = responder_states
+        .by_ref()
+        .skip_while(|state| Ok(*state != ConnectionState::Closing))
+        .into_future()
+        .map_err(|_| SaltyError::Crash("Could not receive connection state".into()));
+    let (state, _) = run(&mut core, future);
+    assert_eq!(state, Some(ConnectionState::Closing));
+}